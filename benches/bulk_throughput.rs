@@ -0,0 +1,100 @@
+//! Measures the sustained throughput of a bulk IN endpoint with `DeviceHandle::bulk_stream`.
+//!
+//! Needs a device that sends bulk data as fast as it can, e.g., a loopback or a streaming
+//! gadget, given as `BULK_BENCH_DEVICE=<vendor-id-in-hex>:<product-id-in-hex>`. Run with
+//! `cargo bench --bench bulk_throughput`. `BULK_BENCH_TRANSFERS` and `BULK_BENCH_SIZE` override
+//! the depth derived from the endpoint, and `BULK_BENCH_SECONDS` the duration, 10 seconds.
+extern crate libusb_async as libusb;
+use libusb::*;
+
+use libusb::blocking::block_on_stream;
+use std::time::{Duration, Instant};
+
+fn main()
+{
+    let device = match std::env::var("BULK_BENCH_DEVICE") {
+        Ok(device) => device,
+        Err(_) => {
+            println!("BULK_BENCH_DEVICE=<vendor-id-in-hex>:<product-id-in-hex> not set, skipping");
+            return;
+        }
+    };
+
+    let mut ids = device.split(':').map(|id| u16::from_str_radix(id, 16).expect("Invalid device id"));
+    let (vid, pid) = match (ids.next(), ids.next()) {
+        (Some(vid), Some(pid)) => (vid, pid),
+        _ => panic!("BULK_BENCH_DEVICE should be <vendor-id>:<product-id>"),
+    };
+    let seconds = env_usize("BULK_BENCH_SECONDS").unwrap_or(10) as u64;
+
+    let mut context = libusb::Context::new().unwrap();
+    let (device, mut handle) = open_device(&mut context, vid, pid).expect("Device not found");
+
+    let config_value = handle.active_configuration().unwrap();
+    let config = device.config_descriptor_by_value(config_value)
+        .expect("No config descriptor found");
+    let (intf, ep) = config.find_endpoint(TransferType::Bulk, Direction::In)
+        .expect("No bulk IN endpoint");
+    let (intf, address) = (intf.interface_number(), ep.address());
+
+    let mut depth = QueueDepth::for_endpoint(TransferType::Bulk, device.speed(), ep.max_packet_size());
+    depth.transfers = env_usize("BULK_BENCH_TRANSFERS").unwrap_or(depth.transfers);
+    depth.transfer_size = env_usize("BULK_BENCH_SIZE").unwrap_or(depth.transfer_size);
+
+    if handle.kernel_driver_active(intf).unwrap_or(false) {
+        handle.detach_kernel_driver(intf).unwrap();
+    }
+    handle.claim_interface(intf).unwrap();
+    handle.enable_metrics(true);
+
+    println!("Reading endpoint {:02x} at {:?} speed, {} transfers of {} bytes, for {} s",
+             address, device.speed(), depth.transfers, depth.transfer_size, seconds);
+
+    let duration = Duration::from_secs(seconds);
+    let start = Instant::now();
+    let mut bytes = 0u64;
+    let mut reads = 0u64;
+    for buffer in block_on_stream(handle.bulk_stream(address.into(), depth)) {
+        match buffer {
+            Ok(buffer) => {
+                bytes += buffer.len() as u64;
+                reads += 1;
+            },
+            Err(e) => {
+                println!("Read failed: {}", e);
+                break;
+            }
+        }
+        if start.elapsed() >= duration {
+            break;
+        }
+    }
+
+    let elapsed = start.elapsed().as_secs_f64();
+    println!("{} bytes in {} reads, {:.1} MB/s", bytes, reads, bytes as f64 / elapsed / 1e6);
+    if let Some(metrics) = handle.metrics().endpoint(address) {
+        println!("Most reads in flight: {}, median latency: {:?}, 99th percentile: {:?}",
+                 metrics.max_in_flight, metrics.latency.percentile(50.0), metrics.latency.percentile(99.0));
+    }
+}
+
+fn env_usize(name: &str) -> Option<usize> {
+    std::env::var(name).ok().map(|value| value.parse().expect(name))
+}
+
+fn open_device(context: &mut libusb::Context, vid: u16, pid: u16) -> Option<(libusb::Device, libusb::DeviceHandle)> {
+    for device in context.devices().ok()?.iter() {
+        let device_desc = match device.device_descriptor() {
+            Ok(d) => d,
+            Err(_) => continue
+        };
+
+        if device_desc.vendor_id() == vid && device_desc.product_id() == pid {
+            if let Ok(handle) = device.open() {
+                return Some((device, handle));
+            }
+        }
+    }
+
+    None
+}
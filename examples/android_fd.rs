@@ -0,0 +1,82 @@
+//! Opens a device from a file descriptor, as an Android application does with the descriptor
+//! it gets from `UsbManager`.
+//!
+//! On Android, the Java side opens the device and hands the descriptor over through JNI:
+//!
+//! ```java
+//! UsbDeviceConnection connection = usbManager.openDevice(device);
+//! connection.claimInterface(device.getInterface(0), true);
+//! UsbBridge.nativeDescribe(connection.getFileDescriptor());
+//! ```
+//!
+//! which lands in `Java_com_example_usb_UsbBridge_nativeDescribe` below. On Linux, the example
+//! opens the usbfs node itself, e.g., `android_fd /dev/bus/usb/001/004`, to go the same way.
+
+extern crate libc;
+extern crate libusb_async as libusb;
+
+use std::fs::OpenOptions;
+use std::os::unix::io::AsRawFd;
+
+use libc::{c_int, c_void};
+use libusb::blocking::block_on;
+
+fn main()
+{
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.len() < 2 {
+        println!("usage: android_fd <usbfs-node, e.g., /dev/bus/usb/001/004>");
+        return;
+    }
+
+    let node = match OpenOptions::new().read(true).write(true).open(&args[1]) {
+        Ok(node) => node,
+        Err(e) => {
+            println!("could not open {}: {}", args[1], e);
+            return;
+        }
+    };
+    if let Err(e) = describe(node.as_raw_fd()) {
+        println!("could not describe {}: {}", args[1], e);
+    }
+}
+
+/// The JNI entry point of `static native int nativeDescribe(int fd)` in
+/// `com.example.usb.UsbBridge`. The JNI environment and class aren't used, so they are left
+/// untyped instead of pulling in JNI bindings.
+#[no_mangle]
+pub extern "C" fn Java_com_example_usb_UsbBridge_nativeDescribe(_env: *mut c_void, _class: *mut c_void, fd: c_int) -> c_int
+{
+    match describe(fd) {
+        Ok(()) => 0,
+        Err(e) => e.code(),
+    }
+}
+
+fn describe(fd: c_int) -> libusb::Result<()>
+{
+    // Android doesn't let applications enumerate devices
+    let context = libusb::Context::without_discovery()?;
+    let handle = context.wrap_sys_device(fd)?;
+
+    let descriptor = block_on(handle.read_device_descriptor_async())?;
+    println!("Device {:04x}:{:04x}, USB {}", descriptor.vendor_id(), descriptor.product_id(),
+             descriptor.usb_version());
+
+    let config = libusb::parse_config_descriptor(&block_on(handle.raw_config_descriptor(0))?)?;
+    for interface in config.interfaces() {
+        for setting in interface.descriptors() {
+            println!("  Interface {} alternate setting {}: class {}", setting.interface_number(),
+                     setting.setting_number(), setting.class_code());
+        }
+    }
+
+    // Answered with NotSupported on Android, where the Java side claims interfaces
+    match handle.kernel_driver_active(0) {
+        Ok(active) => println!("Kernel driver on interface 0: {}", active),
+        Err(libusb::Error::NotSupported) => println!("Kernel drivers aren't visible here"),
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
@@ -18,7 +18,7 @@ fn list_devices() -> libusb::Result<()> {
 
     let context = libusb::Context::new()?;
 
-    for device in context.devices()?.iter() {
+    for device in context.devices()? {
         let device_desc = match device.device_descriptor() {
             Ok(d) => d,
             Err(_) => continue
@@ -47,7 +47,7 @@ fn list_devices() -> libusb::Result<()> {
             }
         };
 
-        println!("Bus {:03} Device {:03} ID {:04x}:{:04x} {}", device.bus_number(), device.address(), device_desc.vendor_id(), device_desc.product_id(), get_speed(device.speed()));
+        println!("Bus {:03} Device {:03} ID {:04x}:{:04x} {:>9}", device.bus_number(), device.address(), device_desc.vendor_id(), device_desc.product_id(), device.speed());
         print_device(&device_desc, &mut usb_device);
 
         for n in 0..device_desc.num_configurations() {
@@ -76,7 +76,7 @@ fn list_devices() -> libusb::Result<()> {
 fn print_device(device_desc: &libusb::DeviceDescriptor, handle: &mut Option<UsbDevice>) {
     println!("Device Descriptor:");
     println!("  bcdUSB             {:2}.{}{}", device_desc.usb_version().major(), device_desc.usb_version().minor(), device_desc.usb_version().sub_minor());
-    println!("  bDeviceClass        {:#04x}", device_desc.class_code());
+    println!("  bDeviceClass        {:#04x} {}", u8::from(device_desc.class_code()), device_desc.class_code());
     println!("  bDeviceSubClass     {:#04x}", device_desc.sub_class_code());
     println!("  bDeviceProtocol     {:#04x}", device_desc.protocol_code());
     println!("  bMaxPacketSize0      {:3}", device_desc.max_packet_size());
@@ -113,7 +113,7 @@ fn print_interface(interface_desc: &libusb::InterfaceDescriptor, handle: &mut Op
     println!("      bInterfaceNumber     {:3}", interface_desc.interface_number());
     println!("      bAlternateSetting    {:3}", interface_desc.setting_number());
     println!("      bNumEndpoints        {:3}", interface_desc.num_endpoints());
-    println!("      bInterfaceClass     {:#04x}", interface_desc.class_code());
+    println!("      bInterfaceClass     {:#04x} {}", u8::from(interface_desc.class_code()), interface_desc.class_code());
     println!("      bInterfaceSubClass  {:#04x}", interface_desc.sub_class_code());
     println!("      bInterfaceProtocol  {:#04x}", interface_desc.protocol_code());
     println!("      iInterface           {:3} {}",
@@ -123,21 +123,11 @@ fn print_interface(interface_desc: &libusb::InterfaceDescriptor, handle: &mut Op
 
 fn print_endpoint(endpoint_desc: &libusb::EndpointDescriptor) {
     println!("      Endpoint Descriptor:");
-    println!("        bEndpointAddress    {:#04x} EP {} {:?}", endpoint_desc.address(), endpoint_desc.number(), endpoint_desc.direction());
+    println!("        bEndpointAddress    {:#04x} EP {} {}", endpoint_desc.address(), endpoint_desc.number(), endpoint_desc.direction());
     println!("        bmAttributes:");
-    println!("          Transfer Type          {:?}", endpoint_desc.transfer_type());
-    println!("          Synch Type             {:?}", endpoint_desc.sync_type());
-    println!("          Usage Type             {:?}", endpoint_desc.usage_type());
+    println!("          Transfer Type          {}", endpoint_desc.transfer_type());
+    println!("          Synch Type             {}", endpoint_desc.sync_type());
+    println!("          Usage Type             {}", endpoint_desc.usage_type());
     println!("        wMaxPacketSize    {:#06x}", endpoint_desc.max_packet_size());
     println!("        bInterval            {:3}", endpoint_desc.interval());
 }
-
-fn get_speed(speed: libusb::Speed) -> &'static str {
-    match speed {
-        libusb::Speed::Super   => "5000 Mbps",
-        libusb::Speed::High    => " 480 Mbps",
-        libusb::Speed::Full    => "  12 Mbps",
-        libusb::Speed::Low     => " 1.5 Mbps",
-        libusb::Speed::Unknown => "(unknown)"
-    }
-}
@@ -1,144 +1,40 @@
 extern crate libusb_async as libusb;
-extern crate futures;
 use libusb::*;
 
- use std::convert::TryInto;
-use futures::executor::block_on;
+use libusb::blocking::{block_on, block_on_stream};
 fn main()
 {
     let args: Vec<String> = std::env::args().collect();
 
     if args.len() < 3 {
-        println!("usage: read_async <vendor-id-in-hex> <product-id-in-hex>");
+        println!("usage: read_async <vendor-id-in-hex> <product-id-in-hex> [interface]");
         return;
     }
-    
+
     let vid = u16::from_str_radix(args[1].as_ref(), 16).unwrap();
     let pid = u16::from_str_radix(args[2].as_ref(), 16).unwrap();
+    let intf = args.get(3).map_or(0, |intf| intf.parse().unwrap());
 
-    match libusb::Context::new() {
-        Ok(mut context) => {
-            match open_device(&mut context, vid, pid) {
-                Some((device, _, mut handle)) => {
-                    // Find an interrupt IN endpoint
-                    let config_value = handle.active_configuration().unwrap();
-                    let config =
-                        device.config_descriptor_by_value(config_value)
-                        .expect("No config descriptor found");
-                    let ep_intf = config.interfaces().find_map(|intf| {
-                        let intf_descr = intf.descriptors().next();
-                        if let Some(intf_descr) = intf_descr {
-                            match intf_descr.endpoint_descriptors().find(|ep| {
-                                ep.transfer_type() == TransferType::Interrupt
-                                    && ep.direction() == Direction::In
-                            }) {
-                                Some(ep) => Some((intf.number(),ep.address())),
-                                None => None
-                            }
-                        } else {
-                            None
-                        }
-                    });
-
-                    /*
-                    let mut trans = handle.alloc_transfer(0).unwrap();
-                    trans.fill_control_write(
-                        request_type(Direction::Out,
-                                     RequestType::Standard,
-                                     Recipient::Device),
-                        0x09,
-                        0,
-                        0,
-                        &[]);
-
-                    
-                    let submit = trans.submit().unwrap();
-                    let res = block_on(submit);
-                    println!("Result status: {}", res.get_status());
-                     */
-                    
-                    let mut trans = handle.alloc_transfer(0).unwrap();
-                    // Get string descriptor 1
-                    trans.fill_control_read(
-                        request_type(Direction::In,
-                                     RequestType::Standard,
-                                     Recipient::Device),
-                        0x06,
-                        0x0301,
-                        0x0409,
-                        100);
-                    
-                    
-                    let submit = trans.submit();
-                    match block_on(submit){
-                        Ok(res) => {
-                            let b = res.get_buffer();
-                            if b.len() >= 12 {
-                                let name_utf16 = b[10..].chunks(2).map({
-                                    |chunk| u16::from_le_bytes(chunk.try_into().unwrap())
-                                }).collect::<Vec<u16>>();
-                                let name = String::from_utf16(&name_utf16).unwrap();
-                                println!("String 1: {}", name);
-                            } else {
-                                println!("No string descriptor");
-                            }
-                        },
-                        Err(s) => println!("Result status: {}", s)
-                    }
-
-                    if let Some((intf,ep)) = ep_intf {
-                        println!("Using interface {}, endpoint: {}", intf, ep);
-                        if handle.kernel_driver_active(intf).unwrap_or(false) {
-                            handle.detach_kernel_driver(intf).unwrap();
-                        }
-                        handle.claim_interface(intf).unwrap();
-                        loop {
-                            let mut trans = handle.alloc_transfer(0).unwrap();
-                            
-                            trans.fill_interrupt_read(ep, 8);
-                            
-                            
-                            let submit = trans.submit();
-                            
-                            match block_on(submit) {
-                                Ok(res) => {
-                                    println!("Interrupt in: {:?}", res.get_buffer());
-                                },
-                                Err(s) => {
-                                    println!("Result status: {}", s);
-                                    break
-                                }
-                            }
-                        }
-                    }
-                    
-                },
-                None => println!("could not find device {:04x}:{:04x}", vid, pid)
-            }
-        },
-        Err(e) => panic!("could not initialize libusb: {}", e)
+    if let Err(e) = read_interrupts(vid, pid, intf) {
+        println!("could not read device {:04x}:{:04x}: {}", vid, pid, e);
     }
 }
 
-fn open_device(context: &mut libusb::Context, vid: u16, pid: u16) -> Option<(libusb::Device, libusb::DeviceDescriptor, libusb::DeviceHandle)> {
-    let devices = match context.devices() {
-        Ok(d) => d,
-        Err(_) => return None
-    };
-
-    for device in devices.iter() {
-        let device_desc = match device.device_descriptor() {
-            Ok(d) => d,
-            Err(_) => continue
-        };
-
-        if device_desc.vendor_id() == vid && device_desc.product_id() == pid {
-            match device.open() {
-                Ok(handle) => return Some((device, device_desc, handle)),
-                Err(_) => continue
-            }
-        }
+fn read_interrupts(vid: u16, pid: u16, intf: u8) -> libusb::Result<()>
+{
+    let context = libusb::Context::new()?;
+    let device = context.find(&DeviceFilter::new().product(vid, pid))?;
+    let device_desc = device.device_descriptor()?;
+    let claimed = device.open()?.claim(intf)?;
+
+    match block_on(device_desc.product_string(claimed.handle())) {
+        Ok(name) => println!("Product: {}", name),
+        Err(e) => println!("No product string: {}", e)
     }
 
-    None
+    println!("Using interface {}", intf);
+    for data in block_on_stream(claimed.interrupt_in()?) {
+        println!("Interrupt in: {:?}", data?);
+    }
+    Ok(())
 }
@@ -135,7 +135,7 @@ fn read_endpoint(handle: &mut libusb::DeviceHandle, endpoint: Endpoint, transfer
 
             match transfer_type {
                 libusb::TransferType::Interrupt => {
-                    match handle.read_interrupt(endpoint.address, buf, timeout) {
+                    match handle.read_interrupt(endpoint.address.into(), buf, timeout) {
                         Ok(len) => {
                             unsafe { vec.set_len(len) };
                             println!(" - read: {:?}", vec);
@@ -144,7 +144,7 @@ fn read_endpoint(handle: &mut libusb::DeviceHandle, endpoint: Endpoint, transfer
                     }
                 },
                 libusb::TransferType::Bulk => {
-                    match handle.read_bulk(endpoint.address, buf, timeout) {
+                    match handle.read_bulk(endpoint.address.into(), buf, timeout) {
                         Ok(len) => {
                             unsafe { vec.set_len(len) };
                             println!(" - read: {:?}", vec);
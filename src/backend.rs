@@ -0,0 +1,889 @@
+//! The USB stack underneath a context, `libusb` unless another is given with
+//! `Context::with_backend`, e.g., a replay of a capture or a mock in tests.
+//!
+//! A backend identifies its contexts, devices, handles and transfers by the pointer-sized
+//! references of this module, e.g., `libusb` by the pointers to its structures, and reports
+//! errors with the error codes of `libusb`, e.g., `LIBUSB_ERROR_NOT_SUPPORTED` (-12).
+
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::AtomicI32;
+use std::time::Duration;
+
+use libc::{c_int, c_uchar, c_uint, c_void, timeval};
+use libusb::*;
+
+use config_descriptor::{self, ConfigDescriptor};
+use device_descriptor::{self, DeviceDescriptor};
+use fields::{self, Speed, TransferType};
+use transfer::TransferStatus;
+
+// Came with libusb 1.0.19 and 1.0.21, after the libusb-sys bindings
+extern "C" {
+    fn libusb_alloc_streams(dev_handle: *mut libusb_device_handle, num_streams: u32,
+                            endpoints: *mut c_uchar, num_endpoints: c_int) -> c_int;
+    fn libusb_free_streams(dev_handle: *mut libusb_device_handle,
+                           endpoints: *mut c_uchar, num_endpoints: c_int) -> c_int;
+    fn libusb_interrupt_event_handler(ctx: *mut libusb_context);
+}
+
+macro_rules! backend_ref {
+    ($(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        #[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+        pub struct $name(*mut c_void);
+
+        impl $name {
+            /// Wraps what the backend identifies it by.
+            pub fn from_ptr(ptr: *mut c_void) -> $name {
+                $name(ptr)
+            }
+
+            /// Returns what the backend identifies it by.
+            pub fn as_ptr(self) -> *mut c_void {
+                self.0
+            }
+        }
+
+        // Only the backend dereferences it
+        unsafe impl Send for $name {}
+        unsafe impl Sync for $name {}
+    }
+}
+
+backend_ref! {
+    /// A context of a backend, null for a context that isn't a `libusb` one.
+    ContextRef
+}
+
+backend_ref! {
+    /// A device of a backend, which counts the references to it.
+    DeviceRef
+}
+
+backend_ref! {
+    /// An open device of a backend.
+    HandleRef
+}
+
+backend_ref! {
+    /// A hotplug registration of a backend.
+    HotplugRef
+}
+
+/// What happened to a device, as reported to a hotplug callback.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum DeviceEvent {
+    /// The device was connected, or was already connected when the callback was registered.
+    Arrived,
+
+    /// The device was disconnected.
+    Left,
+}
+
+/// The fields of the setup packet of a control transfer, in host byte order.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct ControlSetup {
+    /// The `bmRequestType` field.
+    pub request_type: u8,
+
+    /// The `bRequest` field.
+    pub request: u8,
+
+    /// The `wValue` field.
+    pub value: u16,
+
+    /// The `wIndex` field.
+    pub index: u16,
+}
+
+/// Called by a backend for each device that arrives or leaves, with a reference to the device
+/// that it keeps.
+pub type HotplugCallback = Box<dyn Fn(DeviceRef, DeviceEvent) + Send + Sync>;
+
+/// A transfer submitted to a backend, laid out as a `libusb_transfer`.
+///
+/// It stays valid until the backend completes it with
+/// [`complete`](#method.complete), which is why its accessors are unsafe.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub struct TransferRef(*mut libusb_transfer);
+
+unsafe impl Send for TransferRef {}
+unsafe impl Sync for TransferRef {}
+
+impl TransferRef {
+    pub fn from_libusb(transfer: *mut libusb_transfer) -> TransferRef {
+        TransferRef(transfer)
+    }
+
+    /// Returns the address of the transfer, which identifies it until it completes.
+    pub fn as_ptr(self) -> *mut c_void {
+        self.0 as *mut c_void
+    }
+
+    /// Returns the handle of the device the transfer is for.
+    ///
+    /// # Safety
+    ///
+    /// The transfer must be submitted, and not completed yet.
+    pub unsafe fn handle(self) -> HandleRef {
+        HandleRef((*self.0).dev_handle as *mut c_void)
+    }
+
+    /// Returns the address of the endpoint, 0 for the default control endpoint.
+    ///
+    /// # Safety
+    ///
+    /// The transfer must be submitted, and not completed yet.
+    pub unsafe fn endpoint(self) -> u8 {
+        (*self.0).endpoint
+    }
+
+    /// Returns the type of the transfer.
+    ///
+    /// # Safety
+    ///
+    /// The transfer must be submitted, and not completed yet.
+    pub unsafe fn transfer_type(self) -> TransferType {
+        match (*self.0).transfer_type {
+            LIBUSB_TRANSFER_TYPE_CONTROL => TransferType::Control,
+            LIBUSB_TRANSFER_TYPE_ISOCHRONOUS => TransferType::Isochronous,
+            LIBUSB_TRANSFER_TYPE_INTERRUPT => TransferType::Interrupt,
+            _ => TransferType::Bulk,
+        }
+    }
+
+    /// Returns how long the transfer may take before it times out, or `None` if it may take
+    /// forever.
+    ///
+    /// # Safety
+    ///
+    /// The transfer must be submitted, and not completed yet.
+    pub unsafe fn timeout(self) -> Option<Duration> {
+        match (*self.0).timeout {
+            0 => None,
+            timeout => Some(Duration::from_millis(timeout as u64)),
+        }
+    }
+
+    /// Returns the buffer of the transfer, which starts with the setup packet for a control
+    /// transfer, and holds the packets one after the other for an isochronous one.
+    ///
+    /// # Safety
+    ///
+    /// The transfer must be submitted, and not completed yet. The buffer can't be used after it
+    /// completes.
+    pub unsafe fn buffer<'a>(self) -> &'a mut [u8] {
+        let transfer = &*self.0;
+        if transfer.buffer.is_null() || transfer.length <= 0 {
+            return &mut [];
+        }
+        slice::from_raw_parts_mut(transfer.buffer, transfer.length as usize)
+    }
+
+    /// Returns the lengths of the packets of an isochronous transfer.
+    ///
+    /// # Safety
+    ///
+    /// The transfer must be submitted, and not completed yet.
+    pub unsafe fn iso_packet_lengths(self) -> Vec<usize> {
+        iso_packets(self.0).iter().map(|packet| packet.length as usize).collect()
+    }
+
+    /// Sets the status and the length of the data actually transferred of a packet of an
+    /// isochronous transfer, before the transfer is completed.
+    ///
+    /// # Safety
+    ///
+    /// The transfer must be submitted, and not completed yet. `index` must be less than its
+    /// number of packets.
+    pub unsafe fn set_iso_packet_result(self, index: usize, status: TransferStatus, actual_length: usize) {
+        let packet = &mut iso_packets(self.0)[index];
+        packet.status = status_code(status);
+        packet.actual_length = actual_length as c_uint;
+    }
+
+    /// Completes the transfer, with the length of the data actually transferred after the
+    /// setup packet of a control transfer, and runs its callback.
+    ///
+    /// The transfer is no longer the backend's after this, as the callback may free or submit
+    /// it again.
+    ///
+    /// # Safety
+    ///
+    /// The transfer must be submitted, and not completed yet.
+    pub unsafe fn complete(self, status: TransferStatus, actual_length: usize) {
+        let transfer = &mut *self.0;
+        transfer.status = status_code(status);
+        transfer.actual_length = actual_length as c_int;
+        (transfer.callback)(self.0);
+    }
+}
+
+unsafe fn iso_packets<'a>(transfer: *mut libusb_transfer) -> &'a mut [libusb_iso_packet_descriptor] {
+    let count = (*transfer).num_iso_packets.max(0) as usize;
+    slice::from_raw_parts_mut((*transfer).iso_packet_desc.as_mut_ptr(), count)
+}
+
+fn status_code(status: TransferStatus) -> c_int {
+    match status {
+        TransferStatus::Completed => LIBUSB_TRANSFER_COMPLETED,
+        TransferStatus::TimedOut => LIBUSB_TRANSFER_TIMED_OUT,
+        TransferStatus::Cancelled => LIBUSB_TRANSFER_CANCELLED,
+        TransferStatus::Stall => LIBUSB_TRANSFER_STALL,
+        TransferStatus::NoDevice => LIBUSB_TRANSFER_NO_DEVICE,
+        TransferStatus::Overflow => LIBUSB_TRANSFER_OVERFLOW,
+        TransferStatus::Error | TransferStatus::Unknown => LIBUSB_TRANSFER_ERROR,
+    }
+}
+
+/// The operations the crate needs from the USB stack underneath it: enumerating devices and
+/// reading their descriptors, opening them and managing their interfaces, transferring data,
+/// handling the events that complete the transfers, and reporting hotplug events.
+///
+/// Each context holds a backend, and its devices, handles and transfers go through it. Only
+/// creating a `libusb` context, its options, its file descriptors for the event drivers of
+/// [`runtime`](../runtime/index.html) and `Context::wrap_sys_device` are left to `libusb`.
+///
+/// The operations return `libusb` error codes. Those a backend doesn't provide default to
+/// failing with `LIBUSB_ERROR_NOT_SUPPORTED`, or to doing nothing where they can't fail, so,
+/// e.g., a mock for tests only has to submit, cancel and complete transfers. The operations
+/// are unsafe as they take the references the backend handed out, which the crate only uses
+/// while they are valid.
+pub trait UsbBackend: Send + Sync {
+    /// Lists the devices, each with a reference that the crate releases with
+    /// [`unref_device`](#method.unref_device).
+    ///
+    /// # Safety
+    ///
+    /// `context` must be the context the backend was given.
+    unsafe fn get_device_list(&self, _context: ContextRef) -> Result<Vec<DeviceRef>, c_int> {
+        Err(LIBUSB_ERROR_NOT_SUPPORTED)
+    }
+
+    /// Adds a reference to a device.
+    ///
+    /// # Safety
+    ///
+    /// `device` must be a device of the backend that is still referenced.
+    unsafe fn ref_device(&self, _device: DeviceRef) {}
+
+    /// Releases a reference to a device.
+    ///
+    /// # Safety
+    ///
+    /// `device` must be a device of the backend that is still referenced. The reference
+    /// released must be one the caller holds.
+    unsafe fn unref_device(&self, _device: DeviceRef) {}
+
+    /// Returns the device descriptor of a device.
+    ///
+    /// # Safety
+    ///
+    /// `device` must be a device of the backend that is still referenced.
+    unsafe fn device_descriptor(&self, _device: DeviceRef) -> Result<DeviceDescriptor, c_int> {
+        Err(LIBUSB_ERROR_NOT_SUPPORTED)
+    }
+
+    /// Returns a configuration descriptor of a device, by its index.
+    ///
+    /// # Safety
+    ///
+    /// `device` must be a device of the backend that is still referenced.
+    unsafe fn config_descriptor(&self, _device: DeviceRef, _index: u8) -> Result<ConfigDescriptor, c_int> {
+        Err(LIBUSB_ERROR_NOT_SUPPORTED)
+    }
+
+    /// Returns a configuration descriptor of a device, by its configuration value.
+    ///
+    /// # Safety
+    ///
+    /// `device` must be a device of the backend that is still referenced.
+    unsafe fn config_descriptor_by_value(&self, _device: DeviceRef, _value: u8) -> Result<ConfigDescriptor, c_int> {
+        Err(LIBUSB_ERROR_NOT_SUPPORTED)
+    }
+
+    /// Returns the descriptor of the active configuration of a device.
+    ///
+    /// # Safety
+    ///
+    /// `device` must be a device of the backend that is still referenced.
+    unsafe fn active_config_descriptor(&self, _device: DeviceRef) -> Result<ConfigDescriptor, c_int> {
+        Err(LIBUSB_ERROR_NOT_SUPPORTED)
+    }
+
+    /// Returns the number of the bus a device is connected to.
+    ///
+    /// # Safety
+    ///
+    /// `device` must be a device of the backend that is still referenced.
+    unsafe fn bus_number(&self, _device: DeviceRef) -> u8 {
+        0
+    }
+
+    /// Returns the number of the port of the parent hub a device is connected to, or 0.
+    ///
+    /// # Safety
+    ///
+    /// `device` must be a device of the backend that is still referenced.
+    unsafe fn port_number(&self, _device: DeviceRef) -> u8 {
+        0
+    }
+
+    /// Returns the address of a device on its bus.
+    ///
+    /// # Safety
+    ///
+    /// `device` must be a device of the backend that is still referenced.
+    unsafe fn device_address(&self, _device: DeviceRef) -> u8 {
+        0
+    }
+
+    /// Returns the connection speed of a device.
+    ///
+    /// # Safety
+    ///
+    /// `device` must be a device of the backend that is still referenced.
+    unsafe fn device_speed(&self, _device: DeviceRef) -> Speed {
+        Speed::Unknown
+    }
+
+    /// Opens a device.
+    ///
+    /// # Safety
+    ///
+    /// `device` must be a device of the backend that is still referenced.
+    unsafe fn open(&self, _device: DeviceRef) -> Result<HandleRef, c_int> {
+        Err(LIBUSB_ERROR_NOT_SUPPORTED)
+    }
+
+    /// Closes a handle, once its transfers are done.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be a handle of the backend that isn't closed. It can't be used after this.
+    unsafe fn close(&self, _handle: HandleRef) {}
+
+    /// Returns the device of a handle, without adding a reference to it.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be a handle of the backend that isn't closed.
+    unsafe fn get_device(&self, _handle: HandleRef) -> DeviceRef {
+        DeviceRef(ptr::null_mut())
+    }
+
+    /// Returns the active configuration value of a device, 0 if it's unconfigured.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be a handle of the backend that isn't closed.
+    unsafe fn get_configuration(&self, _handle: HandleRef) -> Result<u8, c_int> {
+        Err(LIBUSB_ERROR_NOT_SUPPORTED)
+    }
+
+    /// Sets the active configuration of a device, or unconfigures it with `None`.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be a handle of the backend that isn't closed.
+    unsafe fn set_configuration(&self, _handle: HandleRef, _config: Option<u8>) -> c_int {
+        LIBUSB_ERROR_NOT_SUPPORTED
+    }
+
+    /// Claims an interface.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be a handle of the backend that isn't closed.
+    unsafe fn claim_interface(&self, _handle: HandleRef, _iface: u8) -> c_int {
+        LIBUSB_ERROR_NOT_SUPPORTED
+    }
+
+    /// Releases a claimed interface.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be a handle of the backend that isn't closed.
+    unsafe fn release_interface(&self, _handle: HandleRef, _iface: u8) -> c_int {
+        LIBUSB_ERROR_NOT_SUPPORTED
+    }
+
+    /// Sets the alternate setting of a claimed interface.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be a handle of the backend that isn't closed.
+    unsafe fn set_alternate_setting(&self, _handle: HandleRef, _iface: u8, _setting: u8) -> c_int {
+        LIBUSB_ERROR_NOT_SUPPORTED
+    }
+
+    /// Resets a device.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be a handle of the backend that isn't closed.
+    unsafe fn reset_device(&self, _handle: HandleRef) -> c_int {
+        LIBUSB_ERROR_NOT_SUPPORTED
+    }
+
+    /// Clears the halt condition of an endpoint.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be a handle of the backend that isn't closed.
+    unsafe fn clear_halt(&self, _handle: HandleRef, _endpoint: u8) -> c_int {
+        LIBUSB_ERROR_NOT_SUPPORTED
+    }
+
+    /// Tells whether a kernel driver is bound to an interface.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be a handle of the backend that isn't closed.
+    unsafe fn kernel_driver_active(&self, _handle: HandleRef, _iface: u8) -> Result<bool, c_int> {
+        Err(LIBUSB_ERROR_NOT_SUPPORTED)
+    }
+
+    /// Detaches the kernel driver of an interface.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be a handle of the backend that isn't closed.
+    unsafe fn detach_kernel_driver(&self, _handle: HandleRef, _iface: u8) -> c_int {
+        LIBUSB_ERROR_NOT_SUPPORTED
+    }
+
+    /// Attaches the kernel driver of an interface again.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be a handle of the backend that isn't closed.
+    unsafe fn attach_kernel_driver(&self, _handle: HandleRef, _iface: u8) -> c_int {
+        LIBUSB_ERROR_NOT_SUPPORTED
+    }
+
+    /// Allocates bulk streams on endpoints, and returns how many were allocated.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be a handle of the backend that isn't closed.
+    unsafe fn alloc_streams(&self, _handle: HandleRef, _num_streams: u32, _endpoints: &[u8]) -> Result<u32, c_int> {
+        Err(LIBUSB_ERROR_NOT_SUPPORTED)
+    }
+
+    /// Frees the bulk streams of endpoints.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be a handle of the backend that isn't closed.
+    unsafe fn free_streams(&self, _handle: HandleRef, _endpoints: &[u8]) -> c_int {
+        LIBUSB_ERROR_NOT_SUPPORTED
+    }
+
+    /// Runs a control transfer to completion, with `length` bytes of `data` after the setup
+    /// packet, and returns the number of bytes transferred, like `libusb_control_transfer`.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be a handle of the backend that isn't closed. `data` must point to
+    /// `length` bytes, which are only written for a read.
+    unsafe fn control_transfer(&self, _handle: HandleRef, _setup: ControlSetup, _data: *mut u8, _length: u16,
+                               _timeout: Duration) -> Result<usize, c_int> {
+        Err(LIBUSB_ERROR_NOT_SUPPORTED)
+    }
+
+    /// Runs a bulk transfer to completion, like `libusb_bulk_transfer`, setting `transferred`
+    /// even if it fails.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be a handle of the backend that isn't closed. `data` must point to
+    /// `length` bytes, which are only written for a read.
+    unsafe fn bulk_transfer(&self, _handle: HandleRef, _endpoint: u8, _data: *mut u8, _length: usize,
+                            _transferred: &mut usize, _timeout: Duration) -> c_int {
+        LIBUSB_ERROR_NOT_SUPPORTED
+    }
+
+    /// Runs an interrupt transfer to completion, like
+    /// [`bulk_transfer`](#method.bulk_transfer).
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be a handle of the backend that isn't closed. `data` must point to
+    /// `length` bytes, which are only written for a read.
+    unsafe fn interrupt_transfer(&self, _handle: HandleRef, _endpoint: u8, _data: *mut u8, _length: usize,
+                                 _transferred: &mut usize, _timeout: Duration) -> c_int {
+        LIBUSB_ERROR_NOT_SUPPORTED
+    }
+
+    /// Submits a transfer, to be completed with
+    /// [`TransferRef::complete`](struct.TransferRef.html#method.complete) while the events are
+    /// handled.
+    ///
+    /// # Safety
+    ///
+    /// `transfer` must be filled for a handle of the backend, and stay allocated until it is
+    /// completed.
+    unsafe fn submit_transfer(&self, transfer: TransferRef) -> c_int;
+
+    /// Asks for a submitted transfer to be completed as cancelled.
+    ///
+    /// # Safety
+    ///
+    /// `transfer` must still be allocated. It may have completed already.
+    unsafe fn cancel_transfer(&self, transfer: TransferRef) -> c_int;
+
+    /// Handles the events that arrive within `timeout`, or until `completed` is set, running
+    /// the callbacks of the transfers and hotplug registrations they complete.
+    ///
+    /// # Safety
+    ///
+    /// `context` must be the context the backend was given.
+    unsafe fn handle_events(&self, context: ContextRef, timeout: Duration, completed: Option<&AtomicI32>) -> c_int;
+
+    /// Makes a thread handling events return, e.g., to see that it should stop.
+    ///
+    /// # Safety
+    ///
+    /// `context` must be the context the backend was given.
+    unsafe fn interrupt_event_handler(&self, _context: ContextRef) {}
+
+    /// Tells whether the backend reports hotplug events.
+    fn has_hotplug(&self) -> bool {
+        false
+    }
+
+    /// Registers a callback for the devices that arrive and leave, which is first called for
+    /// those already connected.
+    ///
+    /// # Safety
+    ///
+    /// `context` must be the context the backend was given.
+    unsafe fn register_hotplug(&self, _context: ContextRef, _callback: HotplugCallback) -> Result<HotplugRef, c_int> {
+        Err(LIBUSB_ERROR_NOT_SUPPORTED)
+    }
+
+    /// Deregisters a hotplug callback, which isn't called after this returns.
+    ///
+    /// # Safety
+    ///
+    /// `context` must be the context the backend was given. `hotplug` must be registered with
+    /// it, and can't be used after this.
+    unsafe fn deregister_hotplug(&self, _context: ContextRef, _hotplug: HotplugRef) {}
+}
+
+/// The `libusb` library.
+pub struct Libusb;
+
+/// The backend of contexts opened by `Context::new`, to which another backend can hand the
+/// operations it doesn't provide itself.
+pub static LIBUSB: Libusb = Libusb;
+
+fn device(device: DeviceRef) -> *mut libusb_device {
+    device.0 as *mut libusb_device
+}
+
+fn handle(handle: HandleRef) -> *mut libusb_device_handle {
+    handle.0 as *mut libusb_device_handle
+}
+
+fn timeout_ms(timeout: Duration) -> c_uint {
+    (timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1_000_000) as c_uint
+}
+
+/// Whether kernel drivers can be queried and detached, which Android doesn't allow.
+fn supports_kernel_drivers() -> bool {
+    !cfg!(target_os = "android") &&
+        unsafe { libusb_has_capability(LIBUSB_CAP_SUPPORTS_DETACH_KERNEL_DRIVER) != 0 }
+}
+
+/// Reads a configuration descriptor allocated by `libusb`.
+unsafe fn read_config<F>(read: F) -> Result<ConfigDescriptor, c_int>
+    where F: FnOnce(*mut *const libusb_config_descriptor) -> c_int
+{
+    let mut config = MaybeUninit::<*const libusb_config_descriptor>::uninit();
+    match read(config.as_mut_ptr()) {
+        0 => Ok(config_descriptor::from_libusb(config.assume_init())),
+        err => Err(err),
+    }
+}
+
+/// The hotplug callback registered with `libusb`, with the callback it runs.
+struct LibusbHotplug {
+    handle: libusb_hotplug_callback_handle,
+    callback: HotplugCallback,
+}
+
+extern "C" fn libusb_hotplug(_ctx: *mut libusb_context, device: *mut libusb_device,
+                             event: c_int, user_data: *mut c_void) -> c_int {
+    let hotplug = unsafe { &*(user_data as *const LibusbHotplug) };
+    let event = match event {
+        LIBUSB_HOTPLUG_EVENT_DEVICE_ARRIVED => DeviceEvent::Arrived,
+        LIBUSB_HOTPLUG_EVENT_DEVICE_LEFT => DeviceEvent::Left,
+        _ => return 0,
+    };
+    (hotplug.callback)(DeviceRef(device as *mut c_void), event);
+
+    // Stays registered until it is deregistered
+    0
+}
+
+impl UsbBackend for Libusb {
+    unsafe fn get_device_list(&self, context: ContextRef) -> Result<Vec<DeviceRef>, c_int> {
+        let mut list = MaybeUninit::<*const *mut libusb_device>::uninit();
+        let n = libusb_get_device_list(context.0 as *mut libusb_context, list.as_mut_ptr());
+        if n < 0 {
+            return Err(n as c_int);
+        }
+        let list = list.assume_init();
+
+        // The devices keep the references of the list
+        let devices = slice::from_raw_parts(list, n as usize).iter()
+            .map(|&device| DeviceRef(device as *mut c_void))
+            .collect();
+        libusb_free_device_list(list, 0);
+        Ok(devices)
+    }
+
+    unsafe fn ref_device(&self, device: DeviceRef) {
+        libusb_ref_device(self::device(device));
+    }
+
+    unsafe fn unref_device(&self, device: DeviceRef) {
+        libusb_unref_device(self::device(device));
+    }
+
+    unsafe fn device_descriptor(&self, device: DeviceRef) -> Result<DeviceDescriptor, c_int> {
+        let mut descriptor = MaybeUninit::<libusb_device_descriptor>::uninit();
+
+        // since libusb 1.0.16, this function always succeeds
+        match libusb_get_device_descriptor(self::device(device), descriptor.as_mut_ptr()) {
+            0 => Ok(device_descriptor::from_libusb(descriptor.assume_init())),
+            err => Err(err),
+        }
+    }
+
+    unsafe fn config_descriptor(&self, device: DeviceRef, index: u8) -> Result<ConfigDescriptor, c_int> {
+        read_config(|config| libusb_get_config_descriptor(self::device(device), index, config))
+    }
+
+    unsafe fn config_descriptor_by_value(&self, device: DeviceRef, value: u8) -> Result<ConfigDescriptor, c_int> {
+        read_config(|config| libusb_get_config_descriptor_by_value(self::device(device), value, config))
+    }
+
+    unsafe fn active_config_descriptor(&self, device: DeviceRef) -> Result<ConfigDescriptor, c_int> {
+        read_config(|config| libusb_get_active_config_descriptor(self::device(device), config))
+    }
+
+    unsafe fn bus_number(&self, device: DeviceRef) -> u8 {
+        libusb_get_bus_number(self::device(device))
+    }
+
+    unsafe fn port_number(&self, device: DeviceRef) -> u8 {
+        libusb_get_port_number(self::device(device))
+    }
+
+    unsafe fn device_address(&self, device: DeviceRef) -> u8 {
+        libusb_get_device_address(self::device(device))
+    }
+
+    unsafe fn device_speed(&self, device: DeviceRef) -> Speed {
+        fields::speed_from_libusb(libusb_get_device_speed(self::device(device)))
+    }
+
+    unsafe fn open(&self, device: DeviceRef) -> Result<HandleRef, c_int> {
+        let mut handle = MaybeUninit::<*mut libusb_device_handle>::uninit();
+        match libusb_open(self::device(device), handle.as_mut_ptr()) {
+            0 => Ok(HandleRef(handle.assume_init() as *mut c_void)),
+            err => Err(err),
+        }
+    }
+
+    unsafe fn close(&self, handle: HandleRef) {
+        libusb_close(self::handle(handle));
+    }
+
+    unsafe fn get_device(&self, handle: HandleRef) -> DeviceRef {
+        DeviceRef(libusb_get_device(self::handle(handle)) as *mut c_void)
+    }
+
+    unsafe fn get_configuration(&self, handle: HandleRef) -> Result<u8, c_int> {
+        let mut config = MaybeUninit::<c_int>::uninit();
+        match libusb_get_configuration(self::handle(handle), config.as_mut_ptr()) {
+            0 => Ok(config.assume_init() as u8),
+            err => Err(err),
+        }
+    }
+
+    unsafe fn set_configuration(&self, handle: HandleRef, config: Option<u8>) -> c_int {
+        libusb_set_configuration(self::handle(handle), config.map_or(-1, c_int::from))
+    }
+
+    unsafe fn claim_interface(&self, handle: HandleRef, iface: u8) -> c_int {
+        libusb_claim_interface(self::handle(handle), iface as c_int)
+    }
+
+    unsafe fn release_interface(&self, handle: HandleRef, iface: u8) -> c_int {
+        libusb_release_interface(self::handle(handle), iface as c_int)
+    }
+
+    unsafe fn set_alternate_setting(&self, handle: HandleRef, iface: u8, setting: u8) -> c_int {
+        libusb_set_interface_alt_setting(self::handle(handle), iface as c_int, setting as c_int)
+    }
+
+    unsafe fn reset_device(&self, handle: HandleRef) -> c_int {
+        libusb_reset_device(self::handle(handle))
+    }
+
+    unsafe fn clear_halt(&self, handle: HandleRef, endpoint: u8) -> c_int {
+        libusb_clear_halt(self::handle(handle), endpoint)
+    }
+
+    unsafe fn kernel_driver_active(&self, handle: HandleRef, iface: u8) -> Result<bool, c_int> {
+        if !supports_kernel_drivers() {
+            return Err(LIBUSB_ERROR_NOT_SUPPORTED);
+        }
+        match libusb_kernel_driver_active(self::handle(handle), iface as c_int) {
+            0 => Ok(false),
+            1 => Ok(true),
+            err => Err(err),
+        }
+    }
+
+    unsafe fn detach_kernel_driver(&self, handle: HandleRef, iface: u8) -> c_int {
+        if !supports_kernel_drivers() {
+            return LIBUSB_ERROR_NOT_SUPPORTED;
+        }
+        libusb_detach_kernel_driver(self::handle(handle), iface as c_int)
+    }
+
+    unsafe fn attach_kernel_driver(&self, handle: HandleRef, iface: u8) -> c_int {
+        if !supports_kernel_drivers() {
+            return LIBUSB_ERROR_NOT_SUPPORTED;
+        }
+        libusb_attach_kernel_driver(self::handle(handle), iface as c_int)
+    }
+
+    unsafe fn alloc_streams(&self, handle: HandleRef, num_streams: u32, endpoints: &[u8]) -> Result<u32, c_int> {
+        let mut endpoints = endpoints.to_vec();
+        match libusb_alloc_streams(self::handle(handle), num_streams, endpoints.as_mut_ptr(), endpoints.len() as c_int) {
+            n if n < 0 => Err(n),
+            n => Ok(n as u32),
+        }
+    }
+
+    unsafe fn free_streams(&self, handle: HandleRef, endpoints: &[u8]) -> c_int {
+        let mut endpoints = endpoints.to_vec();
+        libusb_free_streams(self::handle(handle), endpoints.as_mut_ptr(), endpoints.len() as c_int)
+    }
+
+    unsafe fn control_transfer(&self, handle: HandleRef, setup: ControlSetup, data: *mut u8, length: u16,
+                               timeout: Duration) -> Result<usize, c_int> {
+        match libusb_control_transfer(self::handle(handle), setup.request_type, setup.request, setup.value,
+                                      setup.index, data, length, timeout_ms(timeout)) {
+            n if n < 0 => Err(n),
+            n => Ok(n as usize),
+        }
+    }
+
+    unsafe fn bulk_transfer(&self, handle: HandleRef, endpoint: u8, data: *mut u8, length: usize,
+                            transferred: &mut usize, timeout: Duration) -> c_int {
+        let mut actual_length: c_int = 0;
+        let result = libusb_bulk_transfer(self::handle(handle), endpoint, data, length as c_int,
+                                          &mut actual_length, timeout_ms(timeout));
+        *transferred = actual_length.max(0) as usize;
+        result
+    }
+
+    unsafe fn interrupt_transfer(&self, handle: HandleRef, endpoint: u8, data: *mut u8, length: usize,
+                                 transferred: &mut usize, timeout: Duration) -> c_int {
+        let mut actual_length: c_int = 0;
+        let result = libusb_interrupt_transfer(self::handle(handle), endpoint, data, length as c_int,
+                                               &mut actual_length, timeout_ms(timeout));
+        *transferred = actual_length.max(0) as usize;
+        result
+    }
+
+    unsafe fn submit_transfer(&self, transfer: TransferRef) -> c_int {
+        libusb_submit_transfer(transfer.0)
+    }
+
+    unsafe fn cancel_transfer(&self, transfer: TransferRef) -> c_int {
+        libusb_cancel_transfer(transfer.0)
+    }
+
+    unsafe fn handle_events(&self, context: ContextRef, timeout: Duration, completed: Option<&AtomicI32>) -> c_int {
+        let timeout = timeval {
+            tv_sec: timeout.as_secs() as _,
+            tv_usec: timeout.subsec_micros() as _,
+        };
+        // libusb reads the flag as a C int
+        let completed = completed.map_or(ptr::null_mut(), |completed| completed.as_ptr() as *mut c_int);
+        libusb_handle_events_timeout_completed(context.0 as *mut libusb_context, &timeout, completed)
+    }
+
+    unsafe fn interrupt_event_handler(&self, context: ContextRef) {
+        libusb_interrupt_event_handler(context.0 as *mut libusb_context);
+    }
+
+    fn has_hotplug(&self) -> bool {
+        unsafe { libusb_has_capability(LIBUSB_CAP_HAS_HOTPLUG) != 0 }
+    }
+
+    unsafe fn register_hotplug(&self, context: ContextRef, callback: HotplugCallback) -> Result<HotplugRef, c_int> {
+        let hotplug = Box::into_raw(Box::new(LibusbHotplug { handle: 0, callback }));
+        let mut handle = MaybeUninit::<libusb_hotplug_callback_handle>::uninit();
+
+        // The devices already connected are reported before this returns
+        let result = libusb_hotplug_register_callback(context.0 as *mut libusb_context,
+                                                      LIBUSB_HOTPLUG_EVENT_DEVICE_ARRIVED | LIBUSB_HOTPLUG_EVENT_DEVICE_LEFT,
+                                                      LIBUSB_HOTPLUG_ENUMERATE,
+                                                      LIBUSB_HOTPLUG_MATCH_ANY, LIBUSB_HOTPLUG_MATCH_ANY,
+                                                      LIBUSB_HOTPLUG_MATCH_ANY,
+                                                      libusb_hotplug,
+                                                      hotplug as *mut c_void,
+                                                      handle.as_mut_ptr());
+        if result != 0 {
+            drop(Box::from_raw(hotplug));
+            return Err(result);
+        }
+        (*hotplug).handle = handle.assume_init();
+        Ok(HotplugRef(hotplug as *mut c_void))
+    }
+
+    unsafe fn deregister_hotplug(&self, context: ContextRef, hotplug: HotplugRef) {
+        let hotplug = Box::from_raw(hotplug.0 as *mut LibusbHotplug);
+        libusb_hotplug_deregister_callback(context.0 as *mut libusb_context, hotplug.handle);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use context::Context;
+    use test_helpers::{config_bytes, device_bytes, Call, MockBackend};
+
+    #[test]
+    fn it_routes_devices_and_handles_through_the_backend() {
+        let mut backend = MockBackend::new(vec![(device_bytes(0x1234, 0x5678), config_bytes(&[0xFF]))]);
+        backend.kernel_drivers = vec![0];
+        let backend = backend.leak();
+        let context = Context::with_backend(backend);
+
+        let device = context.devices().unwrap().into_iter().next().unwrap();
+        assert_eq!(0x5678, device.device_descriptor().unwrap().product_id());
+        let mut handle = device.open().unwrap();
+        assert!(handle.kernel_driver_active(0).unwrap());
+        handle.detach_kernel_driver(0).unwrap();
+        handle.claim_interface(0).unwrap();
+        drop(handle);
+        drop(device);
+
+        assert_eq!(vec![Call::Open, Call::Detach(0), Call::Claim(0), Call::Release(0), Call::Close], backend.calls());
+        assert_eq!(0, backend.references());
+        assert!(context.open_device_with_vid_pid(0x1234, 0x5678).is_some());
+        assert!(context.open_device_with_vid_pid(0x1234, 0x0001).is_none());
+    }
+}
@@ -0,0 +1,145 @@
+//! Blocking use of the asynchronous API, without an async runtime.
+//!
+//! [`block_on`](fn.block_on.html) runs a future on the calling thread until it completes, and
+//! [`block_on_stream`](fn.block_on_stream.html) turns a stream into an iterator. Together with
+//! the synchronous methods of [`DeviceHandle`](../struct.DeviceHandle.html), e.g.,
+//! `read_bulk` and `read_string_descriptor`, they give the same surface as a synchronous USB
+//! library, so code can move to the asynchronous API one call at a time:
+//!
+//! ```no_run
+//! # fn main() -> libusb_async::Result<()> {
+//! use libusb_async::{Context, DeviceFilter};
+//! use libusb_async::blocking::{block_on, block_on_stream};
+//!
+//! let context = Context::new()?;
+//! let device = context.find(&DeviceFilter::new().product(0x046D, 0xC52B))?;
+//! let interface = device.open()?.claim(0)?;
+//!
+//! println!("{}", block_on(device.device_descriptor()?.product_string(interface.handle()))?);
+//! for data in block_on_stream(interface.interrupt_in()?).take(10) {
+//!     println!("{:?}", data?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Transfers complete on the event thread, so without the `event-thread` feature another
+//! thread has to call [`Context::handle_events`](../struct.Context.html#method.handle_events)
+//! while the calling thread blocks.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{self, Wake};
+use std::thread::{self, Thread};
+
+use futures_core::Stream;
+
+/// Wakes the thread that blocks on a future.
+struct ThreadWaker {
+    thread: Thread,
+}
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.thread.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.thread.unpark();
+    }
+}
+
+/// Runs a future to completion on the calling thread, parking it while the future is pending.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = Arc::new(ThreadWaker { thread: thread::current() }).into();
+    let mut cx = task::Context::from_waker(&waker);
+
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            task::Poll::Ready(output) => return output,
+            // A wakeup that came before parking makes park return at once
+            task::Poll::Pending => thread::park(),
+        }
+    }
+}
+
+/// Turns a stream into an iterator whose `next` blocks the calling thread until the stream
+/// yields an item or ends.
+pub fn block_on_stream<S: Stream + Unpin>(stream: S) -> BlockingStream<S> {
+    BlockingStream { stream }
+}
+
+/// Iterator over the items of a stream, returned by
+/// [`block_on_stream`](fn.block_on_stream.html).
+pub struct BlockingStream<S> {
+    stream: S,
+}
+
+impl<S> BlockingStream<S> {
+    /// Returns the stream, e.g., to change its settings between items.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Returns the stream, consuming the iterator.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: Stream + Unpin> Iterator for BlockingStream<S> {
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<S::Item> {
+        let stream = &mut self.stream;
+        block_on(NextItem { stream })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.stream.size_hint()
+    }
+}
+
+/// Future of the next item of a stream.
+struct NextItem<'a, S: 'a> {
+    stream: &'a mut S,
+}
+
+impl<'a, S: Stream + Unpin> Future for NextItem<'a, S> {
+    type Output = Option<S::Item>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<S::Item>> {
+        Pin::new(&mut *self.get_mut().stream).poll_next(cx)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    extern crate futures;
+
+    use super::*;
+    use std::time::Duration;
+    use self::futures::channel::oneshot;
+    use self::futures::stream;
+
+    #[test]
+    fn it_blocks_until_woken_from_another_thread() {
+        let (sender, receiver) = oneshot::channel();
+        let sending = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            sender.send(42).unwrap();
+        });
+
+        assert_eq!(Ok(42), block_on(receiver));
+        sending.join().unwrap();
+    }
+
+    #[test]
+    fn it_iterates_over_a_stream() {
+        let items: Vec<u8> = block_on_stream(stream::iter(vec![1, 2, 3])).collect();
+        assert_eq!(vec![1, 2, 3], items);
+    }
+}
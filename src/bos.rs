@@ -0,0 +1,620 @@
+use control::{self, ControlFuture};
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::{Direction, RequestType, Recipient, Version, request_type};
+use string_descriptor::{self, StringDescriptorFuture};
+
+const BOS_DESCRIPTOR_LENGTH: usize = 5;
+const DT_BOS: u8 = 0x0F;
+const DT_DEVICE_CAPABILITY: u8 = 0x10;
+
+const WEBUSB_URL: u8 = 0x03;
+const WEBUSB_GET_URL: u16 = 0x02;
+
+const CAPABILITY_USB_2_0_EXTENSION: u8 = 0x02;
+const CAPABILITY_SUPERSPEED_USB: u8 = 0x03;
+const CAPABILITY_CONTAINER_ID: u8 = 0x04;
+const CAPABILITY_PLATFORM: u8 = 0x05;
+const CAPABILITY_BILLBOARD: u8 = 0x0D;
+
+const BILLBOARD_HEADER_LENGTH: usize = 41;
+const BILLBOARD_MODE_LENGTH: usize = 4;
+const BILLBOARD_MAX_MODES: usize = 34;
+
+/// Platform capability UUID of WebUSB, `{3408b638-09a9-47a0-8bfd-a0768815b665}`, in the byte
+/// order used on the wire.
+pub const WEBUSB_PLATFORM_UUID: [u8; 16] = [
+    0x38, 0xB6, 0x08, 0x34, 0xA9, 0x09, 0xA0, 0x47,
+    0x8B, 0xFD, 0xA0, 0x76, 0x88, 0x15, 0xB6, 0x65,
+];
+
+/// Platform capability UUID of Microsoft OS 2.0 descriptors,
+/// `{d8dd60df-4589-4cc7-9cd2-659d9e648a9f}`, in the byte order used on the wire.
+pub const MS_OS_20_PLATFORM_UUID: [u8; 16] = [
+    0xDF, 0x60, 0xDD, 0xD8, 0x89, 0x45, 0xC7, 0x4C,
+    0x9C, 0xD2, 0x65, 0x9D, 0x9E, 0x64, 0x8A, 0x9F,
+];
+
+/// Describes a device's Binary device Object Store (BOS).
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+pub struct BosDescriptor {
+    capabilities: Vec<DeviceCapability>,
+}
+
+impl BosDescriptor {
+    /// Returns the device capabilities in the order the device reported them.
+    pub fn capabilities(&self) -> &[DeviceCapability] {
+        &self.capabilities
+    }
+
+    /// Returns the WebUSB platform capability, if the device has one.
+    pub fn webusb(&self) -> Option<&WebUsbCapability> {
+        self.capabilities.iter().filter_map(|capability| {
+            match *capability {
+                DeviceCapability::Platform(PlatformCapability::WebUsb(ref webusb)) => Some(webusb),
+                _ => None,
+            }
+        }).next()
+    }
+
+    /// Returns the Microsoft OS 2.0 descriptor set information, if the device has any.
+    pub fn ms_os_20(&self) -> Option<&[MsOs20DescriptorSetInfo]> {
+        self.capabilities.iter().filter_map(|capability| {
+            match *capability {
+                DeviceCapability::Platform(PlatformCapability::MsOs20(ref sets)) => Some(&sets[..]),
+                _ => None,
+            }
+        }).next()
+    }
+
+    /// Returns the Billboard capability, if the device has one.
+    pub fn billboard(&self) -> Option<&BillboardCapability> {
+        self.capabilities.iter().filter_map(|capability| {
+            match *capability {
+                DeviceCapability::Billboard(ref billboard) => Some(billboard),
+                _ => None,
+            }
+        }).next()
+    }
+}
+
+/// A device capability descriptor from the BOS.
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+pub enum DeviceCapability {
+    /// USB 2.0 extension capability. Contains the `bmAttributes` field.
+    Usb2Extension(u32),
+
+    /// SuperSpeed USB device capability.
+    SuperSpeed {
+        /// The `bmAttributes` field.
+        attributes: u8,
+
+        /// Bitmap of the speeds the device supports (`wSpeedsSupported`).
+        speeds_supported: u16,
+
+        /// Lowest speed at which all functionality is available (`bFunctionalitySupport`).
+        functionality_support: u8,
+
+        /// U1 device exit latency in microseconds.
+        u1_exit_latency: u8,
+
+        /// U2 device exit latency in microseconds.
+        u2_exit_latency: u16,
+    },
+
+    /// Container ID capability. Contains the UUID identifying the device instance.
+    ContainerId([u8; 16]),
+
+    /// Platform capability.
+    Platform(PlatformCapability),
+
+    /// Billboard capability of a USB Type-C device that failed to enter an alternate mode.
+    Billboard(BillboardCapability),
+
+    /// Capability this crate doesn't decode.
+    Unknown {
+        /// The `bDevCapabilityType` field.
+        capability_type: u8,
+
+        /// The capability-specific bytes following `bDevCapabilityType`.
+        data: Vec<u8>,
+    },
+}
+
+/// A platform capability descriptor.
+///
+/// Platform capabilities are identified by a UUID. The ones this crate knows about are decoded
+/// into typed variants; others are kept as raw bytes.
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+pub enum PlatformCapability {
+    /// WebUSB platform capability.
+    WebUsb(WebUsbCapability),
+
+    /// Microsoft OS 2.0 platform capability. Contains one entry per descriptor set, each
+    /// applying to a range of Windows versions.
+    MsOs20(Vec<MsOs20DescriptorSetInfo>),
+
+    /// Platform capability with an unrecognized UUID.
+    Other {
+        /// The `PlatformCapabilityUUID` field, in the byte order used on the wire.
+        uuid: [u8; 16],
+
+        /// The `CapabilityData` field.
+        data: Vec<u8>,
+    },
+}
+
+/// Contents of a WebUSB platform capability.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub struct WebUsbCapability {
+    /// Version of the WebUSB specification the device implements (`bcdVersion`).
+    pub version: Version,
+
+    /// `bRequest` value used for WebUSB requests (`bVendorCode`).
+    pub vendor_code: u8,
+
+    /// Index of the landing page URL descriptor, or `None` if there is no landing page.
+    pub landing_page: Option<u8>,
+}
+
+impl WebUsbCapability {
+    /// Reads the landing page URL from the device.
+    ///
+    /// The future resolves to `Err(Error::InvalidParam)` if the device has no landing page.
+    pub fn landing_page_url(&self, handle: &DeviceHandle) -> ControlFuture<String> {
+        match self.landing_page {
+            Some(index) => self.url(handle, index),
+            None => control::failed(Error::InvalidParam, parse_webusb_url),
+        }
+    }
+
+    /// Reads a URL descriptor from the device using the WebUSB GET_URL request.
+    pub fn url(&self, handle: &DeviceHandle, index: u8) -> ControlFuture<String> {
+        control::read(handle,
+                      request_type(Direction::In, RequestType::Vendor, Recipient::Device),
+                      self.vendor_code,
+                      index as u16,
+                      WEBUSB_GET_URL,
+                      255,
+                      parse_webusb_url)
+    }
+}
+
+/// Describes one Microsoft OS 2.0 descriptor set.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub struct MsOs20DescriptorSetInfo {
+    /// Minimum Windows version the descriptor set applies to (`dwWindowsVersion`).
+    pub windows_version: u32,
+
+    /// Length of the descriptor set in bytes (`wMSOSDescriptorSetTotalLength`).
+    pub total_length: u16,
+
+    /// `bRequest` value used to retrieve the descriptor set (`bMS_VendorCode`).
+    pub vendor_code: u8,
+
+    /// Non-zero if the device supports alternate enumeration (`bAltEnumCode`).
+    pub alt_enum_code: u8,
+}
+
+/// Contents of a Billboard capability.
+///
+/// USB Type-C devices expose a Billboard device when an alternate mode couldn't be entered, so
+/// the host can tell the user which alternate modes the device supports.
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+pub struct BillboardCapability {
+    /// Index of the string descriptor with a URL to more information (`iAddtionalInfoURL`).
+    pub additional_info_url_index: Option<u8>,
+
+    /// Index into `alternate_modes` of the mode the device prefers (`bPreferredAlternateMode`).
+    pub preferred_alternate_mode: u8,
+
+    /// The `VCONN Power` field.
+    pub vconn_power: u16,
+
+    /// Version of the Billboard specification the device implements (`bcdVersion`).
+    pub version: Version,
+
+    /// The `bAdditionalFailureInfo` field.
+    pub additional_failure_info: u8,
+
+    /// The alternate modes the device supports.
+    pub alternate_modes: Vec<AlternateMode>,
+}
+
+impl BillboardCapability {
+    /// Reads the additional information URL from the device in its first supported language.
+    ///
+    /// The future resolves to `Err(Error::InvalidParam)` if the capability has no URL.
+    pub fn additional_info_url<'h>(&self, handle: &'h DeviceHandle) -> StringDescriptorFuture<'h> {
+        string_descriptor::read_string(handle, None, self.additional_info_url_index)
+    }
+}
+
+/// An alternate mode listed in a Billboard capability.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub struct AlternateMode {
+    /// Standard or vendor ID of the alternate mode (`wSVID`).
+    pub svid: u16,
+
+    /// Index of the mode within the SVID (`bAlternateMode`).
+    pub mode: u8,
+
+    /// Index of the string descriptor describing the mode (`iAlternateModeString`).
+    pub description_string_index: Option<u8>,
+
+    /// Outcome of the device's attempt to enter the mode.
+    pub state: AlternateModeState,
+}
+
+impl AlternateMode {
+    /// Reads the mode's description from the device in its first supported language.
+    ///
+    /// The future resolves to `Err(Error::InvalidParam)` if the mode has no description string.
+    pub fn description_string<'h>(&self, handle: &'h DeviceHandle) -> StringDescriptorFuture<'h> {
+        string_descriptor::read_string(handle, None, self.description_string_index)
+    }
+}
+
+/// Configuration state of an alternate mode, from the Billboard `bmConfigured` field.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum AlternateModeState {
+    /// The device reported an unspecified error.
+    Error,
+
+    /// The device didn't attempt to enter the mode.
+    NotAttempted,
+
+    /// The device attempted to enter the mode but failed.
+    Unsuccessful,
+
+    /// The device entered the mode.
+    Configured,
+}
+
+/// Parses a BOS descriptor and its device capabilities from raw bytes.
+///
+/// `bytes` is what a `GET_DESCRIPTOR` request for the BOS returns, e.g., the output of
+/// [`DeviceHandle::raw_bos_descriptor`](struct.DeviceHandle.html#method.raw_bos_descriptor).
+/// Descriptors that aren't device capabilities are skipped.
+///
+/// Returns `Error::InvalidParam` if the BOS header or a device capability is malformed.
+pub fn parse_bos_descriptor(bytes: &[u8]) -> ::Result<BosDescriptor> {
+    if bytes.len() < BOS_DESCRIPTOR_LENGTH
+        || (bytes[0] as usize) < BOS_DESCRIPTOR_LENGTH
+        || (bytes[0] as usize) > bytes.len()
+        || bytes[1] != DT_BOS
+    {
+        return Err(Error::InvalidParam);
+    }
+
+    let total_length = (read_u16(bytes, 2) as usize).min(bytes.len());
+    let mut offset = bytes[0] as usize;
+    let mut capabilities = Vec::new();
+
+    while offset + 2 <= total_length {
+        let length = bytes[offset] as usize;
+
+        if length < 2 || offset + length > total_length {
+            return Err(Error::InvalidParam);
+        }
+
+        if bytes[offset + 1] == DT_DEVICE_CAPABILITY {
+            capabilities.push(parse_capability(&bytes[offset..offset + length])?);
+        }
+
+        offset += length;
+    }
+
+    Ok(BosDescriptor { capabilities })
+}
+
+fn parse_capability(descriptor: &[u8]) -> ::Result<DeviceCapability> {
+    if descriptor.len() < 3 {
+        return Err(Error::InvalidParam);
+    }
+
+    let data = &descriptor[3..];
+
+    let capability = match descriptor[2] {
+        CAPABILITY_USB_2_0_EXTENSION if data.len() >= 4 => {
+            DeviceCapability::Usb2Extension(read_u32(data, 0))
+        },
+        CAPABILITY_SUPERSPEED_USB if data.len() >= 7 => {
+            DeviceCapability::SuperSpeed {
+                attributes: data[0],
+                speeds_supported: read_u16(data, 1),
+                functionality_support: data[3],
+                u1_exit_latency: data[4],
+                u2_exit_latency: read_u16(data, 5),
+            }
+        },
+        CAPABILITY_CONTAINER_ID if data.len() >= 17 => {
+            DeviceCapability::ContainerId(uuid(&data[1..17]))
+        },
+        CAPABILITY_PLATFORM if data.len() >= 17 => {
+            DeviceCapability::Platform(parse_platform(uuid(&data[1..17]), &data[17..])?)
+        },
+        CAPABILITY_BILLBOARD if data.len() >= BILLBOARD_HEADER_LENGTH => {
+            DeviceCapability::Billboard(parse_billboard(data)?)
+        },
+        CAPABILITY_USB_2_0_EXTENSION | CAPABILITY_SUPERSPEED_USB | CAPABILITY_CONTAINER_ID | CAPABILITY_PLATFORM
+            | CAPABILITY_BILLBOARD => {
+            return Err(Error::InvalidParam);
+        },
+        capability_type => {
+            DeviceCapability::Unknown { capability_type, data: data.to_vec() }
+        },
+    };
+
+    Ok(capability)
+}
+
+/// Decodes a WebUSB URL descriptor into a URL string.
+///
+/// The scheme byte is expanded to `http://` or `https://`. A scheme of 255 means the URL
+/// already includes its scheme. Returns `Error::InvalidParam` for an unknown scheme, a URL
+/// that isn't valid UTF-8, or a malformed descriptor.
+pub fn parse_webusb_url(bytes: &[u8]) -> ::Result<String> {
+    if bytes.len() < 3 || bytes[1] != WEBUSB_URL {
+        return Err(Error::InvalidParam);
+    }
+
+    let length = bytes[0] as usize;
+
+    if length < 3 || length > bytes.len() {
+        return Err(Error::InvalidParam);
+    }
+
+    let scheme = match bytes[2] {
+        0 => "http://",
+        1 => "https://",
+        255 => "",
+        _ => return Err(Error::InvalidParam),
+    };
+
+    let url = ::std::str::from_utf8(&bytes[3..length]).map_err(|_| Error::InvalidParam)?;
+
+    Ok(format!("{}{}", scheme, url))
+}
+
+fn parse_platform(uuid: [u8; 16], data: &[u8]) -> ::Result<PlatformCapability> {
+    if uuid == WEBUSB_PLATFORM_UUID {
+        if data.len() < 4 {
+            return Err(Error::InvalidParam);
+        }
+
+        Ok(PlatformCapability::WebUsb(WebUsbCapability {
+            version: Version::from_bcd(read_u16(data, 0)),
+            vendor_code: data[2],
+            landing_page: match data[3] {
+                0 => None,
+                n => Some(n),
+            },
+        }))
+    }
+    else if uuid == MS_OS_20_PLATFORM_UUID {
+        let sets = data.chunks_exact(8);
+
+        if data.is_empty() || !sets.remainder().is_empty() {
+            return Err(Error::InvalidParam);
+        }
+
+        Ok(PlatformCapability::MsOs20(sets.map(|set| {
+            MsOs20DescriptorSetInfo {
+                windows_version: read_u32(set, 0),
+                total_length: read_u16(set, 4),
+                vendor_code: set[6],
+                alt_enum_code: set[7],
+            }
+        }).collect()))
+    }
+    else {
+        Ok(PlatformCapability::Other { uuid, data: data.to_vec() })
+    }
+}
+
+fn parse_billboard(data: &[u8]) -> ::Result<BillboardCapability> {
+    let num_modes = data[1] as usize;
+
+    if num_modes > BILLBOARD_MAX_MODES || data.len() < BILLBOARD_HEADER_LENGTH + num_modes * BILLBOARD_MODE_LENGTH {
+        return Err(Error::InvalidParam);
+    }
+
+    let configured = &data[5..37];
+
+    let alternate_modes = data[BILLBOARD_HEADER_LENGTH..].chunks(BILLBOARD_MODE_LENGTH).take(num_modes).enumerate().map(|(i, mode)| {
+        AlternateMode {
+            svid: read_u16(mode, 0),
+            mode: mode[2],
+            description_string_index: match mode[3] {
+                0 => None,
+                n => Some(n),
+            },
+            state: match (configured[i / 4] >> ((i % 4) * 2)) & 0x03 {
+                0 => AlternateModeState::Error,
+                1 => AlternateModeState::NotAttempted,
+                2 => AlternateModeState::Unsuccessful,
+                _ => AlternateModeState::Configured,
+            },
+        }
+    }).collect();
+
+    Ok(BillboardCapability {
+        additional_info_url_index: match data[0] {
+            0 => None,
+            n => Some(n),
+        },
+        preferred_alternate_mode: data[2],
+        vconn_power: read_u16(data, 3),
+        version: Version::from_bcd(read_u16(data, 37)),
+        additional_failure_info: data[39],
+        alternate_modes,
+    })
+}
+
+fn uuid(bytes: &[u8]) -> [u8; 16] {
+    let mut uuid = [0; 16];
+    uuid.copy_from_slice(bytes);
+    uuid
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    bytes[offset] as u16 | (bytes[offset + 1] as u16) << 8
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    read_u16(bytes, offset) as u32 | (read_u16(bytes, offset + 2) as u32) << 16
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fields::Version;
+
+    fn bos(capabilities: &[&[u8]]) -> Vec<u8> {
+        let total = 5 + capabilities.iter().map(|c| c.len()).sum::<usize>();
+        let mut bytes = vec![0x05, 0x0F, total as u8, (total >> 8) as u8, capabilities.len() as u8];
+
+        for capability in capabilities {
+            bytes.extend_from_slice(capability);
+        }
+
+        bytes
+    }
+
+    fn platform(uuid: &[u8; 16], data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![(20 + data.len()) as u8, 0x10, 0x05, 0x00];
+        bytes.extend_from_slice(uuid);
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn it_parses_empty_bos() {
+        assert!(parse_bos_descriptor(&bos(&[])).unwrap().capabilities().is_empty());
+    }
+
+    #[test]
+    fn it_rejects_wrong_descriptor_type() {
+        assert!(parse_bos_descriptor(&[0x05, 0x02, 0x05, 0x00, 0x00]).is_err());
+    }
+
+    #[test]
+    fn it_parses_usb2_extension() {
+        let bytes = bos(&[&[0x07, 0x10, 0x02, 0x06, 0x00, 0x00, 0x00]]);
+
+        assert_eq!(&[DeviceCapability::Usb2Extension(0x06)], parse_bos_descriptor(&bytes).unwrap().capabilities());
+    }
+
+    #[test]
+    fn it_parses_webusb_capability() {
+        let bytes = bos(&[&platform(&WEBUSB_PLATFORM_UUID, &[0x00, 0x01, 0x22, 0x01])]);
+        let descriptor = parse_bos_descriptor(&bytes).unwrap();
+
+        assert_eq!(Some(&WebUsbCapability {
+            version: Version(1, 0, 0),
+            vendor_code: 0x22,
+            landing_page: Some(1),
+        }), descriptor.webusb());
+    }
+
+    #[test]
+    fn it_parses_ms_os_20_capability() {
+        let data = [0x00, 0x00, 0x03, 0x06, 0xB2, 0x00, 0x21, 0x00];
+        let bytes = bos(&[&platform(&MS_OS_20_PLATFORM_UUID, &data)]);
+        let descriptor = parse_bos_descriptor(&bytes).unwrap();
+
+        assert_eq!(Some(&[MsOs20DescriptorSetInfo {
+            windows_version: 0x06030000,
+            total_length: 0x00B2,
+            vendor_code: 0x21,
+            alt_enum_code: 0,
+        }][..]), descriptor.ms_os_20());
+    }
+
+    #[test]
+    fn it_keeps_unknown_platform_capability() {
+        let uuid = [0xAA; 16];
+        let bytes = bos(&[&platform(&uuid, &[0x01, 0x02])]);
+
+        assert_eq!(&[DeviceCapability::Platform(PlatformCapability::Other { uuid, data: vec![0x01, 0x02] })],
+                   parse_bos_descriptor(&bytes).unwrap().capabilities());
+    }
+
+    fn billboard(modes: &[(u16, u8, u8)], configured: u8) -> Vec<u8> {
+        let mut bytes = vec![(44 + modes.len() * 4) as u8, 0x10, 0x0D, 0x01, modes.len() as u8, 0x00, 0x00, 0x00];
+        bytes.push(configured);
+        bytes.extend_from_slice(&[0; 31]);
+        bytes.extend_from_slice(&[0x21, 0x01, 0x00, 0x00]);
+
+        for &(svid, mode, string) in modes {
+            bytes.extend_from_slice(&[svid as u8, (svid >> 8) as u8, mode, string]);
+        }
+
+        bytes
+    }
+
+    #[test]
+    fn it_parses_billboard_capability() {
+        let bytes = bos(&[&billboard(&[(0xFF01, 0, 2), (0x8087, 1, 0)], 0b0000_1011)]);
+        let descriptor = parse_bos_descriptor(&bytes).unwrap();
+        let billboard = descriptor.billboard().unwrap();
+
+        assert_eq!(Some(1), billboard.additional_info_url_index);
+        assert_eq!(Version(1, 2, 1), billboard.version);
+        assert_eq!(vec![
+            AlternateMode { svid: 0xFF01, mode: 0, description_string_index: Some(2), state: AlternateModeState::Configured },
+            AlternateMode { svid: 0x8087, mode: 1, description_string_index: None, state: AlternateModeState::Unsuccessful },
+        ], billboard.alternate_modes);
+    }
+
+    #[test]
+    fn it_rejects_billboard_with_missing_modes() {
+        let mut capability = billboard(&[(0xFF01, 0, 0)], 0);
+        capability[4] = 2;
+
+        assert!(parse_bos_descriptor(&bos(&[&capability])).is_err());
+    }
+
+    #[test]
+    fn it_rejects_truncated_webusb_capability() {
+        let bytes = bos(&[&platform(&WEBUSB_PLATFORM_UUID, &[0x00, 0x01])]);
+
+        assert!(parse_bos_descriptor(&bytes).is_err());
+    }
+
+    #[test]
+    fn it_rejects_partial_ms_os_20_set() {
+        let bytes = bos(&[&platform(&MS_OS_20_PLATFORM_UUID, &[0x00, 0x00, 0x03, 0x06])]);
+
+        assert!(parse_bos_descriptor(&bytes).is_err());
+    }
+
+    #[test]
+    fn it_decodes_https_url() {
+        let mut bytes = vec![0x10, 0x03, 0x01];
+        bytes.extend_from_slice(b"example.com/x");
+
+        assert_eq!("https://example.com/x", parse_webusb_url(&bytes).unwrap());
+    }
+
+    #[test]
+    fn it_decodes_url_with_explicit_scheme() {
+        let mut bytes = vec![0x0C, 0x03, 0xFF];
+        bytes.extend_from_slice(b"ftp://a.b");
+        bytes.extend_from_slice(&[0, 0]);
+
+        assert_eq!("ftp://a.b", parse_webusb_url(&bytes).unwrap());
+    }
+
+    #[test]
+    fn it_rejects_unknown_url_scheme() {
+        assert!(parse_webusb_url(&[0x04, 0x03, 0x02, b'a']).is_err());
+    }
+
+    #[test]
+    fn it_rejects_truncated_url() {
+        assert!(parse_webusb_url(&[0x08, 0x03, 0x00, b'a']).is_err());
+    }
+}
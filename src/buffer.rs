@@ -0,0 +1,260 @@
+use std::alloc::{self, Layout};
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::ptr::{self, NonNull};
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+/// Alignment of transfer buffers unless another one is asked for, the size of a cache line.
+pub const DEFAULT_ALIGNMENT: usize = 64;
+
+/// The smallest capacity allocated, so that building a control transfer byte by byte doesn't
+/// reallocate.
+const MIN_CAPACITY: usize = 64;
+
+/// The most free allocations a pool keeps of each size class.
+const POOL_DEPTH: usize = 32;
+
+/// Free allocations of transfer buffers, recycled by size class and alignment.
+///
+/// Each context has one, so streaming transfers don't allocate and free their buffers on every
+/// cycle. A size class is a power of two, and at most `POOL_DEPTH` allocations of each are kept,
+/// the rest being freed.
+pub struct BufferPool {
+    // Addresses of the free allocations by size and alignment
+    free: Mutex<HashMap<(usize, usize), Vec<usize>>>,
+}
+
+impl BufferPool {
+    pub fn new() -> BufferPool {
+        BufferPool { free: Mutex::new(HashMap::new()) }
+    }
+
+    /// Returns the number of bytes in the free allocations.
+    pub fn free_bytes(&self) -> usize {
+        self.free.lock().unwrap().iter().map(|(&(size, _), free)| size * free.len()).sum()
+    }
+
+    fn take(&self, layout: Layout) -> NonNull<u8> {
+        let recycled = self.free.lock().unwrap()
+            .get_mut(&(layout.size(), layout.align()))
+            .and_then(Vec::pop);
+
+        match recycled {
+            Some(address) => NonNull::new(address as *mut u8).unwrap(),
+            None => allocate(layout),
+        }
+    }
+
+    fn give(&self, data: NonNull<u8>, layout: Layout) {
+        let mut free = self.free.lock().unwrap();
+        let free = free.entry((layout.size(), layout.align())).or_default();
+
+        if free.len() < POOL_DEPTH {
+            free.push(data.as_ptr() as usize);
+        } else {
+            unsafe {
+                alloc::dealloc(data.as_ptr(), layout);
+            }
+        }
+    }
+}
+
+impl Drop for BufferPool {
+    fn drop(&mut self) {
+        for (&(size, alignment), free) in self.free.get_mut().unwrap().iter() {
+            let layout = Layout::from_size_align(size, alignment).unwrap();
+            for &address in free {
+                unsafe {
+                    alloc::dealloc(address as *mut u8, layout);
+                }
+            }
+        }
+    }
+}
+
+fn allocate(layout: Layout) -> NonNull<u8> {
+    match NonNull::new(unsafe { alloc::alloc(layout) }) {
+        Some(data) => data,
+        None => alloc::handle_alloc_error(layout),
+    }
+}
+
+/// A growable byte buffer whose data starts at an aligned address.
+///
+/// Some host controller drivers copy unaligned buffers before DMA, or refuse them, so transfer
+/// buffers are allocated with an alignment of their own rather than as a `Vec<u8>`.
+pub struct AlignedBuffer {
+    data: NonNull<u8>,
+    len: usize,
+    capacity: usize,
+    alignment: usize,
+    pool: Option<Arc<BufferPool>>,
+}
+
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    /// Creates an empty buffer. `alignment` must be a power of two.
+    pub fn new(alignment: usize) -> AlignedBuffer {
+        assert!(alignment.is_power_of_two(), "Alignment must be a power of two");
+        AlignedBuffer { data: NonNull::dangling(), len: 0, capacity: 0, alignment, pool: None }
+    }
+
+    /// Creates an empty buffer whose allocations come from and go back to `pool`.
+    pub fn pooled(alignment: usize, pool: &Arc<BufferPool>) -> AlignedBuffer {
+        AlignedBuffer { pool: Some(pool.clone()), ..AlignedBuffer::new(alignment) }
+    }
+
+    pub fn alignment(&self) -> usize {
+        self.alignment
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    pub fn push(&mut self, byte: u8) {
+        self.extend_from_slice(&[byte]);
+    }
+
+    pub fn extend_from_slice(&mut self, bytes: &[u8]) {
+        self.reserve(bytes.len());
+        unsafe {
+            ptr::copy_nonoverlapping(bytes.as_ptr(), self.data.as_ptr().add(self.len), bytes.len());
+        }
+        self.len += bytes.len();
+    }
+
+    pub fn resize(&mut self, len: usize, value: u8) {
+        if len > self.len {
+            self.reserve(len - self.len);
+            unsafe {
+                ptr::write_bytes(self.data.as_ptr().add(self.len), value, len - self.len);
+            }
+        }
+        self.len = len;
+    }
+
+    /// Makes room for at least `additional` more bytes.
+    fn reserve(&mut self, additional: usize) {
+        let required = self.len.checked_add(additional).expect("Buffer too large");
+        if required <= self.capacity {
+            return;
+        }
+
+        let capacity = required.max(self.capacity * 2).max(MIN_CAPACITY);
+        let capacity = match self.pool {
+            Some(_) => capacity.checked_next_power_of_two().expect("Buffer too large"),
+            None => capacity,
+        };
+        let layout = Layout::from_size_align(capacity, self.alignment).expect("Buffer too large");
+        let data = match self.pool {
+            Some(ref pool) => pool.take(layout),
+            None => allocate(layout),
+        };
+
+        if self.capacity != 0 {
+            unsafe {
+                ptr::copy_nonoverlapping(self.data.as_ptr(), data.as_ptr(), self.len);
+            }
+            self.release();
+        }
+
+        self.data = data;
+        self.capacity = capacity;
+    }
+
+    /// Frees the allocation, or gives it back to the pool.
+    fn release(&mut self) {
+        let layout = self.layout();
+        match self.pool {
+            Some(ref pool) => pool.give(self.data, layout),
+            None => unsafe { alloc::dealloc(self.data.as_ptr(), layout) },
+        }
+    }
+
+    fn layout(&self) -> Layout {
+        Layout::from_size_align(self.capacity, self.alignment).unwrap()
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        if self.capacity != 0 {
+            self.release();
+        }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.data.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.data.as_ptr(), self.len) }
+    }
+}
+
+impl AsRef<[u8]> for AlignedBuffer {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_stays_aligned_when_growing() {
+        let mut buffer = AlignedBuffer::new(4096);
+        buffer.extend_from_slice(&[1, 2, 3]);
+        buffer.push(4);
+        assert_eq!(0, buffer.as_ptr() as usize % 4096);
+
+        buffer.resize(10_000, 0xAA);
+        assert_eq!(0, buffer.as_ptr() as usize % 4096);
+        assert_eq!(&[1, 2, 3, 4, 0xAA], &buffer[..5]);
+        assert_eq!(10_000, buffer.len());
+
+        buffer.truncate(2);
+        assert_eq!(&[1, 2], &buffer[..]);
+        buffer.clear();
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn it_recycles_allocations_by_size_class() {
+        let pool = Arc::new(BufferPool::new());
+
+        let mut buffer = AlignedBuffer::pooled(64, &pool);
+        buffer.resize(1000, 0);
+        let data = buffer.as_ptr();
+        drop(buffer);
+        assert_eq!(1024, pool.free_bytes());
+
+        // Any length of the same size class gets the same allocation
+        let mut buffer = AlignedBuffer::pooled(64, &pool);
+        buffer.resize(600, 0);
+        assert_eq!(data, buffer.as_ptr());
+        assert_eq!(0, pool.free_bytes());
+
+        // Growing gives the smaller allocation back
+        buffer.resize(2000, 0);
+        assert_eq!(1024, pool.free_bytes());
+        drop(buffer);
+        assert_eq!(1024 + 2048, pool.free_bytes());
+    }
+}
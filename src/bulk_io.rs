@@ -0,0 +1,148 @@
+//! `AsyncRead` and `AsyncWrite` plumbing shared by the serial port drivers.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task;
+
+use device_handle::DeviceHandle;
+use fields::EndpointAddress;
+use transfer::TransferFuture;
+
+/// Size of the bulk IN transfers used for reading, a multiple of all packet sizes.
+const READ_SIZE: usize = 4096;
+
+/// The pending transfers and buffered data of a pair of bulk endpoints.
+///
+/// One read and one write can be pending at a time. Received data that didn't fit in the
+/// caller's buffer is kept for the next read.
+#[derive(Default)]
+pub struct BulkIo {
+    read: Mutex<ReadState>,
+    write: Mutex<WriteState>,
+    zero_packet_size: usize,
+}
+
+#[derive(Default)]
+struct ReadState {
+    pending: Option<TransferFuture>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+/// The transfers of a pending write, its data followed by any zero-length packet.
+#[derive(Default)]
+struct WriteState {
+    pending: VecDeque<TransferFuture>,
+    written: usize,
+}
+
+impl BulkIo {
+    /// Creates the state for endpoints where a write that fills its last packet is terminated
+    /// by a zero-length packet, as message-based protocols need to tell where a write ends.
+    pub fn with_zero_length_packets(max_packet_size: usize) -> BulkIo {
+        BulkIo { zero_packet_size: max_packet_size, ..BulkIo::default() }
+    }
+
+    /// Reads from a bulk IN endpoint.
+    ///
+    /// `decode` turns each completed transfer into the data it carries, e.g., by removing
+    /// headers. Transfers that carry no data are skipped, since returning 0 would signal end of
+    /// file.
+    pub fn poll_read<F>(&self, handle: &DeviceHandle, endpoint: EndpointAddress, cx: &mut task::Context, buf: &mut [u8], mut decode: F) -> task::Poll<io::Result<usize>>
+        where F: FnMut(&[u8]) -> Vec<u8>
+    {
+        let mut state = self.read.lock().unwrap();
+
+        loop {
+            if state.position < state.buffer.len() {
+                let length = buf.len().min(state.buffer.len() - state.position);
+                buf[..length].copy_from_slice(&state.buffer[state.position..state.position + length]);
+                state.position += length;
+                return task::Poll::Ready(Ok(length));
+            }
+
+            if state.pending.is_none() {
+                let mut transfer = handle.alloc_transfer(0)?;
+                transfer.fill_bulk_read(endpoint, READ_SIZE)?;
+                state.pending = Some(transfer.submit());
+            }
+
+            let result = match state.pending {
+                Some(ref mut future) => match Pin::new(future).poll(cx) {
+                    task::Poll::Pending => return task::Poll::Pending,
+                    task::Poll::Ready(result) => result,
+                },
+                None => unreachable!(),
+            };
+
+            state.pending = None;
+
+            let transfer = result?;
+            transfer.check_status()?;
+            state.buffer = decode(transfer.get_buffer());
+            state.position = 0;
+        }
+    }
+
+    /// Writes to a bulk OUT endpoint, resolving to the number of bytes written.
+    pub fn poll_write(&self, handle: &DeviceHandle, endpoint: EndpointAddress, cx: &mut task::Context, buf: &[u8]) -> task::Poll<io::Result<usize>> {
+        let mut state = self.write.lock().unwrap();
+
+        if state.pending.is_empty() {
+            let mut transfer = handle.alloc_transfer(0)?;
+            transfer.fill_bulk_write(endpoint, buf)?;
+            state.pending.push_back(transfer.submit());
+
+            // Submitted right away, so that nothing else is sent on the endpoint in between
+            if self.zero_packet_size != 0 && !buf.is_empty() && buf.len().is_multiple_of(self.zero_packet_size) {
+                let mut transfer = handle.alloc_transfer(0)?;
+                transfer.fill_bulk_write(endpoint, &[])?;
+                state.pending.push_back(transfer.submit());
+            }
+        }
+
+        poll_write_transfers(&mut state, cx)
+    }
+
+    /// Waits for a pending write to complete.
+    pub fn poll_flush(&self, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        let mut state = self.write.lock().unwrap();
+
+        if state.pending.is_empty() {
+            return task::Poll::Ready(Ok(()));
+        }
+
+        poll_write_transfers(&mut state, cx).map(|result| result.map(|_| ()))
+    }
+}
+
+/// Polls the transfers of a pending write to completion, resolving to the number of bytes
+/// written.
+fn poll_write_transfers(state: &mut WriteState, cx: &mut task::Context) -> task::Poll<io::Result<usize>> {
+    while let Some(future) = state.pending.front_mut() {
+        let result = match Pin::new(future).poll(cx) {
+            task::Poll::Pending => return task::Poll::Pending,
+            task::Poll::Ready(result) => result,
+        };
+
+        state.pending.pop_front();
+
+        let transfer = match result.and_then(|transfer| transfer.check_status().map(|_| transfer)) {
+            Ok(transfer) => transfer,
+            Err(e) => {
+                state.pending.clear();
+                state.written = 0;
+                return task::Poll::Ready(Err(e.into()));
+            },
+        };
+
+        state.written += transfer.get_buffer().len();
+    }
+
+    let written = state.written;
+    state.written = 0;
+    task::Poll::Ready(Ok(written))
+}
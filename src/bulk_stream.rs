@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task;
+
+use futures_core::Stream;
+
+use device_handle::DeviceHandle;
+use fields::EndpointAddress;
+use read_queue::QueueDepth;
+use transfer::{CompletedTransfer, Transfer, TransferFuture};
+
+/// Stream of the data of a bulk IN endpoint, returned by
+/// [`DeviceHandle::bulk_stream`](struct.DeviceHandle.html#method.bulk_stream).
+///
+/// The stream keeps `transfers` reads of `transfer_size` bytes in flight, so the host controller
+/// always has a read queued for the device. The data isn't copied: each item loans the buffer of
+/// a completed read to the consumer, and the read is resubmitted when the loan is dropped. As
+/// long as loans are dropped in time, the transfers and their buffers are allocated once.
+///
+/// Up to as many loans as reads in flight may be held before the stream stops reading, which can
+/// be changed with [`max_loans`](#method.max_loans). The stream ends after the first error.
+pub struct BulkStream<'a> {
+    handle: &'a DeviceHandle,
+    endpoint: EndpointAddress,
+    depth: QueueDepth,
+    max_loans: usize,
+    in_flight: VecDeque<TransferFuture>,
+    returned: Arc<Returned>,
+    allocated: usize,
+    done: bool,
+}
+
+/// The transfers given back by loans, and the waker of a stream waiting for them.
+struct Returned {
+    state: Mutex<(Vec<Transfer>, Option<task::Waker>)>,
+}
+
+impl Returned {
+    fn give(&self, transfer: Transfer) {
+        let waker = {
+            let mut state = self.state.lock().unwrap();
+            state.0.push(transfer);
+            state.1.take()
+        };
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<'a> BulkStream<'a> {
+    pub fn new(handle: &'a DeviceHandle, endpoint: EndpointAddress, depth: QueueDepth) -> BulkStream<'a> {
+        BulkStream {
+            handle,
+            endpoint,
+            depth,
+            max_loans: depth.transfers,
+            in_flight: VecDeque::with_capacity(depth.transfers),
+            returned: Arc::new(Returned { state: Mutex::new((Vec::new(), None)) }),
+            allocated: 0,
+            done: false,
+        }
+    }
+
+    /// Sets the number of loaned buffers the consumer may hold before the stream stops reading.
+    pub fn max_loans(mut self, max_loans: usize) -> BulkStream<'a> {
+        self.max_loans = max_loans;
+        self
+    }
+
+    /// Submits reads until `depth.transfers` are in flight, or no transfer is available.
+    fn fill(&mut self) -> ::Result<()> {
+        while self.in_flight.len() < self.depth.transfers.max(1) {
+            let mut transfer = match self.returned.state.lock().unwrap().0.pop() {
+                Some(transfer) => transfer,
+                None if self.allocated < self.depth.transfers.max(1) + self.max_loans => {
+                    self.allocated += 1;
+                    self.handle.alloc_transfer(0)?
+                },
+                None => break,
+            };
+
+            transfer.fill_bulk_read(self.endpoint, self.depth.transfer_size)?;
+            self.in_flight.push_back(transfer.submit());
+        }
+        Ok(())
+    }
+
+    fn fail<T>(&mut self, error: ::Error) -> task::Poll<Option<::Result<T>>> {
+        self.done = true;
+        self.in_flight.clear();
+        task::Poll::Ready(Some(Err(error)))
+    }
+}
+
+impl<'a> Stream for BulkStream<'a> {
+    type Item = ::Result<LoanedBuffer>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.done {
+            return task::Poll::Ready(None);
+        }
+
+        if let Err(e) = this.fill() {
+            return this.fail(e);
+        }
+
+        let result = match this.in_flight.front_mut() {
+            Some(future) => match Pin::new(future).poll(cx) {
+                task::Poll::Pending => return task::Poll::Pending,
+                task::Poll::Ready(result) => result,
+            },
+            None => {
+                // Every transfer is loaned, so the stream waits for one to come back
+                let mut state = this.returned.state.lock().unwrap();
+                if state.0.is_empty() {
+                    state.1 = Some(cx.waker().clone());
+                    return task::Poll::Pending;
+                }
+                drop(state);
+                cx.waker().wake_by_ref();
+                return task::Poll::Pending;
+            },
+        };
+
+        this.in_flight.pop_front();
+        let transfer = match result.and_then(|transfer| transfer.check_status().map(|_| transfer)) {
+            Ok(transfer) => transfer,
+            Err(e) => return this.fail(e),
+        };
+
+        // Resubmitted from the spare transfers before handing out the data, so the endpoint
+        // stays busy. A failure is reported by the next poll.
+        let _ = this.fill();
+
+        task::Poll::Ready(Some(Ok(LoanedBuffer { transfer: Some(transfer), returned: this.returned.clone() })))
+    }
+}
+
+/// The data of a completed read of a [`BulkStream`](struct.BulkStream.html), loaned to the
+/// consumer without being copied.
+///
+/// Dropping it gives the buffer back to the stream to be read into again.
+pub struct LoanedBuffer {
+    transfer: Option<CompletedTransfer>,
+    returned: Arc<Returned>,
+}
+
+impl Deref for LoanedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.transfer.as_ref().map_or(&[], |transfer| transfer.get_buffer())
+    }
+}
+
+impl AsRef<[u8]> for LoanedBuffer {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Drop for LoanedBuffer {
+    fn drop(&mut self) {
+        if let Some(transfer) = self.transfer.take() {
+            self.returned.give(transfer.into_transfer());
+        }
+    }
+}
@@ -0,0 +1,499 @@
+use std::io::{self, Read, Write};
+use std::slice;
+use std::sync::{Mutex, PoisonError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use libusb::*;
+
+use backend::UsbBackend;
+use fields::TransferType;
+use transfer::{self, TransferStatus};
+
+/// Whether a [`CaptureRecord`](struct.CaptureRecord.html) was taken when a transfer was
+/// submitted or when it completed.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum CaptureEvent {
+    Submit,
+    Complete,
+}
+
+/// A transfer as captured by [`Context::start_capture`](struct.Context.html#method.start_capture).
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct CaptureRecord {
+    pub timestamp: SystemTime,
+    pub event: CaptureEvent,
+
+    /// Pairs the submission of a transfer with its completion, like
+    /// [`TransferInfo::id`](struct.TransferInfo.html#structfield.id).
+    pub id: u64,
+
+    pub bus_number: u8,
+    pub address: u8,
+
+    /// The address of the endpoint. For control transfers, the direction is taken from the
+    /// setup packet.
+    pub endpoint: u8,
+
+    pub transfer_type: TransferType,
+
+    /// The setup packet of a control transfer, when it is submitted.
+    pub setup: Option<[u8; 8]>,
+
+    /// The length of the data stage when submitted, and the number of bytes transferred when
+    /// completed.
+    pub length: usize,
+
+    /// The data that went to the device when submitted, or came from it when completed.
+    pub data: Vec<u8>,
+
+    /// How the transfer completed, `None` when it is submitted.
+    pub status: Option<TransferStatus>,
+}
+
+/// The capture of a context.
+pub struct Capture {
+    // Checked first, so transfers don't take the lock while nothing is captured
+    running: AtomicBool,
+    records: Mutex<Vec<CaptureRecord>>,
+}
+
+impl Capture {
+    pub fn new() -> Capture {
+        Capture { running: AtomicBool::new(false), records: Mutex::new(Vec::new()) }
+    }
+
+    /// Drops what was captured before, and starts capturing.
+    pub fn start(&self) {
+        let mut records = self.records.lock().unwrap_or_else(PoisonError::into_inner);
+        records.clear();
+        self.running.store(true, Ordering::Release);
+    }
+
+    /// Stops capturing, and returns what was captured.
+    pub fn stop(&self) -> Vec<CaptureRecord> {
+        let mut records = self.records.lock().unwrap_or_else(PoisonError::into_inner);
+        self.running.store(false, Ordering::Release);
+        ::std::mem::take(&mut *records)
+    }
+
+    /// Records a transfer being submitted or completed, if the capture is running.
+    ///
+    /// Safety: the buffer of the transfer must hold `length` bytes.
+    pub unsafe fn record(&self, backend: &dyn UsbBackend, event: CaptureEvent, transfer: &libusb_transfer) {
+        if !self.running.load(Ordering::Acquire) {
+            return;
+        }
+        let record = capture_record(backend, event, transfer, SystemTime::now());
+        let mut records = self.records.lock().unwrap_or_else(PoisonError::into_inner);
+        // Stopped while the record was taken
+        if self.running.load(Ordering::Acquire) {
+            records.push(record);
+        }
+    }
+}
+
+unsafe fn capture_record(backend: &dyn UsbBackend, event: CaptureEvent, transfer: &libusb_transfer,
+                         timestamp: SystemTime) -> CaptureRecord {
+    let (bus_number, address) = transfer::device_location(backend, transfer);
+
+    let length = transfer.length.max(0) as usize;
+    let buffer = if length == 0 || transfer.buffer.is_null() {
+        &[][..]
+    } else {
+        slice::from_raw_parts(transfer.buffer as *const u8, length)
+    };
+
+    let transfer_type = match transfer.transfer_type {
+        LIBUSB_TRANSFER_TYPE_CONTROL => TransferType::Control,
+        LIBUSB_TRANSFER_TYPE_ISOCHRONOUS => TransferType::Isochronous,
+        LIBUSB_TRANSFER_TYPE_INTERRUPT => TransferType::Interrupt,
+        _ => TransferType::Bulk,
+    };
+    let (setup, payload) = if transfer_type == TransferType::Control && buffer.len() >= 8 {
+        let mut setup = [0; 8];
+        setup.copy_from_slice(&buffer[..8]);
+        (Some(setup), &buffer[8..])
+    } else {
+        (None, buffer)
+    };
+    let endpoint = captured_endpoint(transfer);
+    let is_in = endpoint & LIBUSB_ENDPOINT_DIR_MASK == LIBUSB_ENDPOINT_IN;
+
+    let (length, data, status) = match event {
+        CaptureEvent::Submit => {
+            let data = if is_in { Vec::new() } else { payload.to_vec() };
+            (payload.len(), data, None)
+        },
+        CaptureEvent::Complete => {
+            let actual_length = (transfer.actual_length.max(0) as usize).min(payload.len());
+            let data = if is_in { payload[..actual_length].to_vec() } else { Vec::new() };
+            (actual_length, data, Some(TransferStatus::from(transfer.status)))
+        },
+    };
+
+    CaptureRecord {
+        timestamp,
+        event,
+        id: transfer as *const libusb_transfer as u64,
+        bus_number,
+        address,
+        endpoint,
+        transfer_type,
+        // Only the submission carries the setup packet, as in usbmon
+        setup: if event == CaptureEvent::Submit { setup } else { None },
+        length,
+        data,
+        status,
+    }
+}
+
+/// The endpoint a transfer is captured on, with the direction of the setup packet for control
+/// transfers.
+///
+/// Safety: the buffer of a control transfer must hold its setup packet.
+pub unsafe fn captured_endpoint(transfer: &libusb_transfer) -> u8 {
+    if transfer.transfer_type == LIBUSB_TRANSFER_TYPE_CONTROL && transfer.length >= 8 && !transfer.buffer.is_null() {
+        transfer.endpoint | (*transfer.buffer & LIBUSB_ENDPOINT_DIR_MASK)
+    } else {
+        transfer.endpoint
+    }
+}
+
+/// `LINKTYPE_USB_LINUX_MMAPPED`, USB packets with the 64 byte header of usbmon.
+const LINKTYPE_USB_LINUX_MMAPPED: u16 = 220;
+
+/// Writes captured transfers as a pcapng file, which Wireshark decodes as if captured with
+/// usbmon.
+///
+/// Each record becomes a packet with the usbmon header of the transfer, followed by its data.
+/// The packets of isochronous transfers don't carry the descriptors of their packets.
+pub fn write_pcapng<W: Write>(records: &[CaptureRecord], mut out: W) -> io::Result<()> {
+    // Section header block, with the byte-order magic and an unspecified section length
+    let mut section = Vec::new();
+    section.extend_from_slice(&0x1A2B_3C4Du32.to_le_bytes());
+    section.extend_from_slice(&1u16.to_le_bytes());
+    section.extend_from_slice(&0u16.to_le_bytes());
+    section.extend_from_slice(&(-1i64).to_le_bytes());
+    write_block(&mut out, 0x0A0D_0D0A, &section)?;
+
+    // Interface description block, without a snapshot length limit
+    let mut interface = Vec::new();
+    interface.extend_from_slice(&LINKTYPE_USB_LINUX_MMAPPED.to_le_bytes());
+    interface.extend_from_slice(&0u16.to_le_bytes());
+    interface.extend_from_slice(&0u32.to_le_bytes());
+    write_block(&mut out, 1, &interface)?;
+
+    for record in records {
+        let packet = usbmon_packet(record);
+        // Microseconds, the default resolution of the interface
+        let micros = timestamp(record).as_micros() as u64;
+
+        let mut block = Vec::with_capacity(20 + packet.len() + 3);
+        block.extend_from_slice(&0u32.to_le_bytes());
+        block.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        block.extend_from_slice(&(micros as u32).to_le_bytes());
+        block.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        block.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        block.extend_from_slice(&packet);
+        while block.len() % 4 != 0 {
+            block.push(0);
+        }
+        // Enhanced packet block
+        write_block(&mut out, 6, &block)?;
+    }
+    out.flush()
+}
+
+/// Writes a block with its type and its length before and after the body.
+fn write_block<W: Write>(out: &mut W, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let length = (body.len() + 12) as u32;
+    out.write_all(&block_type.to_le_bytes())?;
+    out.write_all(&length.to_le_bytes())?;
+    out.write_all(body)?;
+    out.write_all(&length.to_le_bytes())
+}
+
+/// `LINKTYPE_USB_LINUX`, the same packets with the 48 byte header of the text interface.
+const LINKTYPE_USB_LINUX: u16 = 189;
+
+/// Reads the transfers of a pcapng file written by [`write_pcapng`](fn.write_pcapng.html), or
+/// captured with usbmon, e.g., to replay what a device answered in a bug report.
+///
+/// Packets of other link types, and the error events of usbmon, are skipped. Fails with
+/// `InvalidData` if the file isn't a little-endian pcapng file.
+pub fn read_pcapng<R: Read>(mut input: R) -> io::Result<Vec<CaptureRecord>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_owned());
+
+    let mut records = Vec::new();
+    // The link type and the timestamp resolution, in units per second, of each interface of
+    // the section
+    let mut interfaces: Vec<(u16, u64)> = Vec::new();
+    let mut header = [0; 8];
+    let mut first = true;
+
+    loop {
+        match input.read_exact(&mut header) {
+            Ok(()) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof && !first => break,
+            Err(e) => return Err(e),
+        }
+        let block_type = u32_at(&header, 0);
+        let length = u32_at(&header, 4) as usize;
+        if length < 12 || !length.is_multiple_of(4) {
+            return Err(invalid("malformed pcapng block"));
+        }
+        let mut block = vec![0; length - 8];
+        input.read_exact(&mut block)?;
+        let body = &block[..length - 12];
+
+        match block_type {
+            0x0A0D_0D0A => {
+                if body.len() < 4 || u32_at(body, 0) != 0x1A2B_3C4D {
+                    return Err(invalid("not a little-endian pcapng file"));
+                }
+                interfaces.clear();
+            },
+            _ if first => return Err(invalid("not a pcapng file")),
+            1 if body.len() >= 8 => {
+                interfaces.push((u16_at(body, 0), timestamp_resolution(&body[8..])));
+            },
+            6 if body.len() >= 20 => {
+                let &(link_type, resolution) = match interfaces.get(u32_at(body, 0) as usize) {
+                    Some(interface) => interface,
+                    None => return Err(invalid("packet of an undescribed interface")),
+                };
+                let header_length = match link_type {
+                    LINKTYPE_USB_LINUX_MMAPPED => 64,
+                    LINKTYPE_USB_LINUX => 48,
+                    _ => continue,
+                };
+                let units = (u32_at(body, 4) as u64) << 32 | u32_at(body, 8) as u64;
+                let timestamp = UNIX_EPOCH + Duration::new(units / resolution,
+                    ((units % resolution) as u128 * 1_000_000_000 / resolution as u128) as u32);
+                let captured = (u32_at(body, 12) as usize).min(body.len() - 20);
+                if let Some(record) = parse_usbmon(&body[20..20 + captured], header_length, timestamp) {
+                    records.push(record);
+                }
+            },
+            _ => {},
+        }
+        first = false;
+    }
+    Ok(records)
+}
+
+/// The timestamp resolution of an interface, from its `if_tsresol` option.
+fn timestamp_resolution(mut options: &[u8]) -> u64 {
+    while options.len() >= 4 {
+        let code = u16_at(options, 0);
+        let length = u16_at(options, 2) as usize;
+        if code == 0 {
+            break;
+        }
+        if code == 9 && length == 1 && options.len() > 4 {
+            let exponent = options[4] & 0x7F;
+            return if options[4] & 0x80 == 0 {
+                10u64.checked_pow(exponent as u32)
+            } else {
+                1u64.checked_shl(exponent as u32)
+            }.unwrap_or(1_000_000);
+        }
+        let padded = 4 + length.div_ceil(4) * 4;
+        options = &options[padded.min(options.len())..];
+    }
+    1_000_000
+}
+
+/// Parses a usbmon packet, skipping error events and truncated packets.
+fn parse_usbmon(packet: &[u8], header_length: usize, timestamp: SystemTime) -> Option<CaptureRecord> {
+    if packet.len() < header_length {
+        return None;
+    }
+    let event = match packet[8] {
+        b'S' => CaptureEvent::Submit,
+        b'C' => CaptureEvent::Complete,
+        _ => return None,
+    };
+    let transfer_type = match packet[9] {
+        0 => TransferType::Isochronous,
+        1 => TransferType::Interrupt,
+        2 => TransferType::Control,
+        _ => TransferType::Bulk,
+    };
+    let mut setup = [0; 8];
+    setup.copy_from_slice(&packet[40..48]);
+    let captured = (u32_at(packet, 36) as usize).min(packet.len() - header_length);
+
+    Some(CaptureRecord {
+        timestamp,
+        event,
+        id: u32_at(packet, 0) as u64 | (u32_at(packet, 4) as u64) << 32,
+        bus_number: u16_at(packet, 12) as u8,
+        address: packet[11],
+        endpoint: packet[10],
+        transfer_type,
+        setup: if packet[14] == 0 { Some(setup) } else { None },
+        length: u32_at(packet, 32) as usize,
+        data: packet[header_length..header_length + captured].to_vec(),
+        status: match event {
+            CaptureEvent::Submit => None,
+            CaptureEvent::Complete => Some(status_of_usbmon(u32_at(packet, 28) as i32)),
+        },
+    })
+}
+
+/// The inverse of `usbmon_status`, also for the errors usbmon reports besides those.
+fn status_of_usbmon(status: i32) -> TransferStatus {
+    match status {
+        0 => TransferStatus::Completed,
+        -2 | -104 => TransferStatus::Cancelled,      // ENOENT, ECONNRESET
+        -19 | -108 => TransferStatus::NoDevice,      // ENODEV, ESHUTDOWN
+        -32 => TransferStatus::Stall,
+        -75 => TransferStatus::Overflow,
+        -110 => TransferStatus::TimedOut,
+        _ => TransferStatus::Error,
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn timestamp(record: &CaptureRecord) -> Duration {
+    record.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+/// The usbmon header of a record, as in `struct usbmon_packet` of Linux, and its data.
+fn usbmon_packet(record: &CaptureRecord) -> Vec<u8> {
+    let timestamp = timestamp(record);
+    let is_in = record.endpoint & LIBUSB_ENDPOINT_DIR_MASK == LIBUSB_ENDPOINT_IN;
+
+    let mut packet = Vec::with_capacity(64 + record.data.len());
+    packet.extend_from_slice(&record.id.to_le_bytes());
+    packet.push(match record.event {
+        CaptureEvent::Submit => b'S',
+        CaptureEvent::Complete => b'C',
+    });
+    packet.push(match record.transfer_type {
+        TransferType::Isochronous => 0,
+        TransferType::Interrupt => 1,
+        TransferType::Control => 2,
+        TransferType::Bulk => 3,
+    });
+    packet.push(record.endpoint);
+    packet.push(record.address);
+    packet.extend_from_slice(&(record.bus_number as u16).to_le_bytes());
+    // The flags are 0 when the setup packet and data are present
+    packet.push(if record.setup.is_some() { 0 } else { b'-' });
+    packet.push(match (record.data.is_empty(), is_in) {
+        (false, _) => 0,
+        (true, true) => b'<',
+        (true, false) => b'>',
+    });
+    packet.extend_from_slice(&(timestamp.as_secs() as i64).to_le_bytes());
+    packet.extend_from_slice(&(timestamp.subsec_micros() as i32).to_le_bytes());
+    packet.extend_from_slice(&usbmon_status(record.status).to_le_bytes());
+    packet.extend_from_slice(&(record.length as u32).to_le_bytes());
+    packet.extend_from_slice(&(record.data.len() as u32).to_le_bytes());
+    packet.extend_from_slice(&record.setup.unwrap_or([0; 8]));
+    // Interval, start frame, transfer flags and number of isochronous descriptors
+    packet.extend_from_slice(&[0; 16]);
+    packet.extend_from_slice(&record.data);
+    packet
+}
+
+/// The status of the URB that usbmon would report, a negated Linux error number.
+fn usbmon_status(status: Option<TransferStatus>) -> i32 {
+    match status {
+        None => -115,                                // EINPROGRESS
+        Some(TransferStatus::Completed) => 0,
+        Some(TransferStatus::Cancelled) => -2,       // ENOENT
+        Some(TransferStatus::NoDevice) => -19,       // ENODEV
+        Some(TransferStatus::Stall) => -32,          // EPIPE
+        Some(TransferStatus::Overflow) => -75,       // EOVERFLOW
+        Some(TransferStatus::TimedOut) => -110,      // ETIMEDOUT
+        Some(_) => -71,                              // EPROTO
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(event: CaptureEvent, data: Vec<u8>) -> CaptureRecord {
+        CaptureRecord {
+            timestamp: UNIX_EPOCH + Duration::new(0x1_0000_0000, 5_000),
+            event,
+            id: 0x1122,
+            bus_number: 3,
+            address: 7,
+            endpoint: 0x80,
+            transfer_type: TransferType::Control,
+            setup: if event == CaptureEvent::Submit { Some([0x80, 6, 0, 1, 0, 0, 18, 0]) } else { None },
+            length: 18,
+            data,
+            status: if event == CaptureEvent::Submit { None } else { Some(TransferStatus::Completed) },
+        }
+    }
+
+    #[test]
+    fn it_writes_the_usbmon_header() {
+        let packet = usbmon_packet(&record(CaptureEvent::Submit, Vec::new()));
+        assert_eq!(64, packet.len());
+        assert_eq!(&[0x22, 0x11, 0, 0, 0, 0, 0, 0, b'S', 2, 0x80, 7, 3, 0, 0, b'<'], &packet[..16]);
+        assert_eq!(&0x1_0000_0000i64.to_le_bytes(), &packet[16..24]);
+        assert_eq!(&[5, 0, 0, 0], &packet[24..28]);
+        assert_eq!(&(-115i32).to_le_bytes(), &packet[28..32]);
+        assert_eq!(&[18, 0, 0, 0, 0, 0, 0, 0], &packet[32..40]);
+        assert_eq!(&[0x80, 6, 0, 1, 0, 0, 18, 0], &packet[40..48]);
+
+        let packet = usbmon_packet(&record(CaptureEvent::Complete, vec![0x12, 0x01, 0x00]));
+        assert_eq!(67, packet.len());
+        assert_eq!(&[b'C', 2, 0x80, 7, 3, 0, b'-', 0], &packet[8..16]);
+        assert_eq!(&[0, 0, 0, 0, 18, 0, 0, 0, 3, 0, 0, 0], &packet[28..40]);
+        assert_eq!(&[0x12, 0x01, 0x00], &packet[64..]);
+    }
+
+    #[test]
+    fn it_writes_pcapng_blocks() {
+        let mut file = Vec::new();
+        write_pcapng(&[record(CaptureEvent::Complete, vec![1, 2, 3])], &mut file).unwrap();
+
+        // Section header, interface description and a packet of 67 bytes padded to 68
+        assert_eq!(28 + 20 + 100, file.len());
+        assert_eq!(&[0x0A, 0x0D, 0x0D, 0x0A, 28, 0, 0, 0, 0x4D, 0x3C, 0x2B, 0x1A, 1, 0, 0, 0], &file[..16]);
+        assert_eq!(&[1, 0, 0, 0, 20, 0, 0, 0, 220, 0, 0, 0, 0, 0, 0, 0, 20, 0, 0, 0], &file[28..48]);
+
+        let packet = &file[48..];
+        assert_eq!(&[6, 0, 0, 0, 100, 0, 0, 0, 0, 0, 0, 0], &packet[..12]);
+        let micros = 0x1_0000_0000u64 * 1_000_000 + 5;
+        assert_eq!(&((micros >> 32) as u32).to_le_bytes(), &packet[12..16]);
+        assert_eq!(&(micros as u32).to_le_bytes(), &packet[16..20]);
+        assert_eq!(&[67, 0, 0, 0, 67, 0, 0, 0], &packet[20..28]);
+        assert_eq!(&[1, 2, 3, 0, 100, 0, 0, 0], &packet[92..]);
+    }
+
+    #[test]
+    fn it_reads_what_it_writes() {
+        let records = vec![record(CaptureEvent::Submit, Vec::new()), record(CaptureEvent::Complete, vec![1, 2, 3])];
+        let mut file = Vec::new();
+        write_pcapng(&records, &mut file).unwrap();
+
+        assert_eq!(records, read_pcapng(&file[..]).unwrap());
+        assert_eq!(io::ErrorKind::InvalidData, read_pcapng(&file[28..]).unwrap_err().kind());
+        assert_eq!(io::ErrorKind::UnexpectedEof, read_pcapng(&file[..file.len() - 1]).unwrap_err().kind());
+    }
+
+    #[test]
+    fn it_reads_the_timestamp_resolution() {
+        assert_eq!(1_000_000, timestamp_resolution(&[]));
+        assert_eq!(1_000_000_000, timestamp_resolution(&[9, 0, 1, 0, 9, 0, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(1 << 10, timestamp_resolution(&[2, 0, 2, 0, b'a', b'b', 0, 0, 9, 0, 1, 0, 0x8A, 0, 0, 0]));
+    }
+}
@@ -0,0 +1,301 @@
+//! Helpers shared by the CDC class drivers.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task;
+
+use futures_core::Stream;
+
+use config_descriptor::ConfigDescriptor;
+use device_handle::DeviceHandle;
+use error::Error;
+use extra_descriptors::ExtraDescriptors;
+use fields::ClassCode;
+use string_descriptor::StringDescriptorFuture;
+use transfer::TransferFuture;
+
+pub const CS_INTERFACE: u8 = 0x24;
+
+const UNION_FUNCTIONAL: u8 = 0x06;
+
+/// Length of the header of a notification on the interrupt endpoint.
+const NOTIFICATION_HEADER_LENGTH: usize = 8;
+
+const NOTIFICATION_NETWORK_CONNECTION: u8 = 0x00;
+const NOTIFICATION_CONNECTION_SPEED_CHANGE: u8 = 0x2A;
+
+/// Finds the functions of a CDC subclass, as pairs of communications and data interface numbers.
+///
+/// The data interface comes from the Union functional descriptor, or is assumed to follow the
+/// communications interface.
+pub fn find_functions(config: &ConfigDescriptor, sub_class_code: u8) -> Vec<(u8, u8)> {
+    let mut functions = Vec::new();
+
+    for interface in config.interfaces() {
+        let setting = match interface.descriptors().next() {
+            Some(setting) => setting,
+            None => continue,
+        };
+
+        if setting.class_code() != ClassCode::Communications || setting.sub_class_code() != sub_class_code {
+            continue;
+        }
+
+        let control_interface = setting.interface_number();
+        let data_interface = union_data_interface(setting.extra()).unwrap_or(control_interface + 1);
+
+        let has_data_interface = config.interfaces()
+            .filter(|i| i.number() == data_interface)
+            .flat_map(|i| i.descriptors())
+            .any(|setting| setting.class_code() == ClassCode::CdcData);
+
+        if has_data_interface {
+            functions.push((control_interface, data_interface));
+        }
+    }
+
+    functions
+}
+
+/// Returns the first subordinate interface of a Union functional descriptor.
+pub fn union_data_interface(extra: &[u8]) -> Option<u8> {
+    ExtraDescriptors::new(extra)
+        .filter(|&(descriptor_type, _)| descriptor_type == CS_INTERFACE)
+        .find(|&(_, payload)| payload.len() >= 3 && payload[0] == UNION_FUNCTIONAL)
+        .map(|(_, payload)| payload[2])
+}
+
+/// Returns the payload of the first class-specific functional descriptor of a subtype.
+pub fn functional_descriptor(extra: &[u8], subtype: u8) -> Option<&[u8]> {
+    ExtraDescriptors::new(extra)
+        .filter(|&(descriptor_type, _)| descriptor_type == CS_INTERFACE)
+        .find(|&(_, payload)| payload.first() == Some(&subtype))
+        .map(|(_, payload)| payload)
+}
+
+/// Splits a notification into its code, `wValue` and data.
+pub fn parse_notification(notification: &[u8]) -> Option<(u8, u16, &[u8])> {
+    if notification.len() < NOTIFICATION_HEADER_LENGTH {
+        return None;
+    }
+
+    let value = u16::from_le_bytes([notification[2], notification[3]]);
+    let length = u16::from_le_bytes([notification[6], notification[7]]) as usize;
+    let data = &notification[NOTIFICATION_HEADER_LENGTH..];
+
+    Some((notification[1], value, &data[..length.min(data.len())]))
+}
+
+/// Parses the MAC address string of an Ethernet Networking functional descriptor.
+///
+/// The string has 12 hexadecimal digits, most significant byte first.
+///
+/// ```
+/// let mac = libusb_async::ecm::parse_mac_address("0250B6A1C2D3").unwrap();
+/// assert_eq!([0x02, 0x50, 0xB6, 0xA1, 0xC2, 0xD3], mac);
+/// ```
+pub fn parse_mac_address(address: &str) -> ::Result<[u8; 6]> {
+    let address = address.trim();
+    let mut mac = [0; 6];
+
+    if address.len() != 12 || !address.is_ascii() {
+        return Err(Error::InvalidParam);
+    }
+
+    for (i, byte) in mac.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&address[i * 2..i * 2 + 2], 16).map_err(|_| Error::InvalidParam)?;
+    }
+
+    Ok(mac)
+}
+
+/// Future that resolves to the MAC address of a network function.
+pub struct MacAddressFuture<'a> {
+    string: StringDescriptorFuture<'a>,
+}
+
+#[doc(hidden)]
+pub fn mac_address<'a>(handle: &'a DeviceHandle, index: u8) -> MacAddressFuture<'a> {
+    MacAddressFuture { string: handle.read_string_descriptor_async(None, index) }
+}
+
+impl<'a> Future for MacAddressFuture<'a> {
+    type Output = ::Result<[u8; 6]>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Self::Output> {
+        Pin::new(&mut self.get_mut().string).poll(cx).map(|result| parse_mac_address(&result?))
+    }
+}
+
+/// The packet types a network function passes to the host, as set by
+/// SetEthernetPacketFilter.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct PacketFilter {
+    /// All packets, regardless of destination address.
+    pub promiscuous: bool,
+
+    /// All multicast packets.
+    pub all_multicast: bool,
+
+    /// Packets addressed to the function's own MAC address.
+    pub directed: bool,
+
+    /// Broadcast packets.
+    pub broadcast: bool,
+
+    /// Multicast packets that match the function's multicast filters.
+    pub multicast: bool,
+}
+
+impl PacketFilter {
+    /// Returns the filter as the `wValue` of SetEthernetPacketFilter.
+    pub fn bits(&self) -> u16 {
+        self.promiscuous as u16
+            | (self.all_multicast as u16) << 1
+            | (self.directed as u16) << 2
+            | (self.broadcast as u16) << 3
+            | (self.multicast as u16) << 4
+    }
+}
+
+impl Default for PacketFilter {
+    /// Directed, broadcast and all multicast packets, which is what a regular network interface
+    /// receives.
+    fn default() -> PacketFilter {
+        PacketFilter { promiscuous: false, all_multicast: true, directed: true, broadcast: true, multicast: false }
+    }
+}
+
+/// A notification from a network function.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum NetworkEvent {
+    /// The network cable is connected, or the link is up.
+    Connected,
+
+    /// The network cable is disconnected, or the link is down.
+    Disconnected,
+
+    /// The link speed changed.
+    SpeedChange {
+        /// Downlink bit rate in bits per second.
+        downlink: u32,
+
+        /// Uplink bit rate in bits per second.
+        uplink: u32,
+    },
+}
+
+impl NetworkEvent {
+    fn from_notification(notification: &[u8]) -> Option<NetworkEvent> {
+        match parse_notification(notification)? {
+            (NOTIFICATION_NETWORK_CONNECTION, 0, _) => Some(NetworkEvent::Disconnected),
+            (NOTIFICATION_NETWORK_CONNECTION, _, _) => Some(NetworkEvent::Connected),
+            (NOTIFICATION_CONNECTION_SPEED_CHANGE, _, data) if data.len() >= 8 => Some(NetworkEvent::SpeedChange {
+                downlink: u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+                uplink: u32::from_le_bytes([data[4], data[5], data[6], data[7]]),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Stream of notifications from the interrupt endpoint of a network function.
+pub struct NetworkEvents<'a> {
+    handle: &'a DeviceHandle,
+    endpoint: Option<(u8, u16)>,
+    pending: Option<TransferFuture>,
+    done: bool,
+}
+
+#[doc(hidden)]
+pub fn network_events<'a>(handle: &'a DeviceHandle, endpoint: Option<(u8, u16)>) -> NetworkEvents<'a> {
+    NetworkEvents { handle, endpoint, pending: None, done: false }
+}
+
+impl<'a> NetworkEvents<'a> {
+    fn submit(&self) -> ::Result<TransferFuture> {
+        let (endpoint, packet_size) = self.endpoint.ok_or(Error::NotFound)?;
+        let mut transfer = self.handle.alloc_transfer(0)?;
+        transfer.fill_interrupt_read(endpoint.into(), packet_size)?;
+        Ok(transfer.submit())
+    }
+}
+
+impl<'a> Stream for NetworkEvents<'a> {
+    type Item = ::Result<NetworkEvent>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        while !this.done {
+            if this.pending.is_none() {
+                match this.submit() {
+                    Ok(future) => this.pending = Some(future),
+                    Err(e) => {
+                        this.done = true;
+                        return task::Poll::Ready(Some(Err(e)));
+                    },
+                }
+            }
+
+            let result = match this.pending {
+                Some(ref mut future) => match Pin::new(future).poll(cx) {
+                    task::Poll::Pending => return task::Poll::Pending,
+                    task::Poll::Ready(result) => result,
+                },
+                None => unreachable!(),
+            };
+
+            this.pending = None;
+
+            let event = result.and_then(|transfer| {
+                transfer.check_status()?;
+                Ok(NetworkEvent::from_notification(transfer.get_buffer()))
+            });
+
+            match event {
+                Ok(Some(event)) => {
+                    // Resubmit before handing out the event to keep the endpoint polled
+                    this.pending = this.submit().ok();
+                    return task::Poll::Ready(Some(Ok(event)));
+                },
+                Ok(None) => {},
+                Err(e) => {
+                    this.done = true;
+                    return task::Poll::Ready(Some(Err(e)));
+                },
+            }
+        }
+
+        task::Poll::Ready(None)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_parses_mac_address() {
+        assert_eq!([0x00, 0x1A, 0x2B, 0x3C, 0x4D, 0x5E], parse_mac_address("001a2b3c4d5e").unwrap());
+        assert!(parse_mac_address("001A2B3C4D").is_err());
+        assert!(parse_mac_address("001A2B3C4D5G").is_err());
+    }
+
+    #[test]
+    fn it_encodes_packet_filter() {
+        assert_eq!(0x000E, PacketFilter::default().bits());
+        assert_eq!(0x0001, PacketFilter { promiscuous: true, all_multicast: false, directed: false, broadcast: false, multicast: false }.bits());
+    }
+
+    #[test]
+    fn it_parses_network_notifications() {
+        assert_eq!(Some(NetworkEvent::Connected), NetworkEvent::from_notification(&[0xA1, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00]));
+        assert_eq!(Some(NetworkEvent::Disconnected), NetworkEvent::from_notification(&[0xA1, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]));
+        assert_eq!(Some(NetworkEvent::SpeedChange { downlink: 100_000_000, uplink: 10_000_000 }),
+                   NetworkEvent::from_notification(&[0xA1, 0x2A, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00,
+                                                     0x00, 0xE1, 0xF5, 0x05, 0x80, 0x96, 0x98, 0x00]));
+        assert_eq!(None, NetworkEvent::from_notification(&[0xA1, 0x20, 0x00, 0x00]));
+    }
+}
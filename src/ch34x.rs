@@ -0,0 +1,405 @@
+//! Support for WCH CH340 and CH341 USB serial converters.
+//!
+//! The CH34x chips are configured by writing pairs of registers with vendor requests, and use
+//! plain bulk endpoints for data.
+
+use std::io;
+use std::pin::Pin;
+use std::task;
+use std::time::Duration;
+
+use futures_io::{AsyncRead, AsyncWrite};
+
+use bulk_io::BulkIo;
+use control::{self, ControlFuture};
+use device::Device;
+use device_descriptor::DeviceDescriptor;
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::{Direction, EndpointAddress, Recipient, RequestType, TransferType, request_type};
+use serial::{Parity, StopBits};
+
+/// The vendor ID of Nanjing Qinheng Microelectronics (WCH).
+pub const CH34X_VENDOR_ID: u16 = 0x1A86;
+
+/// The product IDs of the CH340 and CH341 in serial mode.
+const CH34X_PRODUCT_IDS: [u16; 3] = [0x7523, 0x7522, 0x5523];
+
+const READ_REGISTER: u8 = 0x95;
+const WRITE_REGISTER: u8 = 0x9A;
+const SERIAL_INIT: u8 = 0xA1;
+const MODEM_CONTROL: u8 = 0xA4;
+const READ_VERSION: u8 = 0x5F;
+
+/// The prescaler register followed by the divisor register.
+const REGISTERS_DIVISOR: u16 = 0x1312;
+
+/// The two line control registers.
+const REGISTERS_LCR: u16 = 0x2518;
+
+/// The two modem status registers.
+const REGISTERS_STATUS: u16 = 0x0706;
+
+const LCR_ENABLE_RX: u8 = 0x80;
+const LCR_ENABLE_TX: u8 = 0x40;
+const LCR_MARK_SPACE: u8 = 0x20;
+const LCR_EVEN: u8 = 0x10;
+const LCR_ENABLE_PARITY: u8 = 0x08;
+const LCR_STOP_BITS_2: u8 = 0x04;
+
+const CLOCK_RATE: u32 = 48_000_000;
+
+const MIN_BAUD_RATE: u32 = 46;
+const MAX_BAUD_RATE: u32 = 3_000_000;
+
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Indicates if a device descriptor has the WCH vendor ID and a CH340 or CH341 serial product
+/// ID.
+pub fn is_ch34x_device(descriptor: &DeviceDescriptor) -> bool {
+    descriptor.vendor_id() == CH34X_VENDOR_ID && CH34X_PRODUCT_IDS.contains(&descriptor.product_id())
+}
+
+/// Returns the clock divider for a prescaler setting and clock factor.
+fn clock_divider(prescaler: u32, factor: u32) -> u32 {
+    1 << (12 - 3 * prescaler - factor)
+}
+
+/// Computes the value of the prescaler and divisor registers for a baud rate.
+///
+/// Chips from version 0x28 on also need bit 7 set, which this doesn't include.
+///
+/// Returns `Error::InvalidParam` if the baud rate is outside 46 to 3,000,000.
+pub fn encode_baud_rate(baud_rate: u32) -> ::Result<u16> {
+    if !(MIN_BAUD_RATE..=MAX_BAUD_RATE).contains(&baud_rate) {
+        return Err(Error::InvalidParam);
+    }
+
+    // Use the fastest base clock that gives a divisor below 512
+    let prescaler = (0..4).rev()
+        .find(|&prescaler| baud_rate > CLOCK_RATE / (clock_divider(prescaler, 1) * 512))
+        .ok_or(Error::InvalidParam)?;
+
+    let mut factor = 1;
+    let mut divider = clock_divider(prescaler, factor);
+    let mut divisor = CLOCK_RATE / (divider * baud_rate);
+
+    if !(9..=255).contains(&divisor) {
+        divisor /= 2;
+        divider *= 2;
+        factor = 0;
+    }
+
+    if divisor < 2 {
+        return Err(Error::InvalidParam);
+    }
+
+    // Round to the nearest rate, scaled up to avoid rounding errors at low rates
+    if 16 * CLOCK_RATE / (divider * divisor) - 16 * baud_rate >= 16 * baud_rate - 16 * CLOCK_RATE / (divider * (divisor + 1)) {
+        divisor += 1;
+    }
+
+    // Prefer the slower base clock for even divisors
+    if factor == 1 && divisor.is_multiple_of(2) {
+        divisor /= 2;
+        factor = 0;
+    }
+
+    Ok(((0x100 - divisor) << 8 | factor << 2 | prescaler) as u16)
+}
+
+/// Encodes the character framing as the value of the line control register.
+///
+/// Returns `Error::InvalidParam` for data bits other than 5 to 8 and for 1.5 stop bits.
+pub fn encode_line_control(data_bits: u8, parity: Parity, stop_bits: StopBits) -> ::Result<u8> {
+    if !(5..=8).contains(&data_bits) {
+        return Err(Error::InvalidParam);
+    }
+
+    let parity = match parity {
+        Parity::None => 0,
+        Parity::Odd => LCR_ENABLE_PARITY,
+        Parity::Even => LCR_ENABLE_PARITY | LCR_EVEN,
+        Parity::Mark => LCR_ENABLE_PARITY | LCR_MARK_SPACE,
+        Parity::Space => LCR_ENABLE_PARITY | LCR_MARK_SPACE | LCR_EVEN,
+    };
+
+    let stop_bits = match stop_bits {
+        StopBits::One => 0,
+        StopBits::OnePointFive => return Err(Error::InvalidParam),
+        StopBits::Two => LCR_STOP_BITS_2,
+    };
+
+    Ok(LCR_ENABLE_RX | LCR_ENABLE_TX | parity | stop_bits | (data_bits - 5))
+}
+
+/// The modem status, as read from the status registers.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct ModemStatus {
+    bits: u8,
+}
+
+impl ModemStatus {
+    /// Returns the status bits, with 1 meaning active.
+    pub fn bits(&self) -> u8 {
+        self.bits
+    }
+
+    /// Clear to send.
+    pub fn cts(&self) -> bool {
+        self.bits & 0x01 != 0
+    }
+
+    /// Data set ready.
+    pub fn dsr(&self) -> bool {
+        self.bits & 0x02 != 0
+    }
+
+    /// Ring indicator.
+    pub fn ri(&self) -> bool {
+        self.bits & 0x04 != 0
+    }
+
+    /// Data carrier detect.
+    pub fn dcd(&self) -> bool {
+        self.bits & 0x08 != 0
+    }
+
+    fn decode(data: &[u8]) -> ::Result<ModemStatus> {
+        // The chip reports the signals active low
+        match data.first() {
+            Some(&bits) => Ok(ModemStatus { bits: !bits & 0x0F }),
+            None => Err(Error::InvalidParam),
+        }
+    }
+}
+
+/// An open CH340 or CH341 serial converter.
+///
+/// Data is read and written through the `AsyncRead` and `AsyncWrite` implementations, which
+/// are also implemented for `&Ch34xPort` so the port can be read and written concurrently.
+pub struct Ch34xPort {
+    handle: DeviceHandle,
+    interface: u8,
+    version: u8,
+    bulk_in: EndpointAddress,
+    bulk_out: EndpointAddress,
+    io: BulkIo,
+    kernel_driver_detached: bool,
+}
+
+impl Ch34xPort {
+    /// Opens a CH34x device, claims its interface and initializes the serial port.
+    ///
+    /// A kernel driver bound to the interface, e.g., `ch341`, is detached and reattached when
+    /// the port is dropped.
+    ///
+    /// ## Errors
+    ///
+    /// * `NotFound` if the active configuration has no such interface, or it lacks a bulk IN
+    ///   or bulk OUT endpoint.
+    /// * Any error returned while opening the device, claiming the interface or initializing
+    ///   the chip.
+    pub fn open(device: &Device, interface: u8) -> ::Result<Ch34xPort> {
+        let (bulk_in, bulk_out) = {
+            let config = device.active_config_descriptor()?;
+            let setting = config.interfaces()
+                .find(|i| i.number() == interface)
+                .and_then(|i| i.descriptors().next())
+                .ok_or(Error::NotFound)?;
+
+            let bulk_in = setting.first_endpoint(TransferType::Bulk, Direction::In).ok_or(Error::NotFound)?;
+            let bulk_out = setting.first_endpoint(TransferType::Bulk, Direction::Out).ok_or(Error::NotFound)?;
+
+            (bulk_in.address().into(), bulk_out.address().into())
+        };
+
+        let mut handle = device.open()?;
+
+        let kernel_driver_detached = handle.kernel_driver_active(interface).unwrap_or(false);
+        if kernel_driver_detached {
+            handle.detach_kernel_driver(interface)?;
+        }
+
+        handle.claim_interface(interface)?;
+
+        let mut version = [0; 2];
+        handle.read_control(request_type(Direction::In, RequestType::Vendor, Recipient::Device),
+                            READ_VERSION,
+                            0,
+                            0,
+                            &mut version,
+                            CONTROL_TIMEOUT)?;
+
+        handle.write_control(request_type(Direction::Out, RequestType::Vendor, Recipient::Device),
+                             SERIAL_INIT,
+                             0,
+                             0,
+                             &[],
+                             CONTROL_TIMEOUT)?;
+
+        Ok(Ch34xPort {
+            handle,
+            interface,
+            version: version[0],
+            bulk_in,
+            bulk_out,
+            io: BulkIo::default(),
+            kernel_driver_detached,
+        })
+    }
+
+    /// Returns the handle of the device.
+    pub fn handle(&self) -> &DeviceHandle {
+        &self.handle
+    }
+
+    /// Returns the number of the claimed interface.
+    pub fn interface_number(&self) -> u8 {
+        self.interface
+    }
+
+    /// Returns the chip version read when the port was opened.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Sets the baud rate to the closest one the chip supports.
+    ///
+    /// The future resolves to `Err(Error::InvalidParam)` if the baud rate is out of range; see
+    /// [`encode_baud_rate`](fn.encode_baud_rate.html).
+    pub fn set_baud_rate(&self, baud_rate: u32) -> ControlFuture<usize> {
+        match encode_baud_rate(baud_rate) {
+            Ok(value) if self.version > 0x27 => self.write_register(REGISTERS_DIVISOR, value | 0x80),
+            Ok(value) => self.write_register(REGISTERS_DIVISOR, value),
+            Err(e) => control::failed(e, control::written),
+        }
+    }
+
+    /// Sets the character framing.
+    ///
+    /// Chips before version 0x30 have a fixed framing of 8 data bits, no parity and one stop
+    /// bit, and the future resolves to `Err(Error::NotSupported)` for them.
+    pub fn set_data_characteristics(&self, data_bits: u8, parity: Parity, stop_bits: StopBits) -> ControlFuture<usize> {
+        if self.version < 0x30 {
+            return control::failed(Error::NotSupported, control::written);
+        }
+
+        match encode_line_control(data_bits, parity, stop_bits) {
+            Ok(lcr) => self.write_register(REGISTERS_LCR, lcr as u16),
+            Err(e) => control::failed(e, control::written),
+        }
+    }
+
+    /// Sets the DTR and RTS control signals.
+    pub fn set_control_lines(&self, dtr: bool, rts: bool) -> ControlFuture<usize> {
+        // The chip drives the signals active low
+        let value = !((dtr as u16) << 5 | (rts as u16) << 6) & 0xFF;
+
+        control::write(&self.handle,
+                       request_type(Direction::Out, RequestType::Vendor, Recipient::Device),
+                       MODEM_CONTROL,
+                       value,
+                       0,
+                       &[])
+    }
+
+    /// Reads the modem status.
+    pub fn modem_status(&self) -> ControlFuture<ModemStatus> {
+        control::read(&self.handle,
+                      request_type(Direction::In, RequestType::Vendor, Recipient::Device),
+                      READ_REGISTER,
+                      REGISTERS_STATUS,
+                      0,
+                      2,
+                      ModemStatus::decode)
+    }
+
+    fn write_register(&self, registers: u16, value: u16) -> ControlFuture<usize> {
+        control::write(&self.handle,
+                       request_type(Direction::Out, RequestType::Vendor, Recipient::Device),
+                       WRITE_REGISTER,
+                       registers,
+                       value,
+                       &[])
+    }
+}
+
+impl Drop for Ch34xPort {
+    fn drop(&mut self) {
+        if self.kernel_driver_detached {
+            let _ = self.handle.release_interface(self.interface);
+            let _ = self.handle.attach_kernel_driver(self.interface);
+        }
+    }
+}
+
+impl AsyncRead for &Ch34xPort {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context, buf: &mut [u8]) -> task::Poll<io::Result<usize>> {
+        self.io.poll_read(&self.handle, self.bulk_in, cx, buf, |data| data.to_vec())
+    }
+}
+
+impl AsyncWrite for &Ch34xPort {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context, buf: &[u8]) -> task::Poll<io::Result<usize>> {
+        self.io.poll_write(&self.handle, self.bulk_out, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.io.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.io.poll_flush(cx)
+    }
+}
+
+impl AsyncRead for Ch34xPort {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context, buf: &mut [u8]) -> task::Poll<io::Result<usize>> {
+        self.io.poll_read(&self.handle, self.bulk_in, cx, buf, |data| data.to_vec())
+    }
+}
+
+impl AsyncWrite for Ch34xPort {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context, buf: &[u8]) -> task::Poll<io::Result<usize>> {
+        self.io.poll_write(&self.handle, self.bulk_out, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.io.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.io.poll_flush(cx)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_encodes_baud_rates() {
+        // Values used by the Linux ch341 driver
+        assert_eq!(0xB202, encode_baud_rate(9600).unwrap());
+        assert_eq!(0xCC03, encode_baud_rate(115_200).unwrap());
+        assert!(encode_baud_rate(45).is_err());
+        assert!(encode_baud_rate(3_000_001).is_err());
+    }
+
+    #[test]
+    fn it_encodes_line_control() {
+        assert_eq!(0xC3, encode_line_control(8, Parity::None, StopBits::One).unwrap());
+        assert_eq!(0xDE, encode_line_control(7, Parity::Even, StopBits::Two).unwrap());
+        assert!(encode_line_control(8, Parity::None, StopBits::OnePointFive).is_err());
+    }
+
+    #[test]
+    fn it_decodes_active_low_modem_status() {
+        let status = ModemStatus::decode(&[0xFE, 0xEE]).unwrap();
+
+        assert!(status.cts());
+        assert!(!status.dsr());
+    }
+}
@@ -0,0 +1,184 @@
+use std::pin::Pin;
+use std::task;
+
+use futures_core::Stream;
+
+use bulk_stream::BulkStream;
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::{Direction, EndpointAddress, TransferType};
+use read_queue::{Backpressure, QueueDepth, ReadQueue};
+use transfer::TransferFuture;
+
+/// An interface claimed for the caller's own protocol, returned by
+/// [`DeviceHandle::claim`](struct.DeviceHandle.html#method.claim).
+///
+/// It finds the endpoints of the interface, so reading from a device takes a few lines:
+///
+/// ```no_run
+/// # extern crate futures;
+/// # extern crate libusb_async;
+/// # fn main() -> libusb_async::Result<()> {
+/// use futures::executor::block_on_stream;
+/// use libusb_async::{Context, DeviceFilter};
+///
+/// let context = Context::new()?;
+/// let interface = context.find(&DeviceFilter::new().product(0x046D, 0xC52B))?.open()?.claim(0)?;
+/// for data in block_on_stream(interface.interrupt_in()?) {
+///     println!("{:?}", data?);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Dropping it releases the interface and reattaches the kernel driver that was detached.
+pub struct ClaimedInterface {
+    handle: DeviceHandle,
+    interface: u8,
+    kernel_driver_detached: bool,
+}
+
+impl ClaimedInterface {
+    pub fn claim(mut handle: DeviceHandle, interface: u8) -> ::Result<ClaimedInterface> {
+        let device = handle.device();
+        if handle.active_configuration()? == 0 {
+            let first = device.config_descriptor(0)?.number();
+            handle.set_active_configuration(first)?;
+        }
+        if !device.active_config_descriptor()?.interfaces().any(|i| i.number() == interface) {
+            return Err(Error::NotFound);
+        }
+
+        let kernel_driver_detached = handle.kernel_driver_active(interface).unwrap_or(false);
+        if kernel_driver_detached {
+            handle.detach_kernel_driver(interface)?;
+        }
+
+        if let Err(e) = handle.claim_interface(interface) {
+            if kernel_driver_detached {
+                let _ = handle.attach_kernel_driver(interface);
+            }
+            return Err(e);
+        }
+
+        Ok(ClaimedInterface { handle, interface, kernel_driver_detached })
+    }
+
+    /// Returns the handle of the device.
+    pub fn handle(&self) -> &DeviceHandle {
+        &self.handle
+    }
+
+    /// Returns the interface number.
+    pub fn interface(&self) -> u8 {
+        self.interface
+    }
+
+    /// Returns the address and maximum packet size of the first endpoint of the interface with
+    /// a transfer type and direction, in its first alternate setting.
+    ///
+    /// Fails with `NotFound` if there is none.
+    pub fn endpoint(&self, transfer_type: TransferType, direction: Direction) -> ::Result<(EndpointAddress, u16)> {
+        let config = self.handle.device().active_config_descriptor()?;
+        config.interfaces()
+            .find(|i| i.number() == self.interface)
+            .and_then(|i| i.descriptors().next())
+            .and_then(|setting| setting.first_endpoint(transfer_type, direction))
+            .map(|endpoint| (endpoint.address().into(), endpoint.max_packet_size()))
+            .ok_or(Error::NotFound)
+    }
+
+    /// Returns a stream of the packets read from the first interrupt IN endpoint of the
+    /// interface.
+    pub fn interrupt_in<'a>(&'a self) -> ::Result<InterruptIn<'a>> {
+        let (endpoint, packet_size) = self.endpoint(TransferType::Interrupt, Direction::In)?;
+        let depth = QueueDepth::for_endpoint(TransferType::Interrupt, self.handle.device().speed(), packet_size);
+
+        Ok(InterruptIn {
+            handle: &self.handle,
+            endpoint,
+            packet_size,
+            reads: ReadQueue::new(depth.transfers),
+            done: false,
+        })
+    }
+
+    /// Returns a stream of the data read from the first bulk IN endpoint of the interface,
+    /// with the queue depth for the endpoint.
+    pub fn bulk_in<'a>(&'a self) -> ::Result<BulkStream<'a>> {
+        let (endpoint, packet_size) = self.endpoint(TransferType::Bulk, Direction::In)?;
+        let depth = QueueDepth::for_endpoint(TransferType::Bulk, self.handle.device().speed(), packet_size);
+
+        Ok(self.handle.bulk_stream(endpoint, depth))
+    }
+}
+
+impl Drop for ClaimedInterface {
+    fn drop(&mut self) {
+        let _ = self.handle.release_interface(self.interface);
+        if self.kernel_driver_detached {
+            let _ = self.handle.attach_kernel_driver(self.interface);
+        }
+    }
+}
+
+/// Stream of the packets of an interrupt IN endpoint, returned by
+/// [`ClaimedInterface::interrupt_in`](struct.ClaimedInterface.html#method.interrupt_in).
+///
+/// The stream ends after the first error.
+pub struct InterruptIn<'a> {
+    handle: &'a DeviceHandle,
+    endpoint: EndpointAddress,
+    packet_size: u16,
+    reads: ReadQueue<TransferFuture>,
+    done: bool,
+}
+
+impl<'a> InterruptIn<'a> {
+    /// Sets the number of reads kept in flight, by default from
+    /// [`QueueDepth::for_endpoint`](struct.QueueDepth.html#method.for_endpoint).
+    pub fn queue_depth(mut self, transfers: usize) -> InterruptIn<'a> {
+        self.reads.set_depth(transfers);
+        self
+    }
+
+    /// Sets what happens to packets the consumer is too slow to take, blocking by default.
+    pub fn backpressure(mut self, backpressure: Backpressure) -> InterruptIn<'a> {
+        self.reads.set_backpressure(backpressure);
+        self
+    }
+}
+
+impl<'a> Stream for InterruptIn<'a> {
+    type Item = ::Result<Vec<u8>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.done {
+            return task::Poll::Ready(None);
+        }
+
+        let (handle, endpoint, packet_size) = (this.handle, this.endpoint, this.packet_size);
+        let result = match this.reads.poll_next(cx, || {
+            let mut transfer = handle.alloc_transfer(0)?;
+            transfer.fill_interrupt_read(endpoint, packet_size)?;
+            Ok(transfer.submit())
+        }) {
+            task::Poll::Pending => return task::Poll::Pending,
+            task::Poll::Ready(result) => result,
+        };
+
+        let data = result.and_then(|transfer| {
+            transfer.check_status()?;
+            Ok(transfer.get_buffer().to_vec())
+        });
+
+        if data.is_err() {
+            this.done = true;
+            this.reads.clear();
+        }
+
+        task::Poll::Ready(Some(data))
+    }
+}
@@ -1,20 +1,31 @@
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::mem;
 use std::slice;
 
 use libusb::*;
 
-use interface_descriptor::{self, Interface};
+use interface_descriptor::{self, Interface, InterfaceDescriptor};
+use endpoint_descriptor::EndpointDescriptor;
+use extra_descriptors::{self, ExtraDescriptors};
+use fields::{ClassCode, TransferType, Direction};
+use device_handle::DeviceHandle;
+use string_descriptor::{self, StringDescriptorFuture};
+use parse::OwnedConfig;
 
 /// Describes a configuration.
 pub struct ConfigDescriptor {
     descriptor: *const libusb_config_descriptor,
+    // Set if the descriptor was parsed by this crate rather than allocated by libusb
+    owned: Option<Box<OwnedConfig>>,
 }
 
 impl Drop for ConfigDescriptor {
     fn drop(&mut self) {
-        unsafe {
-            libusb_free_config_descriptor(self.descriptor);
+        if self.owned.is_none() {
+            unsafe {
+                libusb_free_config_descriptor(self.descriptor);
+            }
         }
     }
 }
@@ -61,6 +72,14 @@ impl ConfigDescriptor {
         }
     }
 
+    /// Reads the configuration's description from the device in its first supported language.
+    ///
+    /// The future resolves to `Err(Error::InvalidParam)` if the configuration has no
+    /// description string.
+    pub fn description_string<'h>(&self, handle: &'h DeviceHandle) -> StringDescriptorFuture<'h> {
+        string_descriptor::read_string(handle, None, self.description_string_index())
+    }
+
     /// Returns the number of interfaces for this configuration.
     pub fn num_interfaces(&self) -> u8 {
         unsafe {
@@ -71,14 +90,91 @@ impl ConfigDescriptor {
     /// Returns a collection of the configuration's interfaces.
     pub fn interfaces(&self) -> Interfaces {
         let interfaces = unsafe {
-            slice::from_raw_parts(
-                (*self.descriptor).interface,
-                (*self.descriptor).bNumInterfaces as usize
-            )
+            if (*self.descriptor).interface.is_null() {
+                &[]
+            }
+            else {
+                slice::from_raw_parts(
+                    (*self.descriptor).interface,
+                    (*self.descriptor).bNumInterfaces as usize
+                )
+            }
         };
 
         Interfaces { iter: interfaces.iter() }
     }
+
+    /// Returns the first alternate setting with the given class, sub class and protocol codes.
+    pub fn find_interface<'a>(&'a self, class_code: ClassCode, sub_class_code: u8, protocol_code: u8) -> Option<InterfaceDescriptor<'a>> {
+        self.interfaces().flat_map(|interface| interface.descriptors()).find(|setting| {
+            setting.class_code() == class_code
+                && setting.sub_class_code() == sub_class_code
+                && setting.protocol_code() == protocol_code
+        })
+    }
+
+    /// Returns the alternate settings with the given class code, in the order they appear in
+    /// the configuration.
+    ///
+    /// The interface each setting belongs to is available through
+    /// [`InterfaceDescriptor::interface_number`](struct.InterfaceDescriptor.html#method.interface_number).
+    pub fn interfaces_of_class<'a>(&'a self, class_code: ClassCode) -> Vec<InterfaceDescriptor<'a>> {
+        self.interfaces().flat_map(|interface| interface.descriptors()).filter(|setting| {
+            setting.class_code() == class_code
+        }).collect()
+    }
+
+    /// Returns the first endpoint with the given transfer type and direction together with the
+    /// alternate setting it belongs to.
+    ///
+    /// Only the first alternate setting of each interface is searched, since that is the one
+    /// that is active after the interface is claimed.
+    pub fn find_endpoint<'a>(&'a self, transfer_type: TransferType, direction: Direction) -> Option<(InterfaceDescriptor<'a>, EndpointDescriptor<'a>)> {
+        self.interfaces().filter_map(|interface| interface.descriptors().next()).filter_map(|setting| {
+            setting.first_endpoint(transfer_type, direction).map(|endpoint| (setting, endpoint))
+        }).next()
+    }
+
+    /// Returns the unknown descriptors that follow the configuration descriptor, e.g.,
+    /// interface association descriptors.
+    pub fn extra(&self) -> &[u8] {
+        unsafe {
+            extra_descriptors::extra_from_libusb((*self.descriptor).extra,
+                                                 (*self.descriptor).extra_length)
+        }
+    }
+
+    /// Returns an iterator over the descriptors in [`extra`](#method.extra).
+    pub fn extra_descriptors<'a>(&'a self) -> ExtraDescriptors<'a> {
+        ExtraDescriptors::new(self.extra())
+    }
+}
+
+impl ConfigDescriptor {
+    fn key(&self) -> (u8, u8, u16, u8, u8, u8, u8, u8, &[u8]) {
+        let d: &libusb_config_descriptor = unsafe { &*self.descriptor };
+
+        (d.bLength, d.bDescriptorType, d.wTotalLength, d.bNumInterfaces, d.bConfigurationValue,
+         d.iConfiguration, d.bmAttributes, d.bMaxPower, self.extra())
+    }
+}
+
+impl PartialEq for ConfigDescriptor {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key() && self.interfaces().eq(other.interfaces())
+    }
+}
+
+impl Eq for ConfigDescriptor {}
+
+impl Hash for ConfigDescriptor {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+
+        for interface in self.interfaces() {
+            interface.hash(state);
+        }
+    }
 }
 
 impl fmt::Debug for ConfigDescriptor {
@@ -102,6 +198,31 @@ impl fmt::Debug for ConfigDescriptor {
     }
 }
 
+impl fmt::Display for ConfigDescriptor {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(fmt, "Configuration {}: {} interface(s), max power {} mA",
+               self.number(), self.num_interfaces(), self.max_power())?;
+
+        if self.self_powered() {
+            fmt.write_str(", self-powered")?;
+        }
+
+        if self.remote_wakeup() {
+            fmt.write_str(", remote wakeup")?;
+        }
+
+        for interface in self.interfaces() {
+            for setting in interface.descriptors() {
+                for line in setting.to_string().lines() {
+                    write!(fmt, "\n  {}", line)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
 /// Iterator over a configuration's interfaces.
 pub struct Interfaces<'a> {
     iter: slice::Iter<'a, libusb_interface>,
@@ -124,7 +245,12 @@ impl<'a> Iterator for Interfaces<'a> {
 
 #[doc(hidden)]
 pub unsafe fn from_libusb(config: *const libusb_config_descriptor) -> ConfigDescriptor {
-    ConfigDescriptor { descriptor: config }
+    ConfigDescriptor { descriptor: config, owned: None }
+}
+
+#[doc(hidden)]
+pub fn from_owned(config: Box<OwnedConfig>) -> ConfigDescriptor {
+    ConfigDescriptor { descriptor: config.descriptor(), owned: Some(config) }
 }
 
 
@@ -132,6 +258,8 @@ pub unsafe fn from_libusb(config: *const libusb_config_descriptor) -> ConfigDesc
 mod test {
     use std::mem;
 
+    use fields::ClassCode;
+
     // The Drop trait impl calls libusb_free_config_descriptor(), which would attempt to free
     // unallocated memory for a stack-allocated config descriptor. Allocating a config descriptor
     // is not a simple malloc()/free() inside libusb. Mimicking libusb's allocation would be
@@ -208,6 +336,71 @@ mod test {
         });
     }
 
+    #[test]
+    fn it_compares_header_and_interfaces() {
+        let interface1 = interface!(interface_descriptor!(bInterfaceNumber: 1));
+        let interface2 = interface!(interface_descriptor!(bInterfaceNumber: 1));
+        let interface3 = interface!(interface_descriptor!(bInterfaceNumber: 2));
+
+        with_config!(a: config_descriptor!(interface1) => {
+            with_config!(b: config_descriptor!(interface2) => {
+                assert_eq!(a, b);
+            });
+            with_config!(c: config_descriptor!(interface3) => {
+                assert!(a != c);
+            });
+        });
+
+        with_config!(a: config_descriptor!(bMaxPower: 50) => {
+            with_config!(b: config_descriptor!(bMaxPower: 250) => {
+                assert!(a != b);
+            });
+        });
+    }
+
+    #[test]
+    fn it_finds_interface_by_class_sub_class_and_protocol() {
+        let interface1 = interface!(interface_descriptor!(bInterfaceNumber: 0, bInterfaceClass: 0x02, bInterfaceSubClass: 0x02, bInterfaceProtocol: 0x01));
+        let interface2 = interface!(interface_descriptor!(bInterfaceNumber: 1, bInterfaceClass: 0x0A, bInterfaceSubClass: 0x00, bInterfaceProtocol: 0x00));
+
+        with_config!(config: config_descriptor!(interface1, interface2) => {
+            assert_eq!(Some(1), config.find_interface(ClassCode::CdcData, 0x00, 0x00).map(|setting| setting.interface_number()));
+            assert_eq!(None, config.find_interface(ClassCode::CdcData, 0x00, 0x01).map(|setting| setting.interface_number()));
+        });
+    }
+
+    #[test]
+    fn it_lists_interfaces_of_class() {
+        let interface1 = interface!(interface_descriptor!(bInterfaceNumber: 0, bInterfaceClass: 0x03),
+                                    interface_descriptor!(bInterfaceNumber: 0, bAlternateSetting: 1, bInterfaceClass: 0x03));
+        let interface2 = interface!(interface_descriptor!(bInterfaceNumber: 1, bInterfaceClass: 0xFF));
+
+        with_config!(config: config_descriptor!(interface1, interface2) => {
+            let settings = config.interfaces_of_class(ClassCode::Hid).iter().map(|setting| {
+                (setting.interface_number(), setting.setting_number())
+            }).collect::<Vec<_>>();
+
+            assert_eq!(vec![(0, 0), (0, 1)], settings);
+        });
+    }
+
+    #[test]
+    fn it_finds_endpoint_and_its_interface() {
+        use fields::{TransferType, Direction};
+
+        let interface1 = interface!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x81, bmAttributes: 0x02)));
+        let interface2 = interface!(merge!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x82, bmAttributes: 0x03)) => bInterfaceNumber: 1));
+
+        with_config!(config: config_descriptor!(interface1, interface2) => {
+            let found = config.find_endpoint(TransferType::Interrupt, Direction::In).map(|(setting, endpoint)| {
+                (setting.interface_number(), endpoint.address())
+            });
+
+            assert_eq!(Some((1, 0x82)), found);
+            assert!(config.find_endpoint(TransferType::Interrupt, Direction::Out).is_none());
+        });
+    }
+
     #[test]
     fn it_has_interfaces() {
         let interface = interface!(interface_descriptor!(bInterfaceNumber: 1));
@@ -220,4 +413,18 @@ mod test {
             assert_eq!(vec![1], interface_numbers);
         });
     }
+
+    #[test]
+    fn it_displays_header_interfaces_and_endpoints() {
+        let interface1 = interface!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x81, bmAttributes: 0x02)));
+        let interface2 = interface!(interface_descriptor!(bInterfaceNumber: 1, bInterfaceClass: 0xFF));
+
+        with_config!(config: merge!(config_descriptor!(interface1, interface2) => bConfigurationValue: 1, bmAttributes: 0xC0) => {
+            assert_eq!("Configuration 1: 2 interface(s), max power 20 mA, self-powered\n  \
+                        Interface 0 alt 0: Per interface (0x00/0x00/0x00)\n    \
+                        Endpoint 0x81 In Bulk, max packet size 16\n  \
+                        Interface 1 alt 0: Vendor specific (0xff/0x00/0x00)",
+                       config.to_string());
+        });
+    }
 }
@@ -1,28 +1,76 @@
 use std::mem::MaybeUninit;
-use std::thread::{self, JoinHandle};
-use std::sync::{Arc, Mutex,RwLock};
+use std::sync::Arc;
+use std::ptr;
+use std::time::Duration;
 
-use libc::c_int;
+use libc::{c_int, c_void};
 use libusb::*;
 
+use backend::{ContextRef, HandleRef, UsbBackend, LIBUSB};
+use buffer::BufferPool;
+use capture::{Capture, CaptureRecord};
+use device::Device;
+use device_filter::DeviceFilter;
+use diagnostics::{Accounting, Diagnostics};
 use device_list::{self, DeviceList};
 use device_handle::{self, DeviceHandle};
-use error;
-
+use driver::{self, BoundDevice, ClassDriver, DriverBindings, DriverRegistry};
+use error::{self, Error, Operation};
+use event_thread::{self, EventThread};
+use hotplug::{self, HotplugEvents};
+use journal::{self, UrbJournal};
+use observer::{Observers, TransferObserver};
+use runtime::{self, PollFd};
+use transfer::TransferFreelist;
+#[cfg(all(feature = "udev", target_os = "linux"))]
+use udev;
 
 // The part of the context that can be shared
 pub struct ContextAsync
 {
     pub context: *mut libusb_context,
-    // Lock while starting and stopping thread
-    async_thread: Mutex<Option<JoinHandle<()>>>,
-    open_count: RwLock<u32>,
-        
+    // Runs while devices are open or hotplug events registered
+    pub event_thread: EventThread,
+    // Transfers of the context, for diagnostics
+    pub accounting: Accounting,
+    // Recycled transfer buffers
+    pub buffers: Arc<BufferPool>,
+    // Recycled libusb transfers
+    pub transfers: TransferFreelist,
+    // Told about the transfers of the context
+    pub observers: Observers,
+    // What the context, its devices and transfers go through
+    pub backend: &'static dyn UsbBackend,
+    // Records the transfers of the context while a capture runs
+    pub capture: Capture,
 }
 
 /// A `libusb` context.
+///
+/// The context and the devices, handles and transfers from it can be dropped in any order. They
+/// keep the `libusb` context alive, and it is exited when the last of them is dropped. The
+/// events of the context are handled on a thread of its own while devices are open or hotplug
+/// events are registered.
 pub struct Context {
-    context: Arc<ContextAsync>
+    context: Arc<ContextAsync>,
+    drivers: Arc<DriverRegistry>,
+    usbdk: bool,
+}
+
+/// Options of a context that `libusb` takes when it is opened, returned by
+/// [`Context::builder`](struct.Context.html#method.builder).
+#[derive(Debug,Clone,Default)]
+pub struct ContextBuilder {
+    no_device_discovery: bool,
+    usbdk: UsbDk,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+enum UsbDk {
+    #[default]
+    Off,
+    Preferred,
+    Required,
 }
 
 unsafe impl Send for ContextAsync {}
@@ -31,8 +79,11 @@ unsafe impl Sync for ContextAsync {}
 impl Drop for ContextAsync {
     /// Closes the `libusb` context.
     fn drop(&mut self) {
-        unsafe {
-            libusb_exit(self.context);
+        self.transfers.clear();
+        if !self.context.is_null() {
+            unsafe {
+                libusb_exit(self.context);
+            }
         }
     }
 }
@@ -40,24 +91,147 @@ impl Drop for ContextAsync {
 unsafe impl Sync for Context {}
 unsafe impl Send for Context {}
 
-impl Context {
-    /// Opens a new `libusb` context.
-    pub fn new() -> ::Result<Self> {
+// Came with libusb 1.0.22 and 1.0.23, after the libusb-sys bindings
+extern "C" {
+    fn libusb_set_option(ctx: *mut libusb_context, option: c_int, ...) -> c_int;
+    fn libusb_wrap_sys_device(ctx: *mut libusb_context, sys_dev: isize,
+                              dev_handle: *mut *mut libusb_device_handle) -> c_int;
+}
+
+const LIBUSB_OPTION_USE_USBDK: c_int = 1;
+
+/// `LIBUSB_OPTION_NO_DEVICE_DISCOVERY`, named `LIBUSB_OPTION_WEAK_AUTHORITY` before libusb 1.0.25.
+const LIBUSB_OPTION_NO_DEVICE_DISCOVERY: c_int = 2;
+
+impl ContextBuilder {
+    pub fn new() -> ContextBuilder {
+        ContextBuilder::default()
+    }
+
+    /// Doesn't enumerate the devices of the system, e.g., on Android, where an application
+    /// isn't allowed to, and gets the devices it may use as file descriptors from `UsbManager`
+    /// instead. Open them with [`Context::wrap_sys_device`](struct.Context.html#method.wrap_sys_device).
+    ///
+    /// Needs libusb 1.0.23 or later. Before 1.0.27, `libusb` only takes the option as a
+    /// default for every context opened after this one.
+    pub fn no_device_discovery(mut self) -> ContextBuilder {
+        self.no_device_discovery = true;
+        self
+    }
+
+    /// Goes through the UsbDk driver on Windows, which reaches devices whose interfaces WinUSB
+    /// can't claim, e.g., those bound to a driver of the system. Opening the context fails
+    /// with `NotFound` if UsbDk isn't installed, and with `NotSupported` on other platforms.
+    ///
+    /// While a handle is open, UsbDk takes the whole device away from its Windows driver and
+    /// other programs, and gives it back when the handle is dropped, so handles should be
+    /// dropped as soon as they aren't used.
+    ///
+    /// Needs libusb 1.0.22 or later.
+    pub fn use_usbdk(mut self) -> ContextBuilder {
+        self.usbdk = UsbDk::Required;
+        self
+    }
+
+    /// Goes through UsbDk, like [`use_usbdk`](#method.use_usbdk), where it is installed, and
+    /// through the default backend of `libusb` elsewhere.
+    /// [`Context::uses_usbdk`](struct.Context.html#method.uses_usbdk) tells which one it got.
+    pub fn prefer_usbdk(mut self) -> ContextBuilder {
+        self.usbdk = UsbDk::Preferred;
+        self
+    }
+
+    /// Opens the context.
+    pub fn build(self) -> ::Result<Context> {
+        if self.no_device_discovery {
+            try_unsafe!(libusb_set_option(ptr::null_mut(), LIBUSB_OPTION_NO_DEVICE_DISCOVERY));
+        }
+
         let mut context = MaybeUninit::<*mut libusb_context>::uninit();
             
         try_unsafe!(libusb_init(context.as_mut_ptr()));
         let context = unsafe{ context.assume_init() };
+
+        // Set before the devices are listed, as the backend can't change after that
+        let usbdk = match self.usbdk {
+            UsbDk::Off => false,
+            usbdk => match unsafe { libusb_set_option(context, LIBUSB_OPTION_USE_USBDK) } {
+                0 => true,
+                err if usbdk == UsbDk::Required => {
+                    unsafe { libusb_exit(context) };
+                    return Err(error::from_libusb(err));
+                },
+                _ => false,
+            },
+        };
         
         let context = Arc::new(
             ContextAsync{ context: context ,
-                          async_thread: Mutex::new(None),
-                          open_count: RwLock::new(0),
+                          event_thread: EventThread::new(),
+                          accounting: Accounting::new(),
+                          buffers: Arc::new(BufferPool::new()),
+                          transfers: TransferFreelist::new(),
+                          observers: Observers::new(),
+                          backend: &LIBUSB,
+                          capture: Capture::new(),
             });
-        Ok(Context {context})
+        Ok(Context {context, drivers: Arc::new(DriverRegistry::default()), usbdk})
+    }
+}
+
+impl Context {
+    /// Opens a new `libusb` context.
+    pub fn new() -> ::Result<Self> {
+        ContextBuilder::new().build()
+    }
+
+    /// Returns a builder of a context with options, e.g., to use UsbDk on Windows.
+    pub fn builder() -> ContextBuilder {
+        ContextBuilder::new()
+    }
+
+    /// Opens a `libusb` context that doesn't enumerate the devices of the system, like
+    /// `Context::builder().no_device_discovery().build()`.
+    pub fn without_discovery() -> ::Result<Self> {
+        ContextBuilder::new().no_device_discovery().build()
+    }
+
+    /// Opens a context that goes through another USB stack than `libusb`, e.g., a mock for
+    /// tests, or one that replays a capture.
+    ///
+    /// The devices, handles, transfers and hotplug events of the context go through the
+    /// backend, which gets a null `ContextRef`. What only a `libusb` context provides isn't
+    /// available: `set_log_level` does nothing, `wrap_sys_device` fails with `NotSupported`,
+    /// and there are no `pollfds` or `next_timeout`, so the events are handled by the event
+    /// thread or `handle_events`.
+    #[cfg(any(test, feature = "replay"))]
+    pub(crate) fn with_backend(backend: &'static dyn UsbBackend) -> Context {
+        Context { context: ContextAsync::with_backend(backend), drivers: Arc::new(DriverRegistry::default()), usbdk: false }
+    }
+
+    /// Opens a context whose devices answer with what they answered in a capture, e.g., one
+    /// read with [`read_pcapng`](fn.read_pcapng.html) from a bug report, see the
+    /// [`replay`](replay/index.html) module.
+    ///
+    /// The context goes through a [`Replay`](replay/struct.Replay.html) of the records, which
+    /// lives as long as the program.
+    #[cfg(any(test, feature = "replay"))]
+    pub fn replay(records: &[CaptureRecord]) -> Context {
+        Context::with_backend(::replay::Replay::leak(records))
+    }
+
+    /// Tells whether the context goes through UsbDk, as asked for with
+    /// [`ContextBuilder::use_usbdk`](struct.ContextBuilder.html#method.use_usbdk) or
+    /// [`ContextBuilder::prefer_usbdk`](struct.ContextBuilder.html#method.prefer_usbdk).
+    pub fn uses_usbdk(&self) -> bool {
+        self.usbdk
     }
 
     /// Sets the log level of a `libusb` context.
     pub fn set_log_level(&mut self, level: LogLevel) {
+        if self.context.context.is_null() {
+            return;
+        }
         unsafe {
             libusb_set_debug(self.context.context, level.as_c_int());
         }
@@ -69,11 +243,10 @@ impl Context {
         }
     }
 
-    /// Tests whether the running `libusb` library supports hotplug.
+    /// Tests whether the running `libusb` library, or the backend of the context, supports
+    /// hotplug.
     pub fn has_hotplug(&self) -> bool {
-        unsafe {
-            libusb_has_capability(LIBUSB_CAP_HAS_HOTPLUG) != 0
-        }
+        self.context.backend.has_hotplug()
     }
 
     /// Tests whether the running `libusb` library has HID access.
@@ -90,21 +263,66 @@ impl Context {
         }
     }
 
+    /// Returns a stream of the devices that are connected and disconnected.
+    ///
+    /// The devices that are already connected are reported as arrived first. The events are
+    /// queued by the `libusb` callback and handled when the stream is polled, so a device can be
+    /// opened while handling an event.
+    ///
+    /// Returns `Error::NotSupported` if the running `libusb` library doesn't support hotplug.
+    pub fn hotplug_events(&self) -> ::Result<HotplugEvents> {
+        if !self.has_hotplug() {
+            return Err(Error::NotSupported);
+        }
+
+        hotplug::register(&self.context, None)
+    }
+
+    /// Returns a stream of the devices matching a filter that are connected and disconnected,
+    /// like [`hotplug_events`](#method.hotplug_events).
+    ///
+    /// Devices are matched when their arrival is polled, which opens them if the filter has a
+    /// serial number. A device that leaves is reported if it matched when it arrived.
+    pub fn hotplug_events_matching(&self, filter: DeviceFilter) -> ::Result<HotplugEvents> {
+        if !self.has_hotplug() {
+            return Err(Error::NotSupported);
+        }
+
+        hotplug::register(&self.context, Some(filter))
+    }
+
+    /// Returns a stream of the devices that udev sees connected and disconnected, with what
+    /// udev knows about them, as an alternative to [`hotplug_events`](#method.hotplug_events).
+    ///
+    /// The devices that are already connected are reported as arrived first. The device of an
+    /// event is found with [`UdevInfo::device`](udev/struct.UdevInfo.html#method.device).
+    ///
+    /// ## Errors
+    ///
+    /// Returns an error if udev can't be monitored, e.g., in a container without it.
+    #[cfg(all(feature = "udev", target_os = "linux"))]
+    pub fn udev_events(&self) -> ::Result<udev::UdevEvents> {
+        udev::events()
+    }
+
     /// Returns a list of the current USB devices. The context must outlive the device list.
     pub fn devices(&self) -> ::Result<DeviceList> {
-        let mut list = MaybeUninit::<*const *mut libusb_device>::uninit();
+        let devices = unsafe { self.context.backend.get_device_list(self.context.backend_context()) }
+            .map_err(error::from_libusb)?;
+        Ok(unsafe { device_list::from_backend(&self.context, devices) })
+    }
 
-        let n = unsafe { libusb_get_device_list(
-            self.context.context,
-            list.as_mut_ptr()) };
-        let list = unsafe{list.assume_init()};
+    /// Returns the current USB devices that match a filter.
+    pub fn devices_matching(&self, filter: &DeviceFilter) -> ::Result<Vec<Device>> {
+        Ok(self.devices()?.into_iter().filter(|device| filter.matches(device)).collect())
+    }
 
-        if n < 0 {
-            Err(error::from_libusb(n as c_int))
-        }
-        else {
-            Ok(unsafe { device_list::from_libusb(&self.context, list, n as usize) })
-        }
+    /// Returns the first current USB device that matches a filter, to be opened and claimed,
+    /// e.g., `context.find(&filter)?.open()?.claim(0)?`.
+    ///
+    /// Fails with `NotFound` if no device matches.
+    pub fn find(&self, filter: &DeviceFilter) -> ::Result<Device> {
+        self.devices()?.into_iter().find(|device| filter.matches(device)).ok_or(Error::NotFound)
     }
 
     /// Convenience function to open a device by its vendor ID and product ID.
@@ -116,70 +334,192 @@ impl Context {
     /// Returns a device handle for the first device found matching `vendor_id` and `product_id`.
     /// On error, or if the device could not be found, it returns `None`.
     pub fn open_device_with_vid_pid<'a>(&'a self, vendor_id: u16, product_id: u16) -> Option<DeviceHandle> {
-        let handle = unsafe { libusb_open_device_with_vid_pid(
-            self.context.context, vendor_id, product_id) };
+        self.devices().ok()?.into_iter()
+            .find(|device| device.device_descriptor().is_ok_and(|descriptor| {
+                (descriptor.vendor_id(), descriptor.product_id()) == (vendor_id, product_id)
+            }))?
+            .open().ok()
+    }
 
-        if handle.is_null() {
-            None
+    /// Opens a device from a file descriptor of its usbfs node, e.g., the one an Android
+    /// application gets from `UsbDeviceConnection.getFileDescriptor()`, which must stay open
+    /// while the handle is.
+    ///
+    /// The handle works like one from [`Device::open`](struct.Device.html#method.open). On
+    /// Android, interfaces are claimed with the `UsbDeviceConnection` first, as
+    /// [`DeviceHandle::detach_kernel_driver`](struct.DeviceHandle.html#method.detach_kernel_driver)
+    /// isn't supported there, and the descriptors can also be read from the device with
+    /// [`DeviceHandle::read_device_descriptor_async`](struct.DeviceHandle.html#method.read_device_descriptor_async)
+    /// and [`DeviceHandle::raw_config_descriptor`](struct.DeviceHandle.html#method.raw_config_descriptor).
+    ///
+    /// Needs libusb 1.0.23 or later, on Linux or Android.
+    pub fn wrap_sys_device(&self, fd: c_int) -> ::Result<DeviceHandle> {
+        if self.context.context.is_null() {
+            return Err(Error::NotSupported);
         }
-        else {
-            Some(unsafe { device_handle::from_libusb(&self.context, handle) })
+        let mut handle = ptr::null_mut();
+        try_unsafe!(libusb_wrap_sys_device(self.context.context, fd as isize, &mut handle), Operation::Open);
+
+        self.context.event_thread.acquire(&self.context);
+        Ok(unsafe { device_handle::from_backend(&self.context, HandleRef::from_ptr(handle as *mut c_void)) })
+    }
+
+    /// Handles the events of the context that arrive within `timeout`, running the callbacks of
+    /// the transfers and hotplug registrations they complete.
+    ///
+    /// With the `event-thread` feature, enabled by default, a thread does this while devices
+    /// are open or hotplug events are registered. Without it, no thread is spawned, and the
+    /// application calls this in a loop on a thread or task of its own, e.g., next to its
+    /// executor, for transfers and hotplug events to complete. The loop must keep running while
+    /// a device handle with transfers in flight is dropped, as the handle waits for them.
+    pub fn handle_events(&self, timeout: Duration) -> ::Result<()> {
+        match event_thread::handle_events_once(&self.context, timeout, None) {
+            0 => Ok(()),
+            err => Err(error::from_libusb(err)),
         }
     }
 
+    /// Returns the file descriptors to watch for events, to handle them on the reactor of an
+    /// async runtime, see [`runtime`](runtime/index.html).
+    pub fn pollfds(&self) -> Vec<PollFd> {
+        runtime::pollfds(&self.context)
+    }
+
+    /// Returns when the next timeout has to be handled, if the file descriptors returned by
+    /// [`pollfds`](#method.pollfds) don't signal it.
+    pub fn next_timeout(&self) -> ::Result<Option<Duration>> {
+        runtime::next_timeout(&self.context)
+    }
+
+    /// Returns counts of the users of the event thread and of the transfers of the context, e.g., to
+    /// find leaks.
+    pub fn diagnostics(&self) -> Diagnostics {
+        Diagnostics {
+            event_thread_users: self.context.event_thread.users(),
+            live_transfers: self.context.accounting.live_transfers(),
+            in_flight_transfers: self.context.accounting.in_flight_transfers(),
+            pooled_buffer_bytes: self.context.buffers.free_bytes(),
+            pooled_transfers: self.context.transfers.len(),
+        }
+    }
+
+    /// Registers an observer to be told about every transfer of the context, e.g., to feed a
+    /// profiler. Observers are called in the order they were added.
+    pub fn add_transfer_observer(&self, observer: Arc<dyn TransferObserver>) {
+        self.context.observers.add(observer);
+    }
+
+    /// Removes an observer added by [`add_transfer_observer`](#method.add_transfer_observer),
+    /// returning whether it was registered.
+    pub fn remove_transfer_observer(&self, observer: &Arc<dyn TransferObserver>) -> bool {
+        self.context.observers.remove(observer)
+    }
+
+    /// Opens a journal of every transfer submitted and completed on the context, and of the
+    /// devices connected and disconnected if the running `libusb` library supports hotplug.
+    ///
+    /// ## Errors
+    ///
+    /// Returns an error if the hotplug events can't be registered.
+    pub fn urb_journal(&self) -> ::Result<UrbJournal> {
+        let hotplug = if self.has_hotplug() {
+            Some(hotplug::register(&self.context, None)?)
+        } else {
+            None
+        };
+        Ok(journal::open(&self.context, hotplug))
+    }
+
+    /// Starts recording every transfer of the context when it is submitted and when it
+    /// completes, with its setup packet, data, status and timestamps, dropping what an earlier
+    /// capture recorded.
+    ///
+    /// The records are kept in memory until [`stop_capture`](#method.stop_capture), and can be
+    /// written with [`write_pcapng`](fn.write_pcapng.html) to be opened in Wireshark, without
+    /// the privileges usbmon needs.
+    pub fn start_capture(&self) {
+        self.context.capture.start();
+    }
+
+    /// Stops the capture started by [`start_capture`](#method.start_capture), and returns what
+    /// it recorded, in the order the transfers were submitted and completed.
+    pub fn stop_capture(&self) -> Vec<CaptureRecord> {
+        self.context.capture.stop()
+    }
+
+    /// Registers a class driver, to be probed by [`bind_drivers`](#method.bind_drivers) after
+    /// the drivers registered before it.
+    pub fn register_driver<D: ClassDriver>(&self) {
+        self.drivers.register::<D>(DeviceFilter::new());
+    }
+
+    /// Registers a class driver like [`register_driver`](#method.register_driver), to be
+    /// probed only for the devices that match a filter.
+    pub fn register_driver_matching<D: ClassDriver>(&self, filter: DeviceFilter) {
+        self.drivers.register::<D>(filter);
+    }
+
+    /// Opens a device and binds the registered class drivers to its interfaces.
+    ///
+    /// Each interface of the active configuration gets the first registered driver whose probe
+    /// accepts one of its alternate settings. Interfaces without a driver are left alone. Call
+    /// this for each device of interest, e.g., when it is discovered.
+    ///
+    /// ## Errors
+    ///
+    /// * `NotFound` if no driver accepts any interface of the device.
+    /// * Any error returned while opening the device, claiming an interface or binding a
+    ///   driver. The interfaces claimed so far are released again.
+    pub fn bind_drivers(&self, device: &Device) -> ::Result<BoundDevice> {
+        self.drivers.bind(device)
+    }
+
+    /// Returns a stream that binds the registered class drivers to each device as it is
+    /// connected, like [`bind_drivers`](#method.bind_drivers), and reports when a bound device
+    /// is disconnected.
+    ///
+    /// The devices that are already connected are bound first. Devices that no driver accepts
+    /// are left alone. The drivers stay bound while the application keeps the
+    /// [`BoundDevice`](struct.BoundDevice.html) of a device, which it drops when the device
+    /// leaves.
+    ///
+    /// Returns `Error::NotSupported` if the running `libusb` library doesn't support hotplug.
+    pub fn driver_bindings(&self) -> ::Result<DriverBindings> {
+        Ok(driver::bindings(self.hotplug_events()?, self.drivers.clone()))
+    }
+
 }
 
 impl ContextAsync
 {
-    /// A device has been opened and if necessary start the event loop
-    pub fn device_opened(ca: &Arc<Self>)
+    /// A context that was never initialized, for tests that don't reach libusb
+    #[cfg(test)]
+    pub fn uninitialized() -> Arc<Self>
     {
-        let mut thread = ca.async_thread.lock().unwrap();
-        let mut count = ca.open_count.write().unwrap();
-        *count += 1;
-
-        if thread.is_none() {
-            let context = ca.clone();
-            *thread = Some(thread::spawn(move || {
-                //println!("USB event loop started");
-                let libusb_ctxt = context.context;
-                loop {
-                    {
-                        let count = context.open_count.read().unwrap();
-                        if *count == 0 {
-                            break;
-                        }
-                    }
-                    unsafe {
-                        libusb_handle_events(libusb_ctxt);
-                    }
-                }
-                //println!("USB event loop stopped");
-            }));
-        }
+        ContextAsync::with_backend(&LIBUSB)
     }
 
-
-    /// Close a device
-    /// The actual closing should be done in the supplied closure.
-    /// This is so the correct lock cn be held while doing it.
-    pub fn device_close<F>(ca: &Arc<Self>, close: F)
-        where F: FnOnce()
+    /// A context without a `libusb` one, going through another backend
+    #[cfg(any(test, feature = "replay"))]
+    pub fn with_backend(backend: &'static dyn UsbBackend) -> Arc<Self>
     {
-        let mut thread = ca.async_thread.lock().unwrap();
-        {
-            let mut count = ca.open_count.write().unwrap();
-            *count -= 1;
-        }
-        close();
-        let count = ca.open_count.read().unwrap();
-        if *count == 0 {
-            if let Some(join) = thread.take() {
-                join.join().unwrap();
-            }
-        }
+        Arc::new(ContextAsync{ context: ::std::ptr::null_mut(),
+                               event_thread: EventThread::new(),
+                               accounting: Accounting::new(),
+                               buffers: Arc::new(BufferPool::new()),
+                               transfers: TransferFreelist::new(),
+                               observers: Observers::new(),
+                               backend,
+                               capture: Capture::new(),
+        })
     }
+}
 
+impl ContextAsync {
+    /// The context as the backend knows it.
+    pub fn backend_context(&self) -> ContextRef {
+        ContextRef::from_ptr(self.context as *mut c_void)
+    }
 }
 
 /// Library logging levels.
@@ -214,3 +554,9 @@ impl LogLevel {
     }
 }
 
+
+/// Returns the shared part of a context, e.g., for an event driver to keep it alive.
+#[cfg(any(feature = "tokio", feature = "async-io", all(feature = "io-uring", target_os = "linux")))]
+pub fn shared(context: &Context) -> &Arc<ContextAsync> {
+    &context.context
+}
@@ -0,0 +1,239 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task;
+
+use libusb::*;
+
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::{Direction, RequestType, Recipient, StandardRequest, request_type};
+use transfer::TransferFuture;
+
+/// Future that resolves to the result of an asynchronous control transfer.
+///
+/// Returned by [`DeviceHandle::read_control_async`](struct.DeviceHandle.html#method.read_control_async),
+/// [`DeviceHandle::write_control_async`](struct.DeviceHandle.html#method.write_control_async) and
+/// the class-specific request helpers. The transfer status is checked before the data stage is
+/// converted to the output value, so a stalled request resolves to `Err(Error::Pipe)`.
+pub struct ControlFuture<T> {
+    state: State,
+    convert: fn(&[u8]) -> ::Result<T>,
+}
+
+enum State {
+    Failed(Error),
+    Pending(TransferFuture),
+    Done,
+}
+
+impl<T> Future for ControlFuture<T> {
+    type Output = ::Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Self::Output> {
+        let this = self.get_mut();
+
+        let result = match this.state {
+            State::Failed(ref e) => Err(e.clone()),
+            State::Pending(ref mut future) => {
+                match Pin::new(future).poll(cx) {
+                    task::Poll::Pending => return task::Poll::Pending,
+                    task::Poll::Ready(result) => result.and_then(|transfer| {
+                        transfer.check_status()?;
+                        (this.convert)(transfer.get_control_data())
+                    }),
+                }
+            },
+            State::Done => return task::Poll::Ready(Err(Error::PolledAfterCompletion)),
+        };
+
+        this.state = State::Done;
+        task::Poll::Ready(result)
+    }
+}
+
+/// The setup packet of a control request, without its direction and length, which are given by
+/// how it's sent.
+///
+/// ## Examples
+///
+/// Reading the first string descriptor in US English:
+///
+/// ```no_run
+/// use libusb_async::ControlRequest;
+///
+/// let request = ControlRequest::get_descriptor(0x03, 1).index(0x0409);
+/// ```
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct ControlRequest {
+    /// Who defines the request.
+    pub request_type: RequestType,
+
+    /// Whether the device, an interface, an endpoint or something else is addressed.
+    pub recipient: Recipient,
+
+    /// The `bRequest` field.
+    pub request: u8,
+
+    /// The `wValue` field.
+    pub value: u16,
+
+    /// The `wIndex` field.
+    pub index: u16,
+}
+
+impl ControlRequest {
+    /// A request with a zero value and index.
+    pub fn new(request_type: RequestType, recipient: Recipient, request: u8) -> ControlRequest {
+        ControlRequest { request_type, recipient, request, value: 0, index: 0 }
+    }
+
+    /// A standard request.
+    pub fn standard(request: StandardRequest, recipient: Recipient) -> ControlRequest {
+        ControlRequest::new(RequestType::Standard, recipient, request.request())
+    }
+
+    /// A `GET_DESCRIPTOR` request of the device for a descriptor type, e.g., `0x02` for a
+    /// configuration, and index. For a string, the index of the request is the language.
+    pub fn get_descriptor(descriptor_type: u8, descriptor_index: u8) -> ControlRequest {
+        ControlRequest::standard(StandardRequest::GetDescriptor, Recipient::Device)
+            .value((descriptor_type as u16) << 8 | descriptor_index as u16)
+    }
+
+    /// Sets the recipient.
+    pub fn recipient(mut self, recipient: Recipient) -> ControlRequest {
+        self.recipient = recipient;
+        self
+    }
+
+    /// Sets the `wValue` field.
+    pub fn value(mut self, value: u16) -> ControlRequest {
+        self.value = value;
+        self
+    }
+
+    /// Sets the `wIndex` field.
+    pub fn index(mut self, index: u16) -> ControlRequest {
+        self.index = index;
+        self
+    }
+
+    /// Returns the `bmRequestType` field of the request in a direction.
+    pub fn request_type(&self, direction: Direction) -> u8 {
+        request_type(direction, self.request_type, self.recipient)
+    }
+
+    /// Reads up to `length` bytes with the request, as
+    /// [`DeviceHandle::read_control_async`](struct.DeviceHandle.html#method.read_control_async).
+    pub fn read(&self, handle: &DeviceHandle, length: u16) -> ControlFuture<Vec<u8>> {
+        read(handle, self.request_type(Direction::In), self.request, self.value, self.index, length, to_vec)
+    }
+
+    /// Writes `data` with the request, as
+    /// [`DeviceHandle::write_control_async`](struct.DeviceHandle.html#method.write_control_async).
+    pub fn write(&self, handle: &DeviceHandle, data: &[u8]) -> ControlFuture<usize> {
+        write(handle, self.request_type(Direction::Out), self.request, self.value, self.index, data)
+    }
+
+    /// Sends the request without a data stage, as
+    /// [`DeviceHandle::control_no_data_async`](struct.DeviceHandle.html#method.control_no_data_async).
+    pub fn send(&self, handle: &DeviceHandle) -> ControlFuture<()> {
+        no_data(handle, self.request_type(Direction::Out), self.request, self.value, self.index)
+    }
+}
+
+/// Returns a `GET_DESCRIPTOR` request for a string descriptor in a language.
+#[doc(hidden)]
+pub fn get_string(index: u8, lang_id: u16) -> ControlRequest {
+    ControlRequest::get_descriptor(LIBUSB_DT_STRING, index).index(lang_id)
+}
+
+fn submit(transfer: ::Result<TransferFuture>) -> State {
+    match transfer {
+        Ok(future) => State::Pending(future),
+        Err(e) => State::Failed(e),
+    }
+}
+
+#[doc(hidden)]
+pub fn read<T>(handle: &DeviceHandle, request_type: u8, request: u8, value: u16, index: u16, length: u16, convert: fn(&[u8]) -> ::Result<T>) -> ControlFuture<T> {
+    let transfer = handle.alloc_transfer(0).and_then(|mut transfer| {
+        transfer.fill_control_read(request_type, request, value, index, length)?;
+        Ok(transfer.submit())
+    });
+
+    ControlFuture { state: submit(transfer), convert }
+}
+
+#[doc(hidden)]
+pub fn write(handle: &DeviceHandle, request_type: u8, request: u8, value: u16, index: u16, data: &[u8]) -> ControlFuture<usize> {
+    let transfer = handle.alloc_transfer(0).and_then(|mut transfer| {
+        transfer.fill_control_write(request_type, request, value, index, data)?;
+        Ok(transfer.submit())
+    });
+
+    ControlFuture { state: submit(transfer), convert: written }
+}
+
+#[doc(hidden)]
+pub fn no_data(handle: &DeviceHandle, request_type: u8, request: u8, value: u16, index: u16) -> ControlFuture<()> {
+    let transfer = handle.alloc_transfer(0).and_then(|mut transfer| {
+        transfer.fill_control_no_data(request_type, request, value, index)?;
+        Ok(transfer.submit())
+    });
+
+    ControlFuture { state: submit(transfer), convert: no_output }
+}
+
+#[doc(hidden)]
+pub fn failed<T>(error: Error, convert: fn(&[u8]) -> ::Result<T>) -> ControlFuture<T> {
+    ControlFuture { state: State::Failed(error), convert }
+}
+
+#[doc(hidden)]
+pub fn written(data: &[u8]) -> ::Result<usize> {
+    Ok(data.len())
+}
+
+#[doc(hidden)]
+pub fn no_output(_data: &[u8]) -> ::Result<()> {
+    Ok(())
+}
+
+#[doc(hidden)]
+pub fn to_vec(data: &[u8]) -> ::Result<Vec<u8>> {
+    Ok(data.to_vec())
+}
+
+
+#[cfg(test)]
+mod test {
+    extern crate futures;
+
+    use super::*;
+    use self::futures::task::noop_waker;
+
+    #[test]
+    fn it_builds_descriptor_requests() {
+        let request = ControlRequest::get_descriptor(0x22, 0).recipient(Recipient::Interface).index(2);
+        assert_eq!((0x81, 0x06, 0x2200, 2),
+                   (request.request_type(Direction::In), request.request, request.value, request.index));
+        assert_eq!((0x80, 0x0301, 0x0409),
+                   (get_string(1, 0x0409).request_type(Direction::In), get_string(1, 0x0409).value, get_string(1, 0x0409).index));
+    }
+
+    #[test]
+    fn it_builds_standard_requests() {
+        let request = ControlRequest::standard(StandardRequest::SetConfiguration, Recipient::Device).value(1);
+        assert_eq!((0x00, 0x09, 1, 0), (request.request_type(Direction::Out), request.request, request.value, request.index));
+    }
+
+    #[test]
+    fn it_reports_a_poll_after_completion() {
+        let waker = noop_waker();
+        let mut cx = task::Context::from_waker(&waker);
+        let mut future = failed(Error::Busy, no_output);
+
+        assert!(matches!(Pin::new(&mut future).poll(&mut cx), task::Poll::Ready(Err(Error::Busy))));
+        assert!(matches!(Pin::new(&mut future).poll(&mut cx), task::Poll::Ready(Err(Error::PolledAfterCompletion))));
+    }
+}
@@ -0,0 +1,333 @@
+//! Support for Silicon Labs CP210x USB serial converters.
+//!
+//! The CP210x chips use vendor requests addressed to the interface for configuration and plain
+//! bulk endpoints for data.
+
+use std::io;
+use std::pin::Pin;
+use std::task;
+use std::time::Duration;
+
+use futures_io::{AsyncRead, AsyncWrite};
+
+use bulk_io::BulkIo;
+use control::{self, ControlFuture};
+use device::Device;
+use device_descriptor::DeviceDescriptor;
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::{Direction, EndpointAddress, Recipient, RequestType, TransferType, request_type};
+use serial::{Parity, StopBits};
+
+/// The vendor ID of Silicon Laboratories.
+pub const CP210X_VENDOR_ID: u16 = 0x10C4;
+
+/// The default product IDs of the CP210x chips.
+const CP210X_PRODUCT_IDS: [u16; 4] = [0xEA60, 0xEA63, 0xEA70, 0xEA71];
+
+const IFC_ENABLE: u8 = 0x00;
+const SET_LINE_CTL: u8 = 0x03;
+const SET_BREAK: u8 = 0x05;
+const SET_MHS: u8 = 0x07;
+const GET_MDMSTS: u8 = 0x08;
+const PURGE: u8 = 0x12;
+const GET_BAUDRATE: u8 = 0x1D;
+const SET_BAUDRATE: u8 = 0x1E;
+
+/// Value of PURGE that clears both the transmit and receive queues.
+const PURGE_ALL: u16 = 0x000F;
+
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Indicates if a device descriptor has the Silicon Labs vendor ID and one of the default
+/// CP210x product IDs.
+pub fn is_cp210x_device(descriptor: &DeviceDescriptor) -> bool {
+    descriptor.vendor_id() == CP210X_VENDOR_ID && CP210X_PRODUCT_IDS.contains(&descriptor.product_id())
+}
+
+/// Encodes the character framing as the `wValue` of SET_LINE_CTL.
+///
+/// Returns `Error::InvalidParam` for data bits other than 5 to 8.
+pub fn encode_line_control(data_bits: u8, parity: Parity, stop_bits: StopBits) -> ::Result<u16> {
+    if !(5..=8).contains(&data_bits) {
+        return Err(Error::InvalidParam);
+    }
+
+    let parity = match parity {
+        Parity::None => 0,
+        Parity::Odd => 1,
+        Parity::Even => 2,
+        Parity::Mark => 3,
+        Parity::Space => 4,
+    };
+
+    let stop_bits = match stop_bits {
+        StopBits::One => 0,
+        StopBits::OnePointFive => 1,
+        StopBits::Two => 2,
+    };
+
+    Ok(stop_bits | parity << 4 | (data_bits as u16) << 8)
+}
+
+/// The modem status, as returned by GET_MDMSTS.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct ModemStatus {
+    bits: u8,
+}
+
+impl ModemStatus {
+    /// Returns the raw status byte.
+    pub fn bits(&self) -> u8 {
+        self.bits
+    }
+
+    /// Data terminal ready, as set by the host.
+    pub fn dtr(&self) -> bool {
+        self.bits & 0x01 != 0
+    }
+
+    /// Request to send, as set by the host.
+    pub fn rts(&self) -> bool {
+        self.bits & 0x02 != 0
+    }
+
+    /// Clear to send.
+    pub fn cts(&self) -> bool {
+        self.bits & 0x10 != 0
+    }
+
+    /// Data set ready.
+    pub fn dsr(&self) -> bool {
+        self.bits & 0x20 != 0
+    }
+
+    /// Ring indicator.
+    pub fn ri(&self) -> bool {
+        self.bits & 0x40 != 0
+    }
+
+    /// Data carrier detect.
+    pub fn dcd(&self) -> bool {
+        self.bits & 0x80 != 0
+    }
+
+    fn decode(data: &[u8]) -> ::Result<ModemStatus> {
+        match data.first() {
+            Some(&bits) => Ok(ModemStatus { bits }),
+            None => Err(Error::InvalidParam),
+        }
+    }
+}
+
+/// An open interface of a CP210x chip.
+///
+/// Data is read and written through the `AsyncRead` and `AsyncWrite` implementations, which
+/// are also implemented for `&Cp210xPort` so the port can be read and written concurrently.
+pub struct Cp210xPort {
+    handle: DeviceHandle,
+    interface: u8,
+    bulk_in: EndpointAddress,
+    bulk_out: EndpointAddress,
+    io: BulkIo,
+    kernel_driver_detached: bool,
+}
+
+impl Cp210xPort {
+    /// Opens a CP210x device, claims one of its interfaces and enables the UART.
+    ///
+    /// A kernel driver bound to the interface, e.g., `cp210x`, is detached and reattached when
+    /// the port is dropped.
+    ///
+    /// ## Errors
+    ///
+    /// * `NotFound` if the active configuration has no such interface, or it lacks a bulk IN
+    ///   or bulk OUT endpoint.
+    /// * Any error returned while opening the device, claiming the interface or enabling the
+    ///   UART.
+    pub fn open(device: &Device, interface: u8) -> ::Result<Cp210xPort> {
+        let (bulk_in, bulk_out) = {
+            let config = device.active_config_descriptor()?;
+            let setting = config.interfaces()
+                .find(|i| i.number() == interface)
+                .and_then(|i| i.descriptors().next())
+                .ok_or(Error::NotFound)?;
+
+            let bulk_in = setting.first_endpoint(TransferType::Bulk, Direction::In).ok_or(Error::NotFound)?;
+            let bulk_out = setting.first_endpoint(TransferType::Bulk, Direction::Out).ok_or(Error::NotFound)?;
+
+            (bulk_in.address().into(), bulk_out.address().into())
+        };
+
+        let mut handle = device.open()?;
+
+        let kernel_driver_detached = handle.kernel_driver_active(interface).unwrap_or(false);
+        if kernel_driver_detached {
+            handle.detach_kernel_driver(interface)?;
+        }
+
+        handle.claim_interface(interface)?;
+
+        handle.write_control(request_type(Direction::Out, RequestType::Vendor, Recipient::Interface),
+                             IFC_ENABLE,
+                             1,
+                             interface as u16,
+                             &[],
+                             CONTROL_TIMEOUT)?;
+
+        Ok(Cp210xPort { handle, interface, bulk_in, bulk_out, io: BulkIo::default(), kernel_driver_detached })
+    }
+
+    /// Returns the handle of the device.
+    pub fn handle(&self) -> &DeviceHandle {
+        &self.handle
+    }
+
+    /// Returns the number of the claimed interface.
+    pub fn interface_number(&self) -> u8 {
+        self.interface
+    }
+
+    /// Sets the baud rate. The chip picks the closest rate it supports.
+    pub fn set_baud_rate(&self, baud_rate: u32) -> ControlFuture<usize> {
+        self.write_request(SET_BAUDRATE, 0, &baud_rate.to_le_bytes())
+    }
+
+    /// Reads the baud rate.
+    pub fn baud_rate(&self) -> ControlFuture<u32> {
+        self.read_request(GET_BAUDRATE, 4, |data| {
+            if data.len() < 4 {
+                return Err(Error::InvalidParam);
+            }
+
+            Ok(u32::from_le_bytes([data[0], data[1], data[2], data[3]]))
+        })
+    }
+
+    /// Sets the character framing.
+    pub fn set_data_characteristics(&self, data_bits: u8, parity: Parity, stop_bits: StopBits) -> ControlFuture<usize> {
+        match encode_line_control(data_bits, parity, stop_bits) {
+            Ok(value) => self.write_request(SET_LINE_CTL, value, &[]),
+            Err(e) => control::failed(e, control::written),
+        }
+    }
+
+    /// Sets the DTR and RTS control signals.
+    pub fn set_control_lines(&self, dtr: bool, rts: bool) -> ControlFuture<usize> {
+        // The high byte selects which of the signals to change
+        self.write_request(SET_MHS, 0x0300 | dtr as u16 | (rts as u16) << 1, &[])
+    }
+
+    /// Reads the modem status.
+    pub fn modem_status(&self) -> ControlFuture<ModemStatus> {
+        self.read_request(GET_MDMSTS, 1, ModemStatus::decode)
+    }
+
+    /// Starts or stops sending a break condition.
+    pub fn set_break(&self, enabled: bool) -> ControlFuture<usize> {
+        self.write_request(SET_BREAK, enabled as u16, &[])
+    }
+
+    /// Discards the data in the transmit and receive queues of the chip.
+    pub fn purge(&self) -> ControlFuture<usize> {
+        self.write_request(PURGE, PURGE_ALL, &[])
+    }
+
+    fn write_request(&self, request: u8, value: u16, data: &[u8]) -> ControlFuture<usize> {
+        control::write(&self.handle,
+                       request_type(Direction::Out, RequestType::Vendor, Recipient::Interface),
+                       request,
+                       value,
+                       self.interface as u16,
+                       data)
+    }
+
+    fn read_request<T>(&self, request: u8, length: u16, convert: fn(&[u8]) -> ::Result<T>) -> ControlFuture<T> {
+        control::read(&self.handle,
+                      request_type(Direction::In, RequestType::Vendor, Recipient::Interface),
+                      request,
+                      0,
+                      self.interface as u16,
+                      length,
+                      convert)
+    }
+}
+
+impl Drop for Cp210xPort {
+    fn drop(&mut self) {
+        let _ = self.handle.write_control(request_type(Direction::Out, RequestType::Vendor, Recipient::Interface),
+                                          IFC_ENABLE,
+                                          0,
+                                          self.interface as u16,
+                                          &[],
+                                          CONTROL_TIMEOUT);
+
+        if self.kernel_driver_detached {
+            let _ = self.handle.release_interface(self.interface);
+            let _ = self.handle.attach_kernel_driver(self.interface);
+        }
+    }
+}
+
+impl AsyncRead for &Cp210xPort {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context, buf: &mut [u8]) -> task::Poll<io::Result<usize>> {
+        self.io.poll_read(&self.handle, self.bulk_in, cx, buf, |data| data.to_vec())
+    }
+}
+
+impl AsyncWrite for &Cp210xPort {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context, buf: &[u8]) -> task::Poll<io::Result<usize>> {
+        self.io.poll_write(&self.handle, self.bulk_out, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.io.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.io.poll_flush(cx)
+    }
+}
+
+impl AsyncRead for Cp210xPort {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context, buf: &mut [u8]) -> task::Poll<io::Result<usize>> {
+        self.io.poll_read(&self.handle, self.bulk_in, cx, buf, |data| data.to_vec())
+    }
+}
+
+impl AsyncWrite for Cp210xPort {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context, buf: &[u8]) -> task::Poll<io::Result<usize>> {
+        self.io.poll_write(&self.handle, self.bulk_out, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.io.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.io.poll_flush(cx)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_encodes_line_control() {
+        assert_eq!(0x0800, encode_line_control(8, Parity::None, StopBits::One).unwrap());
+        assert_eq!(0x0722, encode_line_control(7, Parity::Even, StopBits::Two).unwrap());
+        assert!(encode_line_control(9, Parity::None, StopBits::One).is_err());
+    }
+
+    #[test]
+    fn it_decodes_modem_status() {
+        let status = ModemStatus::decode(&[0x31]).unwrap();
+
+        assert!(status.dtr());
+        assert!(status.cts());
+        assert!(status.dsr());
+        assert!(!status.dcd());
+    }
+}
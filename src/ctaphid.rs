@@ -0,0 +1,469 @@
+//! FIDO CTAPHID transport, which carries CTAP1 (U2F) and CTAP2 messages to security keys.
+//!
+//! Messages are split into 64-byte HID reports: an initialization packet with the command and
+//! length, followed by continuation packets with sequence numbers. Each application gets a
+//! channel from the device with INIT, so several applications can share a security key.
+
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::task;
+
+use futures_core::Stream;
+
+use device::Device;
+use error::Error;
+use hid::{HidDevice, InputReports, ReportWriteFuture};
+
+/// Echoes the payload.
+pub const PING: u8 = 0x01;
+
+/// Sends a CTAP1/U2F message.
+pub const MSG: u8 = 0x03;
+
+/// Locks the device to the channel for a number of seconds.
+pub const LOCK: u8 = 0x04;
+
+/// Allocates a channel.
+pub const INIT: u8 = 0x06;
+
+/// Makes the device blink or otherwise identify itself.
+pub const WINK: u8 = 0x08;
+
+/// Sends a CTAP2 CBOR message.
+pub const CBOR: u8 = 0x10;
+
+/// Cancels the pending request on the channel.
+pub const CANCEL: u8 = 0x11;
+
+/// Sent by the device while it processes a request, e.g., waiting for user presence.
+pub const KEEPALIVE: u8 = 0x3B;
+
+/// Sent by the device instead of a response when a request fails.
+pub const ERROR: u8 = 0x3F;
+
+/// The channel used for INIT before a channel is allocated.
+pub const BROADCAST_CHANNEL: u32 = 0xFFFF_FFFF;
+
+/// The usage page of FIDO devices.
+const FIDO_USAGE_PAGE: u16 = 0xF1D0;
+
+/// Size of the HID reports, and so of the packets.
+const PACKET_SIZE: usize = 64;
+
+/// Payload bytes in an initialization packet.
+const INIT_DATA_SIZE: usize = PACKET_SIZE - 7;
+
+/// Payload bytes in a continuation packet.
+const CONT_DATA_SIZE: usize = PACKET_SIZE - 5;
+
+/// The largest payload a message can have: one initialization packet and 128 continuation
+/// packets.
+pub const MAX_PAYLOAD: usize = INIT_DATA_SIZE + 128 * CONT_DATA_SIZE;
+
+/// Length of the nonce sent with INIT.
+const NONCE_LENGTH: usize = 8;
+
+/// Length of an INIT response.
+const INIT_RESPONSE_LENGTH: usize = 17;
+
+/// Splits a message into packets.
+///
+/// Returns `Error::InvalidParam` if the payload is longer than [`MAX_PAYLOAD`](constant.MAX_PAYLOAD.html).
+pub fn encode_message(channel: u32, command: u8, payload: &[u8]) -> ::Result<Vec<Vec<u8>>> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(Error::InvalidParam);
+    }
+
+    let mut packet = Vec::with_capacity(PACKET_SIZE);
+    packet.extend_from_slice(&channel.to_be_bytes());
+    packet.push(0x80 | command);
+    packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+
+    let (first, rest) = payload.split_at(payload.len().min(INIT_DATA_SIZE));
+    packet.extend_from_slice(first);
+    packet.resize(PACKET_SIZE, 0);
+
+    let mut packets = vec![packet];
+
+    for (sequence, chunk) in rest.chunks(CONT_DATA_SIZE).enumerate() {
+        let mut packet = Vec::with_capacity(PACKET_SIZE);
+        packet.extend_from_slice(&channel.to_be_bytes());
+        packet.push(sequence as u8);
+        packet.extend_from_slice(chunk);
+        packet.resize(PACKET_SIZE, 0);
+        packets.push(packet);
+    }
+
+    Ok(packets)
+}
+
+/// A message received from the device.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub struct Message {
+    /// The command, without the bit that marks initialization packets.
+    pub command: u8,
+
+    /// The payload.
+    pub payload: Vec<u8>,
+}
+
+/// Reassembles the messages of one channel from packets.
+#[derive(Debug,Clone)]
+pub struct Reassembler {
+    channel: u32,
+    message: Option<(u8, usize, Vec<u8>)>,
+    sequence: u8,
+}
+
+impl Reassembler {
+    /// Creates a reassembler for messages on a channel.
+    pub fn new(channel: u32) -> Reassembler {
+        Reassembler { channel, message: None, sequence: 0 }
+    }
+
+    /// Adds a packet, returning the message once all of its packets have arrived.
+    ///
+    /// Packets for other channels, and continuation packets without a preceding
+    /// initialization packet, are ignored. Returns `Error::Io` if a continuation packet is out
+    /// of sequence.
+    pub fn push(&mut self, packet: &[u8]) -> ::Result<Option<Message>> {
+        if packet.len() < 7 || u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]) != self.channel {
+            return Ok(None);
+        }
+
+        if packet[4] & 0x80 != 0 {
+            let length = u16::from_be_bytes([packet[5], packet[6]]) as usize;
+            let data = &packet[7..];
+            self.message = Some((packet[4] & 0x7F, length, data[..length.min(data.len())].to_vec()));
+            self.sequence = 0;
+        } else {
+            let (_, length, ref mut payload) = match self.message {
+                Some(ref mut message) => message,
+                None => return Ok(None),
+            };
+
+            if packet[4] != self.sequence {
+                self.message = None;
+                return Err(Error::Io);
+            }
+
+            self.sequence = self.sequence.wrapping_add(1);
+
+            let data = &packet[5..];
+            let remaining = *length - payload.len();
+            payload.extend_from_slice(&data[..remaining.min(data.len())]);
+        }
+
+        match self.message {
+            Some((_, length, ref payload)) if payload.len() >= length => {},
+            _ => return Ok(None),
+        }
+
+        Ok(self.message.take().map(|(command, _, payload)| Message { command, payload }))
+    }
+}
+
+/// The response to INIT.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct InitResponse {
+    /// The allocated channel.
+    pub channel: u32,
+
+    /// The CTAPHID protocol version.
+    pub protocol_version: u8,
+
+    /// The major, minor and build version of the device.
+    pub device_version: (u8, u8, u8),
+
+    /// The capability flags.
+    pub capabilities: u8,
+}
+
+impl InitResponse {
+    /// Decodes an INIT response payload, excluding the nonce.
+    fn decode(payload: &[u8]) -> ::Result<InitResponse> {
+        if payload.len() < INIT_RESPONSE_LENGTH - NONCE_LENGTH {
+            return Err(Error::InvalidParam);
+        }
+
+        Ok(InitResponse {
+            channel: u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]),
+            protocol_version: payload[4],
+            device_version: (payload[5], payload[6], payload[7]),
+            capabilities: payload[8],
+        })
+    }
+
+    /// Indicates if the device implements WINK.
+    pub fn supports_wink(&self) -> bool {
+        self.capabilities & 0x01 != 0
+    }
+
+    /// Indicates if the device implements CBOR, i.e., CTAP2.
+    pub fn supports_cbor(&self) -> bool {
+        self.capabilities & 0x04 != 0
+    }
+
+    /// Indicates if the device implements MSG, i.e., CTAP1.
+    pub fn supports_msg(&self) -> bool {
+        self.capabilities & 0x08 == 0
+    }
+}
+
+/// A FIDO security key reached through its HID interface.
+///
+/// Call [`init`](#method.init) to allocate a channel before sending other commands. One
+/// transaction can be in progress at a time.
+pub struct CtapHid {
+    hid: HidDevice,
+    channel: AtomicU32,
+}
+
+impl CtapHid {
+    /// Opens a device and claims its FIDO HID interface.
+    ///
+    /// ## Errors
+    ///
+    /// * `NotFound` if the interface isn't a HID interface with the FIDO usage page.
+    /// * Any error returned by [`HidDevice::open`](../hid/struct.HidDevice.html#method.open).
+    pub fn open(device: &Device, interface: u8) -> ::Result<CtapHid> {
+        CtapHid::new(HidDevice::open(device, interface)?)
+    }
+
+    /// Uses an open HID interface as a FIDO security key.
+    ///
+    /// Returns `Error::NotFound` if the report descriptor has no FIDO application collection.
+    pub fn new(hid: HidDevice) -> ::Result<CtapHid> {
+        let is_fido = hid.report_descriptor().fields().iter()
+            .any(|field| field.application.is_some_and(|usage| usage.page == FIDO_USAGE_PAGE));
+
+        if !is_fido {
+            return Err(Error::NotFound);
+        }
+
+        Ok(CtapHid { hid, channel: AtomicU32::new(BROADCAST_CHANNEL) })
+    }
+
+    /// Returns the HID interface.
+    pub fn hid(&self) -> &HidDevice {
+        &self.hid
+    }
+
+    /// Returns the allocated channel, or `None` before [`init`](#method.init) has completed.
+    pub fn channel(&self) -> Option<u32> {
+        match self.channel.load(Ordering::Relaxed) {
+            BROADCAST_CHANNEL => None,
+            channel => Some(channel),
+        }
+    }
+
+    /// Allocates a channel with INIT on the broadcast channel.
+    ///
+    /// Responses to other applications' INIT requests are recognized by their nonce and
+    /// skipped. The allocated channel is used by the following transactions.
+    pub fn init<'a>(&'a self) -> InitFuture<'a> {
+        let mut nonce = [0; NONCE_LENGTH];
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(0);
+        nonce.copy_from_slice(&hasher.finish().to_le_bytes());
+
+        InitFuture { device: self, transaction: self.transaction_on(BROADCAST_CHANNEL, INIT, &nonce), nonce }
+    }
+
+    /// Sends a message on the allocated channel and waits for the response.
+    ///
+    /// Keepalive messages are skipped. An `ERROR` response is returned as a message like any
+    /// other, with the CTAPHID error code as payload.
+    pub fn transaction<'a>(&'a self, command: u8, payload: &[u8]) -> TransactionFuture<'a> {
+        self.transaction_on(self.channel.load(Ordering::Relaxed), command, payload)
+    }
+
+    /// Cancels the pending CBOR request on the allocated channel.
+    ///
+    /// The pending transaction then resolves to an `ERROR` message.
+    pub fn cancel(&self) -> ReportWriteFuture {
+        let packets = encode_message(self.channel.load(Ordering::Relaxed), CANCEL, &[]).expect("empty payload fits in a packet");
+        self.hid.write_output_report(0, &packets[0])
+    }
+
+    fn transaction_on<'a>(&'a self, channel: u32, command: u8, payload: &[u8]) -> TransactionFuture<'a> {
+        let state = match encode_message(channel, command, payload) {
+            Ok(mut packets) => {
+                packets.reverse();
+                TransactionState::Sending(packets, None)
+            },
+            Err(e) => TransactionState::Failed(e),
+        };
+
+        TransactionFuture { device: self, reassembler: Reassembler::new(channel), state }
+    }
+}
+
+/// Future returned by [`CtapHid::transaction`](struct.CtapHid.html#method.transaction).
+pub struct TransactionFuture<'a> {
+    device: &'a CtapHid,
+    reassembler: Reassembler,
+    state: TransactionState<'a>,
+}
+
+enum TransactionState<'a> {
+    Failed(Error),
+
+    /// The packets left to send, in reverse order, and the pending write.
+    Sending(Vec<Vec<u8>>, Option<ReportWriteFuture>),
+
+    Receiving(InputReports<'a>),
+    Done,
+}
+
+impl<'a> TransactionFuture<'a> {
+    fn finish(&mut self, result: ::Result<Message>) -> task::Poll<::Result<Message>> {
+        self.state = TransactionState::Done;
+        task::Poll::Ready(result)
+    }
+}
+
+impl<'a> Future for TransactionFuture<'a> {
+    type Output = ::Result<Message>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            let next = match this.state {
+                TransactionState::Failed(ref e) => {
+                    let e = e.clone();
+                    return this.finish(Err(e));
+                },
+                TransactionState::Sending(ref mut packets, ref mut pending) => {
+                    if let Some(ref mut future) = *pending {
+                        match Pin::new(future).poll(cx) {
+                            task::Poll::Pending => return task::Poll::Pending,
+                            task::Poll::Ready(Ok(_)) => {},
+                            task::Poll::Ready(Err(e)) => return this.finish(Err(e)),
+                        }
+                    }
+
+                    match packets.pop() {
+                        Some(packet) => {
+                            *pending = Some(this.device.hid.write_output_report(0, &packet));
+                            continue;
+                        },
+                        None => TransactionState::Receiving(this.device.hid.input_reports()),
+                    }
+                },
+                TransactionState::Receiving(ref mut reports) => {
+                    let report = match Pin::new(reports).poll_next(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(Some(Ok(report))) => report,
+                        task::Poll::Ready(Some(Err(e))) => return this.finish(Err(e)),
+                        task::Poll::Ready(None) => return this.finish(Err(Error::Io)),
+                    };
+
+                    match this.reassembler.push(&report.data) {
+                        Ok(Some(ref message)) if message.command == KEEPALIVE => {},
+                        Ok(Some(message)) => return this.finish(Ok(message)),
+                        Ok(None) => {},
+                        Err(e) => return this.finish(Err(e)),
+                    }
+
+                    continue;
+                },
+                TransactionState::Done => return task::Poll::Ready(Err(Error::PolledAfterCompletion)),
+            };
+
+            this.state = next;
+        }
+    }
+}
+
+/// Future returned by [`CtapHid::init`](struct.CtapHid.html#method.init).
+pub struct InitFuture<'a> {
+    device: &'a CtapHid,
+    transaction: TransactionFuture<'a>,
+    nonce: [u8; NONCE_LENGTH],
+}
+
+impl<'a> Future for InitFuture<'a> {
+    type Output = ::Result<InitResponse>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            let message = match Pin::new(&mut this.transaction).poll(cx) {
+                task::Poll::Pending => return task::Poll::Pending,
+                task::Poll::Ready(result) => result?,
+            };
+
+            if message.command != INIT {
+                return task::Poll::Ready(Err(Error::Io));
+            }
+
+            // A response to another application's INIT, so keep listening
+            if !message.payload.starts_with(&this.nonce) {
+                this.transaction.state = TransactionState::Receiving(this.device.hid.input_reports());
+                continue;
+            }
+
+            let response = InitResponse::decode(&message.payload[NONCE_LENGTH..])?;
+            this.device.channel.store(response.channel, Ordering::Relaxed);
+            return task::Poll::Ready(Ok(response));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_fragments_messages() {
+        let payload = (0..100).map(|i| i as u8).collect::<Vec<_>>();
+        let packets = encode_message(0x0102_0304, CBOR, &payload).unwrap();
+
+        assert_eq!(2, packets.len());
+        assert_eq!(&[0x01, 0x02, 0x03, 0x04, 0x90, 0x00, 100, 0, 1], &packets[0][..9]);
+        assert_eq!(&[0x01, 0x02, 0x03, 0x04, 0x00, 57], &packets[1][..6]);
+        assert!(packets.iter().all(|packet| packet.len() == PACKET_SIZE));
+        assert!(encode_message(1, CBOR, &vec![0; MAX_PAYLOAD + 1]).is_err());
+    }
+
+    #[test]
+    fn it_reassembles_messages() {
+        let payload = (0..200).map(|i| i as u8).collect::<Vec<_>>();
+        let mut reassembler = Reassembler::new(7);
+        let mut message = None;
+
+        reassembler.push(&encode_message(8, MSG, &[1, 2, 3]).unwrap()[0]).unwrap();
+        for packet in encode_message(7, MSG, &payload).unwrap() {
+            message = reassembler.push(&packet).unwrap();
+        }
+
+        assert_eq!(Some(Message { command: MSG, payload }), message);
+    }
+
+    #[test]
+    fn it_rejects_out_of_sequence_packets() {
+        let packets = encode_message(7, MSG, &[0; 200]).unwrap();
+        let mut reassembler = Reassembler::new(7);
+
+        reassembler.push(&packets[0]).unwrap();
+        assert!(reassembler.push(&packets[2]).is_err());
+    }
+
+    #[test]
+    fn it_decodes_init_response() {
+        let response = InitResponse::decode(&[0x00, 0x00, 0x00, 0x2A, 0x02, 0x05, 0x01, 0x00, 0x05]).unwrap();
+
+        assert_eq!(0x2A, response.channel);
+        assert_eq!((5, 1, 0), response.device_version);
+        assert!(response.supports_wink());
+        assert!(response.supports_cbor());
+        assert!(response.supports_msg());
+    }
+}
@@ -0,0 +1,83 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Future that is ready after a given duration.
+///
+/// The crate doesn't depend on a particular async runtime, so with the `event-thread` feature the
+/// wakeup comes from a helper thread that is started on the first poll. Without it, no thread is
+/// spawned and the future wakes itself on each poll until the deadline. This is only meant for
+/// the occasional short waits that some class protocols require between requests.
+pub struct Delay {
+    deadline: Instant,
+    waker: Option<Arc<Mutex<Option<task::Waker>>>>,
+}
+
+impl Delay {
+    /// Creates a future that is ready after `duration`.
+    pub fn new(duration: Duration) -> Delay {
+        Delay { deadline: Instant::now() + duration, waker: None }
+    }
+}
+
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<()> {
+        let this = self.get_mut();
+        let now = Instant::now();
+
+        if now >= this.deadline {
+            return task::Poll::Ready(());
+        }
+
+        if !cfg!(feature = "event-thread") {
+            cx.waker().wake_by_ref();
+            return task::Poll::Pending;
+        }
+
+        match this.waker {
+            Some(ref waker) => *waker.lock().unwrap() = Some(cx.waker().clone()),
+            None => {
+                let waker = Arc::new(Mutex::new(Some(cx.waker().clone())));
+                let thread_waker = waker.clone();
+                let duration = this.deadline - now;
+
+                thread::spawn(move || {
+                    thread::sleep(duration);
+
+                    if let Some(waker) = thread_waker.lock().unwrap().take() {
+                        waker.wake();
+                    }
+                });
+
+                this.waker = Some(waker);
+            },
+        }
+
+        task::Poll::Pending
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    extern crate futures;
+
+    use super::*;
+
+    #[test]
+    fn it_waits_for_duration() {
+        let start = Instant::now();
+        futures::executor::block_on(Delay::new(Duration::from_millis(20)));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn it_is_ready_immediately_for_zero_duration() {
+        futures::executor::block_on(Delay::new(Duration::from_millis(0)));
+    }
+}
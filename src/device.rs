@@ -1,118 +1,152 @@
 use std::sync::Arc;
-use libusb::*;
-use std::mem::MaybeUninit;
 
+use backend::DeviceRef;
 use context::ContextAsync;
 use device_handle::{self, DeviceHandle};
-use device_descriptor::{self, DeviceDescriptor};
-use config_descriptor::{self, ConfigDescriptor};
-use fields::{self, Speed};
+use device_descriptor::DeviceDescriptor;
+use config_descriptor::ConfigDescriptor;
+use fields::{ClassCode, Speed};
+use probe::{self, Function};
+use snapshot::DescriptorSnapshot;
+use error::{self, Operation};
+use hints;
+#[cfg(all(feature = "udev", target_os = "linux"))]
+use udev;
 
 
 /// A reference to a USB device.
 pub struct Device {
     context: Arc<ContextAsync>,
-    device: *mut libusb_device,
+    device: DeviceRef,
 }
 
 impl Drop for Device {
     /// Releases the device reference.
     fn drop(&mut self) {
         unsafe {
-            libusb_unref_device(self.device);
+            self.context.backend.unref_device(self.device);
         }
     }
 }
 
-unsafe impl Send for Device {}
-unsafe impl Sync for Device {}
-
 impl Device {
     /// Reads the device descriptor.
     pub fn device_descriptor(&self) -> ::Result<DeviceDescriptor> {
-        let mut descriptor = MaybeUninit::< libusb_device_descriptor>::uninit();
-
         // since libusb 1.0.16, this function always succeeds
-        try_unsafe!(libusb_get_device_descriptor(self.device, 
-                                                 descriptor.as_mut_ptr()));
-        let descriptor = unsafe{descriptor.assume_init()};
-        Ok(device_descriptor::from_libusb(descriptor))
+        unsafe { self.context.backend.device_descriptor(self.device) }.map_err(error::from_libusb)
     }
 
     /// Reads a configuration description for a given index.
     pub fn config_descriptor(&self, config_index: u8) -> ::Result<ConfigDescriptor> {
-        let mut config = 
-            MaybeUninit::<*const libusb_config_descriptor>::uninit();
-
-        try_unsafe!(libusb_get_config_descriptor(self.device, config_index, 
-                                                 config.as_mut_ptr()));
-        let config = unsafe{config.assume_init()};
-        Ok(unsafe { config_descriptor::from_libusb(config) })
+        unsafe { self.context.backend.config_descriptor(self.device, config_index) }.map_err(error::from_libusb)
     }
     
     /// Reads a configuration descriptor for a given configuration value.
     pub fn config_descriptor_by_value(&self, config_value: u8)
                                       -> ::Result<ConfigDescriptor> {
-        let mut config = 
-            MaybeUninit::<*const libusb_config_descriptor>::uninit();
-        
-        try_unsafe!(libusb_get_config_descriptor_by_value(self.device,
-                                                          config_value, 
-                                                          config.as_mut_ptr()));
-        let config = unsafe{config.assume_init()};
-        Ok(unsafe { config_descriptor::from_libusb(config) })
+        unsafe { self.context.backend.config_descriptor_by_value(self.device, config_value) }
+            .map_err(error::from_libusb)
     }
 
     /// Reads the configuration descriptor for the current configuration.
     pub fn active_config_descriptor(&self) -> ::Result<ConfigDescriptor> {
-        let mut config = 
-            MaybeUninit::<*const libusb_config_descriptor>::uninit();
+        unsafe { self.context.backend.active_config_descriptor(self.device) }.map_err(error::from_libusb)
+    }
+
+    /// Reads the device descriptor and all configuration descriptors.
+    ///
+    /// Compare snapshots taken before and after the device reconnects with
+    /// [`DescriptorSnapshot::changes_since`](struct.DescriptorSnapshot.html#method.changes_since).
+    pub fn descriptor_snapshot(&self) -> ::Result<DescriptorSnapshot> {
+        let device = self.device_descriptor()?;
+
+        let configs = (0..device.num_configurations()).map(|index| {
+            self.config_descriptor(index)
+        }).collect::<::Result<Vec<_>>>()?;
+
+        Ok(DescriptorSnapshot::new(device, configs))
+    }
+
+    /// Returns the interface and alternate setting numbers of the active configuration's
+    /// alternate settings with the given class code.
+    pub fn interfaces_of_class(&self, class_code: ClassCode) -> ::Result<Vec<(u8, u8)>> {
+        let config = self.active_config_descriptor()?;
+
+        Ok(config.interfaces_of_class(class_code).iter().map(|setting| {
+            (setting.interface_number(), setting.setting_number())
+        }).collect())
+    }
 
-        try_unsafe!(libusb_get_active_config_descriptor(self.device,
-                                                        config.as_mut_ptr()));
-        let config = unsafe{config.assume_init()};
-        Ok(unsafe { config_descriptor::from_libusb(config) })
+    /// Finds the functions of the active configuration that the built-in class modules can
+    /// drive, e.g., "interface 0/1: CDC-ACM" and "interface 2: HID".
+    ///
+    /// Only the cached descriptors are inspected, so the device isn't opened. Each function
+    /// can be opened with [`Function::open`](enum.Function.html#method.open).
+    pub fn probe_functions(&self) -> ::Result<Vec<Function>> {
+        let device = self.device_descriptor()?;
+        let config = self.active_config_descriptor()?;
+        Ok(probe::probe_functions(&device, &config))
     }
 
     /// Returns the number of the bus that the device is connected to.
     pub fn bus_number(&self) -> u8 {
         unsafe {
-            libusb_get_bus_number(self.device)
+            self.context.backend.bus_number(self.device)
+        }
+    }
+
+    /// Returns the number of the port of the parent hub that the device is connected to, or 0
+    /// if it's unknown.
+    pub fn port_number(&self) -> u8 {
+        unsafe {
+            self.context.backend.port_number(self.device)
         }
     }
 
     /// Returns the device's address on the bus that it's connected to.
     pub fn address(&self) -> u8 {
         unsafe {
-            libusb_get_device_address(self.device)
+            self.context.backend.device_address(self.device)
         }
     }
 
+    /// Returns what udev knows about the device, e.g., the serial number it identified the
+    /// device by, its `by-id` links and the drivers bound to its interfaces.
+    ///
+    /// Fails with `NotFound` if udev doesn't know the device.
+    #[cfg(all(feature = "udev", target_os = "linux"))]
+    pub fn udev_info(&self) -> ::Result<udev::UdevInfo> {
+        udev::lookup(self.bus_number(), self.address())
+    }
+
     /// Returns the device's connection speed.
     pub fn speed(&self) -> Speed {
-        fields::speed_from_libusb(unsafe {
-            libusb_get_device_speed(self.device)
-        })
+        unsafe {
+            self.context.backend.device_speed(self.device)
+        }
     }
 
     /// Opens the device.
     pub fn open(&self) -> ::Result<DeviceHandle> {
-        let mut handle = MaybeUninit::<*mut libusb_device_handle>::uninit();
-
-        try_unsafe!(libusb_open(self.device, handle.as_mut_ptr()));
-        ContextAsync::device_opened(&self.context);
-        let handle = unsafe {handle.assume_init()};
-        Ok(unsafe { device_handle::from_libusb(&self.context, handle) })
+        let handle = match unsafe { self.context.backend.open(self.device) } {
+            Ok(handle) => handle,
+            Err(err) => {
+                let error = error::from_operation(Operation::Open, err);
+                return Err(hints::open_failed(error, self.bus_number(), self.address()));
+            },
+        };
+        self.context.event_thread.acquire(&self.context);
+        Ok(unsafe { device_handle::from_backend(&self.context, handle) })
     }
 }
 
 #[doc(hidden)]
-pub unsafe fn from_libusb(context: &Arc<ContextAsync>,
-                              device: *mut libusb_device) -> Device {
-    libusb_ref_device(device);
+pub unsafe fn from_backend(context: &Arc<ContextAsync>, device: DeviceRef) -> Device {
+    context.backend.ref_device(device);
 
     Device {
         context: context.clone(),
         device: device,
     }
 }
+
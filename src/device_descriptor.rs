@@ -1,8 +1,11 @@
 use std::fmt;
+use std::hash::{Hash, Hasher};
 
 use libusb::*;
 
-use fields::Version;
+use fields::{ClassCode, Version};
+use device_handle::DeviceHandle;
+use string_descriptor::{self, StringDescriptorFuture};
 
 /// Describes a device.
 pub struct DeviceDescriptor {
@@ -44,9 +47,32 @@ impl DeviceDescriptor {
         }
     }
 
+    /// Reads the manufacturer name from the device in its first supported language.
+    ///
+    /// The future resolves to `Err(Error::InvalidParam)` if the device has no manufacturer
+    /// string.
+    pub fn manufacturer_string<'h>(&self, handle: &'h DeviceHandle) -> StringDescriptorFuture<'h> {
+        string_descriptor::read_string(handle, None, self.manufacturer_string_index())
+    }
+
+    /// Reads the product name from the device in its first supported language.
+    ///
+    /// The future resolves to `Err(Error::InvalidParam)` if the device has no product string.
+    pub fn product_string<'h>(&self, handle: &'h DeviceHandle) -> StringDescriptorFuture<'h> {
+        string_descriptor::read_string(handle, None, self.product_string_index())
+    }
+
+    /// Reads the serial number from the device in its first supported language.
+    ///
+    /// The future resolves to `Err(Error::InvalidParam)` if the device has no serial number
+    /// string.
+    pub fn serial_number_string<'h>(&self, handle: &'h DeviceHandle) -> StringDescriptorFuture<'h> {
+        string_descriptor::read_string(handle, None, self.serial_number_string_index())
+    }
+
     /// Returns the device's class code.
-    pub fn class_code(&self) -> u8 {
-        self.descriptor.bDeviceClass
+    pub fn class_code(&self) -> ClassCode {
+        ClassCode::from(self.descriptor.bDeviceClass)
     }
 
     /// Returns the device's sub class code.
@@ -80,6 +106,31 @@ impl DeviceDescriptor {
     }
 }
 
+impl DeviceDescriptor {
+    fn key(&self) -> [u16; 14] {
+        let d = &self.descriptor;
+
+        [d.bLength as u16, d.bDescriptorType as u16, d.bcdUSB, d.bDeviceClass as u16,
+         d.bDeviceSubClass as u16, d.bDeviceProtocol as u16, d.bMaxPacketSize0 as u16,
+         d.idVendor, d.idProduct, d.bcdDevice, d.iManufacturer as u16, d.iProduct as u16,
+         d.iSerialNumber as u16, d.bNumConfigurations as u16]
+    }
+}
+
+impl PartialEq for DeviceDescriptor {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for DeviceDescriptor {}
+
+impl Hash for DeviceDescriptor {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
+
 impl fmt::Debug for DeviceDescriptor {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         let mut debug = fmt.debug_struct("DeviceDescriptor");
@@ -111,7 +162,7 @@ pub fn from_libusb(device: libusb_device_descriptor) -> DeviceDescriptor {
 
 #[cfg(test)]
 mod test {
-    use fields::Version;
+    use fields::{ClassCode, Version};
 
     #[test]
     fn it_has_usb_version() {
@@ -155,7 +206,8 @@ mod test {
 
     #[test]
     fn it_has_class_code() {
-        assert_eq!(42, super::from_libusb(device_descriptor!(bDeviceClass: 42)).class_code());
+        assert_eq!(ClassCode::Unknown(42), super::from_libusb(device_descriptor!(bDeviceClass: 42)).class_code());
+        assert_eq!(ClassCode::Hub, super::from_libusb(device_descriptor!(bDeviceClass: 0x09)).class_code());
     }
 
     #[test]
@@ -183,6 +235,13 @@ mod test {
         assert_eq!(42, super::from_libusb(device_descriptor!(bMaxPacketSize0: 42)).max_packet_size());
     }
 
+    #[test]
+    fn it_compares_all_fields() {
+        assert_eq!(super::from_libusb(device_descriptor!(idVendor: 42)), super::from_libusb(device_descriptor!(idVendor: 42)));
+        assert!(super::from_libusb(device_descriptor!(idVendor: 42)) != super::from_libusb(device_descriptor!(idVendor: 43)));
+        assert!(super::from_libusb(device_descriptor!(bcdDevice: 0x0100)) != super::from_libusb(device_descriptor!(bcdDevice: 0x0101)));
+    }
+
     #[test]
     fn it_has_num_configurations() {
         assert_eq!(3, super::from_libusb(device_descriptor!(bNumConfigurations: 3)).num_configurations());
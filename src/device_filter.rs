@@ -0,0 +1,215 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use device::Device;
+use device_descriptor::DeviceDescriptor;
+use fields::ClassCode;
+
+/// How long reading the serial number of a device may take, per request.
+const SERIAL_NUMBER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Which devices to use, shared by
+/// [`Context::devices_matching`](struct.Context.html#method.devices_matching),
+/// [`Context::hotplug_events_matching`](struct.Context.html#method.hotplug_events_matching) and
+/// [`Context::register_driver_matching`](struct.Context.html#method.register_driver_matching).
+///
+/// A new filter matches every device. Each criterion narrows it down, and repeating the vendor,
+/// product or class criteria adds alternatives:
+///
+/// ```
+/// use libusb_async::{ClassCode, DeviceFilter};
+///
+/// // Any FTDI device, or a Raspberry Pi Pico, as long as it's a CDC device
+/// let filter = DeviceFilter::new()
+///     .vendor(0x0403)
+///     .product(0x2E8A, 0x000A)
+///     .class(ClassCode::Communications);
+/// ```
+///
+/// The class matches the device class or the class of an interface of the active
+/// configuration. Matching a serial number opens the device to read it, so the other criteria
+/// are checked first.
+#[derive(Clone,Default)]
+pub struct DeviceFilter {
+    // Vendor IDs, with a product ID or any product
+    ids: Vec<(u16, Option<u16>)>,
+    classes: Vec<ClassCode>,
+    serial_number: Option<SerialNumber>,
+    bus_number: Option<u8>,
+    port_number: Option<u8>,
+}
+
+#[derive(Clone)]
+enum SerialNumber {
+    Exact(String),
+    Matching(Arc<dyn Fn(&str) -> bool + Send + Sync>),
+}
+
+impl DeviceFilter {
+    /// A filter that matches every device.
+    pub fn new() -> DeviceFilter {
+        DeviceFilter::default()
+    }
+
+    /// Adds a vendor whose every product matches.
+    pub fn vendor(mut self, vendor_id: u16) -> DeviceFilter {
+        self.ids.push((vendor_id, None));
+        self
+    }
+
+    /// Adds a product of a vendor.
+    pub fn product(mut self, vendor_id: u16, product_id: u16) -> DeviceFilter {
+        self.ids.push((vendor_id, Some(product_id)));
+        self
+    }
+
+    /// Adds a class of the device or of one of its interfaces.
+    pub fn class(mut self, class_code: ClassCode) -> DeviceFilter {
+        self.classes.push(class_code);
+        self
+    }
+
+    /// Only matches the device with a serial number.
+    pub fn serial_number(mut self, serial_number: &str) -> DeviceFilter {
+        self.serial_number = Some(SerialNumber::Exact(serial_number.to_owned()));
+        self
+    }
+
+    /// Only matches devices whose serial number is accepted by `matches`, e.g., a regular
+    /// expression.
+    pub fn serial_number_matching<F>(mut self, matches: F) -> DeviceFilter
+        where F: Fn(&str) -> bool + Send + Sync + 'static
+    {
+        self.serial_number = Some(SerialNumber::Matching(Arc::new(matches)));
+        self
+    }
+
+    /// Only matches devices on a bus.
+    pub fn bus_number(mut self, bus_number: u8) -> DeviceFilter {
+        self.bus_number = Some(bus_number);
+        self
+    }
+
+    /// Only matches devices connected to a port number of their parent hub, usually together
+    /// with the bus number.
+    pub fn port_number(mut self, port_number: u8) -> DeviceFilter {
+        self.port_number = Some(port_number);
+        self
+    }
+
+    /// Returns whether a device matches.
+    ///
+    /// Devices whose descriptors can't be read don't match, nor do devices that can't be opened
+    /// if a serial number is to be matched.
+    pub fn matches(&self, device: &Device) -> bool {
+        if self.bus_number.is_some_and(|bus| bus != device.bus_number()) ||
+            self.port_number.is_some_and(|port| port != device.port_number()) {
+            return false;
+        }
+
+        let descriptor = match device.device_descriptor() {
+            Ok(descriptor) => descriptor,
+            Err(_) => return false,
+        };
+        let interface_classes = if self.classes.is_empty() {
+            Vec::new()
+        } else {
+            device.active_config_descriptor().map(|config| {
+                config.interfaces()
+                    .flat_map(|interface| interface.descriptors().map(|setting| setting.class_code()).collect::<Vec<_>>())
+                    .collect()
+            }).unwrap_or_default()
+        };
+        if !self.matches_descriptor(&descriptor, &interface_classes) {
+            return false;
+        }
+
+        match self.serial_number {
+            Some(ref serial_number) => read_serial_number(device, &descriptor)
+                .is_some_and(|read| serial_number.matches(&read)),
+            None => true,
+        }
+    }
+
+    /// Checks the vendor, product and class against a device descriptor and the classes of the
+    /// interfaces of the device.
+    fn matches_descriptor(&self, descriptor: &DeviceDescriptor, interface_classes: &[ClassCode]) -> bool {
+        let id_matches = self.ids.is_empty() || self.ids.iter().any(|&(vendor_id, product_id)| {
+            vendor_id == descriptor.vendor_id() && product_id.is_none_or(|id| id == descriptor.product_id())
+        });
+        let class_matches = self.classes.is_empty() || self.classes.iter().any(|&class| {
+            class == descriptor.class_code() || interface_classes.contains(&class)
+        });
+
+        id_matches && class_matches
+    }
+}
+
+impl SerialNumber {
+    fn matches(&self, serial_number: &str) -> bool {
+        match *self {
+            SerialNumber::Exact(ref expected) => expected == serial_number,
+            SerialNumber::Matching(ref matches) => matches(serial_number),
+        }
+    }
+}
+
+impl fmt::Debug for DeviceFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let serial_number = self.serial_number.as_ref().map(|serial_number| match *serial_number {
+            SerialNumber::Exact(ref expected) => expected.as_str(),
+            SerialNumber::Matching(_) => "<predicate>",
+        });
+
+        f.debug_struct("DeviceFilter")
+            .field("ids", &self.ids)
+            .field("classes", &self.classes)
+            .field("serial_number", &serial_number)
+            .field("bus_number", &self.bus_number)
+            .field("port_number", &self.port_number)
+            .finish()
+    }
+}
+
+/// Opens a device to read its serial number in its first language.
+fn read_serial_number(device: &Device, descriptor: &DeviceDescriptor) -> Option<String> {
+    let handle = device.open().ok()?;
+    let language = *handle.read_languages(SERIAL_NUMBER_TIMEOUT).ok()?.first()?;
+    handle.read_serial_number_string(language, descriptor, SERIAL_NUMBER_TIMEOUT).ok()
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use device_descriptor::from_libusb;
+
+    #[test]
+    fn it_matches_any_of_the_ids_and_classes() {
+        let ftdi = from_libusb(device_descriptor!(idVendor: 0x0403, idProduct: 0x6001));
+        let pico = from_libusb(device_descriptor!(idVendor: 0x2E8A, idProduct: 0x000A, bDeviceClass: 0xEF));
+
+        assert!(DeviceFilter::new().matches_descriptor(&ftdi, &[]));
+
+        let ids = DeviceFilter::new().vendor(0x0403).product(0x2E8A, 0x000A);
+        assert!(ids.matches_descriptor(&ftdi, &[]) && ids.matches_descriptor(&pico, &[]));
+        assert!(!DeviceFilter::new().product(0x2E8A, 0x0003).matches_descriptor(&pico, &[]));
+
+        let cdc = DeviceFilter::new().vendor(0x2E8A).class(ClassCode::Communications);
+        assert!(!cdc.matches_descriptor(&pico, &[ClassCode::Hid]));
+        assert!(cdc.matches_descriptor(&pico, &[ClassCode::Hid, ClassCode::Communications]));
+        assert!(DeviceFilter::new().class(ClassCode::Miscellaneous).matches_descriptor(&pico, &[]));
+    }
+
+    #[test]
+    fn it_matches_serial_numbers() {
+        assert!(SerialNumber::Exact("A1".to_owned()).matches("A1"));
+        assert!(!SerialNumber::Exact("A1".to_owned()).matches("A10"));
+
+        let filter = DeviceFilter::new().serial_number_matching(|serial| serial.starts_with("E66"));
+        let serial_number = filter.serial_number.as_ref().unwrap();
+        assert!(serial_number.matches("E6614C311B"));
+        assert!(!serial_number.matches("A1"));
+    }
+}
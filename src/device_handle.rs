@@ -1,19 +1,33 @@
-use std::mem::MaybeUninit;
 use std::slice;
 use std::time::Duration;
 use std::sync::{Arc,Mutex,MutexGuard};
 use bit_set::BitSet;
-use libc::{c_int, c_uint, c_uchar};
 use libusb::*;
 
+use backend::{ControlSetup, HandleRef};
 use context::{ContextAsync};
-use error::{self, Error};
-use transfer::{self, Transfer};
+use claimed::ClaimedInterface;
+use device::{self, Device};
+use disconnect::{self, DisconnectFuture};
+use event_thread;
+use metrics::MetricsSnapshot;
+use bulk_stream::BulkStream;
+use read_queue::QueueDepth;
+use buffer;
+use error::{self, Error, Operation};
+use hints;
+use transfer::{self, HandleShared, Transfer};
 use device_descriptor::DeviceDescriptor;
+use parse::{parse_device_descriptor, DEVICE_DESCRIPTOR_LENGTH};
 use config_descriptor::ConfigDescriptor;
 use interface_descriptor::InterfaceDescriptor;
-use fields::{Direction, RequestType, Recipient, request_type};
+use fields::{Direction, EndpointAddress};
 use language::Language;
+use string_descriptor::{self, StringDescriptorFuture};
+use raw_descriptor::{self, RawDescriptorFuture};
+use control::{self, ControlFuture, ControlRequest};
+use bos::MsOs20DescriptorSetInfo;
+use ms_os_20::{self, MsOs20DescriptorSet};
 
 /// A handle to an open USB device.
 pub struct DeviceHandle (Arc<Mutex<DeviceHandleAsync>>);
@@ -29,84 +43,123 @@ impl DeviceHandle
 
 pub struct DeviceHandleAsync {
     context: Arc<ContextAsync>,
-    handle: *mut libusb_device_handle,
+    handle: HandleRef,
     interfaces: BitSet,
+    shared: HandleShared,
 }
 
 impl Drop for DeviceHandle {
     /// Closes the device.
+    ///
+    /// Transfers that are still submitted are cancelled first, and the handle is closed once
+    /// their callbacks have run. When the handle is dropped by a callback, which can't wait for
+    /// the others, it is closed after the events being handled.
     fn drop(&mut self) {
-        let handle = self.handle();
-        unsafe {
-            for iface in handle.interfaces.iter() {
-                libusb_release_interface(handle.handle, iface as c_int);
-            }
-            ContextAsync::device_close(&handle.context,
-                                       || libusb_close(handle.handle));
+        let (context, in_flight) = {
+            let handle = self.handle();
+            (handle.context.clone(), handle.shared.in_flight.clone())
+        };
+
+        // The mutex isn't held while waiting, as the callbacks may need it
+        if event_thread::is_handling_events() {
+            in_flight.cancel(context.backend);
+            let handle = self.0.clone();
+            context.event_thread.defer(move || in_flight.is_empty(), move || close(&handle));
+        } else {
+            in_flight.drain(context.backend);
+            close(&self.0);
         }
     }
 }
 
-unsafe impl Send for DeviceHandle {}
-unsafe impl Sync for DeviceHandle {}
+/// Releases the claimed interfaces of a handle whose transfers are done, and closes it.
+fn close(handle: &Mutex<DeviceHandleAsync>) {
+    let (context, handle, interfaces) = {
+        let handle = handle.lock().unwrap();
+        (handle.context.clone(), handle.handle, handle.interfaces.clone())
+    };
+    unsafe {
+        for iface in interfaces.iter() {
+            context.backend.release_interface(handle, iface as u8);
+        }
+        context.event_thread.release(&context, || context.backend.close(handle));
+    }
+}
 
 impl DeviceHandle {
+    /// Returns the device that the handle is open on.
+    pub fn device(&self) -> Device {
+        let handle = self.handle();
+        unsafe { device::from_backend(&handle.context, handle.context.backend.get_device(handle.handle)) }
+    }
+
     /// Returns the active configuration number.
     pub fn active_configuration(&self) -> ::Result<u8> {
-        let mut config = MaybeUninit::<i32>::uninit();
-
-        try_unsafe!(libusb_get_configuration(self.handle().handle,
-                                             config.as_mut_ptr()));
-        let config = unsafe{config.assume_init()};
-        Ok(config as u8)
+        let handle = self.handle();
+        unsafe { handle.context.backend.get_configuration(handle.handle) }.map_err(error::from_libusb)
     }
 
     /// Sets the device's active configuration.
     pub fn set_active_configuration(&mut self, config: u8) -> ::Result<()> {
-        try_unsafe!(libusb_set_configuration(self.handle().handle, 
-                                             config as c_int));
+        let handle = self.handle();
+        try_unsafe!(handle.context.backend.set_configuration(handle.handle, Some(config)),
+                    Operation::SetConfiguration(Some(config)));
+
+        // The claimed interfaces are back in their default settings, in the new configuration
+        handle.shared.endpoints.clear();
+        if let Ok(config) = active_config_descriptor(&handle) {
+            for iface in handle.interfaces.iter() {
+                handle.shared.endpoints.claim(&config, iface as u8, 0);
+            }
+        }
         Ok(())
     }
 
     /// Puts the device in an unconfigured state.
     pub fn unconfigure(&mut self) -> ::Result<()> {
-        try_unsafe!(libusb_set_configuration(self.handle().handle, -1));
+        let handle = self.handle();
+        try_unsafe!(handle.context.backend.set_configuration(handle.handle, None),
+                    Operation::SetConfiguration(None));
+        handle.shared.endpoints.clear();
         Ok(())
     }
 
     /// Resets the device.
     pub fn reset(&mut self) -> ::Result<()> {
-        try_unsafe!(libusb_reset_device(self.handle().handle));
+        let handle = self.handle();
+        try_unsafe!(handle.context.backend.reset_device(handle.handle), Operation::Reset);
         Ok(())
     }
 
     /// Indicates whether the device has an attached kernel driver.
     ///
-    /// This method is not supported on all platforms.
+    /// This method is not supported on all platforms, and returns `NotSupported` without asking
+    /// `libusb` on those, including Android, where an application claims interfaces with
+    /// `UsbDeviceConnection.claimInterface` instead.
     pub fn kernel_driver_active(&self, iface: u8) -> ::Result<bool> {
-        match unsafe { libusb_kernel_driver_active(self.handle().handle,
-                                                   iface as c_int) } {
-            0 => Ok(false),
-            1 => Ok(true),
-            err => Err(error::from_libusb(err)),
-        }
+        let handle = self.handle();
+        unsafe { handle.context.backend.kernel_driver_active(handle.handle, iface) }.map_err(error::from_libusb)
     }
 
     /// Detaches an attached kernel driver from the device.
     ///
-    /// This method is not supported on all platforms.
+    /// This method is not supported on all platforms, like
+    /// [`kernel_driver_active`](#method.kernel_driver_active).
     pub fn detach_kernel_driver(&mut self, iface: u8) -> ::Result<()> {
-        try_unsafe!(libusb_detach_kernel_driver(self.handle().handle,
-                                                iface as c_int));
+        let handle = self.handle();
+        try_unsafe!(handle.context.backend.detach_kernel_driver(handle.handle, iface),
+                    Operation::DetachKernelDriver(iface));
         Ok(())
     }
 
     /// Attaches a kernel driver to the device.
     ///
-    /// This method is not supported on all platforms.
+    /// This method is not supported on all platforms, like
+    /// [`kernel_driver_active`](#method.kernel_driver_active).
     pub fn attach_kernel_driver(&mut self, iface: u8) -> ::Result<()> {
-        try_unsafe!(libusb_attach_kernel_driver(self.handle().handle, 
-                                                iface as c_int));
+        let handle = self.handle();
+        try_unsafe!(handle.context.backend.attach_kernel_driver(handle.handle, iface),
+                    Operation::AttachKernelDriver(iface));
         Ok(())
     }
 
@@ -116,22 +169,85 @@ impl DeviceHandle {
     /// when the device handle goes out of scope.
     pub fn claim_interface(&mut self, iface: u8) -> ::Result<()> {
         let mut handle = self.handle();
-        try_unsafe!(libusb_claim_interface(handle.handle, iface as c_int));
+        match unsafe { handle.context.backend.claim_interface(handle.handle, iface) } {
+            0 => {},
+            err => {
+                let error = error::from_operation(Operation::ClaimInterface(iface), err);
+                let (bus_number, address) = unsafe {
+                    let backend = handle.context.backend;
+                    let device = backend.get_device(handle.handle);
+                    (backend.bus_number(device), backend.device_address(device))
+                };
+                return Err(hints::claim_failed(error, bus_number, address, iface));
+            },
+        }
         handle.interfaces.insert(iface as usize);
+
+        // Lets transfers check their endpoints against the descriptors, from the default
+        // setting until another one is set
+        if let Ok(config) = active_config_descriptor(&handle) {
+            handle.shared.endpoints.claim(&config, iface, 0);
+        }
         Ok(())
     }
 
     /// Releases a claimed interface.
     pub fn release_interface(&mut self, iface: u8) -> ::Result<()> {
         let mut handle = self.handle();
-        try_unsafe!(libusb_release_interface(handle.handle, iface as c_int));
+        try_unsafe!(handle.context.backend.release_interface(handle.handle, iface),
+                    Operation::ReleaseInterface(iface));
         handle.interfaces.remove(iface as usize);
+        handle.shared.endpoints.release(iface);
         Ok(())
     }
 
     /// Sets an interface's active setting.
     pub fn set_alternate_setting(&mut self, iface: u8, setting: u8) -> ::Result<()> {
-        try_unsafe!(libusb_set_interface_alt_setting(self.handle().handle, iface as c_int, setting as c_int));
+        let handle = self.handle();
+        try_unsafe!(handle.context.backend.set_alternate_setting(handle.handle, iface, setting),
+                    Operation::SetAlternateSetting(iface, setting));
+
+        // Only the endpoints of the new setting can be used
+        if handle.interfaces.contains(iface as usize) {
+            if let Ok(config) = active_config_descriptor(&handle) {
+                handle.shared.endpoints.claim(&config, iface, setting);
+            }
+        }
+        Ok(())
+    }
+
+    /// Clears the halt condition of an endpoint.
+    ///
+    /// This also resets the data toggle of the endpoint, so it must be used rather than a
+    /// CLEAR_FEATURE control request to recover from a stall. The call blocks until the
+    /// request completes.
+    pub fn clear_halt(&self, endpoint: EndpointAddress) -> ::Result<()> {
+        let endpoint = u8::from(endpoint);
+        let handle = self.handle();
+        try_unsafe!(handle.context.backend.clear_halt(handle.handle, endpoint),
+                    Operation::ClearHalt(endpoint));
+        Ok(())
+    }
+
+    /// Allocates bulk streams on SuperSpeed endpoints, and returns how many were allocated.
+    ///
+    /// Stream IDs 1 up to the returned count can then be given to
+    /// [`Transfer::fill_bulk_stream_read`](struct.Transfer.html#method.fill_bulk_stream_read)
+    /// and [`Transfer::fill_bulk_stream_write`](struct.Transfer.html#method.fill_bulk_stream_write).
+    /// The device may support fewer streams than asked for, as told by
+    /// [`EndpointDescriptor::max_streams`](struct.EndpointDescriptor.html#method.max_streams).
+    pub fn alloc_streams(&self, num_streams: u32, endpoints: &[EndpointAddress]) -> ::Result<u32> {
+        let endpoints: Vec<u8> = endpoints.iter().map(|&endpoint| endpoint.into()).collect();
+        let handle = self.handle();
+        unsafe { handle.context.backend.alloc_streams(handle.handle, num_streams, &endpoints) }
+            .map_err(error::from_libusb)
+    }
+
+    /// Frees the bulk streams allocated on endpoints by [`alloc_streams`](#method.alloc_streams).
+    pub fn free_streams(&self, endpoints: &[EndpointAddress]) -> ::Result<()> {
+        let endpoints: Vec<u8> = endpoints.iter().map(|&endpoint| endpoint.into()).collect();
+        let handle = self.handle();
+        try_unsafe!(handle.context.backend.free_streams(handle.handle, &endpoints));
         Ok(())
     }
 
@@ -157,31 +273,25 @@ impl DeviceHandle {
     /// * `Overflow` if the device offered more data.
     /// * `NoDevice` if the device has been disconnected.
     /// * `Io` if the transfer encountered an I/O error.
-    pub fn read_interrupt(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> ::Result<usize> {
-        if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_IN {
+    pub fn read_interrupt(&self, endpoint: EndpointAddress, buf: &mut [u8], timeout: Duration) -> ::Result<usize> {
+        if endpoint.direction() != Direction::In {
             return Err(Error::InvalidParam);
         }
+        let endpoint = u8::from(endpoint);
 
-        let mut transferred = MaybeUninit::<c_int>::uninit();
-        
-        let ptr = buf.as_mut_ptr() as *mut c_uchar;
-        let len = buf.len() as c_int;
-        let timeout_ms = (timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1_000_000) as c_uint;
-        
-        let res = unsafe{
-            libusb_interrupt_transfer(self.handle().handle, endpoint, 
-                                      ptr, len, 
-                                      transferred.as_mut_ptr(),
-                                      timeout_ms) };
-        let transferred = unsafe {transferred.assume_init()};
-        match res 
-        {
+        let mut transferred = 0;
+        let handle = self.handle();
+        let res = unsafe {
+            handle.context.backend.interrupt_transfer(handle.handle, endpoint, buf.as_mut_ptr(), buf.len(),
+                                                   &mut transferred, timeout)
+        };
+        match res {
             0 => {
-                Ok(transferred as usize)
+                Ok(transferred)
             },
             err => {
                 if err == LIBUSB_ERROR_INTERRUPTED && transferred > 0 {
-                    Ok(transferred as usize)
+                    Ok(transferred)
                 }
                 else {
                     Err(error::from_libusb(err))
@@ -210,30 +320,25 @@ impl DeviceHandle {
     /// * `Pipe` if the endpoint halted.
     /// * `NoDevice` if the device has been disconnected.
     /// * `Io` if the transfer encountered an I/O error.
-    pub fn write_interrupt(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> ::Result<usize> {
-        if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_OUT {
+    pub fn write_interrupt(&self, endpoint: EndpointAddress, buf: &[u8], timeout: Duration) -> ::Result<usize> {
+        if endpoint.direction() != Direction::Out {
             return Err(Error::InvalidParam);
         }
+        let endpoint = u8::from(endpoint);
 
-        let mut transferred = MaybeUninit::<c_int>::uninit();
-
-        let ptr = buf.as_ptr() as *mut c_uchar;
-        let len = buf.len() as c_int;
-        let timeout_ms = (timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1_000_000) as c_uint;
-
+        let mut transferred = 0;
+        let handle = self.handle();
         let res = unsafe {
-            libusb_interrupt_transfer(self.handle().handle, endpoint,
-                                      ptr, len,
-                                      transferred.as_mut_ptr(),
-                                      timeout_ms) };
-        let transferred = unsafe {transferred.assume_init()};
+            handle.context.backend.interrupt_transfer(handle.handle, endpoint, buf.as_ptr() as *mut u8, buf.len(),
+                                                   &mut transferred, timeout)
+        };
         match res {
             0 => {
-                Ok(transferred as usize)
+                Ok(transferred)
             },
             err => {
                 if err == LIBUSB_ERROR_INTERRUPTED && transferred > 0 {
-                    Ok(transferred as usize)
+                    Ok(transferred)
                 }
                 else {
                     Err(error::from_libusb(err))
@@ -264,31 +369,25 @@ impl DeviceHandle {
     /// * `Overflow` if the device offered more data.
     /// * `NoDevice` if the device has been disconnected.
     /// * `Io` if the transfer encountered an I/O error.
-    pub fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> ::Result<usize> {
-        if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_IN {
+    pub fn read_bulk(&self, endpoint: EndpointAddress, buf: &mut [u8], timeout: Duration) -> ::Result<usize> {
+        if endpoint.direction() != Direction::In {
             return Err(Error::InvalidParam);
         }
+        let endpoint = u8::from(endpoint);
 
-        let mut transferred = MaybeUninit::<c_int>::uninit();
-
-        let ptr = buf.as_mut_ptr() as *mut c_uchar;
-        let len = buf.len() as c_int;
-        let timeout_ms = (timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1_000_000) as c_uint;
-
+        let mut transferred = 0;
+        let handle = self.handle();
         let res = unsafe {
-            libusb_bulk_transfer(self.handle().handle, endpoint, 
-                                 ptr, len,
-                                 transferred.as_mut_ptr(),
-                                 timeout_ms) };
-        let transferred = unsafe {transferred.assume_init()};
-        
+            handle.context.backend.bulk_transfer(handle.handle, endpoint, buf.as_mut_ptr(), buf.len(),
+                                                   &mut transferred, timeout)
+        };
         match res {
             0 => {
-                Ok(transferred as usize)
+                Ok(transferred)
             },
             err => {
                 if err == LIBUSB_ERROR_INTERRUPTED && transferred > 0 {
-                    Ok(transferred as usize)
+                    Ok(transferred)
                 }
                 else {
                     Err(error::from_libusb(err))
@@ -317,29 +416,25 @@ impl DeviceHandle {
     /// * `Pipe` if the endpoint halted.
     /// * `NoDevice` if the device has been disconnected.
     /// * `Io` if the transfer encountered an I/O error.
-    pub fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> ::Result<usize> {
-        if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_OUT {
+    pub fn write_bulk(&self, endpoint: EndpointAddress, buf: &[u8], timeout: Duration) -> ::Result<usize> {
+        if endpoint.direction() != Direction::Out {
             return Err(Error::InvalidParam);
         }
+        let endpoint = u8::from(endpoint);
 
-        let mut transferred = MaybeUninit::<c_int>::uninit();
-
-        let ptr = buf.as_ptr() as *mut c_uchar;
-        let len = buf.len() as c_int;
-        let timeout_ms = (timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1_000_000) as c_uint;
-        let res =unsafe {
-            libusb_bulk_transfer(self.handle().handle, endpoint, 
-                                 ptr, len,
-                                 transferred.as_mut_ptr(),
-                                 timeout_ms) };
-        let transferred = unsafe{transferred.assume_init()};
+        let mut transferred = 0;
+        let handle = self.handle();
+        let res = unsafe {
+            handle.context.backend.bulk_transfer(handle.handle, endpoint, buf.as_ptr() as *mut u8, buf.len(),
+                                                   &mut transferred, timeout)
+        };
         match res {
             0 => {
-                Ok(transferred as usize)
+                Ok(transferred)
             },
             err => {
                 if err == LIBUSB_ERROR_INTERRUPTED && transferred > 0 {
-                    Ok(transferred as usize)
+                    Ok(transferred)
                 }
                 else {
                     Err(error::from_libusb(err))
@@ -380,21 +475,11 @@ impl DeviceHandle {
             return Err(Error::InvalidParam);
         }
 
-        let ptr = buf.as_mut_ptr() as *mut c_uchar;
-        let len = buf.len() as u16;
-        let timeout_ms = (timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1_000_000) as c_uint;
-
-        let res = unsafe {
-            libusb_control_transfer(self.handle().handle,
-                                    request_type, request,
-                                    value, index, ptr, len, timeout_ms)
-        };
-
-        if res < 0 {
-            Err(error::from_libusb(res))
-        } else {
-            Ok(res as usize)
-        }
+        let setup = ControlSetup { request_type, request, value, index };
+        let handle = self.handle();
+        unsafe {
+            handle.context.backend.control_transfer(handle.handle, setup, buf.as_mut_ptr(), buf.len() as u16, timeout)
+        }.map_err(error::from_libusb)
     }
 
     /// Writes data using a control transfer.
@@ -428,21 +513,45 @@ impl DeviceHandle {
             return Err(Error::InvalidParam);
         }
 
-        let ptr = buf.as_ptr() as *mut c_uchar;
-        let len = buf.len() as u16;
-        let timeout_ms = (timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1_000_000) as c_uint;
+        let setup = ControlSetup { request_type, request, value, index };
+        let handle = self.handle();
+        unsafe {
+            handle.context.backend.control_transfer(handle.handle, setup, buf.as_ptr() as *mut u8, buf.len() as u16, timeout)
+        }.map_err(error::from_libusb)
+    }
+
+    /// Reads data using an asynchronous control transfer.
+    ///
+    /// The parameters are the same as for [`read_control`](#method.read_control), except that
+    /// up to `length` bytes are read into a new buffer. The future resolves to the data
+    /// received from the device, or to the same errors as `read_control`.
+    pub fn read_control_async(&self, request_type: u8, request: u8, value: u16, index: u16, length: u16) -> ControlFuture<Vec<u8>> {
+        if request_type & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_IN {
+            return control::failed(Error::InvalidParam, control::to_vec);
+        }
 
-        let res = unsafe {
-            libusb_control_transfer(self.handle().handle,
-                                    request_type, request,
-                                    value, index, ptr, len, timeout_ms)
-        };
+        control::read(self, request_type, request, value, index, length, control::to_vec)
+    }
 
-        if res < 0 {
-            Err(error::from_libusb(res))
-        } else {
-            Ok(res as usize)
+    /// Writes data using an asynchronous control transfer.
+    ///
+    /// The parameters are the same as for [`write_control`](#method.write_control). The future
+    /// resolves to the number of bytes written, or to the same errors as `write_control`.
+    pub fn write_control_async(&self, request_type: u8, request: u8, value: u16, index: u16, buf: &[u8]) -> ControlFuture<usize> {
+        if request_type & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_OUT {
+            return control::failed(Error::InvalidParam, control::written);
         }
+
+        control::write(self, request_type, request, value, index, buf)
+    }
+
+    /// Sends a control request without a data stage using an asynchronous control transfer,
+    /// e.g., SET_CONFIGURATION or SET_FEATURE.
+    ///
+    /// Only the setup packet is sent. The future resolves to the same errors as
+    /// [`write_control`](#method.write_control).
+    pub fn control_no_data_async(&self, request_type: u8, request: u8, value: u16, index: u16) -> ControlFuture<()> {
+        control::no_data(self, request_type, request, value, index)
     }
 
     /// Reads the languages supported by the device's string descriptors.
@@ -456,10 +565,8 @@ impl DeviceHandle {
             slice::from_raw_parts_mut((&mut buf[..]).as_mut_ptr(), buf.capacity())
         };
 
-        let len = self.read_control(request_type(Direction::In, RequestType::Standard, Recipient::Device),
-                                    LIBUSB_REQUEST_GET_DESCRIPTOR,
-                                    (LIBUSB_DT_STRING as u16) << 8,
-                                    0,
+        let request = control::get_string(0, 0);
+        let len = self.read_control(request.request_type(Direction::In), request.request, request.value, request.index,
                                     buf_slice,
                                     timeout)?;
         
@@ -467,10 +574,7 @@ impl DeviceHandle {
             buf.set_len(len);
         }
 
-        Ok(buf.chunks(2).skip(1).map(|chunk| {
-            let lang_id = chunk[0] as u16 | (chunk[1] as u16) << 8;
-            ::language::from_lang_id(lang_id)
-        }).collect())
+        Ok(string_descriptor::parse_languages(&buf))
     }
 
     /// Reads a string descriptor from the device.
@@ -483,12 +587,8 @@ impl DeviceHandle {
             slice::from_raw_parts_mut((&mut buf[..]).as_mut_ptr(), buf.capacity())
         };
 
-        let len = self.read_control(request_type(Direction::In,
-                                                 RequestType::Standard,
-                                                 Recipient::Device),
-                                    LIBUSB_REQUEST_GET_DESCRIPTOR,
-                                    (LIBUSB_DT_STRING as u16) << 8 | index as u16,
-                                    language.lang_id(),
+        let request = control::get_string(index, language.lang_id());
+        let len = self.read_control(request.request_type(Direction::In), request.request, request.value, request.index,
                                     buf_slice,
                                     timeout)?;
         
@@ -496,11 +596,15 @@ impl DeviceHandle {
             buf.set_len(len);
         }
 
-        let utf16: Vec<u16> = buf.chunks(2).skip(1).map(|chunk| {
-            chunk[0] as u16 | (chunk[1] as u16) << 8
-        }).collect();
+        string_descriptor::parse_string(&buf)
+    }
 
-        String::from_utf16(&utf16[..]).map_err(|_| Error::Other)
+    /// Reads a string descriptor from the device asynchronously.
+    ///
+    /// If `language` is `None`, the device's language table is read first and the first
+    /// language in it is used.
+    pub fn read_string_descriptor_async<'a>(&'a self, language: Option<Language>, index: u8) -> StringDescriptorFuture<'a> {
+        string_descriptor::read_string(self, language, Some(index))
     }
 
     /// Reads the device's manufacturer string descriptor.
@@ -543,35 +647,223 @@ impl DeviceHandle {
         }
     }
 
+    /// Reads the device descriptor from the device asynchronously.
+    ///
+    /// This performs a `GET_DESCRIPTOR` request instead of using the copy cached by libusb,
+    /// e.g., for a handle from [`Context::wrap_sys_device`](struct.Context.html#method.wrap_sys_device).
+    pub fn read_device_descriptor_async(&self) -> ControlFuture<DeviceDescriptor> {
+        let request = ControlRequest::get_descriptor(LIBUSB_DT_DEVICE, 0);
+        control::read(self, request.request_type(Direction::In), request.request, request.value, request.index,
+                      DEVICE_DESCRIPTOR_LENGTH as u16, parse_device_descriptor)
+    }
+
+    /// Reads the raw bytes of a configuration descriptor asynchronously.
+    ///
+    /// This performs `GET_DESCRIPTOR` requests directly on the device instead of using the copy
+    /// cached by libusb, and returns all `wTotalLength` bytes, i.e., the configuration descriptor
+    /// followed by its interface, endpoint and class-specific descriptors. The bytes can be
+    /// inspected as is or parsed with
+    /// [`parse_config_descriptor`](fn.parse_config_descriptor.html).
+    pub fn raw_config_descriptor<'a>(&'a self, index: u8) -> RawDescriptorFuture<'a> {
+        raw_descriptor::read_config(self, index)
+    }
+
+    /// Reads the raw bytes of the device's Binary device Object Store (BOS) asynchronously.
+    ///
+    /// The BOS descriptor and its device capability descriptors are only provided by devices
+    /// with a `bcdUSB` of 2.01 or later. The bytes can be parsed with
+    /// [`parse_bos_descriptor`](fn.parse_bos_descriptor.html).
+    pub fn raw_bos_descriptor<'a>(&'a self) -> RawDescriptorFuture<'a> {
+        raw_descriptor::read_bos(self)
+    }
+
+    /// Reads and parses a Microsoft OS 2.0 descriptor set asynchronously.
+    ///
+    /// `info` is taken from the device's MS OS 2.0 platform capability, see
+    /// [`BosDescriptor::ms_os_20`](struct.BosDescriptor.html#method.ms_os_20).
+    pub fn ms_os_20_descriptor_set(&self, info: &MsOs20DescriptorSetInfo) -> ControlFuture<MsOs20DescriptorSet> {
+        ms_os_20::read_set(self, info)
+    }
+
+    /// Indicates whether the device has been disconnected.
+    ///
+    /// This becomes true when a transfer on the handle ends with `NoDevice`. From then on,
+    /// transfers fail with `Error::Disconnected` without being submitted.
+    pub fn is_disconnected(&self) -> bool {
+        self.handle().shared.disconnect.is_disconnected()
+    }
+
+    /// Returns a future that is ready when the device has been disconnected.
+    ///
+    /// This lets a driver tear down deterministically, e.g., by stopping its streams, rather
+    /// than finding out from the errors of its next transfers. Any number of watchers can wait
+    /// for the same handle.
+    pub fn disconnected(&self) -> DisconnectFuture {
+        disconnect::watch(&self.handle().shared.disconnect)
+    }
+
+    /// Starts recording the throughput, latency and errors of the transfers on the handle, or
+    /// stops it.
+    ///
+    /// Recording is off by default, as it takes a lock when each transfer completes. Enabling it
+    /// starts over from zero. Only transfers submitted while it's on are recorded.
+    pub fn enable_metrics(&self, enabled: bool) {
+        self.handle().shared.metrics.set_enabled(enabled);
+    }
+
+    /// Returns what has been recorded since metrics were enabled with
+    /// [`enable_metrics`](#method.enable_metrics).
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.handle().shared.metrics.snapshot()
+    }
+
     /// Allocate a new transfer object that can be used to send asynchronous
     /// transfer requests.
+    ///
+    /// The buffer of the transfer is aligned to a cache line, 64 bytes.
+    /// Fails with `Error::Disconnected` if the device has been disconnected.
     pub fn alloc_transfer(&self, iso_packets: u32)
                       -> ::Result<Transfer>
     {
+        self.alloc_transfer_aligned(iso_packets, buffer::DEFAULT_ALIGNMENT)
+    }
+
+    /// Allocate a new transfer object whose buffer is aligned to `alignment`
+    /// bytes, e.g., 4096 for a page.
+    ///
+    /// Some host controller drivers copy, or refuse, buffers that aren't
+    /// aligned for DMA. Fails with `Error::InvalidParam` if `alignment` isn't
+    /// a power of two.
+    pub fn alloc_transfer_aligned(&self, iso_packets: u32, alignment: usize)
+                                  -> ::Result<Transfer>
+    {
+        if !alignment.is_power_of_two() {
+            return Err(Error::InvalidParam);
+        }
+
         let handle = self.handle();
+        if handle.shared.disconnect.is_disconnected() {
+            return Err(Error::Disconnected);
+        }
         let transfer = unsafe {
-            let t = libusb_alloc_transfer(iso_packets as c_int);
+            let t = handle.context.transfers.take(iso_packets);
             if t.is_null() {
                 return Err(Error::NoMem);
             }
-            (*t).dev_handle = handle.handle;
+            (*t).dev_handle = handle.handle.as_ptr() as *mut libusb_device_handle;
             t
                 
         };
 
         
         Ok(unsafe{transfer::from_libusb(&handle.context, &self.0,
-                                        transfer)})
+                                        &handle.shared,
+                                        transfer, iso_packets, alignment)})
+    }
+
+    /// Claims an interface for the caller's own protocol, consuming the handle.
+    ///
+    /// If the device is unconfigured, its first configuration is set. A kernel driver bound to
+    /// the interface is detached, and reattached when the returned interface is dropped.
+    ///
+    /// ## Errors
+    ///
+    /// * `NotFound` if the active configuration has no such interface.
+    /// * Any error returned while configuring the device, detaching the kernel driver or
+    ///   claiming the interface.
+    pub fn claim(self, iface: u8) -> ::Result<ClaimedInterface> {
+        ClaimedInterface::claim(self, iface)
+    }
+
+    /// Streams the data of a bulk IN endpoint, keeping `depth.transfers` reads of
+    /// `depth.transfer_size` bytes in flight.
+    ///
+    /// The buffers of completed reads are loaned to the consumer instead of
+    /// copied. For sustained throughput, use reads of many packets, e.g., the
+    /// depth from `QueueDepth::for_endpoint`, and drop each loan promptly.
+    pub fn bulk_stream<'a>(&'a self, endpoint: EndpointAddress, depth: QueueDepth) -> BulkStream<'a> {
+        BulkStream::new(self, endpoint, depth)
     }
 }
 
+/// Gets the descriptor of the active configuration of an open device.
+fn active_config_descriptor(handle: &DeviceHandleAsync) -> ::Result<ConfigDescriptor> {
+    let backend = handle.context.backend;
+    unsafe { backend.active_config_descriptor(backend.get_device(handle.handle)) }.map_err(error::from_libusb)
+}
+
 #[doc(hidden)]
-pub unsafe fn from_libusb(context: &Arc<ContextAsync>, handle: *mut libusb_device_handle) -> DeviceHandle {
+pub unsafe fn from_backend(context: &Arc<ContextAsync>, handle: HandleRef) -> DeviceHandle {
     DeviceHandle {
         0: Arc::new(Mutex::new(DeviceHandleAsync{
             context: context.clone(),
             handle: handle,
             interfaces: BitSet::with_capacity(u8::max_value() as usize + 1),
+            shared: HandleShared::new(),
         }))
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ptr;
+    use std::thread;
+    use std::sync::atomic::AtomicI32;
+    use libc::c_int;
+    use backend::{ContextRef, TransferRef, UsbBackend};
+    use transfer::{HookAction, TransferStatus};
+
+    /// Completes the submitted transfers when events are handled
+    struct Completing(Mutex<Vec<TransferRef>>);
+
+    impl UsbBackend for Completing {
+        unsafe fn submit_transfer(&self, transfer: TransferRef) -> c_int {
+            self.0.lock().unwrap().push(transfer);
+            0
+        }
+
+        unsafe fn cancel_transfer(&self, _transfer: TransferRef) -> c_int {
+            LIBUSB_ERROR_NOT_FOUND
+        }
+
+        unsafe fn handle_events(&self, _context: ContextRef, _timeout: Duration, _completed: Option<&AtomicI32>) -> c_int {
+            let submitted = ::std::mem::take(&mut *self.0.lock().unwrap());
+            for transfer in submitted {
+                transfer.complete(TransferStatus::Completed, 0);
+            }
+            thread::sleep(Duration::from_millis(1));
+            0
+        }
+    }
+
+    #[test]
+    fn it_closes_a_handle_dropped_by_a_completion_hook() {
+        let backend: &'static Completing = Box::leak(Box::new(Completing(Mutex::new(Vec::new()))));
+        let context = ContextAsync::with_backend(backend);
+        context.event_thread.acquire(&context);
+        let handle = unsafe { from_backend(&context, HandleRef::from_ptr(ptr::null_mut())) };
+
+        let mut transfer = handle.alloc_transfer(0).unwrap();
+        transfer.fill_bulk_read(EndpointAddress::in_(1), 8).unwrap();
+        let mut handle = Some(handle);
+        transfer.set_completion_hook(move |_, _| {
+            // Runs on the thread that handles events, which the handle can't wait for
+            drop(handle.take());
+            HookAction::Complete
+        });
+        let _future = transfer.submit();
+
+        for _ in 0..1000 {
+            if context.event_thread.users() == 0 {
+                break;
+            }
+            if !cfg!(feature = "event-thread") {
+                event_thread::handle_events_once(&context, Duration::from_millis(0), None);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(0, context.event_thread.users());
+    }
+}
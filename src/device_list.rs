@@ -1,23 +1,22 @@
-use std::slice;
 use std::sync::{Arc};
 
-use libusb::*;
-
+use backend::DeviceRef;
 use context::ContextAsync;
 use device::{self, Device};
 
 /// A list of detected USB devices.
 pub struct DeviceList {
     context: Arc<ContextAsync>,
-    list: *const *mut libusb_device,
-    len: usize,
+    list: Vec<DeviceRef>,
 }
 
 impl Drop for DeviceList {
     /// Frees the device list.
     fn drop(&mut self) {
-        unsafe {
-            libusb_free_device_list(self.list, 1);
+        for &device in &self.list {
+            unsafe {
+                self.context.backend.unref_device(device);
+            }
         }
     }
 }
@@ -25,7 +24,7 @@ impl Drop for DeviceList {
 impl DeviceList {
     /// Returns the number of devices in the list.
     pub fn len(&self) -> usize {
-        self.len
+        self.list.len()
     }
 
     /// Returns an iterator over the devices in the list.
@@ -34,16 +33,35 @@ impl DeviceList {
     pub fn iter(&self) -> Devices {
         Devices {
             context: self.context.clone(),
-            devices: unsafe { slice::from_raw_parts(self.list, self.len) },
+            devices: &self.list,
             index: 0,
         }
     }
 }
 
+impl IntoIterator for DeviceList {
+    type Item = Device;
+    type IntoIter = IntoDevices;
+
+    /// Consumes the list, e.g., for `for device in context.devices()?`.
+    fn into_iter(self) -> IntoDevices {
+        IntoDevices { list: self, index: 0 }
+    }
+}
+
+impl<'a> IntoIterator for &'a DeviceList {
+    type Item = Device;
+    type IntoIter = Devices<'a>;
+
+    fn into_iter(self) -> Devices<'a> {
+        self.iter()
+    }
+}
+
 /// Iterator over detected USB devices.
 pub struct Devices<'b> {
     context: Arc<ContextAsync>,
-    devices: &'b [*mut libusb_device],
+    devices: &'b [DeviceRef],
     index: usize,
 }
 
@@ -55,7 +73,7 @@ impl<'b> Iterator for Devices<'b> {
             let device = self.devices[self.index];
 
             self.index += 1;
-            Some(unsafe { device::from_libusb(&self.context, device) })
+            Some(unsafe { device::from_backend(&self.context, device) })
         }
         else {
             None
@@ -68,12 +86,41 @@ impl<'b> Iterator for Devices<'b> {
     }
 }
 
+/// Iterator over detected USB devices that owns the list, returned by `DeviceList::into_iter`.
+///
+/// Each `Device` keeps its own reference, so it stays valid after the list is freed.
+pub struct IntoDevices {
+    list: DeviceList,
+    index: usize,
+}
+
+impl Iterator for IntoDevices {
+    type Item = Device;
+
+    fn next(&mut self) -> Option<Device> {
+        if self.index < self.list.len() {
+            let device = self.list.list[self.index];
+
+            self.index += 1;
+            Some(unsafe { device::from_backend(&self.list.context, device) })
+        }
+        else {
+            None
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.list.len() - self.index;
+        (remaining, Some(remaining))
+    }
+}
+
 
+/// Makes a list of the devices listed by the backend, whose references it takes over.
 #[doc(hidden)]
-pub unsafe fn from_libusb(context: &Arc<ContextAsync>, list: *const *mut libusb_device, len: usize,) -> DeviceList {
+pub unsafe fn from_backend(context: &Arc<ContextAsync>, list: Vec<DeviceRef>) -> DeviceList {
     DeviceList {
         context: context.clone(),
         list: list,
-        len: len,
     }
 }
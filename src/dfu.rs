@@ -0,0 +1,585 @@
+//! Device Firmware Upgrade (DFU) 1.1 class support.
+//!
+//! A device in run-time mode is switched to DFU mode with [`Dfu::detach`](struct.Dfu.html#method.detach).
+//! The device then re-enumerates, and the DFU mode interface must be opened again from the new
+//! device before downloading firmware.
+
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::task;
+use std::time::Duration;
+
+use config_descriptor::ConfigDescriptor;
+use control::{self, ControlFuture};
+use delay::Delay;
+use device::Device;
+use device_handle::DeviceHandle;
+use error::Error;
+use extra_descriptors::ExtraDescriptors;
+use fields::{ClassCode, Direction, Recipient, RequestType, Version, request_type};
+
+const SUBCLASS_DFU: u8 = 0x01;
+const PROTOCOL_RUNTIME: u8 = 0x01;
+const PROTOCOL_DFU_MODE: u8 = 0x02;
+
+const DT_DFU_FUNCTIONAL: u8 = 0x21;
+
+const DFU_DETACH: u8 = 0x00;
+const DFU_DNLOAD: u8 = 0x01;
+const DFU_UPLOAD: u8 = 0x02;
+const DFU_GETSTATUS: u8 = 0x03;
+const DFU_CLRSTATUS: u8 = 0x04;
+const DFU_GETSTATE: u8 = 0x05;
+const DFU_ABORT: u8 = 0x06;
+
+/// Whether a DFU interface belongs to a device in run-time or DFU mode.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum DfuMode {
+    /// The device runs its application and can be detached into DFU mode.
+    Runtime,
+
+    /// The device runs its bootloader and accepts firmware.
+    Dfu,
+}
+
+/// Contents of a DFU functional descriptor.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct DfuFunctional {
+    /// The device can download firmware (`bitCanDnload`).
+    pub can_download: bool,
+
+    /// The device can upload firmware (`bitCanUpload`).
+    pub can_upload: bool,
+
+    /// The device keeps communicating after manifestation (`bitManifestationTolerant`).
+    pub manifestation_tolerant: bool,
+
+    /// The device detaches itself after DFU_DETACH (`bitWillDetach`).
+    pub will_detach: bool,
+
+    /// Time in milliseconds the device waits for a reset after DFU_DETACH.
+    pub detach_timeout: u16,
+
+    /// Maximum number of bytes per DFU_DNLOAD or DFU_UPLOAD request.
+    pub transfer_size: u16,
+
+    /// DFU specification version.
+    pub version: Version,
+}
+
+impl DfuFunctional {
+    /// Parses the payload of a DFU functional descriptor, i.e., the bytes after `bLength` and
+    /// `bDescriptorType`.
+    ///
+    /// DFU 1.0 descriptors without `bcdDFUVersion` are reported as version 1.0.
+    pub fn parse(payload: &[u8]) -> ::Result<DfuFunctional> {
+        if payload.len() < 5 {
+            return Err(Error::InvalidParam);
+        }
+
+        let attributes = payload[0];
+
+        Ok(DfuFunctional {
+            can_download: attributes & 0x01 != 0,
+            can_upload: attributes & 0x02 != 0,
+            manifestation_tolerant: attributes & 0x04 != 0,
+            will_detach: attributes & 0x08 != 0,
+            detach_timeout: read_u16(payload, 1),
+            transfer_size: read_u16(payload, 3),
+            version: match payload.get(5..7) {
+                Some(bcd) => Version::from_bcd(read_u16(bcd, 0)),
+                None => Version(1, 0, 0),
+            },
+        })
+    }
+}
+
+/// A DFU interface of a configuration.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct DfuInterface {
+    /// The interface number.
+    pub interface: u8,
+
+    /// The alternate setting, which selects the memory to program in DFU mode.
+    pub setting: u8,
+
+    /// Whether the device is in run-time or DFU mode.
+    pub mode: DfuMode,
+
+    /// The DFU functional descriptor.
+    pub functional: DfuFunctional,
+}
+
+/// Finds the DFU interfaces of a configuration, one for each alternate setting.
+pub fn find_dfu_interfaces(config: &ConfigDescriptor) -> Vec<DfuInterface> {
+    let mut interfaces = Vec::new();
+
+    for setting in config.interfaces().flat_map(|interface| interface.descriptors()) {
+        if setting.class_code() != ClassCode::ApplicationSpecific || setting.sub_class_code() != SUBCLASS_DFU {
+            continue;
+        }
+
+        let mode = match setting.protocol_code() {
+            PROTOCOL_RUNTIME => DfuMode::Runtime,
+            PROTOCOL_DFU_MODE => DfuMode::Dfu,
+            _ => continue,
+        };
+
+        let functional = ExtraDescriptors::new(setting.extra())
+            .find(|&(descriptor_type, _)| descriptor_type == DT_DFU_FUNCTIONAL)
+            .and_then(|(_, payload)| DfuFunctional::parse(payload).ok());
+
+        if let Some(functional) = functional {
+            interfaces.push(DfuInterface {
+                interface: setting.interface_number(),
+                setting: setting.setting_number(),
+                mode,
+                functional,
+            });
+        }
+    }
+
+    interfaces
+}
+
+/// The state of a DFU device.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum DfuState {
+    AppIdle,
+    AppDetach,
+    DfuIdle,
+    DnloadSync,
+    DnBusy,
+    DnloadIdle,
+    ManifestSync,
+    Manifest,
+    ManifestWaitReset,
+    UploadIdle,
+    Error,
+    Unknown(u8),
+}
+
+impl From<u8> for DfuState {
+    fn from(state: u8) -> DfuState {
+        match state {
+            0 => DfuState::AppIdle,
+            1 => DfuState::AppDetach,
+            2 => DfuState::DfuIdle,
+            3 => DfuState::DnloadSync,
+            4 => DfuState::DnBusy,
+            5 => DfuState::DnloadIdle,
+            6 => DfuState::ManifestSync,
+            7 => DfuState::Manifest,
+            8 => DfuState::ManifestWaitReset,
+            9 => DfuState::UploadIdle,
+            10 => DfuState::Error,
+            n => DfuState::Unknown(n),
+        }
+    }
+}
+
+/// The result of the most recent DFU request, as reported by DFU_GETSTATUS.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum DfuStatusCode {
+    Ok,
+    ErrTarget,
+    ErrFile,
+    ErrWrite,
+    ErrErase,
+    ErrCheckErased,
+    ErrProg,
+    ErrVerify,
+    ErrAddress,
+    ErrNotDone,
+    ErrFirmware,
+    ErrVendor,
+    ErrUsbReset,
+    ErrPowerOnReset,
+    ErrUnknown,
+    ErrStalledPacket,
+    Unknown(u8),
+}
+
+impl From<u8> for DfuStatusCode {
+    fn from(status: u8) -> DfuStatusCode {
+        match status {
+            0x00 => DfuStatusCode::Ok,
+            0x01 => DfuStatusCode::ErrTarget,
+            0x02 => DfuStatusCode::ErrFile,
+            0x03 => DfuStatusCode::ErrWrite,
+            0x04 => DfuStatusCode::ErrErase,
+            0x05 => DfuStatusCode::ErrCheckErased,
+            0x06 => DfuStatusCode::ErrProg,
+            0x07 => DfuStatusCode::ErrVerify,
+            0x08 => DfuStatusCode::ErrAddress,
+            0x09 => DfuStatusCode::ErrNotDone,
+            0x0A => DfuStatusCode::ErrFirmware,
+            0x0B => DfuStatusCode::ErrVendor,
+            0x0C => DfuStatusCode::ErrUsbReset,
+            0x0D => DfuStatusCode::ErrPowerOnReset,
+            0x0E => DfuStatusCode::ErrUnknown,
+            0x0F => DfuStatusCode::ErrStalledPacket,
+            n => DfuStatusCode::Unknown(n),
+        }
+    }
+}
+
+/// Response to DFU_GETSTATUS.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct DfuStatus {
+    /// The result of the most recent request.
+    pub status: DfuStatusCode,
+
+    /// Minimum time to wait before the next DFU_GETSTATUS.
+    pub poll_timeout: Duration,
+
+    /// The state the device enters after this response.
+    pub state: DfuState,
+
+    /// Index of a string descriptor describing the status, if any.
+    pub string_index: Option<u8>,
+}
+
+impl DfuStatus {
+    /// Decodes a DFU_GETSTATUS response.
+    pub fn decode(data: &[u8]) -> ::Result<DfuStatus> {
+        if data.len() < 6 {
+            return Err(Error::InvalidParam);
+        }
+
+        let poll_timeout = data[1] as u64 | (data[2] as u64) << 8 | (data[3] as u64) << 16;
+
+        Ok(DfuStatus {
+            status: DfuStatusCode::from(data[0]),
+            poll_timeout: Duration::from_millis(poll_timeout),
+            state: DfuState::from(data[4]),
+            string_index: match data[5] {
+                0 => None,
+                n => Some(n),
+            },
+        })
+    }
+}
+
+/// A claimed DFU interface.
+pub struct Dfu {
+    handle: DeviceHandle,
+    interface: DfuInterface,
+}
+
+impl Dfu {
+    /// Opens a device and claims one of its DFU interfaces.
+    pub fn open(device: &Device, interface: &DfuInterface) -> ::Result<Dfu> {
+        let mut handle = device.open()?;
+
+        handle.claim_interface(interface.interface)?;
+
+        if interface.setting != 0 {
+            handle.set_alternate_setting(interface.interface, interface.setting)?;
+        }
+
+        Ok(Dfu { handle, interface: *interface })
+    }
+
+    /// Returns the handle of the device.
+    pub fn handle(&self) -> &DeviceHandle {
+        &self.handle
+    }
+
+    /// Returns the claimed interface.
+    pub fn interface(&self) -> &DfuInterface {
+        &self.interface
+    }
+
+    /// Asks a device in run-time mode to switch to DFU mode.
+    ///
+    /// Devices that don't detach by themselves are reset after the request succeeds. Either
+    /// way the device re-enumerates in DFU mode, and this handle can't be used afterwards.
+    pub fn detach(mut self) -> ::Result<()> {
+        let timeout = Duration::from_millis(1000);
+        let request_type = request_type(Direction::Out, RequestType::Class, Recipient::Interface);
+
+        self.handle.write_control(request_type,
+                                  DFU_DETACH,
+                                  self.interface.functional.detach_timeout,
+                                  self.interface.interface as u16,
+                                  &[],
+                                  timeout)?;
+
+        if !self.interface.functional.will_detach {
+            self.handle.reset()?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the device's status.
+    pub fn get_status(&self) -> ControlFuture<DfuStatus> {
+        self.read(DFU_GETSTATUS, 0, 6, DfuStatus::decode)
+    }
+
+    /// Clears an error status, returning the device to the idle state.
+    pub fn clear_status(&self) -> ControlFuture<usize> {
+        self.write(DFU_CLRSTATUS, 0, &[])
+    }
+
+    /// Reads the device's state.
+    pub fn get_state(&self) -> ControlFuture<DfuState> {
+        self.read(DFU_GETSTATE, 0, 1, |data| data.first().map(|&state| DfuState::from(state)).ok_or(Error::Io))
+    }
+
+    /// Aborts a download or upload, returning the device to the idle state.
+    pub fn abort(&self) -> ControlFuture<usize> {
+        self.write(DFU_ABORT, 0, &[])
+    }
+
+    /// Downloads firmware to the device.
+    ///
+    /// The firmware is sent in blocks of `wTransferSize` bytes, waiting for the device after
+    /// each block as requested in its status. A zero-length block then starts manifestation.
+    /// The future resolves when the device is idle again, or when it waits for a reset if it
+    /// isn't manifestation tolerant.
+    ///
+    /// If the device reports an error status, the future resolves to `Err(Error::Io)`; read
+    /// the status with [`get_status`](#method.get_status) for details.
+    pub fn download<'a>(&'a self, firmware: &[u8]) -> DownloadFuture<'a> {
+        let mut future = DownloadFuture {
+            dfu: self,
+            firmware: firmware.to_vec(),
+            offset: 0,
+            block: 0,
+            manifesting: false,
+            state: DownloadState::Done,
+        };
+
+        future.state = future.next_block();
+        future
+    }
+
+    /// Uploads the firmware from the device.
+    ///
+    /// Blocks of `wTransferSize` bytes are read until the device returns a short block.
+    pub fn upload<'a>(&'a self) -> UploadFuture<'a> {
+        UploadFuture { dfu: self, firmware: Vec::new(), block: 0, pending: Some(self.upload_block(0)) }
+    }
+
+    fn upload_block(&self, block: u16) -> ControlFuture<Vec<u8>> {
+        self.read(DFU_UPLOAD, block, self.interface.functional.transfer_size, control::to_vec)
+    }
+
+    fn read<T>(&self, request: u8, value: u16, length: u16, convert: fn(&[u8]) -> ::Result<T>) -> ControlFuture<T> {
+        control::read(&self.handle,
+                      request_type(Direction::In, RequestType::Class, Recipient::Interface),
+                      request,
+                      value,
+                      self.interface.interface as u16,
+                      length,
+                      convert)
+    }
+
+    fn write(&self, request: u8, value: u16, data: &[u8]) -> ControlFuture<usize> {
+        control::write(&self.handle,
+                       request_type(Direction::Out, RequestType::Class, Recipient::Interface),
+                       request,
+                       value,
+                       self.interface.interface as u16,
+                       data)
+    }
+}
+
+/// Future returned by [`Dfu::download`](struct.Dfu.html#method.download).
+pub struct DownloadFuture<'a> {
+    dfu: &'a Dfu,
+    firmware: Vec<u8>,
+    offset: usize,
+    block: u16,
+    manifesting: bool,
+    state: DownloadState,
+}
+
+enum DownloadState {
+    Download(ControlFuture<usize>),
+    Status(ControlFuture<DfuStatus>),
+    Wait(Delay),
+    Done,
+}
+
+impl<'a> DownloadFuture<'a> {
+    /// Sends the next block, or the zero-length block that starts manifestation.
+    fn next_block(&mut self) -> DownloadState {
+        let transfer_size = (self.dfu.interface.functional.transfer_size as usize).max(1);
+        let end = self.firmware.len().min(self.offset + transfer_size);
+        let data = &self.firmware[self.offset..end];
+
+        if data.is_empty() {
+            self.manifesting = true;
+        }
+
+        let future = self.dfu.write(DFU_DNLOAD, self.block, data);
+        self.offset = end;
+        self.block = self.block.wrapping_add(1);
+
+        DownloadState::Download(future)
+    }
+}
+
+impl<'a> Future for DownloadFuture<'a> {
+    type Output = ::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            let next = match this.state {
+                DownloadState::Download(ref mut future) => match Pin::new(future).poll(cx) {
+                    task::Poll::Pending => return task::Poll::Pending,
+                    task::Poll::Ready(Ok(_)) => DownloadState::Status(this.dfu.get_status()),
+                    task::Poll::Ready(Err(e)) => return this.finish(Err(e)),
+                },
+                DownloadState::Status(ref mut future) => {
+                    let status = match Pin::new(future).poll(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        // A device that isn't manifestation tolerant may drop off the bus
+                        task::Poll::Ready(Err(Error::NoDevice)) if this.manifesting => return this.finish(Ok(())),
+                        task::Poll::Ready(Err(e)) => return this.finish(Err(e)),
+                        task::Poll::Ready(Ok(status)) => status,
+                    };
+
+                    if status.status != DfuStatusCode::Ok {
+                        return this.finish(Err(Error::Io));
+                    }
+
+                    match status.state {
+                        DfuState::DnBusy | DfuState::DnloadSync | DfuState::Manifest | DfuState::ManifestSync => {
+                            DownloadState::Wait(Delay::new(status.poll_timeout))
+                        },
+                        DfuState::DnloadIdle if !this.manifesting => this.next_block(),
+                        DfuState::DfuIdle | DfuState::ManifestWaitReset if this.manifesting => {
+                            return this.finish(Ok(()));
+                        },
+                        _ => return this.finish(Err(Error::Io)),
+                    }
+                },
+                DownloadState::Wait(ref mut delay) => match Pin::new(delay).poll(cx) {
+                    task::Poll::Pending => return task::Poll::Pending,
+                    task::Poll::Ready(()) => DownloadState::Status(this.dfu.get_status()),
+                },
+                DownloadState::Done => return task::Poll::Ready(Err(Error::PolledAfterCompletion)),
+            };
+
+            this.state = next;
+        }
+    }
+}
+
+impl<'a> DownloadFuture<'a> {
+    fn finish(&mut self, result: ::Result<()>) -> task::Poll<::Result<()>> {
+        self.state = DownloadState::Done;
+        task::Poll::Ready(result)
+    }
+}
+
+/// Future returned by [`Dfu::upload`](struct.Dfu.html#method.upload).
+pub struct UploadFuture<'a> {
+    dfu: &'a Dfu,
+    firmware: Vec<u8>,
+    block: u16,
+    pending: Option<ControlFuture<Vec<u8>>>,
+}
+
+impl<'a> Future for UploadFuture<'a> {
+    type Output = ::Result<Vec<u8>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            let data = match this.pending {
+                Some(ref mut future) => match Pin::new(future).poll(cx) {
+                    task::Poll::Pending => return task::Poll::Pending,
+                    task::Poll::Ready(result) => result,
+                },
+                None => return task::Poll::Ready(Err(Error::PolledAfterCompletion)),
+            };
+
+            let data = match data {
+                Ok(data) => data,
+                Err(e) => {
+                    this.pending = None;
+                    return task::Poll::Ready(Err(e));
+                },
+            };
+
+            this.firmware.extend_from_slice(&data);
+
+            if data.len() < this.dfu.interface.functional.transfer_size as usize {
+                this.pending = None;
+                return task::Poll::Ready(Ok(mem::take(&mut this.firmware)));
+            }
+
+            this.block = this.block.wrapping_add(1);
+            this.pending = Some(this.dfu.upload_block(this.block));
+        }
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    bytes[offset] as u16 | (bytes[offset + 1] as u16) << 8
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::mem;
+
+    #[test]
+    fn it_parses_functional_descriptor() {
+        let functional = DfuFunctional::parse(&[0x0B, 0xFF, 0x00, 0x00, 0x04, 0x1A, 0x01]).unwrap();
+
+        assert_eq!(DfuFunctional {
+            can_download: true,
+            can_upload: true,
+            manifestation_tolerant: false,
+            will_detach: true,
+            detach_timeout: 255,
+            transfer_size: 1024,
+            version: Version(1, 1, 10),
+        }, functional);
+    }
+
+    #[test]
+    fn it_defaults_to_dfu_1_0() {
+        assert_eq!(Version(1, 0, 0), DfuFunctional::parse(&[0x01, 0x00, 0x01, 0x40, 0x00]).unwrap().version);
+    }
+
+    #[test]
+    fn it_decodes_status() {
+        assert_eq!(DfuStatus {
+            status: DfuStatusCode::Ok,
+            poll_timeout: Duration::from_millis(0x01_0203),
+            state: DfuState::DnBusy,
+            string_index: None,
+        }, DfuStatus::decode(&[0x00, 0x03, 0x02, 0x01, 0x04, 0x00]).unwrap());
+
+        assert_eq!(DfuStatusCode::ErrVerify, DfuStatus::decode(&[0x07, 0, 0, 0, 0x0A, 0]).unwrap().status);
+    }
+
+    #[test]
+    fn it_finds_dfu_interfaces() {
+        let extra = [0x09, 0x21, 0x0D, 0xFF, 0x00, 0x00, 0x08, 0x10, 0x01];
+        let runtime = interface!(merge!(interface_descriptor!(bInterfaceClass: 0xFE, bInterfaceSubClass: 0x01, bInterfaceProtocol: 0x01)
+                                        => bInterfaceNumber: 2, extra: extra.as_ptr(), extra_length: 9));
+        let config = config_descriptor!(runtime);
+        let config = unsafe { ::config_descriptor::from_libusb(&config) };
+
+        let interfaces = find_dfu_interfaces(&config);
+
+        assert_eq!(1, interfaces.len());
+        assert_eq!(2, interfaces[0].interface);
+        assert_eq!(DfuMode::Runtime, interfaces[0].mode);
+        assert_eq!(2048, interfaces[0].functional.transfer_size);
+        mem::forget(config);
+    }
+}
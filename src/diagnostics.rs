@@ -0,0 +1,122 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use context::ContextAsync;
+
+/// Counts of what a context has in use, returned by
+/// [`Context::diagnostics`](struct.Context.html#method.diagnostics).
+///
+/// Counts that stay above zero after the handles and transfers of a program should have been
+/// dropped point at a leak.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Default)]
+pub struct Diagnostics {
+    /// Open device handles and hotplug registrations, which keep the event thread running.
+    pub event_thread_users: u32,
+
+    /// Transfers that have been allocated and not yet freed, submitted or not.
+    pub live_transfers: usize,
+
+    /// Transfers that have been submitted and whose callback hasn't run yet.
+    pub in_flight_transfers: usize,
+
+    /// Bytes of transfer buffers that are kept for reuse, up to a fixed number of buffers of
+    /// each size.
+    pub pooled_buffer_bytes: usize,
+
+    /// Freed `libusb` transfers that are kept for reuse.
+    pub pooled_transfers: usize,
+}
+
+/// The transfer counts of a context, updated by its transfers.
+pub struct Accounting {
+    live_transfers: AtomicUsize,
+    in_flight_transfers: AtomicUsize,
+}
+
+impl Accounting {
+    pub fn new() -> Accounting {
+        Accounting {
+            live_transfers: AtomicUsize::new(0),
+            in_flight_transfers: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn live_transfers(&self) -> usize {
+        self.live_transfers.load(Ordering::Acquire)
+    }
+
+    pub fn in_flight_transfers(&self) -> usize {
+        self.in_flight_transfers.load(Ordering::Acquire)
+    }
+}
+
+/// Counts a transfer as live in its context for as long as it exists, and keeps the context
+/// from being dropped meanwhile.
+pub struct LiveTransfer {
+    context: Arc<ContextAsync>,
+}
+
+impl LiveTransfer {
+    pub fn new(context: &Arc<ContextAsync>) -> LiveTransfer {
+        context.accounting.live_transfers.fetch_add(1, Ordering::AcqRel);
+        LiveTransfer { context: context.clone() }
+    }
+
+    pub fn context(&self) -> &Arc<ContextAsync> {
+        &self.context
+    }
+
+    /// The transfer has been submitted.
+    pub fn submitted(&self) {
+        self.context.accounting.in_flight_transfers.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// The callback of the transfer has run, or the submission failed.
+    pub fn completed(&self) {
+        self.context.accounting.in_flight_transfers.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl Drop for LiveTransfer {
+    fn drop(&mut self) {
+        self.context.accounting.live_transfers.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use context::Context;
+    use test_helpers::{config_bytes, device_bytes, Call, MockBackend};
+
+    #[test]
+    fn it_counts_live_and_in_flight_transfers() {
+        let context = ContextAsync::uninitialized();
+        let first = LiveTransfer::new(&context);
+        let second = LiveTransfer::new(&context);
+        second.submitted();
+        assert_eq!((2, 1), (context.accounting.live_transfers(), context.accounting.in_flight_transfers()));
+
+        second.completed();
+        drop(first);
+        assert_eq!((1, 0), (context.accounting.live_transfers(), context.accounting.in_flight_transfers()));
+        drop(second);
+        assert_eq!(0, context.accounting.live_transfers());
+    }
+
+    #[test]
+    fn it_lets_the_context_go_before_its_transfers() {
+        let backend = MockBackend::new(vec![(device_bytes(0x1234, 0x5678), config_bytes(&[0xFF]))]).leak();
+        let context = Context::with_backend(backend);
+        let handle = context.devices().unwrap().into_iter().next().unwrap().open().unwrap();
+        let transfer = handle.alloc_transfer(0).unwrap();
+        assert_eq!(1, context.diagnostics().live_transfers);
+
+        drop(context);
+        drop(handle);
+        drop(transfer);
+        assert_eq!(vec![Call::Open, Call::Close], backend.calls());
+    }
+}
+
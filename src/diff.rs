@@ -0,0 +1,258 @@
+use std::fmt;
+
+use config_descriptor::ConfigDescriptor;
+use device_descriptor::DeviceDescriptor;
+use endpoint_descriptor::EndpointDescriptor;
+use interface_descriptor::InterfaceDescriptor;
+use snapshot::DescriptorSnapshot;
+
+/// A field that differs between two descriptors.
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+pub struct FieldChange {
+    /// Location of the field, e.g., `interface[1].setting[0].endpoint[0x81].wMaxPacketSize`.
+    pub path: String,
+
+    /// The field's value in the old descriptor, or `None` if the old descriptor didn't have it.
+    pub old: Option<String>,
+
+    /// The field's value in the new descriptor, or `None` if the new descriptor doesn't have it.
+    pub new: Option<String>,
+}
+
+impl fmt::Display for FieldChange {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}: {} -> {}",
+               self.path,
+               self.old.as_ref().map_or("(absent)", |s| s.as_str()),
+               self.new.as_ref().map_or("(absent)", |s| s.as_str()))
+    }
+}
+
+/// Descriptors that can be compared field by field with [`diff`](fn.diff.html).
+pub trait DescriptorDiff {
+    /// Appends the fields that differ between `self` and `new` to `changes`, prefixing their
+    /// paths with `path`.
+    fn diff_fields(&self, new: &Self, path: &str, changes: &mut Vec<FieldChange>);
+}
+
+/// Returns the fields that differ between two descriptors.
+///
+/// Interfaces, alternate settings and endpoints are matched by number and address rather than
+/// position, so a reordered descriptor set produces no changes. Descriptors that only exist in
+/// one of the sets are reported as a single change with `None` on the other side.
+pub fn diff<T: DescriptorDiff>(old: &T, new: &T) -> Vec<FieldChange> {
+    let mut changes = Vec::new();
+    old.diff_fields(new, "", &mut changes);
+    changes
+}
+
+fn join(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_owned()
+    }
+    else {
+        format!("{}.{}", path, name)
+    }
+}
+
+fn field<T: PartialEq + fmt::Debug>(changes: &mut Vec<FieldChange>, path: &str, name: &str, old: T, new: T) {
+    if old != new {
+        changes.push(FieldChange {
+            path: join(path, name),
+            old: Some(format!("{:?}", old)),
+            new: Some(format!("{:?}", new)),
+        });
+    }
+}
+
+fn presence(changes: &mut Vec<FieldChange>, path: String, old: bool) {
+    changes.push(FieldChange {
+        path,
+        old: if old { Some("present".to_owned()) } else { None },
+        new: if old { None } else { Some("present".to_owned()) },
+    });
+}
+
+/// Diffs two lists of descriptors matched by `key`.
+fn keyed<T, K, F>(changes: &mut Vec<FieldChange>, path: &str, name: &str, old: Vec<T>, new: Vec<T>, key: F)
+    where T: DescriptorDiff, K: PartialEq + fmt::Display, F: Fn(&T) -> K
+{
+    for o in &old {
+        let path = join(path, &format!("{}[{}]", name, key(o)));
+
+        match new.iter().find(|n| key(n) == key(o)) {
+            Some(n) => o.diff_fields(n, &path, changes),
+            None => presence(changes, path, true),
+        }
+    }
+
+    for n in &new {
+        if !old.iter().any(|o| key(o) == key(n)) {
+            presence(changes, join(path, &format!("{}[{}]", name, key(n))), false);
+        }
+    }
+}
+
+impl DescriptorDiff for DeviceDescriptor {
+    fn diff_fields(&self, new: &Self, path: &str, changes: &mut Vec<FieldChange>) {
+        field(changes, path, "bcdUSB", self.usb_version(), new.usb_version());
+        field(changes, path, "bDeviceClass", self.class_code(), new.class_code());
+        field(changes, path, "bDeviceSubClass", self.sub_class_code(), new.sub_class_code());
+        field(changes, path, "bDeviceProtocol", self.protocol_code(), new.protocol_code());
+        field(changes, path, "bMaxPacketSize0", self.max_packet_size(), new.max_packet_size());
+        field(changes, path, "idVendor", self.vendor_id(), new.vendor_id());
+        field(changes, path, "idProduct", self.product_id(), new.product_id());
+        field(changes, path, "bcdDevice", self.device_version(), new.device_version());
+        field(changes, path, "iManufacturer", self.manufacturer_string_index(), new.manufacturer_string_index());
+        field(changes, path, "iProduct", self.product_string_index(), new.product_string_index());
+        field(changes, path, "iSerialNumber", self.serial_number_string_index(), new.serial_number_string_index());
+        field(changes, path, "bNumConfigurations", self.num_configurations(), new.num_configurations());
+    }
+}
+
+impl DescriptorDiff for ConfigDescriptor {
+    fn diff_fields(&self, new: &Self, path: &str, changes: &mut Vec<FieldChange>) {
+        field(changes, path, "bConfigurationValue", self.number(), new.number());
+        field(changes, path, "iConfiguration", self.description_string_index(), new.description_string_index());
+        field(changes, path, "bMaxPower", self.max_power(), new.max_power());
+        field(changes, path, "selfPowered", self.self_powered(), new.self_powered());
+        field(changes, path, "remoteWakeup", self.remote_wakeup(), new.remote_wakeup());
+        field(changes, path, "bNumInterfaces", self.num_interfaces(), new.num_interfaces());
+        field(changes, path, "extra", self.extra(), new.extra());
+
+        let old_settings = self.interfaces().flat_map(|i| i.descriptors()).collect::<Vec<_>>();
+        let new_settings = new.interfaces().flat_map(|i| i.descriptors()).collect::<Vec<_>>();
+
+        let mut numbers = old_settings.iter().chain(new_settings.iter())
+            .map(|s| s.interface_number())
+            .collect::<Vec<_>>();
+        numbers.sort();
+        numbers.dedup();
+
+        for number in numbers {
+            let interface_path = join(path, &format!("interface[{}]", number));
+            let old = old_settings.iter().filter(|s| s.interface_number() == number).collect::<Vec<_>>();
+            let new = new_settings.iter().filter(|s| s.interface_number() == number).collect::<Vec<_>>();
+
+            if old.is_empty() || new.is_empty() {
+                presence(changes, interface_path, new.is_empty());
+            }
+            else {
+                keyed(changes, &interface_path, "setting", old, new, |s| s.setting_number());
+            }
+        }
+    }
+}
+
+impl DescriptorDiff for &ConfigDescriptor {
+    fn diff_fields(&self, new: &Self, path: &str, changes: &mut Vec<FieldChange>) {
+        (**self).diff_fields(*new, path, changes);
+    }
+}
+
+impl DescriptorDiff for DescriptorSnapshot {
+    fn diff_fields(&self, new: &Self, path: &str, changes: &mut Vec<FieldChange>) {
+        self.device_descriptor().diff_fields(new.device_descriptor(), path, changes);
+
+        keyed(changes, path, "config",
+              self.config_descriptors().iter().collect(), new.config_descriptors().iter().collect(),
+              |c| c.number());
+    }
+}
+
+impl<'a, 'b> DescriptorDiff for &'b InterfaceDescriptor<'a> {
+    fn diff_fields(&self, new: &Self, path: &str, changes: &mut Vec<FieldChange>) {
+        (**self).diff_fields(*new, path, changes);
+    }
+}
+
+impl<'a> DescriptorDiff for InterfaceDescriptor<'a> {
+    fn diff_fields(&self, new: &Self, path: &str, changes: &mut Vec<FieldChange>) {
+        field(changes, path, "bInterfaceClass", self.class_code(), new.class_code());
+        field(changes, path, "bInterfaceSubClass", self.sub_class_code(), new.sub_class_code());
+        field(changes, path, "bInterfaceProtocol", self.protocol_code(), new.protocol_code());
+        field(changes, path, "iInterface", self.description_string_index(), new.description_string_index());
+        field(changes, path, "bNumEndpoints", self.num_endpoints(), new.num_endpoints());
+        field(changes, path, "extra", self.extra(), new.extra());
+
+        keyed(changes, path, "endpoint",
+              self.endpoint_descriptors().collect(), new.endpoint_descriptors().collect(),
+              |e| format!("{:#04x}", e.address()));
+    }
+}
+
+impl<'a> DescriptorDiff for EndpointDescriptor<'a> {
+    fn diff_fields(&self, new: &Self, path: &str, changes: &mut Vec<FieldChange>) {
+        field(changes, path, "transferType", self.transfer_type(), new.transfer_type());
+        field(changes, path, "syncType", self.sync_type(), new.sync_type());
+        field(changes, path, "usageType", self.usage_type(), new.usage_type());
+        field(changes, path, "wMaxPacketSize", self.max_packet_size(), new.max_packet_size());
+        field(changes, path, "bInterval", self.interval(), new.interval());
+        field(changes, path, "extra", self.extra(), new.extra());
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::diff;
+    use parse::{parse_config_descriptor, parse_device_descriptor};
+
+    const DEVICE: [u8; 18] = [
+        0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40,
+        0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0x01, 0x02, 0x00, 0x01,
+    ];
+
+    const CONFIG: [u8; 32] = [
+        0x09, 0x02, 0x20, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32,
+        0x09, 0x04, 0x00, 0x00, 0x02, 0xFF, 0x00, 0x00, 0x00,
+        0x07, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00,
+        0x07, 0x05, 0x01, 0x02, 0x40, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn it_reports_no_changes_for_identical_descriptors() {
+        let a = parse_config_descriptor(&CONFIG).unwrap();
+        let b = parse_config_descriptor(&CONFIG).unwrap();
+
+        assert!(diff(&a, &b).is_empty());
+    }
+
+    #[test]
+    fn it_reports_changed_device_fields() {
+        let mut bytes = DEVICE;
+        bytes[12] = 0x02;
+
+        let changes = diff(&parse_device_descriptor(&DEVICE).unwrap(), &parse_device_descriptor(&bytes).unwrap());
+
+        assert_eq!(1, changes.len());
+        assert_eq!("bcdDevice", changes[0].path);
+    }
+
+    #[test]
+    fn it_reports_changed_endpoint_fields() {
+        let mut bytes = CONFIG;
+        bytes[22] = 0x00;
+        bytes[23] = 0x02;
+
+        let changes = diff(&parse_config_descriptor(&CONFIG).unwrap(), &parse_config_descriptor(&bytes).unwrap());
+
+        assert_eq!(1, changes.len());
+        assert_eq!("interface[0].setting[0].endpoint[0x81].wMaxPacketSize", changes[0].path);
+        assert_eq!(Some("64".to_owned()), changes[0].old);
+        assert_eq!(Some("512".to_owned()), changes[0].new);
+    }
+
+    #[test]
+    fn it_reports_removed_endpoints() {
+        let mut bytes = CONFIG[..25].to_vec();
+        bytes[2] = 25;
+        bytes[13] = 1;
+
+        let changes = diff(&parse_config_descriptor(&CONFIG).unwrap(), &parse_config_descriptor(&bytes).unwrap());
+        let paths = changes.iter().map(|c| c.path.as_str()).collect::<Vec<_>>();
+
+        assert_eq!(vec!["interface[0].setting[0].bNumEndpoints", "interface[0].setting[0].endpoint[0x01]"], paths);
+        assert_eq!(None, changes[1].new);
+    }
+}
@@ -0,0 +1,72 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task;
+
+/// Whether a device handle has seen its device disconnect, shared by the handle and its
+/// transfers.
+///
+/// It's kept apart from the handle's mutex, as it's marked from the transfer callback on the
+/// event thread, which must not wait for the handle.
+pub struct Disconnect {
+    disconnected: AtomicBool,
+    watchers: Mutex<Vec<task::Waker>>,
+}
+
+impl Disconnect {
+    pub fn new() -> Disconnect {
+        Disconnect {
+            disconnected: AtomicBool::new(false),
+            watchers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected.load(Ordering::Acquire)
+    }
+
+    /// Marks the device as disconnected, and wakes the watchers the first time.
+    pub fn mark(&self) {
+        if !self.disconnected.swap(true, Ordering::AcqRel) {
+            let watchers = {
+                let mut watchers = self.watchers.lock().unwrap();
+                watchers.split_off(0)
+            };
+
+            for waker in watchers {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Future that is ready when the device of a handle has been disconnected, returned by
+/// [`DeviceHandle::disconnected`](struct.DeviceHandle.html#method.disconnected).
+pub struct DisconnectFuture {
+    disconnect: Arc<Disconnect>,
+}
+
+impl Future for DisconnectFuture {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<()> {
+        let mut watchers = self.disconnect.watchers.lock().unwrap();
+
+        // Checked while holding the lock, so a disconnect can't slip in before the waker is
+        // registered
+        if self.disconnect.is_disconnected() {
+            return task::Poll::Ready(());
+        }
+
+        if !watchers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            watchers.push(cx.waker().clone());
+        }
+
+        task::Poll::Pending
+    }
+}
+
+pub fn watch(disconnect: &Arc<Disconnect>) -> DisconnectFuture {
+    DisconnectFuture { disconnect: disconnect.clone() }
+}
@@ -0,0 +1,419 @@
+use std::any::Any;
+use std::collections::HashSet;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task;
+
+use futures_core::Stream;
+
+use config_descriptor::ConfigDescriptor;
+use device::Device;
+use device_filter::DeviceFilter;
+use device_descriptor::DeviceDescriptor;
+use device_handle::DeviceHandle;
+use error::Error;
+use hotplug::{HotplugEvent, HotplugEvents};
+use interface_descriptor::InterfaceDescriptor;
+
+/// The descriptors a class driver is probed with.
+pub struct ProbeDescriptors<'a> {
+    /// The descriptor of the device.
+    pub device: &'a DeviceDescriptor,
+
+    /// The active configuration of the device.
+    pub config: &'a ConfigDescriptor,
+
+    /// The alternate setting being probed.
+    pub setting: &'a InterfaceDescriptor<'a>,
+}
+
+/// A driver for an interface, registered with
+/// [`Context::register_driver`](struct.Context.html#method.register_driver).
+///
+/// When a device is bound with [`Context::bind_drivers`](struct.Context.html#method.bind_drivers),
+/// or as it is connected with [`Context::driver_bindings`](struct.Context.html#method.driver_bindings),
+/// the registered drivers are probed with the alternate settings of each interface, in the
+/// order the drivers were registered. The first driver that returns an instance is bound to the
+/// interface after it has been claimed, with any kernel driver detached.
+pub trait ClassDriver: Send + Sync + 'static {
+    /// Checks if the driver handles an alternate setting, and returns an unbound instance if it
+    /// does.
+    fn probe(descriptors: &ProbeDescriptors) -> Option<Self> where Self: Sized;
+
+    /// Starts driving a claimed interface, e.g., by selecting the alternate setting.
+    ///
+    /// The handle is shared with the drivers of the other interfaces of the device. If this
+    /// returns an error, binding the device fails and its interfaces are released.
+    fn bind(&mut self, handle: &Arc<DeviceHandle>, interface: u8) -> ::Result<()>;
+
+    /// Stops driving the interface before it's released.
+    ///
+    /// Drivers must drop their clones of the handle here, or the interfaces stay claimed until
+    /// the device is closed.
+    fn unbind(&mut self);
+}
+
+/// A driver with its concrete type erased.
+trait AnyDriver: Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn bind(&mut self, handle: &Arc<DeviceHandle>, interface: u8) -> ::Result<()>;
+    fn unbind(&mut self);
+}
+
+impl<D: ClassDriver> AnyDriver for D {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn bind(&mut self, handle: &Arc<DeviceHandle>, interface: u8) -> ::Result<()> {
+        ClassDriver::bind(self, handle, interface)
+    }
+
+    fn unbind(&mut self) {
+        ClassDriver::unbind(self)
+    }
+}
+
+type Probe = Box<dyn Fn(&ProbeDescriptors) -> Option<Box<dyn AnyDriver>> + Send + Sync>;
+
+/// The class drivers registered with a context.
+#[derive(Default)]
+pub struct DriverRegistry {
+    // Each driver is only probed for the devices its filter matches
+    probes: RwLock<Vec<(DeviceFilter, Probe)>>,
+}
+
+impl DriverRegistry {
+    pub fn register<D: ClassDriver>(&self, filter: DeviceFilter) {
+        self.probes.write().unwrap().push((filter, Box::new(|descriptors| {
+            D::probe(descriptors).map(|driver| Box::new(driver) as Box<dyn AnyDriver>)
+        })));
+    }
+
+    /// Returns the matching driver of each interface of the active configuration.
+    fn probe(&self, device: &Device) -> ::Result<Vec<(u8, Box<dyn AnyDriver>)>> {
+        let descriptor = device.device_descriptor()?;
+        let config = device.active_config_descriptor()?;
+        let probes = self.probes.read().unwrap();
+        let probes: Vec<_> = probes.iter()
+            .filter(|(filter, _)| filter.matches(device))
+            .map(|(_, probe)| probe)
+            .collect();
+
+        let drivers = config.interfaces().filter_map(|interface| {
+            probes.iter().find_map(|probe| {
+                interface.descriptors().find_map(|setting| {
+                    probe(&ProbeDescriptors { device: &descriptor, config: &config, setting: &setting })
+                })
+            }).map(|driver| (interface.number(), driver))
+        }).collect();
+
+        Ok(drivers)
+    }
+
+    pub fn bind(&self, device: &Device) -> ::Result<BoundDevice> {
+        let drivers = self.probe(device)?;
+
+        if drivers.is_empty() {
+            return Err(Error::NotFound);
+        }
+
+        let mut bound = BoundDevice { handle: Arc::new(device.open()?), interfaces: Vec::new() };
+
+        for (interface, driver) in drivers {
+            let handle = Arc::get_mut(&mut bound.handle).expect("handle shared before binding");
+
+            let kernel_driver_detached = handle.kernel_driver_active(interface).unwrap_or(false);
+            if kernel_driver_detached {
+                handle.detach_kernel_driver(interface)?;
+            }
+
+            if let Err(e) = handle.claim_interface(interface) {
+                if kernel_driver_detached {
+                    let _ = handle.attach_kernel_driver(interface);
+                }
+
+                return Err(e);
+            }
+
+            bound.interfaces.push(BoundInterface { interface, kernel_driver_detached, driver, bound: false });
+        }
+
+        for interface in bound.interfaces.iter_mut() {
+            interface.driver.bind(&bound.handle, interface.interface)?;
+            interface.bound = true;
+        }
+
+        Ok(bound)
+    }
+}
+
+struct BoundInterface {
+    interface: u8,
+    kernel_driver_detached: bool,
+    driver: Box<dyn AnyDriver>,
+    bound: bool,
+}
+
+/// A device with class drivers bound to its interfaces, returned by
+/// [`Context::bind_drivers`](struct.Context.html#method.bind_drivers).
+///
+/// Dropping it unbinds the drivers, releases the interfaces and reattaches the kernel drivers
+/// that were detached.
+pub struct BoundDevice {
+    handle: Arc<DeviceHandle>,
+    interfaces: Vec<BoundInterface>,
+}
+
+impl BoundDevice {
+    /// Returns the handle shared by the drivers.
+    pub fn handle(&self) -> &Arc<DeviceHandle> {
+        &self.handle
+    }
+
+    /// Returns the numbers of the interfaces with a bound driver.
+    pub fn interface_numbers(&self) -> Vec<u8> {
+        self.interfaces.iter().map(|interface| interface.interface).collect()
+    }
+
+    /// Returns the driver bound to an interface, if it is of type `D`.
+    pub fn driver<D: ClassDriver>(&self, interface: u8) -> Option<&D> {
+        self.interfaces.iter()
+            .find(|bound| bound.interface == interface)
+            .and_then(|bound| bound.driver.as_any().downcast_ref())
+    }
+
+    /// Returns the first bound driver of type `D` and the interface it's bound to.
+    pub fn find_driver<D: ClassDriver>(&self) -> Option<(u8, &D)> {
+        self.interfaces.iter().find_map(|bound| {
+            bound.driver.as_any().downcast_ref().map(|driver| (bound.interface, driver))
+        })
+    }
+}
+
+impl Drop for BoundDevice {
+    fn drop(&mut self) {
+        for interface in self.interfaces.iter_mut().filter(|interface| interface.bound) {
+            interface.driver.unbind();
+        }
+
+        let handle = match Arc::get_mut(&mut self.handle) {
+            Some(handle) => handle,
+            None => return,
+        };
+
+        for interface in &self.interfaces {
+            let _ = handle.release_interface(interface.interface);
+
+            if interface.kernel_driver_detached {
+                let _ = handle.attach_kernel_driver(interface.interface);
+            }
+        }
+    }
+}
+
+/// What happened to a device, as reported by [`DriverBindings`](struct.DriverBindings.html).
+pub enum DriverEvent {
+    /// A device was connected, or was already connected when the stream was created, and
+    /// drivers were bound to its interfaces.
+    Bound(BoundDevice),
+
+    /// A device was connected, and a driver accepted it, but binding it failed, e.g., as an
+    /// interface is claimed by another program.
+    Failed(Device, Error),
+
+    /// A bound device was disconnected. Its `BoundDevice` should be dropped.
+    Left(Device),
+}
+
+/// Stream binding the registered class drivers to the devices that are connected, returned by
+/// [`Context::driver_bindings`](struct.Context.html#method.driver_bindings).
+///
+/// Devices are bound when their arrival is polled. The stream never ends. Dropping it
+/// deregisters the hotplug events, and leaves the devices bound so far to the application.
+pub struct DriverBindings {
+    events: HotplugEvents,
+    drivers: Arc<DriverRegistry>,
+    // The bus numbers and addresses of the bound devices, as a device that has left can't be
+    // read
+    bound: HashSet<(u8, u8)>,
+}
+
+impl Stream for DriverBindings {
+    type Item = DriverEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<DriverEvent>> {
+        let this = self.get_mut();
+
+        loop {
+            let event = match Pin::new(&mut this.events).poll_next(cx) {
+                task::Poll::Ready(Some(event)) => event,
+                task::Poll::Ready(None) => return task::Poll::Ready(None),
+                task::Poll::Pending => return task::Poll::Pending,
+            };
+
+            match event {
+                HotplugEvent::Arrived(device) => match this.drivers.bind(&device) {
+                    Ok(bound) => {
+                        this.bound.insert((device.bus_number(), device.address()));
+                        return task::Poll::Ready(Some(DriverEvent::Bound(bound)));
+                    },
+                    Err(Error::NotFound) => {},
+                    Err(e) => return task::Poll::Ready(Some(DriverEvent::Failed(device, e))),
+                },
+                HotplugEvent::Left(device) => {
+                    if this.bound.remove(&(device.bus_number(), device.address())) {
+                        return task::Poll::Ready(Some(DriverEvent::Left(device)));
+                    }
+                },
+            }
+        }
+    }
+}
+
+pub fn bindings(events: HotplugEvents, drivers: Arc<DriverRegistry>) -> DriverBindings {
+    DriverBindings { events, drivers, bound: HashSet::new() }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    extern crate futures;
+
+    use super::*;
+    use context::Context;
+    use test_helpers::{config_bytes, device_bytes, Call, MockBackend};
+    use self::futures::task::noop_waker;
+
+    fn accepts(descriptors: &ProbeDescriptors, class: u8) -> bool {
+        u8::from(descriptors.setting.class_code()) == class
+    }
+
+    /// Drives vendor specific interfaces, holding the handle while bound.
+    struct First(Option<Arc<DeviceHandle>>);
+
+    impl ClassDriver for First {
+        fn probe(descriptors: &ProbeDescriptors) -> Option<First> {
+            if accepts(descriptors, 0xFF) { Some(First(None)) } else { None }
+        }
+
+        fn bind(&mut self, handle: &Arc<DeviceHandle>, _interface: u8) -> ::Result<()> {
+            self.0 = Some(handle.clone());
+            Ok(())
+        }
+
+        fn unbind(&mut self) {
+            self.0 = None;
+        }
+    }
+
+    /// Drives vendor specific interfaces too, for the drivers registered after `First`.
+    struct Second;
+
+    impl ClassDriver for Second {
+        fn probe(descriptors: &ProbeDescriptors) -> Option<Second> {
+            if accepts(descriptors, 0xFF) { Some(Second) } else { None }
+        }
+
+        fn bind(&mut self, _handle: &Arc<DeviceHandle>, _interface: u8) -> ::Result<()> {
+            Ok(())
+        }
+
+        fn unbind(&mut self) {}
+    }
+
+    /// Accepts interfaces of class 0xFE, and then fails to bind them.
+    struct Failing;
+
+    impl ClassDriver for Failing {
+        fn probe(descriptors: &ProbeDescriptors) -> Option<Failing> {
+            if accepts(descriptors, 0xFE) { Some(Failing) } else { None }
+        }
+
+        fn bind(&mut self, _handle: &Arc<DeviceHandle>, _interface: u8) -> ::Result<()> {
+            Err(Error::Busy)
+        }
+
+        fn unbind(&mut self) {}
+    }
+
+    fn context(classes: &[u8], kernel_drivers: Vec<u8>) -> (&'static MockBackend, Context) {
+        let mut backend = MockBackend::new(vec![(device_bytes(0x1234, 0x5678), config_bytes(classes))]);
+        backend.kernel_drivers = kernel_drivers;
+        let backend = backend.leak();
+        (backend, Context::with_backend(backend))
+    }
+
+    fn device(context: &Context) -> Device {
+        context.devices().unwrap().into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn it_binds_the_first_driver_registered() {
+        let (backend, context) = context(&[0xFF], Vec::new());
+        context.register_driver::<First>();
+        context.register_driver::<Second>();
+
+        let bound = context.bind_drivers(&device(&context)).unwrap();
+        assert!(bound.driver::<First>(0).unwrap().0.is_some());
+        assert!(bound.driver::<Second>(0).is_none());
+        assert_eq!(vec![0], bound.interface_numbers());
+        drop(bound);
+
+        assert_eq!(vec![Call::Open, Call::Claim(0), Call::Release(0), Call::Close], backend.calls());
+    }
+
+    #[test]
+    fn it_only_probes_the_drivers_matching_the_device() {
+        let (_, context) = context(&[0xFF], Vec::new());
+        context.register_driver_matching::<First>(DeviceFilter::new().vendor(0x9999));
+        context.register_driver_matching::<Second>(DeviceFilter::new().vendor(0x1234));
+
+        let bound = context.bind_drivers(&device(&context)).unwrap();
+        assert_eq!(Some(0), bound.find_driver::<Second>().map(|(interface, _)| interface));
+        assert!(bound.find_driver::<First>().is_none());
+    }
+
+    #[test]
+    fn it_leaves_a_device_without_drivers_closed() {
+        let (backend, context) = context(&[0x03], Vec::new());
+        context.register_driver::<First>();
+
+        assert!(matches!(context.bind_drivers(&device(&context)), Err(Error::NotFound)));
+        assert!(backend.calls().is_empty());
+    }
+
+    #[test]
+    fn it_releases_the_claimed_interfaces_when_a_driver_fails_to_bind() {
+        let (backend, context) = context(&[0xFF, 0xFE], vec![1]);
+        context.register_driver::<First>();
+        context.register_driver::<Failing>();
+
+        assert!(matches!(context.bind_drivers(&device(&context)), Err(Error::Busy)));
+        assert_eq!(vec![Call::Open, Call::Claim(0), Call::Detach(1), Call::Claim(1),
+                        Call::Release(0), Call::Release(1), Call::Attach(1), Call::Close],
+                   backend.calls());
+    }
+
+    #[test]
+    fn it_binds_devices_as_they_arrive() {
+        let (backend, context) = context(&[0xFF], Vec::new());
+        context.register_driver::<First>();
+        let mut bindings = context.driver_bindings().unwrap();
+        let waker = noop_waker();
+        let mut cx = task::Context::from_waker(&waker);
+
+        let bound = match Pin::new(&mut bindings).poll_next(&mut cx) {
+            task::Poll::Ready(Some(DriverEvent::Bound(bound))) => bound,
+            _ => panic!("device not bound"),
+        };
+        assert!(bound.driver::<First>(0).is_some());
+        assert!(Pin::new(&mut bindings).poll_next(&mut cx).is_pending());
+
+        backend.disconnect(0);
+        assert!(matches!(Pin::new(&mut bindings).poll_next(&mut cx), task::Poll::Ready(Some(DriverEvent::Left(_)))));
+        drop(bound);
+        assert_eq!(vec![Call::Open, Call::Claim(0), Call::Release(0), Call::Close], backend.calls());
+    }
+}
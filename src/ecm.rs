@@ -0,0 +1,357 @@
+//! CDC Ethernet Control Model (ECM) support.
+//!
+//! The frames sent and received are complete Ethernet frames without the frame check sequence,
+//! which is what userspace network stacks such as smoltcp expect from a raw device.
+
+use std::pin::Pin;
+use std::task;
+use std::time::Duration;
+
+use futures_core::Stream;
+use futures_sink::Sink;
+
+pub use cdc::{MacAddressFuture, PacketFilter, NetworkEvent, NetworkEvents, parse_mac_address};
+
+use cdc;
+use config_descriptor::ConfigDescriptor;
+use control::{self, ControlFuture};
+use device::Device;
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::{Direction, Recipient, RequestType, TransferType, request_type};
+use read_queue::{Backpressure, QueueDepth, ReadQueue};
+use transfer::TransferFuture;
+use transfer_queue::{self, TransferQueue};
+
+const SUBCLASS_ECM: u8 = 0x06;
+
+const ETHERNET_NETWORKING_FUNCTIONAL: u8 = 0x0F;
+
+const SET_ETHERNET_PACKET_FILTER: u8 = 0x43;
+
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Maximum segment size used when the functional descriptor is missing.
+const DEFAULT_MAX_SEGMENT_SIZE: u16 = 1514;
+
+/// Number of transmit transfers that may be in flight.
+const TX_TRANSFERS: usize = 4;
+
+/// The interfaces of one CDC-ECM function.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct EcmInterfaces {
+    /// The communications interface, which carries class requests and notifications.
+    pub control_interface: u8,
+
+    /// The data interface, which has the bulk endpoints.
+    pub data_interface: u8,
+
+    /// Index of the string descriptor holding the MAC address, or zero if there is none.
+    pub mac_address_index: u8,
+
+    /// The largest Ethernet frame the function handles (`wMaxSegmentSize`).
+    pub max_segment_size: u16,
+}
+
+/// Finds the CDC-ECM functions of a configuration.
+pub fn find_ecm_interfaces(config: &ConfigDescriptor) -> Vec<EcmInterfaces> {
+    cdc::find_functions(config, SUBCLASS_ECM).into_iter().map(|(control_interface, data_interface)| {
+        let functional = config.interfaces()
+            .find(|i| i.number() == control_interface)
+            .and_then(|i| i.descriptors().next())
+            .and_then(|setting| cdc::functional_descriptor(setting.extra(), ETHERNET_NETWORKING_FUNCTIONAL).map(|payload| payload.to_vec()));
+
+        let (mac_address_index, max_segment_size) = match functional {
+            Some(ref payload) if payload.len() >= 8 => (payload[1], u16::from_le_bytes([payload[6], payload[7]])),
+            _ => (0, DEFAULT_MAX_SEGMENT_SIZE),
+        };
+
+        EcmInterfaces { control_interface, data_interface, mac_address_index, max_segment_size }
+    }).collect()
+}
+
+/// An open CDC-ECM network function.
+///
+/// Received frames are read from [`frames`](#method.frames) and frames are sent through
+/// [`sink`](#method.sink). Both can be used at the same time.
+pub struct EcmDevice {
+    handle: DeviceHandle,
+    interfaces: EcmInterfaces,
+    notification_endpoint: Option<(u8, u16)>,
+    bulk_in: (u8, usize),
+    bulk_out: (u8, usize),
+    rx_depth: QueueDepth,
+    detached: Vec<u8>,
+}
+
+impl EcmDevice {
+    /// Opens a device, claims the interfaces of a CDC-ECM function and starts the data
+    /// interface.
+    ///
+    /// The packet filter is set to [`PacketFilter::default`](struct.PacketFilter.html), since
+    /// some functions don't pass any frames until it has been set. Kernel drivers bound to the
+    /// interfaces are detached and reattached when the device is dropped.
+    ///
+    /// ## Errors
+    ///
+    /// * `NotFound` if the data interface has no setting with a bulk IN and a bulk OUT endpoint.
+    /// * Any error returned while opening the device, claiming the interfaces or setting the
+    ///   packet filter.
+    pub fn open(device: &Device, interfaces: &EcmInterfaces) -> ::Result<EcmDevice> {
+        let (notification_endpoint, data_setting, bulk_in, bulk_out) = {
+            let config = device.active_config_descriptor()?;
+
+            let notification = config.interfaces()
+                .find(|i| i.number() == interfaces.control_interface)
+                .and_then(|i| i.descriptors().next())
+                .ok_or(Error::NotFound)?
+                .first_endpoint(TransferType::Interrupt, Direction::In)
+                .map(|ep| (ep.address(), ep.max_packet_size()));
+
+            // The first setting of the data interface has no endpoints, which stops the function
+            let data = config.interfaces()
+                .find(|i| i.number() == interfaces.data_interface)
+                .ok_or(Error::NotFound)?;
+            let (data_setting, bulk_in, bulk_out) = data.descriptors().filter_map(|setting| {
+                let bulk_in = setting.first_endpoint(TransferType::Bulk, Direction::In)?;
+                let bulk_out = setting.first_endpoint(TransferType::Bulk, Direction::Out)?;
+                Some((setting.setting_number(),
+                      (bulk_in.address(), bulk_in.max_packet_size() as usize),
+                      (bulk_out.address(), bulk_out.max_packet_size() as usize)))
+            }).next().ok_or(Error::NotFound)?;
+
+            (notification, data_setting, bulk_in, bulk_out)
+        };
+
+        let mut handle = device.open()?;
+        let mut detached = Vec::new();
+
+        for &interface in &[interfaces.control_interface, interfaces.data_interface] {
+            if handle.kernel_driver_active(interface).unwrap_or(false) {
+                handle.detach_kernel_driver(interface)?;
+                detached.push(interface);
+            }
+
+            handle.claim_interface(interface)?;
+        }
+
+        handle.set_alternate_setting(interfaces.data_interface, data_setting)?;
+
+        handle.write_control(request_type(Direction::Out, RequestType::Class, Recipient::Interface),
+                             SET_ETHERNET_PACKET_FILTER,
+                             PacketFilter::default().bits(),
+                             interfaces.control_interface as u16,
+                             &[],
+                             CONTROL_TIMEOUT)?;
+
+        let rx_depth = QueueDepth::for_endpoint(TransferType::Bulk, device.speed(), bulk_in.1 as u16);
+
+        Ok(EcmDevice { handle, interfaces: *interfaces, notification_endpoint, bulk_in, bulk_out, rx_depth, detached })
+    }
+
+    /// Returns the handle of the device.
+    pub fn handle(&self) -> &DeviceHandle {
+        &self.handle
+    }
+
+    /// Returns the interfaces of the function.
+    pub fn interfaces(&self) -> &EcmInterfaces {
+        &self.interfaces
+    }
+
+    /// Reads the MAC address of the function.
+    ///
+    /// The future resolves to `Err(NotFound)` if the function has no MAC address string.
+    pub fn mac_address<'a>(&'a self) -> MacAddressFuture<'a> {
+        cdc::mac_address(&self.handle, self.interfaces.mac_address_index)
+    }
+
+    /// Sets the packet types that the function passes to the host.
+    pub fn set_packet_filter(&self, filter: &PacketFilter) -> ControlFuture<usize> {
+        control::write(&self.handle,
+                       request_type(Direction::Out, RequestType::Class, Recipient::Interface),
+                       SET_ETHERNET_PACKET_FILTER,
+                       filter.bits(),
+                       self.interfaces.control_interface as u16,
+                       &[])
+    }
+
+    /// Returns a stream of link state changes.
+    ///
+    /// The stream yields `Err(NotFound)` if the communications interface has no notification
+    /// endpoint, and ends after the first error.
+    pub fn events<'a>(&'a self) -> NetworkEvents<'a> {
+        cdc::network_events(&self.handle, self.notification_endpoint)
+    }
+
+    /// Returns a stream of received Ethernet frames.
+    ///
+    /// The stream ends after the first error.
+    pub fn frames<'a>(&'a self) -> Frames<'a> {
+        Frames { device: self, reads: ReadQueue::new(self.rx_depth.transfers), done: false }
+    }
+
+    /// Returns a sink that sends Ethernet frames.
+    pub fn sink<'a>(&'a self) -> FrameSink<'a> {
+        FrameSink { device: self, transfers: TransferQueue::with_capacity(TX_TRANSFERS) }
+    }
+
+    fn submit_read(&self) -> ::Result<TransferFuture> {
+        let (endpoint, packet_size) = self.bulk_in;
+        let max_segment_size = self.interfaces.max_segment_size as usize;
+
+        // Whole packets, so that a frame of the maximum size doesn't overflow
+        let length = (max_segment_size + packet_size - 1) / packet_size.max(1) * packet_size;
+
+        transfer_queue::submit_bulk_read(&self.handle, endpoint.into(), length.max(max_segment_size))
+    }
+
+    fn submit_write(&self, frame: &[u8]) -> ::Result<TransferFuture> {
+        transfer_queue::submit_bulk_write(&self.handle, self.bulk_out.0.into(), frame)
+    }
+}
+
+impl Drop for EcmDevice {
+    fn drop(&mut self) {
+        let _ = self.handle.set_alternate_setting(self.interfaces.data_interface, 0);
+
+        for &interface in &self.detached {
+            let _ = self.handle.release_interface(interface);
+            let _ = self.handle.attach_kernel_driver(interface);
+        }
+    }
+}
+
+/// Stream of received frames returned by [`EcmDevice::frames`](struct.EcmDevice.html#method.frames).
+pub struct Frames<'a> {
+    device: &'a EcmDevice,
+    reads: ReadQueue<TransferFuture>,
+    done: bool,
+}
+
+impl<'a> Frames<'a> {
+    /// Sets the number of reads kept in flight, by default from
+    /// [`QueueDepth::for_endpoint`](../struct.QueueDepth.html#method.for_endpoint). Each read
+    /// receives one frame.
+    pub fn queue_depth(mut self, transfers: usize) -> Frames<'a> {
+        self.reads.set_depth(transfers);
+        self
+    }
+
+    /// Sets what happens to frames the consumer is too slow to take, blocking by default.
+    pub fn backpressure(mut self, backpressure: Backpressure) -> Frames<'a> {
+        self.reads.set_backpressure(backpressure);
+        self
+    }
+
+    /// Returns the number of frames dropped because the consumer was too slow.
+    pub fn dropped(&self) -> u64 {
+        self.reads.dropped()
+    }
+}
+
+impl<'a> Stream for Frames<'a> {
+    type Item = ::Result<Vec<u8>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        let device = this.device;
+        while !this.done {
+            let result = match this.reads.poll_next(cx, || device.submit_read()) {
+                task::Poll::Pending => return task::Poll::Pending,
+                task::Poll::Ready(result) => result,
+            };
+
+            match result.and_then(|transfer| transfer.check_status().map(|_| transfer)) {
+                // Zero-length packets carry no frame
+                Ok(ref transfer) if transfer.get_buffer().is_empty() => {},
+                Ok(transfer) => return task::Poll::Ready(Some(Ok(transfer.get_buffer().to_vec()))),
+                Err(e) => {
+                    this.done = true;
+                    this.reads.clear();
+                    return task::Poll::Ready(Some(Err(e)));
+                },
+            }
+        }
+
+        task::Poll::Ready(None)
+    }
+}
+
+/// Sink of frames returned by [`EcmDevice::sink`](struct.EcmDevice.html#method.sink).
+///
+/// Frames are submitted as they are sent, with up to four transfers in flight.
+pub struct FrameSink<'a> {
+    device: &'a EcmDevice,
+    transfers: TransferQueue<TransferFuture>,
+}
+
+impl<'a> Sink<Vec<u8>> for FrameSink<'a> {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<::Result<()>> {
+        self.get_mut().transfers.poll_completed(cx, TX_TRANSFERS - 1)
+    }
+
+    fn start_send(self: Pin<&mut Self>, frame: Vec<u8>) -> ::Result<()> {
+        let this = self.get_mut();
+
+        if frame.len() > this.device.interfaces.max_segment_size as usize {
+            return Err(Error::InvalidParam);
+        }
+
+        this.transfers.push(this.device.submit_write(&frame)?);
+
+        // A frame that fills its last packet is terminated by a zero-length packet
+        if frame.len().is_multiple_of(this.device.bulk_out.1.max(1)) {
+            this.transfers.push(this.device.submit_write(&[])?);
+        }
+
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<::Result<()>> {
+        self.get_mut().transfers.poll_completed(cx, 0)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<::Result<()>> {
+        self.get_mut().transfers.poll_completed(cx, 0)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::mem;
+
+    const ECM_EXTRA: [u8; 18] = [
+        0x05, 0x24, 0x06, 0x00, 0x01,
+        0x0D, 0x24, 0x0F, 0x04, 0x00, 0x00, 0x00, 0x00, 0xEA, 0x05, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn it_finds_ecm_interfaces() {
+        let control = interface!(merge!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x83, bmAttributes: 0x03))
+                                        => bInterfaceClass: 0x02, bInterfaceSubClass: 0x06, extra: ECM_EXTRA.as_ptr(), extra_length: 18));
+        let data = interface!(interface_descriptor!(bInterfaceNumber: 1, bInterfaceClass: 0x0A));
+        let config = config_descriptor!(control, data);
+        let config = unsafe { ::config_descriptor::from_libusb(&config) };
+
+        assert_eq!(vec![EcmInterfaces { control_interface: 0, data_interface: 1, mac_address_index: 4, max_segment_size: 1514 }],
+                   find_ecm_interfaces(&config));
+        mem::forget(config);
+    }
+
+    #[test]
+    fn it_defaults_without_ethernet_functional_descriptor() {
+        let control = interface!(interface_descriptor!(bInterfaceClass: 0x02, bInterfaceSubClass: 0x06));
+        let data = interface!(interface_descriptor!(bInterfaceNumber: 1, bInterfaceClass: 0x0A));
+        let config = config_descriptor!(control, data);
+        let config = unsafe { ::config_descriptor::from_libusb(&config) };
+
+        assert_eq!(0, find_ecm_interfaces(&config)[0].mac_address_index);
+        mem::forget(config);
+    }
+}
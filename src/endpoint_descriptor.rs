@@ -1,8 +1,14 @@
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
 
 use libusb::*;
 
-use fields::{Direction, TransferType, SyncType, UsageType};
+use fields::{Direction, Speed, TransferType, SyncType, UsageType};
+use extra_descriptors::{self, ExtraDescriptors};
+use iso_plan::{self, IsoPlan, IsoPlanError};
+
+const SS_ENDPOINT_COMPANION: u8 = 0x30;
 
 /// Describes an endpoint.
 pub struct EndpointDescriptor<'a> {
@@ -62,15 +68,165 @@ impl<'a> EndpointDescriptor<'a> {
         }
     }
 
+    /// Returns the endpoint's synchronisation mode and usage type.
+    ///
+    /// Returns `None` if the endpoint is not isochronous, since the bits have a different
+    /// meaning (or none at all) for other transfer types.
+    pub fn iso_attributes(&self) -> Option<(SyncType, UsageType)> {
+        match self.transfer_type() {
+            TransferType::Isochronous => Some((self.sync_type(), self.usage_type())),
+            _                         => None,
+        }
+    }
+
     /// Returns the endpoint's maximum packet size.
     pub fn max_packet_size(&self) -> u16 {
         self.descriptor.wMaxPacketSize
     }
 
+    /// Returns the number of bytes the endpoint can move in one service interval.
+    ///
+    /// High-speed isochronous and interrupt endpoints can do up to three transactions per
+    /// microframe. The number of additional transactions is encoded in bits 11 and 12 of
+    /// `wMaxPacketSize`.
+    pub fn max_bytes_per_interval(&self) -> usize {
+        let size = self.descriptor.wMaxPacketSize as usize;
+        (size & 0x07FF) * (1 + ((size >> 11) & 0x03))
+    }
+
     /// Returns the endpoint's polling interval.
     pub fn interval(&self) -> u8 {
         self.descriptor.bInterval
     }
+
+    /// Returns the endpoint's polling interval as a duration for a device operating at `speed`.
+    ///
+    /// For full- and low-speed interrupt endpoints, `bInterval` is a number of 1 ms frames. For
+    /// full-speed isochronous endpoints, it is an exponent giving 2<sup>bInterval-1</sup> frames.
+    /// At high speed and above, both interrupt and isochronous endpoints use the exponent
+    /// encoding in units of 125 µs microframes. Out of range values are clamped to the nearest
+    /// valid value.
+    ///
+    /// Returns `None` for control and bulk endpoints, which are not polled, or if the speed is
+    /// unknown.
+    pub fn interval_duration(&self, speed: Speed) -> Option<Duration> {
+        let interval = self.descriptor.bInterval;
+        let exponent = |interval: u8| 1u64 << (interval.clamp(1, 16) - 1);
+
+        match (self.transfer_type(), speed) {
+            (TransferType::Interrupt, Speed::Low) |
+            (TransferType::Interrupt, Speed::Full) => {
+                Some(Duration::from_millis(u64::from(interval.max(1))))
+            },
+            (TransferType::Isochronous, Speed::Low) |
+            (TransferType::Isochronous, Speed::Full) => {
+                Some(Duration::from_millis(exponent(interval)))
+            },
+            (TransferType::Interrupt, Speed::High) |
+            (TransferType::Interrupt, Speed::Super) |
+            (TransferType::Isochronous, Speed::High) |
+            (TransferType::Isochronous, Speed::Super) => {
+                Some(Duration::from_micros(125 * exponent(interval)))
+            },
+            _ => None,
+        }
+    }
+
+    /// Plans how to stream `bytes_per_second` through the endpoint of a device operating at
+    /// `speed`, with transfers spanning at least `transfer_duration`.
+    ///
+    /// The packet size includes the additional transactions of high-bandwidth endpoints, and at
+    /// super speed the bursts and multiplier of the SuperSpeed Endpoint Companion descriptor.
+    ///
+    /// ## Errors
+    ///
+    /// * `NotPeriodic` for control and bulk endpoints, or if the speed is unknown.
+    /// * `InsufficientBandwidth` if the endpoint can't carry the rate, in which case the stream
+    ///   would silently lose data. Another alternate setting may have a larger endpoint.
+    pub fn iso_plan(&self, speed: Speed, bytes_per_second: u64, transfer_duration: Duration) -> Result<IsoPlan, IsoPlanError> {
+        let interval = self.interval_duration(speed).ok_or(IsoPlanError::NotPeriodic)?;
+        let bytes_per_interval = match speed {
+            Speed::Super => companion_bytes_per_interval(self.descriptor.wMaxPacketSize, self.transfer_type(), self.extra()),
+            _ => self.max_bytes_per_interval(),
+        };
+
+        iso_plan::plan(bytes_per_interval, interval, bytes_per_second, transfer_duration)
+    }
+
+    /// Returns the class- or vendor-specific descriptors that follow the endpoint descriptor.
+    pub fn extra(&self) -> &'a [u8] {
+        unsafe {
+            extra_descriptors::extra_from_libusb(self.descriptor.extra,
+                                                 self.descriptor.extra_length)
+        }
+    }
+
+    /// Returns an iterator over the descriptors in [`extra`](#method.extra).
+    pub fn extra_descriptors(&self) -> ExtraDescriptors<'a> {
+        ExtraDescriptors::new(self.extra())
+    }
+
+    /// Returns the number of bulk streams the endpoint supports, from its SuperSpeed Endpoint
+    /// Companion descriptor.
+    ///
+    /// Returns 0 for endpoints without streams, including all endpoints of devices that aren't
+    /// SuperSpeed.
+    pub fn max_streams(&self) -> u32 {
+        if self.transfer_type() != TransferType::Bulk {
+            return 0;
+        }
+
+        companion_max_streams(self.extra())
+    }
+}
+
+fn companion_max_streams(extra: &[u8]) -> u32 {
+    ExtraDescriptors::new(extra)
+        .find(|&(descriptor_type, payload)| descriptor_type == SS_ENDPOINT_COMPANION && payload.len() >= 2)
+        .map_or(0, |(_, payload)| match payload[1] & 0x1F {
+            0 => 0,
+            exponent => 1 << exponent,
+        })
+}
+
+/// Returns the bytes a SuperSpeed endpoint moves per interval, with the bursts and, for
+/// isochronous endpoints, the multiplier of its companion descriptor.
+fn companion_bytes_per_interval(max_packet_size: u16, transfer_type: TransferType, extra: &[u8]) -> usize {
+    let packet_size = (max_packet_size & 0x07FF) as usize;
+
+    ExtraDescriptors::new(extra)
+        .find(|&(descriptor_type, payload)| descriptor_type == SS_ENDPOINT_COMPANION && payload.len() >= 2)
+        .map_or(packet_size, |(_, payload)| {
+            let bursts = 1 + payload[0] as usize;
+            let mult = match transfer_type {
+                TransferType::Isochronous => 1 + (payload[1] & 0x03) as usize,
+                _ => 1,
+            };
+            packet_size * bursts * mult
+        })
+}
+
+impl<'a> EndpointDescriptor<'a> {
+    fn key(&self) -> (u8, u8, u8, u8, u16, u8, u8, u8, &'a [u8]) {
+        let d = self.descriptor;
+
+        (d.bLength, d.bDescriptorType, d.bEndpointAddress, d.bmAttributes, d.wMaxPacketSize,
+         d.bInterval, d.bRefresh, d.bSynchAddress, self.extra())
+    }
+}
+
+impl<'a, 'b> PartialEq<EndpointDescriptor<'b>> for EndpointDescriptor<'a> {
+    fn eq(&self, other: &EndpointDescriptor<'b>) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl<'a> Eq for EndpointDescriptor<'a> {}
+
+impl<'a> Hash for EndpointDescriptor<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
 }
 
 impl<'a> fmt::Debug for EndpointDescriptor<'a> {
@@ -88,6 +244,22 @@ impl<'a> fmt::Debug for EndpointDescriptor<'a> {
     }
 }
 
+impl<'a> fmt::Display for EndpointDescriptor<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(fmt, "Endpoint {:#04x} {:?} {:?}, max packet size {}",
+               self.address(), self.direction(), self.transfer_type(), self.max_packet_size())?;
+
+        if let Some((sync_type, usage_type)) = self.iso_attributes() {
+            write!(fmt, ", {:?} {:?}", sync_type, usage_type)?;
+        }
+
+        match self.transfer_type() {
+            TransferType::Interrupt | TransferType::Isochronous => write!(fmt, ", interval {}", self.interval()),
+            _ => Ok(()),
+        }
+    }
+}
+
 #[doc(hidden)]
 pub fn from_libusb(endpoint: &libusb_endpoint_descriptor) -> EndpointDescriptor {
     EndpointDescriptor { descriptor: endpoint }
@@ -96,7 +268,8 @@ pub fn from_libusb(endpoint: &libusb_endpoint_descriptor) -> EndpointDescriptor
 
 #[cfg(test)]
 mod test {
-    use ::fields::{Direction,TransferType,SyncType,UsageType};
+    use std::time::Duration;
+    use ::fields::{Direction,Speed,TransferType,SyncType,UsageType};
 
     #[test]
     fn it_interprets_number_for_output_endpoints() {
@@ -149,6 +322,20 @@ mod test {
         assert_eq!(UsageType::Reserved,     super::from_libusb(&endpoint_descriptor!(bmAttributes: 0b0011_0001)).usage_type());
     }
 
+    #[test]
+    fn it_has_iso_attributes_for_isochronous_endpoints() {
+        assert_eq!(Some((SyncType::Asynchronous, UsageType::Data)),     super::from_libusb(&endpoint_descriptor!(bmAttributes: 0b0000_0101)).iso_attributes());
+        assert_eq!(Some((SyncType::NoSync, UsageType::Feedback)),       super::from_libusb(&endpoint_descriptor!(bmAttributes: 0b0001_0001)).iso_attributes());
+        assert_eq!(Some((SyncType::Adaptive, UsageType::FeedbackData)), super::from_libusb(&endpoint_descriptor!(bmAttributes: 0b0010_1001)).iso_attributes());
+    }
+
+    #[test]
+    fn it_has_no_iso_attributes_for_other_endpoints() {
+        assert_eq!(None, super::from_libusb(&endpoint_descriptor!(bmAttributes: 0b0000_0000)).iso_attributes());
+        assert_eq!(None, super::from_libusb(&endpoint_descriptor!(bmAttributes: 0b0000_0110)).iso_attributes());
+        assert_eq!(None, super::from_libusb(&endpoint_descriptor!(bmAttributes: 0b0011_0011)).iso_attributes());
+    }
+
     #[test]
     fn it_has_max_packet_size() {
         assert_eq!(64,    super::from_libusb(&endpoint_descriptor!(wMaxPacketSize: 64)).max_packet_size());
@@ -156,10 +343,86 @@ mod test {
         assert_eq!(65535, super::from_libusb(&endpoint_descriptor!(wMaxPacketSize: 65535)).max_packet_size());
     }
 
+    #[test]
+    fn it_multiplies_max_bytes_per_interval_by_transactions() {
+        assert_eq!(1024, super::from_libusb(&endpoint_descriptor!(wMaxPacketSize: 0x0400)).max_bytes_per_interval());
+        assert_eq!(2048, super::from_libusb(&endpoint_descriptor!(wMaxPacketSize: 0x0C00)).max_bytes_per_interval());
+        assert_eq!(3072, super::from_libusb(&endpoint_descriptor!(wMaxPacketSize: 0x1400)).max_bytes_per_interval());
+    }
+
     #[test]
     fn it_has_interval() {
         assert_eq!(1,   super::from_libusb(&endpoint_descriptor!(bInterval: 1)).interval());
         assert_eq!(20,  super::from_libusb(&endpoint_descriptor!(bInterval: 20)).interval());
         assert_eq!(255, super::from_libusb(&endpoint_descriptor!(bInterval: 255)).interval());
     }
+
+    #[test]
+    fn it_converts_full_speed_interrupt_interval_from_frames() {
+        assert_eq!(Some(Duration::from_millis(10)),  super::from_libusb(&endpoint_descriptor!(bmAttributes: 0b0000_0011, bInterval: 10)).interval_duration(Speed::Full));
+        assert_eq!(Some(Duration::from_millis(255)), super::from_libusb(&endpoint_descriptor!(bmAttributes: 0b0000_0011, bInterval: 255)).interval_duration(Speed::Low));
+        assert_eq!(Some(Duration::from_millis(1)),   super::from_libusb(&endpoint_descriptor!(bmAttributes: 0b0000_0011, bInterval: 0)).interval_duration(Speed::Full));
+    }
+
+    #[test]
+    fn it_converts_full_speed_isochronous_interval_from_exponent() {
+        assert_eq!(Some(Duration::from_millis(1)), super::from_libusb(&endpoint_descriptor!(bmAttributes: 0b0000_0001, bInterval: 1)).interval_duration(Speed::Full));
+        assert_eq!(Some(Duration::from_millis(8)), super::from_libusb(&endpoint_descriptor!(bmAttributes: 0b0000_0001, bInterval: 4)).interval_duration(Speed::Full));
+    }
+
+    #[test]
+    fn it_converts_high_speed_interval_from_exponent_in_microframes() {
+        assert_eq!(Some(Duration::from_micros(125)),  super::from_libusb(&endpoint_descriptor!(bmAttributes: 0b0000_0011, bInterval: 1)).interval_duration(Speed::High));
+        assert_eq!(Some(Duration::from_millis(1)),    super::from_libusb(&endpoint_descriptor!(bmAttributes: 0b0000_0011, bInterval: 4)).interval_duration(Speed::High));
+        assert_eq!(Some(Duration::from_millis(4096)), super::from_libusb(&endpoint_descriptor!(bmAttributes: 0b0000_0001, bInterval: 16)).interval_duration(Speed::Super));
+        assert_eq!(Some(Duration::from_millis(4096)), super::from_libusb(&endpoint_descriptor!(bmAttributes: 0b0000_0001, bInterval: 200)).interval_duration(Speed::High));
+    }
+
+    #[test]
+    fn it_has_no_interval_duration_for_unpolled_endpoints() {
+        assert_eq!(None, super::from_libusb(&endpoint_descriptor!(bmAttributes: 0b0000_0010, bInterval: 4)).interval_duration(Speed::High));
+        assert_eq!(None, super::from_libusb(&endpoint_descriptor!(bmAttributes: 0b0000_0000, bInterval: 4)).interval_duration(Speed::Full));
+        assert_eq!(None, super::from_libusb(&endpoint_descriptor!(bmAttributes: 0b0000_0011, bInterval: 4)).interval_duration(Speed::Unknown));
+    }
+
+    #[test]
+    fn it_compares_fields_and_extra() {
+        let extra = [0x02, 0x30];
+        assert_eq!(super::from_libusb(&endpoint_descriptor!(bEndpointAddress: 0x81)), super::from_libusb(&endpoint_descriptor!(bEndpointAddress: 0x81)));
+        assert!(super::from_libusb(&endpoint_descriptor!(bEndpointAddress: 0x81)) != super::from_libusb(&endpoint_descriptor!(bEndpointAddress: 0x82)));
+        assert!(super::from_libusb(&endpoint_descriptor!(bEndpointAddress: 0x81)) != super::from_libusb(&endpoint_descriptor!(bEndpointAddress: 0x81, extra: extra.as_ptr(), extra_length: 2)));
+    }
+
+    #[test]
+    fn it_has_extra() {
+        let extra = [0x06, 0x30, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(&extra[..], super::from_libusb(&endpoint_descriptor!(extra: extra.as_ptr(), extra_length: 6)).extra());
+        assert_eq!(0, super::from_libusb(&endpoint_descriptor!(bInterval: 1)).extra().len());
+    }
+
+    #[test]
+    fn it_reads_max_streams_from_companion() {
+        let extra = [0x06, 0x30, 0x0F, 0x05, 0x00, 0x00];
+        assert_eq!(32, super::from_libusb(&endpoint_descriptor!(bmAttributes: 0b0000_0010, extra: extra.as_ptr(), extra_length: 6)).max_streams());
+        assert_eq!(0, super::from_libusb(&endpoint_descriptor!(bmAttributes: 0b0000_0011, extra: extra.as_ptr(), extra_length: 6)).max_streams());
+        assert_eq!(0, super::from_libusb(&endpoint_descriptor!(bmAttributes: 0b0000_0010)).max_streams());
+    }
+
+    #[test]
+    fn it_plans_super_speed_iso_with_companion_bursts() {
+        // 4 bursts of 1024 bytes, twice per interval
+        let extra = [0x06, 0x30, 0x03, 0x01, 0x00, 0x20];
+        let descriptor = endpoint_descriptor!(bmAttributes: 0b0000_0101, wMaxPacketSize: 1024, bInterval: 1, extra: extra.as_ptr(), extra_length: 6);
+        let endpoint = super::from_libusb(&descriptor);
+        assert_eq!(8192, endpoint.iso_plan(Speed::Super, 1_000_000, Duration::from_millis(1)).unwrap().packet_size);
+        assert_eq!(1024, endpoint.iso_plan(Speed::High, 1_000_000, Duration::from_millis(1)).unwrap().packet_size);
+        assert_eq!(Err(::IsoPlanError::NotPeriodic), endpoint.iso_plan(Speed::Unknown, 1_000_000, Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn it_displays_summary() {
+        assert_eq!("Endpoint 0x02 Out Bulk, max packet size 512", super::from_libusb(&endpoint_descriptor!(bEndpointAddress: 0x02, bmAttributes: 0b0000_0010, wMaxPacketSize: 512)).to_string());
+        assert_eq!("Endpoint 0x81 In Interrupt, max packet size 8, interval 10", super::from_libusb(&endpoint_descriptor!(bEndpointAddress: 0x81, bmAttributes: 0b0000_0011, wMaxPacketSize: 8, bInterval: 10)).to_string());
+        assert_eq!("Endpoint 0x83 In Isochronous, max packet size 16, Asynchronous Data, interval 1", super::from_libusb(&endpoint_descriptor!(bEndpointAddress: 0x83, bmAttributes: 0b0000_0101)).to_string());
+    }
 }
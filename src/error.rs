@@ -1,16 +1,26 @@
 use std::fmt;
+use std::io;
 use std::error::Error as StdError;
 use std::result::Result as StdResult;
 
 use libc::c_int;
 use libusb::*;
 
+use transfer::{FillError, TransferStatus};
+
 /// A result of a function that may return a `Error`.
+///
+/// Every fallible function of the crate returns it, including the class drivers, so errors can
+/// be passed on with `?` across them.
 pub type Result<T> = StdResult<T, Error>;
 
 
-/// Errors returned by the `libusb` library.
+/// Errors returned by the `libusb` library, and by the parts of the crate built on it.
+///
+/// Variants may be added in later versions, so matches need a wildcard arm. Use
+/// [`libusb_error`](#method.libusb_error) to see past `Operation`.
 #[derive(Debug,Clone)]
+#[non_exhaustive]
 pub enum Error {
     /// Success (no error).
     Success,
@@ -52,7 +62,153 @@ pub enum Error {
     NotSupported,
 
     /// Other error.
-    Other
+    Other,
+
+    /// The device was disconnected earlier, and the handle can no longer be used.
+    ///
+    /// Returned instead of submitting a transfer once a transfer on the same handle has ended
+    /// with `NoDevice`.
+    Disconnected,
+
+    /// A transfer finished with a status that doesn't stand for another error.
+    TransferFailed(TransferStatus),
+
+    /// The device broke the protocol of its class, e.g., by answering a class driver with a
+    /// message it didn't expect.
+    ClassProtocol,
+
+    /// A descriptor or message read from the device couldn't be parsed.
+    Parse,
+
+    /// A transfer couldn't be prepared, for the reason given, e.g., a read from an OUT
+    /// endpoint.
+    Fill(FillError),
+
+    /// A future was polled again after it had returned its result.
+    PolledAfterCompletion,
+
+    /// The completion callback of a transfer panicked, so its result is lost.
+    CallbackPanicked,
+
+    /// An operation of the `libusb` library failed, with the error it returned.
+    ///
+    /// Use [`libusb_error`](#method.libusb_error) to match the error regardless of the
+    /// operation.
+    Operation(Box<OperationError>),
+}
+
+/// An operation of the `libusb` library, reported with the error it failed with.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+#[non_exhaustive]
+pub enum Operation {
+    /// Opening a device.
+    Open,
+
+    /// Setting the active configuration, or unconfiguring the device if `None`.
+    SetConfiguration(Option<u8>),
+
+    /// Resetting the device.
+    Reset,
+
+    /// Detaching the kernel driver of an interface.
+    DetachKernelDriver(u8),
+
+    /// Attaching the kernel driver of an interface.
+    AttachKernelDriver(u8),
+
+    /// Claiming an interface.
+    ClaimInterface(u8),
+
+    /// Releasing an interface.
+    ReleaseInterface(u8),
+
+    /// Setting the alternate setting of an interface.
+    SetAlternateSetting(u8, u8),
+
+    /// Clearing the halt condition of an endpoint.
+    ClearHalt(u8),
+
+    /// Submitting a transfer to an endpoint.
+    Submit(u8),
+
+    /// Registering for hotplug events.
+    RegisterHotplug,
+
+    /// A transfer to or from an endpoint, which was submitted and then failed.
+    Transfer(u8),
+}
+
+impl fmt::Display for Operation {
+    /// Formats the operation like the call that failed, e.g., "claim_interface(1)".
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operation::Open => f.write_str("open"),
+            Operation::SetConfiguration(Some(config)) => write!(f, "set_configuration({})", config),
+            Operation::SetConfiguration(None) => f.write_str("set_configuration(-1)"),
+            Operation::Reset => f.write_str("reset_device"),
+            Operation::DetachKernelDriver(iface) => write!(f, "detach_kernel_driver({})", iface),
+            Operation::AttachKernelDriver(iface) => write!(f, "attach_kernel_driver({})", iface),
+            Operation::ClaimInterface(iface) => write!(f, "claim_interface({})", iface),
+            Operation::ReleaseInterface(iface) => write!(f, "release_interface({})", iface),
+            Operation::SetAlternateSetting(iface, setting) =>
+                write!(f, "set_interface_alt_setting({}, {})", iface, setting),
+            Operation::ClearHalt(endpoint) => write!(f, "clear_halt(0x{:02x})", endpoint),
+            Operation::Submit(endpoint) => write!(f, "submit_transfer(0x{:02x})", endpoint),
+            Operation::RegisterHotplug => f.write_str("hotplug_register_callback"),
+            Operation::Transfer(endpoint) => write!(f, "transfer(0x{:02x})", endpoint),
+        }
+    }
+}
+
+/// A failed operation of the `libusb` library, with the code it returned, carried by
+/// `Error::Operation`.
+#[derive(Debug,Clone)]
+pub struct OperationError {
+    operation: Operation,
+    code: c_int,
+    error: Error,
+    hint: Option<String>,
+}
+
+impl OperationError {
+    /// Returns the operation that failed.
+    pub fn operation(&self) -> Operation {
+        self.operation
+    }
+
+    /// Returns the code returned by `libusb`, which is kept even if it's unknown to this crate.
+    pub fn code(&self) -> c_int {
+        self.code
+    }
+
+    /// Returns the error the code stands for.
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
+
+    /// Returns what may have caused the failure and how to fix it, if it could be found out,
+    /// e.g., the kernel driver that holds an interface that can't be claimed.
+    pub fn hint(&self) -> Option<&str> {
+        self.hint.as_deref()
+    }
+}
+
+impl fmt::Display for OperationError {
+    /// Formats the error as the operation and the name of the code, e.g.,
+    /// "claim_interface(1) failed: LIBUSB_ERROR_BUSY", followed by the hint if there is one.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} failed: {}", self.operation, self.error.name())?;
+        match self.hint {
+            Some(ref hint) => write!(f, "; {}", hint),
+            None => Ok(()),
+        }
+    }
+}
+
+impl StdError for OperationError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(&self.error)
+    }
 }
 
 impl Error {
@@ -73,13 +229,90 @@ impl Error {
             Error::NoMem        => "Insufficient memory",
             Error::NotSupported => "Operation not supported or unimplemented on this platform",
             Error::Other        => "Other error",
+            Error::Disconnected => "Device disconnected, the handle is no longer usable",
+            Error::TransferFailed(_) => "Transfer failed",
+            Error::ClassProtocol => "Class protocol error",
+            Error::Parse        => "Malformed data from the device",
+            Error::Fill(_)      => "Invalid transfer",
+            Error::PolledAfterCompletion => "Future polled after completion",
+            Error::CallbackPanicked => "Transfer callback panicked",
+            Error::Operation(ref failed) => failed.error.strerror(),
+        }
+    }
+
+    /// Returns the name of the `libusb` error code, e.g., "LIBUSB_ERROR_BUSY".
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Error::Success      => "LIBUSB_SUCCESS",
+            Error::Io           => "LIBUSB_ERROR_IO",
+            Error::InvalidParam => "LIBUSB_ERROR_INVALID_PARAM",
+            Error::Access       => "LIBUSB_ERROR_ACCESS",
+            Error::NoDevice     => "LIBUSB_ERROR_NO_DEVICE",
+            Error::NotFound     => "LIBUSB_ERROR_NOT_FOUND",
+            Error::Busy         => "LIBUSB_ERROR_BUSY",
+            Error::Timeout      => "LIBUSB_ERROR_TIMEOUT",
+            Error::Overflow     => "LIBUSB_ERROR_OVERFLOW",
+            Error::Pipe         => "LIBUSB_ERROR_PIPE",
+            Error::Interrupted  => "LIBUSB_ERROR_INTERRUPTED",
+            Error::NoMem        => "LIBUSB_ERROR_NO_MEM",
+            Error::NotSupported => "LIBUSB_ERROR_NOT_SUPPORTED",
+            Error::Other        => "LIBUSB_ERROR_OTHER",
+            Error::Disconnected => "LIBUSB_ERROR_NO_DEVICE",
+            Error::TransferFailed(_) => "LIBUSB_ERROR_IO",
+            Error::ClassProtocol => "LIBUSB_ERROR_IO",
+            Error::Parse        => "LIBUSB_ERROR_OTHER",
+            Error::Fill(_)      => "LIBUSB_ERROR_INVALID_PARAM",
+            Error::PolledAfterCompletion => "LIBUSB_ERROR_OTHER",
+            Error::CallbackPanicked => "LIBUSB_ERROR_OTHER",
+            Error::Operation(ref failed) => failed.error.name(),
+        }
+    }
+
+    /// Returns the error without the operation that failed, for matching.
+    pub fn libusb_error(&self) -> &Error {
+        match *self {
+            Error::Operation(ref failed) => &failed.error,
+            ref error => error,
+        }
+    }
+
+    /// Returns the `libusb` error code, as returned by the failed operation if it's known.
+    pub fn code(&self) -> c_int {
+        match *self {
+            Error::Success      => LIBUSB_SUCCESS,
+            Error::Io           => LIBUSB_ERROR_IO,
+            Error::InvalidParam => LIBUSB_ERROR_INVALID_PARAM,
+            Error::Access       => LIBUSB_ERROR_ACCESS,
+            Error::NoDevice     => LIBUSB_ERROR_NO_DEVICE,
+            Error::NotFound     => LIBUSB_ERROR_NOT_FOUND,
+            Error::Busy         => LIBUSB_ERROR_BUSY,
+            Error::Timeout      => LIBUSB_ERROR_TIMEOUT,
+            Error::Overflow     => LIBUSB_ERROR_OVERFLOW,
+            Error::Pipe         => LIBUSB_ERROR_PIPE,
+            Error::Interrupted  => LIBUSB_ERROR_INTERRUPTED,
+            Error::NoMem        => LIBUSB_ERROR_NO_MEM,
+            Error::NotSupported => LIBUSB_ERROR_NOT_SUPPORTED,
+            Error::Other        => LIBUSB_ERROR_OTHER,
+            Error::Disconnected => LIBUSB_ERROR_NO_DEVICE,
+            Error::TransferFailed(_) => LIBUSB_ERROR_IO,
+            Error::ClassProtocol => LIBUSB_ERROR_IO,
+            Error::Parse        => LIBUSB_ERROR_OTHER,
+            Error::Fill(_)      => LIBUSB_ERROR_INVALID_PARAM,
+            Error::PolledAfterCompletion => LIBUSB_ERROR_OTHER,
+            Error::CallbackPanicked => LIBUSB_ERROR_OTHER,
+            Error::Operation(ref failed) => failed.code,
         }
     }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
-        fmt.write_str(self.strerror())
+        match *self {
+            Error::Operation(ref failed) => fmt::Display::fmt(failed, fmt),
+            Error::TransferFailed(status) => write!(fmt, "{}: {}", self.strerror(), status),
+            Error::Fill(ref err) => write!(fmt, "{}: {}", self.strerror(), err),
+            _ => fmt.write_str(self.strerror()),
+        }
     }
 }
 
@@ -87,8 +320,38 @@ impl StdError for Error {
     fn description(&self) -> &'static str {
         self.strerror()
     }
+
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            Error::Operation(ref failed) => Some(&failed.error),
+            Error::Fill(ref err) => Some(err),
+            _ => None,
+        }
+    }
 }
 
+impl From<Error> for io::Error {
+    /// Converts the error for use in I/O traits, e.g., by class drivers that implement `Read`
+    /// or `Write`.
+    fn from(err: Error) -> io::Error {
+        let kind = match *err.libusb_error() {
+            Error::InvalidParam => io::ErrorKind::InvalidInput,
+            Error::Fill(_)      => io::ErrorKind::InvalidInput,
+            Error::Access       => io::ErrorKind::PermissionDenied,
+            Error::NoDevice     => io::ErrorKind::NotConnected,
+            Error::Disconnected => io::ErrorKind::NotConnected,
+            Error::NotFound     => io::ErrorKind::NotFound,
+            Error::Timeout      => io::ErrorKind::TimedOut,
+            Error::Pipe         => io::ErrorKind::BrokenPipe,
+            Error::Interrupted  => io::ErrorKind::Interrupted,
+            Error::NotSupported => io::ErrorKind::Unsupported,
+            Error::Parse        => io::ErrorKind::InvalidData,
+            _                   => io::ErrorKind::Other,
+        };
+
+        io::Error::new(kind, err)
+    }
+}
 
 #[doc(hidden)]
 pub fn from_libusb(err: c_int) -> Error {
@@ -110,6 +373,35 @@ pub fn from_libusb(err: c_int) -> Error {
     }
 }
 
+/// Converts an error code returned by an operation, keeping the operation and the code.
+#[doc(hidden)]
+pub fn from_operation(operation: Operation, code: c_int) -> Error {
+    Error::Operation(Box::new(OperationError { operation, code, error: from_libusb(code), hint: None }))
+}
+
+/// Reports a transfer that completed with an error.
+#[doc(hidden)]
+pub fn from_failed_transfer(endpoint: u8) -> Error {
+    Error::Operation(Box::new(OperationError {
+        operation: Operation::Transfer(endpoint),
+        code: LIBUSB_ERROR_IO,
+        error: Error::Io,
+        hint: None,
+    }))
+}
+
+/// Adds a hint to the error of a failed operation. Other errors are returned as they are.
+#[doc(hidden)]
+pub fn with_hint(error: Error, hint: Option<String>) -> Error {
+    match error {
+        Error::Operation(mut failed) => {
+            failed.hint = hint;
+            Error::Operation(failed)
+        },
+        error => error,
+    }
+}
+
 #[doc(hidden)]
 macro_rules! try_unsafe {
     ($x:expr) => {
@@ -117,5 +409,49 @@ macro_rules! try_unsafe {
             0 => (),
             err => return Err($crate::error::from_libusb(err)),
         }
+    };
+    ($x:expr, $operation:expr) => {
+        match unsafe { $x } {
+            0 => (),
+            err => return Err($crate::error::from_operation($operation, err)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_keeps_the_failed_operation() {
+        let err = from_operation(Operation::ClaimInterface(1), LIBUSB_ERROR_BUSY);
+        assert_eq!("claim_interface(1) failed: LIBUSB_ERROR_BUSY", err.to_string());
+        assert!(matches!(err.libusb_error(), Error::Busy));
+        assert_eq!("Resource busy", err.source().unwrap().to_string());
+        assert_eq!(io::ErrorKind::Other, io::Error::from(err).kind());
+
+        let err = from_failed_transfer(0x81);
+        assert!(matches!(err.libusb_error(), Error::Io));
+        assert_eq!("transfer(0x81) failed: LIBUSB_ERROR_IO", err.to_string());
+
+        let err = with_hint(from_operation(Operation::ClaimInterface(0), LIBUSB_ERROR_BUSY),
+                            Some("interface 0 is bound to the kernel driver usbhid".to_owned()));
+        assert_eq!("claim_interface(0) failed: LIBUSB_ERROR_BUSY; interface 0 is bound to the kernel driver usbhid",
+                   err.to_string());
+
+        let err = from_operation(Operation::Submit(0x81), -42);
+        assert_eq!(-42, err.code());
+        assert!(matches!(err.libusb_error(), Error::Other));
+    }
+
+    #[test]
+    fn it_describes_the_errors_of_the_crate() {
+        let err = Error::TransferFailed(TransferStatus::Unknown);
+        assert_eq!("Transfer failed: Unknown status", err.to_string());
+        assert_eq!(LIBUSB_ERROR_IO, err.code());
+
+        assert_eq!(io::ErrorKind::InvalidData, io::Error::from(Error::Parse).kind());
+        assert_eq!("LIBUSB_ERROR_IO", Error::ClassProtocol.name());
     }
 }
@@ -0,0 +1,241 @@
+use std::cell::Cell;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use libc::c_int;
+
+use context::ContextAsync;
+
+/// How long the thread waits for events before checking if it should stop, in case the
+/// interruption is missed.
+const EVENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+thread_local! {
+    // Set while the thread handles the events of a context, and so runs its callbacks
+    static HANDLING_EVENTS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The thread that handles the events of a context, and runs the transfer and hotplug callbacks.
+///
+/// It runs while the context has users, i.e., open device handles and hotplug registrations.
+/// Without the `event-thread` feature only the users are counted, and the events are handled by
+/// the application through [`Context::handle_events`](struct.Context.html#method.handle_events).
+/// Users are added and removed while holding a single lock, so the thread is started by the
+/// first user and stopped by the last, whichever threads they're on:
+///
+/// * Adding a user while the last one is being removed waits until the thread has stopped, and
+///   then starts a new one.
+/// * The thread keeps the context alive, and each user keeps it alive through its own
+///   reference, so the context can be dropped in any order relative to its users. `libusb` is
+///   exited when the last reference goes away, after the thread has stopped.
+/// * If the last user is removed on the event thread itself, e.g., by a callback, the thread
+///   is told to stop and then left to finish on its own, as it can't wait for itself.
+/// * Work that has to wait for callbacks, like closing a device handle dropped by a callback,
+///   is deferred until the events being handled are, and then until it's ready.
+///
+/// The thread handles events with `libusb_handle_events_timeout_completed`, passing its stop
+/// flag as the completed flag. Transfers signal their own completion through their own flags,
+/// so the thread has nothing to check between events, and `libusb` returns without taking the
+/// event lock again once the flag is set.
+pub struct EventThread {
+    state: Mutex<State>,
+    deferred: Mutex<Vec<Deferred>>,
+}
+
+struct State {
+    users: u32,
+    running: Option<Running>,
+}
+
+/// Work deferred by a callback, run by the thread that handles events once `ready` is true.
+struct Deferred {
+    ready: Box<dyn Fn() -> bool + Send>,
+    run: Box<dyn FnOnce() + Send>,
+}
+
+struct Running {
+    join: JoinHandle<()>,
+    // Read by libusb as a C int, nonzero to stop
+    stop: Arc<AtomicI32>,
+}
+
+impl EventThread {
+    pub fn new() -> EventThread {
+        EventThread { state: Mutex::new(State { users: 0, running: None }), deferred: Mutex::new(Vec::new()) }
+    }
+
+    /// Returns the number of users.
+    pub fn users(&self) -> u32 {
+        self.state.lock().unwrap().users
+    }
+
+    /// Adds a user, starting the thread if it's the first and the `event-thread` feature is
+    /// enabled.
+    pub fn acquire(&self, context: &Arc<ContextAsync>) {
+        let mut state = self.state.lock().unwrap();
+        state.users += 1;
+
+        if state.running.is_none() && cfg!(feature = "event-thread") {
+            let stop = Arc::new(AtomicI32::new(0));
+            let join = {
+                let context = context.clone();
+                let stop = stop.clone();
+                thread::spawn(move || handle_events(&context, &stop))
+            };
+            state.running = Some(Running { join, stop });
+        }
+    }
+
+    /// Removes a user after calling `release`, e.g., to close a device, and stops the thread if
+    /// it was the last.
+    ///
+    /// `release` is called before the lock is taken, so it may wait for the thread, which still
+    /// runs as the user is still counted.
+    pub fn release<F>(&self, context: &Arc<ContextAsync>, release: F)
+        where F: FnOnce()
+    {
+        release();
+
+        let mut state = self.state.lock().unwrap();
+        state.users -= 1;
+
+        if state.users == 0 {
+            if let Some(running) = state.running.take() {
+                running.stop.store(1, Ordering::Release);
+                // Wakes the thread if it is waiting for an event, or makes
+                // the next wait return at once, so it sees the flag
+                unsafe {
+                    context.backend.interrupt_event_handler(context.backend_context());
+                }
+                if running.join.thread().id() != thread::current().id() {
+                    running.join.join().unwrap();
+                }
+            }
+        }
+    }
+
+    /// Runs `run` on the thread that handles events, after the events being handled, once
+    /// `ready` returns true.
+    ///
+    /// Meant for callbacks, which can't wait for other callbacks, as those run on the same
+    /// thread after they return.
+    pub fn defer<R, F>(&self, ready: R, run: F)
+        where R: Fn() -> bool + Send + 'static, F: FnOnce() + Send + 'static
+    {
+        self.deferred.lock().unwrap().push(Deferred { ready: Box::new(ready), run: Box::new(run) });
+    }
+
+    /// Runs the deferred work that is ready, without holding any lock.
+    fn run_deferred(&self) {
+        let ready = {
+            let mut deferred = self.deferred.lock().unwrap();
+            let (ready, waiting) = mem::take(&mut *deferred).into_iter()
+                .partition::<Vec<_>, _>(|deferred| (deferred.ready)());
+            *deferred = waiting;
+            ready
+        };
+        for deferred in ready {
+            (deferred.run)();
+        }
+    }
+
+    #[cfg(test)]
+    pub fn is_running(&self) -> bool {
+        self.state.lock().unwrap().running.is_some()
+    }
+}
+
+/// Handles the events of the context until `stop` is set.
+fn handle_events(context: &ContextAsync, stop: &AtomicI32) {
+    while stop.load(Ordering::Acquire) == 0 {
+        handle_events_once(context, EVENT_TIMEOUT, Some(stop));
+    }
+}
+
+/// Handles the events that arrive within `timeout`, or until `completed` is set, and then runs
+/// the deferred work that is ready.
+pub fn handle_events_once(context: &ContextAsync, timeout: Duration, completed: Option<&AtomicI32>) -> c_int {
+    let nested = HANDLING_EVENTS.with(|handling| handling.replace(true));
+    let result = unsafe {
+        context.backend.handle_events(context.backend_context(), timeout, completed)
+    };
+    HANDLING_EVENTS.with(|handling| handling.set(nested));
+
+    if !nested {
+        context.event_thread.run_deferred();
+    }
+    result
+}
+
+/// Whether the current thread is handling events, e.g., in a transfer callback, whether it's the
+/// event thread or a thread of the application calling
+/// [`Context::handle_events`](struct.Context.html#method.handle_events).
+pub fn is_handling_events() -> bool {
+    HANDLING_EVENTS.with(Cell::get)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "event-thread")]
+    fn it_runs_while_there_are_users() {
+        let context = ContextAsync::uninitialized();
+        assert!(!context.event_thread.is_running());
+
+        context.event_thread.acquire(&context);
+        context.event_thread.acquire(&context);
+        context.event_thread.release(&context, || {});
+        assert!(context.event_thread.is_running());
+        assert_eq!(1, context.event_thread.users());
+
+        let mut released = false;
+        context.event_thread.release(&context, || released = true);
+        assert!(released);
+        assert!(!context.event_thread.is_running());
+
+        // The thread has dropped its reference to the context
+        assert_eq!(1, Arc::strong_count(&context));
+    }
+
+    #[test]
+    fn it_serializes_users_on_several_threads() {
+        let context = ContextAsync::uninitialized();
+
+        let threads: Vec<_> = (0..4).map(|_| {
+            let context = context.clone();
+            thread::spawn(move || {
+                for _ in 0..20 {
+                    context.event_thread.acquire(&context);
+                    context.event_thread.release(&context, || {});
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(0, context.event_thread.users());
+        assert!(!context.event_thread.is_running());
+    }
+
+    #[test]
+    #[cfg(not(feature = "event-thread"))]
+    fn it_only_counts_users_without_the_feature() {
+        let context = ContextAsync::uninitialized();
+
+        context.event_thread.acquire(&context);
+        assert!(!context.event_thread.is_running());
+        assert_eq!(1, context.event_thread.users());
+
+        let mut released = false;
+        context.event_thread.release(&context, || released = true);
+        assert!(released);
+        assert_eq!(0, context.event_thread.users());
+    }
+}
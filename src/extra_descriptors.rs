@@ -0,0 +1,108 @@
+use std::slice;
+
+use libc::{c_int, c_uchar};
+
+/// Iterator over the class- or vendor-specific descriptors stored in an `extra()` byte blob.
+///
+/// Each descriptor starts with a `bLength` byte followed by a `bDescriptorType` byte. The
+/// iterator yields `(descriptor_type, payload)` pairs, where `payload` is the remainder of the
+/// descriptor after those two header bytes.
+///
+/// Iteration stops at the first malformed descriptor, i.e., one with a `bLength` smaller than
+/// the header or larger than the remaining bytes.
+#[derive(Debug,Clone)]
+pub struct ExtraDescriptors<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ExtraDescriptors<'a> {
+    /// Creates an iterator over the descriptors in `bytes`.
+    pub fn new(bytes: &'a [u8]) -> ExtraDescriptors<'a> {
+        ExtraDescriptors { bytes }
+    }
+
+    /// Returns the bytes that have not been consumed by the iterator yet.
+    pub fn remaining(&self) -> &'a [u8] {
+        self.bytes
+    }
+}
+
+impl<'a> Iterator for ExtraDescriptors<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<(u8, &'a [u8])> {
+        if self.bytes.len() < 2 {
+            return None;
+        }
+
+        let length = self.bytes[0] as usize;
+
+        if length < 2 || length > self.bytes.len() {
+            // Malformed descriptor. Don't try to resynchronize.
+            self.bytes = &self.bytes[self.bytes.len()..];
+            return None;
+        }
+
+        let descriptor_type = self.bytes[1];
+        let payload = &self.bytes[2..length];
+        self.bytes = &self.bytes[length..];
+
+        Some((descriptor_type, payload))
+    }
+}
+
+#[doc(hidden)]
+pub unsafe fn extra_from_libusb<'a>(extra: *const c_uchar, extra_length: c_int) -> &'a [u8] {
+    if extra.is_null() || extra_length <= 0 {
+        &[]
+    }
+    else {
+        slice::from_raw_parts(extra, extra_length as usize)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::ExtraDescriptors;
+
+    #[test]
+    fn it_yields_nothing_for_empty_blob() {
+        assert_eq!(0, ExtraDescriptors::new(&[]).count());
+    }
+
+    #[test]
+    fn it_yields_type_and_payload() {
+        let bytes = [0x05, 0x24, 0x00, 0x10, 0x01, 0x04, 0x24, 0x02, 0x06];
+
+        assert_eq!(vec![(0x24, &[0x00, 0x10, 0x01][..]), (0x24, &[0x02, 0x06][..])],
+                   ExtraDescriptors::new(&bytes).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn it_yields_empty_payload_for_header_only_descriptor() {
+        assert_eq!(vec![(0x30, &[][..])], ExtraDescriptors::new(&[0x02, 0x30]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn it_stops_at_zero_length() {
+        let bytes = [0x03, 0x24, 0x01, 0x00, 0x24, 0x03, 0x24, 0x02];
+
+        assert_eq!(vec![(0x24, &[0x01][..])], ExtraDescriptors::new(&bytes).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn it_stops_at_length_beyond_end() {
+        let bytes = [0x03, 0x24, 0x01, 0x08, 0x24, 0x03];
+
+        let mut iter = ExtraDescriptors::new(&bytes);
+        assert_eq!(Some((0x24, &[0x01][..])), iter.next());
+        assert_eq!(None, iter.next());
+        assert_eq!(None, iter.next());
+    }
+
+    #[test]
+    fn it_ignores_trailing_byte() {
+        assert_eq!(vec![(0x24, &[][..])], ExtraDescriptors::new(&[0x02, 0x24, 0x07]).collect::<Vec<_>>());
+    }
+}
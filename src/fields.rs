@@ -1,3 +1,5 @@
+use std::fmt;
+
 use libc::c_int;
 use libusb::*;
 
@@ -32,6 +34,19 @@ pub fn speed_from_libusb(n: c_int) -> Speed {
     }
 }
 
+impl fmt::Display for Speed {
+    /// Formats the signalling rate, e.g., "480 Mbps".
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.pad(match *self {
+            Speed::Unknown => "unknown",
+            Speed::Low => "1.5 Mbps",
+            Speed::Full => "12 Mbps",
+            Speed::High => "480 Mbps",
+            Speed::Super => "5000 Mbps",
+        })
+    }
+}
+
 /// Transfer and endpoint directions.
 #[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
 pub enum Direction {
@@ -42,6 +57,16 @@ pub enum Direction {
     Out,
 }
 
+impl fmt::Display for Direction {
+    /// Formats the direction as in the USB specification, "IN" or "OUT".
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.pad(match *self {
+            Direction::In => "IN",
+            Direction::Out => "OUT",
+        })
+    }
+}
+
 /// An endpoint's transfer type.
 #[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
 pub enum TransferType {
@@ -58,6 +83,81 @@ pub enum TransferType {
     Interrupt,
 }
 
+impl fmt::Display for TransferType {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.pad(match *self {
+            TransferType::Control => "Control",
+            TransferType::Isochronous => "Isochronous",
+            TransferType::Bulk => "Bulk",
+            TransferType::Interrupt => "Interrupt",
+        })
+    }
+}
+
+/// The address of an endpoint, its number together with its direction.
+///
+/// Transfer functions take an `EndpointAddress` rather than a `u8`, so an endpoint number can't be
+/// passed where an address was meant, e.g., `1` for the IN endpoint `0x81`. Addresses are
+/// constructed from a number and direction, or converted from the `bEndpointAddress` of a
+/// descriptor:
+///
+/// ```
+/// use libusb_async::{Direction, EndpointAddress};
+///
+/// let address = EndpointAddress::in_(1);
+/// assert_eq!(EndpointAddress::from(0x81), address);
+/// assert_eq!((1, Direction::In), (address.number(), address.direction()));
+/// ```
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash,PartialOrd,Ord)]
+pub struct EndpointAddress(u8);
+
+impl EndpointAddress {
+    /// The address of the IN endpoint with a number from 0 to 15.
+    pub fn in_(number: u8) -> EndpointAddress {
+        EndpointAddress(LIBUSB_ENDPOINT_IN | number & ENDPOINT_NUMBER_MASK)
+    }
+
+    /// The address of the OUT endpoint with a number from 0 to 15.
+    pub fn out(number: u8) -> EndpointAddress {
+        EndpointAddress(LIBUSB_ENDPOINT_OUT | number & ENDPOINT_NUMBER_MASK)
+    }
+
+    /// Returns the endpoint number.
+    pub fn number(&self) -> u8 {
+        self.0 & ENDPOINT_NUMBER_MASK
+    }
+
+    /// Returns the direction of the endpoint.
+    pub fn direction(&self) -> Direction {
+        match self.0 & LIBUSB_ENDPOINT_DIR_MASK {
+            LIBUSB_ENDPOINT_OUT => Direction::Out,
+            _                   => Direction::In,
+        }
+    }
+}
+
+const ENDPOINT_NUMBER_MASK: u8 = 0x0F;
+
+impl From<u8> for EndpointAddress {
+    /// Converts a `bEndpointAddress` field. The reserved bits are cleared.
+    fn from(address: u8) -> EndpointAddress {
+        EndpointAddress(address & (LIBUSB_ENDPOINT_DIR_MASK | ENDPOINT_NUMBER_MASK))
+    }
+}
+
+impl From<EndpointAddress> for u8 {
+    fn from(address: EndpointAddress) -> u8 {
+        address.0
+    }
+}
+
+impl fmt::Display for EndpointAddress {
+    /// Formats the address in hexadecimal, e.g., "0x81".
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "0x{:02x}", self.0)
+    }
+}
+
 
 /// Isochronous synchronization mode.
 #[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
@@ -75,6 +175,17 @@ pub enum SyncType {
     Synchronous,
 }
 
+impl fmt::Display for SyncType {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.pad(match *self {
+            SyncType::NoSync => "No synchronization",
+            SyncType::Asynchronous => "Asynchronous",
+            SyncType::Adaptive => "Adaptive",
+            SyncType::Synchronous => "Synchronous",
+        })
+    }
+}
+
 
 /// Isochronous usage type.
 #[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
@@ -85,13 +196,25 @@ pub enum UsageType {
     /// Feedback endpoint.
     Feedback,
 
-    /// Explicit feedback data endpoint.
+    /// Implicit feedback data endpoint, i.e., a data endpoint whose packets also serve as
+    /// feedback for another endpoint.
     FeedbackData,
 
     /// Reserved.
     Reserved,
 }
 
+impl fmt::Display for UsageType {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.pad(match *self {
+            UsageType::Data => "Data",
+            UsageType::Feedback => "Feedback",
+            UsageType::FeedbackData => "Implicit feedback data",
+            UsageType::Reserved => "Reserved",
+        })
+    }
+}
+
 
 /// Types of control transfers.
 #[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
@@ -109,6 +232,17 @@ pub enum RequestType {
     Reserved,
 }
 
+impl fmt::Display for RequestType {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.pad(match *self {
+            RequestType::Standard => "Standard",
+            RequestType::Class => "Class",
+            RequestType::Vendor => "Vendor",
+            RequestType::Reserved => "Reserved",
+        })
+    }
+}
+
 /// Recipients of control transfers.
 #[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
 pub enum Recipient {
@@ -125,6 +259,268 @@ pub enum Recipient {
     Other,
 }
 
+impl fmt::Display for Recipient {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.pad(match *self {
+            Recipient::Device => "Device",
+            Recipient::Interface => "Interface",
+            Recipient::Endpoint => "Endpoint",
+            Recipient::Other => "Other",
+        })
+    }
+}
+
+/// Standard requests, defined by chapter 9 of the USB specification for every device.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum StandardRequest {
+    GetStatus,
+    ClearFeature,
+    SetFeature,
+    SetAddress,
+    GetDescriptor,
+    SetDescriptor,
+    GetConfiguration,
+    SetConfiguration,
+    GetInterface,
+    SetInterface,
+    SynchFrame,
+
+    /// Sets the exit latencies of the link power states (SuperSpeed only).
+    SetSel,
+
+    /// Sets the delay from the host to the device (SuperSpeed only).
+    SetIsochDelay,
+}
+
+impl StandardRequest {
+    /// Returns the `bRequest` value of the request.
+    pub fn request(&self) -> u8 {
+        match *self {
+            StandardRequest::GetStatus        => LIBUSB_REQUEST_GET_STATUS,
+            StandardRequest::ClearFeature     => LIBUSB_REQUEST_CLEAR_FEATURE,
+            StandardRequest::SetFeature       => LIBUSB_REQUEST_SET_FEATURE,
+            StandardRequest::SetAddress       => LIBUSB_REQUEST_SET_ADDRESS,
+            StandardRequest::GetDescriptor    => LIBUSB_REQUEST_GET_DESCRIPTOR,
+            StandardRequest::SetDescriptor    => LIBUSB_REQUEST_SET_DESCRIPTOR,
+            StandardRequest::GetConfiguration => LIBUSB_REQUEST_GET_CONFIGURATION,
+            StandardRequest::SetConfiguration => LIBUSB_REQUEST_SET_CONFIGURATION,
+            StandardRequest::GetInterface     => LIBUSB_REQUEST_GET_INTERFACE,
+            StandardRequest::SetInterface     => LIBUSB_REQUEST_SET_INTERFACE,
+            StandardRequest::SynchFrame       => LIBUSB_REQUEST_SYNCH_FRAME,
+            StandardRequest::SetSel           => LIBUSB_REQUEST_SET_SEL,
+            StandardRequest::SetIsochDelay    => LIBUSB_SET_ISOCH_DELAY,
+        }
+    }
+}
+
+/// USB class codes, as used in device and interface descriptors.
+///
+/// Codes that aren't defined by the USB-IF are kept as `Unknown`, so converting a code to a
+/// `ClassCode` and back is lossless.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum ClassCode {
+    /// The class is defined by each interface (device descriptors only).
+    PerInterface,
+
+    /// Audio.
+    Audio,
+
+    /// Communications and CDC control.
+    Communications,
+
+    /// Human interface device.
+    Hid,
+
+    /// Physical.
+    Physical,
+
+    /// Still imaging.
+    Image,
+
+    /// Printer.
+    Printer,
+
+    /// Mass storage.
+    MassStorage,
+
+    /// Hub.
+    Hub,
+
+    /// CDC data.
+    CdcData,
+
+    /// Smart card.
+    SmartCard,
+
+    /// Content security.
+    ContentSecurity,
+
+    /// Video.
+    Video,
+
+    /// Personal healthcare.
+    PersonalHealthcare,
+
+    /// Audio/video devices.
+    AudioVideo,
+
+    /// Billboard.
+    Billboard,
+
+    /// USB Type-C bridge.
+    TypeCBridge,
+
+    /// Diagnostic device.
+    Diagnostic,
+
+    /// Wireless controller.
+    WirelessController,
+
+    /// Miscellaneous.
+    Miscellaneous,
+
+    /// Application specific, e.g., DFU or USBTMC.
+    ApplicationSpecific,
+
+    /// Vendor specific.
+    VendorSpecific,
+
+    /// A class code that isn't defined by the USB-IF.
+    Unknown(u8),
+}
+
+impl ClassCode {
+    /// Returns a human-readable name for a class, sub class and protocol triple, if it is a
+    /// well-known one.
+    ///
+    /// ```
+    /// use libusb_async::ClassCode;
+    ///
+    /// assert_eq!(Some("HID boot keyboard"), ClassCode::Hid.function_name(0x01, 0x01));
+    /// ```
+    pub fn function_name(&self, sub_class_code: u8, protocol_code: u8) -> Option<&'static str> {
+        let name = match (*self, sub_class_code, protocol_code) {
+            (ClassCode::Audio, 0x01, _) => "Audio control",
+            (ClassCode::Audio, 0x02, _) => "Audio streaming",
+            (ClassCode::Audio, 0x03, _) => "MIDI streaming",
+            (ClassCode::Communications, 0x02, _) => "CDC ACM",
+            (ClassCode::Communications, 0x06, _) => "CDC ECM",
+            (ClassCode::Communications, 0x0D, _) => "CDC NCM",
+            (ClassCode::Hid, 0x01, 0x01) => "HID boot keyboard",
+            (ClassCode::Hid, 0x01, 0x02) => "HID boot mouse",
+            (ClassCode::Image, 0x01, 0x01) => "PTP still image capture",
+            (ClassCode::Printer, 0x01, _) => "Printer",
+            (ClassCode::MassStorage, 0x06, 0x50) => "Mass storage (SCSI, bulk-only)",
+            (ClassCode::MassStorage, 0x06, 0x62) => "Mass storage (SCSI, UAS)",
+            (ClassCode::Hub, _, _) => "Hub",
+            (ClassCode::Video, 0x01, _) => "Video control",
+            (ClassCode::Video, 0x02, _) => "Video streaming",
+            (ClassCode::WirelessController, 0x01, 0x01) => "Bluetooth",
+            (ClassCode::WirelessController, 0x01, 0x03) => "RNDIS",
+            (ClassCode::Miscellaneous, 0x02, 0x01) => "Interface association",
+            (ClassCode::Miscellaneous, 0x04, 0x01) => "RNDIS over Ethernet",
+            (ClassCode::ApplicationSpecific, 0x01, _) => "Device firmware upgrade",
+            (ClassCode::ApplicationSpecific, 0x02, _) => "IrDA bridge",
+            (ClassCode::ApplicationSpecific, 0x03, _) => "USB test and measurement",
+            _ => return None,
+        };
+
+        Some(name)
+    }
+}
+
+impl From<u8> for ClassCode {
+    fn from(code: u8) -> ClassCode {
+        match code {
+            0x00 => ClassCode::PerInterface,
+            0x01 => ClassCode::Audio,
+            0x02 => ClassCode::Communications,
+            0x03 => ClassCode::Hid,
+            0x05 => ClassCode::Physical,
+            0x06 => ClassCode::Image,
+            0x07 => ClassCode::Printer,
+            0x08 => ClassCode::MassStorage,
+            0x09 => ClassCode::Hub,
+            0x0A => ClassCode::CdcData,
+            0x0B => ClassCode::SmartCard,
+            0x0D => ClassCode::ContentSecurity,
+            0x0E => ClassCode::Video,
+            0x0F => ClassCode::PersonalHealthcare,
+            0x10 => ClassCode::AudioVideo,
+            0x11 => ClassCode::Billboard,
+            0x12 => ClassCode::TypeCBridge,
+            0xDC => ClassCode::Diagnostic,
+            0xE0 => ClassCode::WirelessController,
+            0xEF => ClassCode::Miscellaneous,
+            0xFE => ClassCode::ApplicationSpecific,
+            0xFF => ClassCode::VendorSpecific,
+            n => ClassCode::Unknown(n),
+        }
+    }
+}
+
+impl From<ClassCode> for u8 {
+    fn from(class_code: ClassCode) -> u8 {
+        match class_code {
+            ClassCode::PerInterface => 0x00,
+            ClassCode::Audio => 0x01,
+            ClassCode::Communications => 0x02,
+            ClassCode::Hid => 0x03,
+            ClassCode::Physical => 0x05,
+            ClassCode::Image => 0x06,
+            ClassCode::Printer => 0x07,
+            ClassCode::MassStorage => 0x08,
+            ClassCode::Hub => 0x09,
+            ClassCode::CdcData => 0x0A,
+            ClassCode::SmartCard => 0x0B,
+            ClassCode::ContentSecurity => 0x0D,
+            ClassCode::Video => 0x0E,
+            ClassCode::PersonalHealthcare => 0x0F,
+            ClassCode::AudioVideo => 0x10,
+            ClassCode::Billboard => 0x11,
+            ClassCode::TypeCBridge => 0x12,
+            ClassCode::Diagnostic => 0xDC,
+            ClassCode::WirelessController => 0xE0,
+            ClassCode::Miscellaneous => 0xEF,
+            ClassCode::ApplicationSpecific => 0xFE,
+            ClassCode::VendorSpecific => 0xFF,
+            ClassCode::Unknown(n) => n,
+        }
+    }
+}
+
+impl fmt::Display for ClassCode {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            ClassCode::PerInterface => "Per interface",
+            ClassCode::Audio => "Audio",
+            ClassCode::Communications => "Communications",
+            ClassCode::Hid => "Human interface device",
+            ClassCode::Physical => "Physical",
+            ClassCode::Image => "Image",
+            ClassCode::Printer => "Printer",
+            ClassCode::MassStorage => "Mass storage",
+            ClassCode::Hub => "Hub",
+            ClassCode::CdcData => "CDC data",
+            ClassCode::SmartCard => "Smart card",
+            ClassCode::ContentSecurity => "Content security",
+            ClassCode::Video => "Video",
+            ClassCode::PersonalHealthcare => "Personal healthcare",
+            ClassCode::AudioVideo => "Audio/video",
+            ClassCode::Billboard => "Billboard",
+            ClassCode::TypeCBridge => "USB Type-C bridge",
+            ClassCode::Diagnostic => "Diagnostic",
+            ClassCode::WirelessController => "Wireless controller",
+            ClassCode::Miscellaneous => "Miscellaneous",
+            ClassCode::ApplicationSpecific => "Application specific",
+            ClassCode::VendorSpecific => "Vendor specific",
+            ClassCode::Unknown(n) => return write!(fmt, "Unknown ({:#04x})", n),
+        };
+
+        fmt.pad(name)
+    }
+}
+
 /// A three-part version consisting of major, minor, and sub minor components.
 ///
 /// This can be used to represent versions of the format `J.M.N`, where `J` is the major version,
@@ -181,6 +577,13 @@ impl Version {
     }
 }
 
+impl fmt::Display for Version {
+    /// Formats the version as `J.M.N`, e.g., "2.0.1".
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
 /// Builds a value for the `bmRequestType` field of a control transfer setup packet.
 ///
 /// The `bmRequestType` field of a USB control transfer setup packet is a bit field specifying
@@ -271,6 +674,39 @@ mod test {
         assert_eq!(Version(12, 3, 4), Version::from_bcd(0x1234));
     }
 
+    #[test]
+    fn version_has_display() {
+        assert_eq!("2.0.1", Version::from_bcd(0x0201).to_string());
+    }
+
+    // ClassCode
+
+    #[test]
+    fn class_code_round_trips_through_u8() {
+        for code in 0..=255u8 {
+            assert_eq!(code, u8::from(ClassCode::from(code)));
+        }
+    }
+
+    #[test]
+    fn class_code_maps_defined_codes() {
+        assert_eq!(ClassCode::Hid, ClassCode::from(0x03));
+        assert_eq!(ClassCode::VendorSpecific, ClassCode::from(0xFF));
+        assert_eq!(ClassCode::Unknown(0x04), ClassCode::from(0x04));
+    }
+
+    #[test]
+    fn class_code_has_display_name() {
+        assert_eq!("Mass storage", ClassCode::MassStorage.to_string());
+        assert_eq!("Unknown (0x42)", ClassCode::Unknown(0x42).to_string());
+    }
+
+    #[test]
+    fn class_code_names_well_known_functions() {
+        assert_eq!(Some("Mass storage (SCSI, bulk-only)"), ClassCode::MassStorage.function_name(0x06, 0x50));
+        assert_eq!(None, ClassCode::VendorSpecific.function_name(0x00, 0x00));
+    }
+
     // request_type for direction
 
     #[test]
@@ -326,4 +762,41 @@ mod test {
     fn request_type_builds_value_for_other_recipient() {
         assert_eq!(request_type(Direction::Out, RequestType::Standard, Recipient::Other) & 0x0F, 0x03);
     }
+
+    // EndpointAddress
+
+    #[test]
+    fn endpoint_address_combines_number_and_direction() {
+        assert_eq!(0x81, u8::from(EndpointAddress::in_(1)));
+        assert_eq!(0x0F, u8::from(EndpointAddress::out(0x1F)));
+        assert_eq!((2, Direction::Out), (EndpointAddress::from(0x02).number(), EndpointAddress::from(0x02).direction()));
+        assert_eq!(EndpointAddress::in_(3), EndpointAddress::from(0xF3));
+    }
+
+    #[test]
+    fn endpoint_address_has_display() {
+        assert_eq!("0x81", EndpointAddress::in_(1).to_string());
+        assert_eq!("0x02 OUT", format!("{} {}", EndpointAddress::out(2), EndpointAddress::out(2).direction()));
+    }
+
+    // Display
+
+    #[test]
+    fn enums_have_display_names() {
+        assert_eq!(" 480 Mbps", format!("{:>9}", Speed::High));
+        assert_eq!("Interrupt", TransferType::Interrupt.to_string());
+        assert_eq!("Implicit feedback data", UsageType::FeedbackData.to_string());
+        assert_eq!("Vendor/Interface", format!("{}/{}", RequestType::Vendor, Recipient::Interface));
+    }
+
+    // StandardRequest
+
+    #[test]
+    fn standard_request_has_the_request_value_of_the_specification() {
+        assert_eq!(0x00, StandardRequest::GetStatus.request());
+        assert_eq!(0x06, StandardRequest::GetDescriptor.request());
+        assert_eq!(0x09, StandardRequest::SetConfiguration.request());
+        assert_eq!(0x0C, StandardRequest::SynchFrame.request());
+        assert_eq!(0x31, StandardRequest::SetIsochDelay.request());
+    }
 }
@@ -0,0 +1,644 @@
+//! Message framing over byte streams, e.g., a pair of bulk endpoints.
+//!
+//! Vendor protocols often send messages over bulk endpoints as a length followed by a payload.
+//! [`Framed`](struct.Framed.html) turns any `AsyncRead + AsyncWrite` into a `Stream` and `Sink`
+//! of messages, using a [`Decoder`](trait.Decoder.html) and [`Encoder`](trait.Encoder.html)
+//! that only deal with bytes in a buffer. [`BulkPipe`](struct.BulkPipe.html) provides the byte
+//! stream for a pair of bulk endpoints of a claimed interface, and the serial port drivers
+//! provide it for their ports.
+//!
+//! ```no_run
+//! # extern crate futures;
+//! # extern crate libusb_async;
+//! # use futures::executor::block_on;
+//! # use futures::{SinkExt, StreamExt};
+//! # use libusb_async::EndpointAddress;
+//! # use libusb_async::framed::{BulkPipe, Framed, LengthPrefixedCodec};
+//! # fn ping(handle: &libusb_async::DeviceHandle) -> std::io::Result<()> {
+//! let mut framed = Framed::new(BulkPipe::new(handle, EndpointAddress::in_(1), EndpointAddress::out(1)), LengthPrefixedCodec::new());
+//!
+//! block_on(framed.send(b"ping".to_vec()))?;
+//! let reply = block_on(framed.next());
+//! # Ok(())
+//! # }
+//! # fn main() {}
+//! ```
+
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::task;
+use std::time::{Duration, Instant};
+
+use futures_core::Stream;
+use futures_io::{AsyncRead, AsyncWrite};
+use futures_sink::Sink;
+
+use bulk_io::BulkIo;
+use fields::EndpointAddress;
+use device_handle::DeviceHandle;
+
+/// Size of the reads made while a message is incomplete.
+const READ_SIZE: usize = 4096;
+
+/// Amount of encoded data after which a sink waits for it to be written before accepting more.
+const WRITE_BACKPRESSURE: usize = 64 * 1024;
+
+/// Decodes messages from received bytes.
+pub trait Decoder {
+    /// The decoded message.
+    type Item;
+
+    /// The error returned for malformed data. It must be able to represent I/O errors of the
+    /// underlying byte stream.
+    type Error: From<io::Error>;
+
+    /// Decodes a message from the start of `src`, removing its bytes.
+    ///
+    /// Returns `Ok(None)` if `src` doesn't hold a complete message yet, in which case more
+    /// bytes are read and the decoder is called again.
+    fn decode(&mut self, src: &mut Vec<u8>) -> Result<Option<Self::Item>, Self::Error>;
+
+    /// Decodes a message when the byte stream has ended.
+    ///
+    /// The default implementation calls [`decode`](#tymethod.decode), and returns an
+    /// `UnexpectedEof` error if bytes of an incomplete message remain.
+    fn decode_eof(&mut self, src: &mut Vec<u8>) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(src)? {
+            Some(item) => Ok(Some(item)),
+            None if src.is_empty() => Ok(None),
+            None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "incomplete message at end of stream").into()),
+        }
+    }
+}
+
+/// Encodes messages of type `Item` into bytes.
+pub trait Encoder<Item> {
+    /// The error returned for messages that can't be encoded. It must be able to represent I/O
+    /// errors of the underlying byte stream.
+    type Error: From<io::Error>;
+
+    /// Appends the encoded message to `dst`.
+    fn encode(&mut self, item: Item, dst: &mut Vec<u8>) -> Result<(), Self::Error>;
+}
+
+/// A codec for messages made of a 32-bit length followed by that many bytes of payload.
+///
+/// The length counts the payload only, and is little-endian unless the codec is created with
+/// [`big_endian`](#method.big_endian). Messages longer than
+/// [`max_length`](#method.max_length), 8 MiB by default, are refused with an `InvalidData`
+/// error, so that a corrupted length doesn't make the decoder buffer without bound.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct LengthPrefixedCodec {
+    big_endian: bool,
+    max_length: usize,
+}
+
+impl LengthPrefixedCodec {
+    /// Creates a codec with a little-endian length.
+    pub fn new() -> LengthPrefixedCodec {
+        LengthPrefixedCodec { big_endian: false, max_length: 8 * 1024 * 1024 }
+    }
+
+    /// Creates a codec with a big-endian length.
+    pub fn big_endian() -> LengthPrefixedCodec {
+        LengthPrefixedCodec { big_endian: true, ..LengthPrefixedCodec::new() }
+    }
+
+    /// Sets the longest payload accepted, in either direction.
+    pub fn max_length(self, max_length: usize) -> LengthPrefixedCodec {
+        LengthPrefixedCodec { max_length, ..self }
+    }
+
+    fn check_length(&self, length: usize) -> io::Result<()> {
+        if length > self.max_length {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "message longer than the maximum length"));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for LengthPrefixedCodec {
+    fn default() -> LengthPrefixedCodec {
+        LengthPrefixedCodec::new()
+    }
+}
+
+impl Decoder for LengthPrefixedCodec {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+
+        let prefix = [src[0], src[1], src[2], src[3]];
+        let length = if self.big_endian { u32::from_be_bytes(prefix) } else { u32::from_le_bytes(prefix) } as usize;
+        self.check_length(length)?;
+
+        if src.len() < 4 + length {
+            return Ok(None);
+        }
+
+        let message = src[4..4 + length].to_vec();
+        src.drain(..4 + length);
+        Ok(Some(message))
+    }
+}
+
+impl<T: AsRef<[u8]>> Encoder<T> for LengthPrefixedCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: T, dst: &mut Vec<u8>) -> io::Result<()> {
+        let payload = item.as_ref();
+        self.check_length(payload.len())?;
+
+        let length = payload.len() as u32;
+        dst.extend_from_slice(&if self.big_endian { length.to_be_bytes() } else { length.to_le_bytes() });
+        dst.extend_from_slice(payload);
+        Ok(())
+    }
+}
+
+/// A `Stream` and `Sink` of messages over a byte stream.
+///
+/// Received bytes are buffered until the decoder finds a complete message in them, and sent
+/// messages are buffered until the sink is flushed or the buffer grows large.
+pub struct Framed<T, C> {
+    io: T,
+    codec: C,
+    read_buffer: Vec<u8>,
+    write_buffer: Vec<u8>,
+    eof: bool,
+}
+
+impl<T, C> Framed<T, C> {
+    /// Creates a framed stream of the messages of `codec` over `io`.
+    pub fn new(io: T, codec: C) -> Framed<T, C> {
+        Framed {
+            io,
+            codec,
+            read_buffer: Vec::new(),
+            write_buffer: Vec::new(),
+            eof: false,
+        }
+    }
+
+    /// Returns the byte stream.
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    /// Returns the byte stream mutably. Reading or writing it directly corrupts the framing.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    /// Returns the codec.
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    /// Returns the codec mutably, e.g., to change its settings between messages.
+    pub fn codec_mut(&mut self) -> &mut C {
+        &mut self.codec
+    }
+
+    /// Returns the received bytes that are not yet part of a message.
+    pub fn read_buffer(&self) -> &[u8] {
+        &self.read_buffer
+    }
+
+    /// Returns the byte stream, discarding any buffered bytes.
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+}
+
+impl<T: AsyncRead + Unpin, C: Decoder + Unpin> Stream for Framed<T, C> {
+    type Item = Result<C::Item, C::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.eof {
+                return task::Poll::Ready(this.codec.decode_eof(&mut this.read_buffer).transpose());
+            }
+
+            if let Some(item) = this.codec.decode(&mut this.read_buffer).transpose() {
+                return task::Poll::Ready(Some(item));
+            }
+
+            let length = this.read_buffer.len();
+            this.read_buffer.resize(length + READ_SIZE, 0);
+
+            let result = Pin::new(&mut this.io).poll_read(cx, &mut this.read_buffer[length..]);
+            let read = match result {
+                task::Poll::Ready(Ok(read)) => read,
+                task::Poll::Ready(Err(e)) => {
+                    this.read_buffer.truncate(length);
+                    return task::Poll::Ready(Some(Err(e.into())));
+                },
+                task::Poll::Pending => {
+                    this.read_buffer.truncate(length);
+                    return task::Poll::Pending;
+                },
+            };
+
+            this.read_buffer.truncate(length + read);
+            this.eof = read == 0;
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin, C: Unpin> Framed<T, C> {
+    /// Writes the buffered messages.
+    fn poll_write_buffer(&mut self, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        while !self.write_buffer.is_empty() {
+            let written = match Pin::new(&mut self.io).poll_write(cx, &self.write_buffer) {
+                task::Poll::Ready(result) => result?,
+                task::Poll::Pending => return task::Poll::Pending,
+            };
+
+            if written == 0 {
+                return task::Poll::Ready(Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write buffered messages")));
+            }
+
+            self.write_buffer.drain(..written);
+        }
+
+        task::Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin, I, C: Encoder<I> + Unpin> Sink<I> for Framed<T, C> {
+    type Error = C::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Result<(), C::Error>> {
+        let this = self.get_mut();
+
+        if this.write_buffer.len() < WRITE_BACKPRESSURE {
+            return task::Poll::Ready(Ok(()));
+        }
+
+        this.poll_write_buffer(cx).map_err(Into::into)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: I) -> Result<(), C::Error> {
+        let this = self.get_mut();
+        this.codec.encode(item, &mut this.write_buffer)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Result<(), C::Error>> {
+        let this = self.get_mut();
+
+        match this.poll_write_buffer(cx) {
+            task::Poll::Ready(Ok(())) => Pin::new(&mut this.io).poll_flush(cx).map_err(Into::into),
+            task::Poll::Ready(Err(e)) => task::Poll::Ready(Err(e.into())),
+            task::Poll::Pending => task::Poll::Pending,
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Result<(), C::Error>> {
+        let this = self.get_mut();
+
+        match this.poll_write_buffer(cx) {
+            task::Poll::Ready(Ok(())) => Pin::new(&mut this.io).poll_close(cx).map_err(Into::into),
+            task::Poll::Ready(Err(e)) => task::Poll::Ready(Err(e.into())),
+            task::Poll::Pending => task::Poll::Pending,
+        }
+    }
+}
+
+/// A byte stream over a pair of bulk endpoints of an interface claimed by the caller.
+///
+/// Each write is sent as one bulk transfer, and reads return the data of bulk transfers as it
+/// arrives. Zero-length packets received carry no data and are skipped, rather than read as
+/// the end of the stream.
+pub struct BulkPipe<'a> {
+    handle: &'a DeviceHandle,
+    bulk_in: EndpointAddress,
+    bulk_out: EndpointAddress,
+    io: BulkIo,
+}
+
+impl<'a> BulkPipe<'a> {
+    /// Creates a byte stream reading from `bulk_in` and writing to `bulk_out`.
+    pub fn new(handle: &'a DeviceHandle, bulk_in: EndpointAddress, bulk_out: EndpointAddress) -> BulkPipe<'a> {
+        BulkPipe { handle, bulk_in, bulk_out, io: BulkIo::default() }
+    }
+
+    /// Terminates writes that fill their last packet with a zero-length packet.
+    ///
+    /// `max_packet_size` is that of the OUT endpoint. Many protocols need this to tell where a
+    /// message ends, as the device can't tell a full packet from the end of a message otherwise.
+    pub fn zero_length_packets(mut self, max_packet_size: u16) -> BulkPipe<'a> {
+        self.io = BulkIo::with_zero_length_packets(max_packet_size as usize);
+        self
+    }
+
+    /// Returns the handle of the device.
+    pub fn handle(&self) -> &'a DeviceHandle {
+        self.handle
+    }
+}
+
+impl<'a, 'b> AsyncRead for &'b BulkPipe<'a> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context, buf: &mut [u8]) -> task::Poll<io::Result<usize>> {
+        self.io.poll_read(self.handle, self.bulk_in, cx, buf, |data| data.to_vec())
+    }
+}
+
+impl<'a, 'b> AsyncWrite for &'b BulkPipe<'a> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context, buf: &[u8]) -> task::Poll<io::Result<usize>> {
+        self.io.poll_write(self.handle, self.bulk_out, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.io.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.io.poll_flush(cx)
+    }
+}
+
+impl<'a> AsyncRead for BulkPipe<'a> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context, buf: &mut [u8]) -> task::Poll<io::Result<usize>> {
+        self.io.poll_read(self.handle, self.bulk_in, cx, buf, |data| data.to_vec())
+    }
+}
+
+impl<'a> AsyncWrite for BulkPipe<'a> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context, buf: &[u8]) -> task::Poll<io::Result<usize>> {
+        self.io.poll_write(self.handle, self.bulk_out, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.io.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.io.poll_flush(cx)
+    }
+}
+
+/// A writer that batches small writes into larger ones, e.g., to send the many short messages of
+/// a chatty protocol in fewer bulk transfers.
+///
+/// Writes are collected until the next one wouldn't fit in a batch of `max_packets` packets, or
+/// until the writer is flushed. Writes of a whole batch or more are passed through as they are.
+/// A batch is also sent on the next write or flush after its deadline, which is a fixed delay
+/// after its first write. The crate has no timer of its own, so a task that stops writing must
+/// flush the writer itself, e.g., at [`deadline`](#method.deadline).
+pub struct CoalescingWriter<W> {
+    inner: W,
+    batch: Vec<u8>,
+    max_packet_size: usize,
+    limit: usize,
+    delay: Duration,
+    deadline: Option<Instant>,
+    // The batch is being written, and must not change until it has been
+    sending: bool,
+}
+
+impl<W: AsyncWrite + Unpin> CoalescingWriter<W> {
+    /// Creates a writer whose batches are up to 8 packets of `max_packet_size`, and are sent
+    /// at the latest `delay` after their first write.
+    pub fn new(inner: W, max_packet_size: u16, delay: Duration) -> CoalescingWriter<W> {
+        let max_packet_size = (max_packet_size as usize).max(1);
+        let limit = max_packet_size * 8;
+        CoalescingWriter { inner, batch: Vec::with_capacity(limit), max_packet_size, limit, delay, deadline: None, sending: false }
+    }
+
+    /// Sets the size of a batch in packets.
+    pub fn max_packets(mut self, max_packets: usize) -> CoalescingWriter<W> {
+        self.limit = self.max_packet_size * max_packets.max(1);
+        self
+    }
+
+    /// Returns when the batch being collected should be sent, if there is one.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Returns the number of bytes waiting to be sent.
+    pub fn buffered(&self) -> usize {
+        self.batch.len()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Writes the batch.
+    fn poll_send(&mut self, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        while !self.batch.is_empty() {
+            self.sending = true;
+            let written = match Pin::new(&mut self.inner).poll_write(cx, &self.batch) {
+                task::Poll::Ready(result) => result,
+                task::Poll::Pending => return task::Poll::Pending,
+            };
+            self.sending = false;
+
+            match written {
+                Ok(0) => return task::Poll::Ready(Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write batch"))),
+                Ok(written) => { self.batch.drain(..written); },
+                Err(e) => {
+                    self.batch.clear();
+                    self.deadline = None;
+                    return task::Poll::Ready(Err(e));
+                },
+            }
+        }
+
+        self.deadline = None;
+        task::Poll::Ready(Ok(()))
+    }
+
+    /// Sends the batch first if `length` more bytes don't fit, or it's being sent or is due.
+    fn poll_room(&mut self, cx: &mut task::Context, length: usize) -> task::Poll<io::Result<()>> {
+        let due = self.deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        if self.sending || due || self.batch.len() + length > self.limit {
+            return self.poll_send(cx);
+        }
+        task::Poll::Ready(Ok(()))
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        if self.batch.is_empty() {
+            self.deadline = Some(Instant::now() + self.delay);
+        }
+        self.batch.extend_from_slice(data);
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CoalescingWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context, buf: &[u8]) -> task::Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        match this.poll_room(cx, buf.len()) {
+            task::Poll::Ready(Ok(())) => {},
+            task::Poll::Ready(Err(e)) => return task::Poll::Ready(Err(e)),
+            task::Poll::Pending => return task::Poll::Pending,
+        }
+
+        if buf.len() >= this.limit {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        this.append(buf);
+        if this.batch.len() == this.limit {
+            // Started at once, the batch can't grow. A failure is reported by the next call.
+            if let task::Poll::Ready(Err(e)) = this.poll_send(cx) {
+                return task::Poll::Ready(Err(e));
+            }
+        }
+        task::Poll::Ready(Ok(buf.len()))
+    }
+
+    /// Adds as many of the buffers as fit in the batch.
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut task::Context, bufs: &[IoSlice]) -> task::Poll<io::Result<usize>> {
+        let first = match bufs.iter().find(|buf| !buf.is_empty()) {
+            Some(first) => first,
+            None => return task::Poll::Ready(Ok(0)),
+        };
+        if first.len() >= self.limit {
+            return self.poll_write(cx, first);
+        }
+
+        let this = self.get_mut();
+        let total = bufs.iter().map(|buf| buf.len()).sum::<usize>().min(this.limit);
+        match this.poll_room(cx, total) {
+            task::Poll::Ready(Ok(())) => {},
+            task::Poll::Ready(Err(e)) => return task::Poll::Ready(Err(e)),
+            task::Poll::Pending => return task::Poll::Pending,
+        }
+
+        let mut written = 0;
+        for buf in bufs {
+            if this.batch.len() + buf.len() > this.limit {
+                break;
+            }
+            this.append(buf);
+            written += buf.len();
+        }
+        task::Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        match this.poll_send(cx) {
+            task::Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        match this.poll_send(cx) {
+            task::Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_close(cx),
+            other => other,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    extern crate futures;
+
+    use super::*;
+
+    use self::futures::executor::block_on;
+    use self::futures::io::Cursor;
+    use self::futures::{AsyncWriteExt, SinkExt, StreamExt};
+
+    /// Records each write as one transfer.
+    #[derive(Default)]
+    struct Transfers(Vec<Vec<u8>>);
+
+    impl AsyncWrite for Transfers {
+        fn poll_write(self: Pin<&mut Self>, _cx: &mut task::Context, buf: &[u8]) -> task::Poll<io::Result<usize>> {
+            self.get_mut().0.push(buf.to_vec());
+            task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+            task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+            task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn it_coalesces_small_writes() {
+        let mut writer = CoalescingWriter::new(Transfers::default(), 4, Duration::from_secs(60)).max_packets(2);
+
+        block_on(writer.write_all(b"abc")).unwrap();
+        block_on(writer.write_all(b"def")).unwrap();
+        // Doesn't fit, so the batch is sent first
+        block_on(writer.write_all(b"ghi")).unwrap();
+        assert_eq!(3, writer.buffered());
+        // A whole batch is passed through
+        block_on(writer.write_all(b"0123456789")).unwrap();
+        block_on(writer.write_vectored(&[IoSlice::new(b"jk"), IoSlice::new(b"l")])).unwrap();
+        block_on(writer.flush()).unwrap();
+
+        let transfers: Vec<&[u8]> = writer.get_ref().0.iter().map(|t| &t[..]).collect();
+        assert_eq!(vec![&b"abcdef"[..], b"ghi", b"0123456789", b"jkl"], transfers);
+    }
+
+    #[test]
+    fn it_sends_batches_past_their_deadline() {
+        let mut writer = CoalescingWriter::new(Transfers::default(), 64, Duration::from_millis(0));
+
+        block_on(writer.write_all(b"a")).unwrap();
+        assert!(writer.deadline().is_some());
+        block_on(writer.write_all(b"b")).unwrap();
+
+        assert_eq!(vec![b"a".to_vec()], writer.get_ref().0);
+        assert_eq!(1, writer.buffered());
+    }
+
+    #[test]
+    fn it_decodes_length_prefixed_messages() {
+        let mut codec = LengthPrefixedCodec::new();
+        let mut src = vec![0x02, 0x00, 0x00, 0x00, 0xAA, 0xBB, 0x01, 0x00];
+
+        assert_eq!(Some(vec![0xAA, 0xBB]), codec.decode(&mut src).unwrap());
+        assert_eq!(None, codec.decode(&mut src).unwrap());
+        assert_eq!(vec![0x01, 0x00], src);
+        assert!(codec.decode_eof(&mut src).is_err());
+    }
+
+    #[test]
+    fn it_refuses_long_messages() {
+        let mut codec = LengthPrefixedCodec::big_endian().max_length(16);
+
+        assert!(codec.decode(&mut vec![0x00, 0x00, 0x00, 0x11]).is_err());
+        assert!(codec.encode(vec![0; 17], &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn it_frames_messages_over_a_byte_stream() {
+        let mut framed = Framed::new(Cursor::new(Vec::new()), LengthPrefixedCodec::big_endian());
+
+        block_on(framed.send(b"ping".to_vec())).unwrap();
+        assert_eq!(&[0x00, 0x00, 0x00, 0x04, b'p', b'i', b'n', b'g'][..], &framed.get_ref().get_ref()[..]);
+
+        let mut framed = Framed::new(Cursor::new(framed.into_inner().into_inner()), LengthPrefixedCodec::big_endian());
+        assert_eq!(b"ping".to_vec(), block_on(framed.next()).unwrap().unwrap());
+        assert!(block_on(framed.next()).is_none());
+    }
+}
@@ -0,0 +1,621 @@
+//! Support for FTDI USB serial converters, e.g., FT232R, FT2232H and FT232H.
+//!
+//! FTDI chips use vendor requests instead of a USB class. Every bulk IN packet starts with two
+//! modem status bytes, which [`FtdiPort`](struct.FtdiPort.html) strips before handing out the
+//! received data through `AsyncRead`.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task;
+
+use futures_io::{AsyncRead, AsyncWrite};
+
+use bulk_io::BulkIo;
+use control::{self, ControlFuture};
+use device::Device;
+use device_descriptor::DeviceDescriptor;
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::{Direction, EndpointAddress, Recipient, RequestType, TransferType, Version, request_type};
+use serial::{Parity, StopBits};
+
+/// The vendor ID of Future Technology Devices International.
+pub const FTDI_VENDOR_ID: u16 = 0x0403;
+
+/// The default product IDs of the FTDI chips.
+const FTDI_PRODUCT_IDS: [u16; 5] = [0x6001, 0x6010, 0x6011, 0x6014, 0x6015];
+
+const SIO_RESET: u8 = 0x00;
+const SIO_SET_MODEM_CTRL: u8 = 0x01;
+const SIO_SET_FLOW_CTRL: u8 = 0x02;
+const SIO_SET_BAUDRATE: u8 = 0x03;
+const SIO_SET_DATA: u8 = 0x04;
+const SIO_POLL_MODEM_STATUS: u8 = 0x05;
+const SIO_SET_LATENCY_TIMER: u8 = 0x09;
+const SIO_GET_LATENCY_TIMER: u8 = 0x0A;
+const SIO_SET_BITMODE: u8 = 0x0B;
+const SIO_READ_PINS: u8 = 0x0C;
+
+const SIO_RESET_SIO: u16 = 0;
+const SIO_RESET_PURGE_RX: u16 = 1;
+const SIO_RESET_PURGE_TX: u16 = 2;
+
+/// Length of the modem status header of each bulk IN packet.
+const STATUS_LENGTH: usize = 2;
+
+/// Maps eighths of the divisor to the code of the fractional divisor bits.
+const FRACTION_CODES: [u32; 8] = [0, 3, 2, 4, 1, 5, 6, 7];
+
+/// Indicates if a device descriptor has an FTDI vendor ID and one of the default FTDI product
+/// IDs.
+pub fn is_ftdi_device(descriptor: &DeviceDescriptor) -> bool {
+    descriptor.vendor_id() == FTDI_VENDOR_ID && FTDI_PRODUCT_IDS.contains(&descriptor.product_id())
+}
+
+/// The FTDI chip generation, which determines how the baud rate is encoded.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum ChipType {
+    Am,
+    Bm,
+    Ft2232C,
+    Ft232R,
+    Ft2232H,
+    Ft4232H,
+    Ft232H,
+    Ft230X,
+    Unknown(Version),
+}
+
+impl ChipType {
+    /// Returns the chip type for the `bcdDevice` of an FTDI device.
+    pub fn from_device_version(version: Version) -> ChipType {
+        match version.major() {
+            2 => ChipType::Am,
+            4 => ChipType::Bm,
+            5 => ChipType::Ft2232C,
+            6 => ChipType::Ft232R,
+            7 => ChipType::Ft2232H,
+            8 => ChipType::Ft4232H,
+            9 => ChipType::Ft232H,
+            10 => ChipType::Ft230X,
+            _ => ChipType::Unknown(version),
+        }
+    }
+
+    /// Indicates if the chip has the 120 MHz baud rate clock of the high speed chips.
+    pub fn is_high_speed(&self) -> bool {
+        matches!(*self, ChipType::Ft2232H | ChipType::Ft4232H | ChipType::Ft232H)
+    }
+
+    /// Indicates if the chip has more than one interface, which the requests then select in
+    /// their index.
+    fn is_multi_interface(&self) -> bool {
+        matches!(*self, ChipType::Ft2232C | ChipType::Ft2232H | ChipType::Ft4232H)
+    }
+}
+
+/// Computes the encoded baud rate divisor for a chip.
+///
+/// Returns the 18-bit divisor and the baud rate it actually gives, which may differ from the
+/// requested one since the divisor has a resolution of one eighth.
+///
+/// Returns `Error::InvalidParam` if the baud rate is zero or above what the chip supports.
+///
+/// ```
+/// use libusb_async::ftdi::{ChipType, encode_baud_rate};
+///
+/// assert_eq!((0x4138, 9600), encode_baud_rate(ChipType::Ft232R, 9600).unwrap());
+/// assert_eq!((0x001A, 115385), encode_baud_rate(ChipType::Ft232R, 115200).unwrap());
+/// ```
+pub fn encode_baud_rate(chip: ChipType, baud_rate: u32) -> ::Result<(u32, u32)> {
+    // The high speed chips divide 120 MHz by 10 instead of 48 MHz by 16, unless the baud rate is
+    // too low for the 17-bit divisor
+    let (base, high_speed_bit) = if chip.is_high_speed() && baud_rate >= 1200 {
+        (12_000_000, 0x20000)
+    } else {
+        (3_000_000, 0)
+    };
+
+    if baud_rate == 0 || baud_rate > base {
+        return Err(Error::InvalidParam);
+    }
+
+    // Divisors 1 and 1.5 have special codes, and there are no divisors between 1.5 and 2
+    let (divisor, actual) = if baud_rate >= base {
+        (0, base)
+    } else if baud_rate >= base * 2 / 3 {
+        (1, base * 2 / 3)
+    } else if baud_rate >= base / 2 {
+        (2, base / 2)
+    } else {
+        // The divisor in eighths, rounded to nearest
+        let sixteenths = (base as u64 * 16 / baud_rate as u64) as u32;
+        let eighths = (sixteenths / 2 + (sixteenths & 1)).min(0x1FFFF);
+        let actual = (base as u64 * 16 / eighths as u64) as u32;
+
+        ((eighths >> 3) | FRACTION_CODES[(eighths & 7) as usize] << 14, actual / 2 + (actual & 1))
+    };
+
+    Ok((divisor | high_speed_bit, actual))
+}
+
+/// The modem and line status sent at the start of each bulk IN packet, or returned by
+/// SIO_POLL_MODEM_STATUS.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct ModemStatus {
+    modem: u8,
+    line: u8,
+}
+
+impl ModemStatus {
+    /// Returns the raw modem status byte.
+    pub fn modem_bits(&self) -> u8 {
+        self.modem
+    }
+
+    /// Returns the raw line status byte.
+    pub fn line_bits(&self) -> u8 {
+        self.line
+    }
+
+    /// Clear to send.
+    pub fn cts(&self) -> bool {
+        self.modem & 0x10 != 0
+    }
+
+    /// Data set ready.
+    pub fn dsr(&self) -> bool {
+        self.modem & 0x20 != 0
+    }
+
+    /// Ring indicator.
+    pub fn ri(&self) -> bool {
+        self.modem & 0x40 != 0
+    }
+
+    /// Data carrier detect.
+    pub fn dcd(&self) -> bool {
+        self.modem & 0x80 != 0
+    }
+
+    /// Indicates if received data was lost because the receive buffer was full.
+    pub fn overrun_error(&self) -> bool {
+        self.line & 0x02 != 0
+    }
+
+    /// Indicates if a character was received with bad parity.
+    pub fn parity_error(&self) -> bool {
+        self.line & 0x04 != 0
+    }
+
+    /// Indicates if a character was received without a valid stop bit.
+    pub fn framing_error(&self) -> bool {
+        self.line & 0x08 != 0
+    }
+
+    /// Indicates if a break condition was received.
+    pub fn break_interrupt(&self) -> bool {
+        self.line & 0x10 != 0
+    }
+
+    fn decode(data: &[u8]) -> ::Result<ModemStatus> {
+        if data.len() < STATUS_LENGTH {
+            return Err(Error::InvalidParam);
+        }
+
+        Ok(ModemStatus { modem: data[0], line: data[1] })
+    }
+}
+
+/// Removes the modem status header from each packet of a bulk IN transfer.
+///
+/// Returns the data and the status of the last packet, or `None` if the transfer was empty.
+pub fn strip_modem_status(transfer: &[u8], packet_size: usize) -> (Vec<u8>, Option<ModemStatus>) {
+    let mut data = Vec::with_capacity(transfer.len());
+    let mut status = None;
+
+    for packet in transfer.chunks(packet_size.max(STATUS_LENGTH + 1)) {
+        if let Ok(packet_status) = ModemStatus::decode(packet) {
+            status = Some(packet_status);
+            data.extend_from_slice(&packet[STATUS_LENGTH..]);
+        }
+    }
+
+    (data, status)
+}
+
+/// The pin mode set with SIO_SET_BITMODE.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum BitMode {
+    /// Normal serial or FIFO operation.
+    Reset,
+
+    /// Asynchronous bit-bang.
+    Bitbang,
+
+    /// Multi-Protocol Synchronous Serial Engine, for SPI, I2C and JTAG.
+    Mpsse,
+
+    /// Synchronous bit-bang.
+    SyncBitbang,
+
+    /// MCU host bus emulation.
+    Mcu,
+
+    /// Fast opto-isolated serial.
+    Opto,
+
+    /// CBUS pin bit-bang.
+    Cbus,
+
+    /// Single channel synchronous FIFO.
+    SyncFifo,
+}
+
+impl BitMode {
+    fn bits(&self) -> u8 {
+        match *self {
+            BitMode::Reset => 0x00,
+            BitMode::Bitbang => 0x01,
+            BitMode::Mpsse => 0x02,
+            BitMode::SyncBitbang => 0x04,
+            BitMode::Mcu => 0x08,
+            BitMode::Opto => 0x10,
+            BitMode::Cbus => 0x20,
+            BitMode::SyncFifo => 0x40,
+        }
+    }
+}
+
+/// Flow control of a serial line.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum FlowControl {
+    None,
+    RtsCts,
+    DtrDsr,
+
+    /// Software flow control with the given XON and XOFF characters.
+    XonXoff(u8, u8),
+}
+
+/// Encodes the character framing as the `wValue` of SIO_SET_DATA.
+///
+/// Returns `Error::InvalidParam` for data bits other than 7 or 8.
+pub fn encode_data_characteristics(data_bits: u8, parity: Parity, stop_bits: StopBits) -> ::Result<u16> {
+    if data_bits != 7 && data_bits != 8 {
+        return Err(Error::InvalidParam);
+    }
+
+    let parity = match parity {
+        Parity::None => 0,
+        Parity::Odd => 1,
+        Parity::Even => 2,
+        Parity::Mark => 3,
+        Parity::Space => 4,
+    };
+
+    let stop_bits = match stop_bits {
+        StopBits::One => 0,
+        StopBits::OnePointFive => 1,
+        StopBits::Two => 2,
+    };
+
+    Ok(data_bits as u16 | parity << 8 | stop_bits << 11)
+}
+
+/// An open interface of an FTDI chip.
+///
+/// Data is read and written through the `AsyncRead` and `AsyncWrite` implementations, which
+/// are also implemented for `&FtdiPort` so the port can be read and written concurrently.
+pub struct FtdiPort {
+    handle: DeviceHandle,
+    chip: ChipType,
+    interface: u8,
+    bulk_in: (EndpointAddress, usize),
+    bulk_out: EndpointAddress,
+    io: BulkIo,
+    status: Mutex<Option<ModemStatus>>,
+    kernel_driver_detached: bool,
+}
+
+impl FtdiPort {
+    /// Opens an FTDI device and claims one of its interfaces.
+    ///
+    /// The chip type is taken from `bcdDevice`. A kernel driver bound to the interface, e.g.,
+    /// `ftdi_sio`, is detached and reattached when the port is dropped.
+    ///
+    /// ## Errors
+    ///
+    /// * `NotFound` if the active configuration has no such interface, or it lacks a bulk IN
+    ///   or bulk OUT endpoint.
+    /// * Any error returned while opening the device or claiming the interface.
+    pub fn open(device: &Device, interface: u8) -> ::Result<FtdiPort> {
+        let chip = ChipType::from_device_version(device.device_descriptor()?.device_version());
+
+        let (bulk_in, bulk_out) = {
+            let config = device.active_config_descriptor()?;
+            let setting = config.interfaces()
+                .find(|i| i.number() == interface)
+                .and_then(|i| i.descriptors().next())
+                .ok_or(Error::NotFound)?;
+
+            let bulk_in = setting.first_endpoint(TransferType::Bulk, Direction::In).ok_or(Error::NotFound)?;
+            let bulk_out = setting.first_endpoint(TransferType::Bulk, Direction::Out).ok_or(Error::NotFound)?;
+
+            ((bulk_in.address().into(), bulk_in.max_packet_size() as usize), bulk_out.address().into())
+        };
+
+        let mut handle = device.open()?;
+
+        let kernel_driver_detached = handle.kernel_driver_active(interface).unwrap_or(false);
+        if kernel_driver_detached {
+            handle.detach_kernel_driver(interface)?;
+        }
+
+        handle.claim_interface(interface)?;
+
+        Ok(FtdiPort {
+            handle,
+            chip,
+            interface,
+            bulk_in,
+            bulk_out,
+            io: BulkIo::default(),
+            status: Mutex::new(None),
+            kernel_driver_detached,
+        })
+    }
+
+    /// Returns the handle of the device.
+    pub fn handle(&self) -> &DeviceHandle {
+        &self.handle
+    }
+
+    /// Returns the chip type.
+    pub fn chip_type(&self) -> ChipType {
+        self.chip
+    }
+
+    /// Returns the number of the claimed interface.
+    pub fn interface_number(&self) -> u8 {
+        self.interface
+    }
+
+    /// Returns the modem status of the most recently received packet, or `None` if nothing has
+    /// been read yet.
+    pub fn last_modem_status(&self) -> Option<ModemStatus> {
+        *self.status.lock().unwrap()
+    }
+
+    /// Resets the interface.
+    pub fn reset(&self) -> ControlFuture<usize> {
+        self.write_request(SIO_RESET, SIO_RESET_SIO, 0)
+    }
+
+    /// Discards the data in the receive buffer of the chip.
+    pub fn purge_rx(&self) -> ControlFuture<usize> {
+        self.write_request(SIO_RESET, SIO_RESET_PURGE_RX, 0)
+    }
+
+    /// Discards the data in the transmit buffer of the chip.
+    pub fn purge_tx(&self) -> ControlFuture<usize> {
+        self.write_request(SIO_RESET, SIO_RESET_PURGE_TX, 0)
+    }
+
+    /// Sets the baud rate to the closest one the chip supports.
+    ///
+    /// The future resolves to `Err(Error::InvalidParam)` if the baud rate is out of range; see
+    /// [`encode_baud_rate`](fn.encode_baud_rate.html).
+    pub fn set_baud_rate(&self, baud_rate: u32) -> ControlFuture<usize> {
+        let divisor = match encode_baud_rate(self.chip, baud_rate) {
+            Ok((divisor, _)) => divisor,
+            Err(e) => return control::failed(e, control::written),
+        };
+
+        // Multi-interface chips need the interface in the low byte of the index, so the upper
+        // divisor bits move to the high byte
+        let index = if self.chip.is_multi_interface() {
+            ((divisor >> 8) & 0xFF00) as u16 | self.port_index()
+        } else {
+            (divisor >> 16) as u16
+        };
+
+        control::write(&self.handle,
+                       request_type(Direction::Out, RequestType::Vendor, Recipient::Device),
+                       SIO_SET_BAUDRATE,
+                       divisor as u16,
+                       index,
+                       &[])
+    }
+
+    /// Sets the character framing. The chips support 7 or 8 data bits.
+    pub fn set_data_characteristics(&self, data_bits: u8, parity: Parity, stop_bits: StopBits) -> ControlFuture<usize> {
+        match encode_data_characteristics(data_bits, parity, stop_bits) {
+            Ok(value) => self.write_request(SIO_SET_DATA, value, 0),
+            Err(e) => control::failed(e, control::written),
+        }
+    }
+
+    /// Sets the flow control.
+    pub fn set_flow_control(&self, flow_control: FlowControl) -> ControlFuture<usize> {
+        let (value, mode) = match flow_control {
+            FlowControl::None => (0, 0x00),
+            FlowControl::RtsCts => (0, 0x01),
+            FlowControl::DtrDsr => (0, 0x02),
+            FlowControl::XonXoff(xon, xoff) => ((xoff as u16) << 8 | xon as u16, 0x04),
+        };
+
+        self.write_request(SIO_SET_FLOW_CTRL, value, mode << 8)
+    }
+
+    /// Sets the DTR and RTS control signals.
+    pub fn set_control_lines(&self, dtr: bool, rts: bool) -> ControlFuture<usize> {
+        // The high byte selects which of the signals to change
+        self.write_request(SIO_SET_MODEM_CTRL, 0x0300 | dtr as u16 | (rts as u16) << 1, 0)
+    }
+
+    /// Reads the current modem status.
+    pub fn modem_status(&self) -> ControlFuture<ModemStatus> {
+        self.read_request(SIO_POLL_MODEM_STATUS, 2, ModemStatus::decode)
+    }
+
+    /// Sets the latency timer in milliseconds, which is how long the chip waits before it
+    /// sends a packet that isn't full. The chips accept 1 to 255 ms.
+    pub fn set_latency_timer(&self, latency: u8) -> ControlFuture<usize> {
+        if latency == 0 {
+            return control::failed(Error::InvalidParam, control::written);
+        }
+
+        self.write_request(SIO_SET_LATENCY_TIMER, latency as u16, 0)
+    }
+
+    /// Reads the latency timer in milliseconds.
+    pub fn latency_timer(&self) -> ControlFuture<u8> {
+        self.read_request(SIO_GET_LATENCY_TIMER, 1, |data| data.first().cloned().ok_or(Error::Io))
+    }
+
+    /// Sets the pin mode. In the bit-bang modes, `mask` selects the pins that are outputs.
+    pub fn set_bitmode(&self, mask: u8, mode: BitMode) -> ControlFuture<usize> {
+        self.write_request(SIO_SET_BITMODE, (mode.bits() as u16) << 8 | mask as u16, 0)
+    }
+
+    /// Reads the state of the data pins.
+    pub fn read_pins(&self) -> ControlFuture<u8> {
+        self.read_request(SIO_READ_PINS, 1, |data| data.first().cloned().ok_or(Error::Io))
+    }
+
+    /// Returns the index that selects the interface in the vendor requests.
+    fn port_index(&self) -> u16 {
+        if self.chip.is_multi_interface() { self.interface as u16 + 1 } else { 0 }
+    }
+
+    fn write_request(&self, request: u8, value: u16, index: u16) -> ControlFuture<usize> {
+        control::write(&self.handle,
+                       request_type(Direction::Out, RequestType::Vendor, Recipient::Device),
+                       request,
+                       value,
+                       index | self.port_index(),
+                       &[])
+    }
+
+    fn read_request<T>(&self, request: u8, length: u16, convert: fn(&[u8]) -> ::Result<T>) -> ControlFuture<T> {
+        control::read(&self.handle,
+                      request_type(Direction::In, RequestType::Vendor, Recipient::Device),
+                      request,
+                      0,
+                      self.port_index(),
+                      length,
+                      convert)
+    }
+
+    fn poll_read_bytes(&self, cx: &mut task::Context, buf: &mut [u8]) -> task::Poll<io::Result<usize>> {
+        self.io.poll_read(&self.handle, self.bulk_in.0, cx, buf, |transfer| {
+            let (data, status) = strip_modem_status(transfer, self.bulk_in.1);
+            if status.is_some() {
+                *self.status.lock().unwrap() = status;
+            }
+            data
+        })
+    }
+}
+
+impl Drop for FtdiPort {
+    fn drop(&mut self) {
+        if self.kernel_driver_detached {
+            let _ = self.handle.release_interface(self.interface);
+            let _ = self.handle.attach_kernel_driver(self.interface);
+        }
+    }
+}
+
+impl AsyncRead for &FtdiPort {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context, buf: &mut [u8]) -> task::Poll<io::Result<usize>> {
+        self.poll_read_bytes(cx, buf)
+    }
+}
+
+impl AsyncWrite for &FtdiPort {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context, buf: &[u8]) -> task::Poll<io::Result<usize>> {
+        self.io.poll_write(&self.handle, self.bulk_out, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.io.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.io.poll_flush(cx)
+    }
+}
+
+impl AsyncRead for FtdiPort {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context, buf: &mut [u8]) -> task::Poll<io::Result<usize>> {
+        self.poll_read_bytes(cx, buf)
+    }
+}
+
+impl AsyncWrite for FtdiPort {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context, buf: &[u8]) -> task::Poll<io::Result<usize>> {
+        self.io.poll_write(&self.handle, self.bulk_out, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.io.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.io.poll_flush(cx)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_encodes_baud_rates() {
+        assert_eq!((0x0000, 3_000_000), encode_baud_rate(ChipType::Ft232R, 3_000_000).unwrap());
+        assert_eq!((0x0001, 2_000_000), encode_baud_rate(ChipType::Ft232R, 2_000_000).unwrap());
+        assert_eq!((0x4138, 9600), encode_baud_rate(ChipType::Ft232R, 9600).unwrap());
+        assert_eq!((0x2000D, 923_077), encode_baud_rate(ChipType::Ft232H, 921_600).unwrap());
+        assert_eq!((0x2710, 300), encode_baud_rate(ChipType::Ft2232H, 300).unwrap());
+    }
+
+    #[test]
+    fn it_rejects_unsupported_baud_rates() {
+        assert!(encode_baud_rate(ChipType::Ft232R, 0).is_err());
+        assert!(encode_baud_rate(ChipType::Ft232R, 6_000_000).is_err());
+        assert!(encode_baud_rate(ChipType::Ft232H, 12_000_000).is_ok());
+    }
+
+    #[test]
+    fn it_identifies_chip_types() {
+        assert_eq!(ChipType::Ft232R, ChipType::from_device_version(Version::from_bcd(0x0600)));
+        assert_eq!(ChipType::Ft230X, ChipType::from_device_version(Version::from_bcd(0x1000)));
+        assert_eq!(ChipType::Unknown(Version(3, 0, 0)), ChipType::from_device_version(Version::from_bcd(0x0300)));
+    }
+
+    #[test]
+    fn it_strips_modem_status() {
+        let mut transfer = vec![0x01, 0x60];
+        transfer.extend_from_slice(&[b'a'; 62]);
+        transfer.extend_from_slice(&[0x11, 0x62, b'b']);
+
+        let (data, status) = strip_modem_status(&transfer, 64);
+        assert_eq!(63, data.len());
+        assert_eq!(b'b', data[62]);
+
+        let status = status.unwrap();
+        assert!(status.cts());
+        assert!(status.overrun_error());
+        assert_eq!((Vec::new(), None), strip_modem_status(&[], 64));
+    }
+
+    #[test]
+    fn it_encodes_data_characteristics() {
+        assert_eq!(0x1208, encode_data_characteristics(8, Parity::Even, StopBits::Two).unwrap());
+        assert!(encode_data_characteristics(5, Parity::None, StopBits::One).is_err());
+    }
+}
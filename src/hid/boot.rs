@@ -0,0 +1,266 @@
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task;
+
+use futures_core::Stream;
+
+use error::Error;
+
+use super::device::InputReports;
+
+/// Usage ID that fills all key slots when more keys are pressed than a report can hold.
+const ERROR_ROLL_OVER: u8 = 0x01;
+
+/// Usage ID of the first modifier key, Left Control. The modifier bits follow in usage order.
+const FIRST_MODIFIER: u8 = 0xE0;
+
+/// Length of a boot protocol keyboard report.
+const KEYBOARD_REPORT_LENGTH: usize = 8;
+
+/// Shortest boot protocol mouse report: buttons, X and Y.
+const MOUSE_REPORT_LENGTH: usize = 3;
+
+/// A boot protocol keyboard input report.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub struct KeyboardReport {
+    /// The modifier keys, one bit per key from Left Control (bit 0) to Right GUI (bit 7).
+    pub modifiers: u8,
+
+    /// Usage IDs of the pressed keys, in the order of the report.
+    pub keys: Vec<u8>,
+}
+
+impl KeyboardReport {
+    /// Decodes a boot protocol keyboard report.
+    ///
+    /// Returns `Error::InvalidParam` if the report is shorter than 8 bytes.
+    pub fn decode(data: &[u8]) -> ::Result<KeyboardReport> {
+        if data.len() < KEYBOARD_REPORT_LENGTH {
+            return Err(Error::InvalidParam);
+        }
+
+        Ok(KeyboardReport {
+            modifiers: data[0],
+            keys: data[2..KEYBOARD_REPORT_LENGTH].iter().cloned().filter(|&key| key != 0).collect(),
+        })
+    }
+
+    /// Indicates if the keyboard reported that too many keys are pressed to tell which.
+    pub fn is_roll_over(&self) -> bool {
+        self.keys.contains(&ERROR_ROLL_OVER)
+    }
+
+    /// Returns the usage IDs of the pressed keys, including the modifier keys.
+    pub fn pressed_keys(&self) -> Vec<u8> {
+        let modifiers = (0..8).filter(|bit| self.modifiers & (1 << bit) != 0).map(|bit| FIRST_MODIFIER + bit);
+        modifiers.chain(self.keys.iter().cloned()).collect()
+    }
+}
+
+/// A key press or release, identified by its usage ID on the Keyboard/Keypad page.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum KeyEvent {
+    Pressed(u8),
+    Released(u8),
+}
+
+/// Turns successive keyboard reports into key events.
+#[derive(Debug,Clone,Default)]
+pub struct KeyboardState {
+    pressed: Vec<u8>,
+}
+
+impl KeyboardState {
+    /// Creates a state with no keys pressed.
+    pub fn new() -> KeyboardState {
+        KeyboardState { pressed: Vec::new() }
+    }
+
+    /// Returns the usage IDs of the keys that are currently pressed.
+    pub fn pressed_keys(&self) -> &[u8] {
+        &self.pressed
+    }
+
+    /// Updates the state from a report and returns the changes.
+    ///
+    /// Releases are returned before presses. Roll over reports are ignored, since they don't
+    /// tell which keys are pressed.
+    ///
+    /// ```
+    /// use libusb_async::hid::{KeyEvent, KeyboardReport, KeyboardState};
+    ///
+    /// let mut state = KeyboardState::new();
+    /// let report = KeyboardReport::decode(&[0x02, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00]).unwrap();
+    /// assert_eq!(vec![KeyEvent::Pressed(0xE1), KeyEvent::Pressed(0x04)], state.update(&report));
+    /// ```
+    pub fn update(&mut self, report: &KeyboardReport) -> Vec<KeyEvent> {
+        if report.is_roll_over() {
+            return Vec::new();
+        }
+
+        let pressed = report.pressed_keys();
+
+        let mut events: Vec<KeyEvent> = self.pressed.iter()
+            .filter(|key| !pressed.contains(key))
+            .map(|&key| KeyEvent::Released(key))
+            .collect();
+
+        events.extend(pressed.iter().filter(|key| !self.pressed.contains(key)).map(|&key| KeyEvent::Pressed(key)));
+
+        self.pressed = pressed;
+        events
+    }
+}
+
+/// A boot protocol mouse input report.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct MouseReport {
+    /// The buttons, one bit per button starting with the primary button in bit 0.
+    pub buttons: u8,
+
+    /// Relative horizontal movement.
+    pub dx: i8,
+
+    /// Relative vertical movement, positive downwards.
+    pub dy: i8,
+
+    /// Relative wheel movement, or 0 if the report has no wheel byte.
+    pub wheel: i8,
+}
+
+impl MouseReport {
+    /// Decodes a boot protocol mouse report.
+    ///
+    /// Many mice send a wheel byte after the three bytes of the boot protocol, which is
+    /// decoded when present. Returns `Error::InvalidParam` if the report is shorter than 3
+    /// bytes.
+    pub fn decode(data: &[u8]) -> ::Result<MouseReport> {
+        if data.len() < MOUSE_REPORT_LENGTH {
+            return Err(Error::InvalidParam);
+        }
+
+        Ok(MouseReport {
+            buttons: data[0],
+            dx: data[1] as i8,
+            dy: data[2] as i8,
+            wheel: data.get(3).map_or(0, |&wheel| wheel as i8),
+        })
+    }
+
+    /// Indicates if a button is pressed. Button 1 is the primary button.
+    pub fn button(&self, button: u8) -> bool {
+        (1..=8).contains(&button) && self.buttons & (1 << (button - 1)) != 0
+    }
+}
+
+/// Stream of key events returned by
+/// [`HidDevice::keyboard_events`](struct.HidDevice.html#method.keyboard_events).
+pub struct KeyboardEvents<'a> {
+    reports: InputReports<'a>,
+    state: KeyboardState,
+    events: VecDeque<KeyEvent>,
+}
+
+#[doc(hidden)]
+pub fn keyboard_events<'a>(reports: InputReports<'a>) -> KeyboardEvents<'a> {
+    KeyboardEvents { reports, state: KeyboardState::new(), events: VecDeque::new() }
+}
+
+impl<'a> Stream for KeyboardEvents<'a> {
+    type Item = ::Result<KeyEvent>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(event) = this.events.pop_front() {
+                return task::Poll::Ready(Some(Ok(event)));
+            }
+
+            let report = match Pin::new(&mut this.reports).poll_next(cx) {
+                task::Poll::Pending => return task::Poll::Pending,
+                task::Poll::Ready(None) => return task::Poll::Ready(None),
+                task::Poll::Ready(Some(report)) => report,
+            };
+
+            match report.and_then(|report| KeyboardReport::decode(&report.data)) {
+                Ok(report) => this.events.extend(this.state.update(&report)),
+                Err(e) => return task::Poll::Ready(Some(Err(e))),
+            }
+        }
+    }
+}
+
+/// Stream of mouse reports returned by
+/// [`HidDevice::mouse_reports`](struct.HidDevice.html#method.mouse_reports).
+pub struct MouseReports<'a> {
+    reports: InputReports<'a>,
+}
+
+#[doc(hidden)]
+pub fn mouse_reports<'a>(reports: InputReports<'a>) -> MouseReports<'a> {
+    MouseReports { reports }
+}
+
+impl<'a> Stream for MouseReports<'a> {
+    type Item = ::Result<MouseReport>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<Self::Item>> {
+        Pin::new(&mut self.get_mut().reports).poll_next(cx)
+            .map(|report| report.map(|report| report.and_then(|report| MouseReport::decode(&report.data))))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_decodes_keyboard_report() {
+        let report = KeyboardReport::decode(&[0x11, 0x00, 0x04, 0x05, 0x00, 0x00, 0x00, 0x00]).unwrap();
+
+        assert_eq!(vec![0x04, 0x05], report.keys);
+        assert_eq!(vec![0xE0, 0xE4, 0x04, 0x05], report.pressed_keys());
+        assert!(KeyboardReport::decode(&[0x00; 7]).is_err());
+    }
+
+    #[test]
+    fn it_diffs_keyboard_reports() {
+        let mut state = KeyboardState::new();
+
+        state.update(&KeyboardReport::decode(&[0x00, 0x00, 0x04, 0x05, 0x00, 0x00, 0x00, 0x00]).unwrap());
+        let events = state.update(&KeyboardReport::decode(&[0x00, 0x00, 0x05, 0x06, 0x00, 0x00, 0x00, 0x00]).unwrap());
+
+        assert_eq!(vec![KeyEvent::Released(0x04), KeyEvent::Pressed(0x06)], events);
+        assert_eq!(&[0x05, 0x06], state.pressed_keys());
+    }
+
+    #[test]
+    fn it_ignores_roll_over_reports() {
+        let mut state = KeyboardState::new();
+
+        state.update(&KeyboardReport::decode(&[0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00]).unwrap());
+        let events = state.update(&KeyboardReport::decode(&[0x00, 0x00, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01]).unwrap());
+
+        assert!(events.is_empty());
+        assert_eq!(&[0x04], state.pressed_keys());
+    }
+
+    #[test]
+    fn it_decodes_mouse_report() {
+        assert_eq!(MouseReport { buttons: 0x01, dx: -2, dy: 3, wheel: 0 }, MouseReport::decode(&[0x01, 0xFE, 0x03]).unwrap());
+        assert_eq!(-1, MouseReport::decode(&[0x00, 0x00, 0x00, 0xFF]).unwrap().wheel);
+        assert!(MouseReport::decode(&[0x01, 0x02]).is_err());
+    }
+
+    #[test]
+    fn it_tests_mouse_buttons() {
+        let report = MouseReport { buttons: 0x05, dx: 0, dy: 0, wheel: 0 };
+
+        assert!(report.button(1));
+        assert!(!report.button(2));
+        assert!(report.button(3));
+        assert!(!report.button(0));
+    }
+}
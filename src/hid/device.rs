@@ -0,0 +1,531 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task;
+use std::time::Duration;
+
+use futures_core::Stream;
+
+use control::{self, ControlFuture, ControlRequest};
+use device::Device;
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::{ClassCode, Direction, Recipient, RequestType, TransferType, request_type};
+use read_queue::{Backpressure, QueueDepth, ReadQueue};
+use transfer::{Transfer, TransferFuture};
+
+use super::boot::{self, KeyboardEvents, MouseReports};
+use super::report_descriptor::{ReportDescriptor, ReportKind, parse_report_descriptor};
+
+const DT_HID: u8 = 0x21;
+const DT_REPORT: u8 = 0x22;
+
+const GET_REPORT: u8 = 0x01;
+const GET_IDLE: u8 = 0x02;
+const GET_PROTOCOL: u8 = 0x03;
+const SET_REPORT: u8 = 0x09;
+const SET_IDLE: u8 = 0x0A;
+const SET_PROTOCOL: u8 = 0x0B;
+
+const REPORT_TYPE_OUTPUT: u16 = 0x02;
+const REPORT_TYPE_FEATURE: u16 = 0x03;
+
+const DESCRIPTOR_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A report sent to or received from a HID device.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub struct Report {
+    /// The report ID, or 0 if the device doesn't use report IDs.
+    pub id: u8,
+
+    /// The report data, excluding the report ID.
+    pub data: Vec<u8>,
+}
+
+impl Report {
+    /// Splits a report as sent on the wire into its ID and data.
+    fn from_bytes(bytes: &[u8], uses_report_ids: bool) -> Report {
+        match bytes.split_first() {
+            Some((&id, data)) if uses_report_ids => Report { id, data: data.to_vec() },
+            _ => Report { id: 0, data: bytes.to_vec() },
+        }
+    }
+}
+
+/// The report protocol of a boot interface.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum Protocol {
+    /// The fixed reports of the boot protocol, decoded by
+    /// [`KeyboardReport`](struct.KeyboardReport.html) and [`MouseReport`](struct.MouseReport.html).
+    Boot,
+
+    /// The reports described by the report descriptor. Devices start in this protocol.
+    Report,
+}
+
+/// A claimed HID interface.
+///
+/// Input reports are read from the interrupt IN endpoint. Output reports are written to the
+/// interrupt OUT endpoint if the interface has one, and with SET_REPORT requests otherwise.
+/// Feature reports always use control transfers.
+///
+/// Report IDs are handled as in the HID specification: a report ID of 0 means that the device
+/// doesn't use report IDs and no ID byte is sent or expected.
+pub struct HidDevice {
+    handle: DeviceHandle,
+    interface: u8,
+    input_endpoint: u8,
+    input_packet_size: u16,
+    input_depth: QueueDepth,
+    output_endpoint: Option<u8>,
+    report_descriptor: ReportDescriptor,
+    kernel_driver_detached: bool,
+}
+
+impl HidDevice {
+    /// Opens a device and claims one of its HID interfaces.
+    ///
+    /// A kernel driver bound to the interface is detached and reattached when the `HidDevice` is
+    /// dropped. The report descriptor is read while opening.
+    ///
+    /// ## Errors
+    ///
+    /// * `NotFound` if the active configuration has no such interface, or it isn't a HID
+    ///   interface with an interrupt IN endpoint.
+    /// * `InvalidParam` if the report descriptor is malformed.
+    /// * Any error returned while opening the device, claiming the interface or reading the
+    ///   report descriptor.
+    pub fn open(device: &Device, interface: u8) -> ::Result<HidDevice> {
+        let (input_endpoint, input_packet_size, output_endpoint, descriptor_length) = {
+            let config = device.active_config_descriptor()?;
+            let setting = config.interfaces()
+                .find(|i| i.number() == interface)
+                .and_then(|i| i.descriptors().next())
+                .filter(|setting| setting.class_code() == ClassCode::Hid)
+                .ok_or(Error::NotFound)?;
+
+            let input = setting.first_endpoint(TransferType::Interrupt, Direction::In).ok_or(Error::NotFound)?;
+            let output = setting.first_endpoint(TransferType::Interrupt, Direction::Out);
+            let descriptor_length = report_descriptor_length(setting.extra()).ok_or(Error::NotFound)?;
+
+            (input.address(), input.max_packet_size(), output.map(|ep| ep.address()), descriptor_length)
+        };
+
+        let mut handle = device.open()?;
+
+        let kernel_driver_detached = handle.kernel_driver_active(interface).unwrap_or(false);
+        if kernel_driver_detached {
+            handle.detach_kernel_driver(interface)?;
+        }
+
+        handle.claim_interface(interface)?;
+
+        let mut buf = vec![0; descriptor_length as usize];
+        let request = ControlRequest::get_descriptor(DT_REPORT, 0)
+            .recipient(Recipient::Interface)
+            .index(interface as u16);
+        let length = handle.read_control(request.request_type(Direction::In), request.request, request.value, request.index,
+                                         &mut buf,
+                                         DESCRIPTOR_TIMEOUT)?;
+
+        let report_descriptor = parse_report_descriptor(&buf[..length])?;
+        let input_depth = QueueDepth::for_endpoint(TransferType::Interrupt, device.speed(), input_packet_size);
+
+        Ok(HidDevice {
+            handle,
+            interface,
+            input_endpoint,
+            input_packet_size,
+            input_depth,
+            output_endpoint,
+            report_descriptor,
+            kernel_driver_detached,
+        })
+    }
+
+    /// Returns the handle of the device.
+    pub fn handle(&self) -> &DeviceHandle {
+        &self.handle
+    }
+
+    /// Returns the number of the claimed interface.
+    pub fn interface_number(&self) -> u8 {
+        self.interface
+    }
+
+    /// Returns the interface's report descriptor.
+    pub fn report_descriptor(&self) -> &ReportDescriptor {
+        &self.report_descriptor
+    }
+
+    /// Returns a stream of input reports.
+    ///
+    /// Reads are kept pending on the interrupt IN endpoint while the stream exists, so reports
+    /// aren't lost between polls. The stream ends after the first error.
+    pub fn input_reports<'a>(&'a self) -> InputReports<'a> {
+        InputReports { device: self, reads: ReadQueue::new(self.input_depth.transfers), spare: None, done: false }
+    }
+
+    /// Returns a stream of key presses and releases decoded from boot protocol keyboard
+    /// reports.
+    ///
+    /// The input reports must have the boot protocol layout, which keyboards use in the boot
+    /// protocol and most also use in the report protocol. Reports that are too short are
+    /// yielded as `Err(InvalidParam)`. The stream ends after the first transfer error.
+    pub fn keyboard_events<'a>(&'a self) -> KeyboardEvents<'a> {
+        boot::keyboard_events(self.input_reports())
+    }
+
+    /// Returns a stream of boot protocol mouse reports.
+    ///
+    /// The input reports must have the boot protocol layout, optionally followed by a wheel
+    /// byte. Reports that are too short are yielded as `Err(InvalidParam)`. The stream ends
+    /// after the first transfer error.
+    pub fn mouse_reports<'a>(&'a self) -> MouseReports<'a> {
+        boot::mouse_reports(self.input_reports())
+    }
+
+    /// Writes an output report.
+    ///
+    /// The future resolves to the number of bytes transferred, including the report ID byte if
+    /// `report_id` isn't 0.
+    pub fn write_output_report(&self, report_id: u8, data: &[u8]) -> ReportWriteFuture {
+        let bytes = with_report_id(report_id, data);
+
+        let state = match self.output_endpoint {
+            Some(endpoint) => {
+                let transfer = self.handle.alloc_transfer(0).and_then(|mut transfer| {
+                    transfer.fill_interrupt_write(endpoint.into(), &bytes)?;
+                    Ok(transfer.submit())
+                });
+
+                match transfer {
+                    Ok(future) => WriteState::Interrupt(future),
+                    Err(e) => WriteState::Failed(e),
+                }
+            },
+            None => WriteState::Control(self.set_report(REPORT_TYPE_OUTPUT, report_id, &bytes)),
+        };
+
+        ReportWriteFuture { state }
+    }
+
+    /// Reads a feature report.
+    ///
+    /// The length of the report is taken from the report descriptor. The future resolves to
+    /// the report data, without the report ID.
+    pub fn get_feature_report(&self, report_id: u8) -> ControlFuture<Vec<u8>> {
+        let id = if report_id == 0 { None } else { Some(report_id) };
+        let length = self.report_descriptor.report_length(ReportKind::Feature, id) + id.map_or(0, |_| 1);
+
+        control::read(&self.handle,
+                      request_type(Direction::In, RequestType::Class, Recipient::Interface),
+                      GET_REPORT,
+                      REPORT_TYPE_FEATURE << 8 | report_id as u16,
+                      self.interface as u16,
+                      length as u16,
+                      if id.is_some() { strip_report_id } else { control::to_vec })
+    }
+
+    /// Writes a feature report.
+    ///
+    /// The future resolves to the number of bytes transferred, including the report ID byte if
+    /// `report_id` isn't 0.
+    pub fn set_feature_report(&self, report_id: u8, data: &[u8]) -> ControlFuture<usize> {
+        self.set_report(REPORT_TYPE_FEATURE, report_id, &with_report_id(report_id, data))
+    }
+
+    /// Sets the idle rate, i.e., how often the device repeats an unchanged input report.
+    ///
+    /// The duration is rounded down to a multiple of 4 ms and limited to 1020 ms. A zero
+    /// duration makes the device report only when the data changes. A `report_id` of 0 applies
+    /// the rate to all input reports.
+    pub fn set_idle(&self, report_id: u8, duration: Duration) -> ControlFuture<usize> {
+        control::write(&self.handle,
+                       request_type(Direction::Out, RequestType::Class, Recipient::Interface),
+                       SET_IDLE,
+                       (idle_rate(duration) as u16) << 8 | report_id as u16,
+                       self.interface as u16,
+                       &[])
+    }
+
+    /// Reads the idle rate of an input report, or of all input reports if `report_id` is 0.
+    ///
+    /// The future resolves to a zero duration if the device only reports when the data
+    /// changes.
+    pub fn get_idle(&self, report_id: u8) -> ControlFuture<Duration> {
+        control::read(&self.handle,
+                      request_type(Direction::In, RequestType::Class, Recipient::Interface),
+                      GET_IDLE,
+                      report_id as u16,
+                      self.interface as u16,
+                      1,
+                      decode_idle)
+    }
+
+    /// Selects the boot or report protocol.
+    ///
+    /// Only interfaces with the boot sub class support this. Hosts that decode boot reports,
+    /// e.g., with [`keyboard_events`](#method.keyboard_events), should select the boot
+    /// protocol, since a device may otherwise send reports of another layout.
+    pub fn set_protocol(&self, protocol: Protocol) -> ControlFuture<usize> {
+        control::write(&self.handle,
+                       request_type(Direction::Out, RequestType::Class, Recipient::Interface),
+                       SET_PROTOCOL,
+                       match protocol { Protocol::Boot => 0, Protocol::Report => 1 },
+                       self.interface as u16,
+                       &[])
+    }
+
+    /// Reads the protocol that is selected.
+    pub fn get_protocol(&self) -> ControlFuture<Protocol> {
+        control::read(&self.handle,
+                      request_type(Direction::In, RequestType::Class, Recipient::Interface),
+                      GET_PROTOCOL,
+                      0,
+                      self.interface as u16,
+                      1,
+                      decode_protocol)
+    }
+
+    fn set_report(&self, report_type: u16, report_id: u8, bytes: &[u8]) -> ControlFuture<usize> {
+        control::write(&self.handle,
+                       request_type(Direction::Out, RequestType::Class, Recipient::Interface),
+                       SET_REPORT,
+                       report_type << 8 | report_id as u16,
+                       self.interface as u16,
+                       bytes)
+    }
+
+    fn submit_input(&self) -> ::Result<TransferFuture> {
+        self.resubmit_input(self.handle.alloc_transfer(0)?)
+    }
+
+    fn resubmit_input(&self, mut transfer: Transfer) -> ::Result<TransferFuture> {
+        transfer.fill_interrupt_read(self.input_endpoint.into(), self.input_packet_size)?;
+        Ok(transfer.submit())
+    }
+}
+
+impl Drop for HidDevice {
+    fn drop(&mut self) {
+        if self.kernel_driver_detached {
+            let _ = self.handle.release_interface(self.interface);
+            let _ = self.handle.attach_kernel_driver(self.interface);
+        }
+    }
+}
+
+/// Stream of input reports returned by
+/// [`HidDevice::input_reports`](struct.HidDevice.html#method.input_reports).
+pub struct InputReports<'a> {
+    device: &'a HidDevice,
+    reads: ReadQueue<TransferFuture>,
+    // Completed transfers kept for the next reads in low latency mode
+    spare: Option<Vec<Transfer>>,
+    done: bool,
+}
+
+impl<'a> InputReports<'a> {
+    /// Reuses the transfers of the stream and their buffers for all reports, instead of
+    /// allocating a transfer for each.
+    ///
+    /// A report then goes from the completion of its read to the consumer without an allocation
+    /// or a lock on the way, except for the copy of its data. The read is resubmitted before the
+    /// report is returned, as in the default mode, so the endpoint stays polled. The time saved
+    /// per report depends on the allocator and the platform, and matters most for devices that
+    /// report every millisecond or faster.
+    pub fn low_latency(mut self) -> InputReports<'a> {
+        self.spare = Some(Vec::new());
+        self
+    }
+
+    /// Sets the number of reads kept in flight, by default from
+    /// [`QueueDepth::for_endpoint`](../struct.QueueDepth.html#method.for_endpoint).
+    pub fn queue_depth(mut self, transfers: usize) -> InputReports<'a> {
+        self.reads.set_depth(transfers);
+        self
+    }
+
+    /// Sets what happens to reports the consumer is too slow to take, blocking by default.
+    pub fn backpressure(mut self, backpressure: Backpressure) -> InputReports<'a> {
+        self.reads.set_backpressure(backpressure);
+        self
+    }
+
+    /// Returns the number of reports dropped because the consumer was too slow.
+    pub fn dropped(&self) -> u64 {
+        self.reads.dropped()
+    }
+}
+
+impl<'a> Stream for InputReports<'a> {
+    type Item = ::Result<Report>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.done {
+            return task::Poll::Ready(None);
+        }
+
+        let device = this.device;
+        let spare = &mut this.spare;
+        let result = match this.reads.poll_next(cx, || match spare.as_mut().and_then(Vec::pop) {
+            Some(transfer) => device.resubmit_input(transfer),
+            None => device.submit_input(),
+        }) {
+            task::Poll::Pending => return task::Poll::Pending,
+            task::Poll::Ready(result) => result,
+        };
+
+        let uses_report_ids = device.report_descriptor.uses_report_ids();
+        let report = result.and_then(|transfer| {
+            transfer.check_status()?;
+            let report = Report::from_bytes(transfer.get_buffer(), uses_report_ids);
+            if let Some(ref mut spare) = *spare {
+                spare.push(transfer.into_transfer());
+            }
+            Ok(report)
+        });
+
+        if report.is_err() {
+            this.done = true;
+            this.reads.clear();
+        }
+
+        task::Poll::Ready(Some(report))
+    }
+}
+
+/// Future that resolves to the result of writing an output report.
+pub struct ReportWriteFuture {
+    state: WriteState,
+}
+
+enum WriteState {
+    Failed(Error),
+    Interrupt(TransferFuture),
+    Control(ControlFuture<usize>),
+    Done,
+}
+
+impl Future for ReportWriteFuture {
+    type Output = ::Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Self::Output> {
+        let this = self.get_mut();
+
+        let result = match this.state {
+            WriteState::Failed(ref e) => Err(e.clone()),
+            WriteState::Interrupt(ref mut future) => match Pin::new(future).poll(cx) {
+                task::Poll::Pending => return task::Poll::Pending,
+                task::Poll::Ready(result) => result.and_then(|transfer| {
+                    transfer.check_status()?;
+                    Ok(transfer.get_buffer().len())
+                }),
+            },
+            WriteState::Control(ref mut future) => match Pin::new(future).poll(cx) {
+                task::Poll::Pending => return task::Poll::Pending,
+                task::Poll::Ready(result) => result,
+            },
+            WriteState::Done => return task::Poll::Ready(Err(Error::PolledAfterCompletion)),
+        };
+
+        this.state = WriteState::Done;
+        task::Poll::Ready(result)
+    }
+}
+
+/// Returns the report descriptor length from the HID descriptor in an interface's extra
+/// descriptors.
+fn report_descriptor_length(extra: &[u8]) -> Option<u16> {
+    ::extra_descriptors::ExtraDescriptors::new(extra)
+        .find(|&(descriptor_type, _)| descriptor_type == DT_HID)
+        .and_then(|(_, payload)| {
+            // bcdHID, bCountryCode and bNumDescriptors precede the class descriptor list
+            payload.get(4..)?.chunks_exact(3)
+                .find(|entry| entry[0] == DT_REPORT)
+                .map(|entry| entry[1] as u16 | (entry[2] as u16) << 8)
+        })
+}
+
+fn with_report_id(report_id: u8, data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len() + 1);
+
+    if report_id != 0 {
+        bytes.push(report_id);
+    }
+
+    bytes.extend_from_slice(data);
+    bytes
+}
+
+fn strip_report_id(data: &[u8]) -> ::Result<Vec<u8>> {
+    Ok(data.get(1..).unwrap_or(&[]).to_vec())
+}
+
+fn idle_rate(duration: Duration) -> u8 {
+    let rate = (duration.as_millis() / 4).min(255) as u8;
+
+    if rate == 0 && duration > Duration::from_millis(0) { 1 } else { rate }
+}
+
+fn decode_idle(data: &[u8]) -> ::Result<Duration> {
+    data.first().map(|&rate| Duration::from_millis(rate as u64 * 4)).ok_or(Error::InvalidParam)
+}
+
+fn decode_protocol(data: &[u8]) -> ::Result<Protocol> {
+    match data.first() {
+        Some(&0) => Ok(Protocol::Boot),
+        Some(&1) => Ok(Protocol::Report),
+        _ => Err(Error::InvalidParam),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_finds_report_descriptor_length_in_hid_descriptor() {
+        let extra = [0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 0x3F, 0x01];
+        assert_eq!(Some(0x013F), report_descriptor_length(&extra));
+    }
+
+    #[test]
+    fn it_skips_other_class_descriptors() {
+        let extra = [0x0C, 0x21, 0x11, 0x01, 0x00, 0x02, 0x23, 0x10, 0x00, 0x22, 0x40, 0x00];
+        assert_eq!(Some(0x40), report_descriptor_length(&extra));
+        assert_eq!(None, report_descriptor_length(&[0x07, 0x25, 0x01, 0x00, 0x00, 0x00, 0x00]));
+    }
+
+    #[test]
+    fn it_splits_report_id() {
+        assert_eq!(Report { id: 2, data: vec![0x10, 0x20] }, Report::from_bytes(&[0x02, 0x10, 0x20], true));
+        assert_eq!(Report { id: 0, data: vec![0x02, 0x10] }, Report::from_bytes(&[0x02, 0x10], false));
+    }
+
+    #[test]
+    fn it_prefixes_report_id() {
+        assert_eq!(vec![0x05, 0xAA], with_report_id(5, &[0xAA]));
+        assert_eq!(vec![0xAA], with_report_id(0, &[0xAA]));
+    }
+
+    #[test]
+    fn it_converts_idle_rate_to_4_ms_units() {
+        assert_eq!(0, idle_rate(Duration::from_millis(0)));
+        assert_eq!(1, idle_rate(Duration::from_millis(2)));
+        assert_eq!(125, idle_rate(Duration::from_millis(500)));
+        assert_eq!(255, idle_rate(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn it_decodes_idle_rate_and_protocol() {
+        assert_eq!(Duration::from_millis(500), decode_idle(&[125]).unwrap());
+        assert!(decode_idle(&[]).is_err());
+        assert_eq!(Protocol::Boot, decode_protocol(&[0]).unwrap());
+        assert_eq!(Protocol::Report, decode_protocol(&[1]).unwrap());
+        assert!(decode_protocol(&[2]).is_err());
+    }
+}
@@ -0,0 +1,11 @@
+//! Human Interface Device (HID) class support.
+
+pub use self::boot::{KeyboardReport, KeyboardState, KeyEvent, MouseReport, KeyboardEvents, MouseReports};
+pub use self::device::{HidDevice, Protocol, Report, InputReports, ReportWriteFuture};
+pub use self::report_descriptor::{ReportDescriptor, ReportField, ReportKind, parse_report_descriptor};
+pub use self::usage::{Usage, usage_name, usage_page_name};
+
+mod boot;
+mod device;
+mod report_descriptor;
+mod usage;
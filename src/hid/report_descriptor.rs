@@ -0,0 +1,428 @@
+use std::collections::HashMap;
+
+use error::Error;
+
+use super::usage::Usage;
+
+const MAX_USAGE_RANGE: u32 = 0x1_0000;
+
+/// The kind of report a field belongs to.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum ReportKind {
+    /// Input report, sent from the device to the host.
+    Input,
+
+    /// Output report, sent from the host to the device.
+    Output,
+
+    /// Feature report, read or written with control transfers.
+    Feature,
+}
+
+/// A field of a report, as defined by one Input, Output or Feature main item.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub struct ReportField {
+    /// The kind of report the field belongs to.
+    pub kind: ReportKind,
+
+    /// The report ID, or `None` if the device doesn't use report IDs.
+    pub report_id: Option<u8>,
+
+    /// The data of the main item, e.g., `0x02` for data, variable, absolute.
+    pub flags: u32,
+
+    /// Position of the field in bits from the start of the report, excluding the report ID.
+    pub bit_offset: u32,
+
+    /// Size of each element in bits.
+    pub size: u32,
+
+    /// Number of elements.
+    pub count: u32,
+
+    /// The usages of the elements for variable fields, or the usages that the element values
+    /// select for array fields.
+    pub usages: Vec<Usage>,
+
+    /// The smallest value an element can have.
+    pub logical_minimum: i32,
+
+    /// The largest value an element can have.
+    pub logical_maximum: i32,
+
+    /// The usage of the innermost application collection containing the field.
+    pub application: Option<Usage>,
+}
+
+impl ReportField {
+    /// Indicates if the field is padding or otherwise constant.
+    pub fn is_constant(&self) -> bool {
+        self.flags & 0x01 != 0
+    }
+
+    /// Indicates if each element is a value of its own usage, as opposed to an array of
+    /// usage selectors.
+    pub fn is_variable(&self) -> bool {
+        self.flags & 0x02 != 0
+    }
+
+    /// Indicates if the values are changes relative to the previous report.
+    pub fn is_relative(&self) -> bool {
+        self.flags & 0x04 != 0
+    }
+
+    fn element(&self, data: &[u8], index: u32) -> Option<i32> {
+        let start = self.bit_offset + index * self.size;
+
+        if self.size == 0 || self.size > 32 || (start + self.size) as usize > data.len() * 8 {
+            return None;
+        }
+
+        let mut value = 0u32;
+
+        for bit in 0..self.size {
+            let position = (start + bit) as usize;
+
+            if data[position / 8] & (1 << (position % 8)) != 0 {
+                value |= 1 << bit;
+            }
+        }
+
+        if self.logical_minimum < 0 && self.size < 32 && value & (1 << (self.size - 1)) != 0 {
+            value |= !0 << self.size;
+        }
+
+        Some(value as i32)
+    }
+}
+
+/// A parsed HID report descriptor.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct ReportDescriptor {
+    fields: Vec<ReportField>,
+}
+
+impl ReportDescriptor {
+    /// Returns the report fields in the order they are defined.
+    pub fn fields(&self) -> &[ReportField] {
+        &self.fields
+    }
+
+    /// Indicates if the reports start with a report ID byte.
+    pub fn uses_report_ids(&self) -> bool {
+        self.fields.iter().any(|field| field.report_id.is_some())
+    }
+
+    /// Returns the report IDs used for reports of the given kind.
+    pub fn report_ids(&self, kind: ReportKind) -> Vec<u8> {
+        let mut ids = self.fields.iter()
+            .filter(|field| field.kind == kind)
+            .filter_map(|field| field.report_id)
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+
+    /// Returns the length in bytes of a report, excluding the report ID.
+    pub fn report_length(&self, kind: ReportKind, report_id: Option<u8>) -> usize {
+        let bits = self.fields.iter()
+            .filter(|field| field.kind == kind && field.report_id == report_id)
+            .map(|field| field.bit_offset + field.size * field.count)
+            .max()
+            .unwrap_or(0);
+
+        bits.div_ceil(8) as usize
+    }
+
+    /// Decodes a report into `(usage, value)` pairs.
+    ///
+    /// `report` must start with the report ID if the device uses report IDs. Variable fields
+    /// yield one pair per element. Array fields yield the selected usages with a value of 1;
+    /// elements selecting usage ID 0 mean "no event" and are skipped. Constant fields are
+    /// skipped.
+    ///
+    /// Returns `Error::InvalidParam` if the report ID is unknown or the report is too short.
+    pub fn decode(&self, kind: ReportKind, report: &[u8]) -> ::Result<Vec<(Usage, i32)>> {
+        let (report_id, data) = if self.uses_report_ids() {
+            match report.split_first() {
+                Some((&id, data)) => (Some(id), data),
+                None => return Err(Error::InvalidParam),
+            }
+        }
+        else {
+            (None, report)
+        };
+
+        let mut fields = self.fields.iter().filter(|field| field.kind == kind && field.report_id == report_id).peekable();
+
+        if fields.peek().is_none() || data.len() < self.report_length(kind, report_id) {
+            return Err(Error::InvalidParam);
+        }
+
+        let mut values = Vec::new();
+
+        for field in fields.filter(|field| !field.is_constant() && !field.usages.is_empty()) {
+            for index in 0..field.count {
+                let value = match field.element(data, index) {
+                    Some(value) => value,
+                    None => continue,
+                };
+
+                if field.is_variable() {
+                    let usage = field.usages[(index as usize).min(field.usages.len() - 1)];
+                    values.push((usage, value));
+                }
+                else if value >= field.logical_minimum && value <= field.logical_maximum {
+                    match field.usages.get((value - field.logical_minimum) as usize) {
+                        Some(&usage) if usage.id != 0 => values.push((usage, 1)),
+                        _ => {},
+                    }
+                }
+            }
+        }
+
+        Ok(values)
+    }
+}
+
+#[derive(Clone,Copy,Default)]
+struct Globals {
+    usage_page: u16,
+    logical_minimum: i32,
+    logical_maximum: i32,
+    report_size: u32,
+    report_count: u32,
+    report_id: Option<u8>,
+}
+
+#[derive(Default)]
+struct Locals {
+    usages: Vec<Usage>,
+    usage_minimum: Option<u32>,
+}
+
+/// Parses a HID report descriptor.
+///
+/// Returns `Error::InvalidParam` if an item is truncated, collections are unbalanced or a Pop
+/// item has no matching Push.
+pub fn parse_report_descriptor(bytes: &[u8]) -> ::Result<ReportDescriptor> {
+    let mut globals = Globals::default();
+    let mut stack = Vec::new();
+    let mut locals = Locals::default();
+    let mut collections: Vec<Option<Usage>> = Vec::new();
+    let mut offsets = HashMap::new();
+    let mut fields = Vec::new();
+
+    let mut position = 0;
+
+    while position < bytes.len() {
+        let prefix = bytes[position];
+
+        if prefix == 0xFE {
+            // Long item: bDataSize, bLongItemTag, data
+            let size = *bytes.get(position + 1).ok_or(Error::InvalidParam)? as usize;
+            position += 3 + size;
+            continue;
+        }
+
+        let size = match prefix & 0x03 {
+            3 => 4,
+            n => n as usize,
+        };
+
+        let data = bytes.get(position + 1..position + 1 + size).ok_or(Error::InvalidParam)?;
+        position += 1 + size;
+
+        let unsigned = data.iter().rev().fold(0u32, |value, &byte| value << 8 | byte as u32);
+        let signed = match size {
+            1 => data[0] as i8 as i32,
+            2 => (data[0] as u16 | (data[1] as u16) << 8) as i16 as i32,
+            _ => unsigned as i32,
+        };
+
+        let usage = |value: u32| {
+            if size == 4 {
+                Usage::new((value >> 16) as u16, value as u16)
+            }
+            else {
+                Usage::new(globals.usage_page, value as u16)
+            }
+        };
+
+        match (prefix >> 2) & 0x03 {
+            // Main items
+            0 => {
+                let tag = prefix >> 4;
+
+                let kind = match tag {
+                    0x8 => Some(ReportKind::Input),
+                    0x9 => Some(ReportKind::Output),
+                    0xB => Some(ReportKind::Feature),
+                    0xA => {
+                        // Collection; 0x01 is an application collection
+                        let application = if unsigned == 0x01 {
+                            locals.usages.first().cloned()
+                        }
+                        else {
+                            collections.last().cloned().and_then(|application| application)
+                        };
+                        collections.push(application);
+                        None
+                    },
+                    0xC => {
+                        collections.pop().ok_or(Error::InvalidParam)?;
+                        None
+                    },
+                    _ => None,
+                };
+
+                if let Some(kind) = kind {
+                    let offset = offsets.entry((kind, globals.report_id)).or_insert(0);
+
+                    fields.push(ReportField {
+                        kind,
+                        report_id: globals.report_id,
+                        flags: unsigned,
+                        bit_offset: *offset,
+                        size: globals.report_size,
+                        count: globals.report_count,
+                        usages: locals.usages.clone(),
+                        logical_minimum: globals.logical_minimum,
+                        logical_maximum: globals.logical_maximum,
+                        application: collections.last().cloned().and_then(|application| application),
+                    });
+
+                    *offset += globals.report_size * globals.report_count;
+                }
+
+                locals = Locals::default();
+            },
+            // Global items
+            1 => {
+                match prefix >> 4 {
+                    0x0 => globals.usage_page = unsigned as u16,
+                    0x1 => globals.logical_minimum = signed,
+                    0x2 => {
+                        // Logical maximum is unsigned unless the minimum is negative
+                        globals.logical_maximum = if globals.logical_minimum < 0 { signed } else { unsigned as i32 };
+                    },
+                    0x7 => globals.report_size = unsigned,
+                    0x8 => globals.report_id = Some(unsigned as u8),
+                    0x9 => globals.report_count = unsigned,
+                    0xA => stack.push(globals),
+                    0xB => globals = stack.pop().ok_or(Error::InvalidParam)?,
+                    _ => {},
+                }
+            },
+            // Local items
+            2 => {
+                match prefix >> 4 {
+                    0x0 => locals.usages.push(usage(unsigned)),
+                    0x1 => locals.usage_minimum = Some(unsigned),
+                    0x2 => {
+                        let minimum = locals.usage_minimum.take().ok_or(Error::InvalidParam)?;
+
+                        if unsigned < minimum || unsigned - minimum >= MAX_USAGE_RANGE {
+                            return Err(Error::InvalidParam);
+                        }
+
+                        for value in minimum..=unsigned {
+                            locals.usages.push(usage(value));
+                        }
+                    },
+                    _ => {},
+                }
+            },
+            _ => {},
+        }
+    }
+
+    if !collections.is_empty() {
+        return Err(Error::InvalidParam);
+    }
+
+    Ok(ReportDescriptor { fields })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Boot protocol compatible mouse with a wheel
+    const MOUSE: [u8; 52] = [
+        0x05, 0x01, 0x09, 0x02, 0xA1, 0x01, 0x09, 0x01, 0xA1, 0x00,
+        0x05, 0x09, 0x19, 0x01, 0x29, 0x03, 0x15, 0x00, 0x25, 0x01,
+        0x95, 0x03, 0x75, 0x01, 0x81, 0x02, 0x95, 0x01, 0x75, 0x05,
+        0x81, 0x01, 0x05, 0x01, 0x09, 0x30, 0x09, 0x31, 0x09, 0x38,
+        0x15, 0x81, 0x25, 0x7F, 0x75, 0x08, 0x95, 0x03, 0x81, 0x06,
+        0xC0, 0xC0,
+    ];
+
+    // Keyboard with a report ID and a 6-key array
+    const KEYBOARD: [u8; 45] = [
+        0x05, 0x01, 0x09, 0x06, 0xA1, 0x01, 0x85, 0x01, 0x05, 0x07,
+        0x19, 0xE0, 0x29, 0xE7, 0x15, 0x00, 0x25, 0x01, 0x75, 0x01,
+        0x95, 0x08, 0x81, 0x02, 0x95, 0x01, 0x75, 0x08, 0x81, 0x01,
+        0x95, 0x06, 0x75, 0x08, 0x15, 0x00, 0x25, 0x65, 0x19, 0x00,
+        0x29, 0x65, 0x81, 0x00, 0xC0,
+    ];
+
+    #[test]
+    fn it_parses_field_layout() {
+        let descriptor = parse_report_descriptor(&MOUSE).unwrap();
+        let fields = descriptor.fields();
+
+        assert_eq!(3, fields.len());
+        assert_eq!((0, 1, 3), (fields[0].bit_offset, fields[0].size, fields[0].count));
+        assert!(fields[1].is_constant());
+        assert_eq!((8, 8, 3), (fields[2].bit_offset, fields[2].size, fields[2].count));
+        assert!(fields[2].is_relative());
+        assert_eq!(-127, fields[2].logical_minimum);
+        assert_eq!(Some(Usage::new(0x01, 0x02)), fields[2].application);
+        assert_eq!(4, descriptor.report_length(ReportKind::Input, None));
+    }
+
+    #[test]
+    fn it_decodes_variable_fields() {
+        let descriptor = parse_report_descriptor(&MOUSE).unwrap();
+        let values = descriptor.decode(ReportKind::Input, &[0x05, 0x10, 0xFE, 0x01]).unwrap();
+
+        assert_eq!(vec![
+            (Usage::new(0x09, 1), 1), (Usage::new(0x09, 2), 0), (Usage::new(0x09, 3), 1),
+            (Usage::new(0x01, 0x30), 16), (Usage::new(0x01, 0x31), -2), (Usage::new(0x01, 0x38), 1),
+        ], values);
+    }
+
+    #[test]
+    fn it_decodes_array_fields_with_report_id() {
+        let descriptor = parse_report_descriptor(&KEYBOARD).unwrap();
+
+        assert_eq!(vec![1], descriptor.report_ids(ReportKind::Input));
+
+        let values = descriptor.decode(ReportKind::Input, &[0x01, 0x02, 0x00, 0x04, 0x05, 0x00, 0x00, 0x00, 0x00]).unwrap();
+        let pressed = values.into_iter().filter(|&(_, value)| value != 0).map(|(usage, _)| usage).collect::<Vec<_>>();
+
+        // Left shift, A, B
+        assert_eq!(vec![Usage::new(0x07, 0xE1), Usage::new(0x07, 0x04), Usage::new(0x07, 0x05)], pressed);
+    }
+
+    #[test]
+    fn it_rejects_unknown_report_id() {
+        let descriptor = parse_report_descriptor(&KEYBOARD).unwrap();
+
+        assert!(descriptor.decode(ReportKind::Input, &[0x02, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn it_rejects_truncated_item() {
+        assert!(parse_report_descriptor(&[0x05, 0x01, 0x26, 0xFF]).is_err());
+    }
+
+    #[test]
+    fn it_rejects_unbalanced_collections() {
+        assert!(parse_report_descriptor(&[0xA1, 0x01]).is_err());
+        assert!(parse_report_descriptor(&[0xC0]).is_err());
+    }
+}
@@ -0,0 +1,252 @@
+use std::fmt;
+
+/// A HID usage, i.e., a usage page and a usage ID within that page.
+#[derive(Debug,PartialEq,Eq,PartialOrd,Ord,Clone,Copy,Hash)]
+pub struct Usage {
+    /// The usage page.
+    pub page: u16,
+
+    /// The usage ID.
+    pub id: u16,
+}
+
+impl Usage {
+    /// Creates a usage from a page and an ID.
+    pub fn new(page: u16, id: u16) -> Usage {
+        Usage { page, id }
+    }
+
+    /// Returns the name of the usage page, if it is a known one.
+    pub fn page_name(&self) -> Option<&'static str> {
+        usage_page_name(self.page)
+    }
+
+    /// Returns the name of the usage, if it is a known one.
+    pub fn name(&self) -> Option<String> {
+        usage_name(self.page, self.id)
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.page_name() {
+            Some(page) => write!(fmt, "{} / ", page)?,
+            None => write!(fmt, "Page {:#06x} / ", self.page)?,
+        }
+
+        match self.name() {
+            Some(name) => fmt.write_str(&name),
+            None => write!(fmt, "{:#06x}", self.id),
+        }
+    }
+}
+
+/// Returns the name of a usage page.
+pub fn usage_page_name(page: u16) -> Option<&'static str> {
+    let name = match page {
+        0x01 => "Generic Desktop",
+        0x02 => "Simulation Controls",
+        0x03 => "VR Controls",
+        0x04 => "Sport Controls",
+        0x05 => "Game Controls",
+        0x06 => "Generic Device Controls",
+        0x07 => "Keyboard/Keypad",
+        0x08 => "LED",
+        0x09 => "Button",
+        0x0A => "Ordinal",
+        0x0B => "Telephony Device",
+        0x0C => "Consumer",
+        0x0D => "Digitizers",
+        0x0F => "Physical Input Device",
+        0x10 => "Unicode",
+        0x14 => "Auxiliary Display",
+        0x40 => "Medical Instrument",
+        0x59 => "Lighting and Illumination",
+        0x80..=0x83 => "Monitor",
+        0x84 => "Power",
+        0x85 => "Battery System",
+        0xF1D0 => "FIDO Alliance",
+        0xFF00..=0xFFFF => "Vendor-defined",
+        _ => return None,
+    };
+
+    Some(name)
+}
+
+/// Returns the name of a usage.
+///
+/// Names are provided for the Generic Desktop, Keyboard/Keypad, LED, Button, Ordinal and
+/// Consumer pages.
+pub fn usage_name(page: u16, id: u16) -> Option<String> {
+    match page {
+        0x01 => generic_desktop_name(id).map(str::to_owned),
+        0x07 => keyboard_name(id).map(str::to_owned),
+        0x08 => led_name(id).map(str::to_owned),
+        0x09 if id > 0 => Some(format!("Button {}", id)),
+        0x0A if id > 0 => Some(format!("Instance {}", id)),
+        0x0C => consumer_name(id).map(str::to_owned),
+        _ => None,
+    }
+}
+
+fn generic_desktop_name(id: u16) -> Option<&'static str> {
+    let name = match id {
+        0x01 => "Pointer",
+        0x02 => "Mouse",
+        0x04 => "Joystick",
+        0x05 => "Gamepad",
+        0x06 => "Keyboard",
+        0x07 => "Keypad",
+        0x08 => "Multi-axis Controller",
+        0x30 => "X",
+        0x31 => "Y",
+        0x32 => "Z",
+        0x33 => "Rx",
+        0x34 => "Ry",
+        0x35 => "Rz",
+        0x36 => "Slider",
+        0x37 => "Dial",
+        0x38 => "Wheel",
+        0x39 => "Hat Switch",
+        0x3D => "Start",
+        0x3E => "Select",
+        0x40 => "Vx",
+        0x41 => "Vy",
+        0x42 => "Vz",
+        0x80 => "System Control",
+        0x81 => "System Power Down",
+        0x82 => "System Sleep",
+        0x83 => "System Wake Up",
+        0x90 => "D-pad Up",
+        0x91 => "D-pad Down",
+        0x92 => "D-pad Right",
+        0x93 => "D-pad Left",
+        _ => return None,
+    };
+
+    Some(name)
+}
+
+/// Names of the Keyboard/Keypad page usages 0x00 to 0x81.
+const KEYBOARD_NAMES: [&str; 0x82] = [
+    "No Event", "Error Roll Over", "POST Fail", "Error Undefined",
+    "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M",
+    "N", "O", "P", "Q", "R", "S", "T", "U", "V", "W", "X", "Y", "Z",
+    "1", "2", "3", "4", "5", "6", "7", "8", "9", "0",
+    "Enter", "Escape", "Backspace", "Tab", "Space", "-", "=", "[", "]", "\\",
+    "Non-US #", ";", "'", "`", ",", ".", "/", "Caps Lock",
+    "F1", "F2", "F3", "F4", "F5", "F6", "F7", "F8", "F9", "F10", "F11", "F12",
+    "Print Screen", "Scroll Lock", "Pause", "Insert", "Home", "Page Up",
+    "Delete", "End", "Page Down", "Right Arrow", "Left Arrow", "Down Arrow", "Up Arrow",
+    "Num Lock", "Keypad /", "Keypad *", "Keypad -", "Keypad +", "Keypad Enter",
+    "Keypad 1", "Keypad 2", "Keypad 3", "Keypad 4", "Keypad 5",
+    "Keypad 6", "Keypad 7", "Keypad 8", "Keypad 9", "Keypad 0", "Keypad .",
+    "Non-US \\", "Application", "Power", "Keypad =",
+    "F13", "F14", "F15", "F16", "F17", "F18", "F19", "F20", "F21", "F22", "F23", "F24",
+    "Execute", "Help", "Menu", "Select", "Stop", "Again", "Undo", "Cut", "Copy", "Paste",
+    "Find", "Mute", "Volume Up", "Volume Down",
+];
+
+/// Names of the Keyboard/Keypad page modifier usages 0xE0 to 0xE7.
+const MODIFIER_NAMES: [&str; 8] = [
+    "Left Control", "Left Shift", "Left Alt", "Left GUI",
+    "Right Control", "Right Shift", "Right Alt", "Right GUI",
+];
+
+fn keyboard_name(id: u16) -> Option<&'static str> {
+    match id {
+        0x00..=0x81 => Some(KEYBOARD_NAMES[id as usize]),
+        0xE0..=0xE7 => Some(MODIFIER_NAMES[(id - 0xE0) as usize]),
+        _ => None,
+    }
+}
+
+fn led_name(id: u16) -> Option<&'static str> {
+    let name = match id {
+        0x01 => "Num Lock",
+        0x02 => "Caps Lock",
+        0x03 => "Scroll Lock",
+        0x04 => "Compose",
+        0x05 => "Kana",
+        0x06 => "Power",
+        0x07 => "Shift",
+        0x09 => "Mute",
+        _ => return None,
+    };
+
+    Some(name)
+}
+
+fn consumer_name(id: u16) -> Option<&'static str> {
+    let name = match id {
+        0x01 => "Consumer Control",
+        0x30 => "Power",
+        0x40 => "Menu",
+        0x6F => "Display Brightness Increment",
+        0x70 => "Display Brightness Decrement",
+        0xB0 => "Play",
+        0xB1 => "Pause",
+        0xB2 => "Record",
+        0xB3 => "Fast Forward",
+        0xB4 => "Rewind",
+        0xB5 => "Scan Next Track",
+        0xB6 => "Scan Previous Track",
+        0xB7 => "Stop",
+        0xB8 => "Eject",
+        0xCD => "Play/Pause",
+        0xE0 => "Volume",
+        0xE2 => "Mute",
+        0xE9 => "Volume Increment",
+        0xEA => "Volume Decrement",
+        0x183 => "AL Consumer Control Configuration",
+        0x18A => "AL Email Reader",
+        0x192 => "AL Calculator",
+        0x194 => "AL Local Machine Browser",
+        0x221 => "AC Search",
+        0x223 => "AC Home",
+        0x224 => "AC Back",
+        0x225 => "AC Forward",
+        0x226 => "AC Stop",
+        0x227 => "AC Refresh",
+        0x22A => "AC Bookmarks",
+        0x238 => "AC Pan",
+        _ => return None,
+    };
+
+    Some(name)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_names_keyboard_usages() {
+        assert_eq!(Some("A".to_owned()), usage_name(0x07, 0x04));
+        assert_eq!(Some("0".to_owned()), usage_name(0x07, 0x27));
+        assert_eq!(Some("F12".to_owned()), usage_name(0x07, 0x45));
+        assert_eq!(Some("Keypad =".to_owned()), usage_name(0x07, 0x67));
+        assert_eq!(Some("Volume Down".to_owned()), usage_name(0x07, 0x81));
+        assert_eq!(Some("Right GUI".to_owned()), usage_name(0x07, 0xE7));
+        assert_eq!(None, usage_name(0x07, 0xA5));
+    }
+
+    #[test]
+    fn it_names_buttons() {
+        assert_eq!(Some("Button 3".to_owned()), usage_name(0x09, 3));
+        assert_eq!(None, usage_name(0x09, 0));
+    }
+
+    #[test]
+    fn it_displays_known_usage() {
+        assert_eq!("Generic Desktop / Wheel", Usage::new(0x01, 0x38).to_string());
+        assert_eq!("Consumer / Play/Pause", Usage::new(0x0C, 0xCD).to_string());
+    }
+
+    #[test]
+    fn it_displays_unknown_usage() {
+        assert_eq!("Vendor-defined / 0x0001", Usage::new(0xFF00, 0x01).to_string());
+        assert_eq!("Page 0x1234 / 0x0005", Usage::new(0x1234, 0x05).to_string());
+    }
+}
@@ -0,0 +1,169 @@
+use error::{self, Error};
+
+/// Adds what the platform tells about a device that couldn't be opened to the error.
+pub fn open_failed(error: Error, bus_number: u8, address: u8) -> Error {
+    let hint = match *error.libusb_error() {
+        Error::Access | Error::Busy | Error::NotSupported => platform::open_hint(error.libusb_error(), bus_number, address),
+        _ => None,
+    };
+    error::with_hint(error, hint)
+}
+
+/// Adds what the platform tells about an interface that couldn't be claimed to the error.
+pub fn claim_failed(error: Error, bus_number: u8, address: u8, interface: u8) -> Error {
+    let hint = match *error.libusb_error() {
+        Error::Access | Error::Busy | Error::NotSupported => {
+            platform::claim_hint(error.libusb_error(), bus_number, address, interface)
+        },
+        _ => None,
+    };
+    error::with_hint(error, hint)
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+
+    use error::Error;
+
+    const SYSFS_DEVICES: &str = "/sys/bus/usb/devices";
+
+    pub fn open_hint(error: &Error, bus_number: u8, address: u8) -> Option<String> {
+        match *error {
+            Error::Access => {
+                let node = format!("/dev/bus/usb/{:03}/{:03}", bus_number, address);
+                let metadata = fs::metadata(&node).ok()?;
+                Some(format!("{} has mode {:o} and owner {}:{}, which don't let this user open it; \
+                              grant access with a udev rule, e.g., with MODE=\"0660\" and GROUP=\"plugdev\"",
+                             node, metadata.mode() & 0o777, metadata.uid(), metadata.gid()))
+            },
+            _ => None,
+        }
+    }
+
+    pub fn claim_hint(error: &Error, bus_number: u8, address: u8, interface: u8) -> Option<String> {
+        let driver = interface_driver(Path::new(SYSFS_DEVICES), bus_number, address, interface);
+        describe_claim(error, interface, driver.as_deref())
+    }
+
+    fn describe_claim(error: &Error, interface: u8, driver: Option<&str>) -> Option<String> {
+        match (error, driver) {
+            (&Error::Busy, Some("usbfs")) => {
+                Some(format!("interface {} is claimed by another program, or another handle, through usbfs", interface))
+            },
+            (&Error::Busy, Some(driver)) => {
+                Some(format!("interface {} is bound to the kernel driver {}; detach it first, e.g., by claiming \
+                              the interface with DeviceHandle::claim", interface, driver))
+            },
+            _ => None,
+        }
+    }
+
+    /// Finds the name of the driver bound to an interface of a device in sysfs.
+    fn interface_driver(root: &Path, bus_number: u8, address: u8, interface: u8) -> Option<String> {
+        let device = fs::read_dir(root).ok()?.flatten().map(|entry| entry.path()).find(|path| {
+            read_number(&path.join("busnum"), 10) == Some(bus_number as u32) &&
+                read_number(&path.join("devnum"), 10) == Some(address as u32)
+        })?;
+
+        // The interfaces are named after the device, e.g., 1-1.2:1.0
+        let prefix = format!("{}:", device.file_name()?.to_str()?);
+        let driver = fs::read_dir(&device).ok()?.flatten()
+            .filter(|entry| entry.file_name().to_str().is_some_and(|name| name.starts_with(&prefix)))
+            .find(|entry| read_number(&entry.path().join("bInterfaceNumber"), 16) == Some(interface as u32))
+            .and_then(|entry| fs::read_link(entry.path().join("driver")).ok())?;
+        driver.file_name()?.to_str().map(str::to_owned)
+    }
+
+    fn read_number(path: &Path, radix: u32) -> Option<u32> {
+        u32::from_str_radix(fs::read_to_string(path).ok()?.trim(), radix).ok()
+    }
+
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use std::env;
+        use std::os::unix::fs::symlink;
+        use std::process;
+
+        #[test]
+        fn it_finds_the_driver_of_an_interface_in_sysfs() {
+            let root = env::temp_dir().join(format!("libusb-async-sysfs-{}", process::id()));
+            let interface = root.join("1-1.2").join("1-1.2:1.1");
+            fs::create_dir_all(&interface).unwrap();
+            fs::write(root.join("1-1.2").join("busnum"), "1\n").unwrap();
+            fs::write(root.join("1-1.2").join("devnum"), "12\n").unwrap();
+            fs::write(interface.join("bInterfaceNumber"), "01\n").unwrap();
+            symlink("../../../../bus/usb/drivers/usbhid", interface.join("driver")).unwrap();
+
+            let driver = interface_driver(&root, 1, 12, 1);
+            let unbound = interface_driver(&root, 1, 12, 0);
+            let other_device = interface_driver(&root, 1, 13, 1);
+            fs::remove_dir_all(&root).unwrap();
+
+            assert_eq!((Some("usbhid".to_owned()), None, None), (driver, unbound, other_device));
+        }
+
+        #[test]
+        fn it_describes_why_a_claim_failed() {
+            assert_eq!(Some("interface 1 is bound to the kernel driver usbhid; detach it first, e.g., by claiming \
+                             the interface with DeviceHandle::claim".to_owned()),
+                       describe_claim(&Error::Busy, 1, Some("usbhid")));
+            assert!(describe_claim(&Error::Busy, 1, Some("usbfs")).unwrap().contains("another program"));
+            assert_eq!(None, describe_claim(&Error::Busy, 1, None));
+            assert_eq!(None, describe_claim(&Error::NotSupported, 1, Some("usbhid")));
+        }
+    }
+}
+
+// Which driver is bound isn't found out on Windows and macOS, so the hints tell what it takes
+#[cfg(windows)]
+mod platform {
+    use error::Error;
+
+    pub fn open_hint(error: &Error, _bus_number: u8, _address: u8) -> Option<String> {
+        match *error {
+            Error::Busy => Some("another program has the device open, which WinUSB allows only one program at a time".to_owned()),
+            _ => Some("the device isn't bound to WinUSB, libusbK or libusb0, e.g., with Zadig; \
+                       UsbDk reaches devices without them, see ContextBuilder::prefer_usbdk".to_owned()),
+        }
+    }
+
+    pub fn claim_hint(error: &Error, _bus_number: u8, _address: u8, interface: u8) -> Option<String> {
+        match *error {
+            Error::Busy => Some(format!("interface {} is claimed by another program", interface)),
+            _ => Some(format!("interface {} isn't bound to WinUSB or libusbK; each interface of a composite \
+                               device needs its own driver", interface)),
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use error::Error;
+
+    pub fn open_hint(_error: &Error, _bus_number: u8, _address: u8) -> Option<String> {
+        None
+    }
+
+    pub fn claim_hint(_error: &Error, _bus_number: u8, _address: u8, interface: u8) -> Option<String> {
+        Some(format!("interface {} may be held by a kernel extension or driver extension of macOS, which \
+                      libusb can only detach when run as root", interface))
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows, target_os = "macos")))]
+mod platform {
+    use error::Error;
+
+    pub fn open_hint(_error: &Error, _bus_number: u8, _address: u8) -> Option<String> {
+        None
+    }
+
+    pub fn claim_hint(_error: &Error, _bus_number: u8, _address: u8, _interface: u8) -> Option<String> {
+        None
+    }
+}
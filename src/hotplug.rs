@@ -0,0 +1,185 @@
+use std::collections::{HashSet, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task;
+
+use futures_core::Stream;
+
+use backend::{DeviceEvent, HotplugCallback, HotplugRef};
+use context::ContextAsync;
+use device::{self, Device};
+use device_filter::DeviceFilter;
+use error::{self, Operation};
+
+/// A device that was connected or disconnected, as reported by
+/// [`HotplugEvents`](struct.HotplugEvents.html).
+pub enum HotplugEvent {
+    /// The device was connected, or was already connected when the events were registered.
+    Arrived(Device),
+
+    /// The device was disconnected.
+    Left(Device),
+}
+
+/// The events reported by the callback, waiting to be polled.
+struct Queue {
+    context: Arc<ContextAsync>,
+    state: Mutex<QueueState>,
+}
+
+struct QueueState {
+    events: VecDeque<HotplugEvent>,
+    waker: Option<task::Waker>,
+}
+
+impl Queue {
+    fn push(&self, event: HotplugEvent) {
+        let waker = {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            state.events.push_back(event);
+            state.waker.take()
+        };
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    fn poll_next(&self, cx: &mut task::Context) -> task::Poll<HotplugEvent> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        match state.events.pop_front() {
+            Some(event) => task::Poll::Ready(event),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                task::Poll::Pending
+            },
+        }
+    }
+}
+
+/// The callback registered with the backend, which runs on the event thread, or on the
+/// registering thread for the devices already connected.
+///
+/// The event is only queued here. Handling it, e.g., by opening the device, happens when the
+/// stream is polled, outside the callback and without any lock of the context held, as opening
+/// a device from the callback would wait for the event thread that runs it.
+fn hotplug_callback(queue: Arc<Queue>) -> HotplugCallback {
+    Box::new(move |device, event| {
+        // Called from libusb, where unwinding is undefined behaviour
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            let device = unsafe { device::from_backend(&queue.context, device) };
+            match event {
+                DeviceEvent::Arrived => queue.push(HotplugEvent::Arrived(device)),
+                DeviceEvent::Left => queue.push(HotplugEvent::Left(device)),
+            }
+        }));
+    })
+}
+
+/// Stream of the devices connected to and disconnected from a context, returned by
+/// [`Context::hotplug_events`](struct.Context.html#method.hotplug_events).
+///
+/// The stream never ends. Dropping it deregisters the events.
+pub struct HotplugEvents {
+    queue: Arc<Queue>,
+    handle: HotplugRef,
+    filter: Option<DeviceFilter>,
+    // The bus numbers and addresses of the devices that arrived and matched the filter, as a
+    // device that has left can't be matched
+    matched: HashSet<(u8, u8)>,
+}
+
+impl Stream for HotplugEvents {
+    type Item = HotplugEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<HotplugEvent>> {
+        let this = self.get_mut();
+
+        loop {
+            let event = match this.queue.poll_next(cx) {
+                task::Poll::Ready(event) => event,
+                task::Poll::Pending => return task::Poll::Pending,
+            };
+            let filter = match this.filter {
+                Some(ref filter) => filter,
+                None => return task::Poll::Ready(Some(event)),
+            };
+
+            let matches = match event {
+                HotplugEvent::Arrived(ref device) => filter.matches(device) &&
+                    this.matched.insert((device.bus_number(), device.address())),
+                HotplugEvent::Left(ref device) => this.matched.remove(&(device.bus_number(), device.address())),
+            };
+            if matches {
+                return task::Poll::Ready(Some(event));
+            }
+        }
+    }
+}
+
+impl Drop for HotplugEvents {
+    fn drop(&mut self) {
+        let context = self.queue.context.clone();
+        let handle = self.handle;
+
+        // The callback doesn't run after it is deregistered, so the queue can be dropped after
+        // this
+        context.event_thread.release(&context, || unsafe {
+            context.backend.deregister_hotplug(context.backend_context(), handle);
+        });
+    }
+}
+
+pub fn register(context: &Arc<ContextAsync>, filter: Option<DeviceFilter>) -> ::Result<HotplugEvents> {
+    let queue = Arc::new(Queue {
+        context: context.clone(),
+        state: Mutex::new(QueueState { events: VecDeque::new(), waker: None }),
+    });
+
+    // The event thread runs the callback
+    context.event_thread.acquire(context);
+
+    let result = unsafe {
+        context.backend.register_hotplug(context.backend_context(), hotplug_callback(queue.clone()))
+    };
+
+    match result {
+        Ok(handle) => Ok(HotplugEvents { queue, handle, filter, matched: HashSet::new() }),
+        Err(err) => {
+            context.event_thread.release(context, || {});
+            Err(error::from_operation(Operation::RegisterHotplug, err))
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    extern crate futures;
+
+    use super::*;
+    use std::ptr;
+    use backend::DeviceRef;
+    use self::futures::task::noop_waker;
+
+    #[test]
+    fn it_queues_events_from_the_callback() {
+        let queue = Arc::new(Queue {
+            context: ContextAsync::uninitialized(),
+            state: Mutex::new(QueueState { events: VecDeque::new(), waker: None }),
+        });
+        let callback = hotplug_callback(queue.clone());
+        let waker = noop_waker();
+        let mut cx = task::Context::from_waker(&waker);
+
+        assert!(queue.poll_next(&mut cx).is_pending());
+        callback(DeviceRef::from_ptr(ptr::null_mut()), DeviceEvent::Arrived);
+        callback(DeviceRef::from_ptr(ptr::null_mut()), DeviceEvent::Left);
+
+        assert!(matches!(queue.poll_next(&mut cx), task::Poll::Ready(HotplugEvent::Arrived(_))));
+        assert!(matches!(queue.poll_next(&mut cx), task::Poll::Ready(HotplugEvent::Left(_))));
+        assert!(queue.poll_next(&mut cx).is_pending());
+    }
+}
@@ -0,0 +1,325 @@
+//! A snapshot of the buses and devices of a context, to attach to bug reports or to build
+//! `lsusb`-like tools on.
+//!
+//! ```no_run
+//! # fn main() -> libusb_async::Result<()> {
+//! let context = libusb_async::Context::new()?;
+//! let report = libusb_async::inspect::report(&context)?;
+//! println!("{}", report);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The report holds plain values, so it outlives the context and, with the `serde` feature,
+//! can be serialized, e.g., to JSON.
+
+use std::fmt;
+use std::time::Duration;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use config_descriptor::ConfigDescriptor;
+use context::Context;
+use device::Device;
+use device_descriptor::DeviceDescriptor;
+use version::version;
+
+/// How long reading a string descriptor may take.
+const STRING_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The buses and devices of a context, returned by [`report`](fn.report.html).
+#[derive(Debug,Clone,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct InspectionReport {
+    /// The version of the `libusb` library, e.g., "1.0.26.11724".
+    pub libusb_version: String,
+
+    /// The buses, in order of their numbers.
+    pub buses: Vec<BusReport>,
+}
+
+/// A bus and the devices on it.
+#[derive(Debug,Clone,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct BusReport {
+    pub number: u8,
+
+    /// The devices, in order of their addresses.
+    pub devices: Vec<DeviceReport>,
+}
+
+/// A device, with its descriptors and string descriptors.
+#[derive(Debug,Clone,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct DeviceReport {
+    pub bus_number: u8,
+    pub address: u8,
+    pub port_number: u8,
+    pub speed: String,
+    pub usb_version: String,
+    pub device_version: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub class_code: u8,
+    pub sub_class_code: u8,
+    pub protocol_code: u8,
+    pub max_packet_size: u8,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+    pub configurations: Vec<ConfigReport>,
+
+    /// Why the device couldn't be opened to read its string descriptors, e.g., for lack of
+    /// permissions, or why its descriptors couldn't be read.
+    pub error: Option<String>,
+}
+
+/// A configuration of a device.
+#[derive(Debug,Clone,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ConfigReport {
+    pub number: u8,
+    pub max_power_ma: u16,
+    pub self_powered: bool,
+    pub remote_wakeup: bool,
+    pub description: Option<String>,
+
+    /// Every alternate setting of every interface.
+    pub interfaces: Vec<InterfaceReport>,
+}
+
+/// An alternate setting of an interface.
+#[derive(Debug,Clone,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct InterfaceReport {
+    pub number: u8,
+    pub alternate_setting: u8,
+    pub class_code: u8,
+    pub sub_class_code: u8,
+    pub protocol_code: u8,
+    pub description: Option<String>,
+    pub endpoints: Vec<EndpointReport>,
+}
+
+/// An endpoint of an alternate setting.
+#[derive(Debug,Clone,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct EndpointReport {
+    pub address: u8,
+    pub transfer_type: String,
+    pub max_packet_size: u16,
+    pub interval: u8,
+}
+
+/// Inspects every device of a context.
+///
+/// Devices are opened to read their string descriptors. A device that can't be opened is
+/// still reported, without strings and with the error. Only failing to list the devices is an
+/// error.
+pub fn report(context: &Context) -> ::Result<InspectionReport> {
+    let mut devices: Vec<DeviceReport> = context.devices()?.into_iter().map(|device| inspect_device(&device)).collect();
+    devices.sort_by_key(|device| (device.bus_number, device.address));
+
+    let mut buses: Vec<BusReport> = Vec::new();
+    for device in devices {
+        match buses.last_mut() {
+            Some(bus) if bus.number == device.bus_number => bus.devices.push(device),
+            _ => buses.push(BusReport { number: device.bus_number, devices: vec![device] }),
+        }
+    }
+
+    let version = version();
+    Ok(InspectionReport {
+        libusb_version: format!("{}.{}.{}.{}{}", version.major(), version.minor(), version.micro(), version.nano(),
+                                version.rc().unwrap_or("")),
+        buses,
+    })
+}
+
+/// Reads the descriptors and string descriptors of a device.
+fn inspect_device(device: &Device) -> DeviceReport {
+    let location = (device.bus_number(), device.address(), device.port_number(), device.speed().to_string());
+
+    let descriptor = match device.device_descriptor() {
+        Ok(descriptor) => descriptor,
+        Err(e) => return DeviceReport {
+            bus_number: location.0,
+            address: location.1,
+            port_number: location.2,
+            speed: location.3,
+            usb_version: String::new(),
+            device_version: String::new(),
+            vendor_id: 0,
+            product_id: 0,
+            class_code: 0,
+            sub_class_code: 0,
+            protocol_code: 0,
+            max_packet_size: 0,
+            manufacturer: None,
+            product: None,
+            serial_number: None,
+            configurations: Vec::new(),
+            error: Some(e.to_string()),
+        },
+    };
+    let configs: Vec<ConfigDescriptor> = (0..descriptor.num_configurations())
+        .filter_map(|index| device.config_descriptor(index).ok())
+        .collect();
+
+    let opened = device.open().and_then(|handle| {
+        let language = handle.read_languages(STRING_TIMEOUT)?.first().cloned();
+        Ok((handle, language))
+    });
+
+    match opened {
+        Ok((handle, language)) => describe_device(location, &descriptor, &configs, &mut |index| {
+            language.and_then(|language| handle.read_string_descriptor(language, index, STRING_TIMEOUT).ok())
+        }),
+        Err(e) => DeviceReport {
+            error: Some(e.to_string()),
+            ..describe_device(location, &descriptor, &configs, &mut |_| None)
+        },
+    }
+}
+
+/// Builds the report of a device from its location, i.e., bus, address, port and speed, and its
+/// descriptors, reading strings by their index with `read_string`.
+fn describe_device(location: (u8, u8, u8, String), descriptor: &DeviceDescriptor, configs: &[ConfigDescriptor],
+                   read_string: &mut dyn FnMut(u8) -> Option<String>) -> DeviceReport {
+    let (bus_number, address, port_number, speed) = location;
+
+    DeviceReport {
+        bus_number,
+        address,
+        port_number,
+        speed,
+        usb_version: descriptor.usb_version().to_string(),
+        device_version: descriptor.device_version().to_string(),
+        vendor_id: descriptor.vendor_id(),
+        product_id: descriptor.product_id(),
+        class_code: descriptor.class_code().into(),
+        sub_class_code: descriptor.sub_class_code(),
+        protocol_code: descriptor.protocol_code(),
+        max_packet_size: descriptor.max_packet_size(),
+        manufacturer: descriptor.manufacturer_string_index().and_then(&mut *read_string),
+        product: descriptor.product_string_index().and_then(&mut *read_string),
+        serial_number: descriptor.serial_number_string_index().and_then(&mut *read_string),
+        configurations: configs.iter().map(|config| describe_config(config, read_string)).collect(),
+        error: None,
+    }
+}
+
+fn describe_config(config: &ConfigDescriptor, read_string: &mut dyn FnMut(u8) -> Option<String>) -> ConfigReport {
+    let mut interfaces = Vec::new();
+    for interface in config.interfaces() {
+        for setting in interface.descriptors() {
+            interfaces.push(InterfaceReport {
+                number: setting.interface_number(),
+                alternate_setting: setting.setting_number(),
+                class_code: setting.class_code().into(),
+                sub_class_code: setting.sub_class_code(),
+                protocol_code: setting.protocol_code(),
+                description: setting.description_string_index().and_then(&mut *read_string),
+                endpoints: setting.endpoint_descriptors().map(|endpoint| EndpointReport {
+                    address: endpoint.address(),
+                    transfer_type: endpoint.transfer_type().to_string(),
+                    max_packet_size: endpoint.max_packet_size(),
+                    interval: endpoint.interval(),
+                }).collect(),
+            });
+        }
+    }
+
+    ConfigReport {
+        number: config.number(),
+        max_power_ma: config.max_power(),
+        self_powered: config.self_powered(),
+        remote_wakeup: config.remote_wakeup(),
+        description: config.description_string_index().and_then(&mut *read_string),
+        interfaces,
+    }
+}
+
+impl fmt::Display for InspectionReport {
+    /// Formats the report as an indented tree, one line per device, configuration, interface
+    /// and endpoint.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "libusb {}", self.libusb_version)?;
+        for device in self.buses.iter().flat_map(|bus| bus.devices.iter()) {
+            write!(f, "{}", device)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DeviceReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Bus {:03} Device {:03}: ID {:04x}:{:04x}", self.bus_number, self.address, self.vendor_id,
+               self.product_id)?;
+        for name in self.manufacturer.iter().chain(self.product.iter()) {
+            write!(f, " {}", name)?;
+        }
+        writeln!(f, " ({}, USB {})", self.speed, self.usb_version)?;
+        if let Some(ref error) = self.error {
+            writeln!(f, "  Error: {}", error)?;
+        }
+
+        for config in &self.configurations {
+            writeln!(f, "  Configuration {}: {} mA", config.number, config.max_power_ma)?;
+            for interface in &config.interfaces {
+                writeln!(f, "    Interface {}.{}: class {:02x}:{:02x}:{:02x}", interface.number,
+                         interface.alternate_setting, interface.class_code, interface.sub_class_code,
+                         interface.protocol_code)?;
+                for endpoint in &interface.endpoints {
+                    writeln!(f, "      Endpoint 0x{:02x}: {}, {} bytes", endpoint.address, endpoint.transfer_type,
+                             endpoint.max_packet_size)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use parse::{parse_config_descriptor, parse_device_descriptor};
+
+    const DEVICE: [u8; 18] = [
+        0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40,
+        0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0x01, 0x02, 0x00, 0x01,
+    ];
+
+    const CONFIG: [u8; 25] = [
+        0x09, 0x02, 0x19, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32,
+        0x09, 0x04, 0x00, 0x00, 0x01, 0xFF, 0x00, 0x00, 0x00,
+        0x07, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn it_describes_a_device_with_its_strings() {
+        let device = parse_device_descriptor(&DEVICE).unwrap();
+        let config = parse_config_descriptor(&CONFIG).unwrap();
+        let report = describe_device((1, 4, 2, "480 Mbps".to_owned()), &device, &[config], &mut |index| {
+            Some(["", "Acme", "Widget"][index as usize].to_owned())
+        });
+
+        assert_eq!(Some("Widget"), report.product.as_deref());
+        assert_eq!(None, report.serial_number);
+        assert_eq!("2.0.0", report.usb_version);
+        assert_eq!(100, report.configurations[0].max_power_ma);
+
+        let interface = &report.configurations[0].interfaces[0];
+        assert_eq!(0xFF, interface.class_code);
+        assert_eq!(vec![EndpointReport { address: 0x81, transfer_type: "Bulk".to_owned(), max_packet_size: 64, interval: 0 }],
+                   interface.endpoints);
+
+        assert_eq!("Bus 001 Device 004: ID 1234:5678 Acme Widget (480 Mbps, USB 2.0.0)\n\
+                    \x20 Configuration 1: 100 mA\n\
+                    \x20   Interface 0.0: class ff:00:00\n\
+                    \x20     Endpoint 0x81: Bulk, 64 bytes\n",
+                   report.to_string());
+    }
+}
@@ -1,9 +1,14 @@
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::slice;
 
 use libusb::*;
 
 use endpoint_descriptor::{self, EndpointDescriptor};
+use fields::{ClassCode, TransferType, Direction};
+use extra_descriptors::{self, ExtraDescriptors};
+use device_handle::DeviceHandle;
+use string_descriptor::{self, StringDescriptorFuture};
 
 /// A device interface.
 ///
@@ -27,6 +32,22 @@ impl<'a> Interface<'a> {
     }
 }
 
+impl<'a, 'b> PartialEq<Interface<'b>> for Interface<'a> {
+    fn eq(&self, other: &Interface<'b>) -> bool {
+        self.descriptors().eq(other.descriptors())
+    }
+}
+
+impl<'a> Eq for Interface<'a> {}
+
+impl<'a> Hash for Interface<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for descriptor in self.descriptors() {
+            descriptor.hash(state);
+        }
+    }
+}
+
 /// Iterator over an interface's descriptors.
 pub struct InterfaceDescriptors<'a> {
     iter: slice::Iter<'a, libusb_interface_descriptor>,
@@ -64,8 +85,8 @@ impl<'a> InterfaceDescriptor<'a> {
     }
 
     /// Returns the interface's class code.
-    pub fn class_code(&self) -> u8 {
-        self.descriptor.bInterfaceClass
+    pub fn class_code(&self) -> ClassCode {
+        ClassCode::from(self.descriptor.bInterfaceClass)
     }
 
     /// Returns the interface's sub class code.
@@ -86,22 +107,82 @@ impl<'a> InterfaceDescriptor<'a> {
         }
     }
 
+    /// Reads the interface's description from the device in its first supported language.
+    ///
+    /// The future resolves to `Err(Error::InvalidParam)` if the interface has no description
+    /// string.
+    pub fn description_string<'h>(&self, handle: &'h DeviceHandle) -> StringDescriptorFuture<'h> {
+        string_descriptor::read_string(handle, None, self.description_string_index())
+    }
+
     /// Returns the number of endpoints belonging to this interface.
     pub fn num_endpoints(&self) -> u8 {
         self.descriptor.bNumEndpoints
     }
 
     /// Returns an iterator over the interface's endpoint descriptors.
-    pub fn endpoint_descriptors(&self) -> EndpointDescriptors {
-        let endpoints = unsafe {
-            slice::from_raw_parts(
-                self.descriptor.endpoint,
-                self.descriptor.bNumEndpoints as usize
-            )
+    pub fn endpoint_descriptors(&self) -> EndpointDescriptors<'a> {
+        let endpoints = if self.descriptor.endpoint.is_null() {
+            &[]
+        }
+        else {
+            unsafe {
+                slice::from_raw_parts(
+                    self.descriptor.endpoint,
+                    self.descriptor.bNumEndpoints as usize
+                )
+            }
         };
 
         EndpointDescriptors { iter: endpoints.iter() }
     }
+
+    /// Returns the first endpoint with the given transfer type and direction.
+    pub fn first_endpoint(&self, transfer_type: TransferType, direction: Direction) -> Option<EndpointDescriptor<'a>> {
+        self.endpoint_descriptors().find(|endpoint| {
+            endpoint.transfer_type() == transfer_type && endpoint.direction() == direction
+        })
+    }
+
+    /// Returns the class- or vendor-specific descriptors that follow the interface descriptor.
+    pub fn extra(&self) -> &'a [u8] {
+        unsafe {
+            extra_descriptors::extra_from_libusb(self.descriptor.extra,
+                                                 self.descriptor.extra_length)
+        }
+    }
+
+    /// Returns an iterator over the descriptors in [`extra`](#method.extra).
+    pub fn extra_descriptors(&self) -> ExtraDescriptors<'a> {
+        ExtraDescriptors::new(self.extra())
+    }
+}
+
+impl<'a> InterfaceDescriptor<'a> {
+    fn key(&self) -> (u8, u8, u8, u8, u8, u8, u8, u8, u8, &'a [u8]) {
+        let d = self.descriptor;
+
+        (d.bLength, d.bDescriptorType, d.bInterfaceNumber, d.bAlternateSetting, d.bNumEndpoints,
+         d.bInterfaceClass, d.bInterfaceSubClass, d.bInterfaceProtocol, d.iInterface, self.extra())
+    }
+}
+
+impl<'a, 'b> PartialEq<InterfaceDescriptor<'b>> for InterfaceDescriptor<'a> {
+    fn eq(&self, other: &InterfaceDescriptor<'b>) -> bool {
+        self.key() == other.key() && self.endpoint_descriptors().eq(other.endpoint_descriptors())
+    }
+}
+
+impl<'a> Eq for InterfaceDescriptor<'a> {}
+
+impl<'a> Hash for InterfaceDescriptor<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+
+        for endpoint in self.endpoint_descriptors() {
+            endpoint.hash(state);
+        }
+    }
 }
 
 impl<'a> fmt::Debug for InterfaceDescriptor<'a> {
@@ -122,6 +203,24 @@ impl<'a> fmt::Debug for InterfaceDescriptor<'a> {
     }
 }
 
+impl<'a> fmt::Display for InterfaceDescriptor<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(fmt, "Interface {} alt {}: {} ({:#04x}/{:#04x}/{:#04x})",
+               self.interface_number(), self.setting_number(), self.class_code(),
+               u8::from(self.class_code()), self.sub_class_code(), self.protocol_code())?;
+
+        if let Some(name) = self.class_code().function_name(self.sub_class_code(), self.protocol_code()) {
+            write!(fmt, ", {}", name)?;
+        }
+
+        for endpoint in self.endpoint_descriptors() {
+            write!(fmt, "\n  {}", endpoint)?;
+        }
+
+        Ok(())
+    }
+}
+
 /// Iterator over an interface's endpoint descriptors.
 pub struct EndpointDescriptors<'a> {
     iter: slice::Iter<'a, libusb_endpoint_descriptor>,
@@ -153,6 +252,8 @@ pub unsafe fn from_libusb(interface: &libusb_interface) -> Interface {
 
 #[cfg(test)]
 mod test {
+    use fields::ClassCode;
+
     #[test]
     fn it_has_interface_number() {
         assert_eq!(42, unsafe { super::from_libusb(&interface!(interface_descriptor!(bInterfaceNumber: 42))) }.number());
@@ -170,7 +271,8 @@ mod test {
 
     #[test]
     fn it_has_class_code() {
-        assert_eq!(vec!(42), unsafe { super::from_libusb(&interface!(interface_descriptor!(bInterfaceClass: 42))) }.descriptors().map(|setting| setting.class_code()).collect::<Vec<_>>());
+        assert_eq!(vec!(ClassCode::Unknown(42)), unsafe { super::from_libusb(&interface!(interface_descriptor!(bInterfaceClass: 42))) }.descriptors().map(|setting| setting.class_code()).collect::<Vec<_>>());
+        assert_eq!(vec!(ClassCode::Hid), unsafe { super::from_libusb(&interface!(interface_descriptor!(bInterfaceClass: 0x03))) }.descriptors().map(|setting| setting.class_code()).collect::<Vec<_>>());
     }
 
     #[test]
@@ -212,4 +314,55 @@ mod test {
 
         assert_eq!(vec![0x87], endpoint_addresses);
     }
+
+    #[test]
+    fn it_compares_settings_and_endpoints() {
+        let a = interface!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x81)));
+        let b = interface!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x81)));
+        let c = interface!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x82)));
+
+        assert!(unsafe { super::from_libusb(&a) } == unsafe { super::from_libusb(&b) });
+        assert!(unsafe { super::from_libusb(&a) } != unsafe { super::from_libusb(&c) });
+    }
+
+    #[test]
+    fn it_finds_first_endpoint_by_type_and_direction() {
+        use fields::{TransferType, Direction};
+
+        let libusb_interface = interface!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x01, bmAttributes: 0x02),
+                                                                endpoint_descriptor!(bEndpointAddress: 0x83, bmAttributes: 0x03),
+                                                                endpoint_descriptor!(bEndpointAddress: 0x82, bmAttributes: 0x02),
+                                                                endpoint_descriptor!(bEndpointAddress: 0x84, bmAttributes: 0x02)));
+        let interface = unsafe { super::from_libusb(&libusb_interface) };
+        let setting = interface.descriptors().next().unwrap();
+
+        assert_eq!(Some(0x82), setting.first_endpoint(TransferType::Bulk, Direction::In).map(|endpoint| endpoint.address()));
+        assert_eq!(None, setting.first_endpoint(TransferType::Isochronous, Direction::In).map(|endpoint| endpoint.address()));
+    }
+
+    #[test]
+    fn it_has_extra_descriptors() {
+        let extra = [0x03, 0x21, 0x11];
+        let libusb_interface = interface!(interface_descriptor!(extra: extra.as_ptr(), extra_length: 3));
+        let interface = unsafe { super::from_libusb(&libusb_interface) };
+
+        let descriptors = interface.descriptors().next().unwrap().extra_descriptors().collect::<Vec<_>>();
+
+        assert_eq!(vec![(0x21, &[0x11][..])], descriptors);
+    }
+
+    #[test]
+    fn it_handles_missing_extra_descriptors() {
+        assert_eq!(vec!(0), unsafe { super::from_libusb(&interface!(interface_descriptor!(bInterfaceNumber: 0))) }.descriptors().map(|setting| setting.extra().len()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn it_displays_setting_and_endpoints() {
+        let setting = merge!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x81, bmAttributes: 0x03)) => bInterfaceClass: 0x03, bInterfaceSubClass: 0x01, bInterfaceProtocol: 0x01);
+        let libusb_interface = interface!(setting);
+        let interface = unsafe { super::from_libusb(&libusb_interface) };
+
+        assert_eq!("Interface 0 alt 0: Human interface device (0x03/0x01/0x01), HID boot keyboard\n  Endpoint 0x81 In Interrupt, max packet size 16, interval 1",
+                   interface.descriptors().next().unwrap().to_string());
+    }
 }
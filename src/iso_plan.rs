@@ -0,0 +1,112 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::time::Duration;
+
+/// How long the host controller should have transfers queued for at least, so an isochronous
+/// stream survives the scheduling delays of the thread that resubmits them.
+const MIN_QUEUED: Duration = Duration::from_millis(4);
+
+/// How to keep an isochronous endpoint streaming at a byte rate, returned by
+/// [`EndpointDescriptor::iso_plan`](struct.EndpointDescriptor.html#method.iso_plan).
+///
+/// Each packet of a transfer is one service interval of the endpoint, so the packets of a
+/// transfer span `packets_per_transfer` intervals.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct IsoPlan {
+    /// The length to give each packet, the most the endpoint moves in an interval, including
+    /// the additional transactions of high-bandwidth endpoints.
+    pub packet_size: usize,
+
+    /// The bytes a packet carries on average at the requested rate.
+    pub bytes_per_packet: usize,
+
+    /// The number of packets of each transfer.
+    pub packets_per_transfer: u32,
+
+    /// The number of transfers to keep in flight.
+    pub transfers: usize,
+
+    /// The most bytes per second the endpoint can carry.
+    pub capacity: u64,
+}
+
+impl IsoPlan {
+    /// Returns the length of the buffer of each transfer.
+    pub fn transfer_size(&self) -> usize {
+        self.packet_size * self.packets_per_transfer as usize
+    }
+}
+
+/// Why an isochronous stream can't be planned.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum IsoPlanError {
+    /// The endpoint isn't isochronous or interrupt, or the speed of the device is unknown.
+    NotPeriodic,
+
+    /// The endpoint can carry at most `capacity` bytes per second, less than the `required`
+    /// rate. Another alternate setting of the interface may have a larger endpoint.
+    InsufficientBandwidth { required: u64, capacity: u64 },
+}
+
+impl fmt::Display for IsoPlanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IsoPlanError::NotPeriodic => f.write_str("Not a periodic endpoint, or unknown device speed"),
+            IsoPlanError::InsufficientBandwidth { required, capacity } =>
+                write!(f, "Endpoint carries at most {} bytes/s, {} bytes/s required", capacity, required),
+        }
+    }
+}
+
+impl StdError for IsoPlanError {}
+
+/// Plans a stream of `bytes_per_second` on an endpoint that moves up to `bytes_per_interval`
+/// bytes each `interval`, with transfers spanning at least `transfer_duration`.
+pub fn plan(bytes_per_interval: usize, interval: Duration, bytes_per_second: u64, transfer_duration: Duration) -> Result<IsoPlan, IsoPlanError> {
+    let interval_nanos = interval.as_nanos().max(1);
+    let capacity = (bytes_per_interval as u128 * 1_000_000_000 / interval_nanos) as u64;
+    if bytes_per_second > capacity {
+        return Err(IsoPlanError::InsufficientBandwidth { required: bytes_per_second, capacity });
+    }
+
+    let bytes_per_packet = (bytes_per_second as u128 * interval_nanos).div_ceil(1_000_000_000) as usize;
+    let packets_per_transfer = transfer_duration.as_nanos().div_ceil(interval_nanos).max(1);
+    let transfer_nanos = packets_per_transfer * interval_nanos;
+    // One more than covers the queued time, as one transfer at a time is being resubmitted
+    let transfers = MIN_QUEUED.as_nanos().div_ceil(transfer_nanos).max(1) + 1;
+
+    Ok(IsoPlan {
+        packet_size: bytes_per_interval,
+        bytes_per_packet,
+        packets_per_transfer: packets_per_transfer.min(u32::MAX as u128) as u32,
+        transfers: transfers as usize,
+        capacity,
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_plans_high_bandwidth_streams() {
+        // Three transactions of 1024 bytes per microframe
+        let plan = plan(3072, Duration::from_micros(125), 20_000_000, Duration::from_millis(1)).unwrap();
+        assert_eq!(IsoPlan { packet_size: 3072, bytes_per_packet: 2500, packets_per_transfer: 8, transfers: 5, capacity: 24_576_000 }, plan);
+        assert_eq!(8 * 3072, plan.transfer_size());
+    }
+
+    #[test]
+    fn it_plans_full_speed_audio() {
+        // 48 kHz stereo 16-bit audio on a 1 ms endpoint
+        let plan = plan(196, Duration::from_millis(1), 192_000, Duration::from_millis(8)).unwrap();
+        assert_eq!((192, 8, 2), (plan.bytes_per_packet, plan.packets_per_transfer, plan.transfers));
+    }
+
+    #[test]
+    fn it_rejects_streams_beyond_the_endpoint() {
+        assert_eq!(Err(IsoPlanError::InsufficientBandwidth { required: 200_000, capacity: 192_000 }),
+                   plan(192, Duration::from_millis(1), 200_000, Duration::from_millis(1)));
+    }
+}
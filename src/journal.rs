@@ -0,0 +1,254 @@
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task;
+use std::time::{Duration, Instant};
+
+use futures_core::Stream;
+
+use context::ContextAsync;
+use hotplug::{HotplugEvent, HotplugEvents};
+use observer::{TransferInfo, TransferObserver};
+use transfer::TransferStatus;
+
+/// How many events a journal keeps while it isn't polled, before it drops the oldest.
+pub const JOURNAL_CAPACITY: usize = 4096;
+
+/// Something that happened on a context, as reported by [`UrbJournal`](struct.UrbJournal.html).
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum UrbEvent {
+    /// A transfer was handed to `libusb`, including when a completion hook resubmitted it.
+    Submitted {
+        transfer: TransferInfo,
+        at: Instant,
+    },
+
+    /// A transfer completed, was cancelled, or failed to be submitted.
+    Completed {
+        transfer: TransferInfo,
+        status: TransferStatus,
+
+        /// The number of bytes transferred, 0 unless it completed successfully.
+        actual_length: usize,
+
+        /// The time since the transfer was submitted, or `None` if it was submitted before the
+        /// journal was opened.
+        latency: Option<Duration>,
+
+        at: Instant,
+    },
+
+    /// A device was connected, or was already connected when the journal was opened.
+    DeviceArrived {
+        bus_number: u8,
+        address: u8,
+        at: Instant,
+    },
+
+    /// A device was disconnected.
+    DeviceLeft {
+        bus_number: u8,
+        address: u8,
+        at: Instant,
+    },
+}
+
+/// Queues the transfer events of the context, as an observer of it.
+struct Recorder {
+    state: Mutex<RecorderState>,
+}
+
+struct RecorderState {
+    events: VecDeque<UrbEvent>,
+    // When the transfers in flight were submitted, by id
+    submitted: HashMap<usize, Instant>,
+    dropped: u64,
+    waker: Option<task::Waker>,
+}
+
+impl Recorder {
+    fn push(&self, event: UrbEvent) {
+        let waker = {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            match event {
+                UrbEvent::Submitted { ref transfer, at } => {
+                    state.submitted.insert(transfer.id, at);
+                },
+                UrbEvent::Completed { ref transfer, .. } => {
+                    state.submitted.remove(&transfer.id);
+                },
+                _ => {},
+            }
+            if state.events.len() == JOURNAL_CAPACITY {
+                state.events.pop_front();
+                state.dropped += 1;
+            }
+            state.events.push_back(event);
+            state.waker.take()
+        };
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    fn completed(&self, transfer: &TransferInfo, status: TransferStatus, actual_length: usize) {
+        let at = Instant::now();
+        let latency = {
+            let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            state.submitted.get(&transfer.id).map(|&submitted| at.saturating_duration_since(submitted))
+        };
+        self.push(UrbEvent::Completed { transfer: *transfer, status, actual_length, latency, at });
+    }
+
+    fn poll_next(&self, cx: &mut task::Context) -> task::Poll<UrbEvent> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        match state.events.pop_front() {
+            Some(event) => task::Poll::Ready(event),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                task::Poll::Pending
+            },
+        }
+    }
+}
+
+impl TransferObserver for Recorder {
+    fn on_submit(&self, transfer: &TransferInfo) {
+        self.push(UrbEvent::Submitted { transfer: *transfer, at: Instant::now() });
+    }
+
+    fn on_complete(&self, transfer: &TransferInfo, actual_length: usize) {
+        self.completed(transfer, TransferStatus::Completed, actual_length);
+    }
+
+    fn on_cancel(&self, transfer: &TransferInfo) {
+        self.completed(transfer, TransferStatus::Cancelled, 0);
+    }
+
+    fn on_error(&self, transfer: &TransferInfo, status: TransferStatus) {
+        self.completed(transfer, status, 0);
+    }
+}
+
+/// Stream of every transfer submitted and completed on a context, and of the devices connected
+/// to and disconnected from it, returned by
+/// [`Context::urb_journal`](struct.Context.html#method.urb_journal), e.g., for a live monitor
+/// of the USB activity of an application.
+///
+/// The transfer events of the stream are in the order they happened, and so are its device
+/// events, but the two are interleaved as they are polled. The `at` of a transfer event tells
+/// when it happened, that of a device event when the stream returned it. While the stream isn't polled, it keeps the latest
+/// [`JOURNAL_CAPACITY`](constant.JOURNAL_CAPACITY.html) events.
+///
+/// The stream never ends. Dropping it stops the journal.
+pub struct UrbJournal {
+    context: Arc<ContextAsync>,
+    recorder: Arc<Recorder>,
+    hotplug: Option<HotplugEvents>,
+}
+
+impl UrbJournal {
+    /// Returns the number of events dropped because the stream wasn't polled in time.
+    pub fn dropped(&self) -> u64 {
+        self.recorder.state.lock().unwrap_or_else(PoisonError::into_inner).dropped
+    }
+}
+
+impl Stream for UrbJournal {
+    type Item = UrbEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<UrbEvent>> {
+        let this = self.get_mut();
+
+        if let task::Poll::Ready(event) = this.recorder.poll_next(cx) {
+            return task::Poll::Ready(Some(event));
+        }
+        let hotplug = match this.hotplug {
+            Some(ref mut hotplug) => hotplug,
+            None => return task::Poll::Pending,
+        };
+        match Pin::new(hotplug).poll_next(cx) {
+            task::Poll::Ready(Some(HotplugEvent::Arrived(device))) => task::Poll::Ready(Some(UrbEvent::DeviceArrived {
+                bus_number: device.bus_number(), address: device.address(), at: Instant::now(),
+            })),
+            task::Poll::Ready(Some(HotplugEvent::Left(device))) => task::Poll::Ready(Some(UrbEvent::DeviceLeft {
+                bus_number: device.bus_number(), address: device.address(), at: Instant::now(),
+            })),
+            task::Poll::Ready(None) | task::Poll::Pending => task::Poll::Pending,
+        }
+    }
+}
+
+impl Drop for UrbJournal {
+    fn drop(&mut self) {
+        let observer: Arc<dyn TransferObserver> = self.recorder.clone();
+        self.context.observers.remove(&observer);
+    }
+}
+
+/// Opens a journal of the transfers of a context, and of the hotplug events, if given.
+pub fn open(context: &Arc<ContextAsync>, hotplug: Option<HotplugEvents>) -> UrbJournal {
+    let recorder = Arc::new(Recorder {
+        state: Mutex::new(RecorderState {
+            events: VecDeque::new(), submitted: HashMap::new(), dropped: 0, waker: None,
+        }),
+    });
+    context.observers.add(recorder.clone());
+    UrbJournal { context: context.clone(), recorder, hotplug }
+}
+
+
+#[cfg(test)]
+mod test {
+    extern crate futures;
+
+    use super::*;
+    use self::futures::task::noop_waker;
+
+    fn info(id: usize) -> TransferInfo {
+        TransferInfo { id, bus_number: 1, address: 4, endpoint: 0x81, length: 64 }
+    }
+
+    fn poll(journal: &mut UrbJournal) -> task::Poll<Option<UrbEvent>> {
+        let waker = noop_waker();
+        Pin::new(journal).poll_next(&mut task::Context::from_waker(&waker))
+    }
+
+    #[test]
+    fn it_journals_transfers_with_their_latency() {
+        let context = ContextAsync::uninitialized();
+        let mut journal = open(&context, None);
+
+        assert!(poll(&mut journal).is_pending());
+        context.observers.submitted(&info(1));
+        context.observers.completed(&info(1), TransferStatus::Completed, 10);
+        context.observers.completed(&info(2), TransferStatus::Stall, 0);
+
+        assert!(matches!(poll(&mut journal), task::Poll::Ready(Some(UrbEvent::Submitted { transfer, .. })) if transfer == info(1)));
+        match poll(&mut journal) {
+            task::Poll::Ready(Some(UrbEvent::Completed { transfer, status, actual_length, latency, .. })) => {
+                assert_eq!((info(1), TransferStatus::Completed, 10), (transfer, status, actual_length));
+                assert!(latency.is_some());
+            },
+            event => panic!("unexpected {:?}", event),
+        }
+        assert!(matches!(poll(&mut journal),
+                         task::Poll::Ready(Some(UrbEvent::Completed { status: TransferStatus::Stall, latency: None, .. }))));
+        assert!(poll(&mut journal).is_pending());
+    }
+
+    #[test]
+    fn it_drops_the_oldest_events_when_full() {
+        let context = ContextAsync::uninitialized();
+        let mut journal = open(&context, None);
+
+        for id in 0..JOURNAL_CAPACITY + 2 {
+            context.observers.submitted(&info(id));
+        }
+
+        assert_eq!(2, journal.dropped());
+        assert!(matches!(poll(&mut journal), task::Poll::Ready(Some(UrbEvent::Submitted { transfer, .. })) if transfer.id == 2));
+    }
+}
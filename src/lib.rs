@@ -3,24 +3,70 @@
 extern crate bit_set;
 extern crate libusb_sys as libusb;
 extern crate libc;
+extern crate futures_core;
+extern crate futures_io;
+extern crate futures_sink;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(feature = "async-io")]
+extern crate async_io;
+#[cfg(all(feature = "udev", target_os = "linux"))]
+extern crate udev as libudev;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+extern crate io_uring;
 
 pub use version::{LibraryVersion, version};
-pub use error::{Result, Error};
+pub use error::{Result, Error, Operation, OperationError};
 
-pub use context::{Context, LogLevel};
-pub use device_list::{DeviceList, Devices};
+pub use context::{Context, ContextBuilder, LogLevel};
+pub use device_list::{DeviceList, Devices, IntoDevices};
 pub use device::Device;
+pub use device_filter::DeviceFilter;
 pub use device_handle::DeviceHandle;
 pub use transfer::TransferStatus;
+pub use transfer::HookAction;
 pub use transfer::Transfer;
+pub use transfer::CompletedTransfer;
+pub use transfer::FillError;
 pub use transfer::TransferFuture;
+pub use transfer::IsoPacket;
+#[cfg(feature = "timing")]
+pub use transfer::TransferTiming;
 
-pub use fields::{Speed, TransferType, SyncType, UsageType, Direction, RequestType, Recipient, Version, request_type};
+pub use fields::{Speed, ClassCode, TransferType, SyncType, UsageType, Direction, EndpointAddress, RequestType, Recipient, StandardRequest, Version, request_type};
 pub use device_descriptor::DeviceDescriptor;
 pub use config_descriptor::{ConfigDescriptor, Interfaces};
 pub use interface_descriptor::{Interface, InterfaceDescriptors, InterfaceDescriptor, EndpointDescriptors};
 pub use endpoint_descriptor::EndpointDescriptor;
+pub use iso_plan::{IsoPlan, IsoPlanError};
+pub use extra_descriptors::ExtraDescriptors;
+pub use parse::{parse_device_descriptor, parse_config_descriptor};
+pub use bos::{BosDescriptor, DeviceCapability, PlatformCapability, WebUsbCapability, MsOs20DescriptorSetInfo,
+              BillboardCapability, AlternateMode, AlternateModeState,
+              WEBUSB_PLATFORM_UUID, MS_OS_20_PLATFORM_UUID, parse_bos_descriptor, parse_webusb_url};
+pub use ms_os_20::{MsOs20DescriptorSet, MsOs20ConfigurationSubset, MsOs20FunctionSubset, MsOs20Feature, RegistryValue,
+                   parse_ms_os_20_descriptor_set};
+pub use diff::{diff, DescriptorDiff, FieldChange};
+pub use snapshot::{DescriptorSnapshot, DescriptorChanges};
 pub use language::{Language, PrimaryLanguage, SubLanguage};
+pub use string_descriptor::StringDescriptorFuture;
+pub use raw_descriptor::RawDescriptorFuture;
+pub use control::{ControlFuture, ControlRequest};
+pub use disconnect::DisconnectFuture;
+pub use read_queue::{Backpressure, QueueDepth};
+pub use bulk_stream::{BulkStream, LoanedBuffer};
+pub use claimed::{ClaimedInterface, InterruptIn};
+pub use hotplug::{HotplugEvent, HotplugEvents};
+pub use diagnostics::Diagnostics;
+pub use runtime::PollFd;
+pub use observer::{TransferObserver, TransferInfo};
+pub use journal::{UrbEvent, UrbJournal, JOURNAL_CAPACITY};
+pub use capture::{CaptureRecord, CaptureEvent, write_pcapng, read_pcapng};
+pub use metrics::{MetricsSnapshot, EndpointMetrics, LatencyHistogram, LATENCY_BUCKETS};
+pub use driver::{ClassDriver, ProbeDescriptors, BoundDevice, DriverBindings, DriverEvent};
+pub use probe::{Function, Driver, probe_functions};
 
 
 #[cfg(test)]
@@ -29,17 +75,75 @@ mod test_helpers;
 
 #[macro_use]
 mod error;
+mod hints;
 mod version;
 
 mod context;
+mod event_thread;
+mod backend;
 mod device_list;
 mod device;
+mod device_filter;
 mod device_handle;
 mod transfer;
+mod disconnect;
+mod buffer;
+mod diagnostics;
+mod metrics;
+mod observer;
+mod capture;
+mod journal;
+mod hotplug;
 
 mod fields;
 mod device_descriptor;
 mod config_descriptor;
 mod interface_descriptor;
 mod endpoint_descriptor;
+mod extra_descriptors;
+mod parse;
+mod bos;
+mod ms_os_20;
+mod diff;
+mod snapshot;
 mod language;
+mod string_descriptor;
+mod raw_descriptor;
+mod control;
+mod delay;
+mod cdc;
+mod bulk_io;
+mod transfer_queue;
+mod read_queue;
+mod bulk_stream;
+mod claimed;
+mod iso_plan;
+mod driver;
+mod probe;
+
+pub mod uvc;
+pub mod uac;
+pub mod hid;
+pub mod serial;
+pub mod msc;
+pub mod dfu;
+pub mod midi;
+pub mod printer;
+pub mod ecm;
+pub mod ncm;
+pub mod tmc;
+pub mod ftdi;
+pub mod cp210x;
+pub mod ch34x;
+pub mod ctaphid;
+pub mod ptp;
+pub mod rndis;
+pub mod framed;
+pub mod inspect;
+pub mod blocking;
+pub mod runtime;
+pub mod selftest;
+#[cfg(all(feature = "udev", target_os = "linux"))]
+pub mod udev;
+#[cfg(any(test, feature = "replay"))]
+pub mod replay;
@@ -0,0 +1,242 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use libc::c_int;
+use libusb::*;
+
+/// Upper bound of the first latency bucket, in microseconds. Each following bucket doubles it.
+const FIRST_BUCKET_MICROS: u64 = 125;
+
+/// The number of latency buckets, the last of which has no upper bound.
+pub const LATENCY_BUCKETS: usize = 12;
+
+/// Completion latencies of the transfers of an endpoint, counted in buckets of doubling width.
+///
+/// The first bucket counts latencies up to 125 µs, the next up to 250 µs and so on, up to
+/// 128 ms. The last bucket counts the longer latencies.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Default)]
+pub struct LatencyHistogram {
+    counts: [u64; LATENCY_BUCKETS],
+}
+
+impl LatencyHistogram {
+    /// Returns the upper bound of a bucket, or `None` for the last one.
+    pub fn bucket_bound(bucket: usize) -> Option<Duration> {
+        if bucket + 1 < LATENCY_BUCKETS {
+            Some(Duration::from_micros(FIRST_BUCKET_MICROS << bucket))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the number of latencies in each bucket.
+    pub fn counts(&self) -> &[u64; LATENCY_BUCKETS] {
+        &self.counts
+    }
+
+    /// Returns the number of latencies counted.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Returns the upper bound of the bucket holding the given percentile, e.g., 99.0, or `None`
+    /// if nothing has been counted or the percentile is in the last bucket.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+
+        let rank = ((percentile / 100.0) * count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, &n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return LatencyHistogram::bucket_bound(bucket);
+            }
+        }
+        None
+    }
+
+    fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros();
+        let bucket = (0..LATENCY_BUCKETS - 1)
+            .find(|&bucket| micros <= (FIRST_BUCKET_MICROS << bucket) as u128)
+            .unwrap_or(LATENCY_BUCKETS - 1);
+        self.counts[bucket] += 1;
+    }
+}
+
+/// What was recorded for one endpoint, part of a [`MetricsSnapshot`](struct.MetricsSnapshot.html).
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Default)]
+pub struct EndpointMetrics {
+    /// The address of the endpoint.
+    pub endpoint: u8,
+
+    /// Transfers that completed, whatever their status.
+    pub transfers: u64,
+
+    /// Bytes transferred by the completed transfers.
+    pub bytes: u64,
+
+    /// Transfers that failed to be submitted or ended with an error, other than a stall.
+    pub errors: u64,
+
+    /// Transfers that ended with a stall.
+    pub stalls: u64,
+
+    /// Transfers that were cancelled.
+    pub cancelled: u64,
+
+    /// Transfers submitted and not yet completed.
+    pub in_flight: u32,
+
+    /// The most transfers that have been in flight at once.
+    pub max_in_flight: u32,
+
+    /// Time from submission to completion of the completed transfers.
+    pub latency: LatencyHistogram,
+}
+
+/// Transfer metrics of a device handle, returned by
+/// [`DeviceHandle::metrics`](struct.DeviceHandle.html#method.metrics).
+#[derive(Debug,PartialEq,Clone,Default)]
+pub struct MetricsSnapshot {
+    /// Time since the metrics were enabled.
+    pub elapsed: Duration,
+
+    /// The endpoints that have had transfers, in order of address.
+    pub endpoints: Vec<EndpointMetrics>,
+}
+
+impl MetricsSnapshot {
+    /// Returns the metrics of an endpoint, if it has had transfers.
+    pub fn endpoint(&self, endpoint: u8) -> Option<&EndpointMetrics> {
+        self.endpoints.iter().find(|metrics| metrics.endpoint == endpoint)
+    }
+
+    /// Returns the average throughput of an endpoint since the metrics were enabled, in bytes
+    /// per second.
+    pub fn throughput(&self, endpoint: u8) -> f64 {
+        match self.endpoint(endpoint) {
+            Some(metrics) if self.elapsed > Duration::ZERO => metrics.bytes as f64 / self.elapsed.as_secs_f64(),
+            _ => 0.0,
+        }
+    }
+}
+
+/// The metrics of a device handle, updated by its transfers while enabled.
+///
+/// Recording takes a lock on the transfer callback, which is why it's opt-in.
+pub struct Metrics {
+    enabled: AtomicBool,
+    state: Mutex<State>,
+}
+
+struct State {
+    since: Instant,
+    endpoints: BTreeMap<u8, EndpointMetrics>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            enabled: AtomicBool::new(false),
+            state: Mutex::new(State { since: Instant::now(), endpoints: BTreeMap::new() }),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    /// Enables or disables recording. Enabling starts over from zero.
+    pub fn set_enabled(&self, enabled: bool) {
+        if enabled {
+            let mut state = self.state.lock().unwrap();
+            state.since = Instant::now();
+            state.endpoints.clear();
+        }
+        self.enabled.store(enabled, Ordering::Release);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let state = self.state.lock().unwrap();
+        MetricsSnapshot {
+            elapsed: state.since.elapsed(),
+            endpoints: state.endpoints.values().cloned().collect(),
+        }
+    }
+
+    /// Records a transfer being submitted.
+    pub fn submitted(&self, endpoint: u8) {
+        self.update(endpoint, |metrics| {
+            metrics.in_flight += 1;
+            metrics.max_in_flight = metrics.max_in_flight.max(metrics.in_flight);
+        });
+    }
+
+    /// Records a transfer that was counted as submitted, but failed to be.
+    pub fn submit_failed(&self, endpoint: u8) {
+        self.update(endpoint, |metrics| {
+            metrics.in_flight = metrics.in_flight.saturating_sub(1);
+            metrics.errors += 1;
+        });
+    }
+
+    /// Records a transfer completing with a `libusb` status.
+    pub fn completed(&self, endpoint: u8, status: c_int, length: usize, latency: Duration) {
+        self.update(endpoint, |metrics| {
+            metrics.in_flight = metrics.in_flight.saturating_sub(1);
+            metrics.transfers += 1;
+            metrics.bytes += length as u64;
+            metrics.latency.record(latency);
+
+            match status {
+                LIBUSB_TRANSFER_COMPLETED => {},
+                LIBUSB_TRANSFER_STALL => metrics.stalls += 1,
+                LIBUSB_TRANSFER_CANCELLED => metrics.cancelled += 1,
+                _ => metrics.errors += 1,
+            }
+        });
+    }
+
+    fn update<F>(&self, endpoint: u8, update: F) where F: FnOnce(&mut EndpointMetrics) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let metrics = state.endpoints.entry(endpoint)
+            .or_insert_with(|| EndpointMetrics { endpoint, ..EndpointMetrics::default() });
+        update(metrics);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_records_endpoint_metrics() {
+        let metrics = Metrics::new();
+        metrics.set_enabled(true);
+
+        metrics.submitted(0x81);
+        metrics.submitted(0x81);
+        metrics.completed(0x81, LIBUSB_TRANSFER_COMPLETED, 512, Duration::from_micros(100));
+        metrics.completed(0x81, LIBUSB_TRANSFER_STALL, 0, Duration::from_millis(3));
+        metrics.submitted(0x02);
+        metrics.submit_failed(0x02);
+
+        let snapshot = metrics.snapshot();
+        let bulk_in = snapshot.endpoint(0x81).unwrap();
+        assert_eq!((2, 512, 1, 0), (bulk_in.transfers, bulk_in.bytes, bulk_in.stalls, bulk_in.errors));
+        assert_eq!((0, 2), (bulk_in.in_flight, bulk_in.max_in_flight));
+        assert_eq!(Some(Duration::from_micros(125)), bulk_in.latency.percentile(50.0));
+        assert_eq!(Some(Duration::from_millis(4)), bulk_in.latency.percentile(100.0));
+        assert_eq!(1, snapshot.endpoint(0x02).unwrap().errors);
+
+        metrics.set_enabled(true);
+        assert!(metrics.snapshot().endpoints.is_empty());
+    }
+}
@@ -0,0 +1,431 @@
+//! USB MIDI 1.0 class support.
+//!
+//! MIDI data is carried in 4-byte event packets. The first byte holds the virtual cable number
+//! and a code index number (CIN) that tells how many of the remaining three bytes are used.
+//! System exclusive messages are split over several packets.
+
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::task;
+
+use futures_core::Stream;
+use futures_sink::Sink;
+
+use config_descriptor::ConfigDescriptor;
+use device::Device;
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::{ClassCode, Direction, TransferType};
+use transfer::TransferFuture;
+use transfer_queue;
+
+const SUBCLASS_MIDI_STREAMING: u8 = 0x03;
+
+const SYSEX_START: u8 = 0xF0;
+const SYSEX_END: u8 = 0xF7;
+
+/// Number of bytes written in one bulk transfer before the sink flushes by itself.
+const WRITE_SIZE: usize = 512;
+
+/// Finds the first MIDIStreaming interface of a configuration.
+pub fn find_midi_interface(config: &ConfigDescriptor) -> Option<u8> {
+    config.interfaces_of_class(ClassCode::Audio).into_iter()
+        .find(|setting| setting.sub_class_code() == SUBCLASS_MIDI_STREAMING)
+        .map(|setting| setting.interface_number())
+}
+
+/// A complete MIDI message on a virtual cable.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub struct MidiEvent {
+    /// The virtual cable number, 0 to 15.
+    pub cable: u8,
+
+    /// The MIDI message, starting with its status byte. System exclusive messages include
+    /// the `0xF0` and `0xF7` bytes.
+    pub message: Vec<u8>,
+}
+
+/// Returns the number of MIDI bytes in a packet with the given code index number.
+fn packet_length(cin: u8) -> usize {
+    match cin {
+        0x5 | 0xF => 1,
+        0x2 | 0x6 | 0xC | 0xD => 2,
+        0x3 | 0x4 | 0x7 | 0x8 | 0x9 | 0xA | 0xB | 0xE => 3,
+        // Reserved for future extensions
+        _ => 0,
+    }
+}
+
+/// Returns the code index number for a message that isn't system exclusive.
+fn message_cin(message: &[u8]) -> Option<u8> {
+    let status = *message.first()?;
+
+    let cin = match status {
+        0x80..=0xEF => status >> 4,
+        0xF1 | 0xF3 => 0x2,
+        0xF2 => 0x3,
+        0xF6 => 0x5,
+        0xF8..=0xFF => 0xF,
+        _ => return None,
+    };
+
+    if message.len() == packet_length(cin) { Some(cin) } else { None }
+}
+
+/// Encodes a MIDI message as event packets.
+///
+/// Returns `Error::InvalidParam` if the cable number is out of range or the message isn't a
+/// complete MIDI message.
+pub fn encode_event(event: &MidiEvent) -> ::Result<Vec<u8>> {
+    if event.cable > 15 {
+        return Err(Error::InvalidParam);
+    }
+
+    let header = event.cable << 4;
+    let mut packets = Vec::new();
+
+    if event.message.first() == Some(&SYSEX_START) {
+        if event.message.len() < 2 || event.message.last() != Some(&SYSEX_END) {
+            return Err(Error::InvalidParam);
+        }
+
+        let mut chunks = event.message.chunks(3).peekable();
+
+        while let Some(chunk) = chunks.next() {
+            let cin = match (chunks.peek().is_some(), chunk.len()) {
+                (true, _) => 0x4,
+                (false, 1) => 0x5,
+                (false, 2) => 0x6,
+                (false, _) => 0x7,
+            };
+
+            packets.push(header | cin);
+            packets.extend_from_slice(chunk);
+            packets.resize(packets.len() + 3 - chunk.len(), 0);
+        }
+    }
+    else {
+        let cin = message_cin(&event.message).ok_or(Error::InvalidParam)?;
+
+        packets.push(header | cin);
+        packets.extend_from_slice(&event.message);
+        packets.resize(4, 0);
+    }
+
+    Ok(packets)
+}
+
+/// Reassembles MIDI messages from event packets.
+///
+/// System exclusive messages are buffered per cable until their last packet arrives.
+#[derive(Debug,Clone,Default)]
+pub struct MidiDecoder {
+    sysex: [Vec<u8>; 16],
+}
+
+impl MidiDecoder {
+    /// Creates a decoder with no partial messages.
+    pub fn new() -> MidiDecoder {
+        MidiDecoder::default()
+    }
+
+    /// Decodes a buffer of event packets, returning the messages that are complete.
+    ///
+    /// Trailing bytes that don't form a whole packet, empty packets and packets with reserved
+    /// code index numbers are ignored.
+    pub fn decode(&mut self, packets: &[u8]) -> Vec<MidiEvent> {
+        let mut events = Vec::new();
+
+        for packet in packets.chunks_exact(4) {
+            let cable = packet[0] >> 4;
+            let cin = packet[0] & 0x0F;
+            let data = &packet[1..1 + packet_length(cin)];
+            let sysex = &mut self.sysex[cable as usize];
+
+            match cin {
+                0x0 | 0x1 => {},
+                0x4 => sysex.extend_from_slice(data),
+                0x5 if data[0] != SYSEX_END && sysex.is_empty() => {
+                    // Single-byte system common message
+                    events.push(MidiEvent { cable, message: data.to_vec() });
+                },
+                0x5..=0x7 => {
+                    sysex.extend_from_slice(data);
+                    events.push(MidiEvent { cable, message: mem::take(sysex) });
+                },
+                _ => events.push(MidiEvent { cable, message: data.to_vec() }),
+            }
+        }
+
+        events
+    }
+}
+
+/// A claimed MIDIStreaming interface.
+pub struct MidiPort {
+    handle: DeviceHandle,
+    interface: u8,
+    bulk_in: Option<(u8, u16)>,
+    bulk_out: Option<u8>,
+    kernel_driver_detached: bool,
+}
+
+impl MidiPort {
+    /// Opens a device and claims one of its MIDIStreaming interfaces.
+    ///
+    /// A kernel driver bound to the interface is detached and reattached when the port is
+    /// dropped.
+    ///
+    /// ## Errors
+    ///
+    /// * `NotFound` if the active configuration has no such interface, or it has no bulk
+    ///   endpoints.
+    /// * Any error returned while opening the device or claiming the interface.
+    pub fn open(device: &Device, interface: u8) -> ::Result<MidiPort> {
+        let (bulk_in, bulk_out) = {
+            let config = device.active_config_descriptor()?;
+            let setting = config.interfaces()
+                .find(|i| i.number() == interface)
+                .and_then(|i| i.descriptors().next())
+                .ok_or(Error::NotFound)?;
+
+            let bulk_in = setting.first_endpoint(TransferType::Bulk, Direction::In).map(|ep| (ep.address(), ep.max_packet_size()));
+            let bulk_out = setting.first_endpoint(TransferType::Bulk, Direction::Out).map(|ep| ep.address());
+
+            if bulk_in.is_none() && bulk_out.is_none() {
+                return Err(Error::NotFound);
+            }
+
+            (bulk_in, bulk_out)
+        };
+
+        let mut handle = device.open()?;
+
+        let kernel_driver_detached = handle.kernel_driver_active(interface).unwrap_or(false);
+        if kernel_driver_detached {
+            handle.detach_kernel_driver(interface)?;
+        }
+
+        handle.claim_interface(interface)?;
+
+        Ok(MidiPort { handle, interface, bulk_in, bulk_out, kernel_driver_detached })
+    }
+
+    /// Returns the handle of the device.
+    pub fn handle(&self) -> &DeviceHandle {
+        &self.handle
+    }
+
+    /// Returns the number of the claimed interface.
+    pub fn interface_number(&self) -> u8 {
+        self.interface
+    }
+
+    /// Returns a stream of received MIDI messages.
+    ///
+    /// The stream yields `Err(NotFound)` if the interface has no bulk IN endpoint, and ends
+    /// after the first error.
+    pub fn events<'a>(&'a self) -> MidiEvents<'a> {
+        MidiEvents { port: self, decoder: MidiDecoder::new(), events: Vec::new(), pending: None, done: false }
+    }
+
+    /// Returns a sink that sends MIDI messages.
+    ///
+    /// Messages are buffered until the sink is flushed or the buffer is full. Sending fails
+    /// with `NotFound` if the interface has no bulk OUT endpoint.
+    pub fn sink<'a>(&'a self) -> MidiSink<'a> {
+        MidiSink { port: self, buffer: Vec::new(), pending: None }
+    }
+
+    fn submit_read(&self) -> ::Result<TransferFuture> {
+        let (endpoint, packet_size) = self.bulk_in.ok_or(Error::NotFound)?;
+        transfer_queue::submit_bulk_read(&self.handle, endpoint.into(), packet_size as usize)
+    }
+
+    fn submit_write(&self, data: &[u8]) -> ::Result<TransferFuture> {
+        let endpoint = self.bulk_out.ok_or(Error::NotFound)?;
+        transfer_queue::submit_bulk_write(&self.handle, endpoint.into(), data)
+    }
+}
+
+impl Drop for MidiPort {
+    fn drop(&mut self) {
+        if self.kernel_driver_detached {
+            let _ = self.handle.release_interface(self.interface);
+            let _ = self.handle.attach_kernel_driver(self.interface);
+        }
+    }
+}
+
+/// Stream of MIDI messages returned by [`MidiPort::events`](struct.MidiPort.html#method.events).
+pub struct MidiEvents<'a> {
+    port: &'a MidiPort,
+    decoder: MidiDecoder,
+    events: Vec<MidiEvent>,
+    pending: Option<TransferFuture>,
+    done: bool,
+}
+
+impl<'a> Stream for MidiEvents<'a> {
+    type Item = ::Result<MidiEvent>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if !this.events.is_empty() {
+                return task::Poll::Ready(Some(Ok(this.events.remove(0))));
+            }
+
+            if this.done {
+                return task::Poll::Ready(None);
+            }
+
+            if this.pending.is_none() {
+                match this.port.submit_read() {
+                    Ok(future) => this.pending = Some(future),
+                    Err(e) => {
+                        this.done = true;
+                        return task::Poll::Ready(Some(Err(e)));
+                    },
+                }
+            }
+
+            let result = match this.pending {
+                Some(ref mut future) => match Pin::new(future).poll(cx) {
+                    task::Poll::Pending => return task::Poll::Pending,
+                    task::Poll::Ready(result) => result,
+                },
+                None => unreachable!(),
+            };
+
+            this.pending = None;
+
+            match result.and_then(|transfer| transfer.check_status().map(|_| transfer)) {
+                Ok(transfer) => {
+                    this.events = this.decoder.decode(transfer.get_buffer());
+                    // Resubmit before handing out the events to keep the endpoint polled
+                    this.pending = this.port.submit_read().ok();
+                },
+                Err(e) => {
+                    this.done = true;
+                    return task::Poll::Ready(Some(Err(e)));
+                },
+            }
+        }
+    }
+}
+
+/// Sink of MIDI messages returned by [`MidiPort::sink`](struct.MidiPort.html#method.sink).
+pub struct MidiSink<'a> {
+    port: &'a MidiPort,
+    buffer: Vec<u8>,
+    pending: Option<TransferFuture>,
+}
+
+impl<'a> MidiSink<'a> {
+    fn poll_write(&mut self, cx: &mut task::Context) -> task::Poll<::Result<()>> {
+        loop {
+            if let Some(ref mut future) = self.pending {
+                let result = match Pin::new(future).poll(cx) {
+                    task::Poll::Pending => return task::Poll::Pending,
+                    task::Poll::Ready(result) => result,
+                };
+
+                self.pending = None;
+                result.and_then(|transfer| transfer.check_status())?;
+            }
+
+            if self.buffer.is_empty() {
+                return task::Poll::Ready(Ok(()));
+            }
+
+            self.pending = Some(self.port.submit_write(&self.buffer)?);
+            self.buffer.clear();
+        }
+    }
+}
+
+impl<'a> Sink<MidiEvent> for MidiSink<'a> {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<::Result<()>> {
+        let this = self.get_mut();
+
+        if this.buffer.len() < WRITE_SIZE {
+            task::Poll::Ready(Ok(()))
+        }
+        else {
+            this.poll_write(cx)
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, event: MidiEvent) -> ::Result<()> {
+        let packets = encode_event(&event)?;
+        self.get_mut().buffer.extend_from_slice(&packets);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<::Result<()>> {
+        self.get_mut().poll_write(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<::Result<()>> {
+        self.get_mut().poll_write(cx)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event(cable: u8, message: &[u8]) -> MidiEvent {
+        MidiEvent { cable, message: message.to_vec() }
+    }
+
+    #[test]
+    fn it_encodes_channel_messages() {
+        assert_eq!(vec![0x19, 0x90, 0x3C, 0x7F], encode_event(&event(1, &[0x90, 0x3C, 0x7F])).unwrap());
+        assert_eq!(vec![0x0C, 0xC0, 0x05, 0x00], encode_event(&event(0, &[0xC0, 0x05])).unwrap());
+        assert_eq!(vec![0x0F, 0xF8, 0x00, 0x00], encode_event(&event(0, &[0xF8])).unwrap());
+    }
+
+    #[test]
+    fn it_splits_sysex_over_packets() {
+        let packets = encode_event(&event(2, &[0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7])).unwrap();
+        assert_eq!(vec![0x24, 0xF0, 0x7E, 0x7F, 0x27, 0x06, 0x01, 0xF7], packets);
+    }
+
+    #[test]
+    fn it_rejects_incomplete_messages() {
+        assert!(encode_event(&event(0, &[0x90, 0x3C])).is_err());
+        assert!(encode_event(&event(0, &[0xF0, 0x01])).is_err());
+        assert!(encode_event(&event(16, &[0xF8])).is_err());
+    }
+
+    #[test]
+    fn it_decodes_messages_on_each_cable() {
+        let mut decoder = MidiDecoder::new();
+        let events = decoder.decode(&[0x08, 0x80, 0x3C, 0x00, 0x1B, 0xB0, 0x07, 0x64, 0x00, 0x00, 0x00, 0x00]);
+
+        assert_eq!(vec![event(0, &[0x80, 0x3C, 0x00]), event(1, &[0xB0, 0x07, 0x64])], events);
+    }
+
+    #[test]
+    fn it_reassembles_sysex_across_buffers() {
+        let mut decoder = MidiDecoder::new();
+
+        assert!(decoder.decode(&[0x04, 0xF0, 0x43, 0x10, 0x04, 0x4C, 0x00, 0x00]).is_empty());
+        assert_eq!(vec![event(0, &[0xF0, 0x43, 0x10, 0x4C, 0x00, 0x00, 0xF7])], decoder.decode(&[0x05, 0xF7, 0x00, 0x00]));
+    }
+
+    #[test]
+    fn it_round_trips_sysex() {
+        let sysex = event(3, &[0xF0, 0x01, 0x02, 0x03, 0xF7]);
+        let packets = encode_event(&sysex).unwrap();
+
+        assert_eq!(vec![sysex], MidiDecoder::new().decode(&packets));
+    }
+}
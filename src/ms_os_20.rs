@@ -0,0 +1,404 @@
+use bos::MsOs20DescriptorSetInfo;
+use control::{self, ControlFuture};
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::{Direction, RequestType, Recipient, request_type};
+
+const MS_OS_20_DESCRIPTOR_INDEX: u16 = 0x07;
+
+const SET_HEADER_DESCRIPTOR: u16 = 0x00;
+const SUBSET_HEADER_CONFIGURATION: u16 = 0x01;
+const SUBSET_HEADER_FUNCTION: u16 = 0x02;
+const FEATURE_COMPATIBLE_ID: u16 = 0x03;
+const FEATURE_REG_PROPERTY: u16 = 0x04;
+const FEATURE_MIN_RESUME_TIME: u16 = 0x05;
+const FEATURE_MODEL_ID: u16 = 0x06;
+const FEATURE_CCGP_DEVICE: u16 = 0x07;
+const FEATURE_VENDOR_REVISION: u16 = 0x08;
+
+/// A Microsoft OS 2.0 descriptor set.
+///
+/// Windows reads the set to decide, among other things, which driver (e.g., WinUSB) binds to
+/// the device or its functions.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub struct MsOs20DescriptorSet {
+    /// Minimum Windows version the set applies to (`dwWindowsVersion`).
+    pub windows_version: u32,
+
+    /// Features that apply to the whole device.
+    pub features: Vec<MsOs20Feature>,
+
+    /// Configuration subsets.
+    pub configurations: Vec<MsOs20ConfigurationSubset>,
+}
+
+/// The features of one configuration of a device.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub struct MsOs20ConfigurationSubset {
+    /// The configuration the subset applies to. This is the configuration index, not
+    /// `bConfigurationValue`, despite the field's name in the specification.
+    pub configuration: u8,
+
+    /// Features that apply to the whole configuration.
+    pub features: Vec<MsOs20Feature>,
+
+    /// Function subsets.
+    pub functions: Vec<MsOs20FunctionSubset>,
+}
+
+/// The features of one function of a composite device.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub struct MsOs20FunctionSubset {
+    /// The first interface of the function.
+    pub first_interface: u8,
+
+    /// Features that apply to the function.
+    pub features: Vec<MsOs20Feature>,
+}
+
+/// A Microsoft OS 2.0 feature descriptor.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub enum MsOs20Feature {
+    /// Compatible ID, e.g., `WINUSB`, used to select a driver.
+    CompatibleId {
+        /// The compatible ID.
+        compatible_id: String,
+
+        /// The sub-compatible ID.
+        sub_compatible_id: String,
+    },
+
+    /// A registry property added to the device's hardware key.
+    RegistryProperty {
+        /// The property name, e.g., `DeviceInterfaceGUIDs`.
+        name: String,
+
+        /// The property value.
+        value: RegistryValue,
+    },
+
+    /// Minimum USB resume time.
+    MinResumeTime {
+        /// Time in milliseconds the device needs to recover after resume.
+        recovery_time: u8,
+
+        /// Time in milliseconds the host must signal resume.
+        signaling_time: u8,
+    },
+
+    /// Model ID that identifies the physical device across its functions.
+    ModelId([u8; 16]),
+
+    /// The device should be treated as a composite device.
+    CcgpDevice,
+
+    /// Revision of the descriptor set, used to invalidate cached sets.
+    VendorRevision(u16),
+
+    /// A feature descriptor this crate doesn't decode.
+    Unknown {
+        /// The `wDescriptorType` field.
+        descriptor_type: u16,
+
+        /// The bytes following `wDescriptorType`.
+        data: Vec<u8>,
+    },
+}
+
+/// The value of a registry property feature.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub enum RegistryValue {
+    /// `REG_SZ`.
+    String(String),
+
+    /// `REG_EXPAND_SZ`.
+    ExpandString(String),
+
+    /// `REG_BINARY`.
+    Binary(Vec<u8>),
+
+    /// `REG_DWORD_LITTLE_ENDIAN`.
+    Dword(u32),
+
+    /// `REG_DWORD_BIG_ENDIAN`.
+    DwordBigEndian(u32),
+
+    /// `REG_LINK`.
+    Link(String),
+
+    /// `REG_MULTI_SZ`.
+    MultiString(Vec<String>),
+
+    /// A value of another type.
+    Other {
+        /// The `wPropertyDataType` field.
+        data_type: u16,
+
+        /// The raw property data.
+        data: Vec<u8>,
+    },
+}
+
+/// Parses a Microsoft OS 2.0 descriptor set.
+///
+/// `bytes` is the response to the vendor request described by a
+/// [`MsOs20DescriptorSetInfo`](struct.MsOs20DescriptorSetInfo.html). Returns
+/// `Error::InvalidParam` if the set header is missing or a descriptor is malformed.
+pub fn parse_ms_os_20_descriptor_set(bytes: &[u8]) -> ::Result<MsOs20DescriptorSet> {
+    if bytes.len() < 10 || read_u16(bytes, 0) != 10 || read_u16(bytes, 2) != SET_HEADER_DESCRIPTOR {
+        return Err(Error::InvalidParam);
+    }
+
+    let total_length = (read_u16(bytes, 8) as usize).min(bytes.len());
+
+    let mut set = MsOs20DescriptorSet {
+        windows_version: read_u32(bytes, 4),
+        features: Vec::new(),
+        configurations: Vec::new(),
+    };
+
+    let mut offset = 10;
+
+    while offset + 4 <= total_length {
+        let length = read_u16(bytes, offset) as usize;
+
+        if length < 4 || offset + length > total_length {
+            return Err(Error::InvalidParam);
+        }
+
+        let descriptor_type = read_u16(bytes, offset + 2);
+        let data = &bytes[offset + 4..offset + length];
+        offset += length;
+
+        match descriptor_type {
+            SUBSET_HEADER_CONFIGURATION if data.len() >= 4 => {
+                set.configurations.push(MsOs20ConfigurationSubset {
+                    configuration: data[0],
+                    features: Vec::new(),
+                    functions: Vec::new(),
+                });
+            },
+            SUBSET_HEADER_FUNCTION if data.len() >= 4 => {
+                let function = MsOs20FunctionSubset { first_interface: data[0], features: Vec::new() };
+
+                match set.configurations.last_mut() {
+                    Some(configuration) => configuration.functions.push(function),
+                    None => return Err(Error::InvalidParam),
+                }
+            },
+            SET_HEADER_DESCRIPTOR | SUBSET_HEADER_CONFIGURATION | SUBSET_HEADER_FUNCTION => {
+                return Err(Error::InvalidParam);
+            },
+            _ => {
+                let feature = parse_feature(descriptor_type, data)?;
+
+                // Features belong to the innermost subset that precedes them
+                let features = match set.configurations.last_mut() {
+                    Some(configuration) => match configuration.functions.last_mut() {
+                        Some(function) => &mut function.features,
+                        None => &mut configuration.features,
+                    },
+                    None => &mut set.features,
+                };
+
+                features.push(feature);
+            },
+        }
+    }
+
+    Ok(set)
+}
+
+fn parse_feature(descriptor_type: u16, data: &[u8]) -> ::Result<MsOs20Feature> {
+    let feature = match descriptor_type {
+        FEATURE_COMPATIBLE_ID if data.len() >= 16 => {
+            MsOs20Feature::CompatibleId {
+                compatible_id: ascii_id(&data[..8]),
+                sub_compatible_id: ascii_id(&data[8..16]),
+            }
+        },
+        FEATURE_REG_PROPERTY if data.len() >= 4 => {
+            let data_type = read_u16(data, 0);
+            let name_length = read_u16(data, 2) as usize;
+
+            if data.len() < 6 + name_length {
+                return Err(Error::InvalidParam);
+            }
+
+            let name = utf16_string(&data[4..4 + name_length]);
+            let value_length = read_u16(data, 4 + name_length) as usize;
+            let value = data.get(6 + name_length..6 + name_length + value_length).ok_or(Error::InvalidParam)?;
+
+            MsOs20Feature::RegistryProperty { name, value: registry_value(data_type, value) }
+        },
+        FEATURE_MIN_RESUME_TIME if data.len() >= 2 => {
+            MsOs20Feature::MinResumeTime { recovery_time: data[0], signaling_time: data[1] }
+        },
+        FEATURE_MODEL_ID if data.len() >= 16 => {
+            let mut model_id = [0; 16];
+            model_id.copy_from_slice(&data[..16]);
+            MsOs20Feature::ModelId(model_id)
+        },
+        FEATURE_CCGP_DEVICE => MsOs20Feature::CcgpDevice,
+        FEATURE_VENDOR_REVISION if data.len() >= 2 => {
+            MsOs20Feature::VendorRevision(read_u16(data, 0))
+        },
+        FEATURE_COMPATIBLE_ID | FEATURE_REG_PROPERTY | FEATURE_MIN_RESUME_TIME | FEATURE_MODEL_ID
+            | FEATURE_VENDOR_REVISION => {
+            return Err(Error::InvalidParam);
+        },
+        _ => MsOs20Feature::Unknown { descriptor_type, data: data.to_vec() },
+    };
+
+    Ok(feature)
+}
+
+fn registry_value(data_type: u16, data: &[u8]) -> RegistryValue {
+    match data_type {
+        1 => RegistryValue::String(utf16_string(data)),
+        2 => RegistryValue::ExpandString(utf16_string(data)),
+        3 => RegistryValue::Binary(data.to_vec()),
+        4 if data.len() == 4 => RegistryValue::Dword(read_u32(data, 0)),
+        5 if data.len() == 4 => RegistryValue::DwordBigEndian(u32::from_be_bytes([data[0], data[1], data[2], data[3]])),
+        6 => RegistryValue::Link(utf16_string(data)),
+        7 => {
+            let units = utf16_units(data);
+
+            RegistryValue::MultiString(units.split(|&unit| unit == 0)
+                .filter(|string| !string.is_empty())
+                .map(String::from_utf16_lossy)
+                .collect())
+        },
+        _ => RegistryValue::Other { data_type, data: data.to_vec() },
+    }
+}
+
+fn utf16_units(data: &[u8]) -> Vec<u16> {
+    data.chunks(2).filter(|chunk| chunk.len() == 2).map(|chunk| chunk[0] as u16 | (chunk[1] as u16) << 8).collect()
+}
+
+/// Decodes a null-terminated UTF-16LE string.
+fn utf16_string(data: &[u8]) -> String {
+    let units = utf16_units(data);
+    let end = units.iter().position(|&unit| unit == 0).unwrap_or(units.len());
+
+    String::from_utf16_lossy(&units[..end])
+}
+
+/// Decodes a null-padded ASCII identifier.
+fn ascii_id(data: &[u8]) -> String {
+    data.iter().take_while(|&&byte| byte != 0).map(|&byte| byte as char).collect()
+}
+
+#[doc(hidden)]
+pub fn read_set(handle: &DeviceHandle, info: &MsOs20DescriptorSetInfo) -> ControlFuture<MsOs20DescriptorSet> {
+    control::read(handle,
+                  request_type(Direction::In, RequestType::Vendor, Recipient::Device),
+                  info.vendor_code,
+                  0,
+                  MS_OS_20_DESCRIPTOR_INDEX,
+                  info.total_length,
+                  parse_ms_os_20_descriptor_set)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    bytes[offset] as u16 | (bytes[offset + 1] as u16) << 8
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    read_u16(bytes, offset) as u32 | (read_u16(bytes, offset + 2) as u32) << 16
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn utf16(s: &str) -> Vec<u8> {
+        s.encode_utf16().chain(Some(0)).flat_map(|unit| vec![unit as u8, (unit >> 8) as u8]).collect()
+    }
+
+    fn descriptor(descriptor_type: u16, data: &[u8]) -> Vec<u8> {
+        let length = 4 + data.len();
+        let mut bytes = vec![length as u8, (length >> 8) as u8, descriptor_type as u8, 0];
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn set(descriptors: &[Vec<u8>]) -> Vec<u8> {
+        let total = 10 + descriptors.iter().map(|d| d.len()).sum::<usize>();
+        let mut bytes = vec![0x0A, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x06, total as u8, (total >> 8) as u8];
+
+        for descriptor in descriptors {
+            bytes.extend_from_slice(descriptor);
+        }
+
+        bytes
+    }
+
+    fn compatible_id(id: &[u8]) -> Vec<u8> {
+        let mut data = [0; 16];
+        data[..id.len()].copy_from_slice(id);
+        descriptor(0x03, &data)
+    }
+
+    fn registry_property(data_type: u16, name: &str, value: &[u8]) -> Vec<u8> {
+        let name = utf16(name);
+        let mut data = vec![data_type as u8, 0, name.len() as u8, 0];
+        data.extend_from_slice(&name);
+        data.extend_from_slice(&[value.len() as u8, 0]);
+        data.extend_from_slice(value);
+        descriptor(0x04, &data)
+    }
+
+    #[test]
+    fn it_parses_device_level_features() {
+        let bytes = set(&[compatible_id(b"WINUSB"), descriptor(0x08, &[0x01, 0x00])]);
+        let set = parse_ms_os_20_descriptor_set(&bytes).unwrap();
+
+        assert_eq!(0x06030000, set.windows_version);
+        assert_eq!(vec![
+            MsOs20Feature::CompatibleId { compatible_id: "WINUSB".to_owned(), sub_compatible_id: String::new() },
+            MsOs20Feature::VendorRevision(1),
+        ], set.features);
+    }
+
+    #[test]
+    fn it_assigns_features_to_function_subsets() {
+        let guid = "{CDB3B5AD-293B-4663-AA36-1AAE46463776}";
+        let bytes = set(&[
+            descriptor(0x01, &[0x00, 0x00, 0x00, 0x00]),
+            descriptor(0x02, &[0x02, 0x00, 0x00, 0x00]),
+            compatible_id(b"WINUSB"),
+            registry_property(7, "DeviceInterfaceGUIDs", &[utf16(guid), vec![0, 0]].concat()),
+        ]);
+        let set = parse_ms_os_20_descriptor_set(&bytes).unwrap();
+        let function = &set.configurations[0].functions[0];
+
+        assert_eq!(2, function.first_interface);
+        assert_eq!(MsOs20Feature::RegistryProperty {
+            name: "DeviceInterfaceGUIDs".to_owned(),
+            value: RegistryValue::MultiString(vec![guid.to_owned()]),
+        }, function.features[1]);
+    }
+
+    #[test]
+    fn it_decodes_dword_property() {
+        let bytes = set(&[registry_property(4, "DeviceIdleEnabled", &[0x01, 0x00, 0x00, 0x00])]);
+
+        assert_eq!(MsOs20Feature::RegistryProperty {
+            name: "DeviceIdleEnabled".to_owned(),
+            value: RegistryValue::Dword(1),
+        }, parse_ms_os_20_descriptor_set(&bytes).unwrap().features[0]);
+    }
+
+    #[test]
+    fn it_rejects_function_subset_without_configuration() {
+        assert!(parse_ms_os_20_descriptor_set(&set(&[descriptor(0x02, &[0x00, 0x00, 0x00, 0x00])])).is_err());
+    }
+
+    #[test]
+    fn it_rejects_missing_header() {
+        assert!(parse_ms_os_20_descriptor_set(&compatible_id(b"WINUSB")).is_err());
+    }
+}
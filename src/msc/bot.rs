@@ -0,0 +1,508 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::task;
+
+use config_descriptor::ConfigDescriptor;
+use control::{self, ControlFuture};
+use device::Device;
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::{ClassCode, Direction, Recipient, RequestType, TransferType, request_type};
+use transfer::{CompletedTransfer, TransferFuture, TransferStatus};
+
+const SUBCLASS_SCSI: u8 = 0x06;
+const PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+const BULK_ONLY_RESET: u8 = 0xFF;
+const GET_MAX_LUN: u8 = 0xFE;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CBW_LENGTH: usize = 31;
+const CSW_LENGTH: usize = 13;
+
+/// Finds the first Bulk-Only Transport interface of a configuration that uses the SCSI
+/// transparent command set.
+pub fn find_bulk_only_interface(config: &ConfigDescriptor) -> Option<u8> {
+    config.find_interface(ClassCode::MassStorage, SUBCLASS_SCSI, PROTOCOL_BULK_ONLY)
+        .map(|setting| setting.interface_number())
+}
+
+/// A Command Block Wrapper, which starts each Bulk-Only Transport command.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub struct CommandBlockWrapper {
+    /// Tag that associates the command with its status.
+    pub tag: u32,
+
+    /// Number of bytes expected in the data stage.
+    pub data_transfer_length: u32,
+
+    /// Direction of the data stage.
+    pub direction: Direction,
+
+    /// Logical unit the command is sent to.
+    pub lun: u8,
+
+    /// The command block, at most 16 bytes.
+    pub command: Vec<u8>,
+}
+
+impl CommandBlockWrapper {
+    /// Encodes the wrapper as sent on the bulk OUT endpoint.
+    ///
+    /// Returns `Error::InvalidParam` if the command block is empty or longer than 16 bytes.
+    pub fn encode(&self) -> ::Result<[u8; CBW_LENGTH]> {
+        if self.command.is_empty() || self.command.len() > 16 {
+            return Err(Error::InvalidParam);
+        }
+
+        let mut bytes = [0; CBW_LENGTH];
+        bytes[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.data_transfer_length.to_le_bytes());
+        bytes[12] = match self.direction {
+            Direction::In => 0x80,
+            Direction::Out => 0x00,
+        };
+        bytes[13] = self.lun & 0x0F;
+        bytes[14] = self.command.len() as u8;
+        bytes[15..15 + self.command.len()].copy_from_slice(&self.command);
+
+        Ok(bytes)
+    }
+}
+
+/// Status of a Bulk-Only Transport command.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum CommandStatus {
+    /// The command succeeded.
+    Passed,
+
+    /// The command failed. The sense data tells why.
+    Failed,
+
+    /// The host and device disagree about the command's data stage. The device must be reset.
+    PhaseError,
+}
+
+/// A Command Status Wrapper, which ends each Bulk-Only Transport command.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct CommandStatusWrapper {
+    /// Tag of the command.
+    pub tag: u32,
+
+    /// Difference between the expected and actual data stage length.
+    pub data_residue: u32,
+
+    /// The command status.
+    pub status: CommandStatus,
+}
+
+impl CommandStatusWrapper {
+    /// Decodes a wrapper as received on the bulk IN endpoint.
+    ///
+    /// Returns `Error::InvalidParam` if the wrapper has the wrong length, signature or an
+    /// unknown status.
+    pub fn decode(bytes: &[u8]) -> ::Result<CommandStatusWrapper> {
+        if bytes.len() != CSW_LENGTH || read_u32(bytes, 0) != CSW_SIGNATURE {
+            return Err(Error::InvalidParam);
+        }
+
+        let status = match bytes[12] {
+            0 => CommandStatus::Passed,
+            1 => CommandStatus::Failed,
+            2 => CommandStatus::PhaseError,
+            _ => return Err(Error::InvalidParam),
+        };
+
+        Ok(CommandStatusWrapper { tag: read_u32(bytes, 4), data_residue: read_u32(bytes, 8), status })
+    }
+}
+
+/// The data stage of a command.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub enum DataTransfer {
+    /// The command has no data stage.
+    None,
+
+    /// The command reads up to this many bytes.
+    In(usize),
+
+    /// The command writes these bytes.
+    Out(Vec<u8>),
+}
+
+/// The outcome of a command that completed its status stage.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub struct CommandResult {
+    /// Either `Passed` or `Failed`. Phase errors resolve to an error instead.
+    pub status: CommandStatus,
+
+    /// Difference between the expected and actual data stage length.
+    pub data_residue: u32,
+
+    /// Data read by the command. Empty for commands without an IN data stage.
+    pub data: Vec<u8>,
+}
+
+/// A claimed Bulk-Only Transport mass storage interface.
+pub struct MassStorage {
+    handle: DeviceHandle,
+    interface: u8,
+    bulk_in: u8,
+    bulk_out: u8,
+    tag: AtomicU32,
+    kernel_driver_detached: bool,
+}
+
+impl MassStorage {
+    /// Opens a device and claims its mass storage interface.
+    ///
+    /// A kernel driver bound to the interface is detached and reattached when the
+    /// `MassStorage` is dropped. Note that detaching the kernel driver unmounts any file
+    /// systems on the device.
+    ///
+    /// ## Errors
+    ///
+    /// * `NotFound` if the active configuration has no such interface, or it lacks a bulk IN
+    ///   or bulk OUT endpoint.
+    /// * Any error returned while opening the device or claiming the interface.
+    pub fn open(device: &Device, interface: u8) -> ::Result<MassStorage> {
+        let (bulk_in, bulk_out) = {
+            let config = device.active_config_descriptor()?;
+            let setting = config.interfaces()
+                .find(|i| i.number() == interface)
+                .and_then(|i| i.descriptors().next())
+                .ok_or(Error::NotFound)?;
+
+            let bulk_in = setting.first_endpoint(TransferType::Bulk, Direction::In).ok_or(Error::NotFound)?;
+            let bulk_out = setting.first_endpoint(TransferType::Bulk, Direction::Out).ok_or(Error::NotFound)?;
+
+            (bulk_in.address(), bulk_out.address())
+        };
+
+        let mut handle = device.open()?;
+
+        let kernel_driver_detached = handle.kernel_driver_active(interface).unwrap_or(false);
+        if kernel_driver_detached {
+            handle.detach_kernel_driver(interface)?;
+        }
+
+        handle.claim_interface(interface)?;
+
+        Ok(MassStorage { handle, interface, bulk_in, bulk_out, tag: AtomicU32::new(1), kernel_driver_detached })
+    }
+
+    /// Returns the handle of the device.
+    pub fn handle(&self) -> &DeviceHandle {
+        &self.handle
+    }
+
+    /// Returns the number of the claimed interface.
+    pub fn interface_number(&self) -> u8 {
+        self.interface
+    }
+
+    /// Reads the highest logical unit number of the device.
+    ///
+    /// Devices with a single logical unit may stall the request, in which case the future
+    /// resolves to `Err(Error::Pipe)` and the maximum LUN is 0.
+    pub fn max_lun(&self) -> ControlFuture<u8> {
+        control::read(&self.handle,
+                      request_type(Direction::In, RequestType::Class, Recipient::Interface),
+                      GET_MAX_LUN,
+                      0,
+                      self.interface as u16,
+                      1,
+                      |data| data.first().cloned().ok_or(Error::Io))
+    }
+
+    /// Sends a Bulk-Only Mass Storage Reset request.
+    ///
+    /// This only resets the interface. Use [`command`](#method.command) for commands, which
+    /// performs the full reset recovery by itself when needed.
+    pub fn reset(&self) -> ControlFuture<usize> {
+        control::write(&self.handle,
+                       request_type(Direction::Out, RequestType::Class, Recipient::Interface),
+                       BULK_ONLY_RESET,
+                       0,
+                       self.interface as u16,
+                       &[])
+    }
+
+    /// Sends a command and runs its data and status stages.
+    ///
+    /// A stalled data stage is cleared and the status is read as usual. A stalled status stage
+    /// is cleared and retried once. If the device reports a phase error or returns an invalid
+    /// status, reset recovery is performed and the future resolves to `Err(Error::Io)`.
+    pub fn command<'a>(&'a self, lun: u8, command: &[u8], data: DataTransfer) -> CommandFuture<'a> {
+        let tag = self.tag.fetch_add(1, Ordering::Relaxed);
+
+        let (direction, length, out_data) = match data {
+            DataTransfer::None => (Direction::Out, 0, Vec::new()),
+            DataTransfer::In(length) => (Direction::In, length, Vec::new()),
+            DataTransfer::Out(data) => (Direction::Out, data.len(), data),
+        };
+
+        let cbw = CommandBlockWrapper {
+            tag,
+            data_transfer_length: length as u32,
+            direction,
+            lun,
+            command: command.to_vec(),
+        };
+
+        let state = match cbw.encode() {
+            Ok(bytes) => submit(self.bulk_write(&bytes), State::Command),
+            Err(e) => State::Failed(e),
+        };
+
+        CommandFuture { storage: self, tag, direction, length, out_data, data: Vec::new(), state }
+    }
+
+    fn bulk_read(&self, length: usize) -> ::Result<TransferFuture> {
+        let mut transfer = self.handle.alloc_transfer(0)?;
+        transfer.fill_bulk_read(self.bulk_in.into(), length)?;
+        Ok(transfer.submit())
+    }
+
+    fn bulk_write(&self, data: &[u8]) -> ::Result<TransferFuture> {
+        let mut transfer = self.handle.alloc_transfer(0)?;
+        transfer.fill_bulk_write(self.bulk_out.into(), data)?;
+        Ok(transfer.submit())
+    }
+}
+
+impl Drop for MassStorage {
+    fn drop(&mut self) {
+        if self.kernel_driver_detached {
+            let _ = self.handle.release_interface(self.interface);
+            let _ = self.handle.attach_kernel_driver(self.interface);
+        }
+    }
+}
+
+/// Future that resolves to the result of a command sent by
+/// [`MassStorage::command`](struct.MassStorage.html#method.command).
+pub struct CommandFuture<'a> {
+    storage: &'a MassStorage,
+    tag: u32,
+    direction: Direction,
+    length: usize,
+    out_data: Vec<u8>,
+    data: Vec<u8>,
+    state: State,
+}
+
+enum State {
+    Failed(Error),
+    Command(TransferFuture),
+    Data(TransferFuture),
+    Status(TransferFuture, bool),
+    Reset(ControlFuture<usize>),
+    Done,
+}
+
+#[doc(hidden)]
+pub fn failed<'a>(storage: &'a MassStorage, error: Error) -> CommandFuture<'a> {
+    CommandFuture {
+        storage,
+        tag: 0,
+        direction: Direction::Out,
+        length: 0,
+        out_data: Vec::new(),
+        data: Vec::new(),
+        state: State::Failed(error),
+    }
+}
+
+fn submit(transfer: ::Result<TransferFuture>, state: fn(TransferFuture) -> State) -> State {
+    match transfer {
+        Ok(future) => state(future),
+        Err(e) => State::Failed(e),
+    }
+}
+
+fn poll_transfer(future: &mut TransferFuture, cx: &mut task::Context) -> task::Poll<::Result<CompletedTransfer>> {
+    Pin::new(future).poll(cx)
+}
+
+impl<'a> CommandFuture<'a> {
+    fn status_stage(&self, retried: bool) -> State {
+        submit(self.storage.bulk_read(CSW_LENGTH), if retried { retried_status } else { first_status })
+    }
+
+    fn reset_recovery(&self) -> State {
+        State::Reset(self.storage.reset())
+    }
+}
+
+fn first_status(future: TransferFuture) -> State {
+    State::Status(future, false)
+}
+
+fn retried_status(future: TransferFuture) -> State {
+    State::Status(future, true)
+}
+
+impl<'a> Future for CommandFuture<'a> {
+    type Output = ::Result<CommandResult>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            let next = match this.state {
+                State::Failed(ref e) => {
+                    let e = e.clone();
+                    this.state = State::Done;
+                    return task::Poll::Ready(Err(e));
+                },
+                State::Command(ref mut future) => {
+                    let transfer = match poll_transfer(future, cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(transfer) => transfer,
+                    };
+
+                    match transfer.and_then(|transfer| transfer.check_status()) {
+                        Ok(()) if this.length == 0 => this.status_stage(false),
+                        Ok(()) => match this.direction {
+                            Direction::In => submit(this.storage.bulk_read(this.length), State::Data),
+                            Direction::Out => submit(this.storage.bulk_write(&this.out_data), State::Data),
+                        },
+                        // A device that doesn't accept the CBW needs reset recovery
+                        Err(Error::Pipe) => this.reset_recovery(),
+                        Err(e) => State::Failed(e),
+                    }
+                },
+                State::Data(ref mut future) => {
+                    let transfer = match poll_transfer(future, cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(transfer) => transfer,
+                    };
+
+                    match transfer {
+                        Ok(ref transfer) if transfer.get_status() == TransferStatus::Stall => {
+                            let endpoint = match this.direction {
+                                Direction::In => this.storage.bulk_in,
+                                Direction::Out => this.storage.bulk_out,
+                            };
+
+                            match this.storage.handle.clear_halt(endpoint.into()) {
+                                Ok(()) => this.status_stage(false),
+                                Err(e) => State::Failed(e),
+                            }
+                        },
+                        Ok(transfer) => match transfer.check_status() {
+                            Ok(()) => {
+                                if this.direction == Direction::In {
+                                    this.data = transfer.get_buffer().to_vec();
+                                }
+
+                                this.status_stage(false)
+                            },
+                            Err(e) => State::Failed(e),
+                        },
+                        Err(e) => State::Failed(e),
+                    }
+                },
+                State::Status(ref mut future, retried) => {
+                    let transfer = match poll_transfer(future, cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(transfer) => transfer,
+                    };
+
+                    match transfer {
+                        Ok(ref transfer) if transfer.get_status() == TransferStatus::Stall && !retried => {
+                            match this.storage.handle.clear_halt(this.storage.bulk_in.into()) {
+                                Ok(()) => this.status_stage(true),
+                                Err(e) => State::Failed(e),
+                            }
+                        },
+                        Ok(transfer) => match transfer.check_status() {
+                            Ok(()) => match CommandStatusWrapper::decode(transfer.get_buffer()) {
+                                Ok(ref csw) if csw.tag == this.tag && csw.status != CommandStatus::PhaseError => {
+                                    this.state = State::Done;
+
+                                    return task::Poll::Ready(Ok(CommandResult {
+                                        status: csw.status,
+                                        data_residue: csw.data_residue,
+                                        data: ::std::mem::take(&mut this.data),
+                                    }));
+                                },
+                                _ => this.reset_recovery(),
+                            },
+                            Err(Error::Pipe) => this.reset_recovery(),
+                            Err(e) => State::Failed(e),
+                        },
+                        Err(e) => State::Failed(e),
+                    }
+                },
+                State::Reset(ref mut future) => {
+                    let result = match Pin::new(future).poll(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(result) => result,
+                    };
+
+                    let result = result
+                        .and_then(|_| this.storage.handle.clear_halt(this.storage.bulk_in.into()))
+                        .and_then(|_| this.storage.handle.clear_halt(this.storage.bulk_out.into()));
+
+                    State::Failed(result.err().unwrap_or(Error::Io))
+                },
+                State::Done => return task::Poll::Ready(Err(Error::PolledAfterCompletion)),
+            };
+
+            this.state = next;
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_encodes_command_block_wrapper() {
+        let cbw = CommandBlockWrapper {
+            tag: 0x1234_5678,
+            data_transfer_length: 36,
+            direction: Direction::In,
+            lun: 1,
+            command: vec![0x12, 0x00, 0x00, 0x00, 0x24, 0x00],
+        };
+
+        let bytes = cbw.encode().unwrap();
+
+        assert_eq!([0x55, 0x53, 0x42, 0x43, 0x78, 0x56, 0x34, 0x12, 0x24, 0x00, 0x00, 0x00, 0x80, 0x01, 0x06], bytes[..15]);
+        assert_eq!([0x12, 0x00, 0x00, 0x00, 0x24, 0x00], bytes[15..21]);
+        assert!(bytes[21..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn it_rejects_oversized_command_block() {
+        let cbw = CommandBlockWrapper { tag: 1, data_transfer_length: 0, direction: Direction::Out, lun: 0, command: vec![0; 17] };
+        assert!(cbw.encode().is_err());
+    }
+
+    #[test]
+    fn it_decodes_command_status_wrapper() {
+        let bytes = [0x55, 0x53, 0x42, 0x53, 0x02, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x01];
+
+        assert_eq!(CommandStatusWrapper { tag: 2, data_residue: 16, status: CommandStatus::Failed },
+                   CommandStatusWrapper::decode(&bytes).unwrap());
+    }
+
+    #[test]
+    fn it_rejects_invalid_command_status_wrapper() {
+        assert!(CommandStatusWrapper::decode(&[0x55, 0x53, 0x42, 0x43, 0, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        assert!(CommandStatusWrapper::decode(&[0x55, 0x53, 0x42, 0x53, 0, 0, 0, 0, 0, 0, 0, 0, 3]).is_err());
+        assert!(CommandStatusWrapper::decode(&[0x55, 0x53, 0x42, 0x53, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+    }
+}
@@ -0,0 +1,13 @@
+//! Mass storage class support, using the Bulk-Only Transport and SCSI commands.
+//!
+//! Devices that support USB Attached SCSI are driven through the [`uas`](uas/index.html)
+//! module instead.
+
+pub use self::bot::{MassStorage, CommandBlockWrapper, CommandStatusWrapper, CommandStatus, CommandResult,
+                    DataTransfer, CommandFuture, find_bulk_only_interface};
+pub use self::scsi::{InquiryData, Capacity, SenseData, ScsiFuture};
+
+pub mod uas;
+
+mod bot;
+mod scsi;
@@ -0,0 +1,244 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task;
+
+use error::Error;
+
+use super::bot::{self, MassStorage, CommandFuture, CommandStatus, DataTransfer};
+
+pub const TEST_UNIT_READY: u8 = 0x00;
+pub const REQUEST_SENSE: u8 = 0x03;
+pub const INQUIRY: u8 = 0x12;
+pub const READ_CAPACITY_10: u8 = 0x25;
+pub const READ_10: u8 = 0x28;
+pub const WRITE_10: u8 = 0x2A;
+
+pub const INQUIRY_LENGTH: u8 = 36;
+pub const SENSE_LENGTH: u8 = 18;
+
+/// Standard INQUIRY data.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub struct InquiryData {
+    /// Peripheral device type, e.g., 0x00 for a direct access block device.
+    pub device_type: u8,
+
+    /// Indicates if the medium is removable.
+    pub removable: bool,
+
+    /// Vendor identification, with trailing spaces removed.
+    pub vendor: String,
+
+    /// Product identification, with trailing spaces removed.
+    pub product: String,
+
+    /// Product revision level, with trailing spaces removed.
+    pub revision: String,
+}
+
+impl InquiryData {
+    /// Decodes standard INQUIRY data.
+    ///
+    /// Returns `Error::InvalidParam` if the data is shorter than 36 bytes.
+    pub fn decode(data: &[u8]) -> ::Result<InquiryData> {
+        if data.len() < INQUIRY_LENGTH as usize {
+            return Err(Error::InvalidParam);
+        }
+
+        let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).trim_end().to_owned();
+
+        Ok(InquiryData {
+            device_type: data[0] & 0x1F,
+            removable: data[1] & 0x80 != 0,
+            vendor: text(&data[8..16]),
+            product: text(&data[16..32]),
+            revision: text(&data[32..36]),
+        })
+    }
+}
+
+/// The capacity of a logical unit, as returned by READ CAPACITY (10).
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct Capacity {
+    /// Address of the last logical block.
+    pub last_block: u32,
+
+    /// Length of a block in bytes.
+    pub block_length: u32,
+}
+
+impl Capacity {
+    /// Returns the number of blocks.
+    pub fn blocks(&self) -> u64 {
+        self.last_block as u64 + 1
+    }
+
+    /// Returns the capacity in bytes.
+    pub fn bytes(&self) -> u64 {
+        self.blocks() * self.block_length as u64
+    }
+
+    /// Decodes READ CAPACITY (10) data.
+    ///
+    /// Returns `Error::InvalidParam` if the data is shorter than 8 bytes.
+    pub fn decode(data: &[u8]) -> ::Result<Capacity> {
+        if data.len() < 8 {
+            return Err(Error::InvalidParam);
+        }
+
+        Ok(Capacity {
+            last_block: u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+            block_length: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+        })
+    }
+}
+
+/// Fixed format sense data, as returned by REQUEST SENSE.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct SenseData {
+    /// The sense key, e.g., 0x02 for NOT READY.
+    pub key: u8,
+
+    /// The additional sense code.
+    pub code: u8,
+
+    /// The additional sense code qualifier.
+    pub qualifier: u8,
+}
+
+impl SenseData {
+    /// Decodes fixed format sense data.
+    ///
+    /// Returns `Error::InvalidParam` if the data is shorter than 14 bytes.
+    pub fn decode(data: &[u8]) -> ::Result<SenseData> {
+        if data.len() < 14 {
+            return Err(Error::InvalidParam);
+        }
+
+        Ok(SenseData { key: data[2] & 0x0F, code: data[12], qualifier: data[13] })
+    }
+}
+
+/// Future that resolves to the decoded result of a SCSI command.
+///
+/// A command that completes with a failed status resolves to `Err(Error::Io)`. Use
+/// [`MassStorage::request_sense`](struct.MassStorage.html#method.request_sense) to find out
+/// why it failed.
+pub struct ScsiFuture<'a, T> {
+    command: CommandFuture<'a>,
+    convert: fn(&[u8]) -> ::Result<T>,
+}
+
+impl<'a, T> Future for ScsiFuture<'a, T> {
+    type Output = ::Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Self::Output> {
+        let this = self.get_mut();
+
+        match Pin::new(&mut this.command).poll(cx) {
+            task::Poll::Pending => task::Poll::Pending,
+            task::Poll::Ready(result) => task::Poll::Ready(result.and_then(|result| {
+                match result.status {
+                    CommandStatus::Passed => (this.convert)(&result.data),
+                    _ => Err(Error::Io),
+                }
+            })),
+        }
+    }
+}
+
+impl MassStorage {
+    /// Checks if a logical unit is ready.
+    pub fn test_unit_ready<'a>(&'a self, lun: u8) -> ScsiFuture<'a, ()> {
+        self.scsi(lun, &[TEST_UNIT_READY, 0, 0, 0, 0, 0], DataTransfer::None, |_| Ok(()))
+    }
+
+    /// Reads the sense data of the last failed command.
+    pub fn request_sense<'a>(&'a self, lun: u8) -> ScsiFuture<'a, SenseData> {
+        self.scsi(lun, &[REQUEST_SENSE, 0, 0, 0, SENSE_LENGTH, 0], DataTransfer::In(SENSE_LENGTH as usize), SenseData::decode)
+    }
+
+    /// Reads the standard INQUIRY data of a logical unit.
+    pub fn inquiry<'a>(&'a self, lun: u8) -> ScsiFuture<'a, InquiryData> {
+        self.scsi(lun, &[INQUIRY, 0, 0, 0, INQUIRY_LENGTH, 0], DataTransfer::In(INQUIRY_LENGTH as usize), InquiryData::decode)
+    }
+
+    /// Reads the capacity of a logical unit.
+    pub fn read_capacity<'a>(&'a self, lun: u8) -> ScsiFuture<'a, Capacity> {
+        self.scsi(lun, &[READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0], DataTransfer::In(8), Capacity::decode)
+    }
+
+    /// Reads `blocks` blocks starting at `block`.
+    ///
+    /// `block_length` is taken from [`read_capacity`](#method.read_capacity).
+    pub fn read_10<'a>(&'a self, lun: u8, block: u32, blocks: u16, block_length: u32) -> ScsiFuture<'a, Vec<u8>> {
+        let length = blocks as usize * block_length as usize;
+        self.scsi(lun, &block_command(READ_10, block, blocks), DataTransfer::In(length), |data| Ok(data.to_vec()))
+    }
+
+    /// Writes `data` starting at `block`.
+    ///
+    /// The length of `data` must be a whole number of blocks of `block_length` bytes, or the
+    /// future resolves to `Err(Error::InvalidParam)`.
+    pub fn write_10<'a>(&'a self, lun: u8, block: u32, data: &[u8], block_length: u32) -> ScsiFuture<'a, ()> {
+        let blocks = match data.len().checked_div(block_length as usize) {
+            Some(blocks) if blocks * block_length as usize == data.len() && blocks <= u16::MAX as usize => blocks as u16,
+            _ => return ScsiFuture { command: bot::failed(self, Error::InvalidParam), convert: |_| Ok(()) },
+        };
+
+        self.scsi(lun, &block_command(WRITE_10, block, blocks), DataTransfer::Out(data.to_vec()), |_| Ok(()))
+    }
+
+    fn scsi<'a, T>(&'a self, lun: u8, command: &[u8], data: DataTransfer, convert: fn(&[u8]) -> ::Result<T>) -> ScsiFuture<'a, T> {
+        ScsiFuture { command: self.command(lun, command, data), convert }
+    }
+}
+
+/// Encodes a 10 byte CDB that addresses `blocks` blocks starting at `block`.
+pub fn block_command(operation: u8, block: u32, blocks: u16) -> [u8; 10] {
+    let block = block.to_be_bytes();
+    let blocks = blocks.to_be_bytes();
+
+    [operation, 0, block[0], block[1], block[2], block[3], 0, blocks[0], blocks[1], 0]
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_decodes_inquiry_data() {
+        let mut data = vec![0x00, 0x80, 0x04, 0x02, 0x1F, 0x00, 0x00, 0x00];
+        data.extend_from_slice(b"Generic ");
+        data.extend_from_slice(b"Flash Disk      ");
+        data.extend_from_slice(b"8.07");
+
+        assert_eq!(InquiryData {
+            device_type: 0,
+            removable: true,
+            vendor: "Generic".to_owned(),
+            product: "Flash Disk".to_owned(),
+            revision: "8.07".to_owned(),
+        }, InquiryData::decode(&data).unwrap());
+    }
+
+    #[test]
+    fn it_decodes_capacity() {
+        let capacity = Capacity::decode(&[0x00, 0x3B, 0x9F, 0xFF, 0x00, 0x00, 0x02, 0x00]).unwrap();
+
+        assert_eq!(Capacity { last_block: 0x003B_9FFF, block_length: 512 }, capacity);
+        assert_eq!(0x003B_A000, capacity.blocks());
+        assert_eq!(0x003B_A000 * 512, capacity.bytes());
+    }
+
+    #[test]
+    fn it_decodes_sense_data() {
+        let data = [0x70, 0x00, 0x02, 0, 0, 0, 0, 0x0A, 0, 0, 0, 0, 0x3A, 0x00, 0, 0, 0, 0];
+        assert_eq!(SenseData { key: 0x02, code: 0x3A, qualifier: 0x00 }, SenseData::decode(&data).unwrap());
+    }
+
+    #[test]
+    fn it_encodes_block_command() {
+        assert_eq!([0x28, 0, 0x00, 0x01, 0x02, 0x03, 0, 0x00, 0x08, 0], block_command(READ_10, 0x0001_0203, 8));
+    }
+}
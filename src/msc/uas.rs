@@ -0,0 +1,834 @@
+//! USB Attached SCSI (UAS) transport.
+//!
+//! UAS sends SCSI commands as information units (IUs) on four bulk pipes: commands and task
+//! management requests on the command pipe, status on the status pipe and data on the data-in
+//! and data-out pipes. At SuperSpeed each command gets its own bulk stream, with the tag of
+//! the command as stream ID, so that several commands can be in progress at a time. At high
+//! speed the device instead tells when it's ready for the data stage with an IU on the status
+//! pipe.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::task;
+
+use config_descriptor::ConfigDescriptor;
+use device::Device;
+use device_handle::DeviceHandle;
+use error::Error;
+use extra_descriptors::ExtraDescriptors;
+use fields::{ClassCode, EndpointAddress, TransferType};
+use interface_descriptor::InterfaceDescriptor;
+use transfer::{CompletedTransfer, TransferFuture};
+
+use super::bot::DataTransfer;
+use super::scsi::{self, InquiryData, Capacity, SenseData};
+
+const SUBCLASS_SCSI: u8 = 0x06;
+const PROTOCOL_UAS: u8 = 0x62;
+
+/// Descriptor type of the Pipe Usage descriptor that follows each endpoint.
+const PIPE_USAGE: u8 = 0x24;
+
+const COMMAND_PIPE: u8 = 0x01;
+const STATUS_PIPE: u8 = 0x02;
+const DATA_IN_PIPE: u8 = 0x03;
+const DATA_OUT_PIPE: u8 = 0x04;
+
+const COMMAND_IU: u8 = 0x01;
+const SENSE_IU: u8 = 0x03;
+const RESPONSE_IU: u8 = 0x04;
+const TASK_MANAGEMENT_IU: u8 = 0x05;
+const READ_READY_IU: u8 = 0x06;
+const WRITE_READY_IU: u8 = 0x07;
+
+const COMMAND_IU_LENGTH: usize = 32;
+const TASK_MANAGEMENT_IU_LENGTH: usize = 16;
+
+/// Longest CDB that fits in a command IU without additional CDB bytes.
+const MAX_CDB_LENGTH: usize = 16;
+
+/// Room for a sense IU with the largest sense data a device returns.
+const STATUS_LENGTH: usize = 16 + 252;
+
+/// Largest number of streams asked for, which bounds the number of commands in flight.
+const MAX_STREAMS: u32 = 256;
+
+/// SCSI status of a command that completed successfully.
+pub const GOOD: u8 = 0x00;
+
+/// SCSI status of a command that failed, with sense data telling why.
+pub const CHECK_CONDITION: u8 = 0x02;
+
+const TASK_MANAGEMENT_COMPLETE: u8 = 0x00;
+const TASK_MANAGEMENT_SUCCEEDED: u8 = 0x08;
+
+/// Finds the first UAS interface of a configuration.
+///
+/// UAS is usually an alternate setting of an interface that also offers Bulk-Only Transport,
+/// which [`UasStorage::open`](struct.UasStorage.html#method.open) selects.
+pub fn find_uas_interface(config: &ConfigDescriptor) -> Option<u8> {
+    config.find_interface(ClassCode::MassStorage, SUBCLASS_SCSI, PROTOCOL_UAS)
+        .map(|setting| setting.interface_number())
+}
+
+/// The endpoint addresses of the four UAS pipes.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct UasPipes {
+    pub command: EndpointAddress,
+    pub status: EndpointAddress,
+    pub data_in: EndpointAddress,
+    pub data_out: EndpointAddress,
+}
+
+impl UasPipes {
+    /// Finds the pipes of an alternate setting from the Pipe Usage descriptors of its bulk
+    /// endpoints.
+    pub fn from_setting(setting: &InterfaceDescriptor) -> Option<UasPipes> {
+        let pipe = |id: u8| {
+            setting.endpoint_descriptors()
+                .filter(|endpoint| endpoint.transfer_type() == TransferType::Bulk)
+                .find(|endpoint| pipe_id(endpoint.extra()) == Some(id))
+                .map(|endpoint| EndpointAddress::from(endpoint.address()))
+        };
+
+        Some(UasPipes {
+            command: pipe(COMMAND_PIPE)?,
+            status: pipe(STATUS_PIPE)?,
+            data_in: pipe(DATA_IN_PIPE)?,
+            data_out: pipe(DATA_OUT_PIPE)?,
+        })
+    }
+}
+
+fn pipe_id(extra: &[u8]) -> Option<u8> {
+    ExtraDescriptors::new(extra)
+        .find(|&(descriptor_type, payload)| descriptor_type == PIPE_USAGE && !payload.is_empty())
+        .map(|(_, payload)| payload[0])
+}
+
+/// Encodes a command IU for a CDB of at most 16 bytes, with the SIMPLE task attribute.
+///
+/// Returns `Error::InvalidParam` if the CDB is longer.
+pub fn encode_command_iu(tag: u16, lun: u8, cdb: &[u8]) -> ::Result<Vec<u8>> {
+    if cdb.len() > MAX_CDB_LENGTH {
+        return Err(Error::InvalidParam);
+    }
+
+    let mut bytes = vec![0; COMMAND_IU_LENGTH];
+    bytes[0] = COMMAND_IU;
+    bytes[2..4].copy_from_slice(&tag.to_be_bytes());
+    bytes[9] = lun;
+    bytes[16..16 + cdb.len()].copy_from_slice(cdb);
+    Ok(bytes)
+}
+
+/// A task management function.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum TaskManagement {
+    /// Aborts the command with the given tag.
+    AbortTask(u16),
+
+    /// Aborts all commands of the logical unit.
+    AbortTaskSet,
+
+    /// Resets the logical unit.
+    LogicalUnitReset,
+
+    /// Resets the device as seen from this host.
+    ItNexusReset,
+}
+
+/// Encodes a task management IU.
+pub fn encode_task_management_iu(tag: u16, lun: u8, function: TaskManagement) -> Vec<u8> {
+    let (code, task_tag) = match function {
+        TaskManagement::AbortTask(task_tag) => (0x01, task_tag),
+        TaskManagement::AbortTaskSet => (0x02, 0),
+        TaskManagement::LogicalUnitReset => (0x08, 0),
+        TaskManagement::ItNexusReset => (0x10, 0),
+    };
+
+    let mut bytes = vec![0; TASK_MANAGEMENT_IU_LENGTH];
+    bytes[0] = TASK_MANAGEMENT_IU;
+    bytes[2..4].copy_from_slice(&tag.to_be_bytes());
+    bytes[4] = code;
+    bytes[6..8].copy_from_slice(&task_tag.to_be_bytes());
+    bytes[9] = lun;
+    bytes
+}
+
+/// An IU received on the status pipe.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub enum StatusIu {
+    /// The status of a completed command.
+    Sense {
+        tag: u16,
+
+        /// The SCSI status, e.g., `GOOD` or `CHECK_CONDITION`.
+        status: u8,
+
+        status_qualifier: u16,
+
+        /// The sense data, empty if the command succeeded.
+        sense: Vec<u8>,
+    },
+
+    /// The response to a task management function, or to an IU the device didn't accept.
+    Response {
+        tag: u16,
+
+        /// The response code, e.g., 0x00 for TASK MANAGEMENT FUNCTION COMPLETE.
+        code: u8,
+
+        info: [u8; 3],
+    },
+
+    /// The device is ready to send the data of the command with this tag.
+    ReadReady(u16),
+
+    /// The device is ready to receive the data of the command with this tag.
+    WriteReady(u16),
+}
+
+impl StatusIu {
+    /// Decodes an IU.
+    ///
+    /// Returns `Error::InvalidParam` if the IU is truncated or of a type not sent on the status
+    /// pipe.
+    pub fn decode(bytes: &[u8]) -> ::Result<StatusIu> {
+        if bytes.len() < 4 {
+            return Err(Error::InvalidParam);
+        }
+
+        let tag = u16::from_be_bytes([bytes[2], bytes[3]]);
+
+        match bytes[0] {
+            SENSE_IU if bytes.len() >= 16 => {
+                let length = u16::from_be_bytes([bytes[14], bytes[15]]) as usize;
+
+                Ok(StatusIu::Sense {
+                    tag,
+                    status_qualifier: u16::from_be_bytes([bytes[4], bytes[5]]),
+                    status: bytes[6],
+                    sense: bytes[16..bytes.len().min(16 + length)].to_vec(),
+                })
+            },
+            RESPONSE_IU if bytes.len() >= 8 => Ok(StatusIu::Response { tag, code: bytes[7], info: [bytes[4], bytes[5], bytes[6]] }),
+            READ_READY_IU => Ok(StatusIu::ReadReady(tag)),
+            WRITE_READY_IU => Ok(StatusIu::WriteReady(tag)),
+            _ => Err(Error::InvalidParam),
+        }
+    }
+
+    /// Returns the tag of the command or task management function the IU belongs to.
+    pub fn tag(&self) -> u16 {
+        match *self {
+            StatusIu::Sense { tag, .. } | StatusIu::Response { tag, .. } => tag,
+            StatusIu::ReadReady(tag) | StatusIu::WriteReady(tag) => tag,
+        }
+    }
+}
+
+/// The outcome of a command that completed its status stage.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub struct CommandResult {
+    /// The SCSI status, e.g., `GOOD` or `CHECK_CONDITION`.
+    pub status: u8,
+
+    /// The sense data sent with the status, if any.
+    pub sense: Option<SenseData>,
+
+    /// Data read by the command. Empty for commands without an IN data stage.
+    pub data: Vec<u8>,
+}
+
+impl CommandResult {
+    /// Indicates if the command completed with status `GOOD`.
+    pub fn passed(&self) -> bool {
+        self.status == GOOD
+    }
+}
+
+/// A claimed UAS interface.
+///
+/// At SuperSpeed, as many commands as there are [`streams`](#method.streams) may be in
+/// progress at a time. At high speed, one command at a time is supported.
+pub struct UasStorage {
+    handle: DeviceHandle,
+    interface: u8,
+    pipes: UasPipes,
+    streams: u32,
+    tag: AtomicU16,
+    sense: Mutex<Option<SenseData>>,
+    kernel_driver_detached: bool,
+}
+
+impl UasStorage {
+    /// Opens a device, claims its mass storage interface and selects the UAS alternate
+    /// setting.
+    ///
+    /// If the pipes support streams, they are allocated. A kernel driver bound to the
+    /// interface is detached and reattached when the storage is dropped. Note that detaching
+    /// the kernel driver unmounts any file systems on the device.
+    ///
+    /// ## Errors
+    ///
+    /// * `NotFound` if the interface has no UAS alternate setting, or it lacks one of the four
+    ///   pipes.
+    /// * Any error returned while opening the device, claiming the interface, selecting the
+    ///   alternate setting or allocating the streams.
+    pub fn open(device: &Device, interface: u8) -> ::Result<UasStorage> {
+        let (setting, pipes, max_streams) = {
+            let config = device.active_config_descriptor()?;
+            let setting = config.interfaces()
+                .find(|i| i.number() == interface)
+                .and_then(|i| i.descriptors().find(|setting| {
+                    setting.class_code() == ClassCode::MassStorage
+                        && setting.sub_class_code() == SUBCLASS_SCSI
+                        && setting.protocol_code() == PROTOCOL_UAS
+                }))
+                .ok_or(Error::NotFound)?;
+
+            let pipes = UasPipes::from_setting(&setting).ok_or(Error::NotFound)?;
+
+            // The streamed pipes must all support the streams used
+            let max_streams = setting.endpoint_descriptors()
+                .filter(|endpoint| [pipes.status, pipes.data_in, pipes.data_out].contains(&endpoint.address().into()))
+                .map(|endpoint| endpoint.max_streams())
+                .min()
+                .unwrap_or(0);
+
+            (setting.setting_number(), pipes, max_streams)
+        };
+
+        let mut handle = device.open()?;
+
+        let kernel_driver_detached = handle.kernel_driver_active(interface).unwrap_or(false);
+        if kernel_driver_detached {
+            handle.detach_kernel_driver(interface)?;
+        }
+
+        handle.claim_interface(interface)?;
+        handle.set_alternate_setting(interface, setting)?;
+
+        let streams = if max_streams > 0 {
+            handle.alloc_streams(max_streams.min(MAX_STREAMS), &[pipes.status, pipes.data_in, pipes.data_out])?
+        } else {
+            0
+        };
+
+        Ok(UasStorage {
+            handle,
+            interface,
+            pipes,
+            streams,
+            tag: AtomicU16::new(0),
+            sense: Mutex::new(None),
+            kernel_driver_detached,
+        })
+    }
+
+    /// Returns the handle of the device.
+    pub fn handle(&self) -> &DeviceHandle {
+        &self.handle
+    }
+
+    /// Returns the number of the claimed interface.
+    pub fn interface_number(&self) -> u8 {
+        self.interface
+    }
+
+    /// Returns the pipes of the interface.
+    pub fn pipes(&self) -> &UasPipes {
+        &self.pipes
+    }
+
+    /// Returns the number of allocated streams, or 0 if the device isn't operating at
+    /// SuperSpeed.
+    pub fn streams(&self) -> u32 {
+        self.streams
+    }
+
+    /// Returns the sense data of the most recent command that failed with sense data.
+    ///
+    /// UAS returns the sense data with the status, so there is no need for REQUEST SENSE.
+    pub fn last_sense(&self) -> Option<SenseData> {
+        *self.sense.lock().unwrap()
+    }
+
+    /// Sends a command and runs its data and status stages.
+    ///
+    /// The future resolves to the status and sense data sent by the device, and to an error
+    /// only if a transfer fails or the device answers out of sequence.
+    pub fn command<'a>(&'a self, lun: u8, cdb: &[u8], data: DataTransfer) -> CommandFuture<'a> {
+        let mut future = CommandFuture::new(self, data);
+
+        if let Err(e) = future.start(lun, cdb) {
+            future.fail(e);
+        }
+
+        future
+    }
+
+    /// Runs a task management function, e.g., to abort a command that doesn't complete.
+    ///
+    /// The future resolves to `Err(Error::Io)` if the device doesn't complete the function.
+    pub fn task_management<'a>(&'a self, lun: u8, function: TaskManagement) -> TaskFuture<'a> {
+        let tag = self.next_tag();
+
+        let state = match self.submit_write(self.pipes.command, None, &encode_task_management_iu(tag, lun, function)) {
+            Ok(future) => TaskState::Request(future),
+            Err(e) => TaskState::Failed(e),
+        };
+
+        TaskFuture { storage: self, tag, state }
+    }
+
+    /// Checks if a logical unit is ready.
+    pub fn test_unit_ready<'a>(&'a self, lun: u8) -> ScsiFuture<'a, ()> {
+        self.scsi(lun, &[scsi::TEST_UNIT_READY, 0, 0, 0, 0, 0], DataTransfer::None, |_| Ok(()))
+    }
+
+    /// Reads the standard INQUIRY data of a logical unit.
+    pub fn inquiry<'a>(&'a self, lun: u8) -> ScsiFuture<'a, InquiryData> {
+        let length = scsi::INQUIRY_LENGTH;
+        self.scsi(lun, &[scsi::INQUIRY, 0, 0, 0, length, 0], DataTransfer::In(length as usize), InquiryData::decode)
+    }
+
+    /// Reads the capacity of a logical unit.
+    pub fn read_capacity<'a>(&'a self, lun: u8) -> ScsiFuture<'a, Capacity> {
+        self.scsi(lun, &[scsi::READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0], DataTransfer::In(8), Capacity::decode)
+    }
+
+    /// Reads `blocks` blocks starting at `block`.
+    ///
+    /// `block_length` is taken from [`read_capacity`](#method.read_capacity).
+    pub fn read_10<'a>(&'a self, lun: u8, block: u32, blocks: u16, block_length: u32) -> ScsiFuture<'a, Vec<u8>> {
+        let length = blocks as usize * block_length as usize;
+        self.scsi(lun, &scsi::block_command(scsi::READ_10, block, blocks), DataTransfer::In(length), |data| Ok(data.to_vec()))
+    }
+
+    /// Writes `data` starting at `block`.
+    ///
+    /// The length of `data` must be a whole number of blocks of `block_length` bytes, or the
+    /// future resolves to `Err(Error::InvalidParam)`.
+    pub fn write_10<'a>(&'a self, lun: u8, block: u32, data: &[u8], block_length: u32) -> ScsiFuture<'a, ()> {
+        let blocks = match data.len().checked_div(block_length as usize) {
+            Some(blocks) if blocks * block_length as usize == data.len() && blocks <= u16::MAX as usize => blocks as u16,
+            _ => {
+                let mut command = CommandFuture::new(self, DataTransfer::None);
+                command.fail(Error::InvalidParam);
+                return ScsiFuture { command, convert: |_| Ok(()) };
+            },
+        };
+
+        self.scsi(lun, &scsi::block_command(scsi::WRITE_10, block, blocks), DataTransfer::Out(data.to_vec()), |_| Ok(()))
+    }
+
+    fn scsi<'a, T>(&'a self, lun: u8, cdb: &[u8], data: DataTransfer, convert: fn(&[u8]) -> ::Result<T>) -> ScsiFuture<'a, T> {
+        ScsiFuture { command: self.command(lun, cdb, data), convert }
+    }
+
+    /// Returns the next tag, which is also the stream ID at SuperSpeed and is never zero.
+    fn next_tag(&self) -> u16 {
+        let max = if self.streams > 0 { self.streams.min(u16::MAX as u32) as u16 } else { u16::MAX };
+        let mut tag = self.tag.load(Ordering::Relaxed);
+
+        loop {
+            let next = if tag >= max { 1 } else { tag + 1 };
+            match self.tag.compare_exchange_weak(tag, next, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return next,
+                Err(current) => tag = current,
+            }
+        }
+    }
+
+    /// Submits a read, on the stream of the tag if streams are in use.
+    fn submit_read(&self, endpoint: EndpointAddress, tag: Option<u16>, length: usize) -> ::Result<TransferFuture> {
+        let mut transfer = self.handle.alloc_transfer(0)?;
+
+        match tag {
+            Some(tag) if self.streams > 0 => transfer.fill_bulk_stream_read(endpoint, tag as u32, length),
+            _ => transfer.fill_bulk_read(endpoint, length),
+        }?;
+
+        Ok(transfer.submit())
+    }
+
+    /// Submits a write, on the stream of the tag if streams are in use.
+    fn submit_write(&self, endpoint: EndpointAddress, tag: Option<u16>, data: &[u8]) -> ::Result<TransferFuture> {
+        let mut transfer = self.handle.alloc_transfer(0)?;
+
+        match tag {
+            Some(tag) if self.streams > 0 => transfer.fill_bulk_stream_write(endpoint, tag as u32, data),
+            _ => transfer.fill_bulk_write(endpoint, data),
+        }?;
+
+        Ok(transfer.submit())
+    }
+
+    fn submit_data(&self, tag: u16, data: &DataTransfer) -> ::Result<Option<TransferFuture>> {
+        match *data {
+            DataTransfer::None => Ok(None),
+            DataTransfer::In(length) => self.submit_read(self.pipes.data_in, Some(tag), length).map(Some),
+            DataTransfer::Out(ref data) => self.submit_write(self.pipes.data_out, Some(tag), data).map(Some),
+        }
+    }
+}
+
+impl Drop for UasStorage {
+    fn drop(&mut self) {
+        if self.streams > 0 {
+            let _ = self.handle.free_streams(&[self.pipes.status, self.pipes.data_in, self.pipes.data_out]);
+        }
+
+        if self.kernel_driver_detached {
+            let _ = self.handle.release_interface(self.interface);
+            let _ = self.handle.attach_kernel_driver(self.interface);
+        }
+    }
+}
+
+fn completed(result: ::Result<CompletedTransfer>) -> ::Result<Vec<u8>> {
+    let transfer = result?;
+    transfer.check_status()?;
+    Ok(transfer.get_buffer().to_vec())
+}
+
+/// Polls a transfer, and takes it out of `slot` once it has completed.
+fn poll_slot(slot: &mut Option<TransferFuture>, cx: &mut task::Context) -> Option<::Result<Vec<u8>>> {
+    let result = match *slot {
+        Some(ref mut future) => match Pin::new(future).poll(cx) {
+            task::Poll::Pending => return None,
+            task::Poll::Ready(result) => completed(result),
+        },
+        None => return None,
+    };
+
+    *slot = None;
+    Some(result)
+}
+
+/// Future returned by [`UasStorage::command`](struct.UasStorage.html#method.command).
+///
+/// At SuperSpeed the command, data and status transfers are submitted together. At high
+/// speed the data transfer waits for the device to tell that it's ready.
+pub struct CommandFuture<'a> {
+    storage: &'a UasStorage,
+    tag: u16,
+    data_stage: DataTransfer,
+    command: Option<TransferFuture>,
+    data: Option<TransferFuture>,
+    data_submitted: bool,
+    status: Option<TransferFuture>,
+    received: Vec<u8>,
+    result: Option<(u8, Vec<u8>)>,
+    failed: Option<Error>,
+}
+
+impl<'a> CommandFuture<'a> {
+    fn new(storage: &'a UasStorage, data_stage: DataTransfer) -> CommandFuture<'a> {
+        CommandFuture {
+            storage,
+            tag: storage.next_tag(),
+            data_stage,
+            command: None,
+            data: None,
+            data_submitted: false,
+            status: None,
+            received: Vec::new(),
+            result: None,
+            failed: None,
+        }
+    }
+
+    fn start(&mut self, lun: u8, cdb: &[u8]) -> ::Result<()> {
+        let storage = self.storage;
+        let iu = encode_command_iu(self.tag, lun, cdb)?;
+
+        // The status and data transfers must be waiting on their streams before the command
+        if storage.streams > 0 {
+            self.status = Some(storage.submit_read(storage.pipes.status, Some(self.tag), STATUS_LENGTH)?);
+            self.data = storage.submit_data(self.tag, &self.data_stage)?;
+            self.data_submitted = true;
+        }
+
+        self.command = Some(storage.submit_write(storage.pipes.command, None, &iu)?);
+        Ok(())
+    }
+
+    fn fail(&mut self, error: Error) {
+        self.command = None;
+        self.data = None;
+        self.status = None;
+        self.failed = Some(error);
+    }
+
+    fn read_status(&mut self) -> ::Result<()> {
+        self.status = Some(self.storage.submit_read(self.storage.pipes.status, Some(self.tag), STATUS_LENGTH)?);
+        Ok(())
+    }
+
+    /// Handles an IU from the status pipe.
+    fn status_received(&mut self, bytes: &[u8]) -> ::Result<()> {
+        let iu = StatusIu::decode(bytes).map_err(|_| Error::Io)?;
+
+        if iu.tag() != self.tag {
+            return Err(Error::Io);
+        }
+
+        match iu {
+            StatusIu::Sense { status, sense, .. } => {
+                // A failed command may never complete its data stage
+                if status != GOOD {
+                    self.data = None;
+                }
+
+                self.result = Some((status, sense));
+            },
+            StatusIu::ReadReady(_) | StatusIu::WriteReady(_) if !self.data_submitted => {
+                self.data = self.storage.submit_data(self.tag, &self.data_stage)?;
+                self.data_submitted = true;
+            },
+            _ => return Err(Error::Io),
+        }
+
+        Ok(())
+    }
+
+    fn poll_transfers(&mut self, cx: &mut task::Context) -> ::Result<bool> {
+        let mut progress = false;
+
+        if let Some(result) = poll_slot(&mut self.command, cx) {
+            result?;
+            progress = true;
+
+            if self.storage.streams == 0 {
+                self.read_status()?;
+            }
+        }
+
+        if let Some(result) = poll_slot(&mut self.data, cx) {
+            let data = result?;
+            progress = true;
+
+            if let DataTransfer::In(_) = self.data_stage {
+                self.received = data;
+            }
+
+            if self.storage.streams == 0 && self.result.is_none() {
+                self.read_status()?;
+            }
+        }
+
+        if let Some(result) = poll_slot(&mut self.status, cx) {
+            let bytes = result?;
+            progress = true;
+            self.status_received(&bytes)?;
+        }
+
+        Ok(progress)
+    }
+}
+
+impl<'a> Future for CommandFuture<'a> {
+    type Output = ::Result<CommandResult>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(e) = this.failed.take() {
+                this.result = None;
+                return task::Poll::Ready(Err(e));
+            }
+
+            if this.command.is_none() && this.data.is_none() && this.status.is_none() {
+                let (status, sense) = match this.result.take() {
+                    Some(result) => result,
+                    None => return task::Poll::Ready(Err(Error::PolledAfterCompletion)),
+                };
+                let sense = SenseData::decode(&sense).ok();
+
+                if sense.is_some() {
+                    *this.storage.sense.lock().unwrap() = sense;
+                }
+
+                let data = std::mem::take(&mut this.received);
+                return task::Poll::Ready(Ok(CommandResult { status, sense, data }));
+            }
+
+            match this.poll_transfers(cx) {
+                Ok(true) => {},
+                Ok(false) => return task::Poll::Pending,
+                Err(e) => this.fail(e),
+            }
+        }
+    }
+}
+
+/// Future that resolves to the decoded result of a SCSI command sent through
+/// [`UasStorage`](struct.UasStorage.html).
+///
+/// A command that doesn't complete with status `GOOD` resolves to `Err(Error::Io)`, and
+/// [`UasStorage::last_sense`](struct.UasStorage.html#method.last_sense) tells why.
+pub struct ScsiFuture<'a, T> {
+    command: CommandFuture<'a>,
+    convert: fn(&[u8]) -> ::Result<T>,
+}
+
+impl<'a, T> Future for ScsiFuture<'a, T> {
+    type Output = ::Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Self::Output> {
+        let this = self.get_mut();
+
+        match Pin::new(&mut this.command).poll(cx) {
+            task::Poll::Pending => task::Poll::Pending,
+            task::Poll::Ready(result) => task::Poll::Ready(result.and_then(|result| {
+                if result.passed() { (this.convert)(&result.data) } else { Err(Error::Io) }
+            })),
+        }
+    }
+}
+
+/// Future returned by [`UasStorage::task_management`](struct.UasStorage.html#method.task_management).
+pub struct TaskFuture<'a> {
+    storage: &'a UasStorage,
+    tag: u16,
+    state: TaskState,
+}
+
+enum TaskState {
+    Failed(Error),
+    Request(TransferFuture),
+    Response(TransferFuture),
+    Done,
+}
+
+impl<'a> TaskFuture<'a> {
+    fn finish(&mut self, result: ::Result<()>) -> task::Poll<::Result<()>> {
+        self.state = TaskState::Done;
+        task::Poll::Ready(result)
+    }
+}
+
+impl<'a> Future for TaskFuture<'a> {
+    type Output = ::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            let next = match this.state {
+                TaskState::Failed(ref e) => {
+                    let e = e.clone();
+                    return this.finish(Err(e));
+                },
+                TaskState::Request(ref mut future) => {
+                    let result = match Pin::new(future).poll(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(result) => completed(result),
+                    };
+
+                    match result.and_then(|_| this.storage.submit_read(this.storage.pipes.status, Some(this.tag), STATUS_LENGTH)) {
+                        Ok(future) => TaskState::Response(future),
+                        Err(e) => return this.finish(Err(e)),
+                    }
+                },
+                TaskState::Response(ref mut future) => {
+                    let bytes = match Pin::new(future).poll(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(result) => completed(result),
+                    };
+
+                    let result = bytes.and_then(|bytes| match StatusIu::decode(&bytes) {
+                        Ok(StatusIu::Response { tag, code: TASK_MANAGEMENT_COMPLETE, .. }) |
+                        Ok(StatusIu::Response { tag, code: TASK_MANAGEMENT_SUCCEEDED, .. }) if tag == this.tag => Ok(()),
+                        _ => Err(Error::Io),
+                    });
+
+                    return this.finish(result);
+                },
+                TaskState::Done => return task::Poll::Ready(Err(Error::PolledAfterCompletion)),
+            };
+
+            this.state = next;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_encodes_command_iu() {
+        let iu = encode_command_iu(0x0102, 1, &[0x12, 0, 0, 0, 36, 0]).unwrap();
+
+        assert_eq!(&[0x01, 0x00, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00], &iu[..8]);
+        assert_eq!(&[0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], &iu[8..16]);
+        assert_eq!(&[0x12, 0, 0, 0, 36, 0, 0, 0], &iu[16..24]);
+        assert!(encode_command_iu(1, 0, &[0; 17]).is_err());
+    }
+
+    #[test]
+    fn it_encodes_task_management_iu() {
+        assert_eq!(vec![0x05, 0x00, 0x00, 0x03, 0x01, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+                   encode_task_management_iu(3, 0, TaskManagement::AbortTask(2)));
+    }
+
+    #[test]
+    fn it_decodes_sense_iu() {
+        let mut bytes = vec![0x03, 0x00, 0x00, 0x05, 0x00, 0x00, CHECK_CONDITION, 0, 0, 0, 0, 0, 0, 0, 0x00, 0x12];
+        bytes.extend_from_slice(&[0x70, 0x00, 0x02, 0, 0, 0, 0, 0x0A, 0, 0, 0, 0, 0x3A, 0x00, 0, 0, 0, 0]);
+
+        match StatusIu::decode(&bytes).unwrap() {
+            StatusIu::Sense { tag, status, sense, .. } => {
+                assert_eq!(5, tag);
+                assert_eq!(CHECK_CONDITION, status);
+                assert_eq!(SenseData { key: 0x02, code: 0x3A, qualifier: 0x00 }, SenseData::decode(&sense).unwrap());
+            },
+            iu => panic!("unexpected IU {:?}", iu),
+        }
+    }
+
+    #[test]
+    fn it_decodes_other_status_ius() {
+        assert_eq!(StatusIu::ReadReady(7), StatusIu::decode(&[0x06, 0x00, 0x00, 0x07]).unwrap());
+        assert_eq!(StatusIu::WriteReady(7), StatusIu::decode(&[0x07, 0x00, 0x00, 0x07]).unwrap());
+        assert_eq!(StatusIu::Response { tag: 2, code: 0x08, info: [0, 0, 0] },
+                   StatusIu::decode(&[0x04, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x08]).unwrap());
+        assert!(StatusIu::decode(&[0x01, 0x00, 0x00, 0x01]).is_err());
+        assert!(StatusIu::decode(&[0x03, 0x00, 0x00, 0x01]).is_err());
+    }
+
+    #[test]
+    fn it_finds_the_pipes_of_a_setting() {
+        let config = ::parse_config_descriptor(&[
+            0x09, 0x02, 0x3E, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32,
+            0x09, 0x04, 0x00, 0x01, 0x04, 0x08, 0x06, 0x62, 0x00,
+            0x07, 0x05, 0x01, 0x02, 0x00, 0x02, 0x00, 0x04, 0x24, 0x01, 0x00,
+            0x07, 0x05, 0x81, 0x02, 0x00, 0x02, 0x00, 0x04, 0x24, 0x02, 0x00,
+            0x07, 0x05, 0x82, 0x02, 0x00, 0x02, 0x00, 0x04, 0x24, 0x03, 0x00,
+            0x07, 0x05, 0x02, 0x02, 0x00, 0x02, 0x00, 0x04, 0x24, 0x04, 0x00,
+        ]).unwrap();
+        let interface = config.interfaces().next().unwrap();
+        let setting = interface.descriptors().next().unwrap();
+
+        assert_eq!(Some(UasPipes {
+            command: EndpointAddress::out(1),
+            status: EndpointAddress::in_(1),
+            data_in: EndpointAddress::in_(2),
+            data_out: EndpointAddress::out(2),
+        }), UasPipes::from_setting(&setting));
+    }
+
+    #[test]
+    fn it_reads_pipe_usage() {
+        assert_eq!(Some(DATA_IN_PIPE), pipe_id(&[0x06, 0x30, 0x00, 0x00, 0x00, 0x00, 0x04, 0x24, 0x03, 0x00]));
+        assert_eq!(None, pipe_id(&[0x06, 0x30, 0x00, 0x00, 0x00, 0x00]));
+    }
+}
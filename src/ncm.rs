@@ -0,0 +1,781 @@
+//! CDC Network Control Model (NCM) support.
+//!
+//! NCM carries Ethernet frames like ECM, but aggregates several of them into one NCM Transfer
+//! Block (NTB) per bulk transfer. [`NtbBuilder`](struct.NtbBuilder.html) and
+//! [`parse_ntb`](fn.parse_ntb.html) do the aggregation, and [`NcmDevice`](struct.NcmDevice.html)
+//! uses them to expose the data pipes as a stream and a sink of frames.
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task;
+use std::time::Duration;
+
+use futures_core::Stream;
+use futures_sink::Sink;
+
+pub use cdc::{MacAddressFuture, PacketFilter, NetworkEvent, NetworkEvents, parse_mac_address};
+
+use cdc;
+use config_descriptor::ConfigDescriptor;
+use control::{self, ControlFuture};
+use device::Device;
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::{Direction, Recipient, RequestType, TransferType, request_type};
+use read_queue::ReadQueue;
+use transfer::TransferFuture;
+use transfer_queue::{self, TransferQueue};
+
+const SUBCLASS_NCM: u8 = 0x0D;
+
+const ETHERNET_NETWORKING_FUNCTIONAL: u8 = 0x0F;
+
+const SET_ETHERNET_PACKET_FILTER: u8 = 0x43;
+const GET_NTB_PARAMETERS: u8 = 0x80;
+const SET_NTB_FORMAT: u8 = 0x84;
+const SET_NTB_INPUT_SIZE: u8 = 0x86;
+
+const NTB_PARAMETERS_LENGTH: u16 = 28;
+
+const NTH16_SIGNATURE: &[u8] = b"NCMH";
+const NTH32_SIGNATURE: &[u8] = b"ncmh";
+const NDP16_SIGNATURE: &[u8] = b"NCM0";
+const NDP16_CRC_SIGNATURE: &[u8] = b"NCM1";
+const NDP32_SIGNATURE: &[u8] = b"ncm0";
+const NDP32_CRC_SIGNATURE: &[u8] = b"ncm1";
+
+const NTH16_LENGTH: usize = 12;
+const NTH32_LENGTH: usize = 16;
+
+/// Largest number of NDPs followed in one NTB, which stops malformed NDP chains from looping.
+const MAX_NDPS: usize = 32;
+
+/// Largest NTB the host accepts, which bounds the size of the receive buffers.
+const MAX_INPUT_SIZE: u32 = 65_536;
+
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Maximum segment size used when the functional descriptor is missing.
+const DEFAULT_MAX_SEGMENT_SIZE: u16 = 1514;
+
+/// Number of receive transfers kept in flight.
+const RX_TRANSFERS: usize = 4;
+
+/// Number of transmit transfers that may be in flight.
+const TX_TRANSFERS: usize = 4;
+
+/// The layout of NCM Transfer Blocks.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum NtbFormat {
+    /// 16-bit offsets and lengths, limiting an NTB to 64 KiB.
+    Ntb16,
+
+    /// 32-bit offsets and lengths.
+    Ntb32,
+}
+
+/// The NTB parameters of a function, as returned by GET_NTB_PARAMETERS.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct NtbParameters {
+    /// Indicates if the function supports NTB-32. NTB-16 is always supported.
+    pub ntb32_supported: bool,
+
+    /// The largest NTB the function sends (`dwNtbInMaxSize`).
+    pub in_max_size: u32,
+
+    /// Divisor used by the function to align datagrams it sends (`wNdpInDivisor`).
+    pub in_divisor: u16,
+
+    /// Remainder used by the function to align datagrams it sends (`wNdpInPayloadRemainder`).
+    pub in_payload_remainder: u16,
+
+    /// Alignment of NDPs in the NTBs the function sends (`wNdpInAlignment`).
+    pub in_alignment: u16,
+
+    /// The largest NTB the function accepts (`dwNtbOutMaxSize`).
+    pub out_max_size: u32,
+
+    /// Divisor for aligning datagrams sent to the function (`wNdpOutDivisor`).
+    pub out_divisor: u16,
+
+    /// Remainder for aligning datagrams sent to the function (`wNdpOutPayloadRemainder`).
+    pub out_payload_remainder: u16,
+
+    /// Alignment of NDPs in NTBs sent to the function (`wNdpOutAlignment`).
+    pub out_alignment: u16,
+
+    /// The largest number of datagrams in an NTB sent to the function, or zero if there is no
+    /// limit (`wNtbOutMaxDatagrams`).
+    pub out_max_datagrams: u16,
+}
+
+impl NtbParameters {
+    /// Decodes the parameters received from a function.
+    pub fn decode(data: &[u8]) -> ::Result<NtbParameters> {
+        if data.len() < NTB_PARAMETERS_LENGTH as usize {
+            return Err(Error::InvalidParam);
+        }
+
+        Ok(NtbParameters {
+            ntb32_supported: read_u16(data, 2) & 0x0002 != 0,
+            in_max_size: read_u32(data, 4),
+            in_divisor: read_u16(data, 8),
+            in_payload_remainder: read_u16(data, 10),
+            in_alignment: read_u16(data, 12),
+            out_max_size: read_u32(data, 16),
+            out_divisor: read_u16(data, 20),
+            out_payload_remainder: read_u16(data, 22),
+            out_alignment: read_u16(data, 24),
+            out_max_datagrams: read_u16(data, 26),
+        })
+    }
+}
+
+/// Returns the datagrams of an NTB.
+///
+/// Both formats are recognized from the signature of the NTB header. Datagram pointers that
+/// point outside the NTB are skipped.
+///
+/// Returns `Error::InvalidParam` if the NTB header or an NDP is malformed.
+pub fn parse_ntb(ntb: &[u8]) -> ::Result<Vec<&[u8]>> {
+    let format = match ntb.get(0..4) {
+        Some(NTH16_SIGNATURE) if ntb.len() >= NTH16_LENGTH => NtbFormat::Ntb16,
+        Some(NTH32_SIGNATURE) if ntb.len() >= NTH32_LENGTH => NtbFormat::Ntb32,
+        _ => return Err(Error::InvalidParam),
+    };
+
+    let (block_length, mut ndp_index) = match format {
+        NtbFormat::Ntb16 => (read_u16(ntb, 8) as usize, read_u16(ntb, 10) as usize),
+        NtbFormat::Ntb32 => (read_u32(ntb, 8) as usize, read_u32(ntb, 12) as usize),
+    };
+
+    let ntb = &ntb[..block_length.min(ntb.len())];
+    let mut datagrams = Vec::new();
+
+    for _ in 0..MAX_NDPS {
+        if ndp_index == 0 {
+            break;
+        }
+
+        let (header_length, entry_length) = match format {
+            NtbFormat::Ntb16 => (8, 4),
+            NtbFormat::Ntb32 => (16, 8),
+        };
+
+        let ndp = match ntb.get(ndp_index..) {
+            Some(ndp) if ndp.len() >= header_length => ndp,
+            _ => return Err(Error::InvalidParam),
+        };
+
+        match (format, &ndp[0..4]) {
+            (NtbFormat::Ntb16, NDP16_SIGNATURE) | (NtbFormat::Ntb16, NDP16_CRC_SIGNATURE) |
+            (NtbFormat::Ntb32, NDP32_SIGNATURE) | (NtbFormat::Ntb32, NDP32_CRC_SIGNATURE) => {},
+            _ => return Err(Error::InvalidParam),
+        }
+
+        let ndp_length = (read_u16(ndp, 4) as usize).min(ndp.len());
+        let entries = ndp.get(header_length..ndp_length).unwrap_or(&[]);
+
+        for entry in entries.chunks_exact(entry_length) {
+            let (index, length) = match format {
+                NtbFormat::Ntb16 => (read_u16(entry, 0) as usize, read_u16(entry, 2) as usize),
+                NtbFormat::Ntb32 => (read_u32(entry, 0) as usize, read_u32(entry, 4) as usize),
+            };
+
+            if index == 0 || length == 0 {
+                break;
+            }
+
+            if let Some(datagram) = ntb.get(index..index + length) {
+                datagrams.push(datagram);
+            }
+        }
+
+        ndp_index = match format {
+            NtbFormat::Ntb16 => read_u16(ndp, 6) as usize,
+            NtbFormat::Ntb32 => read_u32(ndp, 8) as usize,
+        };
+    }
+
+    Ok(datagrams)
+}
+
+/// Aggregates datagrams into an NTB for sending to a function.
+///
+/// The datagrams are placed after the NTB header at the offsets required by the function's
+/// divisor and remainder, followed by a single NDP.
+#[derive(Debug,Clone)]
+pub struct NtbBuilder {
+    format: NtbFormat,
+    max_size: usize,
+    divisor: usize,
+    remainder: usize,
+    alignment: usize,
+    max_datagrams: usize,
+    datagrams: Vec<Vec<u8>>,
+}
+
+impl NtbBuilder {
+    /// Creates a builder for NTBs that a function with the given parameters accepts.
+    pub fn new(format: NtbFormat, parameters: &NtbParameters) -> NtbBuilder {
+        let max_size = match format {
+            NtbFormat::Ntb16 => parameters.out_max_size.min(u16::MAX as u32),
+            NtbFormat::Ntb32 => parameters.out_max_size,
+        };
+
+        NtbBuilder {
+            format,
+            max_size: max_size as usize,
+            divisor: parameters.out_divisor.max(1) as usize,
+            remainder: parameters.out_payload_remainder as usize,
+            alignment: parameters.out_alignment.max(4) as usize,
+            max_datagrams: parameters.out_max_datagrams as usize,
+            datagrams: Vec::new(),
+        }
+    }
+
+    /// Returns the number of datagrams added.
+    pub fn len(&self) -> usize {
+        self.datagrams.len()
+    }
+
+    /// Indicates if no datagrams have been added.
+    pub fn is_empty(&self) -> bool {
+        self.datagrams.is_empty()
+    }
+
+    /// Adds a datagram.
+    ///
+    /// Returns `false`, and leaves the builder unchanged, if the datagram doesn't fit in the NTB.
+    /// A datagram that doesn't fit in an empty NTB can't be sent at all.
+    pub fn push(&mut self, datagram: &[u8]) -> bool {
+        if self.max_datagrams != 0 && self.datagrams.len() >= self.max_datagrams {
+            return false;
+        }
+
+        self.datagrams.push(datagram.to_vec());
+
+        if self.layout().1 > self.max_size {
+            self.datagrams.pop();
+            return false;
+        }
+
+        true
+    }
+
+    /// Builds the NTB from the added datagrams and empties the builder.
+    pub fn build(&mut self, sequence: u16) -> Vec<u8> {
+        let (offsets, length, ndp_index) = {
+            let (offsets, length) = self.layout();
+            let ndp_index = length - self.ndp_length();
+            (offsets, length, ndp_index)
+        };
+
+        let mut ntb = vec![0; length];
+
+        match self.format {
+            NtbFormat::Ntb16 => {
+                ntb[0..4].copy_from_slice(NTH16_SIGNATURE);
+                ntb[4..6].copy_from_slice(&(NTH16_LENGTH as u16).to_le_bytes());
+                ntb[6..8].copy_from_slice(&sequence.to_le_bytes());
+                ntb[8..10].copy_from_slice(&(length as u16).to_le_bytes());
+                ntb[10..12].copy_from_slice(&(ndp_index as u16).to_le_bytes());
+
+                let ndp = &mut ntb[ndp_index..];
+                ndp[0..4].copy_from_slice(NDP16_SIGNATURE);
+                ndp[4..6].copy_from_slice(&(self.ndp_length() as u16).to_le_bytes());
+
+                for (i, (datagram, &offset)) in self.datagrams.iter().zip(&offsets).enumerate() {
+                    let entry = &mut ndp[8 + i * 4..];
+                    entry[0..2].copy_from_slice(&(offset as u16).to_le_bytes());
+                    entry[2..4].copy_from_slice(&(datagram.len() as u16).to_le_bytes());
+                }
+            },
+            NtbFormat::Ntb32 => {
+                ntb[0..4].copy_from_slice(NTH32_SIGNATURE);
+                ntb[4..6].copy_from_slice(&(NTH32_LENGTH as u16).to_le_bytes());
+                ntb[6..8].copy_from_slice(&sequence.to_le_bytes());
+                ntb[8..12].copy_from_slice(&(length as u32).to_le_bytes());
+                ntb[12..16].copy_from_slice(&(ndp_index as u32).to_le_bytes());
+
+                let ndp = &mut ntb[ndp_index..];
+                ndp[0..4].copy_from_slice(NDP32_SIGNATURE);
+                ndp[4..6].copy_from_slice(&(self.ndp_length() as u16).to_le_bytes());
+
+                for (i, (datagram, &offset)) in self.datagrams.iter().zip(&offsets).enumerate() {
+                    let entry = &mut ndp[16 + i * 8..];
+                    entry[0..4].copy_from_slice(&(offset as u32).to_le_bytes());
+                    entry[4..8].copy_from_slice(&(datagram.len() as u32).to_le_bytes());
+                }
+            },
+        }
+
+        for (datagram, &offset) in self.datagrams.iter().zip(&offsets) {
+            ntb[offset..offset + datagram.len()].copy_from_slice(datagram);
+        }
+
+        self.datagrams.clear();
+        ntb
+    }
+
+    /// Returns the length of the NDP, including the terminating null entry.
+    fn ndp_length(&self) -> usize {
+        match self.format {
+            NtbFormat::Ntb16 => 8 + 4 * (self.datagrams.len() + 1),
+            NtbFormat::Ntb32 => 16 + 8 * (self.datagrams.len() + 1),
+        }
+    }
+
+    /// Returns the offsets of the datagrams and the total length of the NTB.
+    fn layout(&self) -> (Vec<usize>, usize) {
+        let mut offset = match self.format {
+            NtbFormat::Ntb16 => NTH16_LENGTH,
+            NtbFormat::Ntb32 => NTH32_LENGTH,
+        };
+
+        let offsets = self.datagrams.iter().map(|datagram| {
+            let start = align(offset, self.divisor, self.remainder);
+            offset = start + datagram.len();
+            start
+        }).collect();
+
+        (offsets, align(offset, self.alignment, 0) + self.ndp_length())
+    }
+}
+
+/// Returns the smallest offset not below `offset` that leaves `remainder` when divided by
+/// `divisor`.
+fn align(offset: usize, divisor: usize, remainder: usize) -> usize {
+    let aligned = offset - offset % divisor + remainder % divisor;
+    if aligned < offset { aligned + divisor } else { aligned }
+}
+
+/// The interfaces of one CDC-NCM function.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct NcmInterfaces {
+    /// The communications interface, which carries class requests and notifications.
+    pub control_interface: u8,
+
+    /// The data interface, which has the bulk endpoints.
+    pub data_interface: u8,
+
+    /// Index of the string descriptor holding the MAC address, or zero if there is none.
+    pub mac_address_index: u8,
+
+    /// The largest Ethernet frame the function handles (`wMaxSegmentSize`).
+    pub max_segment_size: u16,
+}
+
+/// Finds the CDC-NCM functions of a configuration.
+pub fn find_ncm_interfaces(config: &ConfigDescriptor) -> Vec<NcmInterfaces> {
+    cdc::find_functions(config, SUBCLASS_NCM).into_iter().map(|(control_interface, data_interface)| {
+        let functional = config.interfaces()
+            .find(|i| i.number() == control_interface)
+            .and_then(|i| i.descriptors().next())
+            .and_then(|setting| cdc::functional_descriptor(setting.extra(), ETHERNET_NETWORKING_FUNCTIONAL).map(|payload| payload.to_vec()));
+
+        let (mac_address_index, max_segment_size) = match functional {
+            Some(ref payload) if payload.len() >= 8 => (payload[1], read_u16(payload, 6)),
+            _ => (0, DEFAULT_MAX_SEGMENT_SIZE),
+        };
+
+        NcmInterfaces { control_interface, data_interface, mac_address_index, max_segment_size }
+    }).collect()
+}
+
+/// An open CDC-NCM network function.
+///
+/// Received frames are read from [`frames`](#method.frames) and frames are sent through
+/// [`sink`](#method.sink). Both can be used at the same time.
+pub struct NcmDevice {
+    handle: DeviceHandle,
+    interfaces: NcmInterfaces,
+    parameters: NtbParameters,
+    input_size: usize,
+    notification_endpoint: Option<(u8, u16)>,
+    bulk_in: u8,
+    bulk_out: (u8, usize),
+    detached: Vec<u8>,
+}
+
+impl NcmDevice {
+    /// Opens a device, claims the interfaces of a CDC-NCM function and starts the data
+    /// interface.
+    ///
+    /// The NTB parameters are read, NTB-16 is selected and the input size is limited to 64 KiB
+    /// before the data interface is started. The packet filter is then set to
+    /// [`PacketFilter::default`](struct.PacketFilter.html). Kernel drivers bound to the
+    /// interfaces are detached and reattached when the device is dropped.
+    ///
+    /// ## Errors
+    ///
+    /// * `NotFound` if the data interface has no setting with a bulk IN and a bulk OUT endpoint.
+    /// * Any error returned while opening the device, claiming the interfaces or making the
+    ///   setup requests.
+    pub fn open(device: &Device, interfaces: &NcmInterfaces) -> ::Result<NcmDevice> {
+        let (notification_endpoint, data_setting, bulk_in, bulk_out) = {
+            let config = device.active_config_descriptor()?;
+
+            let notification = config.interfaces()
+                .find(|i| i.number() == interfaces.control_interface)
+                .and_then(|i| i.descriptors().next())
+                .ok_or(Error::NotFound)?
+                .first_endpoint(TransferType::Interrupt, Direction::In)
+                .map(|ep| (ep.address(), ep.max_packet_size()));
+
+            let data = config.interfaces()
+                .find(|i| i.number() == interfaces.data_interface)
+                .ok_or(Error::NotFound)?;
+            let (data_setting, bulk_in, bulk_out) = data.descriptors().filter_map(|setting| {
+                let bulk_in = setting.first_endpoint(TransferType::Bulk, Direction::In)?;
+                let bulk_out = setting.first_endpoint(TransferType::Bulk, Direction::Out)?;
+                Some((setting.setting_number(), bulk_in.address(), (bulk_out.address(), bulk_out.max_packet_size() as usize)))
+            }).next().ok_or(Error::NotFound)?;
+
+            (notification, data_setting, bulk_in, bulk_out)
+        };
+
+        let mut handle = device.open()?;
+        let mut detached = Vec::new();
+
+        for &interface in &[interfaces.control_interface, interfaces.data_interface] {
+            if handle.kernel_driver_active(interface).unwrap_or(false) {
+                handle.detach_kernel_driver(interface)?;
+                detached.push(interface);
+            }
+
+            handle.claim_interface(interface)?;
+        }
+
+        let request_in = request_type(Direction::In, RequestType::Class, Recipient::Interface);
+        let request_out = request_type(Direction::Out, RequestType::Class, Recipient::Interface);
+        let index = interfaces.control_interface as u16;
+
+        // The NTB format and input size can only be changed while the data interface is stopped
+        let mut data = [0; NTB_PARAMETERS_LENGTH as usize];
+        let length = handle.read_control(request_in, GET_NTB_PARAMETERS, 0, index, &mut data, CONTROL_TIMEOUT)?;
+        let parameters = NtbParameters::decode(&data[..length])?;
+
+        if parameters.ntb32_supported {
+            handle.write_control(request_out, SET_NTB_FORMAT, 0, index, &[], CONTROL_TIMEOUT)?;
+        }
+
+        let input_size = parameters.in_max_size.min(MAX_INPUT_SIZE);
+        if input_size != parameters.in_max_size {
+            handle.write_control(request_out, SET_NTB_INPUT_SIZE, 0, index, &input_size.to_le_bytes(), CONTROL_TIMEOUT)?;
+        }
+
+        handle.set_alternate_setting(interfaces.data_interface, data_setting)?;
+
+        handle.write_control(request_out, SET_ETHERNET_PACKET_FILTER, PacketFilter::default().bits(), index, &[], CONTROL_TIMEOUT)?;
+
+        Ok(NcmDevice {
+            handle,
+            interfaces: *interfaces,
+            parameters,
+            input_size: input_size as usize,
+            notification_endpoint,
+            bulk_in,
+            bulk_out,
+            detached,
+        })
+    }
+
+    /// Returns the handle of the device.
+    pub fn handle(&self) -> &DeviceHandle {
+        &self.handle
+    }
+
+    /// Returns the interfaces of the function.
+    pub fn interfaces(&self) -> &NcmInterfaces {
+        &self.interfaces
+    }
+
+    /// Returns the NTB parameters read when the device was opened.
+    pub fn ntb_parameters(&self) -> &NtbParameters {
+        &self.parameters
+    }
+
+    /// Reads the NTB parameters of the function again.
+    pub fn read_ntb_parameters(&self) -> ControlFuture<NtbParameters> {
+        control::read(&self.handle,
+                      request_type(Direction::In, RequestType::Class, Recipient::Interface),
+                      GET_NTB_PARAMETERS,
+                      0,
+                      self.interfaces.control_interface as u16,
+                      NTB_PARAMETERS_LENGTH,
+                      NtbParameters::decode)
+    }
+
+    /// Reads the MAC address of the function.
+    ///
+    /// The future resolves to `Err(NotFound)` if the function has no MAC address string.
+    pub fn mac_address<'a>(&'a self) -> MacAddressFuture<'a> {
+        cdc::mac_address(&self.handle, self.interfaces.mac_address_index)
+    }
+
+    /// Sets the packet types that the function passes to the host.
+    pub fn set_packet_filter(&self, filter: &PacketFilter) -> ControlFuture<usize> {
+        control::write(&self.handle,
+                       request_type(Direction::Out, RequestType::Class, Recipient::Interface),
+                       SET_ETHERNET_PACKET_FILTER,
+                       filter.bits(),
+                       self.interfaces.control_interface as u16,
+                       &[])
+    }
+
+    /// Returns a stream of link state changes.
+    ///
+    /// The stream yields `Err(NotFound)` if the communications interface has no notification
+    /// endpoint, and ends after the first error.
+    pub fn events<'a>(&'a self) -> NetworkEvents<'a> {
+        cdc::network_events(&self.handle, self.notification_endpoint)
+    }
+
+    /// Returns a stream of received Ethernet frames.
+    ///
+    /// Each received NTB is split into its datagrams. The stream ends after the first error,
+    /// including a malformed NTB.
+    pub fn frames<'a>(&'a self) -> Frames<'a> {
+        Frames { device: self, reads: ReadQueue::new(RX_TRANSFERS), frames: VecDeque::new(), done: false }
+    }
+
+    /// Returns a sink that sends Ethernet frames.
+    ///
+    /// Frames are aggregated into an NTB, which is sent when it is full or the sink is
+    /// flushed.
+    pub fn sink<'a>(&'a self) -> FrameSink<'a> {
+        FrameSink {
+            device: self,
+            builder: NtbBuilder::new(NtbFormat::Ntb16, &self.parameters),
+            sequence: 0,
+            transfers: TransferQueue::with_capacity(TX_TRANSFERS),
+        }
+    }
+
+    fn submit_read(&self) -> ::Result<TransferFuture> {
+        transfer_queue::submit_bulk_read(&self.handle, self.bulk_in.into(), self.input_size)
+    }
+
+    fn submit_write(&self, ntb: &[u8]) -> ::Result<TransferFuture> {
+        transfer_queue::submit_bulk_write(&self.handle, self.bulk_out.0.into(), ntb)
+    }
+}
+
+impl Drop for NcmDevice {
+    fn drop(&mut self) {
+        let _ = self.handle.set_alternate_setting(self.interfaces.data_interface, 0);
+
+        for &interface in &self.detached {
+            let _ = self.handle.release_interface(interface);
+            let _ = self.handle.attach_kernel_driver(interface);
+        }
+    }
+}
+
+/// Stream of received frames returned by [`NcmDevice::frames`](struct.NcmDevice.html#method.frames).
+pub struct Frames<'a> {
+    device: &'a NcmDevice,
+    reads: ReadQueue<TransferFuture>,
+    frames: VecDeque<Vec<u8>>,
+    done: bool,
+}
+
+impl<'a> Stream for Frames<'a> {
+    type Item = ::Result<Vec<u8>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(frame) = this.frames.pop_front() {
+                return task::Poll::Ready(Some(Ok(frame)));
+            }
+
+            if this.done {
+                return task::Poll::Ready(None);
+            }
+
+            let device = this.device;
+            let result = match this.reads.poll_next(cx, || device.submit_read()) {
+                task::Poll::Pending => return task::Poll::Pending,
+                task::Poll::Ready(result) => result,
+            };
+
+            let frames = result.and_then(|transfer| {
+                transfer.check_status()?;
+
+                // Zero-length packets carry no NTB
+                if transfer.get_buffer().is_empty() {
+                    return Ok(Vec::new());
+                }
+
+                Ok(parse_ntb(transfer.get_buffer())?.into_iter().map(|datagram| datagram.to_vec()).collect())
+            });
+
+            match frames {
+                Ok(frames) => this.frames.extend(frames),
+                Err(e) => {
+                    this.done = true;
+                    this.reads.clear();
+                    return task::Poll::Ready(Some(Err(e)));
+                },
+            }
+        }
+    }
+}
+
+/// Sink of frames returned by [`NcmDevice::sink`](struct.NcmDevice.html#method.sink).
+pub struct FrameSink<'a> {
+    device: &'a NcmDevice,
+    builder: NtbBuilder,
+    sequence: u16,
+    transfers: TransferQueue<TransferFuture>,
+}
+
+impl<'a> FrameSink<'a> {
+    /// Sends the aggregated frames as one NTB.
+    fn submit_ntb(&mut self) -> ::Result<()> {
+        let ntb = self.builder.build(self.sequence);
+        self.sequence = self.sequence.wrapping_add(1);
+
+        self.transfers.push(self.device.submit_write(&ntb)?);
+
+        // An NTB shorter than the maximum that fills its last packet needs a zero-length packet
+        if ntb.len() < self.device.parameters.out_max_size as usize && ntb.len().is_multiple_of(self.device.bulk_out.1.max(1)) {
+            self.transfers.push(self.device.submit_write(&[])?);
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> Sink<Vec<u8>> for FrameSink<'a> {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<::Result<()>> {
+        self.get_mut().transfers.poll_completed(cx, TX_TRANSFERS - 1)
+    }
+
+    fn start_send(self: Pin<&mut Self>, frame: Vec<u8>) -> ::Result<()> {
+        let this = self.get_mut();
+
+        if this.builder.push(&frame) {
+            return Ok(());
+        }
+
+        if this.builder.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+
+        this.submit_ntb()?;
+
+        if this.builder.push(&frame) { Ok(()) } else { Err(Error::InvalidParam) }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<::Result<()>> {
+        let this = self.get_mut();
+
+        if !this.builder.is_empty() {
+            this.submit_ntb()?;
+        }
+
+        this.transfers.poll_completed(cx, 0)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parameters() -> NtbParameters {
+        NtbParameters::decode(&[
+            0x1C, 0x00, 0x03, 0x00, 0x00, 0x40, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00,
+            0x00, 0x08, 0x00, 0x00, 0x04, 0x00, 0x02, 0x00, 0x04, 0x00, 0x00, 0x00,
+        ]).unwrap()
+    }
+
+    #[test]
+    fn it_decodes_ntb_parameters() {
+        let parameters = parameters();
+
+        assert!(parameters.ntb32_supported);
+        assert_eq!(16384, parameters.in_max_size);
+        assert_eq!(2048, parameters.out_max_size);
+        assert_eq!(4, parameters.out_divisor);
+        assert_eq!(2, parameters.out_payload_remainder);
+        assert!(NtbParameters::decode(&[0; 27]).is_err());
+    }
+
+    #[test]
+    fn it_aligns_offsets() {
+        assert_eq!(14, align(12, 4, 2));
+        assert_eq!(18, align(15, 4, 2));
+        assert_eq!(16, align(16, 4, 0));
+        assert_eq!(13, align(13, 1, 0));
+    }
+
+    #[test]
+    fn it_round_trips_ntb16() {
+        let mut builder = NtbBuilder::new(NtbFormat::Ntb16, &parameters());
+        assert!(builder.push(&[1, 2, 3]));
+        assert!(builder.push(&[4, 5, 6, 7, 8]));
+
+        let ntb = builder.build(7);
+        assert!(builder.is_empty());
+        assert_eq!(b"NCMH", &ntb[0..4]);
+        assert_eq!(7, read_u16(&ntb, 6));
+        assert_eq!(ntb.len(), read_u16(&ntb, 8) as usize);
+        let ndp_index = read_u16(&ntb, 10) as usize;
+        assert_eq!(14, read_u16(&ntb, ndp_index + 8));
+        assert_eq!(18, read_u16(&ntb, ndp_index + 12));
+
+        assert_eq!(vec![&[1, 2, 3][..], &[4, 5, 6, 7, 8][..]], parse_ntb(&ntb).unwrap());
+    }
+
+    #[test]
+    fn it_round_trips_ntb32() {
+        let mut builder = NtbBuilder::new(NtbFormat::Ntb32, &parameters());
+        assert!(builder.push(&[9; 100]));
+
+        let ntb = builder.build(0);
+        assert_eq!(b"ncmh", &ntb[0..4]);
+        assert_eq!(vec![&[9; 100][..]], parse_ntb(&ntb).unwrap());
+    }
+
+    #[test]
+    fn it_refuses_datagrams_beyond_max_size() {
+        let mut builder = NtbBuilder::new(NtbFormat::Ntb16, &parameters());
+
+        assert!(builder.push(&[0; 1500]));
+        assert!(!builder.push(&[0; 1500]));
+        assert_eq!(1, builder.len());
+    }
+
+    #[test]
+    fn it_rejects_malformed_ntb() {
+        assert!(parse_ntb(b"XXXX\x0C\x00\x00\x00\x10\x00\x0C\x00").is_err());
+        assert!(parse_ntb(b"NCMH\x0C\x00\x00\x00\x10\x00\x40\x00").is_err());
+    }
+
+    #[test]
+    fn it_skips_datagrams_outside_ntb() {
+        let mut ntb = b"NCMH\x0C\x00\x00\x00\x1C\x00\x0C\x00NCM0\x10\x00\x00\x00".to_vec();
+        ntb.extend_from_slice(&[0x00, 0x01, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+        assert!(parse_ntb(&ntb).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,149 @@
+use std::sync::{Arc, RwLock, PoisonError};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use transfer::TransferStatus;
+
+/// A transfer as seen by a [`TransferObserver`](trait.TransferObserver.html).
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct TransferInfo {
+    /// Identifies the transfer while it is submitted, to pair its submission with its
+    /// completion. It may be reused once the transfer has completed.
+    pub id: usize,
+
+    /// The bus number and address of the device, which identify it while it is connected.
+    pub bus_number: u8,
+    pub address: u8,
+
+    /// The address of the endpoint, 0 for control transfers.
+    pub endpoint: u8,
+
+    /// The length of the transfer, including the setup packet of control transfers.
+    pub length: usize,
+}
+
+/// Told about the transfers of a context, e.g., to feed a profiler or tracing system.
+///
+/// Register it with [`Context::add_transfer_observer`](struct.Context.html#method.add_transfer_observer).
+/// The methods are called on the thread that submits the transfer and on the event thread, so
+/// they should return quickly, and must not submit or wait for transfers. Each has an empty
+/// default, so an observer implements only what it needs.
+pub trait TransferObserver: Send + Sync {
+    /// Called before a transfer is handed to `libusb`, including when a completion hook
+    /// resubmits it. A submission that fails is followed by `on_error`.
+    fn on_submit(&self, _transfer: &TransferInfo) {}
+
+    /// Called when a transfer completes successfully, with the number of bytes transferred.
+    fn on_complete(&self, _transfer: &TransferInfo, _actual_length: usize) {}
+
+    /// Called when a transfer has been cancelled.
+    fn on_cancel(&self, _transfer: &TransferInfo) {}
+
+    /// Called when a transfer fails to be submitted, with `NoDevice` or `Error`, or completes
+    /// with any other status.
+    fn on_error(&self, _transfer: &TransferInfo, _status: TransferStatus) {}
+}
+
+/// The observers registered on a context.
+pub struct Observers {
+    // Checked first, so transfers don't take the lock while nothing is registered
+    any: AtomicBool,
+    observers: RwLock<Vec<Arc<dyn TransferObserver>>>,
+}
+
+impl Observers {
+    pub fn new() -> Observers {
+        Observers { any: AtomicBool::new(false), observers: RwLock::new(Vec::new()) }
+    }
+
+    pub fn add(&self, observer: Arc<dyn TransferObserver>) {
+        let mut observers = self.observers.write().unwrap_or_else(PoisonError::into_inner);
+        observers.push(observer);
+        self.any.store(true, Ordering::Release);
+    }
+
+    /// Removes an observer, returning whether it was registered.
+    pub fn remove(&self, observer: &Arc<dyn TransferObserver>) -> bool {
+        let mut observers = self.observers.write().unwrap_or_else(PoisonError::into_inner);
+        let count = observers.len();
+        observers.retain(|registered| !Arc::ptr_eq(registered, observer));
+        self.any.store(!observers.is_empty(), Ordering::Release);
+        observers.len() != count
+    }
+
+    /// Whether any observer is registered, so transfers only describe themselves when someone
+    /// is told.
+    pub fn is_active(&self) -> bool {
+        self.any.load(Ordering::Acquire)
+    }
+
+    /// Tells the observers about a submission.
+    pub fn submitted(&self, transfer: &TransferInfo) {
+        self.each(|observer| observer.on_submit(transfer));
+    }
+
+    /// Tells the observers about a submission that failed.
+    pub fn submit_failed(&self, transfer: &TransferInfo, status: TransferStatus) {
+        self.each(|observer| observer.on_error(transfer, status));
+    }
+
+    /// Tells the observers about a completion, according to its status.
+    pub fn completed(&self, transfer: &TransferInfo, status: TransferStatus, actual_length: usize) {
+        self.each(|observer| match status {
+            TransferStatus::Completed => observer.on_complete(transfer, actual_length),
+            TransferStatus::Cancelled => observer.on_cancel(transfer),
+            status => observer.on_error(transfer, status),
+        });
+    }
+
+    fn each<F>(&self, notify: F) where F: Fn(&dyn TransferObserver) {
+        if !self.is_active() {
+            return;
+        }
+        let observers = self.observers.read().unwrap_or_else(PoisonError::into_inner);
+        for observer in observers.iter() {
+            notify(&**observer);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl TransferObserver for Recorder {
+        fn on_complete(&self, transfer: &TransferInfo, actual_length: usize) {
+            self.events.lock().unwrap().push(format!("complete {:02x} {}", transfer.endpoint, actual_length));
+        }
+
+        fn on_error(&self, transfer: &TransferInfo, status: TransferStatus) {
+            self.events.lock().unwrap().push(format!("error {:02x} {}", transfer.endpoint, status));
+        }
+    }
+
+    #[test]
+    fn it_dispatches_by_status_until_removed() {
+        let observers = Observers::new();
+        let recorder = Arc::new(Recorder::default());
+        let observer: Arc<dyn TransferObserver> = recorder.clone();
+        observers.add(observer.clone());
+
+        let transfer = TransferInfo { id: 1, bus_number: 1, address: 2, endpoint: 0x81, length: 64 };
+        observers.submitted(&transfer);
+        observers.completed(&transfer, TransferStatus::Completed, 10);
+        observers.completed(&transfer, TransferStatus::Cancelled, 0);
+        observers.completed(&transfer, TransferStatus::Stall, 0);
+
+        assert!(observers.remove(&observer));
+        assert!(!observers.remove(&observer));
+        observers.completed(&transfer, TransferStatus::Completed, 10);
+
+        assert_eq!(vec!["complete 81 10", "error 81 Stall"], *recorder.events.lock().unwrap());
+    }
+}
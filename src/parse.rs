@@ -0,0 +1,394 @@
+use std::ptr;
+
+use libc::{c_int, c_uchar};
+use libusb::*;
+
+use config_descriptor::{self, ConfigDescriptor};
+use device_descriptor::{self, DeviceDescriptor};
+use error::Error;
+
+pub const DEVICE_DESCRIPTOR_LENGTH: usize = 18;
+const CONFIG_DESCRIPTOR_LENGTH: usize = 9;
+const INTERFACE_DESCRIPTOR_LENGTH: usize = 9;
+const ENDPOINT_DESCRIPTOR_LENGTH: usize = 7;
+const AUDIO_ENDPOINT_DESCRIPTOR_LENGTH: usize = 9;
+
+/// Parses a device descriptor from its raw bytes.
+///
+/// This does not require a device, so it can be used on captured descriptors or in tests.
+/// Returns `Error::InvalidParam` if `bytes` does not start with a valid device descriptor.
+pub fn parse_device_descriptor(bytes: &[u8]) -> ::Result<DeviceDescriptor> {
+    if bytes.len() < DEVICE_DESCRIPTOR_LENGTH
+        || (bytes[0] as usize) < DEVICE_DESCRIPTOR_LENGTH
+        || bytes[1] != LIBUSB_DT_DEVICE
+    {
+        return Err(Error::InvalidParam);
+    }
+
+    Ok(device_descriptor::from_libusb(libusb_device_descriptor {
+        bLength:            bytes[0],
+        bDescriptorType:    bytes[1],
+        bcdUSB:             read_u16(bytes, 2),
+        bDeviceClass:       bytes[4],
+        bDeviceSubClass:    bytes[5],
+        bDeviceProtocol:    bytes[6],
+        bMaxPacketSize0:    bytes[7],
+        idVendor:           read_u16(bytes, 8),
+        idProduct:          read_u16(bytes, 10),
+        bcdDevice:          read_u16(bytes, 12),
+        iManufacturer:      bytes[14],
+        iProduct:           bytes[15],
+        iSerialNumber:      bytes[16],
+        bNumConfigurations: bytes[17],
+    }))
+}
+
+/// Parses a complete configuration descriptor, including its interface, endpoint and extra
+/// descriptors, from its raw bytes.
+///
+/// `bytes` is what a `GET_DESCRIPTOR` request for the configuration returns, i.e.,
+/// `wTotalLength` bytes. The descriptors are grouped the same way libusb groups them, so the
+/// result can be used exactly like one returned by
+/// [`Device::config_descriptor`](struct.Device.html#method.config_descriptor).
+///
+/// Returns `Error::InvalidParam` if the configuration, interface or endpoint descriptors are
+/// malformed. Trailing bytes after the last complete descriptor are ignored.
+pub fn parse_config_descriptor(bytes: &[u8]) -> ::Result<ConfigDescriptor> {
+    if bytes.len() < CONFIG_DESCRIPTOR_LENGTH
+        || (bytes[0] as usize) < CONFIG_DESCRIPTOR_LENGTH
+        || (bytes[0] as usize) > bytes.len()
+        || bytes[1] != LIBUSB_DT_CONFIG
+    {
+        return Err(Error::InvalidParam);
+    }
+
+    let total_length = (read_u16(bytes, 2) as usize).min(bytes.len());
+    let bytes = bytes[..total_length.max(bytes[0] as usize)].to_vec();
+
+    let mut offset = bytes[0] as usize;
+    let config_extra = skip_to(&bytes, &mut offset, &[LIBUSB_DT_INTERFACE]);
+
+    let mut interfaces = Vec::new();
+
+    while interfaces.len() < bytes[4] as usize && header(&bytes, offset) == Some(LIBUSB_DT_INTERFACE) {
+        if (bytes[offset] as usize) < INTERFACE_DESCRIPTOR_LENGTH {
+            return Err(Error::InvalidParam);
+        }
+
+        let number = bytes[offset + 2];
+        let mut altsettings = Vec::new();
+
+        while header(&bytes, offset) == Some(LIBUSB_DT_INTERFACE)
+            && (bytes[offset] as usize) >= INTERFACE_DESCRIPTOR_LENGTH
+            && bytes[offset + 2] == number
+        {
+            altsettings.push(parse_interface(&bytes, &mut offset)?);
+        }
+
+        interfaces.push(altsettings);
+    }
+
+    Ok(OwnedConfig::build(bytes, config_extra, interfaces))
+}
+
+struct ParsedInterface {
+    start: usize,
+    extra: (usize, usize),
+    endpoints: Vec<(usize, (usize, usize))>,
+}
+
+fn parse_interface(bytes: &[u8], offset: &mut usize) -> ::Result<ParsedInterface> {
+    let start = *offset;
+    *offset += bytes[start] as usize;
+    let extra = skip_to(bytes, offset, &[LIBUSB_DT_INTERFACE, LIBUSB_DT_ENDPOINT, LIBUSB_DT_CONFIG, LIBUSB_DT_DEVICE]);
+
+    let mut endpoints = Vec::new();
+
+    for _ in 0..bytes[start + 4] {
+        if header(bytes, *offset) != Some(LIBUSB_DT_ENDPOINT) {
+            break;
+        }
+
+        let endpoint = *offset;
+
+        if (bytes[endpoint] as usize) < ENDPOINT_DESCRIPTOR_LENGTH {
+            return Err(Error::InvalidParam);
+        }
+
+        *offset += bytes[endpoint] as usize;
+        let endpoint_extra = skip_to(bytes, offset, &[LIBUSB_DT_INTERFACE, LIBUSB_DT_ENDPOINT, LIBUSB_DT_CONFIG, LIBUSB_DT_DEVICE]);
+
+        endpoints.push((endpoint, endpoint_extra));
+    }
+
+    Ok(ParsedInterface { start, extra, endpoints })
+}
+
+/// Returns the type of the well-formed descriptor at `offset`, if any.
+fn header(bytes: &[u8], offset: usize) -> Option<u8> {
+    if offset + 2 > bytes.len() {
+        return None;
+    }
+
+    let length = bytes[offset] as usize;
+
+    if length < 2 || offset + length > bytes.len() {
+        None
+    }
+    else {
+        Some(bytes[offset + 1])
+    }
+}
+
+/// Advances `offset` past descriptors until one of type `stop` is found, returning the range
+/// that was skipped.
+fn skip_to(bytes: &[u8], offset: &mut usize, stop: &[u8]) -> (usize, usize) {
+    let start = *offset;
+
+    while let Some(descriptor_type) = header(bytes, *offset) {
+        if stop.contains(&descriptor_type) {
+            break;
+        }
+
+        *offset += bytes[*offset] as usize;
+    }
+
+    (start, *offset)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    bytes[offset] as u16 | (bytes[offset + 1] as u16) << 8
+}
+
+/// Storage for a configuration descriptor tree that was not allocated by libusb.
+///
+/// The libusb structures point into the vectors, whose heap buffers don't move when this
+/// struct does.
+#[doc(hidden)]
+pub struct OwnedConfig {
+    config: libusb_config_descriptor,
+    _interfaces: Vec<libusb_interface>,
+    _altsettings: Vec<Vec<libusb_interface_descriptor>>,
+    _endpoints: Vec<Vec<libusb_endpoint_descriptor>>,
+    _bytes: Vec<u8>,
+}
+
+impl OwnedConfig {
+    fn build(bytes: Vec<u8>, config_extra: (usize, usize), parsed: Vec<Vec<ParsedInterface>>) -> ConfigDescriptor {
+        let extra = |range: (usize, usize)| -> (*const c_uchar, c_int) {
+            if range.0 == range.1 {
+                (ptr::null(), 0)
+            }
+            else {
+                (bytes[range.0..].as_ptr(), (range.1 - range.0) as c_int)
+            }
+        };
+
+        let mut endpoints = Vec::new();
+        let mut altsettings = Vec::new();
+
+        for interface in &parsed {
+            let mut settings = Vec::new();
+
+            for setting in interface {
+                let list = setting.endpoints.iter().map(|&(offset, range)| {
+                    let (extra, extra_length) = extra(range);
+                    let audio = bytes[offset] as usize >= AUDIO_ENDPOINT_DESCRIPTOR_LENGTH;
+
+                    libusb_endpoint_descriptor {
+                        bLength:          bytes[offset],
+                        bDescriptorType:  bytes[offset + 1],
+                        bEndpointAddress: bytes[offset + 2],
+                        bmAttributes:     bytes[offset + 3],
+                        wMaxPacketSize:   read_u16(&bytes, offset + 4),
+                        bInterval:        bytes[offset + 6],
+                        bRefresh:         if audio { bytes[offset + 7] } else { 0 },
+                        bSynchAddress:    if audio { bytes[offset + 8] } else { 0 },
+                        extra,
+                        extra_length,
+                    }
+                }).collect::<Vec<_>>();
+
+                let offset = setting.start;
+                let (extra, extra_length) = extra(setting.extra);
+
+                settings.push(libusb_interface_descriptor {
+                    bLength:            bytes[offset],
+                    bDescriptorType:    bytes[offset + 1],
+                    bInterfaceNumber:   bytes[offset + 2],
+                    bAlternateSetting:  bytes[offset + 3],
+                    bNumEndpoints:      list.len() as u8,
+                    bInterfaceClass:    bytes[offset + 5],
+                    bInterfaceSubClass: bytes[offset + 6],
+                    bInterfaceProtocol: bytes[offset + 7],
+                    iInterface:         bytes[offset + 8],
+                    endpoint:           if list.is_empty() { ptr::null() } else { list.as_ptr() },
+                    extra,
+                    extra_length,
+                });
+
+                endpoints.push(list);
+            }
+
+            altsettings.push(settings);
+        }
+
+        let interfaces = altsettings.iter().map(|settings| {
+            libusb_interface {
+                altsetting:     settings.as_ptr(),
+                num_altsetting: settings.len() as c_int,
+            }
+        }).collect::<Vec<_>>();
+
+        let (extra, extra_length) = extra(config_extra);
+
+        let config = libusb_config_descriptor {
+            bLength:             bytes[0],
+            bDescriptorType:     bytes[1],
+            wTotalLength:        read_u16(&bytes, 2),
+            bNumInterfaces:      interfaces.len() as u8,
+            bConfigurationValue: bytes[5],
+            iConfiguration:      bytes[6],
+            bmAttributes:        bytes[7],
+            bMaxPower:           bytes[8],
+            interface:           if interfaces.is_empty() { ptr::null() } else { interfaces.as_ptr() },
+            extra,
+            extra_length,
+        };
+
+        config_descriptor::from_owned(Box::new(OwnedConfig {
+            config,
+            _interfaces: interfaces,
+            _altsettings: altsettings,
+            _endpoints: endpoints,
+            _bytes: bytes,
+        }))
+    }
+
+    #[doc(hidden)]
+    pub fn descriptor(&self) -> *const libusb_config_descriptor {
+        &self.config
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{parse_config_descriptor, parse_device_descriptor};
+    use fields::ClassCode;
+
+    use fields::{Direction, TransferType};
+
+    // Configuration of a composite CDC-ACM + vendor device.
+    const CONFIG: &[u8] = &[
+        0x09, 0x02, 0x4B, 0x00, 0x02, 0x01, 0x04, 0xA0, 0x32,
+        // Interface association descriptor
+        0x08, 0x0B, 0x00, 0x02, 0x02, 0x02, 0x01, 0x00,
+        // Interface 0: CDC communication
+        0x09, 0x04, 0x00, 0x00, 0x01, 0x02, 0x02, 0x01, 0x05,
+        0x05, 0x24, 0x00, 0x10, 0x01,
+        0x05, 0x24, 0x01, 0x00, 0x01,
+        0x04, 0x24, 0x02, 0x06,
+        0x05, 0x24, 0x06, 0x00, 0x01,
+        0x07, 0x05, 0x82, 0x03, 0x08, 0x00, 0x10,
+        // Interface 1: CDC data
+        0x09, 0x04, 0x01, 0x00, 0x02, 0x0A, 0x00, 0x00, 0x00,
+        0x07, 0x05, 0x01, 0x02, 0x40, 0x00, 0x00,
+        0x07, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn it_parses_device_descriptor() {
+        let device = parse_device_descriptor(&[
+            0x12, 0x01, 0x00, 0x02, 0xEF, 0x02, 0x01, 0x40,
+            0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0x01, 0x02, 0x03, 0x01,
+        ]).unwrap();
+
+        assert_eq!(0x1234, device.vendor_id());
+        assert_eq!(0x5678, device.product_id());
+        assert_eq!(ClassCode::Miscellaneous, device.class_code());
+        assert_eq!(64, device.max_packet_size());
+        assert_eq!(Some(3), device.serial_number_string_index());
+        assert_eq!(1, device.num_configurations());
+    }
+
+    #[test]
+    fn it_rejects_short_device_descriptor() {
+        assert!(parse_device_descriptor(&[0x12, 0x01, 0x00, 0x02]).is_err());
+    }
+
+    #[test]
+    fn it_rejects_wrong_descriptor_type() {
+        assert!(parse_device_descriptor(&[0x12, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        assert!(parse_config_descriptor(&[0x09, 0x01, 0x09, 0x00, 0x00, 0x01, 0x00, 0x80, 0x32]).is_err());
+    }
+
+    #[test]
+    fn it_parses_config_header() {
+        let config = parse_config_descriptor(CONFIG).unwrap();
+
+        assert_eq!(1, config.number());
+        assert_eq!(100, config.max_power());
+        assert!(config.remote_wakeup());
+        assert_eq!(Some(4), config.description_string_index());
+        assert_eq!(2, config.num_interfaces());
+    }
+
+    #[test]
+    fn it_keeps_config_extra_descriptors() {
+        let config = parse_config_descriptor(CONFIG).unwrap();
+
+        assert_eq!(vec![0x0B], config.extra_descriptors().map(|(t, _)| t).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn it_parses_interfaces_and_endpoints() {
+        let config = parse_config_descriptor(CONFIG).unwrap();
+        let interfaces = config.interfaces().collect::<Vec<_>>();
+
+        let comm = interfaces[0].descriptors().next().unwrap();
+        assert_eq!(ClassCode::Communications, comm.class_code());
+        assert_eq!(Some(5), comm.description_string_index());
+        assert_eq!(4, comm.extra_descriptors().count());
+
+        let endpoint = comm.endpoint_descriptors().next().unwrap();
+        assert_eq!(0x82, endpoint.address());
+        assert_eq!(TransferType::Interrupt, endpoint.transfer_type());
+        assert_eq!(16, endpoint.interval());
+
+        let data = interfaces[1].descriptors().next().unwrap();
+        assert_eq!(1, data.interface_number());
+        assert_eq!(vec![Direction::Out, Direction::In],
+                   data.endpoint_descriptors().map(|e| e.direction()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn it_groups_alternate_settings() {
+        let config = parse_config_descriptor(&[
+            0x09, 0x02, 0x2D, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32,
+            0x09, 0x04, 0x01, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00,
+            0x09, 0x04, 0x01, 0x01, 0x01, 0x01, 0x02, 0x00, 0x00,
+            0x09, 0x05, 0x01, 0x09, 0xC0, 0x00, 0x01, 0x00, 0x00,
+        ]).unwrap();
+
+        let interfaces = config.interfaces().collect::<Vec<_>>();
+        assert_eq!(1, interfaces.len());
+        assert_eq!(vec![(0, 0), (1, 1)], interfaces[0].descriptors().map(|s| (s.setting_number(), s.num_endpoints())).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn it_truncates_to_total_length() {
+        let config = parse_config_descriptor(&CONFIG[..CONFIG.len() - 14]).unwrap();
+        let interfaces = config.interfaces().collect::<Vec<_>>();
+
+        assert_eq!(0, interfaces[1].descriptors().next().unwrap().num_endpoints());
+    }
+
+    #[test]
+    fn it_rejects_malformed_interface() {
+        assert!(parse_config_descriptor(&[
+            0x09, 0x02, 0x10, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32,
+            0x07, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]).is_err());
+    }
+}
@@ -0,0 +1,383 @@
+//! Printer class support.
+//!
+//! Print data is sent raw through the `AsyncWrite` implementation of
+//! [`Printer`](struct.Printer.html), so it must already be in a language the printer
+//! understands, e.g., PCL or PostScript.
+
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task;
+
+use futures_io::AsyncWrite;
+
+use config_descriptor::ConfigDescriptor;
+use control::{self, ControlFuture};
+use device::Device;
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::{ClassCode, Direction, Recipient, RequestType, TransferType, request_type};
+use transfer::TransferFuture;
+use transfer_queue;
+
+const GET_DEVICE_ID: u8 = 0x00;
+const GET_PORT_STATUS: u8 = 0x01;
+const SOFT_RESET: u8 = 0x02;
+
+/// Largest device ID that is read.
+const DEVICE_ID_LENGTH: u16 = 1024;
+
+/// An alternate setting of a printer interface.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct PrinterInterface {
+    /// The interface number.
+    pub interface: u8,
+
+    /// The alternate setting.
+    pub setting: u8,
+
+    /// The interface protocol: 1 for unidirectional, 2 for bidirectional and 3 for IEEE 1284.4
+    /// compatible bidirectional.
+    pub protocol: u8,
+}
+
+/// Finds the printer interface settings of a configuration.
+pub fn find_printer_interfaces(config: &ConfigDescriptor) -> Vec<PrinterInterface> {
+    config.interfaces_of_class(ClassCode::Printer).into_iter()
+        .filter(|setting| setting.first_endpoint(TransferType::Bulk, Direction::Out).is_some())
+        .map(|setting| PrinterInterface {
+            interface: setting.interface_number(),
+            setting: setting.setting_number(),
+            protocol: setting.protocol_code(),
+        })
+        .collect()
+}
+
+/// Splits an IEEE 1284 device ID into its keys and values.
+///
+/// Keys and values are trimmed of surrounding whitespace, and entries without a colon are
+/// skipped.
+///
+/// ```
+/// let id = libusb_async::printer::parse_device_id("MFG:ACME;MDL:LaserJet 9;CMD:PCL,PJL;");
+/// assert_eq!(vec![("MFG", "ACME"), ("MDL", "LaserJet 9"), ("CMD", "PCL,PJL")], id);
+/// ```
+pub fn parse_device_id(id: &str) -> Vec<(&str, &str)> {
+    id.split(';')
+        .filter_map(|entry| {
+            let colon = entry.find(':')?;
+            Some((entry[..colon].trim(), entry[colon + 1..].trim()))
+        })
+        .collect()
+}
+
+fn decode_device_id(data: &[u8]) -> ::Result<String> {
+    if data.len() < 2 {
+        return Err(Error::InvalidParam);
+    }
+
+    // The length includes the two length bytes
+    let length = (u16::from_be_bytes([data[0], data[1]]) as usize).clamp(2, data.len());
+    Ok(String::from_utf8_lossy(&data[2..length]).into_owned())
+}
+
+/// The status of the printer port, as returned by GET_PORT_STATUS.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct PortStatus {
+    bits: u8,
+}
+
+impl PortStatus {
+    /// Returns the raw status byte.
+    pub fn bits(&self) -> u8 {
+        self.bits
+    }
+
+    /// Indicates if the printer is out of paper.
+    pub fn paper_empty(&self) -> bool {
+        self.bits & 0x20 != 0
+    }
+
+    /// Indicates if the printer is selected, i.e., online.
+    pub fn selected(&self) -> bool {
+        self.bits & 0x10 != 0
+    }
+
+    /// Indicates if the printer reports an error.
+    pub fn error(&self) -> bool {
+        self.bits & 0x08 == 0
+    }
+
+    fn decode(data: &[u8]) -> ::Result<PortStatus> {
+        match data.first() {
+            Some(&bits) => Ok(PortStatus { bits }),
+            None => Err(Error::InvalidParam),
+        }
+    }
+}
+
+/// A claimed printer interface.
+///
+/// Print data is written through the `AsyncWrite` implementation, which is also implemented
+/// for `&Printer` so the status can be queried while printing. A write that stalls is retried
+/// once after a soft reset of the printer.
+pub struct Printer {
+    handle: DeviceHandle,
+    interface: PrinterInterface,
+    config_index: u8,
+    bulk_out: u8,
+    write: Mutex<WriteState>,
+    kernel_driver_detached: bool,
+}
+
+enum WriteState {
+    Idle,
+    Writing { future: TransferFuture, data: Vec<u8>, retried: bool },
+    Resetting { future: ControlFuture<usize>, data: Vec<u8> },
+}
+
+impl Printer {
+    /// Opens a device, claims a printer interface and selects its alternate setting.
+    ///
+    /// A kernel driver bound to the interface, e.g., `usblp`, is detached and reattached when
+    /// the printer is dropped.
+    ///
+    /// ## Errors
+    ///
+    /// * `NotFound` if the active configuration has no such setting, or it has no bulk OUT
+    ///   endpoint.
+    /// * Any error returned while opening the device or claiming the interface.
+    pub fn open(device: &Device, interface: &PrinterInterface) -> ::Result<Printer> {
+        let (config_number, bulk_out) = {
+            let config = device.active_config_descriptor()?;
+            let bulk_out = config.interfaces()
+                .find(|i| i.number() == interface.interface)
+                .and_then(|i| i.descriptors().find(|s| s.setting_number() == interface.setting))
+                .and_then(|setting| setting.first_endpoint(TransferType::Bulk, Direction::Out))
+                .ok_or(Error::NotFound)?
+                .address();
+
+            (config.number(), bulk_out)
+        };
+
+        // GET_DEVICE_ID addresses the configuration by index rather than value
+        let mut config_index = 0;
+        for index in 0..device.device_descriptor()?.num_configurations() {
+            if device.config_descriptor(index)?.number() == config_number {
+                config_index = index;
+                break;
+            }
+        }
+
+        let mut handle = device.open()?;
+
+        let kernel_driver_detached = handle.kernel_driver_active(interface.interface).unwrap_or(false);
+        if kernel_driver_detached {
+            handle.detach_kernel_driver(interface.interface)?;
+        }
+
+        handle.claim_interface(interface.interface)?;
+
+        if interface.setting != 0 {
+            handle.set_alternate_setting(interface.interface, interface.setting)?;
+        }
+
+        Ok(Printer {
+            handle,
+            interface: *interface,
+            config_index,
+            bulk_out,
+            write: Mutex::new(WriteState::Idle),
+            kernel_driver_detached,
+        })
+    }
+
+    /// Returns the handle of the device.
+    pub fn handle(&self) -> &DeviceHandle {
+        &self.handle
+    }
+
+    /// Returns the claimed interface setting.
+    pub fn interface(&self) -> &PrinterInterface {
+        &self.interface
+    }
+
+    /// Reads the IEEE 1284 device ID string.
+    ///
+    /// Use [`parse_device_id`](fn.parse_device_id.html) to split it into keys and values.
+    pub fn get_device_id(&self) -> ControlFuture<String> {
+        control::read(&self.handle,
+                      request_type(Direction::In, RequestType::Class, Recipient::Interface),
+                      GET_DEVICE_ID,
+                      self.config_index as u16,
+                      (self.interface.interface as u16) << 8 | self.interface.setting as u16,
+                      DEVICE_ID_LENGTH,
+                      decode_device_id)
+    }
+
+    /// Reads the port status.
+    pub fn port_status(&self) -> ControlFuture<PortStatus> {
+        control::read(&self.handle,
+                      request_type(Direction::In, RequestType::Class, Recipient::Interface),
+                      GET_PORT_STATUS,
+                      0,
+                      self.interface.interface as u16,
+                      1,
+                      PortStatus::decode)
+    }
+
+    /// Flushes the buffers of the printer and resets its bulk endpoints.
+    pub fn soft_reset(&self) -> ControlFuture<usize> {
+        control::write(&self.handle,
+                       request_type(Direction::Out, RequestType::Class, Recipient::Interface),
+                       SOFT_RESET,
+                       0,
+                       self.interface.interface as u16,
+                       &[])
+    }
+
+    fn submit_write(&self, data: &[u8]) -> ::Result<TransferFuture> {
+        transfer_queue::submit_bulk_write(&self.handle, self.bulk_out.into(), data)
+    }
+
+    fn poll_write_bytes(&self, cx: &mut task::Context, buf: &[u8]) -> task::Poll<io::Result<usize>> {
+        let mut state = self.write.lock().unwrap();
+
+        if let WriteState::Idle = *state {
+            *state = WriteState::Writing { future: self.submit_write(buf)?, data: buf.to_vec(), retried: false };
+        }
+
+        self.poll_pending_write(&mut state, cx)
+    }
+
+    fn poll_flush_bytes(&self, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        let mut state = self.write.lock().unwrap();
+
+        if let WriteState::Idle = *state {
+            return task::Poll::Ready(Ok(()));
+        }
+
+        self.poll_pending_write(&mut state, cx).map(|result| result.map(|_| ()))
+    }
+
+    /// Polls a pending write to completion, resolving to the number of bytes written.
+    fn poll_pending_write(&self, state: &mut WriteState, cx: &mut task::Context) -> task::Poll<io::Result<usize>> {
+        loop {
+            match mem::replace(state, WriteState::Idle) {
+                WriteState::Idle => unreachable!(),
+                WriteState::Writing { mut future, data, retried } => {
+                    let result = match Pin::new(&mut future).poll(cx) {
+                        task::Poll::Pending => {
+                            *state = WriteState::Writing { future, data, retried };
+                            return task::Poll::Pending;
+                        },
+                        task::Poll::Ready(result) => result,
+                    };
+
+                    match result.and_then(|transfer| transfer.check_status().map(|_| transfer)) {
+                        Ok(transfer) => return task::Poll::Ready(Ok(transfer.get_buffer().len())),
+                        Err(Error::Pipe) if !retried => {
+                            *state = WriteState::Resetting { future: self.soft_reset(), data };
+                        },
+                        Err(e) => return task::Poll::Ready(Err(e.into())),
+                    }
+                },
+                WriteState::Resetting { mut future, data } => {
+                    match Pin::new(&mut future).poll(cx) {
+                        task::Poll::Pending => {
+                            *state = WriteState::Resetting { future, data };
+                            return task::Poll::Pending;
+                        },
+                        task::Poll::Ready(result) => { result?; },
+                    }
+
+                    self.handle.clear_halt(self.bulk_out.into())?;
+                    *state = WriteState::Writing { future: self.submit_write(&data)?, data, retried: true };
+                },
+            }
+        }
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        if self.kernel_driver_detached {
+            let _ = self.handle.release_interface(self.interface.interface);
+            let _ = self.handle.attach_kernel_driver(self.interface.interface);
+        }
+    }
+}
+
+impl AsyncWrite for &Printer {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context, buf: &[u8]) -> task::Poll<io::Result<usize>> {
+        self.poll_write_bytes(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.poll_flush_bytes(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.poll_flush_bytes(cx)
+    }
+}
+
+impl AsyncWrite for Printer {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context, buf: &[u8]) -> task::Poll<io::Result<usize>> {
+        self.poll_write_bytes(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.poll_flush_bytes(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.poll_flush_bytes(cx)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::mem;
+
+    #[test]
+    fn it_finds_printer_interfaces() {
+        let printer = interface!(merge!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x01, bmAttributes: 0x02))
+                                        => bInterfaceClass: 0x07, bInterfaceSubClass: 0x01, bInterfaceProtocol: 0x02));
+        let other = interface!(interface_descriptor!(bInterfaceNumber: 1, bInterfaceClass: 0x07));
+        let config = config_descriptor!(printer, other);
+        let config = unsafe { ::config_descriptor::from_libusb(&config) };
+
+        assert_eq!(vec![PrinterInterface { interface: 0, setting: 0, protocol: 2 }], find_printer_interfaces(&config));
+        mem::forget(config);
+    }
+
+    #[test]
+    fn it_decodes_device_id() {
+        let mut data = vec![0x00, 0x0B];
+        data.extend_from_slice(b"MFG:HP;\0\0\0");
+
+        assert_eq!("MFG:HP;\0\0", decode_device_id(&data).unwrap());
+        assert_eq!("MFG:HP;", decode_device_id(&data[..9]).unwrap());
+        assert!(decode_device_id(&[0x00]).is_err());
+    }
+
+    #[test]
+    fn it_parses_device_id() {
+        assert_eq!(vec![("MFG", "HP"), ("CLS", "PRINTER")], parse_device_id(" MFG: HP ;junk;CLS:PRINTER"));
+    }
+
+    #[test]
+    fn it_decodes_port_status() {
+        let status = PortStatus::decode(&[0x18]).unwrap();
+
+        assert!(status.selected());
+        assert!(!status.paper_empty());
+        assert!(!status.error());
+        assert!(PortStatus::decode(&[0x20]).unwrap().error());
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use config_descriptor::ConfigDescriptor;
+use device::Device;
+use device_descriptor::DeviceDescriptor;
+use error::Error;
+use fields::ClassCode;
+
+use ch34x::{self, Ch34xPort};
+use cp210x::{self, Cp210xPort};
+use dfu::{self, Dfu, DfuInterface};
+use ecm::{self, EcmDevice, EcmInterfaces};
+use ftdi::{self, FtdiPort};
+use hid::HidDevice;
+use midi::{self, MidiPort};
+use msc::{self, MassStorage};
+use msc::uas::{self, UasStorage};
+use ncm::{self, NcmDevice, NcmInterfaces};
+use printer::{self, Printer, PrinterInterface};
+use ptp::{self, PtpDevice};
+use rndis::{self, RndisDevice, RndisInterfaces};
+use serial::{self, AcmInterfaces, AcmPort};
+use tmc::{self, Instrument};
+
+const SUBCLASS_VIDEO_CONTROL: u8 = 0x01;
+const SUBCLASS_AUDIO_CONTROL: u8 = 0x01;
+
+/// A function of a device that one of the built-in class modules can drive, found by
+/// [`Device::probe_functions`](struct.Device.html#method.probe_functions).
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum Function {
+    /// A HID interface, driven by [`HidDevice`](hid/struct.HidDevice.html). FIDO security keys
+    /// are HID interfaces too, and are told apart by their report descriptor.
+    Hid(u8),
+
+    /// A CDC-ACM serial port.
+    Acm(AcmInterfaces),
+
+    /// A CDC-ECM network interface.
+    Ecm(EcmInterfaces),
+
+    /// A CDC-NCM network interface.
+    Ncm(NcmInterfaces),
+
+    /// An RNDIS network interface.
+    Rndis(RndisInterfaces),
+
+    /// A mass storage interface using the Bulk-Only Transport.
+    BulkOnlyStorage(u8),
+
+    /// A mass storage interface with a USB Attached SCSI alternate setting.
+    UasStorage(u8),
+
+    /// A DFU interface, in run-time or DFU mode.
+    Dfu(DfuInterface),
+
+    /// A USB MIDI streaming interface.
+    Midi(u8),
+
+    /// A printer interface.
+    Printer(PrinterInterface),
+
+    /// A USBTMC test and measurement interface.
+    Tmc(u8),
+
+    /// A PTP still image interface.
+    Ptp(u8),
+
+    /// A port of an FTDI serial converter.
+    Ftdi(u8),
+
+    /// A port of a CP210x serial converter.
+    Cp210x(u8),
+
+    /// A CH340 or CH341 serial converter.
+    Ch34x(u8),
+
+    /// A video control interface, whose streams are set up with the [`uvc`](uvc/index.html)
+    /// module.
+    Video(u8),
+
+    /// An audio control interface, whose streams are set up with the [`uac`](uac/index.html)
+    /// module.
+    Audio(u8),
+}
+
+impl Function {
+    /// Returns the interfaces the function uses, in ascending order.
+    pub fn interfaces(&self) -> Vec<u8> {
+        match *self {
+            Function::Acm(interfaces) => pair(interfaces.control_interface, interfaces.data_interface),
+            Function::Ecm(interfaces) => pair(interfaces.control_interface, interfaces.data_interface),
+            Function::Ncm(interfaces) => pair(interfaces.control_interface, interfaces.data_interface),
+            Function::Rndis(interfaces) => pair(interfaces.control_interface, interfaces.data_interface),
+            Function::Dfu(interface) => vec![interface.interface],
+            Function::Printer(interface) => vec![interface.interface],
+            Function::Hid(interface) | Function::BulkOnlyStorage(interface) | Function::UasStorage(interface)
+                | Function::Midi(interface) | Function::Tmc(interface) | Function::Ptp(interface)
+                | Function::Ftdi(interface) | Function::Cp210x(interface) | Function::Ch34x(interface)
+                | Function::Video(interface) | Function::Audio(interface) => vec![interface],
+        }
+    }
+
+    /// Returns a short name of the function, e.g., "CDC-ACM".
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Function::Hid(_) => "HID",
+            Function::Acm(_) => "CDC-ACM",
+            Function::Ecm(_) => "CDC-ECM",
+            Function::Ncm(_) => "CDC-NCM",
+            Function::Rndis(_) => "RNDIS",
+            Function::BulkOnlyStorage(_) => "Mass storage (BOT)",
+            Function::UasStorage(_) => "Mass storage (UAS)",
+            Function::Dfu(_) => "DFU",
+            Function::Midi(_) => "MIDI",
+            Function::Printer(_) => "Printer",
+            Function::Tmc(_) => "USBTMC",
+            Function::Ptp(_) => "PTP",
+            Function::Ftdi(_) => "FTDI serial",
+            Function::Cp210x(_) => "CP210x serial",
+            Function::Ch34x(_) => "CH34x serial",
+            Function::Video(_) => "UVC video",
+            Function::Audio(_) => "UAC audio",
+        }
+    }
+
+    /// Opens the device and claims the interfaces of the function with its driver.
+    ///
+    /// Returns `Error::NotSupported` for video and audio functions, which have no driver
+    /// object.
+    pub fn open(&self, device: &Device) -> ::Result<Driver> {
+        Ok(match *self {
+            Function::Hid(interface) => Driver::Hid(HidDevice::open(device, interface)?),
+            Function::Acm(ref interfaces) => Driver::Acm(AcmPort::open(device, interfaces)?),
+            Function::Ecm(ref interfaces) => Driver::Ecm(EcmDevice::open(device, interfaces)?),
+            Function::Ncm(ref interfaces) => Driver::Ncm(NcmDevice::open(device, interfaces)?),
+            Function::Rndis(ref interfaces) => Driver::Rndis(RndisDevice::open(device, interfaces)?),
+            Function::BulkOnlyStorage(interface) => Driver::BulkOnlyStorage(MassStorage::open(device, interface)?),
+            Function::UasStorage(interface) => Driver::UasStorage(UasStorage::open(device, interface)?),
+            Function::Dfu(ref interface) => Driver::Dfu(Dfu::open(device, interface)?),
+            Function::Midi(interface) => Driver::Midi(MidiPort::open(device, interface)?),
+            Function::Printer(ref interface) => Driver::Printer(Printer::open(device, interface)?),
+            Function::Tmc(interface) => Driver::Tmc(Instrument::open(device, interface)?),
+            Function::Ptp(interface) => Driver::Ptp(PtpDevice::open(device, interface)?),
+            Function::Ftdi(interface) => Driver::Ftdi(FtdiPort::open(device, interface)?),
+            Function::Cp210x(interface) => Driver::Cp210x(Cp210xPort::open(device, interface)?),
+            Function::Ch34x(interface) => Driver::Ch34x(Ch34xPort::open(device, interface)?),
+            Function::Video(_) | Function::Audio(_) => return Err(Error::NotSupported),
+        })
+    }
+}
+
+impl fmt::Display for Function {
+    /// Formats the function as its interfaces and name, e.g., "interface 0/1: CDC-ACM".
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let interfaces = self.interfaces().iter().map(|interface| interface.to_string()).collect::<Vec<_>>();
+        write!(f, "interface {}: {}", interfaces.join("/"), self.name())
+    }
+}
+
+fn pair(first: u8, second: u8) -> Vec<u8> {
+    if first <= second { vec![first, second] } else { vec![second, first] }
+}
+
+/// An open driver, returned by [`Function::open`](enum.Function.html#method.open).
+pub enum Driver {
+    Hid(HidDevice),
+    Acm(AcmPort),
+    Ecm(EcmDevice),
+    Ncm(NcmDevice),
+    Rndis(RndisDevice),
+    BulkOnlyStorage(MassStorage),
+    UasStorage(UasStorage),
+    Dfu(Dfu),
+    Midi(MidiPort),
+    Printer(Printer),
+    Tmc(Instrument),
+    Ptp(PtpDevice),
+    Ftdi(FtdiPort),
+    Cp210x(Cp210xPort),
+    Ch34x(Ch34xPort),
+}
+
+/// Finds the functions of a configuration that the built-in class modules can drive.
+///
+/// The functions are ordered by their first interface. Each interface belongs to at most one
+/// function, with the more specific function chosen, e.g., UAS over Bulk-Only Transport and
+/// RNDIS over a plain interface of its class. Vendor-specific interfaces are reported as ports
+/// of a serial converter if the device descriptor identifies one.
+pub fn probe_functions(device: &DeviceDescriptor, config: &ConfigDescriptor) -> Vec<Function> {
+    let mut functions = Vec::new();
+    let mut claimed = BTreeSet::new();
+
+    {
+        let mut add = |function: Function| {
+            let interfaces = function.interfaces();
+
+            if interfaces.iter().all(|interface| !claimed.contains(interface)) {
+                claimed.extend(interfaces);
+                functions.push(function);
+            }
+        };
+
+        rndis::find_rndis_interfaces(config).into_iter().for_each(|i| add(Function::Rndis(i)));
+        serial::find_acm_interfaces(config).into_iter().for_each(|i| add(Function::Acm(i)));
+        ecm::find_ecm_interfaces(config).into_iter().for_each(|i| add(Function::Ecm(i)));
+        ncm::find_ncm_interfaces(config).into_iter().for_each(|i| add(Function::Ncm(i)));
+        uas::find_uas_interface(config).into_iter().for_each(|i| add(Function::UasStorage(i)));
+        msc::find_bulk_only_interface(config).into_iter().for_each(|i| add(Function::BulkOnlyStorage(i)));
+        dfu::find_dfu_interfaces(config).into_iter().for_each(|i| add(Function::Dfu(i)));
+        midi::find_midi_interface(config).into_iter().for_each(|i| add(Function::Midi(i)));
+        printer::find_printer_interfaces(config).into_iter().for_each(|i| add(Function::Printer(i)));
+        tmc::find_tmc_interface(config).into_iter().for_each(|i| add(Function::Tmc(i)));
+        ptp::find_ptp_interface(config).into_iter().for_each(|i| add(Function::Ptp(i)));
+
+        for setting in config.interfaces().filter_map(|interface| interface.descriptors().next()) {
+            let interface = setting.interface_number();
+
+            match setting.class_code() {
+                ClassCode::Hid => add(Function::Hid(interface)),
+                ClassCode::Video if setting.sub_class_code() == SUBCLASS_VIDEO_CONTROL => add(Function::Video(interface)),
+                ClassCode::Audio if setting.sub_class_code() == SUBCLASS_AUDIO_CONTROL => add(Function::Audio(interface)),
+                ClassCode::VendorSpecific if ftdi::is_ftdi_device(device) => add(Function::Ftdi(interface)),
+                ClassCode::VendorSpecific if cp210x::is_cp210x_device(device) => add(Function::Cp210x(interface)),
+                ClassCode::VendorSpecific if ch34x::is_ch34x_device(device) => add(Function::Ch34x(interface)),
+                _ => {},
+            }
+        }
+    }
+
+    functions.sort_by_key(|function| function.interfaces()[0]);
+    functions
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::mem;
+
+    const CDC_EXTRA: [u8; 5] = [0x05, 0x24, 0x06, 0x00, 0x01];
+
+    #[test]
+    fn it_probes_composite_device() {
+        let hid = interface!(interface_descriptor!(bInterfaceNumber: 2, bInterfaceClass: 0x03));
+        let control = interface!(interface_descriptor!(bInterfaceClass: 0x02, bInterfaceSubClass: 0x02, extra: CDC_EXTRA.as_ptr(), extra_length: 5));
+        let data = interface!(interface_descriptor!(bInterfaceNumber: 1, bInterfaceClass: 0x0A));
+        let config = config_descriptor!(hid, control, data);
+        let config = unsafe { ::config_descriptor::from_libusb(&config) };
+        let device = ::device_descriptor::from_libusb(device_descriptor!(idVendor: 0x1234));
+
+        let functions = probe_functions(&device, &config);
+        assert_eq!(vec![Function::Acm(AcmInterfaces { control_interface: 0, data_interface: 1 }), Function::Hid(2)], functions);
+        assert_eq!("interface 0/1: CDC-ACM", functions[0].to_string());
+        assert_eq!("interface 2: HID", functions[1].to_string());
+        mem::forget(config);
+    }
+}
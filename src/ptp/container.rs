@@ -0,0 +1,137 @@
+use error::Error;
+
+/// Length of the generic container header.
+pub const HEADER_LENGTH: usize = 12;
+
+/// Largest number of parameters in a command, response or event container.
+const MAX_PARAMS: usize = 5;
+
+/// The phase a container belongs to.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum ContainerType {
+    Command,
+    Data,
+    Response,
+    Event,
+}
+
+impl ContainerType {
+    fn code(&self) -> u16 {
+        match *self {
+            ContainerType::Command => 1,
+            ContainerType::Data => 2,
+            ContainerType::Response => 3,
+            ContainerType::Event => 4,
+        }
+    }
+}
+
+/// A PTP USB container, the unit of all transfers on the bulk and interrupt endpoints.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub struct Container {
+    /// The phase of the container.
+    pub kind: ContainerType,
+
+    /// The operation, response or event code.
+    pub code: u16,
+
+    /// The transaction the container belongs to.
+    pub transaction_id: u32,
+
+    /// The parameters of a command, response or event, or the data of a data container.
+    pub payload: Vec<u8>,
+}
+
+impl Container {
+    /// Creates a container with parameters, e.g., a command.
+    ///
+    /// Parameters beyond the fifth are ignored.
+    pub fn with_params(kind: ContainerType, code: u16, transaction_id: u32, params: &[u32]) -> Container {
+        let payload = params.iter().take(MAX_PARAMS).flat_map(|param| param.to_le_bytes().to_vec()).collect();
+        Container { kind, code, transaction_id, payload }
+    }
+
+    /// Returns the parameters of a command, response or event container.
+    pub fn params(&self) -> Vec<u32> {
+        self.payload.chunks_exact(4).take(MAX_PARAMS).map(|param| u32::from_le_bytes([param[0], param[1], param[2], param[3]])).collect()
+    }
+
+    /// Encodes the container as sent to the device.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LENGTH + self.payload.len());
+        bytes.extend_from_slice(&((HEADER_LENGTH + self.payload.len()) as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.kind.code().to_le_bytes());
+        bytes.extend_from_slice(&self.code.to_le_bytes());
+        bytes.extend_from_slice(&self.transaction_id.to_le_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    /// Decodes a container received from the device.
+    ///
+    /// The payload is limited to the length in the header, but may be shorter if only the
+    /// start of a data container is given.
+    ///
+    /// Returns `Error::InvalidParam` if the header is incomplete or has an unknown type.
+    pub fn decode(bytes: &[u8]) -> ::Result<Container> {
+        let length = declared_length(bytes).ok_or(Error::InvalidParam)? as usize;
+
+        let kind = match u16::from_le_bytes([bytes[4], bytes[5]]) {
+            1 => ContainerType::Command,
+            2 => ContainerType::Data,
+            3 => ContainerType::Response,
+            4 => ContainerType::Event,
+            _ => return Err(Error::InvalidParam),
+        };
+
+        if length < HEADER_LENGTH {
+            return Err(Error::InvalidParam);
+        }
+
+        Ok(Container {
+            kind,
+            code: u16::from_le_bytes([bytes[6], bytes[7]]),
+            transaction_id: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            payload: bytes[HEADER_LENGTH..length.min(bytes.len())].to_vec(),
+        })
+    }
+}
+
+/// Returns the total container length from the header, or `None` if the header is incomplete.
+pub fn declared_length(bytes: &[u8]) -> Option<u32> {
+    if bytes.len() < HEADER_LENGTH {
+        return None;
+    }
+
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_encodes_command_container() {
+        let container = Container::with_params(ContainerType::Command, 0x1002, 0, &[1]);
+
+        assert_eq!(vec![0x10, 0x00, 0x00, 0x00, 0x01, 0x00, 0x02, 0x10, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00],
+                   container.encode());
+    }
+
+    #[test]
+    fn it_round_trips_containers() {
+        let container = Container::with_params(ContainerType::Response, 0x2001, 7, &[3, 4]);
+        let decoded = Container::decode(&container.encode()).unwrap();
+
+        assert_eq!(container, decoded);
+        assert_eq!(vec![3, 4], decoded.params());
+    }
+
+    #[test]
+    fn it_rejects_malformed_containers() {
+        assert!(Container::decode(&[0x0C, 0x00, 0x00, 0x00, 0x05, 0x00, 0x01, 0x20, 0x00, 0x00, 0x00, 0x00]).is_err());
+        assert!(Container::decode(&[0x08, 0x00, 0x00, 0x00, 0x03, 0x00, 0x01, 0x20, 0x00, 0x00, 0x00, 0x00]).is_err());
+        assert!(Container::decode(&[0x0C, 0x00, 0x00, 0x00]).is_err());
+    }
+}
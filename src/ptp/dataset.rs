@@ -0,0 +1,243 @@
+use error::Error;
+
+/// Reads the little-endian values of a PTP dataset.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> ::Result<&'a [u8]> {
+        if self.bytes.len() < length {
+            return Err(Error::InvalidParam);
+        }
+
+        let (value, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(value)
+    }
+
+    fn u16(&mut self) -> ::Result<u16> {
+        self.take(2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> ::Result<u32> {
+        self.take(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u16_array(&mut self) -> ::Result<Vec<u16>> {
+        let count = self.u32()?;
+        (0..count).map(|_| self.u16()).collect()
+    }
+
+    /// Reads a string: a character count including the terminating null, then UTF-16 units.
+    fn string(&mut self) -> ::Result<String> {
+        let count = self.take(1)?[0] as usize;
+        let units = (0..count).map(|_| self.u16()).collect::<::Result<Vec<u16>>>()?;
+        let units = match units.iter().position(|&unit| unit == 0) {
+            Some(end) => &units[..end],
+            None => &units[..],
+        };
+
+        Ok(String::from_utf16_lossy(units))
+    }
+}
+
+/// Decodes an array of 32-bit values, e.g., storage IDs or object handles.
+pub fn decode_u32_array(bytes: &[u8]) -> ::Result<Vec<u32>> {
+    let mut reader = Reader { bytes };
+    let count = reader.u32()?;
+    (0..count).map(|_| reader.u32()).collect()
+}
+
+/// The DeviceInfo dataset returned by GetDeviceInfo.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub struct DeviceInfo {
+    /// The PTP version in hundredths, e.g., 100 for 1.00.
+    pub standard_version: u16,
+
+    /// The vendor extension ID, or 0 if there is none.
+    pub vendor_extension_id: u32,
+
+    /// The vendor extension version in hundredths.
+    pub vendor_extension_version: u16,
+
+    /// The vendor extension description.
+    pub vendor_extension_description: String,
+
+    /// The functional mode, 0 for standard.
+    pub functional_mode: u16,
+
+    /// Codes of the supported operations.
+    pub operations_supported: Vec<u16>,
+
+    /// Codes of the events the device may send.
+    pub events_supported: Vec<u16>,
+
+    /// Codes of the supported device properties.
+    pub device_properties_supported: Vec<u16>,
+
+    /// Object format codes the device can capture.
+    pub capture_formats: Vec<u16>,
+
+    /// Object format codes the device can store.
+    pub image_formats: Vec<u16>,
+
+    pub manufacturer: String,
+    pub model: String,
+    pub device_version: String,
+    pub serial_number: String,
+}
+
+impl DeviceInfo {
+    /// Decodes the dataset.
+    ///
+    /// Returns `Error::InvalidParam` if the data ends before the dataset does.
+    pub fn decode(bytes: &[u8]) -> ::Result<DeviceInfo> {
+        let mut reader = Reader { bytes };
+
+        Ok(DeviceInfo {
+            standard_version: reader.u16()?,
+            vendor_extension_id: reader.u32()?,
+            vendor_extension_version: reader.u16()?,
+            vendor_extension_description: reader.string()?,
+            functional_mode: reader.u16()?,
+            operations_supported: reader.u16_array()?,
+            events_supported: reader.u16_array()?,
+            device_properties_supported: reader.u16_array()?,
+            capture_formats: reader.u16_array()?,
+            image_formats: reader.u16_array()?,
+            manufacturer: reader.string()?,
+            model: reader.string()?,
+            device_version: reader.string()?,
+            serial_number: reader.string()?,
+        })
+    }
+}
+
+/// The ObjectInfo dataset returned by GetObjectInfo.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub struct ObjectInfo {
+    /// The storage the object is in.
+    pub storage_id: u32,
+
+    /// The object format code, e.g., `0x3801` for EXIF/JPEG or `0x3001` for an association
+    /// (folder).
+    pub format: u16,
+
+    /// The protection status, 0 for none and 1 for read-only.
+    pub protection_status: u16,
+
+    /// Size of the object in bytes. Objects of 4 GiB and more report `0xFFFFFFFF`.
+    pub compressed_size: u32,
+
+    pub thumb_format: u16,
+    pub thumb_compressed_size: u32,
+    pub thumb_width: u32,
+    pub thumb_height: u32,
+    pub image_width: u32,
+    pub image_height: u32,
+    pub image_bit_depth: u32,
+
+    /// Handle of the association (folder) the object is in, or 0 for the root.
+    pub parent: u32,
+
+    pub association_type: u16,
+    pub association_description: u32,
+    pub sequence_number: u32,
+
+    /// The file name.
+    pub filename: String,
+
+    /// Capture date as an ISO 8601 string, e.g., `20240131T235959`.
+    pub capture_date: String,
+
+    /// Modification date as an ISO 8601 string.
+    pub modification_date: String,
+
+    pub keywords: String,
+}
+
+impl ObjectInfo {
+    /// Decodes the dataset.
+    ///
+    /// Returns `Error::InvalidParam` if the data ends before the dataset does.
+    pub fn decode(bytes: &[u8]) -> ::Result<ObjectInfo> {
+        let mut reader = Reader { bytes };
+
+        Ok(ObjectInfo {
+            storage_id: reader.u32()?,
+            format: reader.u16()?,
+            protection_status: reader.u16()?,
+            compressed_size: reader.u32()?,
+            thumb_format: reader.u16()?,
+            thumb_compressed_size: reader.u32()?,
+            thumb_width: reader.u32()?,
+            thumb_height: reader.u32()?,
+            image_width: reader.u32()?,
+            image_height: reader.u32()?,
+            image_bit_depth: reader.u32()?,
+            parent: reader.u32()?,
+            association_type: reader.u16()?,
+            association_description: reader.u32()?,
+            sequence_number: reader.u32()?,
+            filename: reader.string()?,
+            capture_date: reader.string()?,
+            modification_date: reader.string()?,
+            keywords: reader.string()?,
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn string(s: &str) -> Vec<u8> {
+        if s.is_empty() {
+            return vec![0];
+        }
+
+        let units = s.encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+        let mut bytes = vec![units.len() as u8];
+        bytes.extend(units.iter().flat_map(|unit| unit.to_le_bytes().to_vec()));
+        bytes
+    }
+
+    #[test]
+    fn it_decodes_strings() {
+        assert_eq!("IMG_0001.JPG", Reader { bytes: &string("IMG_0001.JPG") }.string().unwrap());
+        assert_eq!("", Reader { bytes: &[0] }.string().unwrap());
+        assert!(Reader { bytes: &[3, 0x41, 0x00] }.string().is_err());
+    }
+
+    #[test]
+    fn it_decodes_u32_arrays() {
+        assert_eq!(vec![0x0001_0001, 7], decode_u32_array(&[2, 0, 0, 0, 1, 0, 1, 0, 7, 0, 0, 0]).unwrap());
+        assert!(decode_u32_array(&[2, 0, 0, 0, 1, 0, 1, 0]).is_err());
+    }
+
+    #[test]
+    fn it_decodes_device_info() {
+        let mut bytes = vec![0x64, 0x00, 0x06, 0x00, 0x00, 0x00, 0x64, 0x00];
+        bytes.extend(string("microsoft.com: 1.0"));
+        bytes.extend(&[0x00, 0x00]);
+        bytes.extend(&[0x02, 0x00, 0x00, 0x00, 0x01, 0x10, 0x02, 0x10]);
+        bytes.extend(&[0x00, 0x00, 0x00, 0x00]);
+        bytes.extend(&[0x00, 0x00, 0x00, 0x00]);
+        bytes.extend(&[0x00, 0x00, 0x00, 0x00]);
+        bytes.extend(&[0x01, 0x00, 0x00, 0x00, 0x01, 0x38]);
+        bytes.extend(string("Canon Inc."));
+        bytes.extend(string("EOS"));
+        bytes.extend(string("1-1.0.0"));
+        bytes.extend(string(""));
+
+        let info = DeviceInfo::decode(&bytes).unwrap();
+        assert_eq!(100, info.standard_version);
+        assert_eq!("microsoft.com: 1.0", info.vendor_extension_description);
+        assert_eq!(vec![0x1001, 0x1002], info.operations_supported);
+        assert_eq!(vec![0x3801], info.image_formats);
+        assert_eq!("Canon Inc.", info.manufacturer);
+        assert_eq!("", info.serial_number);
+    }
+}
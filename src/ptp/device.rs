@@ -0,0 +1,728 @@
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering};
+use std::task;
+
+use futures_core::Stream;
+
+use config_descriptor::ConfigDescriptor;
+use control::{self, ControlFuture};
+use device::Device;
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::{ClassCode, Direction, Recipient, RequestType, TransferType, request_type};
+use transfer::{CompletedTransfer, TransferFuture};
+
+use super::container::{self, Container, ContainerType, HEADER_LENGTH};
+use super::dataset::{self, DeviceInfo, ObjectInfo};
+
+const SUBCLASS_STILL_IMAGE: u8 = 0x01;
+const PROTOCOL_PTP: u8 = 0x01;
+
+const CANCEL_REQUEST: u8 = 0x64;
+const DEVICE_RESET_REQUEST: u8 = 0x66;
+const GET_DEVICE_STATUS: u8 = 0x67;
+
+/// Event code sent in the cancel request.
+const CANCEL_TRANSACTION: u16 = 0x4001;
+
+const GET_DEVICE_INFO: u16 = 0x1001;
+const OPEN_SESSION: u16 = 0x1002;
+const CLOSE_SESSION: u16 = 0x1003;
+const GET_STORAGE_IDS: u16 = 0x1004;
+const GET_OBJECT_HANDLES: u16 = 0x1007;
+const GET_OBJECT_INFO: u16 = 0x1008;
+const GET_OBJECT: u16 = 0x1009;
+const GET_THUMB: u16 = 0x100A;
+const DELETE_OBJECT: u16 = 0x100B;
+
+/// Response code of a successful operation.
+pub const RESPONSE_OK: u16 = 0x2001;
+
+/// Number of bytes requested from the device in one transfer of the data phase.
+const READ_SIZE: usize = 64 * 1024;
+
+/// Shortest buffer used for reading an event, which has at most three parameters.
+const EVENT_LENGTH: u16 = 24;
+
+/// Finds the first PTP still image interface of a configuration.
+///
+/// MTP devices that use a vendor specific interface class aren't found.
+pub fn find_ptp_interface(config: &ConfigDescriptor) -> Option<u8> {
+    config.find_interface(ClassCode::Image, SUBCLASS_STILL_IMAGE, PROTOCOL_PTP).map(|setting| setting.interface_number())
+}
+
+/// The data phase of an operation.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub enum DataPhase {
+    /// The operation has no data phase.
+    None,
+
+    /// The device sends data.
+    In,
+
+    /// The data is sent to the device.
+    Out(Vec<u8>),
+}
+
+/// The response to an operation.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub struct Response {
+    /// The response code, `RESPONSE_OK` on success.
+    pub code: u16,
+
+    /// The response parameters.
+    pub params: Vec<u32>,
+
+    /// The data sent by the device in the data phase, empty if there was none.
+    pub data: Vec<u8>,
+}
+
+impl Response {
+    /// Indicates if the operation succeeded.
+    pub fn is_ok(&self) -> bool {
+        self.code == RESPONSE_OK
+    }
+}
+
+/// An event sent by the device on the interrupt endpoint, e.g., `0x4002` (ObjectAdded) when a
+/// photo has been taken.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub struct Event {
+    /// The event code.
+    pub code: u16,
+
+    /// The transaction the event refers to, or 0 if it isn't caused by an operation.
+    pub transaction_id: u32,
+
+    /// The event parameters.
+    pub params: Vec<u32>,
+}
+
+/// The status returned by the GET_DEVICE_STATUS class request.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub struct DeviceStatus {
+    /// A response code, `RESPONSE_OK` if the device is ready.
+    pub code: u16,
+
+    /// Parameters of the status, e.g., the endpoints that are halted.
+    pub params: Vec<u32>,
+}
+
+impl DeviceStatus {
+    /// Decodes the status.
+    ///
+    /// Returns `Error::InvalidParam` if the status is shorter than 4 bytes.
+    pub fn decode(data: &[u8]) -> ::Result<DeviceStatus> {
+        if data.len() < 4 {
+            return Err(Error::InvalidParam);
+        }
+
+        let length = (u16::from_le_bytes([data[0], data[1]]) as usize).clamp(4, data.len());
+
+        Ok(DeviceStatus {
+            code: u16::from_le_bytes([data[2], data[3]]),
+            params: data[4..length].chunks_exact(4).map(|param| u32::from_le_bytes([param[0], param[1], param[2], param[3]])).collect(),
+        })
+    }
+}
+
+/// A claimed PTP interface of a camera or other still image device.
+///
+/// Most operations must be done in a session, so [`open_session`](#method.open_session) is
+/// usually the first operation after [`get_device_info`](#method.get_device_info). Only one
+/// operation may be in progress at a time.
+pub struct PtpDevice {
+    handle: DeviceHandle,
+    interface: u8,
+    bulk_in: (u8, usize),
+    bulk_out: (u8, usize),
+    interrupt: Option<(u8, u16)>,
+    transaction_id: AtomicU32,
+    session: AtomicBool,
+    response_code: AtomicU16,
+    kernel_driver_detached: bool,
+}
+
+impl PtpDevice {
+    /// Opens a device and claims its PTP interface.
+    ///
+    /// A kernel driver bound to the interface is detached and reattached when the device is
+    /// dropped.
+    ///
+    /// ## Errors
+    ///
+    /// * `NotFound` if the active configuration has no such interface, or it lacks a bulk IN
+    ///   or bulk OUT endpoint.
+    /// * Any error returned while opening the device or claiming the interface.
+    pub fn open(device: &Device, interface: u8) -> ::Result<PtpDevice> {
+        let (bulk_in, bulk_out, interrupt) = {
+            let config = device.active_config_descriptor()?;
+            let setting = config.interfaces()
+                .find(|i| i.number() == interface)
+                .and_then(|i| i.descriptors().next())
+                .ok_or(Error::NotFound)?;
+
+            let bulk_in = setting.first_endpoint(TransferType::Bulk, Direction::In).ok_or(Error::NotFound)?;
+            let bulk_out = setting.first_endpoint(TransferType::Bulk, Direction::Out).ok_or(Error::NotFound)?;
+            let interrupt = setting.first_endpoint(TransferType::Interrupt, Direction::In);
+
+            ((bulk_in.address(), bulk_in.max_packet_size() as usize),
+             (bulk_out.address(), bulk_out.max_packet_size() as usize),
+             interrupt.map(|endpoint| (endpoint.address(), endpoint.max_packet_size())))
+        };
+
+        let mut handle = device.open()?;
+
+        let kernel_driver_detached = handle.kernel_driver_active(interface).unwrap_or(false);
+        if kernel_driver_detached {
+            handle.detach_kernel_driver(interface)?;
+        }
+
+        handle.claim_interface(interface)?;
+
+        Ok(PtpDevice {
+            handle,
+            interface,
+            bulk_in,
+            bulk_out,
+            interrupt,
+            transaction_id: AtomicU32::new(0),
+            session: AtomicBool::new(false),
+            response_code: AtomicU16::new(RESPONSE_OK),
+            kernel_driver_detached,
+        })
+    }
+
+    /// Returns the handle of the device.
+    pub fn handle(&self) -> &DeviceHandle {
+        &self.handle
+    }
+
+    /// Returns the number of the claimed interface.
+    pub fn interface_number(&self) -> u8 {
+        self.interface
+    }
+
+    /// Returns the response code of the most recent operation.
+    ///
+    /// The typed operations resolve to `Err(Error::Io)` when the device doesn't respond with
+    /// `RESPONSE_OK`, and this tells why, e.g., `0x2009` (Invalid_ObjectHandle).
+    pub fn last_response_code(&self) -> u16 {
+        self.response_code.load(Ordering::Relaxed)
+    }
+
+    /// Runs an operation with up to five parameters and resolves to the response.
+    ///
+    /// The future resolves to `Ok` whatever the response code is, and to `Err(Error::Io)` if
+    /// the device answers out of sequence.
+    pub fn operation<'a>(&'a self, code: u16, params: &[u32], data: DataPhase) -> OperationFuture<'a, Response> {
+        let transaction_id = self.next_transaction_id();
+        OperationFuture::new(self, code, transaction_id, params, data, Ok)
+    }
+
+    /// Reads the DeviceInfo dataset, which may be done outside of a session.
+    pub fn get_device_info<'a>(&'a self) -> OperationFuture<'a, DeviceInfo> {
+        // Outside of a session the transaction ID is 0
+        let transaction_id = if self.session.load(Ordering::Relaxed) { self.next_transaction_id() } else { 0 };
+
+        OperationFuture::new(self, GET_DEVICE_INFO, transaction_id, &[], DataPhase::In, |response| {
+            DeviceInfo::decode(&check(response)?.data)
+        })
+    }
+
+    /// Opens a session, which must have a nonzero ID.
+    pub fn open_session<'a>(&'a self, session_id: u32) -> OperationFuture<'a, ()> {
+        if session_id == 0 {
+            return OperationFuture::failed(self, Error::InvalidParam);
+        }
+
+        // The transactions of a session are numbered from 1, after OpenSession itself
+        self.transaction_id.store(0, Ordering::Relaxed);
+        self.session.store(true, Ordering::Relaxed);
+        OperationFuture::new(self, OPEN_SESSION, 0, &[session_id], DataPhase::None, |response| check(response).map(|_| ()))
+    }
+
+    /// Closes the session.
+    pub fn close_session<'a>(&'a self) -> OperationFuture<'a, ()> {
+        let transaction_id = self.next_transaction_id();
+        self.session.store(false, Ordering::Relaxed);
+
+        OperationFuture::new(self, CLOSE_SESSION, transaction_id, &[], DataPhase::None, |response| check(response).map(|_| ()))
+    }
+
+    /// Reads the IDs of the storages of the device, e.g., its memory cards.
+    pub fn get_storage_ids<'a>(&'a self) -> OperationFuture<'a, Vec<u32>> {
+        self.data_in(GET_STORAGE_IDS, &[], |response| dataset::decode_u32_array(&check(response)?.data))
+    }
+
+    /// Reads the handles of the objects in a storage.
+    ///
+    /// `storage_id` is `0xFFFFFFFF` for all storages, `format` is 0 for objects of any format
+    /// and `parent` is the handle of an association (folder), `0xFFFFFFFF` for the root or 0
+    /// for objects at any level.
+    pub fn get_object_handles<'a>(&'a self, storage_id: u32, format: u16, parent: u32) -> OperationFuture<'a, Vec<u32>> {
+        self.data_in(GET_OBJECT_HANDLES, &[storage_id, format as u32, parent], |response| {
+            dataset::decode_u32_array(&check(response)?.data)
+        })
+    }
+
+    /// Reads the ObjectInfo dataset of an object.
+    pub fn get_object_info<'a>(&'a self, handle: u32) -> OperationFuture<'a, ObjectInfo> {
+        self.data_in(GET_OBJECT_INFO, &[handle], |response| ObjectInfo::decode(&check(response)?.data))
+    }
+
+    /// Reads an object, e.g., a photo.
+    pub fn get_object<'a>(&'a self, handle: u32) -> OperationFuture<'a, Vec<u8>> {
+        self.data_in(GET_OBJECT, &[handle], |response| check(response).map(|response| response.data))
+    }
+
+    /// Reads the thumbnail of an object.
+    pub fn get_thumb<'a>(&'a self, handle: u32) -> OperationFuture<'a, Vec<u8>> {
+        self.data_in(GET_THUMB, &[handle], |response| check(response).map(|response| response.data))
+    }
+
+    /// Deletes an object, or all objects if `handle` is `0xFFFFFFFF`.
+    ///
+    /// `format` limits which objects are deleted when all are, 0 for any format.
+    pub fn delete_object<'a>(&'a self, handle: u32, format: u16) -> OperationFuture<'a, ()> {
+        let transaction_id = self.next_transaction_id();
+
+        OperationFuture::new(self, DELETE_OBJECT, transaction_id, &[handle, format as u32], DataPhase::None, |response| {
+            check(response).map(|_| ())
+        })
+    }
+
+    /// Returns a stream of the events sent by the device.
+    ///
+    /// The stream yields `Err(Error::NotFound)` if the interface has no interrupt endpoint,
+    /// and ends after the first error.
+    pub fn events<'a>(&'a self) -> Events<'a> {
+        Events { device: self, pending: None, done: false }
+    }
+
+    /// Asks the device to cancel the transaction in progress.
+    pub fn cancel(&self, transaction_id: u32) -> ControlFuture<usize> {
+        let mut data = CANCEL_TRANSACTION.to_le_bytes().to_vec();
+        data.extend_from_slice(&transaction_id.to_le_bytes());
+
+        control::write(&self.handle,
+                       request_type(Direction::Out, RequestType::Class, Recipient::Interface),
+                       CANCEL_REQUEST,
+                       0,
+                       self.interface as u16,
+                       &data)
+    }
+
+    /// Resets the device, which closes the session.
+    pub fn reset(&self) -> ControlFuture<usize> {
+        self.session.store(false, Ordering::Relaxed);
+
+        control::write(&self.handle,
+                       request_type(Direction::Out, RequestType::Class, Recipient::Interface),
+                       DEVICE_RESET_REQUEST,
+                       0,
+                       self.interface as u16,
+                       &[])
+    }
+
+    /// Reads the status of the device, e.g., to tell when it's ready after a cancel.
+    pub fn device_status(&self) -> ControlFuture<DeviceStatus> {
+        control::read(&self.handle,
+                      request_type(Direction::In, RequestType::Class, Recipient::Interface),
+                      GET_DEVICE_STATUS,
+                      0,
+                      self.interface as u16,
+                      32,
+                      DeviceStatus::decode)
+    }
+
+    /// Returns the next transaction ID, which is neither 0 nor `0xFFFFFFFF`.
+    fn next_transaction_id(&self) -> u32 {
+        let mut transaction_id = self.transaction_id.load(Ordering::Relaxed);
+
+        loop {
+            let next = match transaction_id.wrapping_add(1) {
+                0 | 0xFFFF_FFFF => 1,
+                next => next,
+            };
+
+            match self.transaction_id.compare_exchange_weak(transaction_id, next, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return next,
+                Err(current) => transaction_id = current,
+            }
+        }
+    }
+
+    fn data_in<'a, T>(&'a self, code: u16, params: &[u32], decode: fn(Response) -> ::Result<T>) -> OperationFuture<'a, T> {
+        let transaction_id = self.next_transaction_id();
+        OperationFuture::new(self, code, transaction_id, params, DataPhase::In, decode)
+    }
+
+    fn bulk_read(&self, length: usize) -> ::Result<TransferFuture> {
+        let mut transfer = self.handle.alloc_transfer(0)?;
+        transfer.fill_bulk_read(self.bulk_in.0.into(), length)?;
+        Ok(transfer.submit())
+    }
+
+    fn bulk_write(&self, data: &[u8]) -> ::Result<TransferFuture> {
+        let mut transfer = self.handle.alloc_transfer(0)?;
+        transfer.fill_bulk_write(self.bulk_out.0.into(), data)?;
+        Ok(transfer.submit())
+    }
+
+    /// Indicates if a transfer of the given length must be followed by a zero length packet to
+    /// end the phase.
+    fn needs_zero_length_packet(&self, length: usize) -> bool {
+        self.bulk_out.1 != 0 && length.is_multiple_of(self.bulk_out.1)
+    }
+}
+
+impl Drop for PtpDevice {
+    fn drop(&mut self) {
+        if self.kernel_driver_detached {
+            let _ = self.handle.release_interface(self.interface);
+            let _ = self.handle.attach_kernel_driver(self.interface);
+        }
+    }
+}
+
+fn check(response: Response) -> ::Result<Response> {
+    if response.is_ok() {
+        Ok(response)
+    } else {
+        Err(Error::Io)
+    }
+}
+
+fn completed(result: ::Result<CompletedTransfer>) -> ::Result<Vec<u8>> {
+    let transfer = result?;
+    transfer.check_status()?;
+    Ok(transfer.get_buffer().to_vec())
+}
+
+/// Future returned by the operations of [`PtpDevice`](struct.PtpDevice.html).
+///
+/// The command is followed by the data phase, if any, and the response.
+pub struct OperationFuture<'a, T> {
+    device: &'a PtpDevice,
+    transaction_id: u32,
+    data_phase: DataPhase,
+    data: Vec<u8>,
+    decode: fn(Response) -> ::Result<T>,
+    state: OperationState,
+}
+
+enum OperationState {
+    Failed(Error),
+    Command(TransferFuture),
+    DataOut(TransferFuture),
+    ZeroLengthPacket(TransferFuture),
+    DataIn(TransferFuture),
+    Response(TransferFuture),
+    Done,
+}
+
+impl<'a, T> OperationFuture<'a, T> {
+    fn new(device: &'a PtpDevice, code: u16, transaction_id: u32, params: &[u32], data_phase: DataPhase,
+           decode: fn(Response) -> ::Result<T>) -> OperationFuture<'a, T> {
+        let command = Container::with_params(ContainerType::Command, code, transaction_id, params);
+
+        let state = match device.bulk_write(&command.encode()) {
+            Ok(future) => OperationState::Command(future),
+            Err(e) => OperationState::Failed(e),
+        };
+
+        OperationFuture { device, transaction_id, data_phase, data: Vec::new(), decode, state }
+    }
+
+    fn failed(device: &'a PtpDevice, error: Error) -> OperationFuture<'a, T> {
+        OperationFuture {
+            device,
+            transaction_id: 0,
+            data_phase: DataPhase::None,
+            data: Vec::new(),
+            decode: |_| Err(Error::InvalidParam),
+            state: OperationState::Failed(error),
+        }
+    }
+
+    /// Returns the transaction ID of the operation, which [`PtpDevice::cancel`](struct.PtpDevice.html#method.cancel)
+    /// takes.
+    pub fn transaction_id(&self) -> u32 {
+        self.transaction_id
+    }
+
+    fn finish(&mut self, result: ::Result<T>) -> task::Poll<::Result<T>> {
+        self.state = OperationState::Done;
+        task::Poll::Ready(result)
+    }
+
+    fn respond(&mut self, container: Container) -> task::Poll<::Result<T>> {
+        if container.kind != ContainerType::Response || container.transaction_id != self.transaction_id {
+            return self.finish(Err(Error::Io));
+        }
+
+        self.device.response_code.store(container.code, Ordering::Relaxed);
+
+        let response = Response { code: container.code, params: container.params(), data: mem::take(&mut self.data) };
+        let result = (self.decode)(response);
+        self.finish(result)
+    }
+
+    /// Returns the length of the transfers of the data phase, in whole packets so that the
+    /// device never sends more than fits.
+    fn read_size(&self) -> usize {
+        let packet_size = self.device.bulk_in.1.max(1);
+        READ_SIZE / packet_size * packet_size
+    }
+
+    fn read_data(&self) -> ::Result<OperationState> {
+        Ok(OperationState::DataIn(self.device.bulk_read(self.read_size())?))
+    }
+
+    fn read_response(&self) -> ::Result<OperationState> {
+        Ok(OperationState::Response(self.device.bulk_read(self.device.bulk_in.1.max(32))?))
+    }
+}
+
+impl<'a, T> Future for OperationFuture<'a, T> {
+    type Output = ::Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            let next = match this.state {
+                OperationState::Failed(_) => match mem::replace(&mut this.state, OperationState::Done) {
+                    OperationState::Failed(e) => return task::Poll::Ready(Err(e)),
+                    _ => unreachable!(),
+                },
+                OperationState::Command(ref mut future) => {
+                    if let Err(e) = match Pin::new(future).poll(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(result) => completed(result),
+                    } {
+                        return this.finish(Err(e));
+                    }
+
+                    let next = match this.data_phase {
+                        DataPhase::None => this.read_response(),
+                        DataPhase::In => this.read_data(),
+                        DataPhase::Out(ref data) => {
+                            let length = (HEADER_LENGTH + data.len()).min(u32::MAX as usize) as u32;
+                            let mut container = Container { kind: ContainerType::Data, code: 0, transaction_id: this.transaction_id, payload: Vec::new() }.encode();
+                            container[0..4].copy_from_slice(&length.to_le_bytes());
+                            container.extend_from_slice(data);
+
+                            this.device.bulk_write(&container).map(OperationState::DataOut)
+                        },
+                    };
+
+                    match next {
+                        Ok(state) => state,
+                        Err(e) => return this.finish(Err(e)),
+                    }
+                },
+                OperationState::DataOut(ref mut future) => {
+                    let length = match Pin::new(future).poll(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(result) => completed(result).map(|data| data.len()),
+                    };
+
+                    let next = match length {
+                        Ok(length) if this.device.needs_zero_length_packet(length) => {
+                            this.device.bulk_write(&[]).map(OperationState::ZeroLengthPacket)
+                        },
+                        Ok(_) => this.read_response(),
+                        Err(e) => Err(e),
+                    };
+
+                    match next {
+                        Ok(state) => state,
+                        Err(e) => return this.finish(Err(e)),
+                    }
+                },
+                OperationState::ZeroLengthPacket(ref mut future) => {
+                    let next = match Pin::new(future).poll(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(result) => completed(result).and_then(|_| this.read_response()),
+                    };
+
+                    match next {
+                        Ok(state) => state,
+                        Err(e) => return this.finish(Err(e)),
+                    }
+                },
+                OperationState::DataIn(ref mut future) => {
+                    let bytes = match Pin::new(future).poll(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(result) => completed(result),
+                    };
+
+                    let received = match bytes {
+                        Ok(bytes) => {
+                            this.data.extend_from_slice(&bytes);
+                            bytes.len()
+                        },
+                        Err(e) => return this.finish(Err(e)),
+                    };
+
+                    let length = match container::declared_length(&this.data) {
+                        Some(length) => length as usize,
+                        None => return this.finish(Err(Error::Io)),
+                    };
+
+                    let header = match Container::decode(&this.data[..HEADER_LENGTH]) {
+                        Ok(header) => header,
+                        Err(_) => return this.finish(Err(Error::Io)),
+                    };
+
+                    match header.kind {
+                        // The device skips the data phase when it fails the operation
+                        ContainerType::Response => {
+                            let response = match Container::decode(&this.data) {
+                                Ok(response) => response,
+                                Err(_) => return this.finish(Err(Error::Io)),
+                            };
+
+                            this.data.clear();
+                            return this.respond(response);
+                        },
+                        ContainerType::Data if header.transaction_id == this.transaction_id => {},
+                        _ => return this.finish(Err(Error::Io)),
+                    }
+
+                    // Objects of 4 GiB and more have the length 0xFFFFFFFF and end with a short packet
+                    let done = if length == u32::MAX as usize {
+                        received < this.read_size()
+                    } else {
+                        this.data.len() >= length
+                    };
+
+                    let next = if done {
+                        this.data.drain(..HEADER_LENGTH);
+                        this.data.truncate(length - HEADER_LENGTH);
+                        this.read_response()
+                    } else {
+                        this.read_data()
+                    };
+
+                    match next {
+                        Ok(state) => state,
+                        Err(e) => return this.finish(Err(e)),
+                    }
+                },
+                OperationState::Response(ref mut future) => {
+                    let bytes = match Pin::new(future).poll(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(result) => completed(result),
+                    };
+
+                    match bytes {
+                        // The zero length packet that ends a data phase of whole packets
+                        Ok(ref bytes) if bytes.is_empty() => match this.read_response() {
+                            Ok(state) => state,
+                            Err(e) => return this.finish(Err(e)),
+                        },
+                        Ok(bytes) => match Container::decode(&bytes) {
+                            Ok(response) => return this.respond(response),
+                            Err(_) => return this.finish(Err(Error::Io)),
+                        },
+                        Err(e) => return this.finish(Err(e)),
+                    }
+                },
+                OperationState::Done => return task::Poll::Ready(Err(Error::PolledAfterCompletion)),
+            };
+
+            this.state = next;
+        }
+    }
+}
+
+/// Stream of events returned by [`PtpDevice::events`](struct.PtpDevice.html#method.events).
+pub struct Events<'a> {
+    device: &'a PtpDevice,
+    pending: Option<TransferFuture>,
+    done: bool,
+}
+
+impl<'a> Events<'a> {
+    fn submit(&self) -> ::Result<TransferFuture> {
+        let (endpoint, packet_size) = self.device.interrupt.ok_or(Error::NotFound)?;
+        let mut transfer = self.device.handle.alloc_transfer(0)?;
+        transfer.fill_interrupt_read(endpoint.into(), packet_size.max(EVENT_LENGTH))?;
+        Ok(transfer.submit())
+    }
+}
+
+impl<'a> Stream for Events<'a> {
+    type Item = ::Result<Event>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        while !this.done {
+            if this.pending.is_none() {
+                match this.submit() {
+                    Ok(future) => this.pending = Some(future),
+                    Err(e) => {
+                        this.done = true;
+                        return task::Poll::Ready(Some(Err(e)));
+                    },
+                }
+            }
+
+            let result = match this.pending {
+                Some(ref mut future) => match Pin::new(future).poll(cx) {
+                    task::Poll::Pending => return task::Poll::Pending,
+                    task::Poll::Ready(result) => result,
+                },
+                None => unreachable!(),
+            };
+
+            this.pending = None;
+
+            match completed(result) {
+                Ok(ref bytes) if bytes.is_empty() => {},
+                Ok(bytes) => {
+                    let event = Container::decode(&bytes).and_then(|container| match container.kind {
+                        ContainerType::Event => Ok(Event {
+                            code: container.code,
+                            transaction_id: container.transaction_id,
+                            params: container.params(),
+                        }),
+                        _ => Err(Error::InvalidParam),
+                    });
+
+                    // Resubmit before handing out the event to keep the endpoint polled
+                    this.pending = this.submit().ok();
+                    return task::Poll::Ready(Some(event));
+                },
+                Err(e) => {
+                    this.done = true;
+                    return task::Poll::Ready(Some(Err(e)));
+                },
+            }
+        }
+
+        task::Poll::Ready(None)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_decodes_device_status() {
+        let status = DeviceStatus::decode(&[0x0C, 0x00, 0x19, 0x20, 0x81, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00]).unwrap();
+
+        assert_eq!(0x2019, status.code);
+        assert_eq!(vec![0x81, 0x02], status.params);
+        assert_eq!(RESPONSE_OK, DeviceStatus::decode(&[0x04, 0x00, 0x01, 0x20]).unwrap().code);
+        assert!(DeviceStatus::decode(&[0x04, 0x00]).is_err());
+    }
+}
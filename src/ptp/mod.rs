@@ -0,0 +1,14 @@
+//! Picture Transfer Protocol (PTP) support for cameras and other still image devices.
+//!
+//! Each operation is a command container sent on the bulk OUT endpoint, an optional data phase
+//! in either direction and a response container read from the bulk IN endpoint. Events, e.g.,
+//! that a photo has been taken, arrive on the interrupt endpoint.
+
+pub use self::container::{Container, ContainerType};
+pub use self::dataset::{DeviceInfo, ObjectInfo};
+pub use self::device::{PtpDevice, DataPhase, Response, Event, DeviceStatus, OperationFuture, Events,
+                       RESPONSE_OK, find_ptp_interface};
+
+mod container;
+mod dataset;
+mod device;
@@ -0,0 +1,146 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task;
+
+use libusb::*;
+
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::Direction;
+use control::ControlRequest;
+use transfer::TransferFuture;
+
+const CONFIG_DESCRIPTOR_LENGTH: u16 = 9;
+const BOS_DESCRIPTOR_LENGTH: u16 = 5;
+const DT_BOS: u8 = 0x0F;
+
+/// Future that resolves to the raw bytes of a descriptor set with a `wTotalLength` field.
+///
+/// Returned by [`DeviceHandle::raw_config_descriptor`](struct.DeviceHandle.html#method.raw_config_descriptor)
+/// and [`DeviceHandle::raw_bos_descriptor`](struct.DeviceHandle.html#method.raw_bos_descriptor).
+/// The future first reads the descriptor header to learn `wTotalLength` and then reads the
+/// complete descriptor set.
+pub struct RawDescriptorFuture<'a> {
+    handle: &'a DeviceHandle,
+    descriptor_type: u8,
+    index: u8,
+    header_length: u16,
+    state: State,
+}
+
+enum State {
+    Failed(Error),
+    ReadingHeader(TransferFuture),
+    ReadingAll(TransferFuture),
+    Done,
+}
+
+impl<'a> Future for RawDescriptorFuture<'a> {
+    type Output = ::Result<Vec<u8>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            let state = match this.state {
+                State::Failed(ref e) => {
+                    let e = e.clone();
+                    this.state = State::Done;
+                    return task::Poll::Ready(Err(e));
+                },
+                State::ReadingHeader(ref mut future) => {
+                    match Pin::new(future).poll(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(result) => {
+                            let total_length = result.and_then(|transfer| {
+                                transfer.check_status()?;
+                                total_length(transfer.get_control_data(), this.descriptor_type, this.header_length)
+                            });
+
+                            match total_length.and_then(|length| submit_get_descriptor(this.handle, this.descriptor_type, this.index, length)) {
+                                Ok(future) => State::ReadingAll(future),
+                                Err(e) => State::Failed(e),
+                            }
+                        }
+                    }
+                },
+                State::ReadingAll(ref mut future) => {
+                    match Pin::new(future).poll(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(result) => {
+                            this.state = State::Done;
+                            return task::Poll::Ready(result.and_then(|transfer| {
+                                transfer.check_status()?;
+                                Ok(transfer.get_control_data().to_vec())
+                            }));
+                        }
+                    }
+                },
+                State::Done => return task::Poll::Ready(Err(Error::PolledAfterCompletion)),
+            };
+
+            this.state = state;
+        }
+    }
+}
+
+fn submit_get_descriptor(handle: &DeviceHandle, descriptor_type: u8, index: u8, length: u16) -> ::Result<TransferFuture> {
+    let mut transfer = handle.alloc_transfer(0)?;
+
+    let request = ControlRequest::get_descriptor(descriptor_type, index);
+    transfer.fill_control_read(request.request_type(Direction::In), request.request, request.value, request.index,
+                               length)?;
+
+    Ok(transfer.submit())
+}
+
+/// Extracts `wTotalLength` from a descriptor header.
+fn total_length(header: &[u8], descriptor_type: u8, header_length: u16) -> ::Result<u16> {
+    if header.len() < header_length as usize || header[1] != descriptor_type {
+        return Err(Error::Parse);
+    }
+
+    Ok((header[2] as u16 | (header[3] as u16) << 8).max(header_length))
+}
+
+fn read<'a>(handle: &'a DeviceHandle, descriptor_type: u8, index: u8, header_length: u16) -> RawDescriptorFuture<'a> {
+    let state = match submit_get_descriptor(handle, descriptor_type, index, header_length) {
+        Ok(future) => State::ReadingHeader(future),
+        Err(e) => State::Failed(e),
+    };
+
+    RawDescriptorFuture { handle, descriptor_type, index, header_length, state }
+}
+
+#[doc(hidden)]
+pub fn read_config<'a>(handle: &'a DeviceHandle, index: u8) -> RawDescriptorFuture<'a> {
+    read(handle, LIBUSB_DT_CONFIG, index, CONFIG_DESCRIPTOR_LENGTH)
+}
+
+#[doc(hidden)]
+pub fn read_bos<'a>(handle: &'a DeviceHandle) -> RawDescriptorFuture<'a> {
+    read(handle, DT_BOS, 0, BOS_DESCRIPTOR_LENGTH)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::total_length;
+
+    #[test]
+    fn it_reads_total_length_from_header() {
+        assert_eq!(0x0120, total_length(&[0x09, 0x02, 0x20, 0x01, 0x02, 0x01, 0x00, 0x80, 0x32], 0x02, 9).unwrap());
+        assert_eq!(0x0021, total_length(&[0x05, 0x0F, 0x21, 0x00, 0x02], 0x0F, 5).unwrap());
+    }
+
+    #[test]
+    fn it_never_reads_less_than_header() {
+        assert_eq!(9, total_length(&[0x09, 0x02, 0x00, 0x00, 0x00, 0x01, 0x00, 0x80, 0x32], 0x02, 9).unwrap());
+    }
+
+    #[test]
+    fn it_rejects_short_or_foreign_header() {
+        assert!(total_length(&[0x09, 0x02, 0x20], 0x02, 9).is_err());
+        assert!(total_length(&[0x09, 0x04, 0x20, 0x01, 0x02, 0x01, 0x00, 0x80, 0x32], 0x02, 9).is_err());
+    }
+}
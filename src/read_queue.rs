@@ -0,0 +1,264 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task;
+
+use fields::{Speed, TransferType};
+
+/// Size of the bulk reads at speeds below super speed, a multiple of all packet sizes.
+const BULK_READ_SIZE: usize = 16_384;
+
+/// How much larger the bulk reads are at super speed, so the host controller isn't idle
+/// between them.
+const SUPER_SPEED_BULK_FACTOR: usize = 8;
+
+/// Packets per isochronous read.
+const ISO_PACKETS: usize = 8;
+
+/// How many reads a stream keeps in flight on an endpoint, and how much each reads.
+///
+/// Completed reads are resubmitted while the stream is polled, so this many are queued at the
+/// host controller at any time. The defaults from [`for_endpoint`](#method.for_endpoint) keep
+/// an endpoint busy, and streams take the number of reads from them unless it is set with their
+/// `queue_depth` method.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub struct QueueDepth {
+    /// The number of reads in flight.
+    pub transfers: usize,
+
+    /// The length of each read, for streams whose reads don't have a length of their own.
+    pub transfer_size: usize,
+}
+
+impl QueueDepth {
+    /// Returns the default depth of an endpoint of a device operating at `speed`.
+    ///
+    /// * Interrupt endpoints get 4 reads of one packet.
+    /// * Bulk endpoints get 4 reads of 16 KiB, 8 times larger at super speed.
+    /// * Isochronous endpoints get 8 reads of 8 packets.
+    /// * Control endpoints get a single read of one packet.
+    pub fn for_endpoint(transfer_type: TransferType, speed: Speed, max_packet_size: u16) -> QueueDepth {
+        let packet_size = (max_packet_size as usize).max(1);
+
+        match transfer_type {
+            TransferType::Interrupt => QueueDepth { transfers: 4, transfer_size: packet_size },
+            TransferType::Bulk => {
+                let size = match speed {
+                    Speed::Super => BULK_READ_SIZE * SUPER_SPEED_BULK_FACTOR,
+                    _ => BULK_READ_SIZE,
+                };
+                // Whole packets, so a read never ends in the middle of one
+                QueueDepth { transfers: 4, transfer_size: size.div_ceil(packet_size) * packet_size }
+            },
+            TransferType::Isochronous => QueueDepth { transfers: 8, transfer_size: ISO_PACKETS * packet_size },
+            TransferType::Control => QueueDepth { transfers: 1, transfer_size: packet_size },
+        }
+    }
+}
+
+/// What a stream does when its consumer is slower than the device, set with the
+/// `backpressure` method of the stream.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Default)]
+pub enum Backpressure {
+    /// Stops reading until the consumer catches up, so the device holds back its data. This
+    /// is the default.
+    #[default]
+    Block,
+
+    /// Keeps reading, and drops the oldest received data that hasn't been taken beyond this
+    /// many items.
+    DropOldest(usize),
+
+    /// Keeps reading, and drops newly received data while this many items haven't been taken.
+    DropNewest(usize),
+}
+
+/// The reads a stream keeps in flight, and the data received that the consumer hasn't taken
+/// yet.
+///
+/// With `Backpressure::Block`, the data stays in the completed reads until it is taken.
+/// Otherwise, completed reads are resubmitted at once and their data is queued, up to the limit
+/// of the backpressure. An error is never dropped, and no reads are submitted after it.
+pub struct ReadQueue<F: Future> {
+    depth: usize,
+    backpressure: Backpressure,
+    in_flight: VecDeque<F>,
+    received: VecDeque<F::Output>,
+    dropped: u64,
+    failed: bool,
+}
+
+impl<T, F> ReadQueue<F> where F: Future<Output = ::Result<T>> + Unpin {
+    /// Creates a queue that keeps `depth` reads in flight.
+    pub fn new(depth: usize) -> ReadQueue<F> {
+        ReadQueue {
+            depth,
+            backpressure: Backpressure::Block,
+            in_flight: VecDeque::with_capacity(depth),
+            received: VecDeque::new(),
+            dropped: 0,
+            failed: false,
+        }
+    }
+
+    /// Sets the number of reads kept in flight. Reads beyond a lowered depth complete, and are
+    /// not resubmitted.
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth.max(1);
+    }
+
+    pub fn set_backpressure(&mut self, backpressure: Backpressure) {
+        self.backpressure = backpressure;
+    }
+
+    /// Returns the number of items dropped because the consumer was too slow.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Cancels the reads in flight and forgets the received data.
+    pub fn clear(&mut self) {
+        self.in_flight.clear();
+        self.received.clear();
+    }
+
+    /// Returns the next received item, submitting reads with `submit` to keep the queue full.
+    pub fn poll_next<S>(&mut self, cx: &mut task::Context, mut submit: S) -> task::Poll<::Result<T>>
+        where S: FnMut() -> ::Result<F>
+    {
+        if let Err(e) = self.fill(&mut submit) {
+            return task::Poll::Ready(Err(e));
+        }
+
+        if self.backpressure == Backpressure::Block {
+            let result = match self.in_flight.front_mut() {
+                Some(future) => match Pin::new(future).poll(cx) {
+                    task::Poll::Pending => return task::Poll::Pending,
+                    task::Poll::Ready(result) => result,
+                },
+                None => return task::Poll::Pending,
+            };
+
+            self.in_flight.pop_front();
+            // Resubmitted before handing out the item to keep the endpoint polled. A failure
+            // to resubmit is reported by the next poll.
+            if result.is_ok() {
+                let _ = self.fill(&mut submit);
+            }
+            return task::Poll::Ready(result);
+        }
+
+        while !self.failed {
+            let result = match self.in_flight.front_mut() {
+                Some(future) => match Pin::new(future).poll(cx) {
+                    task::Poll::Pending => break,
+                    task::Poll::Ready(result) => result,
+                },
+                None => break,
+            };
+
+            self.in_flight.pop_front();
+            self.receive(result);
+            if !self.failed && self.fill(&mut submit).is_err() {
+                break;
+            }
+        }
+
+        match self.received.pop_front() {
+            Some(result) => task::Poll::Ready(result),
+            None => task::Poll::Pending,
+        }
+    }
+
+    fn fill<S>(&mut self, submit: &mut S) -> ::Result<()>
+        where S: FnMut() -> ::Result<F>
+    {
+        while !self.failed && self.in_flight.len() < self.depth {
+            self.in_flight.push_back(submit()?);
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, result: ::Result<T>) {
+        if result.is_err() {
+            self.failed = true;
+            self.received.push_back(result);
+            return;
+        }
+
+        match self.backpressure {
+            Backpressure::DropOldest(limit) if self.received.len() >= limit.max(1) => {
+                self.received.pop_front();
+                self.received.push_back(result);
+                self.dropped += 1;
+            },
+            Backpressure::DropNewest(limit) if self.received.len() >= limit.max(1) => {
+                self.dropped += 1;
+            },
+            _ => self.received.push_back(result),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    extern crate futures;
+
+    use super::*;
+    use error::Error;
+    use self::futures::future::{self as futures_future, Ready};
+    use self::futures::task::noop_waker;
+
+    fn poll_all(queue: &mut ReadQueue<Ready<::Result<u32>>>, items: &mut dyn Iterator<Item = u32>) -> Vec<u32> {
+        let waker = noop_waker();
+        let mut cx = task::Context::from_waker(&waker);
+        let mut submit = || Ok(futures_future::ready(items.next().ok_or(Error::Pipe)));
+
+        match queue.poll_next(&mut cx, &mut submit) {
+            task::Poll::Ready(Ok(item)) => {
+                let mut taken = vec![item];
+                while let task::Poll::Ready(Ok(item)) = queue.poll_next(&mut cx, &mut submit) {
+                    taken.push(item);
+                }
+                taken
+            },
+            _ => vec![],
+        }
+    }
+
+    #[test]
+    fn it_keeps_the_newest_items_when_dropping_oldest() {
+        let mut queue = ReadQueue::new(1);
+        queue.set_backpressure(Backpressure::DropOldest(2));
+
+        // The reads complete at once, so the first poll reads until the source fails
+        assert_eq!(vec![4, 5], poll_all(&mut queue, &mut (1..6)));
+        assert_eq!(3, queue.dropped());
+    }
+
+    #[test]
+    fn it_keeps_the_oldest_items_when_dropping_newest() {
+        let mut queue = ReadQueue::new(1);
+        queue.set_backpressure(Backpressure::DropNewest(2));
+
+        assert_eq!(vec![1, 2], poll_all(&mut queue, &mut (1..6)));
+        assert_eq!(3, queue.dropped());
+    }
+
+    #[test]
+    fn it_derives_the_depth_from_the_endpoint() {
+        assert_eq!(QueueDepth { transfers: 4, transfer_size: 8 }, QueueDepth::for_endpoint(TransferType::Interrupt, Speed::Full, 8));
+        assert_eq!(QueueDepth { transfers: 4, transfer_size: 16_384 }, QueueDepth::for_endpoint(TransferType::Bulk, Speed::High, 512));
+        assert_eq!(QueueDepth { transfers: 4, transfer_size: 131_072 }, QueueDepth::for_endpoint(TransferType::Bulk, Speed::Super, 1024));
+        assert_eq!(QueueDepth { transfers: 8, transfer_size: 8 * 1024 }, QueueDepth::for_endpoint(TransferType::Isochronous, Speed::High, 1024));
+    }
+
+    #[test]
+    fn it_blocks_by_default() {
+        let mut queue = ReadQueue::new(2);
+
+        assert_eq!(vec![1, 2, 3, 4, 5], poll_all(&mut queue, &mut (1..6)));
+        assert_eq!(0, queue.dropped());
+    }
+}
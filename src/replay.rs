@@ -0,0 +1,475 @@
+//! A backend that answers transfers with what a device answered in a capture, to turn a
+//! capture from a bug report into a regression test that runs without the device.
+//!
+//! This module is built with the `replay` feature. A capture recorded in the field with
+//! [`Context::start_capture`](../struct.Context.html#method.start_capture) and written with
+//! [`write_pcapng`](../fn.write_pcapng.html) is read back with
+//! [`read_pcapng`](../fn.read_pcapng.html) and replayed on a context from
+//! [`Context::replay`](../struct.Context.html#method.replay):
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use std::time::Duration;
+//! use libusb_async::{Context, EndpointAddress, TransferStatus, read_pcapng, write_pcapng};
+//! use libusb_async::blocking::block_on;
+//! use libusb_async::replay::answer;
+//!
+//! // The capture of a bug report, here of a device that answered a read, and then stalled
+//! let mut report = Vec::new();
+//! write_pcapng(&[answer(0x81, TransferStatus::Completed, b"hello"),
+//!                answer(0x81, TransferStatus::Stall, b"")], &mut report)?;
+//!
+//! let context = Context::replay(&read_pcapng(&report[..])?);
+//! let device = context.devices()?.into_iter().next().unwrap();
+//! let handle = device.open()?;
+//!
+//! for expected in &[TransferStatus::Completed, TransferStatus::Stall] {
+//!     let mut transfer = handle.alloc_transfer(0)?;
+//!     transfer.fill_bulk_read(EndpointAddress::from(0x81), 64)?;
+//!     let read = transfer.submit();
+//!     context.handle_events(Duration::from_secs(0))?;
+//!     assert_eq!(*expected, block_on(read)?.get_status());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The context has a device for each bus number and address in the capture, with the
+//! descriptors it answered to `GET_DESCRIPTOR` requests, if those were captured. Its interfaces
+//! can be claimed and its settings set, as if it accepted any request. Each endpoint answers
+//! its transfers with the completions recorded on it, in order, when the events are handled. A
+//! transfer without a recorded answer waits until it is cancelled, like on a device that
+//! doesn't answer, and a synchronous transfer times out at once.
+
+use std::collections::{HashMap, VecDeque};
+use std::slice;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use libc::{c_int, c_void};
+use libusb::*;
+
+use backend::{ContextRef, ControlSetup, DeviceRef, HandleRef, TransferRef, UsbBackend};
+use capture::{self, CaptureEvent, CaptureRecord};
+use config_descriptor::ConfigDescriptor;
+use device_descriptor::DeviceDescriptor;
+use fields::TransferType;
+use parse::{parse_config_descriptor, parse_device_descriptor};
+use transfer::TransferStatus;
+
+/// A backend that replays a capture, see the [module documentation](index.html).
+pub struct Replay {
+    devices: Vec<ReplayedDevice>,
+    state: Mutex<ReplayState>,
+    // Notified when a transfer gets its answer, or the events are interrupted
+    answered: Condvar,
+}
+
+struct ReplayedDevice {
+    bus_number: u8,
+    address: u8,
+    // The longest answers to the GET_DESCRIPTOR requests for these descriptors
+    device_descriptor: Option<Vec<u8>>,
+    config_descriptor: Option<Vec<u8>>,
+}
+
+struct ReplayState {
+    // The completions not replayed yet, by endpoint
+    responses: HashMap<u8, VecDeque<CaptureRecord>>,
+    // The submitted transfers, with their answer, or None until they are cancelled
+    pending: Vec<(TransferRef, Option<Response>)>,
+    interrupted: bool,
+}
+
+struct Response {
+    status: TransferStatus,
+    actual_length: usize,
+    data: Vec<u8>,
+}
+
+impl Replay {
+    /// Replays the completions of a capture. Submissions are left out, as the transfers of the
+    /// test take their place.
+    pub fn new(records: &[CaptureRecord]) -> Replay {
+        let mut devices: Vec<ReplayedDevice> = Vec::new();
+        let mut responses: HashMap<u8, VecDeque<CaptureRecord>> = HashMap::new();
+        // The descriptor types of the GET_DESCRIPTOR requests waiting for their completion
+        let mut requests = HashMap::new();
+
+        for record in records {
+            let index = match devices.iter().position(|device| (device.bus_number, device.address) == (record.bus_number, record.address)) {
+                Some(index) => index,
+                None => {
+                    devices.push(ReplayedDevice {
+                        bus_number: record.bus_number,
+                        address: record.address,
+                        device_descriptor: None,
+                        config_descriptor: None,
+                    });
+                    devices.len() - 1
+                },
+            };
+
+            match record.event {
+                CaptureEvent::Submit => {
+                    if let Some(setup) = record.setup {
+                        if setup[0] == LIBUSB_ENDPOINT_IN && setup[1] == LIBUSB_REQUEST_GET_DESCRIPTOR {
+                            requests.insert(record.id, setup[3]);
+                        }
+                    }
+                },
+                CaptureEvent::Complete => {
+                    if let Some(descriptor_type) = requests.remove(&record.id) {
+                        if record.status == Some(TransferStatus::Completed) {
+                            devices[index].answered(descriptor_type, &record.data);
+                        }
+                    }
+                    responses.entry(record.endpoint).or_default().push_back(record.clone());
+                },
+            }
+        }
+
+        Replay {
+            devices,
+            state: Mutex::new(ReplayState { responses, pending: Vec::new(), interrupted: false }),
+            answered: Condvar::new(),
+        }
+    }
+
+    /// Creates a replay that lives as long as the program, as contexts hold their backend.
+    pub fn leak(records: &[CaptureRecord]) -> &'static Replay {
+        Box::leak(Box::new(Replay::new(records)))
+    }
+
+    /// Returns the number of recorded completions that haven't been replayed.
+    pub fn remaining(&self) -> usize {
+        self.lock().responses.values().map(VecDeque::len).sum()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ReplayState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn device(&self, device: DeviceRef) -> &ReplayedDevice {
+        &self.devices[device.as_ptr() as usize - 1]
+    }
+
+    fn next_response(&self, endpoint: u8) -> Option<Response> {
+        self.lock().responses.get_mut(&endpoint).and_then(VecDeque::pop_front).map(Replay::response)
+    }
+
+    fn response(record: CaptureRecord) -> Response {
+        let status = record.status.unwrap_or(TransferStatus::Error);
+        Response { status, actual_length: record.length, data: record.data }
+    }
+
+    /// Answers a synchronous transfer at once, with the error code of its status if it failed.
+    unsafe fn answer_now(&self, endpoint: u8, data: *mut u8, length: usize) -> Result<usize, c_int> {
+        let response = self.next_response(endpoint).ok_or(LIBUSB_ERROR_TIMEOUT)?;
+        let copied = response.data.len().min(length);
+        if copied > 0 {
+            slice::from_raw_parts_mut(data, copied).copy_from_slice(&response.data[..copied]);
+        }
+
+        match response.status {
+            TransferStatus::Completed => Ok(response.actual_length.min(length)),
+            TransferStatus::TimedOut => Err(LIBUSB_ERROR_TIMEOUT),
+            TransferStatus::Stall => Err(LIBUSB_ERROR_PIPE),
+            TransferStatus::NoDevice => Err(LIBUSB_ERROR_NO_DEVICE),
+            TransferStatus::Overflow => Err(LIBUSB_ERROR_OVERFLOW),
+            _ => Err(LIBUSB_ERROR_IO),
+        }
+    }
+}
+
+impl ReplayedDevice {
+    /// Keeps the longest answer for a descriptor, as the first request often reads only its
+    /// header.
+    fn answered(&mut self, descriptor_type: u8, data: &[u8]) {
+        let descriptor = match descriptor_type {
+            LIBUSB_DT_DEVICE => &mut self.device_descriptor,
+            LIBUSB_DT_CONFIG => &mut self.config_descriptor,
+            _ => return,
+        };
+        if descriptor.as_ref().is_none_or(|descriptor| descriptor.len() < data.len()) {
+            *descriptor = Some(data.to_vec());
+        }
+    }
+}
+
+/// Completes a transfer with its answer, and runs its callback.
+unsafe fn complete(transfer: TransferRef, response: Response) {
+    let buffer = transfer.buffer();
+    // The data stage of a control transfer follows its setup packet
+    let offset = if transfer.transfer_type() == TransferType::Control { 8 } else { 0 };
+    let room = buffer.len().saturating_sub(offset);
+
+    let copied = response.data.len().min(room);
+    buffer[offset..offset + copied].copy_from_slice(&response.data[..copied]);
+    transfer.complete(response.status, response.actual_length.min(room));
+}
+
+/// Synchronous transfers report how much was transferred even if they fail.
+fn transferred(result: Result<usize, c_int>, transferred: &mut usize) -> c_int {
+    match result {
+        Ok(length) => {
+            *transferred = length;
+            0
+        },
+        Err(err) => {
+            *transferred = 0;
+            err
+        },
+    }
+}
+
+impl UsbBackend for Replay {
+    unsafe fn get_device_list(&self, _context: ContextRef) -> Result<Vec<DeviceRef>, c_int> {
+        Ok((1..=self.devices.len()).map(|device| DeviceRef::from_ptr(device as *mut c_void)).collect())
+    }
+
+    unsafe fn device_descriptor(&self, device: DeviceRef) -> Result<DeviceDescriptor, c_int> {
+        let bytes = self.device(device).device_descriptor.as_ref().ok_or(LIBUSB_ERROR_NOT_FOUND)?;
+        parse_device_descriptor(bytes).map_err(|_| LIBUSB_ERROR_OTHER)
+    }
+
+    unsafe fn config_descriptor(&self, device: DeviceRef, index: u8) -> Result<ConfigDescriptor, c_int> {
+        match index {
+            0 => self.active_config_descriptor(device),
+            _ => Err(LIBUSB_ERROR_NOT_FOUND),
+        }
+    }
+
+    unsafe fn active_config_descriptor(&self, device: DeviceRef) -> Result<ConfigDescriptor, c_int> {
+        let bytes = self.device(device).config_descriptor.as_ref().ok_or(LIBUSB_ERROR_NOT_FOUND)?;
+        parse_config_descriptor(bytes).map_err(|_| LIBUSB_ERROR_OTHER)
+    }
+
+    unsafe fn bus_number(&self, device: DeviceRef) -> u8 {
+        self.device(device).bus_number
+    }
+
+    unsafe fn device_address(&self, device: DeviceRef) -> u8 {
+        self.device(device).address
+    }
+
+    unsafe fn open(&self, device: DeviceRef) -> Result<HandleRef, c_int> {
+        Ok(HandleRef::from_ptr(device.as_ptr()))
+    }
+
+    unsafe fn get_device(&self, handle: HandleRef) -> DeviceRef {
+        DeviceRef::from_ptr(handle.as_ptr())
+    }
+
+    unsafe fn set_configuration(&self, _handle: HandleRef, _config: Option<u8>) -> c_int {
+        0
+    }
+
+    unsafe fn claim_interface(&self, _handle: HandleRef, _iface: u8) -> c_int {
+        0
+    }
+
+    unsafe fn release_interface(&self, _handle: HandleRef, _iface: u8) -> c_int {
+        0
+    }
+
+    unsafe fn set_alternate_setting(&self, _handle: HandleRef, _iface: u8, _setting: u8) -> c_int {
+        0
+    }
+
+    unsafe fn reset_device(&self, _handle: HandleRef) -> c_int {
+        0
+    }
+
+    unsafe fn clear_halt(&self, _handle: HandleRef, _endpoint: u8) -> c_int {
+        0
+    }
+
+    unsafe fn kernel_driver_active(&self, _handle: HandleRef, _iface: u8) -> Result<bool, c_int> {
+        Ok(false)
+    }
+
+    unsafe fn control_transfer(&self, _handle: HandleRef, setup: ControlSetup, data: *mut u8, length: u16,
+                               _timeout: Duration) -> Result<usize, c_int> {
+        // Captured like the control transfers, with the direction of the setup packet
+        self.answer_now(setup.request_type & LIBUSB_ENDPOINT_DIR_MASK, data, length as usize)
+    }
+
+    unsafe fn bulk_transfer(&self, _handle: HandleRef, endpoint: u8, data: *mut u8, length: usize,
+                            transferred: &mut usize, _timeout: Duration) -> c_int {
+        self::transferred(self.answer_now(endpoint, data, length), transferred)
+    }
+
+    unsafe fn interrupt_transfer(&self, _handle: HandleRef, endpoint: u8, data: *mut u8, length: usize,
+                                 transferred: &mut usize, _timeout: Duration) -> c_int {
+        self::transferred(self.answer_now(endpoint, data, length), transferred)
+    }
+
+    unsafe fn submit_transfer(&self, transfer: TransferRef) -> c_int {
+        let endpoint = capture::captured_endpoint(&*(transfer.as_ptr() as *const libusb_transfer));
+        let response = self.next_response(endpoint);
+        let answered = response.is_some();
+        self.lock().pending.push((transfer, response));
+        if answered {
+            self.answered.notify_all();
+        }
+        0
+    }
+
+    unsafe fn cancel_transfer(&self, transfer: TransferRef) -> c_int {
+        let mut state = self.lock();
+        match state.pending.iter_mut().find(|&&mut (pending, _)| pending == transfer) {
+            Some(&mut (_, ref mut response)) => {
+                // An answer that hasn't been handed over yet is lost, as on a device
+                *response = Some(Response { status: TransferStatus::Cancelled, actual_length: 0, data: Vec::new() });
+                self.answered.notify_all();
+                0
+            },
+            None => LIBUSB_ERROR_NOT_FOUND,
+        }
+    }
+
+    unsafe fn handle_events(&self, _context: ContextRef, timeout: Duration, completed: Option<&AtomicI32>) -> c_int {
+        // None if the timeout is too long to tell apart from waiting forever
+        let deadline = Instant::now().checked_add(timeout);
+        let mut handled = false;
+
+        loop {
+            // The callbacks may submit transfers, so they run without the lock
+            let (transfer, response) = {
+                let mut state = self.lock();
+                loop {
+                    if let Some(index) = state.pending.iter().position(|(_, response)| response.is_some()) {
+                        break state.pending.remove(index);
+                    }
+
+                    let now = Instant::now();
+                    let stop = completed.is_some_and(|completed| completed.load(Ordering::Acquire) != 0);
+                    if handled || stop || state.interrupted || deadline.is_some_and(|deadline| now >= deadline) {
+                        state.interrupted = false;
+                        return 0;
+                    }
+
+                    state = match deadline {
+                        Some(deadline) => self.answered.wait_timeout(state, deadline - now)
+                            .unwrap_or_else(PoisonError::into_inner).0,
+                        None => self.answered.wait(state).unwrap_or_else(PoisonError::into_inner),
+                    };
+                }
+            };
+            if let Some(response) = response {
+                complete(transfer, response);
+            }
+            handled = true;
+        }
+    }
+
+    unsafe fn interrupt_event_handler(&self, _context: ContextRef) {
+        self.lock().interrupted = true;
+        self.answered.notify_all();
+    }
+}
+
+/// A recorded completion, for tests that describe what a device answered without a capture.
+pub fn answer(endpoint: u8, status: TransferStatus, data: &[u8]) -> CaptureRecord {
+    CaptureRecord {
+        timestamp: ::std::time::UNIX_EPOCH,
+        event: CaptureEvent::Complete,
+        id: 0,
+        bus_number: 0,
+        address: 0,
+        endpoint,
+        transfer_type: if endpoint & 0x7F == 0 { TransferType::Control } else { TransferType::Bulk },
+        setup: None,
+        length: data.len(),
+        data: data.to_vec(),
+        status: Some(status),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ptr;
+
+    extern "C" fn record_status(transfer: *mut libusb_transfer) {
+        unsafe {
+            let statuses = (*transfer).user_data as *mut Vec<(c_int, c_int)>;
+            (*statuses).push(((*transfer).status, (*transfer).actual_length));
+        }
+    }
+
+    #[test]
+    fn it_answers_in_order_and_waits_without_an_answer() {
+        let replay = Replay::new(&[answer(0x81, TransferStatus::Completed, b"abc"),
+                                   answer(0x81, TransferStatus::Stall, b"")]);
+        let mut statuses: Vec<(c_int, c_int)> = Vec::new();
+        let mut buffer = [0u8; 8];
+
+        unsafe {
+            let transfer = libusb_alloc_transfer(0);
+            (*transfer).endpoint = 0x81;
+            (*transfer).transfer_type = LIBUSB_TRANSFER_TYPE_BULK;
+            (*transfer).buffer = buffer.as_mut_ptr();
+            (*transfer).length = buffer.len() as c_int;
+            (*transfer).callback = record_status;
+            (*transfer).user_data = &mut statuses as *mut _ as *mut _;
+
+            for _ in 0..3 {
+                assert_eq!(0, replay.submit_transfer(TransferRef::from_libusb(transfer)));
+                assert_eq!(0, replay.handle_events(ContextRef::from_ptr(ptr::null_mut()), Duration::from_secs(0), None));
+            }
+            assert_eq!(0, replay.remaining());
+            assert_eq!(0, replay.cancel_transfer(TransferRef::from_libusb(transfer)));
+            assert_eq!(0, replay.handle_events(ContextRef::from_ptr(ptr::null_mut()), Duration::from_secs(0), None));
+            assert_eq!(LIBUSB_ERROR_NOT_FOUND, replay.cancel_transfer(TransferRef::from_libusb(transfer)));
+            libusb_free_transfer(transfer);
+        }
+
+        assert_eq!(vec![(LIBUSB_TRANSFER_COMPLETED, 3), (LIBUSB_TRANSFER_STALL, 0), (LIBUSB_TRANSFER_CANCELLED, 0)],
+                   statuses);
+        assert_eq!(b"abc", &buffer[..3]);
+    }
+
+    #[test]
+    fn it_opens_the_captured_device_with_its_descriptors() {
+        // A device enumerated at address 5, whose descriptor was read in two steps
+        let request = |id, length: u8| CaptureRecord {
+            event: CaptureEvent::Submit,
+            id,
+            bus_number: 1,
+            address: 5,
+            endpoint: 0x80,
+            transfer_type: TransferType::Control,
+            setup: Some([0x80, 0x06, 0x00, 0x01, 0, 0, length, 0]),
+            length: length as usize,
+            data: Vec::new(),
+            status: None,
+            ..answer(0x80, TransferStatus::Completed, b"")
+        };
+        let descriptor = ::test_helpers::device_bytes(0x1234, 0x5678);
+        let completion = |id, data: &[u8]| CaptureRecord { id, bus_number: 1, address: 5, ..answer(0x80, TransferStatus::Completed, data) };
+        let records = vec![request(1, 8), completion(1, &descriptor[..8]), request(2, 18), completion(2, &descriptor),
+                           CaptureRecord { bus_number: 1, address: 5, ..answer(0x80, TransferStatus::Stall, b"") }];
+
+        let context = ::Context::replay(&records);
+        let devices: Vec<_> = context.devices().unwrap().into_iter().collect();
+        assert_eq!(1, devices.len());
+        assert_eq!((1, 5), (devices[0].bus_number(), devices[0].address()));
+        assert_eq!(0x5678, devices[0].device_descriptor().unwrap().product_id());
+        assert!(devices[0].active_config_descriptor().is_err());
+
+        // The requests of the capture are answered again, by a synchronous transfer here
+        let handle = devices[0].open().unwrap();
+        let mut buffer = [0u8; 18];
+        assert_eq!(8, handle.read_control(0x80, 0x06, 0x0100, 0, &mut buffer, Duration::from_secs(1)).unwrap());
+        assert_eq!(&descriptor[..8], &buffer[..8]);
+        assert_eq!(18, handle.read_control(0x80, 0x06, 0x0100, 0, &mut buffer, Duration::from_secs(1)).unwrap());
+        assert!(matches!(handle.read_control(0x80, 0x06, 0x0100, 0, &mut buffer, Duration::from_secs(1)),
+                         Err(::Error::Pipe)));
+        assert!(matches!(handle.read_control(0x80, 0x06, 0x0100, 0, &mut buffer, Duration::from_secs(1)),
+                         Err(::Error::Timeout)));
+    }
+}
@@ -0,0 +1,886 @@
+//! Remote NDIS (RNDIS) support.
+//!
+//! RNDIS carries Ethernet frames on a pair of bulk endpoints, each wrapped in a packet message.
+//! Control messages are sent with SEND_ENCAPSULATED_COMMAND, and the device tells when the
+//! completion can be read with GET_ENCAPSULATED_RESPONSE by a notification on the interrupt
+//! endpoint. The device may also send keepalive and status messages there, which
+//! [`RequestFuture`](struct.RequestFuture.html) and [`Indications`](struct.Indications.html)
+//! handle.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::task;
+use std::time::Duration;
+
+use futures_core::Stream;
+use futures_sink::Sink;
+
+pub use cdc::{PacketFilter, NetworkEvent};
+
+use cdc;
+use config_descriptor::ConfigDescriptor;
+use control::{self, ControlFuture};
+use device::Device;
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::{ClassCode, Direction, Recipient, RequestType, TransferType, request_type};
+use read_queue::ReadQueue;
+use transfer::{CompletedTransfer, TransferFuture};
+use transfer_queue::{self, TransferQueue};
+
+const SEND_ENCAPSULATED_COMMAND: u8 = 0x00;
+const GET_ENCAPSULATED_RESPONSE: u8 = 0x01;
+
+const PACKET_MSG: u32 = 0x0000_0001;
+const INITIALIZE_MSG: u32 = 0x0000_0002;
+const HALT_MSG: u32 = 0x0000_0003;
+const QUERY_MSG: u32 = 0x0000_0004;
+const SET_MSG: u32 = 0x0000_0005;
+const INDICATE_STATUS_MSG: u32 = 0x0000_0007;
+const KEEPALIVE_MSG: u32 = 0x0000_0008;
+
+/// Set in the message type of the completion of a request.
+const COMPLETION: u32 = 0x8000_0000;
+
+const STATUS_SUCCESS: u32 = 0x0000_0000;
+const STATUS_MEDIA_CONNECT: u32 = 0x4001_000B;
+const STATUS_MEDIA_DISCONNECT: u32 = 0x4001_000C;
+
+/// Object identifier of the packet filter.
+pub const OID_GEN_CURRENT_PACKET_FILTER: u32 = 0x0001_010E;
+
+/// Object identifier of the largest frame the device handles, without the Ethernet header.
+pub const OID_GEN_MAXIMUM_FRAME_SIZE: u32 = 0x0001_0106;
+
+/// Object identifier of the link speed, in units of 100 bit/s.
+pub const OID_GEN_LINK_SPEED: u32 = 0x0001_0107;
+
+/// Object identifier of the connection status, 0 when connected.
+pub const OID_GEN_MEDIA_CONNECT_STATUS: u32 = 0x0001_0114;
+
+/// Object identifier of the MAC address assigned by the manufacturer.
+pub const OID_802_3_PERMANENT_ADDRESS: u32 = 0x0101_0101;
+
+/// Object identifier of the MAC address currently in use.
+pub const OID_802_3_CURRENT_ADDRESS: u32 = 0x0101_0102;
+
+/// Length of the header of a packet message.
+const PACKET_HEADER_LENGTH: usize = 44;
+
+/// Largest transfer the host accepts from the device, which bounds the size of the receive
+/// buffers.
+const MAX_TRANSFER_SIZE: u32 = 16_384;
+
+/// Size of the buffer for the completion of a control message.
+const RESPONSE_LENGTH: u16 = 1025;
+
+/// Length of the RESPONSE_AVAILABLE notification.
+const NOTIFICATION_LENGTH: u16 = 8;
+
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Number of receive transfers kept in flight.
+const RX_TRANSFERS: usize = 4;
+
+/// Number of transmit transfers that may be in flight.
+const TX_TRANSFERS: usize = 4;
+
+/// The interfaces of one RNDIS function.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct RndisInterfaces {
+    /// The control interface, which carries the control messages and notifications.
+    pub control_interface: u8,
+
+    /// The data interface, which has the bulk endpoints.
+    pub data_interface: u8,
+}
+
+/// Finds the RNDIS functions of a configuration.
+///
+/// Control interfaces are recognised by any of the class codes in use: CDC ACM with the vendor
+/// specific protocol, the Remote NDIS wireless controller and the Remote NDIS miscellaneous
+/// class. The data interface comes from the Union functional descriptor, or is assumed to
+/// follow the control interface.
+pub fn find_rndis_interfaces(config: &ConfigDescriptor) -> Vec<RndisInterfaces> {
+    let mut functions = Vec::new();
+
+    for interface in config.interfaces() {
+        let setting = match interface.descriptors().next() {
+            Some(setting) => setting,
+            None => continue,
+        };
+
+        let is_rndis = matches!((setting.class_code(), setting.sub_class_code(), setting.protocol_code()),
+                                (ClassCode::Communications, 0x02, 0xFF)
+                                | (ClassCode::WirelessController, 0x01, 0x03)
+                                | (ClassCode::Miscellaneous, 0x04, 0x01));
+
+        if !is_rndis {
+            continue;
+        }
+
+        let control_interface = setting.interface_number();
+        let data_interface = cdc::union_data_interface(setting.extra()).unwrap_or(control_interface + 1);
+
+        let has_data_interface = config.interfaces()
+            .filter(|i| i.number() == data_interface)
+            .flat_map(|i| i.descriptors())
+            .any(|setting| setting.class_code() == ClassCode::CdcData);
+
+        if has_data_interface {
+            functions.push(RndisInterfaces { control_interface, data_interface });
+        }
+    }
+
+    functions
+}
+
+/// Encodes an INITIALIZE message, which asks for RNDIS 1.0 and transfers of at most
+/// `max_transfer_size` bytes from the device.
+pub fn encode_initialize(request_id: u32, max_transfer_size: u32) -> Vec<u8> {
+    message(INITIALIZE_MSG, &[request_id, 1, 0, max_transfer_size])
+}
+
+/// Encodes a QUERY message for an object identifier.
+pub fn encode_query(request_id: u32, oid: u32) -> Vec<u8> {
+    // The information buffer follows the header, 20 bytes after the request ID
+    message(QUERY_MSG, &[request_id, oid, 0, 20, 0])
+}
+
+/// Encodes a SET message, which sets an object identifier to `value`.
+pub fn encode_set(request_id: u32, oid: u32, value: &[u8]) -> Vec<u8> {
+    let mut bytes = message(SET_MSG, &[request_id, oid, value.len() as u32, 20, 0]);
+    bytes.extend_from_slice(value);
+    set_length(&mut bytes);
+    bytes
+}
+
+/// Encodes a KEEPALIVE message.
+pub fn encode_keepalive(request_id: u32) -> Vec<u8> {
+    message(KEEPALIVE_MSG, &[request_id])
+}
+
+/// Encodes a packet message carrying an Ethernet frame.
+///
+/// ```
+/// use libusb_async::rndis::{encode_packet, parse_packets};
+///
+/// let message = encode_packet(&[0xAA; 60]);
+/// assert_eq!(104, message.len());
+/// assert_eq!(vec![&[0xAA; 60][..]], parse_packets(&message).unwrap());
+/// ```
+pub fn encode_packet(frame: &[u8]) -> Vec<u8> {
+    // The data offset counts from the data offset field itself
+    let mut bytes = message(PACKET_MSG, &[(PACKET_HEADER_LENGTH - 8) as u32, frame.len() as u32, 0, 0, 0, 0, 0, 0, 0]);
+    bytes.extend_from_slice(frame);
+    set_length(&mut bytes);
+    bytes
+}
+
+/// Splits a transfer from the device into the Ethernet frames of its packet messages.
+///
+/// A transfer may hold several messages, and may be padded after the last one. Returns
+/// `Error::InvalidParam` if a message isn't a packet message or doesn't fit in the transfer.
+pub fn parse_packets(transfer: &[u8]) -> ::Result<Vec<&[u8]>> {
+    let mut frames = Vec::new();
+    let mut offset = 0;
+
+    // Padding is shorter than a message header
+    while transfer.len() - offset >= 8 {
+        let message = &transfer[offset..];
+        let length = read_u32(message, 4) as usize;
+
+        if read_u32(message, 0) != PACKET_MSG || length < PACKET_HEADER_LENGTH || length > message.len() {
+            return Err(Error::InvalidParam);
+        }
+
+        let start = 8 + read_u32(message, 8) as usize;
+        let end = start.checked_add(read_u32(message, 12) as usize).ok_or(Error::InvalidParam)?;
+
+        if start < PACKET_HEADER_LENGTH || end > length {
+            return Err(Error::InvalidParam);
+        }
+
+        frames.push(&message[start..end]);
+        offset += length;
+    }
+
+    Ok(frames)
+}
+
+/// The completion of the INITIALIZE message.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct InitializeComplete {
+    /// Major RNDIS version of the device.
+    pub major_version: u32,
+
+    /// Minor RNDIS version of the device.
+    pub minor_version: u32,
+
+    /// The medium of the device, 0 for Ethernet.
+    pub medium: u32,
+
+    /// Largest number of packet messages the device accepts in one transfer.
+    pub max_packets_per_transfer: u32,
+
+    /// Largest transfer the device accepts.
+    pub max_transfer_size: u32,
+
+    /// Packet messages in a transfer to the device are aligned to `1 << packet_alignment`.
+    pub packet_alignment: u32,
+}
+
+impl InitializeComplete {
+    /// Decodes the completion message.
+    ///
+    /// Returns `Error::InvalidParam` if the message is shorter than 44 bytes.
+    pub fn decode(message: &[u8]) -> ::Result<InitializeComplete> {
+        if message.len() < 44 {
+            return Err(Error::InvalidParam);
+        }
+
+        Ok(InitializeComplete {
+            major_version: read_u32(message, 16),
+            minor_version: read_u32(message, 20),
+            medium: read_u32(message, 28),
+            max_packets_per_transfer: read_u32(message, 32),
+            max_transfer_size: read_u32(message, 36),
+            packet_alignment: read_u32(message, 40),
+        })
+    }
+}
+
+/// Returns the NDIS packet filter bits of a filter, as set through
+/// [`OID_GEN_CURRENT_PACKET_FILTER`](constant.OID_GEN_CURRENT_PACKET_FILTER.html).
+pub fn ndis_packet_filter(filter: &PacketFilter) -> u32 {
+    filter.directed as u32
+        | (filter.multicast as u32) << 1
+        | (filter.all_multicast as u32) << 2
+        | (filter.broadcast as u32) << 3
+        | (filter.promiscuous as u32) << 5
+}
+
+/// Returns the information buffer of a QUERY completion.
+fn query_data(message: &[u8]) -> ::Result<Vec<u8>> {
+    if message.len() < 24 {
+        return Err(Error::InvalidParam);
+    }
+
+    let start = 8 + read_u32(message, 20) as usize;
+    let end = start.checked_add(read_u32(message, 16) as usize).ok_or(Error::InvalidParam)?;
+
+    message.get(start..end).map(|data| data.to_vec()).ok_or(Error::InvalidParam)
+}
+
+/// Encodes a message of 32-bit fields, with the type and length first.
+fn message(message_type: u32, fields: &[u32]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(8 + fields.len() * 4);
+    bytes.extend_from_slice(&message_type.to_le_bytes());
+    bytes.extend_from_slice(&((8 + fields.len() * 4) as u32).to_le_bytes());
+
+    for field in fields {
+        bytes.extend_from_slice(&field.to_le_bytes());
+    }
+
+    bytes
+}
+
+fn set_length(message: &mut [u8]) {
+    let length = message.len() as u32;
+    message[4..8].copy_from_slice(&length.to_le_bytes());
+}
+
+/// An open RNDIS network function.
+///
+/// [`initialize`](#method.initialize) must complete before the other requests are made and
+/// before frames are sent or received. Received frames are read from
+/// [`frames`](#method.frames) and frames are sent through [`sink`](#method.sink). Both can be
+/// used at the same time, but only one control request may be in progress at a time.
+pub struct RndisDevice {
+    handle: DeviceHandle,
+    interfaces: RndisInterfaces,
+    notification_endpoint: Option<u8>,
+    bulk_in: u8,
+    bulk_out: (u8, usize),
+    request_id: AtomicU32,
+    max_transfer_size: AtomicU32,
+    detached: Vec<u8>,
+}
+
+impl RndisDevice {
+    /// Opens a device and claims the interfaces of an RNDIS function.
+    ///
+    /// Kernel drivers bound to the interfaces, e.g., `rndis_host`, are detached and reattached
+    /// when the device is dropped, after the function has been halted.
+    ///
+    /// ## Errors
+    ///
+    /// * `NotFound` if the data interface lacks a bulk IN or bulk OUT endpoint.
+    /// * Any error returned while opening the device or claiming the interfaces.
+    pub fn open(device: &Device, interfaces: &RndisInterfaces) -> ::Result<RndisDevice> {
+        let (notification_endpoint, bulk_in, bulk_out) = {
+            let config = device.active_config_descriptor()?;
+
+            let notification = config.interfaces()
+                .find(|i| i.number() == interfaces.control_interface)
+                .and_then(|i| i.descriptors().next())
+                .ok_or(Error::NotFound)?
+                .first_endpoint(TransferType::Interrupt, Direction::In)
+                .map(|ep| ep.address());
+
+            let data = config.interfaces()
+                .find(|i| i.number() == interfaces.data_interface)
+                .and_then(|i| i.descriptors().next())
+                .ok_or(Error::NotFound)?;
+
+            let bulk_in = data.first_endpoint(TransferType::Bulk, Direction::In).ok_or(Error::NotFound)?;
+            let bulk_out = data.first_endpoint(TransferType::Bulk, Direction::Out).ok_or(Error::NotFound)?;
+
+            (notification, bulk_in.address(), (bulk_out.address(), bulk_out.max_packet_size() as usize))
+        };
+
+        let mut handle = device.open()?;
+        let mut detached = Vec::new();
+
+        for &interface in &[interfaces.control_interface, interfaces.data_interface] {
+            if handle.kernel_driver_active(interface).unwrap_or(false) {
+                handle.detach_kernel_driver(interface)?;
+                detached.push(interface);
+            }
+
+            handle.claim_interface(interface)?;
+        }
+
+        Ok(RndisDevice {
+            handle,
+            interfaces: *interfaces,
+            notification_endpoint,
+            bulk_in,
+            bulk_out,
+            request_id: AtomicU32::new(0),
+            max_transfer_size: AtomicU32::new(0),
+            detached,
+        })
+    }
+
+    /// Returns the handle of the device.
+    pub fn handle(&self) -> &DeviceHandle {
+        &self.handle
+    }
+
+    /// Returns the interfaces of the function.
+    pub fn interfaces(&self) -> &RndisInterfaces {
+        &self.interfaces
+    }
+
+    /// Initializes the function.
+    ///
+    /// The largest transfer the device accepts is remembered, and the sink refuses frames that
+    /// don't fit in one.
+    pub fn initialize<'a>(&'a self) -> RequestFuture<'a, InitializeComplete> {
+        let request_id = self.next_request_id();
+
+        self.request(encode_initialize(request_id, MAX_TRANSFER_SIZE), request_id, INITIALIZE_MSG, |device, message| {
+            let complete = InitializeComplete::decode(message)?;
+            device.max_transfer_size.store(complete.max_transfer_size, Ordering::Relaxed);
+            Ok(complete)
+        })
+    }
+
+    /// Reads the value of an object identifier.
+    pub fn query<'a>(&'a self, oid: u32) -> RequestFuture<'a, Vec<u8>> {
+        let request_id = self.next_request_id();
+        self.request(encode_query(request_id, oid), request_id, QUERY_MSG, |_, message| query_data(message))
+    }
+
+    /// Sets the value of an object identifier.
+    pub fn set<'a>(&'a self, oid: u32, value: &[u8]) -> RequestFuture<'a, ()> {
+        let request_id = self.next_request_id();
+        self.request(encode_set(request_id, oid, value), request_id, SET_MSG, |_, _| Ok(()))
+    }
+
+    /// Sets which packets the function receives. No frames are received until a filter is set.
+    pub fn set_packet_filter<'a>(&'a self, filter: &PacketFilter) -> RequestFuture<'a, ()> {
+        self.set(OID_GEN_CURRENT_PACKET_FILTER, &ndis_packet_filter(filter).to_le_bytes())
+    }
+
+    /// Reads the MAC address assigned by the manufacturer.
+    pub fn mac_address<'a>(&'a self) -> RequestFuture<'a, [u8; 6]> {
+        let request_id = self.next_request_id();
+
+        self.request(encode_query(request_id, OID_802_3_PERMANENT_ADDRESS), request_id, QUERY_MSG, |_, message| {
+            let data = query_data(message)?;
+            let mut mac = [0; 6];
+            mac.copy_from_slice(data.get(..6).ok_or(Error::InvalidParam)?);
+            Ok(mac)
+        })
+    }
+
+    /// Sends a keepalive message, which tells if the function still responds.
+    pub fn keepalive<'a>(&'a self) -> RequestFuture<'a, ()> {
+        let request_id = self.next_request_id();
+        self.request(encode_keepalive(request_id), request_id, KEEPALIVE_MSG, |_, _| Ok(()))
+    }
+
+    /// Returns a stream of the connection changes the function indicates.
+    ///
+    /// Keepalive messages from the device are answered while the stream is polled, so that the
+    /// device doesn't reset the function when no requests are made. The stream must not be
+    /// polled while a request is in progress, since both read the same responses. It yields
+    /// `Err(Error::NotFound)` if the function has no interrupt endpoint, and ends after the
+    /// first error.
+    pub fn indications<'a>(&'a self) -> Indications<'a> {
+        Indications { device: self, state: IndicationState::Idle, done: false }
+    }
+
+    /// Returns a stream of received Ethernet frames.
+    ///
+    /// The stream ends after the first error.
+    pub fn frames<'a>(&'a self) -> Frames<'a> {
+        Frames { device: self, reads: ReadQueue::new(RX_TRANSFERS), frames: VecDeque::new(), done: false }
+    }
+
+    /// Returns a sink that sends Ethernet frames, one per transfer.
+    pub fn sink<'a>(&'a self) -> FrameSink<'a> {
+        FrameSink { device: self, transfers: TransferQueue::with_capacity(TX_TRANSFERS) }
+    }
+
+    /// Returns the next request ID, which is never zero.
+    fn next_request_id(&self) -> u32 {
+        let mut request_id = self.request_id.load(Ordering::Relaxed);
+
+        loop {
+            let next = request_id.checked_add(1).unwrap_or(1);
+            match self.request_id.compare_exchange_weak(request_id, next, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return next,
+                Err(current) => request_id = current,
+            }
+        }
+    }
+
+    fn request<'a, T>(&'a self, message: Vec<u8>, request_id: u32, message_type: u32,
+                      decode: fn(&RndisDevice, &[u8]) -> ::Result<T>) -> RequestFuture<'a, T> {
+        RequestFuture {
+            device: self,
+            request_id,
+            completion: message_type | COMPLETION,
+            decode,
+            state: RequestState::Send(self.send_command(&message)),
+        }
+    }
+
+    fn send_command(&self, message: &[u8]) -> ControlFuture<usize> {
+        control::write(&self.handle,
+                       request_type(Direction::Out, RequestType::Class, Recipient::Interface),
+                       SEND_ENCAPSULATED_COMMAND,
+                       0,
+                       self.interfaces.control_interface as u16,
+                       message)
+    }
+
+    fn get_response(&self) -> ControlFuture<Vec<u8>> {
+        control::read(&self.handle,
+                      request_type(Direction::In, RequestType::Class, Recipient::Interface),
+                      GET_ENCAPSULATED_RESPONSE,
+                      0,
+                      self.interfaces.control_interface as u16,
+                      RESPONSE_LENGTH,
+                      control::to_vec)
+    }
+
+    /// Waits for the RESPONSE_AVAILABLE notification, or reads the response right away if the
+    /// function has no interrupt endpoint.
+    fn await_response(&self) -> ::Result<ResponseWait> {
+        match self.notification_endpoint {
+            Some(endpoint) => {
+                let mut transfer = self.handle.alloc_transfer(0)?;
+                transfer.fill_interrupt_read(endpoint.into(), NOTIFICATION_LENGTH)?;
+                Ok(ResponseWait::Notification(transfer.submit()))
+            },
+            None => Ok(ResponseWait::Response(self.get_response())),
+        }
+    }
+
+    fn submit_read(&self) -> ::Result<TransferFuture> {
+        transfer_queue::submit_bulk_read(&self.handle, self.bulk_in.into(), MAX_TRANSFER_SIZE as usize)
+    }
+
+    fn submit_write(&self, data: &[u8]) -> ::Result<TransferFuture> {
+        transfer_queue::submit_bulk_write(&self.handle, self.bulk_out.0.into(), data)
+    }
+}
+
+impl Drop for RndisDevice {
+    fn drop(&mut self) {
+        let request_id = self.next_request_id();
+        let _ = self.handle.write_control(request_type(Direction::Out, RequestType::Class, Recipient::Interface),
+                                          SEND_ENCAPSULATED_COMMAND,
+                                          0,
+                                          self.interfaces.control_interface as u16,
+                                          &message(HALT_MSG, &[request_id]),
+                                          CONTROL_TIMEOUT);
+
+        for &interface in &self.detached {
+            let _ = self.handle.release_interface(interface);
+            let _ = self.handle.attach_kernel_driver(interface);
+        }
+    }
+}
+
+fn completed(result: ::Result<CompletedTransfer>) -> ::Result<Vec<u8>> {
+    let transfer = result?;
+    transfer.check_status()?;
+    Ok(transfer.get_buffer().to_vec())
+}
+
+/// A message sent by the device on its own, rather than in response to a request.
+enum Unsolicited {
+    Keepalive(u32),
+    Status(u32),
+}
+
+/// Recognises keepalive and status messages from the device.
+fn unsolicited(message: &[u8]) -> Option<Unsolicited> {
+    if message.len() < 12 {
+        return None;
+    }
+
+    match read_u32(message, 0) {
+        KEEPALIVE_MSG => Some(Unsolicited::Keepalive(read_u32(message, 8))),
+        INDICATE_STATUS_MSG => Some(Unsolicited::Status(read_u32(message, 8))),
+        _ => None,
+    }
+}
+
+/// Answers a keepalive message from the device.
+fn keepalive_complete(device: &RndisDevice, request_id: u32) -> ControlFuture<usize> {
+    device.send_command(&message(KEEPALIVE_MSG | COMPLETION, &[request_id, STATUS_SUCCESS]))
+}
+
+enum ResponseWait {
+    Notification(TransferFuture),
+    Response(ControlFuture<Vec<u8>>),
+}
+
+/// Future returned by the requests of [`RndisDevice`](struct.RndisDevice.html).
+///
+/// The future resolves to `Err(Error::Io)` if the device completes the request with a failure
+/// status.
+pub struct RequestFuture<'a, T> {
+    device: &'a RndisDevice,
+    request_id: u32,
+    completion: u32,
+    decode: fn(&RndisDevice, &[u8]) -> ::Result<T>,
+    state: RequestState,
+}
+
+enum RequestState {
+    Send(ControlFuture<usize>),
+    Wait(ResponseWait),
+    Reply(ControlFuture<usize>),
+    Done,
+}
+
+impl<'a, T> RequestFuture<'a, T> {
+    fn finish(&mut self, result: ::Result<T>) -> task::Poll<::Result<T>> {
+        self.state = RequestState::Done;
+        task::Poll::Ready(result)
+    }
+}
+
+impl<'a, T> Future for RequestFuture<'a, T> {
+    type Output = ::Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            let next = match this.state {
+                RequestState::Send(ref mut future) | RequestState::Reply(ref mut future) => match Pin::new(future).poll(cx) {
+                    task::Poll::Pending => return task::Poll::Pending,
+                    task::Poll::Ready(Ok(_)) => match this.device.await_response() {
+                        Ok(wait) => RequestState::Wait(wait),
+                        Err(e) => return this.finish(Err(e)),
+                    },
+                    task::Poll::Ready(Err(e)) => return this.finish(Err(e)),
+                },
+                RequestState::Wait(ResponseWait::Notification(ref mut future)) => match Pin::new(future).poll(cx) {
+                    task::Poll::Pending => return task::Poll::Pending,
+                    task::Poll::Ready(result) => match completed(result) {
+                        Ok(_) => RequestState::Wait(ResponseWait::Response(this.device.get_response())),
+                        Err(e) => return this.finish(Err(e)),
+                    },
+                },
+                RequestState::Wait(ResponseWait::Response(ref mut future)) => {
+                    let message = match Pin::new(future).poll(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(Ok(message)) => message,
+                        task::Poll::Ready(Err(e)) => return this.finish(Err(e)),
+                    };
+
+                    match unsolicited(&message) {
+                        Some(Unsolicited::Keepalive(request_id)) => RequestState::Reply(keepalive_complete(this.device, request_id)),
+                        // Completions for earlier requests and status indications are skipped
+                        _ if message.len() < 16 || read_u32(&message, 0) != this.completion || read_u32(&message, 8) != this.request_id => {
+                            match this.device.await_response() {
+                                Ok(wait) => RequestState::Wait(wait),
+                                Err(e) => return this.finish(Err(e)),
+                            }
+                        },
+                        _ if read_u32(&message, 12) != STATUS_SUCCESS => return this.finish(Err(Error::Io)),
+                        _ => {
+                            let result = (this.decode)(this.device, &message);
+                            return this.finish(result);
+                        },
+                    }
+                },
+                RequestState::Done => return task::Poll::Ready(Err(Error::PolledAfterCompletion)),
+            };
+
+            this.state = next;
+        }
+    }
+}
+
+/// Stream of connection changes returned by
+/// [`RndisDevice::indications`](struct.RndisDevice.html#method.indications).
+pub struct Indications<'a> {
+    device: &'a RndisDevice,
+    state: IndicationState,
+    done: bool,
+}
+
+enum IndicationState {
+    Idle,
+    Wait(ResponseWait),
+    Reply(ControlFuture<usize>),
+}
+
+impl<'a> Indications<'a> {
+    fn fail(&mut self, error: Error) -> task::Poll<Option<::Result<NetworkEvent>>> {
+        self.done = true;
+        self.state = IndicationState::Idle;
+        task::Poll::Ready(Some(Err(error)))
+    }
+}
+
+impl<'a> Stream for Indications<'a> {
+    type Item = ::Result<NetworkEvent>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        while !this.done {
+            let next = match this.state {
+                IndicationState::Idle => {
+                    if this.device.notification_endpoint.is_none() {
+                        return this.fail(Error::NotFound);
+                    }
+
+                    match this.device.await_response() {
+                        Ok(wait) => IndicationState::Wait(wait),
+                        Err(e) => return this.fail(e),
+                    }
+                },
+                IndicationState::Wait(ResponseWait::Notification(ref mut future)) => match Pin::new(future).poll(cx) {
+                    task::Poll::Pending => return task::Poll::Pending,
+                    task::Poll::Ready(result) => match completed(result) {
+                        Ok(_) => IndicationState::Wait(ResponseWait::Response(this.device.get_response())),
+                        Err(e) => return this.fail(e),
+                    },
+                },
+                IndicationState::Wait(ResponseWait::Response(ref mut future)) => {
+                    let message = match Pin::new(future).poll(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(Ok(message)) => message,
+                        task::Poll::Ready(Err(e)) => return this.fail(e),
+                    };
+
+                    let event = match unsolicited(&message) {
+                        Some(Unsolicited::Keepalive(request_id)) => {
+                            this.state = IndicationState::Reply(keepalive_complete(this.device, request_id));
+                            continue;
+                        },
+                        Some(Unsolicited::Status(STATUS_MEDIA_CONNECT)) => NetworkEvent::Connected,
+                        Some(Unsolicited::Status(STATUS_MEDIA_DISCONNECT)) => NetworkEvent::Disconnected,
+                        // Other indications and stale completions are skipped
+                        _ => {
+                            this.state = IndicationState::Idle;
+                            continue;
+                        },
+                    };
+
+                    this.state = IndicationState::Idle;
+                    return task::Poll::Ready(Some(Ok(event)));
+                },
+                IndicationState::Reply(ref mut future) => match Pin::new(future).poll(cx) {
+                    task::Poll::Pending => return task::Poll::Pending,
+                    task::Poll::Ready(Ok(_)) => IndicationState::Idle,
+                    task::Poll::Ready(Err(e)) => return this.fail(e),
+                },
+            };
+
+            this.state = next;
+        }
+
+        task::Poll::Ready(None)
+    }
+}
+
+/// Stream of received frames returned by [`RndisDevice::frames`](struct.RndisDevice.html#method.frames).
+pub struct Frames<'a> {
+    device: &'a RndisDevice,
+    reads: ReadQueue<TransferFuture>,
+    frames: VecDeque<Vec<u8>>,
+    done: bool,
+}
+
+impl<'a> Stream for Frames<'a> {
+    type Item = ::Result<Vec<u8>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(frame) = this.frames.pop_front() {
+                return task::Poll::Ready(Some(Ok(frame)));
+            }
+
+            if this.done {
+                return task::Poll::Ready(None);
+            }
+
+            let device = this.device;
+            let result = match this.reads.poll_next(cx, || device.submit_read()) {
+                task::Poll::Pending => return task::Poll::Pending,
+                task::Poll::Ready(result) => result,
+            };
+
+            let frames = completed(result).and_then(|transfer| {
+                Ok(parse_packets(&transfer)?.into_iter().map(|frame| frame.to_vec()).collect::<Vec<_>>())
+            });
+
+            match frames {
+                Ok(frames) => this.frames.extend(frames),
+                Err(e) => {
+                    this.done = true;
+                    this.reads.clear();
+                    return task::Poll::Ready(Some(Err(e)));
+                },
+            }
+        }
+    }
+}
+
+/// Sink of frames returned by [`RndisDevice::sink`](struct.RndisDevice.html#method.sink).
+///
+/// Frames that don't fit in the largest transfer the device accepts are refused with
+/// `Error::InvalidParam`.
+pub struct FrameSink<'a> {
+    device: &'a RndisDevice,
+    transfers: TransferQueue<TransferFuture>,
+}
+
+impl<'a> Sink<Vec<u8>> for FrameSink<'a> {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<::Result<()>> {
+        self.get_mut().transfers.poll_completed(cx, TX_TRANSFERS - 1)
+    }
+
+    fn start_send(self: Pin<&mut Self>, frame: Vec<u8>) -> ::Result<()> {
+        let this = self.get_mut();
+        let mut message = encode_packet(&frame);
+
+        let max_transfer_size = this.device.max_transfer_size.load(Ordering::Relaxed) as usize;
+        if max_transfer_size != 0 && message.len() > max_transfer_size {
+            return Err(Error::InvalidParam);
+        }
+
+        // A short packet ends the transfer instead of a zero-length packet, which some devices
+        // mishandle. The padding is outside of the message length.
+        if message.len().is_multiple_of(this.device.bulk_out.1.max(1)) {
+            message.push(0);
+        }
+
+        this.transfers.push(this.device.submit_write(&message)?);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<::Result<()>> {
+        self.get_mut().transfers.poll_completed(cx, 0)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_encodes_initialize() {
+        assert_eq!(vec![0x02, 0x00, 0x00, 0x00, 0x18, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+                        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x00],
+                   encode_initialize(1, 0x4000));
+    }
+
+    #[test]
+    fn it_encodes_set() {
+        let bytes = encode_set(2, OID_GEN_CURRENT_PACKET_FILTER, &[0x0D, 0x00, 0x00, 0x00]);
+
+        assert_eq!(32, bytes.len());
+        assert_eq!(32, read_u32(&bytes, 4));
+        assert_eq!(OID_GEN_CURRENT_PACKET_FILTER, read_u32(&bytes, 12));
+        assert_eq!(4, read_u32(&bytes, 16));
+        assert_eq!(&[0x0D, 0x00, 0x00, 0x00], &bytes[8 + 20..]);
+    }
+
+    #[test]
+    fn it_parses_several_packets() {
+        let mut transfer = encode_packet(&[1, 2, 3]);
+        transfer.extend(encode_packet(&[4, 5]));
+        transfer.push(0);
+
+        assert_eq!(vec![&[1, 2, 3][..], &[4, 5][..]], parse_packets(&transfer).unwrap());
+    }
+
+    #[test]
+    fn it_rejects_malformed_packets() {
+        let mut truncated = encode_packet(&[1, 2, 3]);
+        truncated.pop();
+        assert!(parse_packets(&truncated).is_err());
+
+        let mut overlong = encode_packet(&[1, 2, 3]);
+        overlong[12] = 0xFF;
+        assert!(parse_packets(&overlong).is_err());
+
+        assert!(parse_packets(&encode_keepalive(1)).is_err());
+    }
+
+    #[test]
+    fn it_decodes_query_completion() {
+        let mut message = vec![0; 24];
+        message[0..4].copy_from_slice(&(QUERY_MSG | COMPLETION).to_le_bytes());
+        message[16..20].copy_from_slice(&6u32.to_le_bytes());
+        message[20..24].copy_from_slice(&16u32.to_le_bytes());
+        message.extend_from_slice(&[0x02, 0x50, 0xB6, 0xA1, 0xC2, 0xD3]);
+
+        assert_eq!(vec![0x02, 0x50, 0xB6, 0xA1, 0xC2, 0xD3], query_data(&message).unwrap());
+        assert!(query_data(&message[..28]).is_err());
+    }
+
+    #[test]
+    fn it_converts_packet_filter() {
+        assert_eq!(0x0D, ndis_packet_filter(&PacketFilter::default()));
+    }
+}
@@ -0,0 +1,98 @@
+//! `async-io` integration, for async-std and smol, with the `async-std` feature.
+
+use std::future::Future;
+use std::os::unix::io::{AsFd, BorrowedFd, RawFd};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task;
+use std::time::Instant;
+
+use async_io::{Async, Timer};
+
+use context::{self, Context, ContextAsync};
+use super::{handle_pending_events, next_timeout, pollfds, sync_fds, PollFd};
+
+/// Future that handles the events of a context on the `async-io` reactor, to be spawned, e.g.,
+/// with `async_std::task::spawn`.
+///
+/// It runs until it is dropped, or resolves to an error if the file descriptors of the context
+/// can't be registered, or `libusb` fails to handle the events.
+pub struct EventDriver {
+    context: Arc<ContextAsync>,
+    fds: Vec<(PollFd, Async<Fd>)>,
+    timer: Option<Timer>,
+}
+
+/// A file descriptor of `libusb`, which is closed by `libusb`.
+struct Fd(RawFd);
+
+impl AsFd for Fd {
+    fn as_fd<'a>(&'a self) -> BorrowedFd<'a> {
+        // Only registered while libusb lists it
+        unsafe { BorrowedFd::borrow_raw(self.0) }
+    }
+}
+
+impl EventDriver {
+    /// Creates a driver for the events of a context.
+    pub fn new(context: &Context) -> EventDriver {
+        EventDriver { context: context::shared(context).clone(), fds: Vec::new(), timer: None }
+    }
+
+    /// Polls the file descriptors and the next timeout, returning whether any is ready.
+    fn poll_ready(&mut self, cx: &mut task::Context) -> ::Result<bool> {
+        // The descriptors are left in the mode libusb chose
+        sync_fds(&mut self.fds, &pollfds(&self.context), |fd| Async::new_nonblocking(Fd(fd.fd)))?;
+
+        let mut ready = false;
+        for &(fd, ref source) in &self.fds {
+            if fd.readable {
+                if let task::Poll::Ready(result) = source.poll_readable(cx) {
+                    result.map_err(|_| ::Error::Io)?;
+                    ready = true;
+                }
+            }
+            if fd.writable {
+                if let task::Poll::Ready(result) = source.poll_writable(cx) {
+                    result.map_err(|_| ::Error::Io)?;
+                    ready = true;
+                }
+            }
+        }
+
+        self.timer = match next_timeout(&self.context)? {
+            Some(timeout) => {
+                let deadline = Instant::now() + timeout;
+                let mut timer = self.timer.take().unwrap_or_else(|| Timer::at(deadline));
+                timer.set_at(deadline);
+                if Pin::new(&mut timer).poll(cx).is_ready() {
+                    ready = true;
+                }
+                Some(timer)
+            },
+            None => None,
+        };
+
+        Ok(ready)
+    }
+}
+
+impl Future for EventDriver {
+    type Output = ::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            match this.poll_ready(cx) {
+                Ok(true) => {},
+                Ok(false) => return task::Poll::Pending,
+                Err(e) => return task::Poll::Ready(Err(e)),
+            }
+
+            if let Err(e) = handle_pending_events(&this.context) {
+                return task::Poll::Ready(Err(e));
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! io_uring integration on Linux, with the `io-uring` feature.
+//!
+//! usbfs submits and reaps URBs with ioctls, which io_uring can't issue, so `libusb` still
+//! does the transfers. The driver waits for the file descriptors of `libusb` on a ring
+//! instead: the polls of all the descriptors go to the kernel in the same call that waits,
+//! with the timeout of `libusb`, and are only submitted again for the descriptors that fired.
+//! `libusb` then handles the events without waiting.
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use io_uring::{opcode, squeue, types, IoUring};
+use libc::{self, POLLIN, POLLOUT};
+
+use context::{self, Context, ContextAsync};
+use super::{handle_pending_events, next_timeout, pollfds, sync_fds, PollFd};
+
+// Enough for the polls of the descriptors of a context, and their removals
+const RING_ENTRIES: u32 = 64;
+
+// The user data of the requests that remove polls, whose completions are ignored
+const REMOVAL: u64 = 0;
+
+/// Handles the events of a context on a thread of the application, waiting for them on an
+/// io_uring, e.g.:
+///
+/// ```no_run
+/// # extern crate libusb_async as libusb;
+/// # fn main() -> libusb::Result<()> {
+/// # let context = libusb::Context::new()?;
+/// let mut driver = libusb::runtime::io_uring::EventDriver::new(&context)?;
+/// loop {
+///     driver.handle_events(None)?;
+/// }
+/// # }
+/// ```
+///
+/// Needs Linux 5.11 or later. Like the other drivers, it is meant for builds without the
+/// `event-thread` feature.
+pub struct EventDriver {
+    context: Arc<ContextAsync>,
+    ring: IoUring,
+    // The descriptors of libusb, with the user data of the poll submitted for each, if any
+    fds: Vec<(PollFd, Option<u64>)>,
+    next_poll: u64,
+}
+
+impl EventDriver {
+    /// Creates a driver for the events of a context, with a ring of its own.
+    ///
+    /// Fails with `NotSupported` if the kernel doesn't provide io_uring, or it is disabled,
+    /// and with `Io` if the ring can't be created otherwise.
+    pub fn new(context: &Context) -> ::Result<EventDriver> {
+        let ring = IoUring::new(RING_ENTRIES).map_err(|e| match e.raw_os_error() {
+            Some(libc::ENOSYS) | Some(libc::EPERM) => ::Error::NotSupported,
+            _ => ::Error::Io,
+        })?;
+        Ok(EventDriver { context: context::shared(context).clone(), ring, fds: Vec::new(), next_poll: REMOVAL + 1 })
+    }
+
+    /// Waits for the events of the context, up to `timeout` or indefinitely if `None`, and
+    /// handles them.
+    pub fn handle_events(&mut self, timeout: Option<Duration>) -> ::Result<()> {
+        self.arm()?;
+
+        let wait = match (timeout, next_timeout(&self.context)?) {
+            (Some(timeout), Some(next)) => Some(timeout.min(next)),
+            (timeout, next) => timeout.or(next),
+        };
+        let waited = match wait {
+            Some(wait) => {
+                let timespec = types::Timespec::from(wait);
+                self.ring.submitter().submit_with_args(1, &types::SubmitArgs::new().timespec(&timespec))
+            },
+            None => self.ring.submit_and_wait(1),
+        };
+        match waited {
+            Ok(_) => {},
+            Err(ref e) if e.raw_os_error() == Some(libc::ETIME) || e.kind() == io::ErrorKind::Interrupted => {},
+            Err(_) => return Err(::Error::Io),
+        }
+
+        // The descriptors that fired are polled again the next time
+        let fired: Vec<u64> = self.ring.completion().map(|completion| completion.user_data()).collect();
+        for &mut (_, ref mut poll) in &mut self.fds {
+            if poll.is_some_and(|poll| fired.contains(&poll)) {
+                *poll = None;
+            }
+        }
+
+        handle_pending_events(&self.context)
+    }
+
+    /// Submits polls for the descriptors of the context that don't have one, and removes those
+    /// of the descriptors that went away.
+    fn arm(&mut self) -> ::Result<()> {
+        let current = pollfds(&self.context);
+
+        let gone: Vec<u64> = self.fds.iter()
+            .filter(|&&(fd, _)| !current.contains(&fd))
+            .filter_map(|&(_, poll)| poll)
+            .collect();
+        for poll in gone {
+            self.push(opcode::PollRemove::new(poll).build().user_data(REMOVAL))?;
+        }
+        sync_fds(&mut self.fds, &current, |_| Ok(None))?;
+
+        for index in 0..self.fds.len() {
+            let (fd, poll) = self.fds[index];
+            if poll.is_some() {
+                continue;
+            }
+            let poll = self.next_poll;
+            self.next_poll += 1;
+            self.push(opcode::PollAdd::new(types::Fd(fd.fd), poll_flags(fd)).build().user_data(poll))?;
+            self.fds[index].1 = Some(poll);
+        }
+        Ok(())
+    }
+
+    /// Queues a request, making room by submitting the queued ones if the queue is full.
+    fn push(&mut self, entry: squeue::Entry) -> ::Result<()> {
+        // The requests only refer to descriptors, which the ring holds on to
+        if unsafe { self.ring.submission().push(&entry) }.is_ok() {
+            return Ok(());
+        }
+        self.ring.submit().map_err(|_| ::Error::Io)?;
+        unsafe { self.ring.submission().push(&entry) }.map_err(|_| ::Error::Overflow)
+    }
+}
+
+fn poll_flags(fd: PollFd) -> u32 {
+    let mut flags = 0;
+    if fd.readable {
+        flags |= POLLIN as u32;
+    }
+    if fd.writable {
+        flags |= POLLOUT as u32;
+    }
+    flags
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_polls_for_the_events_libusb_asks_for() {
+        assert_eq!(POLLIN as u32, poll_flags(PollFd { fd: 3, readable: true, writable: false }));
+        assert_eq!((POLLIN | POLLOUT) as u32, poll_flags(PollFd { fd: 3, readable: true, writable: true }));
+    }
+}
@@ -0,0 +1,147 @@
+//! Handling the events of a context on an async runtime, instead of on the event thread.
+//!
+//! `libusb` signals its events through file descriptors. A runtime's reactor can watch them,
+//! and the events are then handled on a task of the runtime without blocking it:
+//!
+//! * [`tokio::EventDriver`](tokio/struct.EventDriver.html), with the `tokio` feature, also
+//!   adapts the I/O traits of the crate to those of Tokio.
+//! * [`async_io::EventDriver`](async_io/struct.EventDriver.html), with the `async-std` feature,
+//!   for async-std, smol and other runtimes built on `async-io`.
+//! * [`io_uring::EventDriver`](io_uring/struct.EventDriver.html), with the `io-uring` feature,
+//!   on a thread of the application that waits on an io_uring, on Linux.
+//!
+//! The drivers are meant for builds without the `event-thread` feature, as the event thread
+//! would otherwise handle the same events. Other runtimes can be integrated the same way with
+//! [`Context::pollfds`](../struct.Context.html#method.pollfds),
+//! [`Context::next_timeout`](../struct.Context.html#method.next_timeout) and
+//! [`Context::handle_events`](../struct.Context.html#method.handle_events).
+
+use std::time::Duration;
+
+use libc::{c_int, c_short, timeval, POLLIN, POLLOUT};
+use libusb::*;
+
+use context::ContextAsync;
+use event_thread;
+
+#[cfg(all(feature = "tokio", unix))]
+pub mod tokio;
+#[cfg(all(feature = "async-io", unix))]
+pub mod async_io;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod io_uring;
+
+// Came with libusb 1.0.20, after the libusb-sys bindings
+extern "C" {
+    fn libusb_free_pollfds(pollfds: *const *mut libusb_pollfd);
+}
+
+/// A file descriptor to watch for the events of a context, returned by
+/// [`Context::pollfds`](../struct.Context.html#method.pollfds).
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct PollFd {
+    /// The file descriptor.
+    pub fd: c_int,
+
+    /// Whether events come when the file descriptor is readable.
+    pub readable: bool,
+
+    /// Whether events come when the file descriptor is writable.
+    pub writable: bool,
+}
+
+impl PollFd {
+    fn from_events(fd: c_int, events: c_short) -> PollFd {
+        PollFd { fd, readable: events & POLLIN != 0, writable: events & POLLOUT != 0 }
+    }
+}
+
+/// Returns the file descriptors of a context. They change as devices are opened and closed.
+pub fn pollfds(context: &ContextAsync) -> Vec<PollFd> {
+    let mut fds = Vec::new();
+    if context.context.is_null() {
+        return fds;
+    }
+    unsafe {
+        let list = libusb_get_pollfds(context.context);
+        if list.is_null() {
+            return fds;
+        }
+
+        let mut entry = list;
+        while !(*entry).is_null() {
+            fds.push(PollFd::from_events((**entry).fd, (**entry).events));
+            entry = entry.add(1);
+        }
+        libusb_free_pollfds(list);
+    }
+    fds
+}
+
+/// Returns how long until a timeout of the context has to be handled, if it isn't signalled
+/// through the file descriptors.
+pub fn next_timeout(context: &ContextAsync) -> ::Result<Option<Duration>> {
+    if context.context.is_null() {
+        return Ok(None);
+    }
+    if unsafe { libusb_pollfds_handle_timeouts(context.context) } != 0 {
+        return Ok(None);
+    }
+
+    let mut timeout = timeval { tv_sec: 0, tv_usec: 0 };
+    match unsafe { libusb_get_next_timeout(context.context, &mut timeout) } {
+        0 => Ok(None),
+        n if n < 0 => Err(::error::from_libusb(n)),
+        _ => Ok(Some(Duration::new(timeout.tv_sec as u64, timeout.tv_usec as u32 * 1000))),
+    }
+}
+
+/// Handles the events that are pending, without waiting.
+pub fn handle_pending_events(context: &ContextAsync) -> ::Result<()> {
+    match event_thread::handle_events_once(context, Duration::from_secs(0), None) {
+        0 => Ok(()),
+        err => Err(::error::from_libusb(err)),
+    }
+}
+
+/// Brings the file descriptors registered with a reactor in line with those of the context,
+/// dropping those that went away and registering new ones with `register`.
+pub fn sync_fds<T, F>(registered: &mut Vec<(PollFd, T)>, current: &[PollFd], mut register: F) -> ::Result<()>
+    where F: FnMut(PollFd) -> ::std::io::Result<T>
+{
+    registered.retain(|&(fd, _)| current.contains(&fd));
+    for &fd in current {
+        if !registered.iter().any(|&(registered, _)| registered == fd) {
+            let source = register(fd).map_err(|_| ::Error::Io)?;
+            registered.push((fd, source));
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_converts_poll_events() {
+        assert_eq!(PollFd { fd: 3, readable: true, writable: false }, PollFd::from_events(3, POLLIN));
+        assert_eq!(PollFd { fd: 4, readable: false, writable: true }, PollFd::from_events(4, POLLOUT));
+    }
+
+    #[test]
+    fn it_registers_only_new_file_descriptors() {
+        let fd = |fd| PollFd { fd, readable: true, writable: false };
+        let mut registered = vec![(fd(3), 30), (fd(4), 40)];
+        let mut registrations = Vec::new();
+
+        sync_fds(&mut registered, &[fd(4), fd(5)], |fd| {
+            registrations.push(fd.fd);
+            Ok(fd.fd * 10)
+        }).unwrap();
+
+        assert_eq!(vec![(fd(4), 40), (fd(5), 50)], registered);
+        assert_eq!(vec![5], registrations);
+    }
+}
@@ -0,0 +1,199 @@
+//! Tokio integration, with the `tokio` feature.
+
+use std::future::Future;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task;
+use std::time::Instant;
+
+use futures_io;
+use tokio::io::{AsyncRead, AsyncWrite, Interest, ReadBuf};
+use tokio::io::unix::AsyncFd;
+use tokio::time::{self, Sleep};
+
+use context::{self, Context, ContextAsync};
+use super::{handle_pending_events, next_timeout, pollfds, sync_fds, PollFd};
+
+/// Future that handles the events of a context on the Tokio reactor, to be spawned on a Tokio
+/// runtime:
+///
+/// ```no_run
+/// # extern crate libusb_async;
+/// # extern crate tokio;
+/// # fn run(context: &libusb_async::Context) {
+/// tokio::spawn(libusb_async::runtime::tokio::EventDriver::new(context));
+/// # }
+/// # fn main() {}
+/// ```
+///
+/// It runs until it is dropped, or resolves to an error if the file descriptors of the context
+/// can't be registered, or `libusb` fails to handle the events.
+pub struct EventDriver {
+    context: Arc<ContextAsync>,
+    fds: Vec<(PollFd, AsyncFd<Fd>)>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+/// A file descriptor of `libusb`, which is closed by `libusb`.
+struct Fd(RawFd);
+
+impl AsRawFd for Fd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl EventDriver {
+    /// Creates a driver for the events of a context.
+    pub fn new(context: &Context) -> EventDriver {
+        EventDriver { context: context::shared(context).clone(), fds: Vec::new(), sleep: None }
+    }
+
+    /// Polls the file descriptors and the next timeout, returning whether any is ready.
+    fn poll_ready(&mut self, cx: &mut task::Context) -> ::Result<bool> {
+        sync_fds(&mut self.fds, &pollfds(&self.context), |fd| {
+            let interest = match (fd.readable, fd.writable) {
+                (_, false) => Interest::READABLE,
+                (false, true) => Interest::WRITABLE,
+                (true, true) => Interest::READABLE | Interest::WRITABLE,
+            };
+            AsyncFd::with_interest(Fd(fd.fd), interest)
+        })?;
+
+        let mut ready = false;
+        for &(fd, ref source) in &self.fds {
+            // Cleared before the events are handled, so events that arrive while they are
+            // handled make the descriptor ready again
+            if fd.readable {
+                if let task::Poll::Ready(guard) = source.poll_read_ready(cx) {
+                    guard.map_err(|_| ::Error::Io)?.clear_ready();
+                    ready = true;
+                }
+            }
+            if fd.writable {
+                if let task::Poll::Ready(guard) = source.poll_write_ready(cx) {
+                    guard.map_err(|_| ::Error::Io)?.clear_ready();
+                    ready = true;
+                }
+            }
+        }
+
+        self.sleep = match next_timeout(&self.context)? {
+            Some(timeout) => {
+                let deadline = time::Instant::from_std(Instant::now() + timeout);
+                let mut sleep = self.sleep.take().unwrap_or_else(|| Box::pin(time::sleep_until(deadline)));
+                sleep.as_mut().reset(deadline);
+                if sleep.as_mut().poll(cx).is_ready() {
+                    ready = true;
+                }
+                Some(sleep)
+            },
+            None => None,
+        };
+
+        Ok(ready)
+    }
+}
+
+impl Future for EventDriver {
+    type Output = ::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            match this.poll_ready(cx) {
+                Ok(true) => {},
+                Ok(false) => return task::Poll::Pending,
+                Err(e) => return task::Poll::Ready(Err(e)),
+            }
+
+            if let Err(e) = handle_pending_events(&this.context) {
+                return task::Poll::Ready(Err(e));
+            }
+        }
+    }
+}
+
+/// Adapts the `futures` I/O traits implemented by the crate, e.g., by the serial port drivers
+/// and [`BulkPipe`](../../framed/struct.BulkPipe.html), to those of Tokio.
+#[derive(Debug)]
+pub struct TokioIo<T> {
+    inner: T,
+}
+
+impl<T> TokioIo<T> {
+    pub fn new(inner: T) -> TokioIo<T> {
+        TokioIo { inner }
+    }
+
+    /// Returns the adapted reader or writer.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns the adapted reader or writer.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Returns the adapted reader or writer, consuming the adapter.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: futures_io::AsyncRead + Unpin> AsyncRead for TokioIo<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context, buf: &mut ReadBuf) -> task::Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_read(cx, buf.initialize_unfilled()) {
+            task::Poll::Ready(Ok(n)) => {
+                buf.advance(n);
+                task::Poll::Ready(Ok(()))
+            },
+            task::Poll::Ready(Err(e)) => task::Poll::Ready(Err(e)),
+            task::Poll::Pending => task::Poll::Pending,
+        }
+    }
+}
+
+impl<T: futures_io::AsyncWrite + Unpin> AsyncWrite for TokioIo<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context, buf: &[u8]) -> task::Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    extern crate futures;
+
+    use super::*;
+    use self::futures::executor::block_on;
+    use self::futures::future::poll_fn;
+    use self::futures::io::Cursor;
+
+    #[test]
+    fn it_adapts_futures_readers_and_writers() {
+        let mut io = TokioIo::new(Cursor::new(b"abc".to_vec()));
+
+        let mut data = [0; 2];
+        let mut buf = ReadBuf::new(&mut data);
+        block_on(poll_fn(|cx| Pin::new(&mut io).poll_read(cx, &mut buf))).unwrap();
+        assert_eq!(b"ab", buf.filled());
+
+        assert_eq!(2, block_on(poll_fn(|cx| Pin::new(&mut io).poll_write(cx, b"de"))).unwrap());
+        block_on(poll_fn(|cx| Pin::new(&mut io).poll_shutdown(cx))).unwrap();
+        assert_eq!(b"abde", &io.into_inner().into_inner()[..]);
+    }
+}
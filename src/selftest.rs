@@ -0,0 +1,304 @@
+//! Self-test of the crate against a device that loops back what it receives, e.g., the bulk
+//! loopback firmware of a Cypress FX2 or a test gadget.
+//!
+//! [`Loopback::run`](struct.Loopback.html#method.run) writes to the OUT endpoint and reads the
+//! data back from the IN endpoint, with lengths around the maximum packet size, a zero-length
+//! packet, a cancelled read and a read that times out, and then measures the throughput:
+//!
+//! ```no_run
+//! # fn main() -> libusb_async::Result<()> {
+//! use libusb_async::{Context, DeviceFilter};
+//! use libusb_async::selftest::Loopback;
+//!
+//! let context = Context::new()?;
+//! let interface = context.find(&DeviceFilter::new().product(0x04B4, 0x1004))?.open()?.claim(0)?;
+//! let report = Loopback::from_interface(&interface)?.run();
+//! print!("{}", report);
+//! assert!(report.passed());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The tests run one after the other, blocking the calling thread.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use blocking::block_on;
+use claimed::ClaimedInterface;
+use device_handle::DeviceHandle;
+use fields::{Direction, EndpointAddress, TransferType};
+use transfer::{CompletedTransfer, TransferFuture, TransferStatus};
+
+/// How long a read may wait for data that should come at once.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a read waits in the timeout test.
+const SHORT_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// The length of each round trip while measuring the throughput, in packets.
+const THROUGHPUT_PACKETS: usize = 64;
+
+/// A pair of endpoints of a loopback device, to run the self-test on.
+pub struct Loopback<'a> {
+    handle: &'a DeviceHandle,
+    out: EndpointAddress,
+    in_: EndpointAddress,
+    max_packet_size: usize,
+    timeout: Duration,
+    throughput_duration: Duration,
+}
+
+impl<'a> Loopback<'a> {
+    /// Tests the loopback from an OUT endpoint to an IN endpoint of a handle, whose interface
+    /// has been claimed.
+    pub fn new(handle: &'a DeviceHandle, out: EndpointAddress, in_: EndpointAddress, max_packet_size: u16) -> Loopback<'a> {
+        Loopback {
+            handle,
+            out,
+            in_,
+            max_packet_size: max_packet_size.max(1) as usize,
+            timeout: DEFAULT_TIMEOUT,
+            throughput_duration: Duration::from_secs(1),
+        }
+    }
+
+    /// Tests the loopback between the first bulk OUT and bulk IN endpoints of an interface.
+    ///
+    /// Fails with `NotFound` if the interface doesn't have both.
+    pub fn from_interface(interface: &'a ClaimedInterface) -> ::Result<Loopback<'a>> {
+        let (out, max_packet_size) = interface.endpoint(TransferType::Bulk, Direction::Out)?;
+        let (in_, _) = interface.endpoint(TransferType::Bulk, Direction::In)?;
+        Ok(Loopback::new(interface.handle(), out, in_, max_packet_size))
+    }
+
+    /// Sets how long a read may wait for its data before the test fails, 1 second by default.
+    pub fn timeout(mut self, timeout: Duration) -> Loopback<'a> {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how long the throughput is measured, 1 second by default.
+    pub fn throughput_duration(mut self, duration: Duration) -> Loopback<'a> {
+        self.throughput_duration = duration;
+        self
+    }
+
+    /// Runs all tests, and reports their results.
+    pub fn run(&self) -> SelfTestReport {
+        let mut results: Vec<TestResult> = round_trip_lengths(self.max_packet_size).into_iter().map(|length| {
+            self.measure(format!("round trip of {} bytes", length), |loopback| {
+                loopback.round_trip(&pattern(length, length as u8), false)
+            })
+        }).collect();
+
+        let packet = self.max_packet_size;
+        results.push(self.measure("zero-length packet".to_owned(), |loopback| loopback.round_trip(&[], false)));
+        results.push(self.measure("packet-multiple with zero-length packet".to_owned(), |loopback| {
+            loopback.round_trip(&pattern(2 * packet, 1), true)
+        }));
+        results.push(self.measure("cancelled read".to_owned(), |loopback| loopback.cancelled_read()));
+        results.push(self.measure("read timeout".to_owned(), |loopback| loopback.read_timeout()));
+        results.push(self.measure("throughput".to_owned(), |loopback| loopback.throughput()));
+
+        SelfTestReport { results }
+    }
+
+    /// Runs a test, timing it.
+    fn measure<F>(&self, name: String, test: F) -> TestResult
+        where F: FnOnce(&Loopback) -> Result<usize, String>
+    {
+        let start = Instant::now();
+        let result = test(self);
+        let elapsed = start.elapsed();
+
+        match result {
+            Ok(bytes) => TestResult { name, failure: None, bytes, elapsed },
+            Err(failure) => TestResult { name, failure: Some(failure), bytes: 0, elapsed },
+        }
+    }
+
+    /// Writes data and checks that it is read back, returning the number of bytes.
+    fn round_trip(&self, data: &[u8], add_zero_packet: bool) -> Result<usize, String> {
+        // Read first, so the device can send the data back as soon as it has it
+        let read = self.submit_read(data.len().max(self.max_packet_size), self.timeout)?;
+        let write = self.submit_write(data, add_zero_packet)?;
+
+        finish(write, "write")?;
+        let read = finish(read, "read")?;
+        check_echo(data, read.get_buffer())?;
+        Ok(data.len())
+    }
+
+    /// Cancels a read that gets no data, and checks that the endpoints still work.
+    fn cancelled_read(&self) -> Result<usize, String> {
+        drop(self.submit_read(self.max_packet_size, self.timeout)?);
+        self.round_trip(&pattern(self.max_packet_size, 2), false)
+    }
+
+    /// Checks that a read without data times out, and that the endpoints still work.
+    fn read_timeout(&self) -> Result<usize, String> {
+        let read = block_on(self.submit_read(self.max_packet_size, SHORT_TIMEOUT)?).map_err(|e| format!("read: {}", e))?;
+        if read.get_status() != TransferStatus::TimedOut {
+            return Err(format!("read ended with {} instead of timing out", read.get_status()));
+        }
+        self.round_trip(&pattern(self.max_packet_size, 3), false)
+    }
+
+    /// Loops data back for the throughput duration.
+    fn throughput(&self) -> Result<usize, String> {
+        let data = pattern(THROUGHPUT_PACKETS * self.max_packet_size, 4);
+        let start = Instant::now();
+        let mut bytes = 0;
+
+        while start.elapsed() < self.throughput_duration {
+            bytes += self.round_trip(&data, false)?;
+        }
+        Ok(bytes)
+    }
+
+    fn submit_read(&self, length: usize, timeout: Duration) -> Result<TransferFuture, String> {
+        let mut transfer = self.handle.alloc_transfer(0).map_err(|e| format!("alloc: {}", e))?;
+        transfer.fill_bulk_read(self.in_, length).map_err(|e| format!("read: {}", e))?;
+        Ok(transfer.submit_with_timeout(timeout))
+    }
+
+    fn submit_write(&self, data: &[u8], add_zero_packet: bool) -> Result<TransferFuture, String> {
+        let mut transfer = self.handle.alloc_transfer(0).map_err(|e| format!("alloc: {}", e))?;
+        transfer.fill_bulk_write(self.out, data).map_err(|e| format!("write: {}", e))?;
+        transfer.set_add_zero_packet(add_zero_packet);
+        Ok(transfer.submit_with_timeout(self.timeout))
+    }
+}
+
+/// Waits for a transfer, and checks its status.
+fn finish(transfer: TransferFuture, what: &str) -> Result<CompletedTransfer, String> {
+    block_on(transfer)
+        .and_then(|transfer| transfer.check_status().map(|_| transfer))
+        .map_err(|e| format!("{}: {}", what, e))
+}
+
+/// The lengths of the round trips: one byte, and lengths around one and two packets.
+fn round_trip_lengths(max_packet_size: usize) -> Vec<usize> {
+    let mut lengths = vec![1, max_packet_size - 1, max_packet_size, max_packet_size + 1,
+                           2 * max_packet_size, 2 * max_packet_size + 1];
+    lengths.retain(|&length| length > 0);
+    lengths.dedup();
+    lengths
+}
+
+/// Test data, different for each seed so stale data from an earlier test is caught.
+fn pattern(length: usize, seed: u8) -> Vec<u8> {
+    (0..length).map(|i| (i as u8).wrapping_mul(7).wrapping_add(seed)).collect()
+}
+
+/// Compares the data read back with the data written.
+fn check_echo(sent: &[u8], received: &[u8]) -> Result<(), String> {
+    if let Some(offset) = sent.iter().zip(received).position(|(a, b)| a != b) {
+        return Err(format!("data differs at byte {}: wrote 0x{:02x}, read 0x{:02x}",
+                           offset, sent[offset], received[offset]));
+    }
+    if sent.len() != received.len() {
+        return Err(format!("wrote {} bytes, read {}", sent.len(), received.len()));
+    }
+    Ok(())
+}
+
+/// The results of [`Loopback::run`](struct.Loopback.html#method.run).
+///
+/// `Display` formats a line per test, with its throughput.
+#[derive(Debug,Clone,PartialEq)]
+pub struct SelfTestReport {
+    pub results: Vec<TestResult>,
+}
+
+impl SelfTestReport {
+    /// Returns whether every test passed.
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(TestResult::passed)
+    }
+}
+
+/// The result of a test of the self-test.
+#[derive(Debug,Clone,PartialEq)]
+pub struct TestResult {
+    pub name: String,
+
+    /// Why the test failed, or `None` if it passed.
+    pub failure: Option<String>,
+
+    /// The number of bytes looped back.
+    pub bytes: usize,
+
+    pub elapsed: Duration,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+
+    /// Returns the number of bytes looped back per second.
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(1e-9)
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for result in &self.results {
+            writeln!(f, "{}", result)?;
+        }
+        let passed = self.results.iter().filter(|result| result.passed()).count();
+        writeln!(f, "{} of {} passed", passed, self.results.len())
+    }
+}
+
+impl fmt::Display for TestResult {
+    /// Formats the result, e.g., "PASS round trip of 512 bytes (512 bytes in 1.2 ms, 0.4 MB/s)".
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.failure {
+            None => write!(f, "PASS {} ({} bytes in {:.1} ms, {:.1} MB/s)", self.name, self.bytes,
+                           self.elapsed.as_secs_f64() * 1000.0, self.throughput() / 1_000_000.0),
+            Some(ref failure) => write!(f, "FAIL {}: {}", self.name, failure),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_tests_lengths_around_packet_boundaries() {
+        assert_eq!(vec![1, 63, 64, 65, 128, 129], round_trip_lengths(64));
+        assert_eq!(vec![1, 2, 3], round_trip_lengths(1));
+    }
+
+    #[test]
+    fn it_finds_where_the_echo_differs() {
+        let sent = pattern(8, 0);
+        assert_eq!(Ok(()), check_echo(&sent, &sent));
+        assert_eq!(Err("wrote 8 bytes, read 4".to_owned()), check_echo(&sent, &sent[..4]));
+
+        let mut received = sent.clone();
+        received[5] ^= 0xFF;
+        assert!(check_echo(&sent, &received).unwrap_err().starts_with("data differs at byte 5"));
+        assert_ne!(pattern(8, 1), sent);
+    }
+
+    #[test]
+    fn it_reports_results() {
+        let report = SelfTestReport { results: vec![
+            TestResult { name: "throughput".to_owned(), failure: None, bytes: 2_000_000, elapsed: Duration::from_secs(1) },
+            TestResult { name: "read timeout".to_owned(), failure: Some("read ended with Stall".to_owned()),
+                         bytes: 0, elapsed: Duration::from_millis(3) },
+        ]};
+
+        assert!(!report.passed());
+        assert_eq!("PASS throughput (2000000 bytes in 1000.0 ms, 2.0 MB/s)\n\
+                    FAIL read timeout: read ended with Stall\n\
+                    1 of 2 passed\n", report.to_string());
+    }
+}
@@ -0,0 +1,621 @@
+//! CDC-ACM (USB serial port) class support.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task;
+use std::time::Duration;
+
+use futures_core::Stream;
+use futures_io::{AsyncRead, AsyncWrite};
+
+use bulk_io::BulkIo;
+use cdc;
+use config_descriptor::ConfigDescriptor;
+use control::{self, ControlFuture};
+use device::Device;
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::{Direction, EndpointAddress, Recipient, RequestType, TransferType, request_type};
+use transfer::TransferFuture;
+
+const SUBCLASS_ACM: u8 = 0x02;
+
+const SET_LINE_CODING: u8 = 0x20;
+const GET_LINE_CODING: u8 = 0x21;
+const SET_CONTROL_LINE_STATE: u8 = 0x22;
+const SEND_BREAK: u8 = 0x23;
+
+const NOTIFICATION_SERIAL_STATE: u8 = 0x20;
+
+/// The interfaces of one CDC-ACM function.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct AcmInterfaces {
+    /// The communications interface, which carries class requests and notifications.
+    pub control_interface: u8,
+
+    /// The data interface, which has the bulk endpoints.
+    pub data_interface: u8,
+}
+
+/// Finds the CDC-ACM functions of a configuration.
+///
+/// The data interface is taken from the Union functional descriptor of the communications
+/// interface. Devices that omit it are assumed to put the data interface right after the
+/// communications interface.
+pub fn find_acm_interfaces(config: &ConfigDescriptor) -> Vec<AcmInterfaces> {
+    cdc::find_functions(config, SUBCLASS_ACM).into_iter()
+        .map(|(control_interface, data_interface)| AcmInterfaces { control_interface, data_interface })
+        .collect()
+}
+
+/// Parity setting of a serial line.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,
+    Space,
+}
+
+/// Number of stop bits of a serial line.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum StopBits {
+    One,
+    OnePointFive,
+    Two,
+}
+
+/// Character framing and speed of a serial line.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct LineCoding {
+    /// Data terminal rate in bits per second.
+    pub baud_rate: u32,
+
+    /// Number of data bits: 5, 6, 7, 8 or 16.
+    pub data_bits: u8,
+
+    /// Parity.
+    pub parity: Parity,
+
+    /// Number of stop bits.
+    pub stop_bits: StopBits,
+}
+
+impl LineCoding {
+    /// Creates a line coding with 8 data bits, no parity and one stop bit.
+    pub fn new(baud_rate: u32) -> LineCoding {
+        LineCoding { baud_rate, data_bits: 8, parity: Parity::None, stop_bits: StopBits::One }
+    }
+
+    /// Encodes the line coding as sent with SET_LINE_CODING.
+    pub fn encode(&self) -> [u8; 7] {
+        let rate = self.baud_rate.to_le_bytes();
+
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::OnePointFive => 1,
+            StopBits::Two => 2,
+        };
+
+        let parity = match self.parity {
+            Parity::None => 0,
+            Parity::Odd => 1,
+            Parity::Even => 2,
+            Parity::Mark => 3,
+            Parity::Space => 4,
+        };
+
+        [rate[0], rate[1], rate[2], rate[3], stop_bits, parity, self.data_bits]
+    }
+
+    /// Decodes a line coding as returned by GET_LINE_CODING.
+    ///
+    /// Returns `Error::InvalidParam` if the data is too short or has unknown values.
+    pub fn decode(data: &[u8]) -> ::Result<LineCoding> {
+        if data.len() < 7 {
+            return Err(Error::InvalidParam);
+        }
+
+        let stop_bits = match data[4] {
+            0 => StopBits::One,
+            1 => StopBits::OnePointFive,
+            2 => StopBits::Two,
+            _ => return Err(Error::InvalidParam),
+        };
+
+        let parity = match data[5] {
+            0 => Parity::None,
+            1 => Parity::Odd,
+            2 => Parity::Even,
+            3 => Parity::Mark,
+            4 => Parity::Space,
+            _ => return Err(Error::InvalidParam),
+        };
+
+        Ok(LineCoding {
+            baud_rate: u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+            data_bits: data[6],
+            parity,
+            stop_bits,
+        })
+    }
+}
+
+/// The state of the serial line, as reported by a SERIAL_STATE notification.
+///
+/// DCD and DSR are states, while the other bits report that something happened since the
+/// previous notification.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct SerialState {
+    bits: u16,
+}
+
+impl SerialState {
+    /// Returns the raw `bmUartState` bitmap.
+    pub fn bits(&self) -> u16 {
+        self.bits
+    }
+
+    /// Indicates if the Data Carrier Detect signal is active.
+    pub fn dcd(&self) -> bool {
+        self.bits & 0x01 != 0
+    }
+
+    /// Indicates if the Data Set Ready signal is active.
+    pub fn dsr(&self) -> bool {
+        self.bits & 0x02 != 0
+    }
+
+    /// Indicates if a break was detected.
+    pub fn break_detected(&self) -> bool {
+        self.bits & 0x04 != 0
+    }
+
+    /// Indicates if a ring signal was detected.
+    pub fn ring(&self) -> bool {
+        self.bits & 0x08 != 0
+    }
+
+    /// Indicates if a framing error occurred.
+    pub fn framing_error(&self) -> bool {
+        self.bits & 0x10 != 0
+    }
+
+    /// Indicates if a parity error occurred.
+    pub fn parity_error(&self) -> bool {
+        self.bits & 0x20 != 0
+    }
+
+    /// Indicates if received data was lost because the device's buffer overran.
+    pub fn overrun(&self) -> bool {
+        self.bits & 0x40 != 0
+    }
+
+    /// Returns the events this state reports, given the state reported before it.
+    ///
+    /// Without a previous state, the DCD and DSR signals are reported as changed.
+    pub fn events(&self, previous: Option<SerialState>) -> Vec<SerialEvent> {
+        let mut events = Vec::new();
+
+        if previous.is_none_or(|previous| previous.dcd() != self.dcd()) {
+            events.push(SerialEvent::CarrierDetect(self.dcd()));
+        }
+
+        if previous.is_none_or(|previous| previous.dsr() != self.dsr()) {
+            events.push(SerialEvent::DataSetReady(self.dsr()));
+        }
+
+        let errors = [
+            (self.break_detected(), SerialEvent::Break),
+            (self.ring(), SerialEvent::Ring),
+            (self.framing_error(), SerialEvent::FramingError),
+            (self.parity_error(), SerialEvent::ParityError),
+            (self.overrun(), SerialEvent::Overrun),
+        ];
+
+        events.extend(errors.iter().filter(|&&(active, _)| active).map(|&(_, event)| event));
+        events
+    }
+
+    /// Parses a notification. Returns `None` if it isn't a SERIAL_STATE notification.
+    fn from_notification(notification: &[u8]) -> Option<SerialState> {
+        if notification.len() < 10 || notification[1] != NOTIFICATION_SERIAL_STATE {
+            return None;
+        }
+
+        Some(SerialState { bits: notification[8] as u16 | (notification[9] as u16) << 8 })
+    }
+}
+
+/// A change of the serial line reported by the device.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum SerialEvent {
+    /// The Data Carrier Detect signal changed to the given state.
+    CarrierDetect(bool),
+
+    /// The Data Set Ready signal changed to the given state.
+    DataSetReady(bool),
+
+    /// A break was detected.
+    Break,
+
+    /// A ring signal was detected.
+    Ring,
+
+    /// A framing error occurred.
+    FramingError,
+
+    /// A parity error occurred.
+    ParityError,
+
+    /// Received data was lost because the device's buffer overran.
+    Overrun,
+}
+
+/// An open CDC-ACM serial port.
+///
+/// Data is read and written through the `AsyncRead` and `AsyncWrite` implementations. They are
+/// also implemented for `&AcmPort`, so the port can be read, written and monitored for
+/// [`serial_states`](#method.serial_states) concurrently.
+pub struct AcmPort {
+    handle: DeviceHandle,
+    interfaces: AcmInterfaces,
+    notification_endpoint: Option<(u8, u16)>,
+    bulk_in: EndpointAddress,
+    bulk_out: EndpointAddress,
+    io: BulkIo,
+    detached: Vec<u8>,
+}
+
+impl AcmPort {
+    /// Opens a device and claims the interfaces of a CDC-ACM function.
+    ///
+    /// Kernel drivers bound to the interfaces are detached and reattached when the port is
+    /// dropped.
+    ///
+    /// ## Errors
+    ///
+    /// * `NotFound` if the data interface lacks a bulk IN or bulk OUT endpoint.
+    /// * Any error returned while opening the device or claiming the interfaces.
+    pub fn open(device: &Device, interfaces: &AcmInterfaces) -> ::Result<AcmPort> {
+        let (notification_endpoint, data_setting, bulk_in, bulk_out) = {
+            let config = device.active_config_descriptor()?;
+            let setting = |number: u8| {
+                config.interfaces()
+                    .find(|i| i.number() == number)
+                    .and_then(|i| i.descriptors().next())
+                    .ok_or(Error::NotFound)
+            };
+
+            let notification = setting(interfaces.control_interface)?
+                .first_endpoint(TransferType::Interrupt, Direction::In)
+                .map(|ep| (ep.address(), ep.max_packet_size()));
+
+            // The data interface may have a zero-bandwidth first setting
+            let data = config.interfaces()
+                .find(|i| i.number() == interfaces.data_interface)
+                .ok_or(Error::NotFound)?;
+            let (data_setting, bulk_in, bulk_out) = data.descriptors().filter_map(|setting| {
+                let bulk_in = setting.first_endpoint(TransferType::Bulk, Direction::In)?;
+                let bulk_out = setting.first_endpoint(TransferType::Bulk, Direction::Out)?;
+                Some((setting.setting_number(), bulk_in.address().into(), bulk_out.address().into()))
+            }).next().ok_or(Error::NotFound)?;
+
+            (notification, data_setting, bulk_in, bulk_out)
+        };
+
+        let mut handle = device.open()?;
+        let mut detached = Vec::new();
+
+        for &interface in &[interfaces.control_interface, interfaces.data_interface] {
+            if handle.kernel_driver_active(interface).unwrap_or(false) {
+                handle.detach_kernel_driver(interface)?;
+                detached.push(interface);
+            }
+
+            handle.claim_interface(interface)?;
+        }
+
+        if data_setting != 0 {
+            handle.set_alternate_setting(interfaces.data_interface, data_setting)?;
+        }
+
+        Ok(AcmPort {
+            handle,
+            interfaces: *interfaces,
+            notification_endpoint,
+            bulk_in,
+            bulk_out,
+            io: BulkIo::default(),
+            detached,
+        })
+    }
+
+    /// Returns the handle of the device.
+    pub fn handle(&self) -> &DeviceHandle {
+        &self.handle
+    }
+
+    /// Returns the interfaces of the port.
+    pub fn interfaces(&self) -> &AcmInterfaces {
+        &self.interfaces
+    }
+
+    /// Sets the baud rate and character framing.
+    pub fn set_line_coding(&self, line_coding: &LineCoding) -> ControlFuture<usize> {
+        control::write(&self.handle,
+                       request_type(Direction::Out, RequestType::Class, Recipient::Interface),
+                       SET_LINE_CODING,
+                       0,
+                       self.interfaces.control_interface as u16,
+                       &line_coding.encode())
+    }
+
+    /// Reads the current baud rate and character framing.
+    pub fn line_coding(&self) -> ControlFuture<LineCoding> {
+        control::read(&self.handle,
+                      request_type(Direction::In, RequestType::Class, Recipient::Interface),
+                      GET_LINE_CODING,
+                      0,
+                      self.interfaces.control_interface as u16,
+                      7,
+                      LineCoding::decode)
+    }
+
+    /// Sets the DTR and RTS control signals.
+    pub fn set_control_lines(&self, dtr: bool, rts: bool) -> ControlFuture<usize> {
+        control::write(&self.handle,
+                       request_type(Direction::Out, RequestType::Class, Recipient::Interface),
+                       SET_CONTROL_LINE_STATE,
+                       dtr as u16 | (rts as u16) << 1,
+                       self.interfaces.control_interface as u16,
+                       &[])
+    }
+
+    /// Returns a stream of serial line state changes.
+    ///
+    /// Notifications other than SERIAL_STATE are skipped. The stream yields `Err(NotFound)` if
+    /// the communications interface has no notification endpoint, and ends after the first
+    /// error.
+    pub fn serial_states<'a>(&'a self) -> SerialStates<'a> {
+        SerialStates { port: self, pending: None, done: false }
+    }
+
+    /// Returns a stream of serial line events, e.g., DCD changes and parity errors.
+    ///
+    /// The first notification reports the DCD and DSR signals as changed. Errors are handled
+    /// as by [`serial_states`](#method.serial_states).
+    pub fn serial_events<'a>(&'a self) -> SerialEvents<'a> {
+        SerialEvents { states: self.serial_states(), previous: None, events: VecDeque::new() }
+    }
+
+    /// Sends a break of the given duration, rounded down to milliseconds.
+    ///
+    /// A duration of zero ends a break in progress. Durations of 65535 ms or longer keep the
+    /// break on until another break is sent. Only devices that announce SEND_BREAK support in
+    /// their ACM functional descriptor accept this request.
+    pub fn send_break(&self, duration: Duration) -> ControlFuture<usize> {
+        control::write(&self.handle,
+                       request_type(Direction::Out, RequestType::Class, Recipient::Interface),
+                       SEND_BREAK,
+                       duration.as_millis().min(0xFFFF) as u16,
+                       self.interfaces.control_interface as u16,
+                       &[])
+    }
+}
+
+impl Drop for AcmPort {
+    fn drop(&mut self) {
+        for &interface in &self.detached {
+            let _ = self.handle.release_interface(interface);
+            let _ = self.handle.attach_kernel_driver(interface);
+        }
+    }
+}
+
+impl AsyncRead for &AcmPort {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context, buf: &mut [u8]) -> task::Poll<io::Result<usize>> {
+        self.io.poll_read(&self.handle, self.bulk_in, cx, buf, |data| data.to_vec())
+    }
+}
+
+impl AsyncWrite for &AcmPort {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context, buf: &[u8]) -> task::Poll<io::Result<usize>> {
+        self.io.poll_write(&self.handle, self.bulk_out, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.io.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.io.poll_flush(cx)
+    }
+}
+
+impl AsyncRead for AcmPort {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context, buf: &mut [u8]) -> task::Poll<io::Result<usize>> {
+        self.io.poll_read(&self.handle, self.bulk_in, cx, buf, |data| data.to_vec())
+    }
+}
+
+impl AsyncWrite for AcmPort {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context, buf: &[u8]) -> task::Poll<io::Result<usize>> {
+        self.io.poll_write(&self.handle, self.bulk_out, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.io.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<io::Result<()>> {
+        self.io.poll_flush(cx)
+    }
+}
+
+/// Stream of serial line states returned by
+/// [`AcmPort::serial_states`](struct.AcmPort.html#method.serial_states).
+pub struct SerialStates<'a> {
+    port: &'a AcmPort,
+    pending: Option<TransferFuture>,
+    done: bool,
+}
+
+impl<'a> SerialStates<'a> {
+    fn submit(&self) -> ::Result<TransferFuture> {
+        let (endpoint, packet_size) = self.port.notification_endpoint.ok_or(Error::NotFound)?;
+        let mut transfer = self.port.handle.alloc_transfer(0)?;
+        transfer.fill_interrupt_read(endpoint.into(), packet_size)?;
+        Ok(transfer.submit())
+    }
+}
+
+impl<'a> Stream for SerialStates<'a> {
+    type Item = ::Result<SerialState>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        while !this.done {
+            if this.pending.is_none() {
+                match this.submit() {
+                    Ok(future) => this.pending = Some(future),
+                    Err(e) => {
+                        this.done = true;
+                        return task::Poll::Ready(Some(Err(e)));
+                    },
+                }
+            }
+
+            let result = match this.pending {
+                Some(ref mut future) => match Pin::new(future).poll(cx) {
+                    task::Poll::Pending => return task::Poll::Pending,
+                    task::Poll::Ready(result) => result,
+                },
+                None => unreachable!(),
+            };
+
+            this.pending = None;
+
+            let state = result.and_then(|transfer| {
+                transfer.check_status()?;
+                Ok(SerialState::from_notification(transfer.get_buffer()))
+            });
+
+            match state {
+                Ok(Some(state)) => {
+                    // Resubmit before handing out the state to keep the endpoint polled
+                    this.pending = this.submit().ok();
+                    return task::Poll::Ready(Some(Ok(state)));
+                },
+                Ok(None) => {},
+                Err(e) => {
+                    this.done = true;
+                    return task::Poll::Ready(Some(Err(e)));
+                },
+            }
+        }
+
+        task::Poll::Ready(None)
+    }
+}
+
+/// Stream of serial line events returned by
+/// [`AcmPort::serial_events`](struct.AcmPort.html#method.serial_events).
+pub struct SerialEvents<'a> {
+    states: SerialStates<'a>,
+    previous: Option<SerialState>,
+    events: VecDeque<SerialEvent>,
+}
+
+impl<'a> Stream for SerialEvents<'a> {
+    type Item = ::Result<SerialEvent>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(event) = this.events.pop_front() {
+                return task::Poll::Ready(Some(Ok(event)));
+            }
+
+            match Pin::new(&mut this.states).poll_next(cx) {
+                task::Poll::Ready(Some(Ok(state))) => {
+                    this.events.extend(state.events(this.previous));
+                    this.previous = Some(state);
+                },
+                task::Poll::Ready(Some(Err(e))) => return task::Poll::Ready(Some(Err(e))),
+                task::Poll::Ready(None) => return task::Poll::Ready(None),
+                task::Poll::Pending => return task::Poll::Pending,
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::mem;
+
+    const CDC_EXTRA: [u8; 5] = [0x05, 0x24, 0x06, 0x00, 0x01];
+
+    #[test]
+    fn it_finds_acm_interfaces_from_union_descriptor() {
+        let control = interface!(merge!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x83, bmAttributes: 0x03))
+                                        => bInterfaceClass: 0x02, bInterfaceSubClass: 0x02, extra: CDC_EXTRA.as_ptr(), extra_length: 5));
+        let data = interface!(interface_descriptor!(bInterfaceNumber: 1, bInterfaceClass: 0x0A));
+        let config = config_descriptor!(control, data);
+        let config = unsafe { ::config_descriptor::from_libusb(&config) };
+
+        assert_eq!(vec![AcmInterfaces { control_interface: 0, data_interface: 1 }], find_acm_interfaces(&config));
+        mem::forget(config);
+    }
+
+    #[test]
+    fn it_requires_data_interface() {
+        let control = interface!(interface_descriptor!(bInterfaceClass: 0x02, bInterfaceSubClass: 0x02));
+        let other = interface!(interface_descriptor!(bInterfaceNumber: 1, bInterfaceClass: 0x03));
+        let config = config_descriptor!(control, other);
+        let config = unsafe { ::config_descriptor::from_libusb(&config) };
+
+        assert!(find_acm_interfaces(&config).is_empty());
+        mem::forget(config);
+    }
+
+    #[test]
+    fn it_encodes_line_coding() {
+        let coding = LineCoding { baud_rate: 115200, data_bits: 7, parity: Parity::Even, stop_bits: StopBits::Two };
+
+        assert_eq!([0x00, 0xC2, 0x01, 0x00, 0x02, 0x02, 0x07], coding.encode());
+        assert_eq!(coding, LineCoding::decode(&coding.encode()).unwrap());
+    }
+
+    #[test]
+    fn it_rejects_unknown_parity() {
+        assert!(LineCoding::decode(&[0x80, 0x25, 0x00, 0x00, 0x00, 0x05, 0x08]).is_err());
+    }
+
+    #[test]
+    fn it_parses_serial_state_notification() {
+        let notification = [0xA1, 0x20, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x03, 0x00];
+
+        assert_eq!(Some(SerialState { bits: 0x03 }), SerialState::from_notification(&notification));
+        assert_eq!(None, SerialState::from_notification(&[0xA1, 0x2A, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]));
+    }
+
+    #[test]
+    fn it_reports_serial_events() {
+        let state = SerialState { bits: 0x21 };
+
+        assert!(state.dcd() && state.parity_error() && !state.dsr());
+        assert_eq!(vec![SerialEvent::CarrierDetect(true), SerialEvent::DataSetReady(false), SerialEvent::ParityError],
+                   state.events(None));
+        assert_eq!(vec![SerialEvent::ParityError], state.events(Some(SerialState { bits: 0x01 })));
+        assert_eq!(vec![SerialEvent::CarrierDetect(false), SerialEvent::Break],
+                   SerialState { bits: 0x04 }.events(Some(state)));
+    }
+}
@@ -0,0 +1,137 @@
+use config_descriptor::ConfigDescriptor;
+use device_descriptor::DeviceDescriptor;
+use diff::{self, FieldChange};
+
+/// Descriptor fields whose changes don't affect how the device's functions are accessed.
+const COSMETIC_FIELDS: [&str; 10] = [
+    "bcdUSB", "bcdDevice", "iManufacturer", "iProduct", "iSerialNumber",
+    "iConfiguration", "iInterface", "bMaxPower", "selfPowered", "remoteWakeup",
+];
+
+/// The complete descriptor set of a device at one point in time.
+///
+/// Take a snapshot with [`Device::descriptor_snapshot`](struct.Device.html#method.descriptor_snapshot)
+/// when a device is first seen and compare it with a fresh one after the device reconnects to
+/// find out whether class drivers have to probe it again, e.g., because its firmware switched
+/// between runtime and DFU mode.
+#[derive(Debug,PartialEq,Eq,Hash)]
+pub struct DescriptorSnapshot {
+    device: DeviceDescriptor,
+    configs: Vec<ConfigDescriptor>,
+}
+
+impl DescriptorSnapshot {
+    /// Creates a snapshot from a device descriptor and all of the device's configuration
+    /// descriptors.
+    pub fn new(device: DeviceDescriptor, configs: Vec<ConfigDescriptor>) -> DescriptorSnapshot {
+        DescriptorSnapshot { device, configs }
+    }
+
+    /// Returns the device descriptor.
+    pub fn device_descriptor(&self) -> &DeviceDescriptor {
+        &self.device
+    }
+
+    /// Returns the configuration descriptors.
+    pub fn config_descriptors(&self) -> &[ConfigDescriptor] {
+        &self.configs
+    }
+
+    /// Compares this snapshot with one taken earlier.
+    pub fn changes_since(&self, previous: &DescriptorSnapshot) -> DescriptorChanges {
+        DescriptorChanges { changes: diff::diff(previous, self) }
+    }
+}
+
+/// The differences between two descriptor snapshots.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct DescriptorChanges {
+    changes: Vec<FieldChange>,
+}
+
+impl DescriptorChanges {
+    /// Returns `true` if the descriptor sets are identical.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Returns the individual field changes.
+    pub fn changes(&self) -> &[FieldChange] {
+        &self.changes
+    }
+
+    /// Indicates if the device's identity or the layout of its configurations changed.
+    ///
+    /// Changes to string indices, version numbers and power attributes are ignored. Any other
+    /// change, e.g., a different product ID or an added, removed or modified interface or
+    /// endpoint, means that drivers bound to the old descriptor set have to probe again.
+    pub fn layout_changed(&self) -> bool {
+        self.changes.iter().any(|change| {
+            let name = change.path.rsplit('.').next().unwrap_or("");
+            !COSMETIC_FIELDS.contains(&name)
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::DescriptorSnapshot;
+    use parse::{parse_config_descriptor, parse_device_descriptor};
+
+    const DEVICE: [u8; 18] = [
+        0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40,
+        0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0x01, 0x02, 0x00, 0x01,
+    ];
+
+    const CONFIG: [u8; 25] = [
+        0x09, 0x02, 0x19, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32,
+        0x09, 0x04, 0x00, 0x00, 0x01, 0xFF, 0x00, 0x00, 0x00,
+        0x07, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00,
+    ];
+
+    fn snapshot(device: &[u8], config: &[u8]) -> DescriptorSnapshot {
+        DescriptorSnapshot::new(parse_device_descriptor(device).unwrap(),
+                                vec![parse_config_descriptor(config).unwrap()])
+    }
+
+    #[test]
+    fn it_reports_no_changes_for_identical_snapshots() {
+        let changes = snapshot(&DEVICE, &CONFIG).changes_since(&snapshot(&DEVICE, &CONFIG));
+
+        assert!(changes.is_empty());
+        assert!(!changes.layout_changed());
+    }
+
+    #[test]
+    fn it_ignores_cosmetic_changes_for_layout() {
+        let mut device = DEVICE;
+        device[12] = 0x02;
+        let mut config = CONFIG;
+        config[8] = 0xFA;
+
+        let changes = snapshot(&device, &config).changes_since(&snapshot(&DEVICE, &CONFIG));
+
+        assert_eq!(2, changes.changes().len());
+        assert!(!changes.layout_changed());
+    }
+
+    #[test]
+    fn it_detects_product_change() {
+        let mut device = DEVICE;
+        device[10] = 0x79;
+
+        assert!(snapshot(&device, &CONFIG).changes_since(&snapshot(&DEVICE, &CONFIG)).layout_changed());
+    }
+
+    #[test]
+    fn it_detects_interface_change() {
+        let mut config = CONFIG;
+        config[14] = 0xFE;
+
+        let changes = snapshot(&DEVICE, &config).changes_since(&snapshot(&DEVICE, &CONFIG));
+
+        assert_eq!("config[1].interface[0].setting[0].bInterfaceClass", changes.changes()[0].path);
+        assert!(changes.layout_changed());
+    }
+}
@@ -0,0 +1,162 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task;
+
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::Direction;
+use control;
+use language::{self, Language};
+use transfer::TransferFuture;
+
+/// Future that resolves to a string descriptor read from a device.
+///
+/// Returned by [`DeviceHandle::read_string_descriptor_async`](struct.DeviceHandle.html#method.read_string_descriptor_async)
+/// and the `*_string` methods of the descriptor types. If no language was given, the future
+/// first reads the device's language table and uses the first language in it.
+pub struct StringDescriptorFuture<'a> {
+    handle: &'a DeviceHandle,
+    index: u8,
+    state: State,
+}
+
+enum State {
+    Failed(Error),
+    ReadingLanguages(TransferFuture),
+    ReadingString(TransferFuture),
+    Done,
+}
+
+impl<'a> StringDescriptorFuture<'a> {
+    fn read_string(handle: &DeviceHandle, language: Language, index: u8) -> State {
+        match submit_get_string(handle, index, language.lang_id()) {
+            Ok(future) => State::ReadingString(future),
+            Err(e) => State::Failed(e),
+        }
+    }
+}
+
+impl<'a> Future for StringDescriptorFuture<'a> {
+    type Output = ::Result<String>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            let state = match this.state {
+                State::Failed(ref e) => {
+                    let e = e.clone();
+                    this.state = State::Done;
+                    return task::Poll::Ready(Err(e));
+                },
+                State::ReadingLanguages(ref mut future) => {
+                    match Pin::new(future).poll(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(result) => {
+                            let languages = result.and_then(|transfer| {
+                                transfer.check_status()?;
+                                Ok(parse_languages(transfer.get_control_data()))
+                            });
+
+                            match languages {
+                                Ok(ref languages) if languages.is_empty() => State::Failed(Error::NotFound),
+                                Ok(languages) => Self::read_string(this.handle, languages[0], this.index),
+                                Err(e) => State::Failed(e),
+                            }
+                        }
+                    }
+                },
+                State::ReadingString(ref mut future) => {
+                    match Pin::new(future).poll(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(result) => {
+                            this.state = State::Done;
+                            return task::Poll::Ready(result.and_then(|transfer| {
+                                transfer.check_status()?;
+                                parse_string(transfer.get_control_data())
+                            }));
+                        }
+                    }
+                },
+                State::Done => return task::Poll::Ready(Err(Error::PolledAfterCompletion)),
+            };
+
+            this.state = state;
+        }
+    }
+}
+
+fn submit_get_string(handle: &DeviceHandle, index: u8, lang_id: u16) -> ::Result<TransferFuture> {
+    let mut transfer = handle.alloc_transfer(0)?;
+
+    let request = control::get_string(index, lang_id);
+    transfer.fill_control_read(request.request_type(Direction::In), request.request, request.value, request.index,
+                               255)?;
+
+    Ok(transfer.submit())
+}
+
+#[doc(hidden)]
+pub fn read_string<'a>(handle: &'a DeviceHandle, language: Option<Language>, index: Option<u8>) -> StringDescriptorFuture<'a> {
+    let index = match index {
+        Some(index) => index,
+        None => return StringDescriptorFuture { handle, index: 0, state: State::Failed(Error::InvalidParam) },
+    };
+
+    let state = match language {
+        Some(language) => StringDescriptorFuture::read_string(handle, language, index),
+        None => match submit_get_string(handle, 0, 0) {
+            Ok(future) => State::ReadingLanguages(future),
+            Err(e) => State::Failed(e),
+        },
+    };
+
+    StringDescriptorFuture { handle, index, state }
+}
+
+/// Parses the language table in string descriptor zero.
+#[doc(hidden)]
+pub fn parse_languages(descriptor: &[u8]) -> Vec<Language> {
+    descriptor.chunks(2).skip(1).filter(|chunk| chunk.len() == 2).map(|chunk| {
+        let lang_id = chunk[0] as u16 | (chunk[1] as u16) << 8;
+        language::from_lang_id(lang_id)
+    }).collect()
+}
+
+/// Decodes the UTF-16LE contents of a string descriptor.
+#[doc(hidden)]
+pub fn parse_string(descriptor: &[u8]) -> ::Result<String> {
+    let utf16: Vec<u16> = descriptor.chunks(2).skip(1).filter(|chunk| chunk.len() == 2).map(|chunk| {
+        chunk[0] as u16 | (chunk[1] as u16) << 8
+    }).collect();
+
+    String::from_utf16(&utf16[..]).map_err(|_| Error::Parse)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{parse_languages, parse_string};
+
+    #[test]
+    fn it_parses_language_table() {
+        let languages = parse_languages(&[0x06, 0x03, 0x09, 0x04, 0x07, 0x04]);
+
+        assert_eq!(vec![0x0409, 0x0407], languages.iter().map(|l| l.lang_id()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn it_parses_string() {
+        assert_eq!("USB", parse_string(&[0x08, 0x03, 0x55, 0x00, 0x53, 0x00, 0x42, 0x00]).unwrap());
+    }
+
+    #[test]
+    fn it_ignores_odd_trailing_byte() {
+        assert_eq!("U", parse_string(&[0x05, 0x03, 0x55, 0x00, 0x53]).unwrap());
+    }
+
+    #[test]
+    fn it_rejects_invalid_utf16() {
+        assert!(parse_string(&[0x04, 0x03, 0x00, 0xD8]).is_err());
+    }
+}
@@ -162,3 +162,195 @@ macro_rules! device_descriptor {
         )
     }
 }
+
+
+use std::sync::atomic::AtomicI32;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use libc::{c_int, c_void};
+use libusb::*;
+
+use backend::{ContextRef, DeviceEvent, DeviceRef, HandleRef, HotplugCallback, HotplugRef, TransferRef, UsbBackend};
+use config_descriptor::ConfigDescriptor;
+use device_descriptor::DeviceDescriptor;
+use parse::{parse_config_descriptor, parse_device_descriptor};
+
+/// What a `MockBackend` was asked to do to a device.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Call {
+    Open,
+    Close,
+    Claim(u8),
+    Release(u8),
+    Detach(u8),
+    Attach(u8),
+}
+
+/// A backend of devices made of descriptors, which records what is done to them.
+///
+/// Each device is opened at most once, its handle being known by the same number, which is also
+/// its address. Devices arrive when hotplug events are registered, and leave when disconnected.
+pub struct MockBackend {
+    devices: Vec<(Vec<u8>, Vec<u8>)>,
+    // The interfaces bound to a kernel driver, and those that can't be claimed
+    pub kernel_drivers: Vec<u8>,
+    pub failing_claims: Vec<u8>,
+    calls: Mutex<Vec<Call>>,
+    references: Mutex<isize>,
+    hotplug: Mutex<Option<HotplugCallback>>,
+}
+
+impl MockBackend {
+    pub fn new(devices: Vec<(Vec<u8>, Vec<u8>)>) -> MockBackend {
+        MockBackend {
+            devices,
+            kernel_drivers: Vec::new(),
+            failing_claims: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            references: Mutex::new(0),
+            hotplug: Mutex::new(None),
+        }
+    }
+
+    /// Lives as long as the test, as contexts hold their backend.
+    pub fn leak(self) -> &'static MockBackend {
+        Box::leak(Box::new(self))
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// The references to devices that haven't been released.
+    pub fn references(&self) -> isize {
+        *self.references.lock().unwrap()
+    }
+
+    /// Reports that a device, by its index, has left to the registered hotplug callback.
+    pub fn disconnect(&self, index: usize) {
+        if let Some(ref callback) = *self.hotplug.lock().unwrap() {
+            callback(DeviceRef::from_ptr((index + 1) as *mut c_void), DeviceEvent::Left);
+        }
+    }
+
+    fn call(&self, call: Call) -> c_int {
+        self.calls.lock().unwrap().push(call);
+        0
+    }
+
+    fn device(&self, device: DeviceRef) -> &(Vec<u8>, Vec<u8>) {
+        &self.devices[device.as_ptr() as usize - 1]
+    }
+}
+
+/// The bytes of a device descriptor with a vendor and product ID.
+pub fn device_bytes(vendor_id: u16, product_id: u16) -> Vec<u8> {
+    let [vendor_low, vendor_high] = vendor_id.to_le_bytes();
+    let [product_low, product_high] = product_id.to_le_bytes();
+    vec![18, 0x01, 0x00, 0x02, 0, 0, 0, 64, vendor_low, vendor_high, product_low, product_high, 0x00, 0x01, 0, 0, 0, 1]
+}
+
+/// The bytes of a configuration descriptor with interfaces of a class, each with a single
+/// alternate setting without endpoints.
+pub fn config_bytes(classes: &[u8]) -> Vec<u8> {
+    let length = 9 + 9 * classes.len();
+    let mut bytes = vec![9, 0x02, length as u8, (length >> 8) as u8, classes.len() as u8, 1, 0, 0x80, 50];
+    for (number, &class) in classes.iter().enumerate() {
+        bytes.extend_from_slice(&[9, 0x04, number as u8, 0, 0, class, 0, 0, 0]);
+    }
+    bytes
+}
+
+impl UsbBackend for MockBackend {
+    unsafe fn get_device_list(&self, _context: ContextRef) -> Result<Vec<DeviceRef>, c_int> {
+        *self.references.lock().unwrap() += self.devices.len() as isize;
+        Ok((1..=self.devices.len()).map(|device| DeviceRef::from_ptr(device as *mut c_void)).collect())
+    }
+
+    unsafe fn ref_device(&self, _device: DeviceRef) {
+        *self.references.lock().unwrap() += 1;
+    }
+
+    unsafe fn unref_device(&self, _device: DeviceRef) {
+        *self.references.lock().unwrap() -= 1;
+    }
+
+    unsafe fn device_descriptor(&self, device: DeviceRef) -> Result<DeviceDescriptor, c_int> {
+        parse_device_descriptor(&self.device(device).0).map_err(|_| LIBUSB_ERROR_OTHER)
+    }
+
+    unsafe fn active_config_descriptor(&self, device: DeviceRef) -> Result<ConfigDescriptor, c_int> {
+        parse_config_descriptor(&self.device(device).1).map_err(|_| LIBUSB_ERROR_OTHER)
+    }
+
+    unsafe fn device_address(&self, device: DeviceRef) -> u8 {
+        device.as_ptr() as u8
+    }
+
+    unsafe fn open(&self, device: DeviceRef) -> Result<HandleRef, c_int> {
+        self.call(Call::Open);
+        Ok(HandleRef::from_ptr(device.as_ptr()))
+    }
+
+    unsafe fn close(&self, _handle: HandleRef) {
+        self.call(Call::Close);
+    }
+
+    unsafe fn get_device(&self, handle: HandleRef) -> DeviceRef {
+        DeviceRef::from_ptr(handle.as_ptr())
+    }
+
+    unsafe fn claim_interface(&self, _handle: HandleRef, iface: u8) -> c_int {
+        if self.failing_claims.contains(&iface) {
+            return LIBUSB_ERROR_BUSY;
+        }
+        self.call(Call::Claim(iface))
+    }
+
+    unsafe fn release_interface(&self, _handle: HandleRef, iface: u8) -> c_int {
+        self.call(Call::Release(iface))
+    }
+
+    unsafe fn kernel_driver_active(&self, _handle: HandleRef, iface: u8) -> Result<bool, c_int> {
+        Ok(self.kernel_drivers.contains(&iface))
+    }
+
+    unsafe fn detach_kernel_driver(&self, _handle: HandleRef, iface: u8) -> c_int {
+        self.call(Call::Detach(iface))
+    }
+
+    unsafe fn attach_kernel_driver(&self, _handle: HandleRef, iface: u8) -> c_int {
+        self.call(Call::Attach(iface))
+    }
+
+    unsafe fn submit_transfer(&self, _transfer: TransferRef) -> c_int {
+        LIBUSB_ERROR_NOT_SUPPORTED
+    }
+
+    unsafe fn cancel_transfer(&self, _transfer: TransferRef) -> c_int {
+        LIBUSB_ERROR_NOT_FOUND
+    }
+
+    unsafe fn handle_events(&self, _context: ContextRef, timeout: Duration, _completed: Option<&AtomicI32>) -> c_int {
+        thread::sleep(timeout.min(Duration::from_millis(1)));
+        0
+    }
+
+    fn has_hotplug(&self) -> bool {
+        true
+    }
+
+    unsafe fn register_hotplug(&self, _context: ContextRef, callback: HotplugCallback) -> Result<HotplugRef, c_int> {
+        for device in 1..=self.devices.len() {
+            callback(DeviceRef::from_ptr(device as *mut c_void), DeviceEvent::Arrived);
+        }
+        *self.hotplug.lock().unwrap() = Some(callback);
+        Ok(HotplugRef::from_ptr(self as *const MockBackend as *mut c_void))
+    }
+
+    unsafe fn deregister_hotplug(&self, _context: ContextRef, _hotplug: HotplugRef) {
+        *self.hotplug.lock().unwrap() = None;
+    }
+}
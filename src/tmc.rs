@@ -0,0 +1,690 @@
+//! USB Test and Measurement Class (USBTMC) support.
+//!
+//! Instruments exchange device dependent messages, usually SCPI commands and responses, over
+//! a pair of bulk endpoints. Each message is preceded by a bulk header carrying a tag that the
+//! abort requests refer to.
+
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::task;
+use std::time::Duration;
+
+use config_descriptor::ConfigDescriptor;
+use control::{self, ControlFuture};
+use delay::Delay;
+use device::Device;
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::{ClassCode, Direction, Recipient, RequestType, TransferType, Version, request_type};
+use transfer::TransferFuture;
+
+const SUBCLASS_TMC: u8 = 0x03;
+
+const DEV_DEP_MSG_OUT: u8 = 0x01;
+const REQUEST_DEV_DEP_MSG_IN: u8 = 0x02;
+const DEV_DEP_MSG_IN: u8 = 0x02;
+
+const INITIATE_ABORT_BULK_OUT: u8 = 0x01;
+const CHECK_ABORT_BULK_OUT_STATUS: u8 = 0x02;
+const INITIATE_ABORT_BULK_IN: u8 = 0x03;
+const CHECK_ABORT_BULK_IN_STATUS: u8 = 0x04;
+const INITIATE_CLEAR: u8 = 0x05;
+const CHECK_CLEAR_STATUS: u8 = 0x06;
+const GET_CAPABILITIES: u8 = 0x07;
+const INDICATOR_PULSE: u8 = 0x40;
+
+/// Length of the bulk header that precedes each message.
+const HEADER_LENGTH: usize = 12;
+
+/// Largest number of bytes requested from the instrument in one transfer.
+const READ_SIZE: u32 = 4096;
+
+/// Time between the CHECK requests while the instrument reports that an abort is pending.
+const CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Finds the first USBTMC interface of a configuration.
+///
+/// Both plain USBTMC interfaces and those following the USB488 subclass are found.
+pub fn find_tmc_interface(config: &ConfigDescriptor) -> Option<u8> {
+    config.interfaces_of_class(ClassCode::ApplicationSpecific).into_iter()
+        .find(|setting| setting.sub_class_code() == SUBCLASS_TMC)
+        .map(|setting| setting.interface_number())
+}
+
+/// Encodes a DEV_DEP_MSG_OUT transfer: the bulk header followed by the message, padded to a
+/// multiple of four bytes.
+///
+/// `end_of_message` is set on the last transfer of a message.
+pub fn encode_dev_dep_msg_out(tag: u8, data: &[u8], end_of_message: bool) -> Vec<u8> {
+    let mut bytes = header(DEV_DEP_MSG_OUT, tag, data.len() as u32);
+    bytes[8] = end_of_message as u8;
+
+    bytes.extend_from_slice(data);
+    bytes.resize((bytes.len() + 3) & !3, 0);
+    bytes
+}
+
+/// Encodes a REQUEST_DEV_DEP_MSG_IN transfer, which asks the instrument to send up to
+/// `length` bytes of its response.
+///
+/// If `term_char` is given, the instrument ends the transfer after sending that character.
+pub fn encode_request_dev_dep_msg_in(tag: u8, length: u32, term_char: Option<u8>) -> Vec<u8> {
+    let mut bytes = header(REQUEST_DEV_DEP_MSG_IN, tag, length);
+
+    if let Some(term_char) = term_char {
+        bytes[8] = 0x02;
+        bytes[9] = term_char;
+    }
+
+    bytes
+}
+
+fn header(msg_id: u8, tag: u8, transfer_size: u32) -> Vec<u8> {
+    let mut bytes = vec![0; HEADER_LENGTH];
+    bytes[0] = msg_id;
+    bytes[1] = tag;
+    bytes[2] = !tag;
+    bytes[4..8].copy_from_slice(&transfer_size.to_le_bytes());
+    bytes
+}
+
+/// The bulk header of a DEV_DEP_MSG_IN transfer.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct DevDepMsgIn {
+    /// Tag of the REQUEST_DEV_DEP_MSG_IN the transfer answers.
+    pub tag: u8,
+
+    /// Number of message bytes following the header.
+    pub transfer_size: u32,
+
+    /// Indicates if the transfer ends the message.
+    pub end_of_message: bool,
+
+    /// Indicates if the transfer ends with the requested term character.
+    pub term_char: bool,
+}
+
+impl DevDepMsgIn {
+    /// Decodes the bulk header at the start of a transfer from the instrument.
+    ///
+    /// Returns `Error::InvalidParam` if the transfer is shorter than the header, isn't a
+    /// DEV_DEP_MSG_IN or has an inconsistent tag.
+    pub fn decode(bytes: &[u8]) -> ::Result<DevDepMsgIn> {
+        if bytes.len() < HEADER_LENGTH || bytes[0] != DEV_DEP_MSG_IN || bytes[2] != !bytes[1] {
+            return Err(Error::InvalidParam);
+        }
+
+        Ok(DevDepMsgIn {
+            tag: bytes[1],
+            transfer_size: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            end_of_message: bytes[8] & 0x01 != 0,
+            term_char: bytes[8] & 0x02 != 0,
+        })
+    }
+}
+
+/// Status returned by the USBTMC class requests.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum TmcStatus {
+    Success,
+    Pending,
+    Failed,
+    TransferNotInProgress,
+    SplitNotInProgress,
+    SplitInProgress,
+    Unknown(u8),
+}
+
+impl From<u8> for TmcStatus {
+    fn from(status: u8) -> TmcStatus {
+        match status {
+            0x01 => TmcStatus::Success,
+            0x02 => TmcStatus::Pending,
+            0x80 => TmcStatus::Failed,
+            0x81 => TmcStatus::TransferNotInProgress,
+            0x82 => TmcStatus::SplitNotInProgress,
+            0x83 => TmcStatus::SplitInProgress,
+            n => TmcStatus::Unknown(n),
+        }
+    }
+}
+
+/// The capabilities of a USBTMC interface, as returned by GET_CAPABILITIES.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct Capabilities {
+    /// The USBTMC specification release the interface complies with.
+    pub version: Version,
+
+    /// Indicates if the interface accepts INDICATOR_PULSE.
+    pub indicator_pulse: bool,
+
+    /// Indicates if the interface only sends messages.
+    pub talk_only: bool,
+
+    /// Indicates if the interface only receives messages.
+    pub listen_only: bool,
+
+    /// Indicates if the instrument can end a transfer on a term character.
+    pub term_char: bool,
+}
+
+impl Capabilities {
+    /// Decodes a GET_CAPABILITIES response.
+    pub fn decode(data: &[u8]) -> ::Result<Capabilities> {
+        if data.len() < 6 || TmcStatus::from(data[0]) != TmcStatus::Success {
+            return Err(Error::InvalidParam);
+        }
+
+        Ok(Capabilities {
+            version: Version::from_bcd(u16::from_le_bytes([data[2], data[3]])),
+            indicator_pulse: data[4] & 0x04 != 0,
+            talk_only: data[4] & 0x02 != 0,
+            listen_only: data[4] & 0x01 != 0,
+            term_char: data[5] & 0x01 != 0,
+        })
+    }
+}
+
+/// A claimed USBTMC interface.
+///
+/// [`write`](#method.write) sends a message and [`read`](#method.read) reads the response, so
+/// a SCPI query is a write of e.g. `*IDN?\n` followed by a read.
+pub struct Instrument {
+    handle: DeviceHandle,
+    interface: u8,
+    bulk_in: (u8, usize),
+    bulk_out: u8,
+    tag: AtomicU8,
+    kernel_driver_detached: bool,
+}
+
+impl Instrument {
+    /// Opens a device and claims its USBTMC interface.
+    ///
+    /// A kernel driver bound to the interface, e.g., `usbtmc`, is detached and reattached when
+    /// the instrument is dropped.
+    ///
+    /// ## Errors
+    ///
+    /// * `NotFound` if the active configuration has no such interface, or it lacks a bulk IN
+    ///   or bulk OUT endpoint.
+    /// * Any error returned while opening the device or claiming the interface.
+    pub fn open(device: &Device, interface: u8) -> ::Result<Instrument> {
+        let (bulk_in, bulk_out) = {
+            let config = device.active_config_descriptor()?;
+            let setting = config.interfaces()
+                .find(|i| i.number() == interface)
+                .and_then(|i| i.descriptors().next())
+                .ok_or(Error::NotFound)?;
+
+            let bulk_in = setting.first_endpoint(TransferType::Bulk, Direction::In).ok_or(Error::NotFound)?;
+            let bulk_out = setting.first_endpoint(TransferType::Bulk, Direction::Out).ok_or(Error::NotFound)?;
+
+            ((bulk_in.address(), bulk_in.max_packet_size() as usize), bulk_out.address())
+        };
+
+        let mut handle = device.open()?;
+
+        let kernel_driver_detached = handle.kernel_driver_active(interface).unwrap_or(false);
+        if kernel_driver_detached {
+            handle.detach_kernel_driver(interface)?;
+        }
+
+        handle.claim_interface(interface)?;
+
+        Ok(Instrument { handle, interface, bulk_in, bulk_out, tag: AtomicU8::new(0), kernel_driver_detached })
+    }
+
+    /// Returns the handle of the device.
+    pub fn handle(&self) -> &DeviceHandle {
+        &self.handle
+    }
+
+    /// Returns the number of the claimed interface.
+    pub fn interface_number(&self) -> u8 {
+        self.interface
+    }
+
+    /// Returns the tag of the most recent bulk transfer, which the abort requests take.
+    pub fn last_tag(&self) -> u8 {
+        self.tag.load(Ordering::Relaxed)
+    }
+
+    /// Reads the capabilities of the interface.
+    pub fn capabilities(&self) -> ControlFuture<Capabilities> {
+        control::read(&self.handle,
+                      request_type(Direction::In, RequestType::Class, Recipient::Interface),
+                      GET_CAPABILITIES,
+                      0,
+                      self.interface as u16,
+                      0x18,
+                      Capabilities::decode)
+    }
+
+    /// Makes the instrument blink its activity indicator, to tell it apart from others.
+    ///
+    /// The future resolves to `Err(Error::Io)` if the instrument refuses the request.
+    pub fn indicator_pulse(&self) -> ControlFuture<()> {
+        control::read(&self.handle,
+                      request_type(Direction::In, RequestType::Class, Recipient::Interface),
+                      INDICATOR_PULSE,
+                      0,
+                      self.interface as u16,
+                      1,
+                      check_success)
+    }
+
+    /// Sends a message to the instrument in a single transfer.
+    pub fn write(&self, message: &[u8]) -> WriteFuture {
+        let tag = self.next_tag();
+
+        WriteFuture { future: Some(self.bulk_write(&encode_dev_dep_msg_out(tag, message, true))), length: message.len() }
+    }
+
+    /// Reads a message from the instrument.
+    ///
+    /// The message is requested in parts until the instrument marks the end of the message, or
+    /// sends `term_char` if one is given. The future resolves to `Err(Error::Io)` if the
+    /// instrument answers with an unexpected tag.
+    pub fn read<'a>(&'a self, term_char: Option<u8>) -> ReadFuture<'a> {
+        let mut future = ReadFuture { instrument: self, term_char, tag: 0, message: Vec::new(), state: ReadState::Done };
+        future.state = future.request();
+        future
+    }
+
+    /// Aborts the bulk OUT transfer with the given tag and clears the halt on the endpoint.
+    ///
+    /// The future resolves to `Ok(())` also if no transfer with that tag is in progress.
+    pub fn abort_bulk_out<'a>(&'a self, tag: u8) -> AbortFuture<'a> {
+        let future = control::read(&self.handle,
+                                   request_type(Direction::In, RequestType::Class, Recipient::Endpoint),
+                                   INITIATE_ABORT_BULK_OUT,
+                                   tag as u16,
+                                   self.bulk_out as u16,
+                                   2,
+                                   control::to_vec);
+
+        AbortFuture { instrument: self, kind: Abort::BulkOut, state: AbortState::Initiate(future) }
+    }
+
+    /// Aborts the bulk IN transfer with the given tag, discarding the data the instrument has
+    /// queued.
+    ///
+    /// The future resolves to `Ok(())` also if no transfer with that tag is in progress.
+    pub fn abort_bulk_in<'a>(&'a self, tag: u8) -> AbortFuture<'a> {
+        let future = control::read(&self.handle,
+                                   request_type(Direction::In, RequestType::Class, Recipient::Endpoint),
+                                   INITIATE_ABORT_BULK_IN,
+                                   tag as u16,
+                                   self.bulk_in.0 as u16,
+                                   2,
+                                   control::to_vec);
+
+        AbortFuture { instrument: self, kind: Abort::BulkIn, state: AbortState::Initiate(future) }
+    }
+
+    /// Clears the input and output buffers of the instrument and the halt on the bulk OUT
+    /// endpoint.
+    pub fn clear<'a>(&'a self) -> AbortFuture<'a> {
+        let future = control::read(&self.handle,
+                                   request_type(Direction::In, RequestType::Class, Recipient::Interface),
+                                   INITIATE_CLEAR,
+                                   0,
+                                   self.interface as u16,
+                                   1,
+                                   control::to_vec);
+
+        AbortFuture { instrument: self, kind: Abort::Clear, state: AbortState::Initiate(future) }
+    }
+
+    /// Returns the next tag, which is never zero.
+    fn next_tag(&self) -> u8 {
+        let mut tag = self.tag.load(Ordering::Relaxed);
+
+        loop {
+            let next = tag.checked_add(1).unwrap_or(1);
+            match self.tag.compare_exchange_weak(tag, next, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return next,
+                Err(current) => tag = current,
+            }
+        }
+    }
+
+    fn bulk_read(&self, length: usize) -> ::Result<TransferFuture> {
+        let mut transfer = self.handle.alloc_transfer(0)?;
+        transfer.fill_bulk_read(self.bulk_in.0.into(), length)?;
+        Ok(transfer.submit())
+    }
+
+    fn bulk_write(&self, data: &[u8]) -> ::Result<TransferFuture> {
+        let mut transfer = self.handle.alloc_transfer(0)?;
+        transfer.fill_bulk_write(self.bulk_out.into(), data)?;
+        Ok(transfer.submit())
+    }
+
+    fn check_status(&self, kind: Abort) -> ControlFuture<Vec<u8>> {
+        let (recipient, request, index, length) = match kind {
+            Abort::BulkOut => (Recipient::Endpoint, CHECK_ABORT_BULK_OUT_STATUS, self.bulk_out as u16, 8),
+            Abort::BulkIn => (Recipient::Endpoint, CHECK_ABORT_BULK_IN_STATUS, self.bulk_in.0 as u16, 8),
+            Abort::Clear => (Recipient::Interface, CHECK_CLEAR_STATUS, self.interface as u16, 2),
+        };
+
+        control::read(&self.handle,
+                      request_type(Direction::In, RequestType::Class, recipient),
+                      request,
+                      0,
+                      index,
+                      length,
+                      control::to_vec)
+    }
+}
+
+impl Drop for Instrument {
+    fn drop(&mut self) {
+        if self.kernel_driver_detached {
+            let _ = self.handle.release_interface(self.interface);
+            let _ = self.handle.attach_kernel_driver(self.interface);
+        }
+    }
+}
+
+fn check_success(data: &[u8]) -> ::Result<()> {
+    match data.first().map(|&status| TmcStatus::from(status)) {
+        Some(TmcStatus::Success) => Ok(()),
+        _ => Err(Error::Io),
+    }
+}
+
+/// Future returned by [`Instrument::write`](struct.Instrument.html#method.write), which
+/// resolves to the length of the message.
+pub struct WriteFuture {
+    future: Option<::Result<TransferFuture>>,
+    length: usize,
+}
+
+impl Future for WriteFuture {
+    type Output = ::Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Self::Output> {
+        let this = self.get_mut();
+
+        let result = match this.future {
+            Some(Ok(ref mut future)) => match Pin::new(future).poll(cx) {
+                task::Poll::Pending => return task::Poll::Pending,
+                task::Poll::Ready(result) => result,
+            },
+            Some(Err(_)) => match this.future.take() {
+                Some(Err(e)) => return task::Poll::Ready(Err(e)),
+                _ => unreachable!(),
+            },
+            None => return task::Poll::Ready(Err(Error::PolledAfterCompletion)),
+        };
+
+        this.future = None;
+        task::Poll::Ready(result.and_then(|transfer| transfer.check_status()).map(|_| this.length))
+    }
+}
+
+/// Future returned by [`Instrument::read`](struct.Instrument.html#method.read), which resolves
+/// to the message.
+pub struct ReadFuture<'a> {
+    instrument: &'a Instrument,
+    term_char: Option<u8>,
+    tag: u8,
+    message: Vec<u8>,
+    state: ReadState,
+}
+
+enum ReadState {
+    Failed(Error),
+    Request(TransferFuture),
+    Response(TransferFuture),
+    Done,
+}
+
+impl<'a> ReadFuture<'a> {
+    /// Sends the request for the next part of the message.
+    fn request(&mut self) -> ReadState {
+        self.tag = self.instrument.next_tag();
+
+        match self.instrument.bulk_write(&encode_request_dev_dep_msg_in(self.tag, READ_SIZE, self.term_char)) {
+            Ok(future) => ReadState::Request(future),
+            Err(e) => ReadState::Failed(e),
+        }
+    }
+
+    fn finish(&mut self, result: ::Result<Vec<u8>>) -> task::Poll<::Result<Vec<u8>>> {
+        self.state = ReadState::Done;
+        task::Poll::Ready(result)
+    }
+}
+
+impl<'a> Future for ReadFuture<'a> {
+    type Output = ::Result<Vec<u8>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            let next = match this.state {
+                ReadState::Failed(_) => match mem::replace(&mut this.state, ReadState::Done) {
+                    ReadState::Failed(e) => return task::Poll::Ready(Err(e)),
+                    _ => unreachable!(),
+                },
+                ReadState::Request(ref mut future) => match Pin::new(future).poll(cx) {
+                    task::Poll::Pending => return task::Poll::Pending,
+                    task::Poll::Ready(result) => {
+                        if let Err(e) = result.and_then(|transfer| transfer.check_status()) {
+                            return this.finish(Err(e));
+                        }
+
+                        // Room for the header and the padding after the message
+                        match this.instrument.bulk_read(HEADER_LENGTH + READ_SIZE as usize + 3) {
+                            Ok(future) => ReadState::Response(future),
+                            Err(e) => return this.finish(Err(e)),
+                        }
+                    },
+                },
+                ReadState::Response(ref mut future) => {
+                    let transfer = match Pin::new(future).poll(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(result) => result.and_then(|transfer| transfer.check_status().map(|_| transfer)),
+                    };
+
+                    let transfer = match transfer {
+                        Ok(transfer) => transfer,
+                        Err(e) => return this.finish(Err(e)),
+                    };
+
+                    let bytes = transfer.get_buffer();
+                    let header = match DevDepMsgIn::decode(bytes) {
+                        Ok(ref header) if header.tag != this.tag => return this.finish(Err(Error::Io)),
+                        Ok(header) => header,
+                        Err(e) => return this.finish(Err(e)),
+                    };
+
+                    let end = bytes.len().min(HEADER_LENGTH + header.transfer_size as usize);
+                    this.message.extend_from_slice(&bytes[HEADER_LENGTH..end]);
+
+                    if header.end_of_message || header.term_char {
+                        let message = mem::take(&mut this.message);
+                        return this.finish(Ok(message));
+                    }
+
+                    this.request()
+                },
+                ReadState::Done => return task::Poll::Ready(Err(Error::PolledAfterCompletion)),
+            };
+
+            this.state = next;
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy)]
+enum Abort {
+    BulkOut,
+    BulkIn,
+    Clear,
+}
+
+/// Future returned by [`Instrument::abort_bulk_out`](struct.Instrument.html#method.abort_bulk_out),
+/// [`Instrument::abort_bulk_in`](struct.Instrument.html#method.abort_bulk_in) and
+/// [`Instrument::clear`](struct.Instrument.html#method.clear).
+///
+/// The INITIATE request is followed by CHECK requests until the instrument no longer reports
+/// the operation as pending, reading any data the instrument still has queued in between.
+pub struct AbortFuture<'a> {
+    instrument: &'a Instrument,
+    kind: Abort,
+    state: AbortState,
+}
+
+enum AbortState {
+    Initiate(ControlFuture<Vec<u8>>),
+    Check(ControlFuture<Vec<u8>>),
+    Wait(Delay),
+    Drain(TransferFuture),
+    Done,
+}
+
+impl<'a> AbortFuture<'a> {
+    fn finish(&mut self, result: ::Result<()>) -> task::Poll<::Result<()>> {
+        self.state = AbortState::Done;
+        task::Poll::Ready(result)
+    }
+
+    fn drain(&mut self) -> ::Result<AbortState> {
+        Ok(AbortState::Drain(self.instrument.bulk_read(self.instrument.bulk_in.1)?))
+    }
+}
+
+impl<'a> Future for AbortFuture<'a> {
+    type Output = ::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            let next = match this.state {
+                AbortState::Initiate(ref mut future) => {
+                    let data = match Pin::new(future).poll(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(Ok(data)) => data,
+                        task::Poll::Ready(Err(e)) => return this.finish(Err(e)),
+                    };
+
+                    match data.first().map(|&status| TmcStatus::from(status)) {
+                        Some(TmcStatus::Success) => match this.kind {
+                            // The instrument may still have part of the transfer queued
+                            Abort::BulkIn => match this.drain() {
+                                Ok(state) => state,
+                                Err(e) => return this.finish(Err(e)),
+                            },
+                            _ => AbortState::Check(this.instrument.check_status(this.kind)),
+                        },
+                        Some(TmcStatus::Failed) | Some(TmcStatus::TransferNotInProgress) => return this.finish(Ok(())),
+                        _ => return this.finish(Err(Error::Io)),
+                    }
+                },
+                AbortState::Check(ref mut future) => {
+                    let data = match Pin::new(future).poll(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(Ok(data)) => data,
+                        task::Poll::Ready(Err(e)) => return this.finish(Err(e)),
+                    };
+
+                    // Bit 0 of the second byte tells that the bulk IN FIFO has data to discard
+                    let queued = data.get(1).is_some_and(|&bits| bits & 0x01 != 0);
+
+                    match data.first().map(|&status| TmcStatus::from(status)) {
+                        Some(TmcStatus::Pending) if queued => match this.drain() {
+                            Ok(state) => state,
+                            Err(e) => return this.finish(Err(e)),
+                        },
+                        Some(TmcStatus::Pending) => AbortState::Wait(Delay::new(CHECK_INTERVAL)),
+                        Some(TmcStatus::Success) => {
+                            let result = match this.kind {
+                                Abort::BulkOut | Abort::Clear => this.instrument.handle.clear_halt(this.instrument.bulk_out.into()),
+                                Abort::BulkIn => Ok(()),
+                            };
+
+                            return this.finish(result);
+                        },
+                        _ => return this.finish(Err(Error::Io)),
+                    }
+                },
+                AbortState::Wait(ref mut delay) => match Pin::new(delay).poll(cx) {
+                    task::Poll::Pending => return task::Poll::Pending,
+                    task::Poll::Ready(()) => AbortState::Check(this.instrument.check_status(this.kind)),
+                },
+                AbortState::Drain(ref mut future) => {
+                    let length = match Pin::new(future).poll(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(result) => result.and_then(|transfer| {
+                            transfer.check_status()?;
+                            Ok(transfer.get_buffer().len())
+                        }),
+                    };
+
+                    match length {
+                        // Read until a short packet ends the queued data
+                        Ok(length) if length == this.instrument.bulk_in.1 => match this.drain() {
+                            Ok(state) => state,
+                            Err(e) => return this.finish(Err(e)),
+                        },
+                        Ok(_) => AbortState::Check(this.instrument.check_status(this.kind)),
+                        Err(e) => return this.finish(Err(e)),
+                    }
+                },
+                AbortState::Done => return task::Poll::Ready(Err(Error::PolledAfterCompletion)),
+            };
+
+            this.state = next;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_encodes_dev_dep_msg_out() {
+        let bytes = encode_dev_dep_msg_out(1, b"*IDN?\n", true);
+
+        assert_eq!(vec![0x01, 0x01, 0xFE, 0x00, 0x06, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+                        b'*', b'I', b'D', b'N', b'?', b'\n', 0x00, 0x00], bytes);
+    }
+
+    #[test]
+    fn it_encodes_request_dev_dep_msg_in() {
+        assert_eq!(vec![0x02, 0x05, 0xFA, 0x00, 0x00, 0x10, 0x00, 0x00, 0x02, b'\n', 0x00, 0x00],
+                   encode_request_dev_dep_msg_in(5, 4096, Some(b'\n')));
+        assert_eq!(0x00, encode_request_dev_dep_msg_in(5, 4096, None)[8]);
+    }
+
+    #[test]
+    fn it_decodes_dev_dep_msg_in() {
+        let header = DevDepMsgIn::decode(&[0x02, 0x05, 0xFA, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, b'1', b'2', b'\n']).unwrap();
+
+        assert_eq!(DevDepMsgIn { tag: 5, transfer_size: 3, end_of_message: true, term_char: false }, header);
+        assert!(DevDepMsgIn::decode(&[0x02, 0x05, 0x05, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]).is_err());
+        assert!(DevDepMsgIn::decode(&[0x02, 0x05, 0xFA]).is_err());
+    }
+
+    #[test]
+    fn it_decodes_capabilities() {
+        let capabilities = Capabilities::decode(&[0x01, 0x00, 0x00, 0x01, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                                                  0x00, 0x01, 0x07, 0x0F, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]).unwrap();
+
+        assert_eq!(Version::from_bcd(0x0100), capabilities.version);
+        assert!(capabilities.indicator_pulse);
+        assert!(!capabilities.talk_only);
+        assert!(capabilities.term_char);
+        assert!(Capabilities::decode(&[0x80, 0x00, 0x00, 0x01, 0x00, 0x00]).is_err());
+    }
+}
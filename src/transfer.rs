@@ -1,22 +1,50 @@
-use std::sync::{Arc,Weak,Mutex};
+use std::sync::{Arc,Weak,Mutex,Condvar,PoisonError};
+use std::sync::atomic::{AtomicBool,AtomicU8,Ordering};
+#[cfg(feature = "timing")]
+use std::sync::atomic::AtomicU64;
+use std::cell::UnsafeCell;
+use std::panic::{self,AssertUnwindSafe};
+use backend::{HandleRef, TransferRef, UsbBackend};
 use context::ContextAsync;
+use diagnostics::LiveTransfer;
 use device_handle::DeviceHandleAsync;
+use disconnect::Disconnect;
+use metrics::Metrics;
+use observer::TransferInfo;
+use buffer::AlignedBuffer;
+use capture::CaptureEvent;
+use config_descriptor::ConfigDescriptor;
+use fields::{Direction, EndpointAddress, TransferType};
 use error;
-use error::Error;
+use error::{Error, Operation};
 use std::future::{Future};
 use std::task;
 use std::pin::Pin;
-use std::ops::DerefMut;
+use std::mem;
+use std::ptr;
 use libusb::{
     self,
     libusb_transfer,
-    libusb_free_transfer,
-    libusb_submit_transfer,
-    libusb_cancel_transfer
+    libusb_iso_packet_descriptor,
+    libusb_alloc_transfer,
+    libusb_free_transfer
 };
-use libc::{c_uchar, c_int};
+use libc::{c_uchar, c_int, c_uint};
+use std::slice;
 use std::convert::TryFrom;
 use std::fmt;
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::io;
+use std::time::{Duration, Instant};
+
+// Bulk streams came with libusb 1.0.19, after the libusb-sys bindings
+extern "C" {
+    fn libusb_transfer_set_stream_id(transfer: *mut libusb_transfer, stream_id: u32);
+}
+
+/// Size of the setup packet at the start of a control transfer buffer
+const CONTROL_SETUP_SIZE: usize = 8;
 
 /// The result of a finished transfer request sent by
 /// [`Transfer::submit`](struct.Transfer.html#method.submit)
@@ -67,238 +95,2124 @@ impl From<c_int> for TransferStatus
     }
 }
 
+impl TransferStatus
+{
+    /// Converts the status of a finished transfer into a `Result`.
+    ///
+    /// `Completed` maps to `Ok(())`, all other statuses to the corresponding `Error`, and
+    /// `Unknown` to `Error::TransferFailed`.
+    pub fn to_result(&self) -> ::Result<()>
+    {
+        match *self {
+            TransferStatus::Completed => Ok(()),
+            TransferStatus::Error => Err(Error::Io),
+            TransferStatus::TimedOut => Err(Error::Timeout),
+            TransferStatus::Cancelled => Err(Error::Interrupted),
+            TransferStatus::Stall => Err(Error::Pipe),
+            TransferStatus::NoDevice => Err(Error::NoDevice),
+            TransferStatus::Overflow => Err(Error::Overflow),
+            TransferStatus::Unknown => Err(Error::TransferFailed(TransferStatus::Unknown))
+        }
+    }
+}
+
+/// What a completion hook wants done with its transfer, see
+/// [`Transfer::set_completion_hook`](struct.Transfer.html#method.set_completion_hook)
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum HookAction
+{
+    /// Submit the transfer again as it is, without waking its future
+    Resubmit,
+    /// Complete the transfer and wake its future
+    Complete
+}
+
+/// The submitted function that runs on the event thread
+type HookFn = dyn FnMut(TransferStatus, &[u8]) -> HookAction + Send;
+
+/// Why a transfer couldn't be prepared by one of the `fill_*` methods of
+/// [`Transfer`](struct.Transfer.html)
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum FillError
+{
+    /// The data doesn't fit in the transfer, e.g., more than 65535 bytes of
+    /// control data
+    PayloadTooLarge,
+    /// The direction bit of the endpoint address doesn't match the transfer,
+    /// e.g., a read from an OUT endpoint
+    WrongDirection,
+    /// An interrupt transfer to an endpoint that the descriptors of a claimed
+    /// interface declare with another type
+    NotAnInterruptEndpoint,
+    /// More isochronous packets than allocated for the transfer
+    TooManyPackets,
+    /// The endpoint with this address isn't in any claimed interface
+    UnknownEndpoint(u8),
+    /// The endpoint with this address is declared with this other type by the
+    /// descriptors of its claimed interface
+    WrongTransferType(u8, TransferType),
+    /// The `bmRequestType` of a control transfer has a reserved type or
+    /// recipient
+    InvalidRequestType(u8)
+}
+
+impl fmt::Display for FillError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FillError::PayloadTooLarge => f.write_str("Payload too large for the transfer"),
+            FillError::WrongDirection => f.write_str("Wrong endpoint direction for the transfer"),
+            FillError::NotAnInterruptEndpoint => f.write_str("Not an interrupt endpoint"),
+            FillError::TooManyPackets => f.write_str("More packets than allocated for the transfer"),
+            FillError::UnknownEndpoint(endpoint) =>
+                write!(f, "Endpoint 0x{:02x} is not in a claimed interface", endpoint),
+            FillError::WrongTransferType(endpoint, transfer_type) =>
+                write!(f, "Endpoint 0x{:02x} is a {:?} endpoint", endpoint, transfer_type),
+            FillError::InvalidRequestType(request_type) =>
+                write!(f, "Request type 0x{:02x} has a reserved type or recipient", request_type)
+        }
+    }
+}
+
+impl StdError for FillError {}
+
+impl From<FillError> for Error
+{
+    fn from(err: FillError) -> Error
+    {
+        Error::Fill(err)
+    }
+}
+
+impl From<FillError> for io::Error
+{
+    fn from(err: FillError) -> io::Error
+    {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+/// Checks the `bmRequestType` of a control transfer against the direction of
+/// its data stage
+fn check_request_type(request_type: u8, direction: Direction) -> Result<(), FillError>
+{
+    // The type 3 and the recipients from 4 are reserved
+    if request_type & 0x60 == 0x60 || request_type & 0x1F > 3 {
+        return Err(FillError::InvalidRequestType(request_type));
+    }
+    let in_ = request_type & libusb::LIBUSB_ENDPOINT_DIR_MASK == libusb::LIBUSB_ENDPOINT_IN;
+    if in_ != (direction == Direction::In) {
+        return Err(FillError::WrongDirection);
+    }
+    Ok(())
+}
+
+/// Checks that a buffer length fits in a libusb transfer
+fn check_length(length: usize) -> Result<(), FillError>
+{
+    c_int::try_from(length).map(|_| ()).map_err(|_| FillError::PayloadTooLarge)
+}
+
+/// The endpoints of the claimed interfaces of a device handle, shared with
+/// its transfers to check them when they're filled
+#[derive(Default)]
+pub struct ClaimedEndpoints
+{
+    // Interface number to the addresses and types of the endpoints of its
+    // active alternate setting
+    interfaces: Mutex<HashMap<u8, Vec<(u8, TransferType)>>>
+}
+
+impl ClaimedEndpoints
+{
+    /// Sets the endpoints of an interface to those of one of its alternate
+    /// settings, replacing the endpoints of the previous setting
+    pub fn claim(&self, config: &ConfigDescriptor, interface: u8, setting: u8)
+    {
+        let endpoints = config.interfaces()
+            .filter(|i| i.number() == interface)
+            .flat_map(|i| i.descriptors())
+            .filter(|d| d.setting_number() == setting)
+            .flat_map(|d| d.endpoint_descriptors())
+            .map(|endpoint| (endpoint.address(), endpoint.transfer_type()))
+            .collect();
+        self.interfaces.lock().unwrap().insert(interface, endpoints);
+    }
+
+    pub fn release(&self, interface: u8)
+    {
+        self.interfaces.lock().unwrap().remove(&interface);
+    }
+
+    /// Forgets the endpoints of all interfaces, e.g., when the configuration
+    /// they belong to is no longer active
+    pub fn clear(&self)
+    {
+        self.interfaces.lock().unwrap().clear();
+    }
+
+    /// Checks that an endpoint is in a claimed interface, with the type of
+    /// the transfer
+    ///
+    /// Nothing is checked until the descriptors of an interface are known.
+    fn check(&self, endpoint: u8, transfer_type: TransferType)
+             -> Result<(), FillError>
+    {
+        let interfaces = self.interfaces.lock().unwrap();
+        if interfaces.is_empty() {
+            return Ok(());
+        }
+
+        let found = interfaces.values().flatten()
+            .find(|&&(address, _)| address == endpoint);
+        match found {
+            None => Err(FillError::UnknownEndpoint(endpoint)),
+            Some(&(_, t)) if t == transfer_type => Ok(()),
+            Some(_) if transfer_type == TransferType::Interrupt =>
+                Err(FillError::NotAnInterruptEndpoint),
+            Some(&(_, t)) => Err(FillError::WrongTransferType(endpoint, t))
+        }
+    }
+}
+
+/// The parts of a device handle that its transfers share
+///
+/// They're kept apart from the handle's mutex, as the transfer callbacks on
+/// the event thread use them and must not wait for the handle.
+#[derive(Clone)]
+pub struct HandleShared
+{
+    /// Marked when a transfer ends with LIBUSB_TRANSFER_NO_DEVICE
+    pub disconnect: Arc<Disconnect>,
+    /// The submitted transfers of the device handle
+    pub in_flight: Arc<InFlight>,
+    /// The endpoints of the claimed interfaces of the device handle
+    pub endpoints: Arc<ClaimedEndpoints>,
+    /// Transfer metrics of the device handle, recorded while enabled
+    pub metrics: Arc<Metrics>
+}
+
+impl HandleShared
+{
+    pub fn new() -> HandleShared
+    {
+        HandleShared {
+            disconnect: Arc::new(Disconnect::new()),
+            in_flight: Arc::new(InFlight::new()),
+            endpoints: Arc::new(ClaimedEndpoints::default()),
+            metrics: Arc::new(Metrics::new())
+        }
+    }
+}
+
 /// A request to transfer data to or from a device.
 ///
 /// An instance of this struct is obtained by calling
 /// [DeviceHandle::alloc_transfer](struct.DeviceHandle.html#method.alloc_transfer)
+///
+/// Transfers and their futures are `Send` and `Sync`, so they can be
+/// submitted and polled on the threads of multi-threaded executors.
 pub struct Transfer {
-    // Avoids having the context dropped while this transfer is active
-    _context: Arc<ContextAsync>,
+    // Counts the transfer in its context, and avoids having the context
+    // dropped while this transfer is active
+    live: LiveTransfer,
     _device: Weak<Mutex<DeviceHandleAsync>>,
-    buffer: Vec<u8>,
-    transfer: *mut libusb_transfer,
-    waker: Mutex<Option<task::Waker>>
+    shared: HandleShared,
+    buffer: AlignedBuffer,
+    transfer: RawTransfer,
+    iso_packets: u32,
+    // When the transfer was submitted, if metrics are being recorded or
+    // transfers are timed
+    submitted_at: Option<Instant>,
+    // Whether the submission was counted in the metrics of the handle
+    metered: bool,
+    // Run by the callback before completing the transfer. Only locked by
+    // the callback while the transfer is submitted.
+    hook: Mutex<Option<Box<HookFn>>>,
+    // What the transfer was submitted through, to resubmit and cancel it
+    // the same way
+    backend: &'static dyn UsbBackend,
+    // Shared with the callback, which wakes the future after releasing its
+    // reference to the transfer
+    notify: Arc<Notify>
+}
+
+// States of `Notify`
+/// Submitted, without a waker
+const PENDING: u8 = 0;
+/// The future is storing its waker
+const REGISTERING: u8 = 1;
+/// Submitted, with a waker
+const WAKER_STORED: u8 = 2;
+/// The callback is taking the waker
+const WAKING: u8 = 3;
+/// The callback has released its reference to the transfer
+const COMPLETED: u8 = 4;
+
+/// How the callback tells the future that the transfer is done
+///
+/// An atomic state machine, so the callback on the event thread never waits
+/// for the future. Only the side that moved the state away from `PENDING` or
+/// `WAKER_STORED` touches the waker, until it moves the state on:
+///
+/// * The future goes through `REGISTERING` to `WAKER_STORED`. If the callback
+///   completes the transfer meanwhile, the future sees it completed instead.
+/// * The callback goes through `WAKING` to `COMPLETED` if there is a waker to
+///   take, and straight to `COMPLETED` otherwise. A future polled while the
+///   waker is being taken asks to be polled again.
+struct Notify
+{
+    state: AtomicU8,
+    waker: UnsafeCell<Option<task::Waker>>,
+    // Set if the callback panicked, which is reported as the result of the
+    // future instead of unwinding into libusb
+    panicked: AtomicBool,
+    // Set when the future is dropped, so a completion hook stops
+    // resubmitting the transfer
+    stopping: AtomicBool,
+    // Nanoseconds from submission to the callback
+    #[cfg(feature = "timing")]
+    completed_after: AtomicU64
+}
+
+impl Notify
+{
+    fn new() -> Notify
+    {
+        Notify{state: AtomicU8::new(PENDING), waker: UnsafeCell::new(None),
+               panicked: AtomicBool::new(false),
+               stopping: AtomicBool::new(false),
+               #[cfg(feature = "timing")]
+               completed_after: AtomicU64::new(0)}
+    }
+
+    /// Prepares for a submission, while nothing else refers to the transfer
+    fn reset(&self)
+    {
+        self.state.store(PENDING, Ordering::Release);
+        self.panicked.store(false, Ordering::Release);
+        self.stopping.store(false, Ordering::Release);
+    }
+
+    /// Stores the waker of the future, unless the transfer has completed.
+    /// Returns true if it has.
+    fn poll_completed(&self, waker: &task::Waker) -> bool
+    {
+        loop {
+            let state = self.state.load(Ordering::Acquire);
+            match state {
+                COMPLETED => return true,
+                WAKING => {
+                    // The waker being taken may be stale
+                    waker.wake_by_ref();
+                    return false;
+                },
+                _ => {}
+            }
+            if self.state.compare_exchange(state, REGISTERING, Ordering::Acquire,
+                                           Ordering::Acquire).is_err() {
+                continue;
+            }
+
+            // The callback doesn't touch the waker while registering
+            let stored = unsafe{&mut *self.waker.get()};
+            if !stored.as_ref().is_some_and(|w| w.will_wake(waker)) {
+                *stored = Some(waker.clone());
+            }
+
+            // Fails only if the callback has completed the transfer
+            return self.state.compare_exchange(REGISTERING, WAKER_STORED, Ordering::AcqRel,
+                                               Ordering::Acquire).is_err();
+        }
+    }
+
+    /// Marks the transfer completed and wakes the future. Only the first call
+    /// after a submission has an effect.
+    fn complete(&self)
+    {
+        loop {
+            match self.state.load(Ordering::Acquire) {
+                COMPLETED | WAKING => return,
+                WAKER_STORED => {
+                    if self.state.compare_exchange(WAKER_STORED, WAKING, Ordering::Acquire,
+                                                   Ordering::Acquire).is_err() {
+                        continue;
+                    }
+                    // The future doesn't touch the waker while waking
+                    let waker = unsafe{(*self.waker.get()).take()};
+                    self.state.store(COMPLETED, Ordering::Release);
+                    if let Some(w) = waker {
+                        w.wake();
+                    }
+                    return;
+                },
+                state => {
+                    if self.state.compare_exchange(state, COMPLETED, Ordering::AcqRel,
+                                                   Ordering::Acquire).is_ok() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
 }
 
-unsafe impl Send for Transfer {}
-unsafe impl Sync for Transfer {}
+// The waker is only accessed by the side that holds the state, see above
+unsafe impl Sync for Notify {}
+
+/// The libusb transfer owned by a `Transfer`
+///
+/// Together with `Notify`, this is the only part of a transfer that isn't
+/// thread-safe on its own, which makes `Transfer` and `TransferFuture` `Send`
+/// and `Sync` by construction.
+struct RawTransfer(*mut libusb_transfer);
+
+// The libusb transfer is only accessed through its `Transfer`, whose methods
+// that modify it take `&mut self`. While it is submitted, the `Transfer` is
+// shared with the callback, and neither side modifies it until the callback
+// has released its reference. libusb itself may complete or cancel transfers
+// from any thread.
+unsafe impl Send for RawTransfer {}
+unsafe impl Sync for RawTransfer {}
 
 impl Drop for Transfer
 {
     fn drop(&mut self)
     {
+        // Not submitted, as the callback holds a reference while it is
         unsafe {
-            libusb_free_transfer(self.transfer);
+            self.live.context().transfers.give(self.transfer.0, self.iso_packets);
         }
         //println!("Dropped");
     }
 }
 
-extern "C" fn asyn_callback(libusb_transfer: *mut libusb_transfer)
+/// The most freed transfers a freelist keeps for each number of isochronous
+/// packets
+const FREELIST_DEPTH: usize = 32;
+
+/// Freed libusb transfers of a context, kept for reuse by the number of
+/// isochronous packets they were allocated with
+///
+/// Together with the buffer pool, this keeps streaming transfers from
+/// allocating and freeing on every cycle.
+pub struct TransferFreelist
 {
-    {
-        let waker = {
-            let transfer = unsafe {
-                Arc::<Transfer>::from_raw((*libusb_transfer).user_data  
-                                          as *const Transfer)};
-            let w = transfer.waker.lock().unwrap().take();
-            w
-        };
-        // The reference count is decreased at this point.
-        // This signals that the transfer is done. 
-        if let Some(w) = waker {
-            w.wake();
-        }
-    }
-    
-    //println!("Callback done");
+    free: Mutex<HashMap<u32, Vec<usize>>>
 }
 
-impl Transfer {
-    /// Prepare a control transfer that writes data to the device
-    pub fn fill_control_write(&mut self, request_type: u8, request: u8, 
-                              value: u16, index: u16, buf: &[u8])
+impl TransferFreelist
+{
+    pub fn new() -> TransferFreelist
     {
-        
-        let buffer = & mut self.buffer;
-        buffer.clear();
-        buffer.push(request_type);
-        buffer.push(request);
-        buffer.extend_from_slice(&value.to_le_bytes());
-        buffer.extend_from_slice(&index.to_le_bytes());
-        buffer.extend_from_slice(
-            &u16::try_from(buf.len()).unwrap().to_le_bytes());
-        buffer.extend_from_slice(buf);
-        
-        let transfer = unsafe{&mut *self.transfer};
-        transfer.flags = 0;
-        transfer.endpoint = 0;
-        transfer.transfer_type = libusb::LIBUSB_TRANSFER_TYPE_CONTROL;
-        transfer.timeout = 0;
-        transfer.length = self.buffer.len() as c_int;
-        transfer.buffer = self.buffer.as_mut_ptr() as *mut c_uchar;
-        transfer.num_iso_packets = 0;
+        TransferFreelist{free: Mutex::new(HashMap::new())}
     }
 
-    /// Prepare a control transfer that reads data from the device
-    pub fn fill_control_read(&mut self, request_type: u8, request: u8, 
-                             value: u16, index: u16, length: u16)
+    /// Returns the number of transfers kept for reuse
+    pub fn len(&self) -> usize
     {
-        
-        let buffer = & mut self.buffer;
-        buffer.clear();
-        buffer.push(request_type);
-        buffer.push(request);
-        buffer.extend_from_slice(&value.to_le_bytes());
-        buffer.extend_from_slice(&index.to_le_bytes());
-        buffer.extend_from_slice(&length.to_le_bytes());
-        buffer.resize(usize::from(length) + 8, 0);
-        
-        let transfer = unsafe{&mut *self.transfer};
-        transfer.flags = 0;
-        transfer.endpoint = 0;
-        transfer.transfer_type = libusb::LIBUSB_TRANSFER_TYPE_CONTROL;
-        transfer.timeout = 0;
-        transfer.length = self.buffer.len() as c_int;
-        transfer.buffer = self.buffer.as_mut_ptr() as *mut c_uchar;
-        transfer.num_iso_packets = 0;
+        self.free.lock().unwrap().values().map(Vec::len).sum()
     }
 
-    /// Prepare a read (IN) transfer from an interrupt endpoint
-    pub fn fill_interrupt_read(&mut self, endpoint: u8, length: u16)
+    /// Returns a transfer that is reused, or allocated if there is none, in
+    /// the state `libusb_alloc_transfer` leaves it
+    pub fn take(&self, iso_packets: u32) -> *mut libusb_transfer
     {
-        let buffer = & mut self.buffer;
-        buffer.clear();
-        buffer.resize(usize::from(length), 0);
-        
-        let transfer = unsafe{&mut *self.transfer};
-        transfer.flags = 0;
-        transfer.endpoint = endpoint;
-        transfer.transfer_type = libusb::LIBUSB_TRANSFER_TYPE_INTERRUPT;
-        transfer.timeout = 0;
-        transfer.length = self.buffer.len() as c_int;
-        transfer.buffer = self.buffer.as_mut_ptr() as *mut c_uchar;
-        transfer.num_iso_packets = 0;
-    }
+        let reused = self.free.lock().unwrap()
+            .get_mut(&iso_packets)
+            .and_then(Vec::pop);
 
+        match reused {
+            Some(address) => {
+                let transfer = address as *mut libusb_transfer;
+                unsafe {
+                    ptr::write_bytes(transfer, 0, 1);
+                    ptr::write_bytes((*transfer).iso_packet_desc.as_mut_ptr(), 0,
+                                     iso_packets as usize);
+                }
+                transfer
+            },
+            None => unsafe{libusb_alloc_transfer(iso_packets as c_int)}
+        }
+    }
 
-    /// Start a transfer request
+    /// Keeps a transfer that is no longer submitted for reuse, or frees it
     ///
-    /// The transfer must have been prepared by one of the `fill_*` methods.
-    pub fn submit(self) -> ::TransferFuture
+    /// The transfer must have been allocated with `iso_packets` packets.
+    pub unsafe fn give(&self, transfer: *mut libusb_transfer, iso_packets: u32)
     {
-        unsafe{(*self.transfer).callback = asyn_callback};
-        let tarc = Arc::new(self);
-        unsafe{(*tarc.transfer).user_data = Arc::into_raw(tarc.clone()) as *mut libc::c_void};
-        
-        let error = error::from_libusb(
-            unsafe{libusb_submit_transfer(tarc.transfer)});
-            
-        TransferFuture{transfer: Some(tarc), error}
+        let mut free = self.free.lock().unwrap_or_else(PoisonError::into_inner);
+        let free = free.entry(iso_packets).or_default();
+        if free.len() < FREELIST_DEPTH {
+            free.push(transfer as usize);
+        } else {
+            libusb_free_transfer(transfer);
+        }
     }
 
-    /// Get the status of a completed submit 
-    pub fn get_status(&self) -> TransferStatus
+    /// Frees the transfers kept for reuse
+    pub fn clear(&self)
     {
-        TransferStatus::from(unsafe{(*self.transfer).status})
+        let mut free = self.free.lock().unwrap_or_else(PoisonError::into_inner);
+        for &transfer in free.values().flatten() {
+            unsafe {
+                libusb_free_transfer(transfer as *mut libusb_transfer);
+            }
+        }
+        free.clear();
     }
+}
 
-    /// Get the buffer of a transfer
-    ///
-    /// Normally only used on a completed transfer to get response data.
-    pub fn get_buffer<'a>(&'a self) -> &'a [u8]
+impl Drop for TransferFreelist
+{
+    fn drop(&mut self)
     {
-        self.buffer.as_ref()
+        self.clear();
     }
+}
 
+/// The submitted transfers of a device handle
+///
+/// The handle cancels them and waits for their callbacks before it is closed,
+/// so libusb never completes a transfer of a closed handle.
+pub struct InFlight
+{
+    transfers: Mutex<Vec<usize>>,
+    drained: Condvar,
+    // Set once draining, so completion hooks stop resubmitting
+    closing: AtomicBool
 }
 
-impl PartialEq for Transfer
+impl InFlight
 {
-    fn eq(&self, other: &Self) -> bool
+    pub fn new() -> InFlight
+    {
+        InFlight{transfers: Mutex::new(Vec::new()), drained: Condvar::new(),
+                 closing: AtomicBool::new(false)}
+    }
+
+    fn is_closing(&self) -> bool
+    {
+        self.closing.load(Ordering::Acquire)
+    }
+
+    fn add(&self, transfer: *mut libusb_transfer)
+    {
+        self.transfers.lock().unwrap_or_else(PoisonError::into_inner)
+            .push(transfer as usize);
+    }
+
+    fn remove(&self, transfer: *mut libusb_transfer)
+    {
+        let mut transfers =
+            self.transfers.lock().unwrap_or_else(PoisonError::into_inner);
+        transfers.retain(|&t| t != transfer as usize);
+        if transfers.is_empty() {
+            self.drained.notify_all();
+        }
+    }
+
+    /// Cancels the submitted transfers, without waiting for their callbacks
+    pub fn cancel(&self, backend: &dyn UsbBackend)
+    {
+        self.closing.store(true, Ordering::Release);
+        let transfers =
+            self.transfers.lock().unwrap_or_else(PoisonError::into_inner);
+        // The callbacks remove their transfers while holding the lock, so
+        // the transfers are still allocated here
+        for &transfer in transfers.iter() {
+            unsafe {
+                backend.cancel_transfer(TransferRef::from_libusb(transfer as *mut libusb_transfer));
+            }
+        }
+    }
+
+    /// Whether the callbacks of all transfers have run
+    pub fn is_empty(&self) -> bool
+    {
+        self.transfers.lock().unwrap_or_else(PoisonError::into_inner).is_empty()
+    }
+
+    /// Cancels the submitted transfers and waits until their callbacks have
+    /// run
+    ///
+    /// The event loop must be running, on another thread, as it runs the
+    /// callbacks.
+    pub fn drain(&self, backend: &dyn UsbBackend)
     {
-        self.transfer == other.transfer
+        self.cancel(backend);
+        let mut transfers =
+            self.transfers.lock().unwrap_or_else(PoisonError::into_inner);
+        while !transfers.is_empty() {
+            transfers = self.drained.wait(transfers)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
     }
 }
 
-impl Eq for Transfer
+extern "C" fn asyn_callback(libusb_transfer: *mut libusb_transfer)
 {
+    let notify = unsafe {
+        (*((*libusb_transfer).user_data as *const Transfer)).notify.clone()
+    };
+
+    let observed = panic::catch_unwind(AssertUnwindSafe(|| {
+        observe_completion(libusb_transfer)
+    }));
+    if observed.is_err() {
+        notify.panicked.store(true, Ordering::Release);
+    }
+    // Unwinding into libusb is undefined behaviour, so a panic, e.g., from a
+    // waker, is caught and reported by the future
+    match panic::catch_unwind(AssertUnwindSafe(|| run_hook(libusb_transfer))) {
+        Ok(true) => return,
+        Ok(false) => {},
+        Err(_) => notify.panicked.store(true, Ordering::Release)
+    }
+    let released = panic::catch_unwind(AssertUnwindSafe(|| {
+        release_transfer(libusb_transfer)
+    }));
+    if released.is_err() {
+        notify.panicked.store(true, Ordering::Release);
+    }
+    // The reference of the callback has been released, also when unwinding,
+    // so the future can take the transfer once it sees it completed
+    let woken = panic::catch_unwind(AssertUnwindSafe(|| notify.complete()));
+    if woken.is_err() {
+        notify.panicked.store(true, Ordering::Release);
+    }
 }
 
-#[doc(hidden)]
-pub unsafe fn from_libusb(context: &Arc<ContextAsync>,
-                          device: &Arc<Mutex<DeviceHandleAsync>>,
-                          transfer: *mut libusb_transfer)
-                          -> Transfer
+/// Returns the bus number and address of the device of a transfer, or zeros for a transfer
+/// without a handle.
+pub fn device_location(backend: &dyn UsbBackend, transfer: &libusb_transfer) -> (u8, u8)
 {
-    Transfer {
-        _context: context.clone(),
-        _device: Arc::downgrade(device),
-        buffer: Vec::new(),
-        waker: Mutex::new(None),
-        transfer
+    if transfer.dev_handle.is_null() {
+        return (0, 0);
+    }
+    unsafe {
+        let device = backend.get_device(HandleRef::from_ptr(transfer.dev_handle as *mut libc::c_void));
+        (backend.bus_number(device), backend.device_address(device))
     }
 }
 
-/// Future that is ready when a transfer is finished.
-///
-/// The result of a successful transfer is a
-/// [`Transfer`](struct.Transfer.html) object.
-
-pub struct TransferFuture
+/// Tells the observers and the capture of the context how a transfer completed.
+fn observe_completion(libusb_transfer: *mut libusb_transfer)
 {
-    transfer: Option<Arc<Transfer>>,
-    error: Error
+    let transfer = unsafe {
+        &*((*libusb_transfer).user_data as *const Transfer)};
+    let usb_transfer = unsafe{&*libusb_transfer};
+    let length = usize::try_from(usb_transfer.actual_length).unwrap_or(0);
+    unsafe{transfer.live.context().capture.record(transfer.backend, CaptureEvent::Complete, usb_transfer)};
+    if let Some(info) = transfer.observed_info() {
+        transfer.live.context().observers.completed(
+            &info, TransferStatus::from(usb_transfer.status), length);
+    }
 }
 
-impl Drop for TransferFuture
+/// Runs the completion hook of a transfer, and resubmits the transfer if the
+/// hook asks for it. Returns true if the transfer has been resubmitted, and
+/// the callback still holds its reference.
+fn run_hook(libusb_transfer: *mut libusb_transfer) -> bool
 {
-    fn drop(&mut self) {
-        if self.transfer.is_some() {
-            // Cancel transfer if not completed and polled
-            unsafe {
-                libusb_cancel_transfer(self.transfer.as_ref().unwrap().transfer)
-            };
+    let transfer = unsafe {
+        &*((*libusb_transfer).user_data as *const Transfer)};
+    let mut hook = transfer.hook.lock().unwrap_or_else(PoisonError::into_inner);
+    let hook = match *hook {
+        Some(ref mut hook) => hook,
+        None => return false
+    };
+
+    let usb_transfer = unsafe{&mut *libusb_transfer};
+    let status = TransferStatus::from(usb_transfer.status);
+    let length = usize::try_from(usb_transfer.actual_length).unwrap_or(0)
+        .min(transfer.buffer.len());
+    let action = hook(status, &transfer.buffer[..length]);
+
+    let stopping = || transfer.notify.stopping.load(Ordering::Acquire) ||
+        transfer.shared.in_flight.is_closing();
+    if action != HookAction::Resubmit || status != TransferStatus::Completed || stopping() {
+        return false;
+    }
+
+    let observers = &transfer.live.context().observers;
+    let info = transfer.observed_info();
+    if let Some(ref info) = info {
+        observers.submitted(info);
+    }
+    unsafe{transfer.live.context().capture.record(transfer.backend, CaptureEvent::Submit, usb_transfer)};
+    let result = unsafe{transfer.backend.submit_transfer(TransferRef::from_libusb(libusb_transfer))};
+    if result != 0 {
+        // Completed with the reason the transfer couldn't go on
+        usb_transfer.status = if result == libusb::LIBUSB_ERROR_NO_DEVICE {
+            libusb::LIBUSB_TRANSFER_NO_DEVICE
+        } else {
+            libusb::LIBUSB_TRANSFER_ERROR
+        };
+        if let Some(ref info) = info {
+            observers.submit_failed(info, TransferStatus::from(usb_transfer.status));
         }
+        return false;
+    }
+    // The future may have been dropped, and its cancellation missed the
+    // transfer, while it was being resubmitted
+    if stopping() {
+        unsafe{transfer.backend.cancel_transfer(TransferRef::from_libusb(libusb_transfer))};
     }
+    true
 }
 
-impl Future for TransferFuture
+fn release_transfer(libusb_transfer: *mut libusb_transfer)
 {
-    type Output = Result<Transfer, Error>;
-    fn poll(self: Pin<&mut Self>, cx: &mut task::Context)
-            -> task::Poll<Self::Output>
-    {
-        match &self.error {
-            Error::Success => {}
-            e => return task::Poll::Ready(Err(e.clone()))
-        }
-        
-        if self.transfer.is_some() {
-            if Arc::strong_count(self.as_ref().transfer.as_ref().unwrap())==1 {
-                let transfer = self.get_mut().transfer.take().unwrap();
-                if let Ok(mut transfer) = Arc::try_unwrap(transfer) {
-                    let usb_transfer = unsafe{&mut *transfer.transfer};
-                    let mut buf_len = usb_transfer.actual_length;
-                    if usb_transfer.transfer_type 
-                        == libusb::LIBUSB_TRANSFER_TYPE_CONTROL {
-                            buf_len += 8;
-                        }
-                    transfer.buffer.resize(
-                        usize::try_from(buf_len).unwrap(),
-                        0);
-                    return task::Poll::Ready(Ok(transfer));
-                } else {
-                    panic!("Failed to unwrap Arc into Transfer");
-                }
-            }
-            let transfer = self.transfer.as_ref().unwrap();
-            let mut waker = transfer.waker.lock().unwrap();
-            *waker.deref_mut() = Some(cx.waker().clone());
-            task::Poll::Pending
-        } else {
-            panic!("Future contains no transfer");
+    let transfer = unsafe {
+        Arc::<Transfer>::from_raw((*libusb_transfer).user_data
+                                  as *const Transfer)};
+    transfer.shared.in_flight.remove(libusb_transfer);
+    transfer.live.completed();
+    let usb_transfer = unsafe{&*libusb_transfer};
+    if usb_transfer.status == libusb::LIBUSB_TRANSFER_NO_DEVICE {
+        transfer.shared.disconnect.mark();
+    }
+    if let Some(submitted_at) = transfer.submitted_at {
+        let latency = submitted_at.elapsed();
+        #[cfg(feature = "timing")]
+        transfer.notify.completed_after.store(
+            u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX), Ordering::Release);
+        if transfer.metered {
+            let length = usize::try_from(usb_transfer.actual_length).unwrap_or(0);
+            transfer.shared.metrics.completed(usb_transfer.endpoint, usb_transfer.status,
+                                              length, latency);
         }
     }
 }
 
+impl Transfer {
+    /// Prepare a control transfer that writes data to the device
+    ///
+    /// An empty `buf` gives a transfer without a data stage. Fails with `FillError::PayloadTooLarge` if `buf` is longer than the
+    /// 65535 bytes a control transfer can carry, with `FillError::WrongDirection` if `request_type` has the IN
+    /// direction bit, and with `FillError::InvalidRequestType` if it has a reserved type or recipient.
+    pub fn fill_control_write(&mut self, request_type: u8, request: u8, 
+                              value: u16, index: u16, buf: &[u8])
+                              -> Result<(), FillError>
+    {
+        check_request_type(request_type, Direction::Out)?;
+        let length = u16::try_from(buf.len())
+            .map_err(|_| FillError::PayloadTooLarge)?;
+
+        let buffer = & mut self.buffer;
+        buffer.clear();
+        buffer.push(request_type);
+        buffer.push(request);
+        buffer.extend_from_slice(&value.to_le_bytes());
+        buffer.extend_from_slice(&index.to_le_bytes());
+        buffer.extend_from_slice(&length.to_le_bytes());
+        buffer.extend_from_slice(buf);
+
+        self.fill(0, libusb::LIBUSB_TRANSFER_TYPE_CONTROL, 0);
+        Ok(())
+    }
+
+    /// Prepare a control transfer without a data stage, e.g., a
+    /// SET_CONFIGURATION or SET_FEATURE request
+    ///
+    /// Only the setup packet is sent, with a `wLength` of 0. Fails like
+    /// [`fill_control_write`](#method.fill_control_write) if `request_type`
+    /// has the IN direction bit or a reserved type or recipient; a request
+    /// with the IN direction and no data stage is filled with
+    /// [`fill_control_read`](#method.fill_control_read) and a `length` of 0.
+    pub fn fill_control_no_data(&mut self, request_type: u8, request: u8,
+                                value: u16, index: u16)
+                                -> Result<(), FillError>
+    {
+        check_request_type(request_type, Direction::Out)?;
+        let buffer = & mut self.buffer;
+        buffer.clear();
+        buffer.push(request_type);
+        buffer.push(request);
+        buffer.extend_from_slice(&value.to_le_bytes());
+        buffer.extend_from_slice(&index.to_le_bytes());
+        buffer.extend_from_slice(&0u16.to_le_bytes());
+
+        self.fill(0, libusb::LIBUSB_TRANSFER_TYPE_CONTROL, 0);
+        Ok(())
+    }
+
+    /// Prepare a control transfer that reads data from the device
+    ///
+    /// A `length` of 0 gives a transfer without a data stage. Fails with
+    /// `FillError::WrongDirection` if `request_type` lacks the IN direction
+    /// bit, and with `FillError::InvalidRequestType` if it has a reserved
+    /// type or recipient.
+    pub fn fill_control_read(&mut self, request_type: u8, request: u8, 
+                             value: u16, index: u16, length: u16)
+                             -> Result<(), FillError>
+    {
+        check_request_type(request_type, Direction::In)?;
+        let buffer = & mut self.buffer;
+        buffer.clear();
+        buffer.push(request_type);
+        buffer.push(request);
+        buffer.extend_from_slice(&value.to_le_bytes());
+        buffer.extend_from_slice(&index.to_le_bytes());
+        buffer.extend_from_slice(&length.to_le_bytes());
+        buffer.resize(usize::from(length) + CONTROL_SETUP_SIZE, 0);
+
+        self.fill(0, libusb::LIBUSB_TRANSFER_TYPE_CONTROL, 0);
+        Ok(())
+    }
+
+    /// Prepare a read (IN) transfer from an interrupt endpoint
+    pub fn fill_interrupt_read(&mut self, endpoint: EndpointAddress, length: u16)
+                               -> Result<(), FillError>
+    {
+        let endpoint = u8::from(endpoint);
+        self.check_endpoint(endpoint, Direction::In, TransferType::Interrupt)?;
+
+        let buffer = & mut self.buffer;
+        buffer.clear();
+        buffer.resize(usize::from(length), 0);
+
+        self.fill(endpoint, libusb::LIBUSB_TRANSFER_TYPE_INTERRUPT, 0);
+        Ok(())
+    }
+
+    /// Prepare a write (OUT) transfer to an interrupt endpoint
+    pub fn fill_interrupt_write(&mut self, endpoint: EndpointAddress, buf: &[u8])
+                                -> Result<(), FillError>
+    {
+        let endpoint = u8::from(endpoint);
+        self.check_endpoint(endpoint, Direction::Out, TransferType::Interrupt)?;
+        check_length(buf.len())?;
+
+        let buffer = & mut self.buffer;
+        buffer.clear();
+        buffer.extend_from_slice(buf);
+
+        self.fill(endpoint, libusb::LIBUSB_TRANSFER_TYPE_INTERRUPT, 0);
+        Ok(())
+    }
+
+    /// Prepare a read (IN) transfer from a bulk endpoint
+    pub fn fill_bulk_read(&mut self, endpoint: EndpointAddress, length: usize)
+                          -> Result<(), FillError>
+    {
+        let endpoint = u8::from(endpoint);
+        self.check_endpoint(endpoint, Direction::In, TransferType::Bulk)?;
+        check_length(length)?;
+
+        let buffer = & mut self.buffer;
+        buffer.clear();
+        buffer.resize(length, 0);
+
+        self.fill(endpoint, libusb::LIBUSB_TRANSFER_TYPE_BULK, 0);
+        Ok(())
+    }
+
+    /// Prepare a write (OUT) transfer to a bulk endpoint
+    pub fn fill_bulk_write(&mut self, endpoint: EndpointAddress, buf: &[u8])
+                           -> Result<(), FillError>
+    {
+        let endpoint = u8::from(endpoint);
+        self.check_endpoint(endpoint, Direction::Out, TransferType::Bulk)?;
+        check_length(buf.len())?;
+
+        let buffer = & mut self.buffer;
+        buffer.clear();
+        buffer.extend_from_slice(buf);
+
+        self.fill(endpoint, libusb::LIBUSB_TRANSFER_TYPE_BULK, 0);
+        Ok(())
+    }
+
+    /// Make a bulk OUT transfer end with a zero-length packet if its length
+    /// is a multiple of the endpoint's maximum packet size
+    ///
+    /// Must be called after the transfer has been prepared, as the `fill_*`
+    /// methods clear it. libusb only supports this on some platforms, Linux
+    /// among them, and submitting fails with `Error::NotSupported` elsewhere.
+    /// Submitting an empty write after the transfer does the same portably.
+    pub fn set_add_zero_packet(&mut self, add: bool)
+    {
+        let transfer = unsafe{&mut *self.transfer.0};
+        if add {
+            transfer.flags |= libusb::LIBUSB_TRANSFER_ADD_ZERO_PACKET;
+        } else {
+            transfer.flags &= !libusb::LIBUSB_TRANSFER_ADD_ZERO_PACKET;
+        }
+    }
+
+    /// Prepare a read (IN) transfer from a bulk stream
+    ///
+    /// The stream must have been allocated by
+    /// [`DeviceHandle::alloc_streams`](struct.DeviceHandle.html#method.alloc_streams).
+    pub fn fill_bulk_stream_read(&mut self, endpoint: EndpointAddress, stream_id: u32, length: usize)
+                                 -> Result<(), FillError>
+    {
+        self.fill_bulk_read(endpoint, length)?;
+
+        let transfer = unsafe{&mut *self.transfer.0};
+        transfer.transfer_type = libusb::LIBUSB_TRANSFER_TYPE_BULK_STREAM;
+        unsafe{libusb_transfer_set_stream_id(self.transfer.0, stream_id)};
+        Ok(())
+    }
+
+    /// Prepare a write (OUT) transfer to a bulk stream
+    ///
+    /// The stream must have been allocated by
+    /// [`DeviceHandle::alloc_streams`](struct.DeviceHandle.html#method.alloc_streams).
+    pub fn fill_bulk_stream_write(&mut self, endpoint: EndpointAddress, stream_id: u32, buf: &[u8])
+                                  -> Result<(), FillError>
+    {
+        self.fill_bulk_write(endpoint, buf)?;
+
+        let transfer = unsafe{&mut *self.transfer.0};
+        transfer.transfer_type = libusb::LIBUSB_TRANSFER_TYPE_BULK_STREAM;
+        unsafe{libusb_transfer_set_stream_id(self.transfer.0, stream_id)};
+        Ok(())
+    }
+
+    /// Prepare a read (IN) transfer from an isochronous endpoint
+    ///
+    /// All the packets allocated by
+    /// [`DeviceHandle::alloc_transfer`](struct.DeviceHandle.html#method.alloc_transfer) are
+    /// used, each with room for `packet_length` bytes.
+    pub fn fill_iso_read(&mut self, endpoint: EndpointAddress, packet_length: usize)
+                         -> Result<(), FillError>
+    {
+        let endpoint = u8::from(endpoint);
+        self.check_endpoint(endpoint, Direction::In, TransferType::Isochronous)?;
+        let length = (self.iso_packets as usize).checked_mul(packet_length)
+            .ok_or(FillError::PayloadTooLarge)?;
+        check_length(length)?;
+
+        let buffer = & mut self.buffer;
+        buffer.clear();
+        buffer.resize(length, 0);
+
+        self.fill(endpoint, libusb::LIBUSB_TRANSFER_TYPE_ISOCHRONOUS, self.iso_packets);
+
+        for packet in self.iso_packet_descriptors_mut() {
+            packet.length = packet_length as c_uint;
+        }
+        Ok(())
+    }
+
+    /// Prepare a write (OUT) transfer to an isochronous endpoint
+    ///
+    /// `buf` is split into one packet per entry of `packet_lengths`. Fails
+    /// with `FillError::TooManyPackets` if there are more entries than the
+    /// packets allocated by
+    /// [`DeviceHandle::alloc_transfer`](struct.DeviceHandle.html#method.alloc_transfer).
+    pub fn fill_iso_write(&mut self, endpoint: EndpointAddress, buf: &[u8], packet_lengths: &[usize])
+                          -> Result<(), FillError>
+    {
+        let endpoint = u8::from(endpoint);
+        self.check_endpoint(endpoint, Direction::Out, TransferType::Isochronous)?;
+        check_length(buf.len())?;
+        if packet_lengths.len() > self.iso_packets as usize {
+            return Err(FillError::TooManyPackets);
+        }
+
+        let buffer = & mut self.buffer;
+        buffer.clear();
+        buffer.extend_from_slice(buf);
+
+        self.fill(endpoint, libusb::LIBUSB_TRANSFER_TYPE_ISOCHRONOUS,
+                  packet_lengths.len() as u32);
+
+        for (packet, &length) in self.iso_packet_descriptors_mut().iter_mut().zip(packet_lengths) {
+            packet.length = length as c_uint;
+        }
+        Ok(())
+    }
+
+    /// Checks the direction of an endpoint, and that it's in a claimed
+    /// interface with the right type
+    fn check_endpoint(&self, endpoint: u8, direction: Direction,
+                      transfer_type: TransferType) -> Result<(), FillError>
+    {
+        let endpoint_direction = if endpoint & libusb::LIBUSB_ENDPOINT_DIR_MASK == libusb::LIBUSB_ENDPOINT_IN {
+            Direction::In
+        } else {
+            Direction::Out
+        };
+        if endpoint_direction != direction {
+            return Err(FillError::WrongDirection);
+        }
+
+        self.shared.endpoints.check(endpoint, transfer_type)
+    }
+
+    /// Describes the transfer if its context has observers, as finding
+    /// its device goes through the backend
+    fn observed_info(&self) -> Option<TransferInfo>
+    {
+        if self.live.context().observers.is_active() {
+            Some(self.info())
+        } else {
+            None
+        }
+    }
+
+    /// Describes the transfer to the observers of its context
+    fn info(&self) -> TransferInfo
+    {
+        let transfer = unsafe{&*self.transfer.0};
+        let (bus_number, address) = device_location(self.backend, transfer);
+        TransferInfo {
+            id: self.transfer.0 as usize,
+            bus_number,
+            address,
+            endpoint: transfer.endpoint,
+            length: usize::try_from(transfer.length).unwrap_or(0),
+        }
+    }
+
+    /// Points the libusb transfer at the buffer, after it has been filled
+    fn fill(&mut self, endpoint: u8, transfer_type: u8, num_iso_packets: u32)
+    {
+        let transfer = unsafe{&mut *self.transfer.0};
+        transfer.flags = 0;
+        transfer.endpoint = endpoint;
+        transfer.transfer_type = transfer_type;
+        transfer.timeout = 0;
+        transfer.length = self.buffer.len() as c_int;
+        transfer.buffer = self.buffer.as_mut_ptr() as *mut c_uchar;
+        transfer.num_iso_packets = num_iso_packets as c_int;
+    }
+
+    /// Start a transfer request
+    ///
+    /// The transfer must have been prepared by one of the `fill_*` methods.
+    /// If the device has been disconnected, the future resolves to
+    /// `Error::Disconnected` without the transfer being submitted.
+    pub fn submit(self) -> ::TransferFuture
+    {
+        let backend = self.live.context().backend;
+        self.submit_with(backend)
+    }
+
+    /// Run `hook` on the event thread each time the transfer completes,
+    /// before its future is woken
+    ///
+    /// The hook gets the status of the transfer and, for reads, the data
+    /// received. If it returns `HookAction::Resubmit` after a successful
+    /// transfer, the transfer is submitted again as it is and the future
+    /// isn't woken, so a stream can copy out its data and keep the endpoint
+    /// busy without a round trip through the executor. The future resolves
+    /// when the hook returns `HookAction::Complete`, when the transfer fails
+    /// or can't be resubmitted, or when it's cancelled by dropping the
+    /// future.
+    ///
+    /// The hook blocks the event thread, and with it all transfers of the
+    /// context, so it must be short, e.g., a copy into a lock-free buffer.
+    /// The metrics of the handle count a resubmitted transfer once.
+    pub fn set_completion_hook<F>(&mut self, hook: F)
+        where F: FnMut(TransferStatus, &[u8]) -> HookAction + Send + 'static
+    {
+        *self.hook.get_mut().unwrap_or_else(PoisonError::into_inner) = Some(Box::new(hook));
+    }
+
+    /// Remove the completion hook set by
+    /// [`set_completion_hook`](#method.set_completion_hook)
+    pub fn clear_completion_hook(&mut self)
+    {
+        *self.hook.get_mut().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+
+    /// Start a transfer request that is cancelled if it's not done within
+    /// `timeout`
+    ///
+    /// libusb cancels the transfer when the time is up, and the future
+    /// resolves once the cancellation has completed, with the status
+    /// `TransferStatus::TimedOut`. Unlike dropping the future from a timer,
+    /// the transfer is never left in flight after the future is done.
+    pub fn submit_with_timeout(mut self, timeout: Duration) -> ::TransferFuture
+    {
+        self.set_timeout(timeout);
+        self.submit()
+    }
+
+    /// Start a transfer request that is cancelled if it's not done by
+    /// `deadline`
+    ///
+    /// See [`submit_with_timeout`](#method.submit_with_timeout). A deadline
+    /// that has passed times out as soon as possible.
+    pub fn submit_with_deadline(self, deadline: Instant) -> ::TransferFuture
+    {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        self.submit_with_timeout(timeout)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration)
+    {
+        // Rounded up, since a timeout of 0 means none to libusb
+        let timeout_ms = timeout.as_nanos().div_ceil(1_000_000).max(1);
+        let transfer = unsafe{&mut *self.transfer.0};
+        transfer.timeout = c_uint::try_from(timeout_ms).unwrap_or(c_uint::MAX);
+    }
+
+    fn submit_with(mut self, backend: &'static dyn UsbBackend) -> ::TransferFuture
+    {
+        if self.shared.disconnect.is_disconnected() {
+            return TransferFuture{state: TransferState::Failed(Error::Disconnected)};
+        }
+
+        let transfer = self.transfer.0;
+        let endpoint = unsafe{(*transfer).endpoint};
+        unsafe{(*transfer).callback = asyn_callback};
+        self.notify.reset();
+        self.backend = backend;
+        self.metered = self.shared.metrics.is_enabled();
+        if self.metered {
+            self.shared.metrics.submitted(endpoint);
+        }
+        self.submitted_at = if self.metered || cfg!(feature = "timing") {
+            Some(Instant::now())
+        } else {
+            None
+        };
+        // Before submitting, as the transfer may complete before libusb_submit_transfer
+        // returns
+        let info = self.observed_info();
+        if let Some(ref info) = info {
+            self.live.context().observers.submitted(info);
+        }
+        unsafe{self.live.context().capture.record(self.backend, CaptureEvent::Submit, &*transfer)};
+        let tarc = Arc::new(self);
+
+        // The callback may run before libusb_submit_transfer returns, so its
+        // reference must be in place before submitting
+        let user_data = Arc::into_raw(tarc.clone());
+        unsafe{(*transfer).user_data = user_data as *mut libc::c_void};
+        tarc.shared.in_flight.add(transfer);
+        tarc.live.submitted();
+
+        let result = unsafe{backend.submit_transfer(TransferRef::from_libusb(transfer))};
+        let state = if result == 0 {
+            TransferState::Pending(tarc)
+        } else {
+            // The callback will never run, so its reference is released here
+            tarc.shared.in_flight.remove(transfer);
+            tarc.live.completed();
+            unsafe {
+                (*transfer).user_data = ptr::null_mut();
+                drop(Arc::from_raw(user_data));
+            }
+            if result == libusb::LIBUSB_ERROR_NO_DEVICE {
+                tarc.shared.disconnect.mark();
+            }
+            if tarc.metered {
+                tarc.shared.metrics.submit_failed(endpoint);
+            }
+            let status = if result == libusb::LIBUSB_ERROR_NO_DEVICE {
+                TransferStatus::NoDevice
+            } else {
+                TransferStatus::Error
+            };
+            if let Some(ref info) = info {
+                tarc.live.context().observers.submit_failed(info, status);
+            }
+            TransferState::Failed(error::from_operation(Operation::Submit(endpoint), result))
+        };
+
+        TransferFuture{state}
+    }
+
+    /// Get the alignment of the transfer's buffer
+    ///
+    /// The data of the buffer always starts at a multiple of this, as set by
+    /// [`DeviceHandle::alloc_transfer_aligned`](struct.DeviceHandle.html#method.alloc_transfer_aligned).
+    pub fn buffer_alignment(&self) -> usize
+    {
+        self.buffer.alignment()
+    }
+
+    fn iso_packet_descriptors(&self) -> &[libusb_iso_packet_descriptor]
+    {
+        unsafe {
+            let transfer = &*self.transfer.0;
+            slice::from_raw_parts(transfer.iso_packet_desc.as_ptr(),
+                                  transfer.num_iso_packets as usize)
+        }
+    }
+
+    fn iso_packet_descriptors_mut(&mut self) -> &mut [libusb_iso_packet_descriptor]
+    {
+        unsafe {
+            let transfer = &mut *self.transfer.0;
+            slice::from_raw_parts_mut(transfer.iso_packet_desc.as_mut_ptr(),
+                                      transfer.num_iso_packets as usize)
+        }
+    }
+
+}
+
+/// A transfer that has finished, as returned by
+/// [`TransferFuture`](struct.TransferFuture.html)
+///
+/// Only a completed transfer can be read, and only an idle
+/// [`Transfer`](struct.Transfer.html) can be filled and submitted, so the
+/// buffer can't be touched while the hardware may still write into it. The
+/// transfer can be turned back into an idle one to be reused.
+pub struct CompletedTransfer
+{
+    transfer: Transfer,
+    length_clamped: bool,
+    #[cfg(feature = "timing")]
+    timing: Option<TransferTiming>
+}
+
+/// When a transfer went through the stages of its submission, as returned by
+/// [`CompletedTransfer::timing`](struct.CompletedTransfer.html#method.timing)
+///
+/// The bus latency is the time libusb and the device took, the scheduling
+/// latency the time the executor took to poll the future after the callback
+/// woke it. Only recorded with the `timing` feature.
+#[cfg(feature = "timing")]
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct TransferTiming
+{
+    /// When the transfer was submitted
+    pub submitted: Instant,
+    /// When the callback ran on the event thread
+    pub completed: Instant,
+    /// When the future saw the transfer completed
+    pub ready: Instant
+}
+
+#[cfg(feature = "timing")]
+impl TransferTiming
+{
+    /// Time from submission to the callback
+    pub fn bus_latency(&self) -> Duration
+    {
+        self.completed - self.submitted
+    }
+
+    /// Time from the callback to the future being polled
+    pub fn scheduling_latency(&self) -> Duration
+    {
+        self.ready.saturating_duration_since(self.completed)
+    }
+}
+
+impl CompletedTransfer
+{
+    /// Get the status of the transfer
+    pub fn get_status(&self) -> TransferStatus
+    {
+        TransferStatus::from(unsafe{(*self.transfer.transfer.0).status})
+    }
+
+    /// Converts the status of the transfer into a `Result`, like
+    /// [`TransferStatus::to_result`](enum.TransferStatus.html#method.to_result)
+    ///
+    /// A transfer that failed with `TransferStatus::Error` is reported as
+    /// `Error::Operation`, which tells the endpoint of the transfer.
+    pub fn check_status(&self) -> ::Result<()>
+    {
+        match self.get_status() {
+            TransferStatus::Error =>
+                Err(error::from_failed_transfer(self.endpoint())),
+            status => status.to_result()
+        }
+    }
+
+    fn endpoint(&self) -> u8
+    {
+        unsafe{(*self.transfer.transfer.0).endpoint}
+    }
+
+    /// Get the buffer of the transfer
+    ///
+    /// For a completed read it contains the data received from the device.
+    pub fn get_buffer(&self) -> &[u8]
+    {
+        self.transfer.buffer.as_ref()
+    }
+
+    /// Get the data stage of a control transfer
+    ///
+    /// This is the buffer without the leading setup packet. For a completed control read it
+    /// contains the data received from the device.
+    pub fn get_control_data(&self) -> &[u8]
+    {
+        let buffer = self.get_buffer();
+        if buffer.len() < CONTROL_SETUP_SIZE {
+            &[]
+        } else {
+            &buffer[CONTROL_SETUP_SIZE..]
+        }
+    }
+
+    /// Get the packets of an isochronous transfer
+    ///
+    /// Each packet has its own status, and its data is only as long as what was actually
+    /// received.
+    pub fn iso_packets<'a>(&'a self) -> Vec<IsoPacket<'a>>
+    {
+        let buffer = self.get_buffer();
+        let mut offset = 0;
+
+        self.transfer.iso_packet_descriptors().iter().map(|packet| {
+            let start = offset.min(buffer.len());
+            let length = packet.actual_length.min(packet.length) as usize;
+            let end = (start + length).min(buffer.len());
+            offset += packet.length as usize;
+
+            IsoPacket {
+                status: TransferStatus::from(packet.status),
+                data: &buffer[start..end],
+            }
+        }).collect()
+    }
+
+    /// Tells if the transfer reported more data than it was submitted for, or
+    /// a negative length
+    ///
+    /// A misbehaving device or host controller can do that. The data is then
+    /// clamped to the submitted buffer, or to the length of each isochronous
+    /// packet, so it never includes bytes that weren't received.
+    pub fn is_length_clamped(&self) -> bool
+    {
+        self.length_clamped
+    }
+
+    /// Get when the transfer was submitted, completed and seen by its future
+    ///
+    /// `None` if it failed before being submitted.
+    #[cfg(feature = "timing")]
+    pub fn timing(&self) -> Option<TransferTiming>
+    {
+        self.timing
+    }
+
+    /// Get the idle transfer back, to be filled and submitted again
+    pub fn into_transfer(self) -> Transfer
+    {
+        self.transfer
+    }
+}
+
+/// A packet of an isochronous transfer, as returned by
+/// [`CompletedTransfer::iso_packets`](struct.CompletedTransfer.html#method.iso_packets)
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct IsoPacket<'a>
+{
+    status: TransferStatus,
+    data: &'a [u8]
+}
+
+impl<'a> IsoPacket<'a>
+{
+    /// Get the status of the packet
+    pub fn status(&self) -> TransferStatus
+    {
+        self.status
+    }
+
+    /// Get the data received in the packet
+    pub fn data(&self) -> &'a [u8]
+    {
+        self.data
+    }
+}
+
+impl PartialEq for Transfer
+{
+    fn eq(&self, other: &Self) -> bool
+    {
+        self.transfer.0 == other.transfer.0
+    }
+}
+
+impl Eq for Transfer
+{
+}
+
+#[doc(hidden)]
+pub unsafe fn from_libusb(context: &Arc<ContextAsync>,
+                          device: &Arc<Mutex<DeviceHandleAsync>>,
+                          shared: &HandleShared,
+                          transfer: *mut libusb_transfer,
+                          iso_packets: u32,
+                          alignment: usize)
+                          -> Transfer
+{
+    Transfer {
+        live: LiveTransfer::new(context),
+        _device: Arc::downgrade(device),
+        shared: shared.clone(),
+        buffer: AlignedBuffer::pooled(alignment, &context.buffers),
+        iso_packets,
+        submitted_at: None,
+        metered: false,
+        hook: Mutex::new(None),
+        backend: context.backend,
+        notify: Arc::new(Notify::new()),
+        transfer: RawTransfer(transfer)
+    }
+}
+
+/// Future that is ready when a transfer is finished.
+///
+/// This is the submitted state of a transfer, which can't be accessed until
+/// it's done. The result of a successful transfer is a
+/// [`CompletedTransfer`](struct.CompletedTransfer.html) object. Polling the future again after
+/// that resolves to `Error::PolledAfterCompletion`, and a transfer whose completion
+/// callback panicked resolves to `Error::CallbackPanicked`.
+
+pub struct TransferFuture
+{
+    state: TransferState
+}
+
+enum TransferState
+{
+    /// The transfer couldn't be submitted
+    Failed(Error),
+    /// The transfer is submitted, and shared with the callback until it's done
+    Pending(Arc<Transfer>),
+    /// The result has been returned
+    Done
+}
+
+impl Drop for TransferFuture
+{
+    fn drop(&mut self) {
+        if let TransferState::Pending(ref transfer) = self.state {
+            // Cancel transfer if not completed and polled
+            transfer.notify.stopping.store(true, Ordering::Release);
+            unsafe {
+                transfer.backend.cancel_transfer(TransferRef::from_libusb(transfer.transfer.0))
+            };
+        }
+    }
+}
+
+impl Transfer
+{
+    /// Trims the buffer to the data actually transferred
+    fn completed(mut self) -> CompletedTransfer
+    {
+        let usb_transfer = unsafe{&*self.transfer.0};
+        let mut length_clamped = usb_transfer.actual_length < 0;
+        let mut buf_len = usize::try_from(usb_transfer.actual_length)
+            .unwrap_or(0);
+        if usb_transfer.transfer_type == libusb::LIBUSB_TRANSFER_TYPE_CONTROL {
+            buf_len += CONTROL_SETUP_SIZE;
+        }
+        // Isochronous data is spread over the packets, so
+        // the whole buffer is kept
+        if usb_transfer.transfer_type == libusb::LIBUSB_TRANSFER_TYPE_ISOCHRONOUS {
+            length_clamped |= self.iso_packet_descriptors().iter()
+                .any(|packet| packet.actual_length > packet.length);
+        } else {
+            length_clamped |= buf_len > self.buffer.len();
+            let buf_len = buf_len.min(self.buffer.len());
+            self.buffer.truncate(buf_len);
+        }
+        #[cfg(feature = "timing")]
+        let timing = self.submitted_at.map(|submitted| {
+            let completed_after = self.notify.completed_after.load(Ordering::Acquire);
+            TransferTiming {
+                submitted,
+                completed: submitted + Duration::from_nanos(completed_after),
+                ready: Instant::now()
+            }
+        });
+        CompletedTransfer{transfer: self, length_clamped,
+                          #[cfg(feature = "timing")]
+                          timing}
+    }
+}
+
+impl Future for TransferFuture
+{
+    type Output = Result<CompletedTransfer, Error>;
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context)
+            -> task::Poll<Self::Output>
+    {
+        let this = self.get_mut();
+        let transfer = match mem::replace(&mut this.state, TransferState::Done) {
+            TransferState::Failed(e) => return task::Poll::Ready(Err(e)),
+            TransferState::Pending(transfer) => transfer,
+            TransferState::Done => return task::Poll::Ready(Err(Error::PolledAfterCompletion))
+        };
+
+        let notify = transfer.notify.clone();
+        if !notify.poll_completed(cx.waker()) {
+            this.state = TransferState::Pending(transfer);
+            return task::Poll::Pending;
+        }
+
+        if notify.panicked.load(Ordering::Acquire) {
+            return task::Poll::Ready(Err(Error::CallbackPanicked));
+        }
+
+        // The callback released its reference before marking the transfer
+        // completed
+        match Arc::try_unwrap(transfer) {
+            Ok(transfer) => task::Poll::Ready(Ok(transfer.completed())),
+            Err(_) => task::Poll::Ready(Err(Error::Other))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    extern crate futures;
+    extern crate static_assertions;
+
+    use super::*;
+    use self::static_assertions::assert_impl_all;
+    use std::sync::atomic::{AtomicI32, AtomicUsize};
+    use backend::{ContextRef, DeviceRef, LIBUSB};
+    use self::futures::task::{self as futures_task, noop_waker, ArcWake};
+
+    assert_impl_all!(Transfer: Send, Sync);
+    assert_impl_all!(TransferFuture: Send, Sync);
+    assert_impl_all!(::ControlFuture<Vec<u8>>: Send, Sync);
+    assert_impl_all!(::DisconnectFuture: Send, Sync);
+    assert_impl_all!(::DeviceHandle: Send, Sync);
+
+    fn transfer() -> Transfer {
+        let transfer = unsafe { libusb::libusb_alloc_transfer(0) };
+        let mut transfer = Transfer {
+            live: LiveTransfer::new(&ContextAsync::uninitialized()),
+            _device: Weak::new(),
+            shared: HandleShared::new(),
+            buffer: AlignedBuffer::new(::buffer::DEFAULT_ALIGNMENT),
+            transfer: RawTransfer(transfer),
+            iso_packets: 0,
+            submitted_at: None,
+            metered: false,
+            hook: Mutex::new(None),
+            backend: &LIBUSB,
+            notify: Arc::new(Notify::new())
+        };
+        transfer.fill_bulk_read(EndpointAddress::in_(1), 64).unwrap();
+        transfer
+    }
+
+    fn poll(future: &mut TransferFuture) -> task::Poll<::Result<CompletedTransfer>> {
+        let waker = noop_waker();
+        Pin::new(future).poll(&mut task::Context::from_waker(&waker))
+    }
+
+    /// Runs a fake instead of libusb_submit_transfer
+    struct FakeSubmit(unsafe extern "C" fn(*mut libusb_transfer) -> c_int);
+
+    impl UsbBackend for FakeSubmit {
+        unsafe fn submit_transfer(&self, transfer: TransferRef) -> c_int {
+            (self.0)(transfer.as_ptr() as *mut libusb_transfer)
+        }
+
+        unsafe fn cancel_transfer(&self, transfer: TransferRef) -> c_int {
+            LIBUSB.cancel_transfer(transfer)
+        }
+
+        unsafe fn handle_events(&self, context: ContextRef, timeout: Duration, completed: Option<&AtomicI32>) -> c_int {
+            LIBUSB.handle_events(context, timeout, completed)
+        }
+    }
+
+    unsafe extern "C" fn submit_fails(_transfer: *mut libusb_transfer) -> c_int {
+        libusb::LIBUSB_ERROR_NO_DEVICE
+    }
+
+    unsafe extern "C" fn submit_completes(transfer: *mut libusb_transfer) -> c_int {
+        (*transfer).actual_length = 10;
+        asyn_callback(transfer);
+        0
+    }
+
+    #[test]
+    fn it_submits_through_the_backend_of_its_context() {
+        let mut transfer = transfer();
+        transfer.live = LiveTransfer::new(&ContextAsync::with_backend(&FakeSubmit(submit_completes)));
+
+        match poll(&mut transfer.submit()) {
+            task::Poll::Ready(Ok(transfer)) => assert_eq!(10, transfer.get_buffer().len()),
+            _ => panic!("transfer not completed by the backend"),
+        }
+    }
+
+    #[test]
+    fn it_records_metrics_when_enabled() {
+        let unrecorded = transfer();
+        let metrics = unrecorded.shared.metrics.clone();
+        match poll(&mut unrecorded.submit_with(&FakeSubmit(submit_completes))) {
+            task::Poll::Ready(Ok(_)) => {},
+            _ => panic!("transfer not completed"),
+        }
+        assert!(metrics.snapshot().endpoints.is_empty());
+
+        metrics.set_enabled(true);
+        let mut transfer = transfer();
+        transfer.shared.metrics = metrics.clone();
+        match poll(&mut transfer.submit_with(&FakeSubmit(submit_completes))) {
+            task::Poll::Ready(Ok(_)) => {},
+            _ => panic!("transfer not completed"),
+        }
+        let snapshot = metrics.snapshot();
+        let bulk_in = snapshot.endpoint(0x81).unwrap();
+        assert_eq!((1, 10, 0), (bulk_in.transfers, bulk_in.bytes, bulk_in.in_flight));
+        assert_eq!(1, bulk_in.latency.count());
+    }
+
+    #[test]
+    fn it_tells_observers_about_submissions_and_completions() {
+        struct Recorder(Mutex<Vec<(&'static str, u8, usize)>>);
+
+        impl ::TransferObserver for Recorder {
+            fn on_submit(&self, transfer: &TransferInfo) {
+                self.0.lock().unwrap().push(("submit", transfer.endpoint, transfer.length));
+            }
+
+            fn on_complete(&self, transfer: &TransferInfo, actual_length: usize) {
+                self.0.lock().unwrap().push(("complete", transfer.endpoint, actual_length));
+            }
+
+            fn on_error(&self, transfer: &TransferInfo, status: TransferStatus) {
+                assert_eq!(TransferStatus::NoDevice, status);
+                self.0.lock().unwrap().push(("error", transfer.endpoint, 0));
+            }
+        }
+
+        let context = ContextAsync::uninitialized();
+        let recorder = Arc::new(Recorder(Mutex::new(Vec::new())));
+        context.observers.add(recorder.clone());
+
+        let mut completing = transfer();
+        completing.live = LiveTransfer::new(&context);
+        match poll(&mut completing.submit_with(&FakeSubmit(submit_completes))) {
+            task::Poll::Ready(Ok(_)) => {},
+            _ => panic!("transfer not completed"),
+        }
+        let mut failing = transfer();
+        failing.live = LiveTransfer::new(&context);
+        match poll(&mut failing.submit_with(&FakeSubmit(submit_fails))) {
+            task::Poll::Ready(Err(_)) => {},
+            _ => panic!("submission not failed"),
+        }
+
+        assert_eq!(vec![("submit", 0x81, 64), ("complete", 0x81, 10), ("submit", 0x81, 64), ("error", 0x81, 0)],
+                   *recorder.0.lock().unwrap());
+    }
+
+    #[test]
+    fn it_locates_the_device_only_for_observers() {
+        /// Counts the lookups of the device of a transfer
+        struct Locating(AtomicUsize);
+
+        impl UsbBackend for Locating {
+            unsafe fn get_device(&self, handle: HandleRef) -> DeviceRef {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                DeviceRef::from_ptr(handle.as_ptr())
+            }
+
+            unsafe fn submit_transfer(&self, transfer: TransferRef) -> c_int {
+                submit_completes(transfer.as_ptr() as *mut libusb_transfer)
+            }
+
+            unsafe fn cancel_transfer(&self, transfer: TransferRef) -> c_int {
+                LIBUSB.cancel_transfer(transfer)
+            }
+
+            unsafe fn handle_events(&self, context: ContextRef, timeout: Duration, completed: Option<&AtomicI32>) -> c_int {
+                LIBUSB.handle_events(context, timeout, completed)
+            }
+        }
+
+        struct Silent;
+
+        impl ::TransferObserver for Silent {}
+
+        static LOCATING: Locating = Locating(AtomicUsize::new(0));
+        let mut handle = 0u8;
+        let handle = &mut handle as *mut u8 as *mut libusb::libusb_device_handle;
+        let context = ContextAsync::uninitialized();
+        let submit = |context: &Arc<ContextAsync>| {
+            let mut transfer = transfer();
+            transfer.live = LiveTransfer::new(context);
+            transfer.backend = &LOCATING;
+            unsafe { (*transfer.transfer.0).dev_handle = handle };
+            match poll(&mut transfer.submit_with(&LOCATING)) {
+                task::Poll::Ready(Ok(_)) => {},
+                _ => panic!("transfer not completed"),
+            }
+        };
+
+        submit(&context);
+        assert_eq!(0, LOCATING.0.load(Ordering::SeqCst));
+
+        context.observers.add(Arc::new(Silent));
+        submit(&context);
+        assert_eq!(2, LOCATING.0.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn it_captures_submissions_and_completions() {
+        let context = ContextAsync::uninitialized();
+        context.capture.start();
+
+        let mut transfer = transfer();
+        transfer.live = LiveTransfer::new(&context);
+        match poll(&mut transfer.submit_with(&FakeSubmit(submit_completes))) {
+            task::Poll::Ready(Ok(_)) => {},
+            _ => panic!("transfer not completed"),
+        }
+
+        let records = context.capture.stop();
+        assert_eq!(vec![(CaptureEvent::Submit, 64, 0, None), (CaptureEvent::Complete, 10, 10, Some(TransferStatus::Completed))],
+                   records.iter().map(|r| (r.event, r.length, r.data.len(), r.status)).collect::<Vec<_>>());
+        assert_eq!(records[0].id, records[1].id);
+        assert_eq!((0x81, TransferType::Bulk), (records[1].endpoint, records[1].transfer_type));
+        assert!(context.capture.stop().is_empty());
+    }
+
+    #[test]
+    fn it_replays_what_a_device_answered() {
+        // A device that answered a read, then stalled, as written to a bug report
+        let mut report = Vec::new();
+        ::write_pcapng(&[::replay::answer(0x81, TransferStatus::Completed, b"hello"),
+                         ::replay::answer(0x81, TransferStatus::Stall, b"")], &mut report).unwrap();
+        let replay = ::replay::Replay::leak(&::read_pcapng(&report[..]).unwrap());
+        let context = ContextAsync::with_backend(replay);
+        let handle_events = || unsafe {
+            replay.handle_events(ContextRef::from_ptr(ptr::null_mut()), Duration::from_secs(0), None)
+        };
+
+        let mut reading = transfer();
+        reading.live = LiveTransfer::new(&context);
+        let mut read = reading.submit();
+        assert!(poll(&mut read).is_pending());
+        handle_events();
+        match poll(&mut read) {
+            task::Poll::Ready(Ok(transfer)) => assert_eq!(b"hello", transfer.get_buffer()),
+            _ => panic!("read not replayed"),
+        }
+
+        let mut stalling = transfer();
+        stalling.live = LiveTransfer::new(&context);
+        let mut stalled = stalling.submit();
+        handle_events();
+        match poll(&mut stalled) {
+            task::Poll::Ready(Ok(transfer)) => assert_eq!(TransferStatus::Stall, transfer.get_status()),
+            _ => panic!("stall not replayed"),
+        }
+        assert_eq!(0, replay.remaining());
+    }
+
+    #[cfg(feature = "timing")]
+    #[test]
+    fn it_times_completed_transfers() {
+        let transfer = match poll(&mut transfer().submit_with(&FakeSubmit(submit_completes))) {
+            task::Poll::Ready(Ok(transfer)) => transfer,
+            _ => panic!("transfer not completed"),
+        };
+        let timing = transfer.timing().unwrap();
+        assert!(timing.submitted <= timing.completed && timing.completed <= timing.ready);
+        assert_eq!(timing.ready - timing.submitted, timing.bus_latency() + timing.scheduling_latency());
+    }
+
+    #[test]
+    fn it_resubmits_from_the_completion_hook() {
+        use std::sync::mpsc;
+
+        let (received, data) = mpsc::channel();
+        let mut transfer = transfer();
+        let mut cycles = 0;
+        transfer.set_completion_hook(move |status, data| {
+            assert_eq!(TransferStatus::Completed, status);
+            received.send(data.to_vec()).unwrap();
+            cycles += 1;
+            if cycles < 3 { HookAction::Resubmit } else { HookAction::Complete }
+        });
+        let in_flight = transfer.shared.in_flight.clone();
+        let mut future = transfer.submit_with(&FakeSubmit(submit_only));
+        let libusb_transfer = match future.state {
+            TransferState::Pending(ref transfer) => transfer.transfer.0,
+            _ => panic!("transfer not pending"),
+        };
+
+        for cycle in 1..4 {
+            unsafe { (*libusb_transfer).actual_length = cycle };
+            asyn_callback(libusb_transfer);
+        }
+        assert_eq!(vec![vec![0], vec![0, 0], vec![0, 0, 0]], data.try_iter().collect::<Vec<_>>());
+        match poll(&mut future) {
+            task::Poll::Ready(Ok(transfer)) => assert_eq!(3, transfer.get_buffer().len()),
+            _ => panic!("transfer not completed"),
+        }
+        assert!(in_flight.transfers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_stops_resubmitting_when_the_future_is_dropped() {
+        let mut transfer = transfer();
+        transfer.set_completion_hook(|_, _| HookAction::Resubmit);
+        let in_flight = transfer.shared.in_flight.clone();
+        let future = transfer.submit_with(&FakeSubmit(submit_only));
+        let libusb_transfer = match future.state {
+            TransferState::Pending(ref transfer) => transfer.transfer.0 as usize,
+            _ => panic!("transfer not pending"),
+        };
+
+        drop(future);
+        asyn_callback(libusb_transfer as *mut libusb_transfer);
+        assert!(in_flight.transfers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_releases_transfer_when_submit_fails() {
+        let transfer = transfer();
+        let notify = transfer.notify.clone();
+        let mut future = transfer.submit_with(&FakeSubmit(submit_fails));
+
+        // Only the test holds the notification, so the transfer has been freed
+        assert_eq!(1, Arc::strong_count(&notify));
+        match poll(&mut future) {
+            task::Poll::Ready(Err(e)) => {
+                assert!(matches!(e.libusb_error(), Error::NoDevice));
+                assert_eq!("submit_transfer(0x81) failed: LIBUSB_ERROR_NO_DEVICE", e.to_string());
+            },
+            _ => panic!("submit error not reported"),
+        }
+        match poll(&mut future) {
+            task::Poll::Ready(Err(Error::PolledAfterCompletion)) => {},
+            _ => panic!("completed future not reported"),
+        }
+    }
+
+    unsafe extern "C" fn submit_disconnects(transfer: *mut libusb_transfer) -> c_int {
+        (*transfer).status = libusb::LIBUSB_TRANSFER_NO_DEVICE;
+        asyn_callback(transfer);
+        0
+    }
+
+    #[test]
+    fn it_fails_fast_after_disconnect() {
+        let transfer = transfer();
+        let disconnect = transfer.shared.disconnect.clone();
+        let mut watcher = ::disconnect::watch(&disconnect);
+        let waker = noop_waker();
+        let mut cx = task::Context::from_waker(&waker);
+        assert!(Pin::new(&mut watcher).poll(&mut cx).is_pending());
+
+        let mut future = transfer.submit_with(&FakeSubmit(submit_disconnects));
+        match poll(&mut future) {
+            task::Poll::Ready(Ok(transfer)) => {
+                assert_eq!(TransferStatus::NoDevice, transfer.get_status());
+
+                // The submit function would complete the transfer if it was called
+                let mut future = transfer.into_transfer().submit_with(&FakeSubmit(submit_completes));
+                match poll(&mut future) {
+                    task::Poll::Ready(Err(Error::Disconnected)) => {},
+                    _ => panic!("transfer submitted after disconnect"),
+                }
+            },
+            _ => panic!("transfer not completed"),
+        }
+        assert!(disconnect.is_disconnected());
+        assert!(Pin::new(&mut watcher).poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn it_resolves_when_callback_has_run() {
+        let mut future = transfer().submit_with(&FakeSubmit(submit_completes));
+
+        match poll(&mut future) {
+            task::Poll::Ready(Ok(transfer)) => {
+                assert_eq!(10, transfer.get_buffer().len());
+                assert!(!transfer.is_length_clamped());
+            },
+            _ => panic!("transfer not completed"),
+        }
+    }
+
+    unsafe extern "C" fn submit_overruns(transfer: *mut libusb_transfer) -> c_int {
+        (*transfer).actual_length = 1000;
+        asyn_callback(transfer);
+        0
+    }
+
+    unsafe extern "C" fn submit_negative(transfer: *mut libusb_transfer) -> c_int {
+        (*transfer).actual_length = -5;
+        asyn_callback(transfer);
+        0
+    }
+
+    unsafe extern "C" fn submit_setup_only(transfer: *mut libusb_transfer) -> c_int {
+        assert_eq!(CONTROL_SETUP_SIZE as c_int, (*transfer).length);
+        (*transfer).actual_length = 0;
+        asyn_callback(transfer);
+        0
+    }
+
+    #[test]
+    fn it_fills_control_transfers_without_data_stage() {
+        let mut transfer = transfer();
+        transfer.fill_control_no_data(0x00, 0x09, 1, 0).unwrap();
+        assert_eq!(&[0x00, 0x09, 1, 0, 0, 0, 0, 0], &transfer.buffer[..]);
+
+        match poll(&mut transfer.submit_with(&FakeSubmit(submit_setup_only))) {
+            task::Poll::Ready(Ok(transfer)) => {
+                assert!(transfer.get_control_data().is_empty());
+                assert!(!transfer.is_length_clamped());
+                assert!(transfer.check_status().is_ok());
+            },
+            _ => panic!("transfer not completed"),
+        }
+
+        let mut read = self::transfer();
+        read.fill_control_read(0x80, 0x00, 0, 0, 0).unwrap();
+        assert!(matches!(poll(&mut read.submit_with(&FakeSubmit(submit_setup_only))), task::Poll::Ready(Ok(_))));
+
+        let mut write = self::transfer();
+        write.fill_control_write(0x00, 0x03, 1, 0, &[]).unwrap();
+        assert!(matches!(poll(&mut write.submit_with(&FakeSubmit(submit_setup_only))), task::Poll::Ready(Ok(_))));
+    }
+
+    #[test]
+    fn it_clamps_bogus_actual_length() {
+        match poll(&mut transfer().submit_with(&FakeSubmit(submit_overruns))) {
+            task::Poll::Ready(Ok(transfer)) => {
+                assert_eq!(64, transfer.get_buffer().len());
+                assert!(transfer.is_length_clamped());
+            },
+            _ => panic!("transfer not completed"),
+        }
+
+        let mut control = transfer();
+        control.fill_control_read(0xC0, 1, 0, 0, 4).unwrap();
+        match poll(&mut control.submit_with(&FakeSubmit(submit_overruns))) {
+            task::Poll::Ready(Ok(transfer)) => {
+                assert_eq!(4, transfer.get_control_data().len());
+                assert!(transfer.is_length_clamped());
+            },
+            _ => panic!("transfer not completed"),
+        }
+
+        match poll(&mut transfer().submit_with(&FakeSubmit(submit_negative))) {
+            task::Poll::Ready(Ok(transfer)) => {
+                assert!(transfer.get_buffer().is_empty());
+                assert!(transfer.is_length_clamped());
+            },
+            _ => panic!("transfer not completed"),
+        }
+    }
+
+    struct PanickingWaker;
+
+    impl ArcWake for PanickingWaker {
+        fn wake_by_ref(_: &Arc<Self>) {
+            panic!("waker panicked");
+        }
+    }
+
+    unsafe extern "C" fn submit_only(_transfer: *mut libusb_transfer) -> c_int {
+        0
+    }
+
+    #[test]
+    fn it_reports_panicking_waker_on_future() {
+        let mut future = transfer().submit_with(&FakeSubmit(submit_only));
+        let waker = futures_task::waker(Arc::new(PanickingWaker));
+        assert!(Pin::new(&mut future).poll(&mut task::Context::from_waker(&waker)).is_pending());
+
+        // Runs on the event thread, which must survive the panic
+        let libusb_transfer = match future.state {
+            TransferState::Pending(ref transfer) => transfer.transfer.0,
+            _ => panic!("transfer not pending"),
+        };
+        asyn_callback(libusb_transfer);
+
+        match poll(&mut future) {
+            task::Poll::Ready(Err(Error::CallbackPanicked)) => {},
+            _ => panic!("panic not reported"),
+        }
+    }
+
+    struct CountingWaker(AtomicI32);
+
+    impl ArcWake for CountingWaker {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn it_wakes_the_stored_waker_once() {
+        let counter = Arc::new(CountingWaker(AtomicI32::new(0)));
+        let waker = futures_task::waker(counter.clone());
+        let notify = Notify::new();
+
+        assert!(!notify.poll_completed(&waker));
+        assert!(!notify.poll_completed(&waker));
+        notify.complete();
+        notify.complete();
+        assert_eq!(1, counter.0.load(Ordering::SeqCst));
+        assert!(notify.poll_completed(&waker));
+
+        // Completed before a waker is stored
+        notify.reset();
+        notify.complete();
+        assert!(notify.poll_completed(&waker));
+        assert_eq!(1, counter.0.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn it_never_loses_the_completion_wakeup() {
+        use std::sync::mpsc;
+        use std::thread;
+        use std::time::Duration;
+
+        for _ in 0..500 {
+            let future = transfer().submit_with(&FakeSubmit(submit_only));
+            let libusb_transfer = match future.state {
+                TransferState::Pending(ref transfer) => transfer.transfer.0 as usize,
+                _ => panic!("transfer not pending"),
+            };
+
+            // The callback races with the executor polling and parking
+            let event_loop = thread::spawn(move || {
+                thread::yield_now();
+                asyn_callback(libusb_transfer as *mut libusb_transfer);
+            });
+            let (done, finished) = mpsc::channel();
+            thread::spawn(move || {
+                let _ = done.send(futures::executor::block_on(future).is_ok());
+            });
+
+            assert_eq!(Ok(true), finished.recv_timeout(Duration::from_secs(5)));
+            event_loop.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn it_drains_submitted_transfers() {
+        let transfer = transfer();
+        let in_flight = transfer.shared.in_flight.clone();
+        let future = transfer.submit_with(&FakeSubmit(submit_only));
+        let libusb_transfer = match future.state {
+            TransferState::Pending(ref transfer) => transfer.transfer.0 as usize,
+            _ => panic!("transfer not pending"),
+        };
+
+        // Stands in for the event loop completing the cancelled transfer
+        let event_loop = ::std::thread::spawn(move || {
+            ::std::thread::sleep(::std::time::Duration::from_millis(10));
+            asyn_callback(libusb_transfer as *mut libusb_transfer);
+        });
+        in_flight.drain(&LIBUSB);
+        assert!(in_flight.transfers.lock().unwrap().is_empty());
+        event_loop.join().unwrap();
+        drop(future);
+    }
+
+    #[test]
+    fn it_sets_zero_packet_flag() {
+        let mut transfer = transfer();
+        transfer.fill_bulk_write(EndpointAddress::out(1), &[0; 64]).unwrap();
+        transfer.set_add_zero_packet(true);
+        assert_eq!(libusb::LIBUSB_TRANSFER_ADD_ZERO_PACKET, unsafe { (*transfer.transfer.0).flags });
+
+        transfer.set_add_zero_packet(false);
+        assert_eq!(0, unsafe { (*transfer.transfer.0).flags });
+    }
+
+    #[test]
+    fn it_rounds_timeout_up_to_milliseconds() {
+        let mut transfer = transfer();
+        transfer.set_timeout(Duration::from_micros(1500));
+        assert_eq!(2, unsafe { (*transfer.transfer.0).timeout });
+
+        transfer.set_timeout(Duration::from_millis(0));
+        assert_eq!(1, unsafe { (*transfer.transfer.0).timeout });
+
+        transfer.set_timeout(Duration::from_secs(u64::MAX));
+        assert_eq!(c_uint::MAX, unsafe { (*transfer.transfer.0).timeout });
+    }
+
+    #[test]
+    fn it_reuses_freed_transfers() {
+        let transfer = transfer();
+        let context = transfer.live.context().clone();
+        let libusb_transfer = transfer.transfer.0;
+        unsafe { (*libusb_transfer).flags = libusb::LIBUSB_TRANSFER_ADD_ZERO_PACKET };
+        drop(transfer);
+        assert_eq!(1, context.transfers.len());
+
+        let reused = context.transfers.take(0);
+        assert_eq!(libusb_transfer, reused);
+        assert_eq!(0, unsafe { (*reused).flags });
+        assert_eq!(0, context.transfers.len());
+        unsafe { context.transfers.give(reused, 0) };
+    }
+
+    #[test]
+    fn it_validates_fills() {
+        let mut transfer = transfer();
+        assert_eq!(Err(FillError::WrongDirection), transfer.fill_bulk_read(EndpointAddress::out(1), 64));
+        assert_eq!(Err(FillError::WrongDirection), transfer.fill_interrupt_write(EndpointAddress::in_(1), &[1]));
+        assert_eq!(Err(FillError::PayloadTooLarge), transfer.fill_control_write(0x40, 0, 0, 0, &[0; 0x10000]));
+        assert_eq!(Err(FillError::TooManyPackets), transfer.fill_iso_write(EndpointAddress::out(2), &[0; 8], &[4, 4]));
+
+        assert_eq!(Err(FillError::WrongDirection), transfer.fill_control_read(0x40, 0, 0, 0, 4));
+        assert_eq!(Err(FillError::WrongDirection), transfer.fill_control_write(0xC0, 0, 0, 0, &[1]));
+        assert_eq!(Err(FillError::WrongDirection), transfer.fill_control_no_data(0x80, 0, 0, 0));
+        assert_eq!(Err(FillError::InvalidRequestType(0x60)), transfer.fill_control_no_data(0x60, 0, 0, 0));
+        assert_eq!(Err(FillError::InvalidRequestType(0xA4)), transfer.fill_control_read(0xA4, 0, 0, 0, 4));
+        assert_eq!(Ok(()), transfer.fill_control_read(0xA1, 0, 0, 0, 0));
+
+        let error = Error::from(FillError::WrongDirection);
+        assert!(matches!(error, Error::Fill(FillError::WrongDirection)));
+        assert_eq!("Invalid transfer: Wrong endpoint direction for the transfer", error.to_string());
+
+        let bulk = interface!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x81, bmAttributes: 0x02)));
+        let config = config_descriptor!(bulk);
+        let config = unsafe { ::config_descriptor::from_libusb(&config) };
+        transfer.shared.endpoints.claim(&config, 0, 0);
+        assert_eq!(Err(FillError::NotAnInterruptEndpoint), transfer.fill_interrupt_read(EndpointAddress::in_(1), 8));
+        assert_eq!(Err(FillError::WrongTransferType(0x81, TransferType::Bulk)), transfer.fill_iso_read(EndpointAddress::in_(1), 8));
+        assert_eq!(Err(FillError::UnknownEndpoint(0x82)), transfer.fill_bulk_read(EndpointAddress::in_(2), 8));
+        assert_eq!("Endpoint 0x82 is not in a claimed interface", FillError::UnknownEndpoint(0x82).to_string());
+        assert_eq!(Ok(()), transfer.fill_bulk_read(EndpointAddress::in_(1), 8));
+
+        transfer.shared.endpoints.release(0);
+        assert_eq!(Ok(()), transfer.fill_interrupt_read(EndpointAddress::in_(1), 8));
+        ::std::mem::forget(config);
+    }
+
+    #[test]
+    fn it_checks_the_endpoints_of_the_active_setting() {
+        let mut transfer = transfer();
+        let mut iso = interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x82, bmAttributes: 0x01));
+        iso.bAlternateSetting = 1;
+        let mut bulk = interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x82, bmAttributes: 0x02));
+        bulk.bAlternateSetting = 2;
+        let config = config_descriptor!(interface!(interface_descriptor!(bAlternateSetting: 0), iso, bulk));
+        let config = unsafe { ::config_descriptor::from_libusb(&config) };
+
+        // The default setting has no endpoints
+        transfer.shared.endpoints.claim(&config, 0, 0);
+        assert_eq!(Err(FillError::UnknownEndpoint(0x82)), transfer.fill_iso_read(EndpointAddress::in_(2), 8));
+
+        transfer.shared.endpoints.claim(&config, 0, 1);
+        assert_eq!(Ok(()), transfer.fill_iso_read(EndpointAddress::in_(2), 8));
+        assert_eq!(Err(FillError::WrongTransferType(0x82, TransferType::Isochronous)),
+                   transfer.fill_bulk_read(EndpointAddress::in_(2), 8));
+
+        transfer.shared.endpoints.claim(&config, 0, 2);
+        assert_eq!(Ok(()), transfer.fill_bulk_read(EndpointAddress::in_(2), 8));
+
+        transfer.shared.endpoints.clear();
+        assert_eq!(Ok(()), transfer.fill_iso_read(EndpointAddress::in_(2), 8));
+        ::std::mem::forget(config);
+    }
+}
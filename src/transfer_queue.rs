@@ -0,0 +1,128 @@
+//! Transfers that class drivers submit and wait for in order, e.g., the frames of a sink.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task;
+
+use device_handle::DeviceHandle;
+use fields::EndpointAddress;
+use transfer::TransferFuture;
+
+/// Transfers waited for in the order they were submitted, e.g., the frames given to a sink.
+///
+/// `BulkIo` has a single write pending, as `AsyncWrite` reports what was written before taking
+/// more. A sink instead takes each item whole and keeps several in flight, up to a limit that
+/// `poll_ready` waits for.
+pub struct TransferQueue<F> {
+    in_flight: VecDeque<F>,
+}
+
+impl<T, F> TransferQueue<F> where F: Future<Output = ::Result<T>> + Unpin {
+    pub fn with_capacity(capacity: usize) -> TransferQueue<F> {
+        TransferQueue { in_flight: VecDeque::with_capacity(capacity) }
+    }
+
+    pub fn push(&mut self, future: F) {
+        self.in_flight.push_back(future);
+    }
+
+    /// Waits until at most `limit` transfers are in flight, checking each completed one with
+    /// `check`. Stops at the first that fails, which is removed from the queue.
+    pub fn poll_until<C>(&mut self, cx: &mut task::Context, limit: usize, mut check: C) -> task::Poll<::Result<()>>
+        where C: FnMut(T) -> ::Result<()>
+    {
+        while self.in_flight.len() > limit {
+            let result = match self.in_flight.front_mut() {
+                Some(future) => match Pin::new(future).poll(cx) {
+                    task::Poll::Pending => return task::Poll::Pending,
+                    task::Poll::Ready(result) => result,
+                },
+                None => unreachable!(),
+            };
+
+            self.in_flight.pop_front();
+            result.and_then(&mut check)?;
+        }
+
+        task::Poll::Ready(Ok(()))
+    }
+}
+
+impl TransferQueue<TransferFuture> {
+    /// Waits until at most `limit` transfers are in flight, failing with the first that didn't
+    /// complete.
+    pub fn poll_completed(&mut self, cx: &mut task::Context, limit: usize) -> task::Poll<::Result<()>> {
+        self.poll_until(cx, limit, |transfer| transfer.check_status())
+    }
+}
+
+/// Submits a bulk read of `length` bytes.
+pub fn submit_bulk_read(handle: &DeviceHandle, endpoint: EndpointAddress, length: usize) -> ::Result<TransferFuture> {
+    let mut transfer = handle.alloc_transfer(0)?;
+    transfer.fill_bulk_read(endpoint, length)?;
+    Ok(transfer.submit())
+}
+
+/// Submits a bulk write of `data`.
+pub fn submit_bulk_write(handle: &DeviceHandle, endpoint: EndpointAddress, data: &[u8]) -> ::Result<TransferFuture> {
+    let mut transfer = handle.alloc_transfer(0)?;
+    transfer.fill_bulk_write(endpoint, data)?;
+    Ok(transfer.submit())
+}
+
+
+#[cfg(test)]
+mod test {
+    extern crate futures;
+
+    use super::*;
+    use std::future;
+    use error::Error;
+    use self::futures::task::noop_waker;
+
+    #[test]
+    fn it_waits_for_the_oldest_transfers_down_to_the_limit() {
+        let waker = noop_waker();
+        let mut cx = task::Context::from_waker(&waker);
+        let mut queue = TransferQueue::with_capacity(3);
+        let mut checked = Vec::new();
+
+        queue.push(future::ready(Ok(1)));
+        queue.push(future::ready(Ok(2)));
+        queue.push(future::ready(Ok(3)));
+        assert!(matches!(queue.poll_until(&mut cx, 1, |n| { checked.push(n); Ok(()) }), task::Poll::Ready(Ok(()))));
+        assert_eq!(vec![1, 2], checked);
+        assert!(matches!(queue.poll_until(&mut cx, 0, |n| { checked.push(n); Ok(()) }), task::Poll::Ready(Ok(()))));
+        assert_eq!(vec![1, 2, 3], checked);
+    }
+
+    #[test]
+    fn it_stops_at_the_first_failure() {
+        let waker = noop_waker();
+        let mut cx = task::Context::from_waker(&waker);
+        let mut queue = TransferQueue::with_capacity(3);
+        let mut checked = Vec::new();
+
+        queue.push(future::ready(Ok(1)));
+        queue.push(future::ready(Err(Error::Pipe)));
+        queue.push(future::ready(Ok(3)));
+        queue.push(future::ready(Ok(4)));
+        assert!(matches!(queue.poll_until(&mut cx, 0, |n| { checked.push(n); Ok(()) }), task::Poll::Ready(Err(Error::Pipe))));
+        assert!(matches!(queue.poll_until(&mut cx, 0, |n| { checked.push(n); if n == 3 { Err(Error::Io) } else { Ok(()) } }),
+                         task::Poll::Ready(Err(Error::Io))));
+        assert!(matches!(queue.poll_until(&mut cx, 0, |n| { checked.push(n); Ok(()) }), task::Poll::Ready(Ok(()))));
+        assert_eq!(vec![1, 3, 4], checked);
+    }
+
+    #[test]
+    fn it_waits_for_a_pending_transfer() {
+        let waker = noop_waker();
+        let mut cx = task::Context::from_waker(&waker);
+        let mut queue: TransferQueue<future::Pending<::Result<()>>> = TransferQueue::with_capacity(1);
+
+        queue.push(future::pending());
+        assert!(matches!(queue.poll_until(&mut cx, 1, |_| Ok(())), task::Poll::Ready(Ok(()))));
+        assert!(queue.poll_until(&mut cx, 0, |_| Ok(())).is_pending());
+    }
+}
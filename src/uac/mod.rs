@@ -0,0 +1,468 @@
+//! USB Audio Class (UAC) support.
+
+pub use self::stream::AudioStream;
+
+mod stream;
+
+use config_descriptor::ConfigDescriptor;
+use control::{self, ControlFuture};
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::{ClassCode, TransferType, UsageType, Direction, RequestType, Recipient, request_type};
+use interface_descriptor::InterfaceDescriptor;
+
+const SUBCLASS_AUDIO_CONTROL: u8 = 0x01;
+const SUBCLASS_AUDIO_STREAMING: u8 = 0x02;
+const PROTOCOL_UAC2: u8 = 0x20;
+
+const CS_INTERFACE: u8 = 0x24;
+
+const AC_INPUT_TERMINAL: u8 = 0x02;
+const AC_OUTPUT_TERMINAL: u8 = 0x03;
+const AS_GENERAL: u8 = 0x01;
+const AS_FORMAT_TYPE: u8 = 0x02;
+const FORMAT_TYPE_I: u8 = 0x01;
+
+const SET_CUR: u8 = 0x01;
+const RANGE: u8 = 0x02;
+const SAMPLING_FREQ_CONTROL: u8 = 0x01;
+
+const MAX_RANGES_LENGTH: u16 = 2 + 12 * 21;
+
+/// Sample rates that are tried when a device supports a continuous range.
+const STANDARD_SAMPLE_RATES: [u32; 13] = [
+    8_000, 11_025, 16_000, 22_050, 24_000, 32_000, 44_100, 48_000,
+    88_200, 96_000, 176_400, 192_000, 384_000,
+];
+
+/// Revisions of the USB Audio Class specification.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum UacVersion {
+    /// USB Audio 1.0.
+    V1,
+
+    /// USB Audio 2.0.
+    V2,
+}
+
+/// The sample rates supported by an audio format.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub enum SampleRates {
+    /// A list of discrete sample rates in Hz.
+    Discrete(Vec<u32>),
+
+    /// A continuous range of sample rates in Hz.
+    Continuous {
+        /// The lowest sample rate.
+        min: u32,
+
+        /// The highest sample rate.
+        max: u32,
+    },
+
+    /// The sample rates are those of a UAC 2.0 clock entity, which can be read with
+    /// [`clock_frequency_ranges`](fn.clock_frequency_ranges.html).
+    Clock {
+        /// Number of the AudioControl interface containing the clock.
+        control_interface: u8,
+
+        /// ID of the clock entity.
+        clock_id: u8,
+    },
+}
+
+/// A type I (PCM) audio format offered by an alternate setting of an AudioStreaming interface.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub struct AudioFormat {
+    /// The UAC version of the interface.
+    pub version: UacVersion,
+
+    /// The AudioStreaming interface number.
+    pub interface: u8,
+
+    /// The alternate setting that selects this format.
+    pub alt_setting: u8,
+
+    /// The address of the isochronous data endpoint, if the setting has one.
+    pub endpoint: Option<u8>,
+
+    /// Number of channels.
+    pub channels: u8,
+
+    /// Bytes per sample in the stream (`bSubframeSize` or `bSubslotSize`).
+    pub subframe_size: u8,
+
+    /// Significant bits per sample (`bBitResolution`).
+    pub bit_depth: u8,
+
+    /// Supported sample rates.
+    pub sample_rates: SampleRates,
+}
+
+/// A concrete combination of stream parameters that can be selected.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct FormatChoice {
+    /// Sample rate in Hz.
+    pub sample_rate: u32,
+
+    /// Number of channels.
+    pub channels: u8,
+
+    /// Significant bits per sample.
+    pub bit_depth: u8,
+
+    /// The AudioStreaming interface number.
+    pub interface: u8,
+
+    /// The alternate setting to select.
+    pub alt_setting: u8,
+}
+
+/// A frequency range reported by a UAC 2.0 clock entity.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct FrequencyRange {
+    /// The lowest frequency in Hz.
+    pub min: u32,
+
+    /// The highest frequency in Hz.
+    pub max: u32,
+
+    /// The step between supported frequencies in Hz, or zero if any frequency in the range is
+    /// supported.
+    pub resolution: u32,
+}
+
+impl FrequencyRange {
+    fn contains(&self, rate: u32) -> bool {
+        if rate < self.min || rate > self.max {
+            return false;
+        }
+
+        match (rate - self.min).checked_rem(self.resolution) {
+            Some(remainder) => remainder == 0,
+            None => true,
+        }
+    }
+}
+
+impl AudioFormat {
+    /// Replaces `SampleRates::Clock` with the discrete rates allowed by the clock's frequency
+    /// ranges.
+    ///
+    /// Ranges that aren't a single frequency are reduced to the common sample rates within
+    /// them.
+    pub fn resolve_clock(&mut self, ranges: &[FrequencyRange]) {
+        if let SampleRates::Clock { .. } = self.sample_rates {
+            let mut rates = Vec::new();
+
+            for range in ranges {
+                if range.min == range.max {
+                    rates.push(range.min);
+                }
+                else {
+                    rates.extend(STANDARD_SAMPLE_RATES.iter().cloned().filter(|&rate| range.contains(rate)));
+                }
+            }
+
+            rates.sort();
+            rates.dedup();
+
+            self.sample_rates = SampleRates::Discrete(rates);
+        }
+    }
+
+    /// Returns the selectable combinations of this format.
+    ///
+    /// A continuous range yields the common sample rates within it. A format whose rates are
+    /// given by a clock yields nothing until [`resolve_clock`](#method.resolve_clock) is called.
+    pub fn choices(&self) -> Vec<FormatChoice> {
+        let rates = match self.sample_rates {
+            SampleRates::Discrete(ref rates) => rates.clone(),
+            SampleRates::Continuous { min, max } => {
+                STANDARD_SAMPLE_RATES.iter().cloned().filter(|&rate| rate >= min && rate <= max).collect()
+            },
+            SampleRates::Clock { .. } => Vec::new(),
+        };
+
+        rates.into_iter().map(|sample_rate| {
+            FormatChoice {
+                sample_rate,
+                channels: self.channels,
+                bit_depth: self.bit_depth,
+                interface: self.interface,
+                alt_setting: self.alt_setting,
+            }
+        }).collect()
+    }
+}
+
+/// Lists the type I audio formats offered by the AudioStreaming interfaces of a configuration.
+pub fn audio_formats(config: &ConfigDescriptor) -> Vec<AudioFormat> {
+    let settings = config.interfaces().flat_map(|interface| interface.descriptors()).collect::<Vec<_>>();
+
+    settings.iter().filter(|setting| {
+        setting.class_code() == ClassCode::Audio && setting.sub_class_code() == SUBCLASS_AUDIO_STREAMING
+    }).filter_map(|setting| parse_format(setting, &settings)).collect()
+}
+
+fn parse_format(setting: &InterfaceDescriptor, settings: &[InterfaceDescriptor]) -> Option<AudioFormat> {
+    let version = if setting.protocol_code() == PROTOCOL_UAC2 { UacVersion::V2 } else { UacVersion::V1 };
+
+    let mut terminal_link = None;
+    let mut channels = None;
+    let mut format = None;
+
+    for (descriptor_type, payload) in setting.extra_descriptors() {
+        if descriptor_type != CS_INTERFACE || payload.len() < 2 {
+            continue;
+        }
+
+        match (payload[0], version) {
+            (AS_GENERAL, UacVersion::V1) => {
+                terminal_link = Some(payload[1]);
+            },
+            (AS_GENERAL, UacVersion::V2) if payload.len() >= 9 => {
+                terminal_link = Some(payload[1]);
+                channels = Some(payload[8]);
+            },
+            (AS_FORMAT_TYPE, UacVersion::V1) if payload.len() >= 6 && payload[1] == FORMAT_TYPE_I => {
+                let rates = &payload[6..];
+
+                let sample_rates = match payload[5] {
+                    0 if rates.len() >= 6 => SampleRates::Continuous { min: read_u24(rates, 0), max: read_u24(rates, 3) },
+                    0 => continue,
+                    n => SampleRates::Discrete(rates.chunks(3).take(n as usize).filter(|rate| rate.len() == 3).map(|rate| read_u24(rate, 0)).collect()),
+                };
+
+                channels = Some(payload[2]);
+                format = Some((payload[3], payload[4], sample_rates));
+            },
+            (AS_FORMAT_TYPE, UacVersion::V2) if payload.len() >= 4 && payload[1] == FORMAT_TYPE_I => {
+                format = Some((payload[2], payload[3], SampleRates::Discrete(Vec::new())));
+            },
+            _ => {},
+        }
+    }
+
+    let (subframe_size, bit_depth, mut sample_rates) = format?;
+
+    if version == UacVersion::V2 {
+        sample_rates = clock_of_terminal(settings, terminal_link?)?;
+    }
+
+    let endpoint = setting.endpoint_descriptors().find(|endpoint| {
+        endpoint.transfer_type() == TransferType::Isochronous && endpoint.usage_type() != UsageType::Feedback
+    }).map(|endpoint| endpoint.address());
+
+    Some(AudioFormat {
+        version,
+        interface: setting.interface_number(),
+        alt_setting: setting.setting_number(),
+        endpoint,
+        channels: channels?,
+        subframe_size,
+        bit_depth,
+        sample_rates,
+    })
+}
+
+/// Finds the clock entity referenced by a UAC 2.0 terminal.
+fn clock_of_terminal(settings: &[InterfaceDescriptor], terminal_id: u8) -> Option<SampleRates> {
+    settings.iter().filter(|setting| {
+        setting.class_code() == ClassCode::Audio && setting.sub_class_code() == SUBCLASS_AUDIO_CONTROL
+    }).filter_map(|setting| {
+        setting.extra_descriptors().filter_map(|(descriptor_type, payload)| {
+            if descriptor_type != CS_INTERFACE || payload.len() < 2 || payload[1] != terminal_id {
+                return None;
+            }
+
+            match payload[0] {
+                AC_INPUT_TERMINAL if payload.len() >= 6 => Some(payload[5]),
+                AC_OUTPUT_TERMINAL if payload.len() >= 7 => Some(payload[6]),
+                _ => None,
+            }
+        }).next().map(|clock_id| {
+            SampleRates::Clock { control_interface: setting.interface_number(), clock_id }
+        })
+    }).next()
+}
+
+/// Selects the sample rate of an audio format.
+///
+/// For UAC 1.0 this sends a `SET_CUR` sampling frequency request to the format's endpoint. For
+/// UAC 2.0 it sets the frequency of the clock that drives the format's terminal. Select the
+/// format's alternate setting before calling this, since some devices reset the rate when the
+/// setting changes.
+///
+/// The future resolves to `Err(Error::InvalidParam)` if the format has no endpoint (UAC 1.0)
+/// or no clock (UAC 2.0).
+pub fn set_sample_rate(handle: &DeviceHandle, format: &AudioFormat, sample_rate: u32) -> ControlFuture<usize> {
+    match (format.version, &format.sample_rates, format.endpoint) {
+        (UacVersion::V2, &SampleRates::Clock { control_interface, clock_id }, _) => {
+            set_clock_frequency(handle, control_interface, clock_id, sample_rate)
+        },
+        (UacVersion::V1, _, Some(endpoint)) => {
+            let rate = sample_rate.to_le_bytes();
+
+            control::write(handle,
+                           request_type(Direction::Out, RequestType::Class, Recipient::Endpoint),
+                           SET_CUR,
+                           (SAMPLING_FREQ_CONTROL as u16) << 8,
+                           endpoint as u16,
+                           &rate[..3])
+        },
+        _ => control::failed(Error::InvalidParam, control::written),
+    }
+}
+
+/// Sets the frequency of a UAC 2.0 clock source.
+pub fn set_clock_frequency(handle: &DeviceHandle, control_interface: u8, clock_id: u8, frequency: u32) -> ControlFuture<usize> {
+    control::write(handle,
+                   request_type(Direction::Out, RequestType::Class, Recipient::Interface),
+                   SET_CUR,
+                   (SAMPLING_FREQ_CONTROL as u16) << 8,
+                   (clock_id as u16) << 8 | control_interface as u16,
+                   &frequency.to_le_bytes())
+}
+
+/// Reads the frequency ranges supported by a UAC 2.0 clock source.
+pub fn clock_frequency_ranges(handle: &DeviceHandle, control_interface: u8, clock_id: u8) -> ControlFuture<Vec<FrequencyRange>> {
+    control::read(handle,
+                  request_type(Direction::In, RequestType::Class, Recipient::Interface),
+                  RANGE,
+                  (SAMPLING_FREQ_CONTROL as u16) << 8,
+                  (clock_id as u16) << 8 | control_interface as u16,
+                  MAX_RANGES_LENGTH,
+                  parse_ranges)
+}
+
+fn parse_ranges(data: &[u8]) -> ::Result<Vec<FrequencyRange>> {
+    if data.len() < 2 {
+        return Err(Error::Io);
+    }
+
+    let count = (data[0] as u16 | (data[1] as u16) << 8) as usize;
+
+    Ok(data[2..].chunks(12).take(count).filter(|range| range.len() == 12).map(|range| {
+        FrequencyRange {
+            min: read_u32(range, 0),
+            max: read_u32(range, 4),
+            resolution: read_u32(range, 8),
+        }
+    }).collect())
+}
+
+fn read_u24(bytes: &[u8], offset: usize) -> u32 {
+    bytes[offset] as u32 | (bytes[offset + 1] as u32) << 8 | (bytes[offset + 2] as u32) << 16
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    read_u24(bytes, offset) | (bytes[offset + 3] as u32) << 24
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use parse::parse_config_descriptor;
+
+    const UAC1_CONFIG: [u8; 82] = [
+        0x09, 0x02, 0x52, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32,
+        0x09, 0x04, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00,
+        0x09, 0x24, 0x01, 0x00, 0x01, 0x1E, 0x00, 0x01, 0x01,
+        0x09, 0x04, 0x01, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00,
+        0x09, 0x04, 0x01, 0x01, 0x01, 0x01, 0x02, 0x00, 0x00,
+        0x07, 0x24, 0x01, 0x01, 0x01, 0x01, 0x00,
+        0x0E, 0x24, 0x02, 0x01, 0x02, 0x02, 0x10, 0x02, 0x44, 0xAC, 0x00, 0x80, 0xBB, 0x00,
+        0x09, 0x05, 0x01, 0x09, 0xC0, 0x00, 0x01, 0x00, 0x00,
+        0x07, 0x25, 0x01, 0x01, 0x00, 0x00, 0x00,
+    ];
+
+    const UAC2_CONFIG: [u8; 107] = [
+        0x09, 0x02, 0x6B, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32,
+        0x09, 0x04, 0x00, 0x00, 0x00, 0x01, 0x01, 0x20, 0x00,
+        0x09, 0x24, 0x01, 0x00, 0x02, 0x08, 0x2E, 0x00, 0x00,
+        0x08, 0x24, 0x0A, 0x29, 0x03, 0x07, 0x00, 0x00,
+        0x11, 0x24, 0x02, 0x01, 0x01, 0x01, 0x00, 0x29, 0x02, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x09, 0x04, 0x01, 0x00, 0x00, 0x01, 0x02, 0x20, 0x00,
+        0x09, 0x04, 0x01, 0x01, 0x01, 0x01, 0x02, 0x20, 0x00,
+        0x10, 0x24, 0x01, 0x01, 0x00, 0x01, 0x01, 0x00, 0x00, 0x00, 0x02, 0x03, 0x00, 0x00, 0x00, 0x00,
+        0x06, 0x24, 0x02, 0x01, 0x03, 0x18,
+        0x07, 0x05, 0x01, 0x05, 0x00, 0x02, 0x01,
+        0x08, 0x25, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn it_lists_uac1_formats() {
+        let config = parse_config_descriptor(&UAC1_CONFIG).unwrap();
+
+        assert_eq!(vec![AudioFormat {
+            version: UacVersion::V1,
+            interface: 1,
+            alt_setting: 1,
+            endpoint: Some(0x01),
+            channels: 2,
+            subframe_size: 2,
+            bit_depth: 16,
+            sample_rates: SampleRates::Discrete(vec![44_100, 48_000]),
+        }], audio_formats(&config));
+    }
+
+    #[test]
+    fn it_lists_uac1_choices() {
+        let config = parse_config_descriptor(&UAC1_CONFIG).unwrap();
+        let choices = audio_formats(&config)[0].choices();
+
+        assert_eq!(vec![44_100, 48_000], choices.iter().map(|c| c.sample_rate).collect::<Vec<_>>());
+        assert_eq!(FormatChoice { sample_rate: 48_000, channels: 2, bit_depth: 16, interface: 1, alt_setting: 1 }, choices[1]);
+    }
+
+    #[test]
+    fn it_finds_uac2_clock() {
+        let config = parse_config_descriptor(&UAC2_CONFIG).unwrap();
+        let formats = audio_formats(&config);
+
+        assert_eq!(1, formats.len());
+        assert_eq!(UacVersion::V2, formats[0].version);
+        assert_eq!(2, formats[0].channels);
+        assert_eq!(24, formats[0].bit_depth);
+        assert_eq!(SampleRates::Clock { control_interface: 0, clock_id: 0x29 }, formats[0].sample_rates);
+        assert!(formats[0].choices().is_empty());
+    }
+
+    #[test]
+    fn it_resolves_clock_ranges() {
+        let config = parse_config_descriptor(&UAC2_CONFIG).unwrap();
+        let mut format = audio_formats(&config).remove(0);
+
+        format.resolve_clock(&[
+            FrequencyRange { min: 44_100, max: 44_100, resolution: 0 },
+            FrequencyRange { min: 48_000, max: 192_000, resolution: 48_000 },
+        ]);
+
+        assert_eq!(SampleRates::Discrete(vec![44_100, 48_000, 96_000, 192_000]), format.sample_rates);
+    }
+
+    #[test]
+    fn it_uses_standard_rates_in_continuous_range() {
+        let format = AudioFormat {
+            version: UacVersion::V1,
+            interface: 1,
+            alt_setting: 1,
+            endpoint: None,
+            channels: 1,
+            subframe_size: 2,
+            bit_depth: 16,
+            sample_rates: SampleRates::Continuous { min: 16_000, max: 32_000 },
+        };
+
+        assert_eq!(vec![16_000, 22_050, 24_000, 32_000], format.choices().iter().map(|c| c.sample_rate).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn it_parses_frequency_ranges() {
+        let data = [0x01, 0x00, 0x44, 0xAC, 0x00, 0x00, 0x80, 0xBB, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+        assert_eq!(vec![FrequencyRange { min: 44_100, max: 48_000, resolution: 0 }], parse_ranges(&data).unwrap());
+    }
+}
@@ -0,0 +1,468 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task;
+use std::time::Duration;
+
+use futures_core::Stream;
+use futures_sink::Sink;
+
+use config_descriptor::ConfigDescriptor;
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::{Direction, Speed, SyncType, TransferType, UsageType};
+use transfer::TransferFuture;
+
+use super::AudioFormat;
+
+/// Number of transfers kept in flight.
+const TRANSFERS: usize = 4;
+
+/// Number of packets in each isochronous transfer.
+const ISO_PACKETS: u32 = 8;
+
+/// Largest deviation from the nominal rate that is accepted from a feedback endpoint, as a
+/// fraction of the nominal rate.
+const MAX_FEEDBACK_DEVIATION: u64 = 8;
+
+/// Splits a sample rate into the number of samples to send in each packet.
+///
+/// Rates are 16.16 fixed point samples per packet. The fraction is carried over between packets
+/// so that the average matches the rate.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+struct PacketSizer {
+    nominal: u64,
+    rate: u64,
+    remainder: u64,
+    frame_size: usize,
+}
+
+impl PacketSizer {
+    fn new(sample_rate: u32, interval: Duration, frame_size: usize) -> PacketSizer {
+        let nominal = (((sample_rate as u128 * interval.as_nanos()) << 16) + 500_000_000) / 1_000_000_000;
+        PacketSizer { nominal: nominal as u64, rate: nominal as u64, remainder: 0, frame_size }
+    }
+
+    /// Returns the number of bytes in the next packet.
+    fn next_packet(&mut self) -> usize {
+        self.remainder += self.rate;
+        let samples = self.remainder >> 16;
+        self.remainder &= 0xFFFF;
+        samples as usize * self.frame_size
+    }
+
+    /// Adjusts the rate to a value read from a feedback endpoint.
+    ///
+    /// Values too far from the nominal rate are ignored, since they are more likely to be bad
+    /// feedback than real clock drift.
+    fn set_rate(&mut self, rate: u64) {
+        let tolerance = self.nominal / MAX_FEEDBACK_DEVIATION;
+
+        if rate >= self.nominal - tolerance && rate <= self.nominal + tolerance {
+            self.rate = rate;
+        }
+    }
+}
+
+/// Decodes a feedback value to 16.16 fixed point samples per frame or microframe.
+///
+/// Full speed endpoints send a 10.14 value in three bytes and high speed endpoints a 16.16 value
+/// in four bytes.
+fn decode_feedback(data: &[u8]) -> Option<u64> {
+    match data.len() {
+        3 => Some((u32::from_le_bytes([data[0], data[1], data[2], 0]) as u64) << 2),
+        4 => Some(u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as u64),
+        _ => None,
+    }
+}
+
+struct Feedback {
+    endpoint: u8,
+    packet_size: usize,
+    frames_per_packet: u64,
+    pending: Option<TransferFuture>,
+}
+
+/// A stream of PCM audio on an AudioStreaming interface.
+///
+/// Capture streams (with an IN endpoint) implement `Stream`, yielding a buffer of interleaved
+/// samples for each completed transfer. Playback streams (with an OUT endpoint) implement
+/// `Sink`, accepting buffers of interleaved samples that are split into packets at the sample
+/// rate. Using the other direction fails with `NotSupported`.
+///
+/// If the format's endpoint is asynchronous and the setting has a feedback endpoint, the rate
+/// of a playback stream follows the feedback from the device, so that its clock drift doesn't
+/// cause buffer under- or overruns.
+///
+/// Dropping the stream cancels the transfers and selects alternate setting 0.
+pub struct AudioStream<'a> {
+    handle: &'a mut DeviceHandle,
+    interface: u8,
+    endpoint: u8,
+    direction: Direction,
+    packet_capacity: usize,
+    sizer: PacketSizer,
+    feedback: Option<Feedback>,
+    transfers: VecDeque<TransferFuture>,
+    buffer: Vec<u8>,
+    done: bool,
+}
+
+impl<'a> AudioStream<'a> {
+    /// Selects the alternate setting of `format` and prepares the stream.
+    ///
+    /// The interface must already be claimed.
+    ///
+    /// `speed` is the speed of the device, which gives the packet interval. No transfers are
+    /// submitted until the stream is first polled, so the sample rate can be set with
+    /// [`set_sample_rate`](fn.set_sample_rate.html) in between.
+    ///
+    /// ## Errors
+    ///
+    /// * `NotFound` if the format's setting or endpoint isn't in `config`.
+    /// * `InvalidParam` if `sample_rate` doesn't fit in the endpoint's packets.
+    /// * Any error returned while selecting the alternate setting.
+    pub fn open(handle: &'a mut DeviceHandle, config: &ConfigDescriptor, format: &AudioFormat, sample_rate: u32, speed: Speed) -> ::Result<AudioStream<'a>> {
+        let address = format.endpoint.ok_or(Error::NotFound)?;
+
+        let (direction, packet_capacity, interval, asynchronous, feedback) = {
+            let setting = config.interfaces()
+                .find(|i| i.number() == format.interface)
+                .and_then(|i| i.descriptors().find(|s| s.setting_number() == format.alt_setting))
+                .ok_or(Error::NotFound)?;
+            let endpoint = setting.endpoint_descriptors()
+                .find(|ep| ep.address() == address)
+                .ok_or(Error::NotFound)?;
+            let feedback = setting.endpoint_descriptors()
+                .find(|ep| ep.transfer_type() == TransferType::Isochronous && ep.usage_type() == UsageType::Feedback)
+                .map(|ep| (ep.address(), ep.max_bytes_per_interval()));
+
+            (endpoint.direction(),
+             endpoint.max_bytes_per_interval(),
+             endpoint.interval_duration(speed).ok_or(Error::InvalidParam)?,
+             endpoint.sync_type() == SyncType::Asynchronous,
+             feedback)
+        };
+
+        let frame_size = format.channels as usize * format.subframe_size as usize;
+        let sizer = PacketSizer::new(sample_rate, interval, frame_size);
+
+        // Leave room for one extra sample per packet
+        if frame_size == 0 || (sizer.rate >> 16) as usize * frame_size + frame_size > packet_capacity {
+            return Err(Error::InvalidParam);
+        }
+
+        let frame_duration = match speed {
+            Speed::Low | Speed::Full => Duration::from_millis(1),
+            _ => Duration::from_micros(125),
+        };
+
+        let feedback = match (direction, asynchronous, feedback) {
+            (Direction::Out, true, Some((endpoint, packet_size))) => Some(Feedback {
+                endpoint,
+                packet_size,
+                frames_per_packet: (interval.as_nanos() / frame_duration.as_nanos()).max(1) as u64,
+                pending: None,
+            }),
+            _ => None,
+        };
+
+        handle.set_alternate_setting(format.interface, format.alt_setting)?;
+
+        Ok(AudioStream {
+            handle,
+            interface: format.interface,
+            endpoint: address,
+            direction,
+            packet_capacity,
+            sizer,
+            feedback,
+            transfers: VecDeque::with_capacity(TRANSFERS),
+            buffer: Vec::new(),
+            done: false,
+        })
+    }
+
+    /// Returns the handle of the device.
+    pub fn handle(&self) -> &DeviceHandle {
+        self.handle
+    }
+
+    /// Returns the direction of the stream: `In` for capture and `Out` for playback.
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    fn submit_read(&self) -> ::Result<TransferFuture> {
+        let mut transfer = self.handle.alloc_transfer(ISO_PACKETS)?;
+        transfer.fill_iso_read(self.endpoint.into(), self.packet_capacity)?;
+        Ok(transfer.submit())
+    }
+
+    /// Submits a transfer with the buffered samples.
+    ///
+    /// Unless `partial` is set, nothing is sent until the buffer fills a whole transfer.
+    fn submit_write(&mut self, partial: bool) -> ::Result<bool> {
+        let mut sizer = self.sizer;
+        let mut lengths = Vec::with_capacity(ISO_PACKETS as usize);
+        let mut total = 0;
+
+        while lengths.len() < ISO_PACKETS as usize && total < self.buffer.len() {
+            let length = sizer.next_packet();
+
+            if total + length > self.buffer.len() {
+                if !partial {
+                    return Ok(false);
+                }
+
+                lengths.push(self.buffer.len() - total);
+                total = self.buffer.len();
+                break;
+            }
+
+            lengths.push(length);
+            total += length;
+        }
+
+        if total == 0 || (lengths.len() < ISO_PACKETS as usize && !partial) {
+            return Ok(false);
+        }
+
+        let mut transfer = self.handle.alloc_transfer(ISO_PACKETS)?;
+        transfer.fill_iso_write(self.endpoint.into(), &self.buffer[..total], &lengths)?;
+        self.transfers.push_back(transfer.submit());
+        self.buffer.drain(..total);
+        self.sizer = sizer;
+
+        Ok(true)
+    }
+
+    /// Keeps a feedback read in flight and applies the values it returns.
+    fn poll_feedback(&mut self, cx: &mut task::Context) -> ::Result<()> {
+        let feedback = match self.feedback {
+            Some(ref mut feedback) => feedback,
+            None => return Ok(()),
+        };
+
+        loop {
+            if feedback.pending.is_none() {
+                let mut transfer = self.handle.alloc_transfer(1)?;
+                transfer.fill_iso_read(feedback.endpoint.into(), feedback.packet_size)?;
+                feedback.pending = Some(transfer.submit());
+            }
+
+            let result = match feedback.pending {
+                Some(ref mut future) => match Pin::new(future).poll(cx) {
+                    task::Poll::Pending => return Ok(()),
+                    task::Poll::Ready(result) => result,
+                },
+                None => unreachable!(),
+            };
+
+            feedback.pending = None;
+
+            let transfer = result?;
+            transfer.check_status()?;
+
+            for packet in transfer.iso_packets() {
+                if let (Ok(()), Some(rate)) = (packet.status().to_result(), decode_feedback(packet.data())) {
+                    self.sizer.set_rate(rate * feedback.frames_per_packet);
+                }
+            }
+        }
+    }
+
+    /// Waits until at most `limit` transfers are in flight.
+    fn poll_transfers(&mut self, cx: &mut task::Context, limit: usize) -> task::Poll<::Result<()>> {
+        if let Err(e) = self.poll_feedback(cx) {
+            return task::Poll::Ready(Err(e));
+        }
+
+        while self.transfers.len() > limit {
+            let result = match self.transfers.front_mut() {
+                Some(future) => match Pin::new(future).poll(cx) {
+                    task::Poll::Pending => return task::Poll::Pending,
+                    task::Poll::Ready(result) => result,
+                },
+                None => unreachable!(),
+            };
+
+            self.transfers.pop_front();
+            result.and_then(|transfer| transfer.check_status())?;
+        }
+
+        task::Poll::Ready(Ok(()))
+    }
+}
+
+impl<'a> Drop for AudioStream<'a> {
+    fn drop(&mut self) {
+        self.transfers.clear();
+        self.feedback = None;
+        let _ = self.handle.set_alternate_setting(self.interface, 0);
+    }
+}
+
+impl<'a> Stream for AudioStream<'a> {
+    type Item = ::Result<Vec<u8>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.done {
+            return task::Poll::Ready(None);
+        }
+
+        if this.direction != Direction::In {
+            this.done = true;
+            return task::Poll::Ready(Some(Err(Error::NotSupported)));
+        }
+
+        loop {
+            while this.transfers.len() < TRANSFERS {
+                match this.submit_read() {
+                    Ok(future) => this.transfers.push_back(future),
+                    Err(e) => {
+                        this.done = true;
+                        this.transfers.clear();
+                        return task::Poll::Ready(Some(Err(e)));
+                    },
+                }
+            }
+
+            let result = match this.transfers.front_mut() {
+                Some(future) => match Pin::new(future).poll(cx) {
+                    task::Poll::Pending => return task::Poll::Pending,
+                    task::Poll::Ready(result) => result,
+                },
+                None => unreachable!(),
+            };
+
+            this.transfers.pop_front();
+
+            match result.and_then(|transfer| transfer.check_status().map(|_| transfer)) {
+                Ok(transfer) => {
+                    let samples = transfer.iso_packets().iter()
+                        .filter(|packet| packet.status().to_result().is_ok())
+                        .flat_map(|packet| packet.data().iter().cloned())
+                        .collect::<Vec<_>>();
+
+                    // Resubmit before handing out the samples to keep the ring full
+                    if let Ok(future) = this.submit_read() {
+                        this.transfers.push_back(future);
+                    }
+
+                    if !samples.is_empty() {
+                        return task::Poll::Ready(Some(Ok(samples)));
+                    }
+                },
+                Err(e) => {
+                    this.done = true;
+                    this.transfers.clear();
+                    return task::Poll::Ready(Some(Err(e)));
+                },
+            }
+        }
+    }
+}
+
+impl<'a> Sink<Vec<u8>> for AudioStream<'a> {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<::Result<()>> {
+        let this = self.get_mut();
+
+        if this.direction != Direction::Out {
+            return task::Poll::Ready(Err(Error::NotSupported));
+        }
+
+        loop {
+            match this.poll_transfers(cx, TRANSFERS - 1) {
+                task::Poll::Ready(Ok(())) => {},
+                poll => return poll,
+            }
+
+            match this.submit_write(false) {
+                Ok(true) => {},
+                Ok(false) => return task::Poll::Ready(Ok(())),
+                Err(e) => return task::Poll::Ready(Err(e)),
+            }
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, samples: Vec<u8>) -> ::Result<()> {
+        let this = self.get_mut();
+
+        if this.direction != Direction::Out {
+            return Err(Error::NotSupported);
+        }
+
+        this.buffer.extend_from_slice(&samples);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<::Result<()>> {
+        let this = self.get_mut();
+
+        if this.direction != Direction::Out {
+            return task::Poll::Ready(Err(Error::NotSupported));
+        }
+
+        loop {
+            match this.poll_transfers(cx, TRANSFERS - 1) {
+                task::Poll::Ready(Ok(())) => {},
+                poll => return poll,
+            }
+
+            match this.submit_write(true) {
+                Ok(true) => {},
+                Ok(false) => return this.poll_transfers(cx, 0),
+                Err(e) => return task::Poll::Ready(Err(e)),
+            }
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_spreads_fractional_rate_over_packets() {
+        let mut sizer = PacketSizer::new(44_100, Duration::from_millis(1), 4);
+        let lengths = (0..10).map(|_| sizer.next_packet()).collect::<Vec<_>>();
+
+        assert_eq!(44 * 4, lengths[0]);
+        assert_eq!(441 * 4, lengths.iter().sum::<usize>());
+    }
+
+    #[test]
+    fn it_sizes_high_speed_packets() {
+        let mut sizer = PacketSizer::new(48_000, Duration::from_micros(125), 6);
+        assert_eq!(6 * 6, sizer.next_packet());
+    }
+
+    #[test]
+    fn it_follows_feedback_within_tolerance() {
+        let mut sizer = PacketSizer::new(48_000, Duration::from_millis(1), 4);
+
+        sizer.set_rate(49 << 16);
+        assert_eq!(49 * 4, sizer.next_packet());
+
+        sizer.set_rate(96 << 16);
+        assert_eq!(49 * 4, sizer.next_packet());
+    }
+
+    #[test]
+    fn it_decodes_feedback_formats() {
+        // 48.0 samples per frame as 10.14 and as 16.16
+        assert_eq!(Some(48 << 16), decode_feedback(&[0x00, 0x00, 0x0C]));
+        assert_eq!(Some(6 << 16), decode_feedback(&[0x00, 0x00, 0x06, 0x00]));
+        assert_eq!(None, decode_feedback(&[0x00, 0x00]));
+    }
+}
@@ -0,0 +1,355 @@
+//! What udev knows about USB devices on Linux, with the `udev` feature.
+//!
+//! udev keeps more about a device than its descriptors: the serial number it was identified
+//! by, the stable `/dev/.../by-id` links made for it and its interfaces, the drivers bound to
+//! them and who may open its device node. [`Device::udev_info`](../struct.Device.html#method.udev_info)
+//! joins a device with those, and [`Context::udev_events`](../struct.Context.html#method.udev_events)
+//! reports devices as udev sees them connected and disconnected, as an alternative to the
+//! hotplug events of `libusb`.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::task;
+use std::thread::{self, JoinHandle};
+
+use futures_core::Stream;
+use libc::{self, pollfd, POLLIN};
+use libudev::{self, Enumerator, EventType, MonitorBuilder};
+
+use context::Context;
+use device::Device;
+use error::Error;
+
+// How long the monitor thread waits for an event before it checks whether to stop
+const MONITOR_INTERVAL_MS: libc::c_int = 100;
+
+/// The permissions of the device node of a device.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub struct NodePermissions {
+    /// The permission bits, e.g., `0o664`.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// What udev knows about a USB device.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct UdevInfo {
+    pub bus_number: u8,
+    pub address: u8,
+
+    /// The path of the device in sysfs, e.g., `/sys/devices/pci0000:00/0000:00:14.0/usb1/1-2`.
+    pub syspath: PathBuf,
+
+    /// The device node, e.g., `/dev/bus/usb/001/004`.
+    pub devnode: Option<PathBuf>,
+
+    /// The `ID_SERIAL` property, the vendor, model and serial number udev identified the
+    /// device by, e.g., `FTDI_FT232R_USB_UART_A50285BI`.
+    pub serial: Option<String>,
+
+    /// The `by-id` links to the device and to the nodes of its interfaces, e.g.,
+    /// `/dev/serial/by-id/usb-FTDI_FT232R_USB_UART_A50285BI-if00-port0`.
+    pub by_id: Vec<PathBuf>,
+
+    /// The driver bound to the device, usually `usb`.
+    pub driver: Option<String>,
+
+    /// The drivers bound to the interfaces of the device, by interface number.
+    pub interface_drivers: Vec<(u8, String)>,
+
+    /// The permissions of the device node, `None` if it is gone or can't be inspected.
+    pub permissions: Option<NodePermissions>,
+
+    /// Every property of the device, including those added by udev rules.
+    pub properties: BTreeMap<String, String>,
+}
+
+impl UdevInfo {
+    /// Returns the device of a context that this is about.
+    ///
+    /// Fails with `NotFound` if the context doesn't list the device, e.g., as `libusb` hasn't
+    /// seen it yet when it has just arrived.
+    pub fn device(&self, context: &Context) -> ::Result<Device> {
+        context.devices()?.iter()
+            .find(|device| (device.bus_number(), device.address()) == (self.bus_number, self.address))
+            .ok_or(Error::NotFound)
+    }
+}
+
+fn from_io(error: io::Error) -> Error {
+    match error.kind() {
+        io::ErrorKind::PermissionDenied => Error::Access,
+        io::ErrorKind::NotFound => Error::NotFound,
+        _ => Error::Io,
+    }
+}
+
+fn string(value: Option<&OsStr>) -> Option<String> {
+    value.map(|value| value.to_string_lossy().into_owned())
+}
+
+/// Parses the `BUSNUM` and `DEVNUM` properties, which are zero padded, e.g., `001`.
+fn location(bus_number: Option<&OsStr>, address: Option<&OsStr>) -> Option<(u8, u8)> {
+    let parse = |value: Option<&OsStr>| value?.to_str()?.trim().parse::<u8>().ok();
+    Some((parse(bus_number)?, parse(address)?))
+}
+
+/// Picks the `by-id` links from the space separated links of a `DEVLINKS` property.
+fn by_id_links(devlinks: &str) -> impl Iterator<Item = PathBuf> + '_ {
+    devlinks.split_whitespace().filter(|link| link.contains("/by-id/")).map(PathBuf::from)
+}
+
+/// Collects what udev knows about a USB device. The interfaces, and the nodes made for them,
+/// are only looked for while the device is connected.
+fn info_of(device: &libudev::Device, connected: bool) -> Option<UdevInfo> {
+    let (bus_number, address) = location(device.property_value("BUSNUM"), device.property_value("DEVNUM"))?;
+    let devnode = device.devnode().map(PathBuf::from);
+
+    let mut by_id: Vec<PathBuf> = string(device.property_value("DEVLINKS"))
+        .map(|devlinks| by_id_links(&devlinks).collect())
+        .unwrap_or_default();
+    let mut interface_drivers = Vec::new();
+    if connected {
+        // The parent matches itself too, which was already looked at
+        let descendants = Enumerator::new()
+            .and_then(|mut enumerator| {
+                enumerator.match_parent(device)?;
+                enumerator.scan_devices().map(|devices| devices.collect::<Vec<_>>())
+            })
+            .unwrap_or_default();
+        for descendant in descendants.iter().filter(|descendant| descendant.syspath() != device.syspath()) {
+            if let Some(devlinks) = string(descendant.property_value("DEVLINKS")) {
+                by_id.extend(by_id_links(&devlinks));
+            }
+            if descendant.devtype() == Some(OsStr::new("usb_interface")) {
+                let number = descendant.attribute_value("bInterfaceNumber")
+                    .and_then(|number| u8::from_str_radix(number.to_str()?.trim(), 16).ok());
+                if let (Some(number), Some(driver)) = (number, string(descendant.driver())) {
+                    interface_drivers.push((number, driver));
+                }
+            }
+        }
+        by_id.sort();
+        interface_drivers.sort();
+    }
+
+    let permissions = devnode.as_ref().filter(|_| connected)
+        .and_then(|devnode| fs::metadata(devnode).ok())
+        .map(|metadata| NodePermissions { mode: metadata.mode() & 0o7777, uid: metadata.uid(), gid: metadata.gid() });
+
+    Some(UdevInfo {
+        bus_number,
+        address,
+        syspath: device.syspath().to_owned(),
+        devnode,
+        serial: string(device.property_value("ID_SERIAL")),
+        by_id,
+        driver: string(device.driver()),
+        interface_drivers,
+        permissions,
+        properties: device.properties()
+            .map(|entry| (entry.name().to_string_lossy().into_owned(), entry.value().to_string_lossy().into_owned()))
+            .collect(),
+    })
+}
+
+/// Enumerates the USB devices that udev knows.
+fn usb_devices() -> io::Result<Vec<libudev::Device>> {
+    let mut enumerator = Enumerator::new()?;
+    enumerator.match_subsystem("usb")?;
+    enumerator.match_property("DEVTYPE", "usb_device")?;
+    Ok(enumerator.scan_devices()?.collect())
+}
+
+/// Finds what udev knows about the device at an address of a bus.
+pub fn lookup(bus_number: u8, address: u8) -> ::Result<UdevInfo> {
+    usb_devices().map_err(from_io)?.iter()
+        .find(|device| {
+            location(device.property_value("BUSNUM"), device.property_value("DEVNUM")) == Some((bus_number, address))
+        })
+        .and_then(|device| info_of(device, true))
+        .ok_or(Error::NotFound)
+}
+
+/// A device that udev saw connected or disconnected, as reported by
+/// [`UdevEvents`](struct.UdevEvents.html).
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum UdevEvent {
+    /// The device was connected, or was already connected when the events were registered.
+    ///
+    /// udev reports the device before the drivers of its interfaces are bound, so its
+    /// `interface_drivers` and the `by_id` links of its interfaces are usually missing yet.
+    /// [`Device::udev_info`](../struct.Device.html#method.udev_info) finds them later.
+    Arrived(UdevInfo),
+
+    /// The device was disconnected. Only what udev reported with the event is known, without
+    /// the interfaces and the permissions of the device node.
+    Left(UdevInfo),
+}
+
+/// The events of the monitor thread, waiting to be polled.
+struct Queue {
+    state: Mutex<QueueState>,
+    stop: AtomicBool,
+}
+
+struct QueueState {
+    events: VecDeque<UdevEvent>,
+    waker: Option<task::Waker>,
+}
+
+impl Queue {
+    fn push(&self, event: UdevEvent) {
+        let waker = {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            state.events.push_back(event);
+            state.waker.take()
+        };
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    fn poll_next(&self, cx: &mut task::Context) -> task::Poll<UdevEvent> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        match state.events.pop_front() {
+            Some(event) => task::Poll::Ready(event),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                task::Poll::Pending
+            },
+        }
+    }
+}
+
+/// Runs on the monitor thread, which owns the udev monitor as it can't be sent between threads.
+fn monitor(queue: &Queue, started: mpsc::Sender<io::Result<()>>) {
+    let socket = match MonitorBuilder::new()
+        .and_then(|builder| builder.match_subsystem_devtype("usb", "usb_device"))
+        .and_then(|builder| builder.listen()) {
+        Ok(socket) => socket,
+        Err(e) => {
+            let _ = started.send(Err(e));
+            return;
+        },
+    };
+
+    // Listening already, so that a device connected meanwhile isn't missed
+    match usb_devices() {
+        Ok(devices) => {
+            let _ = started.send(Ok(()));
+            for info in devices.iter().filter_map(|device| info_of(device, true)) {
+                queue.push(UdevEvent::Arrived(info));
+            }
+        },
+        Err(e) => {
+            let _ = started.send(Err(e));
+            return;
+        },
+    }
+
+    let mut fds = [pollfd { fd: socket.as_raw_fd(), events: POLLIN, revents: 0 }];
+    while !queue.stop.load(Ordering::Acquire) {
+        if unsafe { libc::poll(fds.as_mut_ptr(), 1, MONITOR_INTERVAL_MS) } <= 0 {
+            continue;
+        }
+        for event in socket.iter() {
+            let event = match event.event_type() {
+                EventType::Add => info_of(&event, true).map(UdevEvent::Arrived),
+                EventType::Remove => info_of(&event, false).map(UdevEvent::Left),
+                _ => None,
+            };
+            if let Some(event) = event {
+                queue.push(event);
+            }
+        }
+    }
+}
+
+/// Stream of the devices that udev sees connected and disconnected, returned by
+/// [`Context::udev_events`](../struct.Context.html#method.udev_events).
+///
+/// The events are received on a thread of the stream, and queued until the stream is polled.
+/// The stream never ends. Dropping it stops the thread.
+pub struct UdevEvents {
+    queue: Arc<Queue>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Stream for UdevEvents {
+    type Item = UdevEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<UdevEvent>> {
+        self.queue.poll_next(cx).map(Some)
+    }
+}
+
+impl Drop for UdevEvents {
+    fn drop(&mut self) {
+        self.queue.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Starts monitoring udev, reporting the devices already connected first.
+pub fn events() -> ::Result<UdevEvents> {
+    let queue = Arc::new(Queue {
+        state: Mutex::new(QueueState { events: VecDeque::new(), waker: None }),
+        stop: AtomicBool::new(false),
+    });
+    let (started, start) = mpsc::channel();
+
+    let thread = {
+        let queue = queue.clone();
+        thread::Builder::new()
+            .name("udev-monitor".to_owned())
+            .spawn(move || monitor(&queue, started))
+            .map_err(from_io)?
+    };
+    match start.recv() {
+        Ok(Ok(())) => Ok(UdevEvents { queue, thread: Some(thread) }),
+        Ok(Err(e)) => {
+            let _ = thread.join();
+            Err(from_io(e))
+        },
+        Err(_) => {
+            let _ = thread.join();
+            Err(Error::Other)
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_parses_the_location_of_a_device() {
+        assert_eq!(Some((1, 4)), location(Some(OsStr::new("001")), Some(OsStr::new("004"))));
+        assert_eq!(None, location(Some(OsStr::new("001")), None));
+        assert_eq!(None, location(Some(OsStr::new("256")), Some(OsStr::new("004"))));
+    }
+
+    #[test]
+    fn it_picks_the_by_id_links() {
+        let devlinks = "/dev/serial/by-path/pci-0000:00:14.0-usb-0:2:1.0-port0 \
+                        /dev/serial/by-id/usb-FTDI_FT232R_USB_UART_A50285BI-if00-port0";
+        assert_eq!(vec![PathBuf::from("/dev/serial/by-id/usb-FTDI_FT232R_USB_UART_A50285BI-if00-port0")],
+                   by_id_links(devlinks).collect::<Vec<_>>());
+        assert_eq!(0, by_id_links("").count());
+    }
+}
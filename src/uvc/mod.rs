@@ -0,0 +1,422 @@
+//! USB Video Class (UVC) support.
+
+pub use self::stream::{VideoStream, Frame, FrameAssembler};
+
+mod stream;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task;
+
+use control::{self, ControlFuture};
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::{Direction, RequestType, Recipient, request_type};
+
+const SET_CUR: u8 = 0x01;
+const GET_CUR: u8 = 0x81;
+const GET_MIN: u8 = 0x82;
+const GET_MAX: u8 = 0x83;
+const GET_DEF: u8 = 0x87;
+
+const VS_PROBE_CONTROL: u8 = 0x01;
+const VS_COMMIT_CONTROL: u8 = 0x02;
+
+/// Revisions of the UVC specification, which differ in the size of the streaming control.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum UvcVersion {
+    /// UVC 1.0, with a 26-byte streaming control.
+    V1_0,
+
+    /// UVC 1.1, with a 34-byte streaming control.
+    V1_1,
+
+    /// UVC 1.5, with a 48-byte streaming control.
+    V1_5,
+}
+
+impl UvcVersion {
+    /// Returns the version matching a `bcdUVC` field from the class-specific VideoControl
+    /// interface header.
+    pub fn from_bcd(bcd_uvc: u16) -> UvcVersion {
+        match bcd_uvc {
+            0x0000..=0x0100 => UvcVersion::V1_0,
+            0x0101..=0x0149 => UvcVersion::V1_1,
+            _ => UvcVersion::V1_5,
+        }
+    }
+
+    /// Returns the length of the streaming control in bytes.
+    pub fn control_length(&self) -> u16 {
+        match *self {
+            UvcVersion::V1_0 => 26,
+            UvcVersion::V1_1 => 34,
+            UvcVersion::V1_5 => 48,
+        }
+    }
+}
+
+/// The probe and commit control of a VideoStreaming interface (`VS_PROBE_CONTROL` and
+/// `VS_COMMIT_CONTROL`).
+///
+/// Fields that don't exist in the UVC version used to decode the control are zero.
+#[derive(Debug,Default,PartialEq,Eq,Clone,Copy,Hash)]
+pub struct StreamingControl {
+    /// Fields that the device should keep fixed during negotiation (`bmHint`).
+    pub hint: u16,
+
+    /// Index of the video format descriptor (`bFormatIndex`).
+    pub format_index: u8,
+
+    /// Index of the video frame descriptor (`bFrameIndex`).
+    pub frame_index: u8,
+
+    /// Frame interval in 100 ns units (`dwFrameInterval`).
+    pub frame_interval: u32,
+
+    /// Key frame rate in key frames per video frame (`wKeyFrameRate`).
+    pub key_frame_rate: u16,
+
+    /// P-frame rate in P-frames per key frame (`wPFrameRate`).
+    pub p_frame_rate: u16,
+
+    /// Compression quality (`wCompQuality`).
+    pub comp_quality: u16,
+
+    /// Window size for average bit rate control (`wCompWindowSize`).
+    pub comp_window_size: u16,
+
+    /// Internal video streaming latency in milliseconds (`wDelay`).
+    pub delay: u16,
+
+    /// Maximum video frame or codec-specific segment size in bytes (`dwMaxVideoFrameSize`).
+    pub max_video_frame_size: u32,
+
+    /// Maximum number of bytes the device transmits in a single payload transfer
+    /// (`dwMaxPayloadTransferSize`).
+    pub max_payload_transfer_size: u32,
+
+    /// Device clock frequency in Hz (`dwClockFrequency`). UVC 1.1 and later.
+    pub clock_frequency: u32,
+
+    /// Framing information for payload headers (`bmFramingInfo`). UVC 1.1 and later.
+    pub framing_info: u8,
+
+    /// Preferred payload format version (`bPreferedVersion`). UVC 1.1 and later.
+    pub preferred_version: u8,
+
+    /// Minimum supported payload format version (`bMinVersion`). UVC 1.1 and later.
+    pub min_version: u8,
+
+    /// Maximum supported payload format version (`bMaxVersion`). UVC 1.1 and later.
+    pub max_version: u8,
+
+    /// Usage of the stream (`bUsage`). UVC 1.5 only.
+    pub usage: u8,
+
+    /// Bit depth of the luma component (`bBitDepthLuma`). UVC 1.5 only.
+    pub bit_depth_luma: u8,
+
+    /// Flags for the stream (`bmSettings`). UVC 1.5 only.
+    pub settings: u8,
+
+    /// Maximum number of reference frames plus one (`bMaxNumberOfRefFramesPlus1`). UVC 1.5
+    /// only.
+    pub max_number_of_ref_frames_plus1: u8,
+
+    /// Rate control modes for up to four simulcast streams (`bmRateControlModes`). UVC 1.5
+    /// only.
+    pub rate_control_modes: u16,
+
+    /// Layout of up to four simulcast streams (`bmLayoutPerStream`). UVC 1.5 only.
+    pub layout_per_stream: u64,
+}
+
+impl StreamingControl {
+    /// Encodes the control in the layout used by `version`.
+    pub fn encode(&self, version: UvcVersion) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(version.control_length() as usize);
+
+        bytes.extend_from_slice(&self.hint.to_le_bytes());
+        bytes.push(self.format_index);
+        bytes.push(self.frame_index);
+        bytes.extend_from_slice(&self.frame_interval.to_le_bytes());
+        bytes.extend_from_slice(&self.key_frame_rate.to_le_bytes());
+        bytes.extend_from_slice(&self.p_frame_rate.to_le_bytes());
+        bytes.extend_from_slice(&self.comp_quality.to_le_bytes());
+        bytes.extend_from_slice(&self.comp_window_size.to_le_bytes());
+        bytes.extend_from_slice(&self.delay.to_le_bytes());
+        bytes.extend_from_slice(&self.max_video_frame_size.to_le_bytes());
+        bytes.extend_from_slice(&self.max_payload_transfer_size.to_le_bytes());
+
+        if version != UvcVersion::V1_0 {
+            bytes.extend_from_slice(&self.clock_frequency.to_le_bytes());
+            bytes.push(self.framing_info);
+            bytes.push(self.preferred_version);
+            bytes.push(self.min_version);
+            bytes.push(self.max_version);
+        }
+
+        if version == UvcVersion::V1_5 {
+            bytes.push(self.usage);
+            bytes.push(self.bit_depth_luma);
+            bytes.push(self.settings);
+            bytes.push(self.max_number_of_ref_frames_plus1);
+            bytes.extend_from_slice(&self.rate_control_modes.to_le_bytes());
+            bytes.extend_from_slice(&self.layout_per_stream.to_le_bytes());
+        }
+
+        bytes
+    }
+
+    /// Decodes a control received from a device.
+    ///
+    /// The UVC version is derived from the length of `bytes`. Returns `Error::InvalidParam` if
+    /// `bytes` is shorter than a UVC 1.0 control.
+    pub fn decode(bytes: &[u8]) -> ::Result<StreamingControl> {
+        if bytes.len() < UvcVersion::V1_0.control_length() as usize {
+            return Err(Error::InvalidParam);
+        }
+
+        let mut control = StreamingControl {
+            hint: read_u16(bytes, 0),
+            format_index: bytes[2],
+            frame_index: bytes[3],
+            frame_interval: read_u32(bytes, 4),
+            key_frame_rate: read_u16(bytes, 8),
+            p_frame_rate: read_u16(bytes, 10),
+            comp_quality: read_u16(bytes, 12),
+            comp_window_size: read_u16(bytes, 14),
+            delay: read_u16(bytes, 16),
+            max_video_frame_size: read_u32(bytes, 18),
+            max_payload_transfer_size: read_u32(bytes, 22),
+            ..StreamingControl::default()
+        };
+
+        if bytes.len() >= UvcVersion::V1_1.control_length() as usize {
+            control.clock_frequency = read_u32(bytes, 26);
+            control.framing_info = bytes[30];
+            control.preferred_version = bytes[31];
+            control.min_version = bytes[32];
+            control.max_version = bytes[33];
+        }
+
+        if bytes.len() >= UvcVersion::V1_5.control_length() as usize {
+            control.usage = bytes[34];
+            control.bit_depth_luma = bytes[35];
+            control.settings = bytes[36];
+            control.max_number_of_ref_frames_plus1 = bytes[37];
+            control.rate_control_modes = read_u16(bytes, 38);
+            control.layout_per_stream = read_u32(bytes, 40) as u64 | (read_u32(bytes, 44) as u64) << 32;
+        }
+
+        Ok(control)
+    }
+}
+
+/// The `GET_*` requests that can be made for the probe control.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum GetRequest {
+    /// The current value (`GET_CUR`).
+    Current,
+
+    /// The minimum value (`GET_MIN`).
+    Minimum,
+
+    /// The maximum value (`GET_MAX`).
+    Maximum,
+
+    /// The default value (`GET_DEF`).
+    Default,
+}
+
+impl GetRequest {
+    fn request(&self) -> u8 {
+        match *self {
+            GetRequest::Current => GET_CUR,
+            GetRequest::Minimum => GET_MIN,
+            GetRequest::Maximum => GET_MAX,
+            GetRequest::Default => GET_DEF,
+        }
+    }
+}
+
+fn set(handle: &DeviceHandle, selector: u8, interface: u8, control: &StreamingControl, version: UvcVersion) -> ControlFuture<usize> {
+    control::write(handle,
+                   request_type(Direction::Out, RequestType::Class, Recipient::Interface),
+                   SET_CUR,
+                   (selector as u16) << 8,
+                   interface as u16,
+                   &control.encode(version))
+}
+
+/// Sends a probe control to a VideoStreaming interface (`SET_CUR` of `VS_PROBE_CONTROL`).
+pub fn set_probe(handle: &DeviceHandle, interface: u8, control: &StreamingControl, version: UvcVersion) -> ControlFuture<usize> {
+    set(handle, VS_PROBE_CONTROL, interface, control, version)
+}
+
+/// Reads the probe control of a VideoStreaming interface.
+pub fn get_probe(handle: &DeviceHandle, interface: u8, request: GetRequest, version: UvcVersion) -> ControlFuture<StreamingControl> {
+    control::read(handle,
+                  request_type(Direction::In, RequestType::Class, Recipient::Interface),
+                  request.request(),
+                  (VS_PROBE_CONTROL as u16) << 8,
+                  interface as u16,
+                  version.control_length(),
+                  StreamingControl::decode)
+}
+
+/// Commits a negotiated control to a VideoStreaming interface (`SET_CUR` of
+/// `VS_COMMIT_CONTROL`).
+pub fn set_commit(handle: &DeviceHandle, interface: u8, control: &StreamingControl, version: UvcVersion) -> ControlFuture<usize> {
+    set(handle, VS_COMMIT_CONTROL, interface, control, version)
+}
+
+/// Negotiates the stream parameters of a VideoStreaming interface.
+///
+/// The returned future sends `control` as the probe, reads back the values the device chose and
+/// commits them. It resolves to the committed control, whose `max_payload_transfer_size` and
+/// `max_video_frame_size` size the streaming transfers.
+pub fn negotiate<'a>(handle: &'a DeviceHandle, interface: u8, control: &StreamingControl, version: UvcVersion) -> NegotiateFuture<'a> {
+    NegotiateFuture {
+        handle,
+        interface,
+        version,
+        state: NegotiateState::SettingProbe(set_probe(handle, interface, control, version)),
+    }
+}
+
+/// Future returned by [`negotiate`](fn.negotiate.html).
+pub struct NegotiateFuture<'a> {
+    handle: &'a DeviceHandle,
+    interface: u8,
+    version: UvcVersion,
+    state: NegotiateState,
+}
+
+enum NegotiateState {
+    SettingProbe(ControlFuture<usize>),
+    GettingProbe(ControlFuture<StreamingControl>),
+    Committing(ControlFuture<usize>, StreamingControl),
+    Done,
+}
+
+impl<'a> Future for NegotiateFuture<'a> {
+    type Output = ::Result<StreamingControl>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            let state = match this.state {
+                NegotiateState::SettingProbe(ref mut future) => {
+                    match Pin::new(future).poll(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(Err(e)) => {
+                            this.state = NegotiateState::Done;
+                            return task::Poll::Ready(Err(e));
+                        },
+                        task::Poll::Ready(Ok(_)) => {
+                            NegotiateState::GettingProbe(get_probe(this.handle, this.interface, GetRequest::Current, this.version))
+                        },
+                    }
+                },
+                NegotiateState::GettingProbe(ref mut future) => {
+                    match Pin::new(future).poll(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(Err(e)) => {
+                            this.state = NegotiateState::Done;
+                            return task::Poll::Ready(Err(e));
+                        },
+                        task::Poll::Ready(Ok(control)) => {
+                            NegotiateState::Committing(set_commit(this.handle, this.interface, &control, this.version), control)
+                        },
+                    }
+                },
+                NegotiateState::Committing(ref mut future, control) => {
+                    match Pin::new(future).poll(cx) {
+                        task::Poll::Pending => return task::Poll::Pending,
+                        task::Poll::Ready(result) => {
+                            this.state = NegotiateState::Done;
+                            return task::Poll::Ready(result.map(|_| control));
+                        },
+                    }
+                },
+                NegotiateState::Done => return task::Poll::Ready(Err(Error::PolledAfterCompletion)),
+            };
+
+            this.state = state;
+        }
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    bytes[offset] as u16 | (bytes[offset + 1] as u16) << 8
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    read_u16(bytes, offset) as u32 | (read_u16(bytes, offset + 2) as u32) << 16
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{StreamingControl, UvcVersion};
+
+    fn control() -> StreamingControl {
+        StreamingControl {
+            hint: 0x0001,
+            format_index: 1,
+            frame_index: 2,
+            frame_interval: 333_333,
+            max_video_frame_size: 614_400,
+            max_payload_transfer_size: 3072,
+            clock_frequency: 48_000_000,
+            framing_info: 0x03,
+            usage: 1,
+            layout_per_stream: 0x0102_0304_0506_0708,
+            ..StreamingControl::default()
+        }
+    }
+
+    #[test]
+    fn it_encodes_version_specific_length() {
+        assert_eq!(26, control().encode(UvcVersion::V1_0).len());
+        assert_eq!(34, control().encode(UvcVersion::V1_1).len());
+        assert_eq!(48, control().encode(UvcVersion::V1_5).len());
+    }
+
+    #[test]
+    fn it_encodes_little_endian_fields() {
+        let bytes = control().encode(UvcVersion::V1_0);
+
+        assert_eq!(&[0x01, 0x00, 0x01, 0x02, 0x15, 0x16, 0x05, 0x00], &bytes[..8]);
+        assert_eq!(&[0x00, 0x0C, 0x00, 0x00], &bytes[22..26]);
+    }
+
+    #[test]
+    fn it_round_trips_uvc_1_5_control() {
+        assert_eq!(control(), StreamingControl::decode(&control().encode(UvcVersion::V1_5)).unwrap());
+    }
+
+    #[test]
+    fn it_zeroes_fields_missing_from_older_versions() {
+        let decoded = StreamingControl::decode(&control().encode(UvcVersion::V1_1)).unwrap();
+
+        assert_eq!(48_000_000, decoded.clock_frequency);
+        assert_eq!(0, decoded.usage);
+        assert_eq!(0, decoded.layout_per_stream);
+    }
+
+    #[test]
+    fn it_rejects_short_control() {
+        assert!(StreamingControl::decode(&[0; 25]).is_err());
+    }
+
+    #[test]
+    fn it_maps_bcd_uvc_to_version() {
+        assert_eq!(UvcVersion::V1_0, UvcVersion::from_bcd(0x0100));
+        assert_eq!(UvcVersion::V1_1, UvcVersion::from_bcd(0x0110));
+        assert_eq!(UvcVersion::V1_5, UvcVersion::from_bcd(0x0150));
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task;
+use std::time::Instant;
+
+use futures_core::Stream;
+
+use config_descriptor::ConfigDescriptor;
+use device_handle::DeviceHandle;
+use error::Error;
+use fields::{Direction, TransferType};
+use transfer::TransferFuture;
+
+use super::StreamingControl;
+
+/// Number of transfers kept in flight.
+const TRANSFERS: usize = 4;
+
+/// Number of packets in each isochronous transfer.
+const ISO_PACKETS: u32 = 32;
+
+const HEADER_FRAME_ID: u8 = 0x01;
+const HEADER_END_OF_FRAME: u8 = 0x02;
+const HEADER_PTS: u8 = 0x04;
+const HEADER_SCR: u8 = 0x08;
+const HEADER_STILL_IMAGE: u8 = 0x20;
+const HEADER_ERROR: u8 = 0x40;
+
+/// A video frame reassembled from payloads.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct Frame {
+    /// The frame data, without payload headers.
+    pub data: Vec<u8>,
+
+    /// The frame ID bit, which toggles between frames.
+    pub frame_id: bool,
+
+    /// The presentation time stamp in device clock units, if the device sent one.
+    pub pts: Option<u32>,
+
+    /// The source clock reference, if the device sent one: the source time clock in device
+    /// clock units and the 11-bit USB frame number it was sampled at.
+    pub scr: Option<(u32, u16)>,
+
+    /// Indicates that the frame is a still image.
+    pub still_image: bool,
+
+    /// Indicates that the device reported an error in one of the payloads, or that the frame
+    /// exceeded the maximum frame size. The data is likely incomplete.
+    pub error: bool,
+
+    /// The time the first payload of the frame was received.
+    pub received: Instant,
+}
+
+struct PayloadHeader {
+    length: usize,
+    info: u8,
+    pts: Option<u32>,
+    scr: Option<(u32, u16)>,
+}
+
+impl PayloadHeader {
+    fn parse(payload: &[u8]) -> Option<PayloadHeader> {
+        let length = *payload.first()? as usize;
+        if length < 2 || length > payload.len() {
+            return None;
+        }
+
+        let info = payload[1];
+        let mut offset = 2;
+        let mut field = |size: usize| {
+            let bytes = payload.get(offset..offset + size);
+            offset += size;
+            bytes
+        };
+
+        let pts = if info & HEADER_PTS != 0 {
+            let bytes = field(4)?;
+            Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        } else { None };
+
+        let scr = if info & HEADER_SCR != 0 {
+            let bytes = field(6)?;
+            Some((u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+                  u16::from_le_bytes([bytes[4], bytes[5]]) & 0x07FF))
+        } else { None };
+
+        if offset > length {
+            return None;
+        }
+
+        Some(PayloadHeader { length, info, pts, scr })
+    }
+}
+
+/// Reassembles frames from the payloads of a video stream.
+///
+/// A frame ends at a payload with the end of frame bit set, or when the frame ID bit toggles.
+/// Payloads with malformed headers are dropped.
+#[derive(Debug)]
+pub struct FrameAssembler {
+    max_frame_size: usize,
+    frame: Option<Frame>,
+    ready: VecDeque<Frame>,
+}
+
+impl FrameAssembler {
+    /// Creates an assembler for frames of at most `max_frame_size` bytes, which is taken from
+    /// `max_video_frame_size` of the committed streaming control.
+    pub fn new(max_frame_size: usize) -> FrameAssembler {
+        FrameAssembler { max_frame_size, frame: None, ready: VecDeque::new() }
+    }
+
+    /// Adds a payload, including its header.
+    pub fn push(&mut self, payload: &[u8]) {
+        let header = match PayloadHeader::parse(payload) {
+            Some(header) => header,
+            None => return,
+        };
+
+        let frame_id = header.info & HEADER_FRAME_ID != 0;
+        let end_of_frame = header.info & HEADER_END_OF_FRAME != 0;
+        let data = &payload[header.length..];
+
+        if self.frame.as_ref().is_some_and(|frame| frame.frame_id != frame_id) {
+            self.ready.extend(self.frame.take());
+        }
+
+        if self.frame.is_none() {
+            // Empty payloads are sent between frames on isochronous endpoints
+            if data.is_empty() && !end_of_frame {
+                return;
+            }
+
+            self.frame = Some(Frame {
+                data: Vec::with_capacity(self.max_frame_size),
+                frame_id,
+                pts: None,
+                scr: None,
+                still_image: false,
+                error: false,
+                received: Instant::now(),
+            });
+        }
+
+        if let Some(ref mut frame) = self.frame {
+            if frame.data.len() + data.len() > self.max_frame_size {
+                frame.error = true;
+            } else {
+                frame.data.extend_from_slice(data);
+            }
+
+            frame.pts = frame.pts.or(header.pts);
+            frame.scr = frame.scr.or(header.scr);
+            frame.still_image |= header.info & HEADER_STILL_IMAGE != 0;
+            frame.error |= header.info & HEADER_ERROR != 0;
+        }
+
+        if end_of_frame {
+            self.ready.extend(self.frame.take());
+        }
+    }
+
+    /// Returns the next complete frame, if any.
+    pub fn pop(&mut self) -> Option<Frame> {
+        self.ready.pop_front()
+    }
+}
+
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+enum Endpoint {
+    Isochronous { address: u8, packet_size: usize },
+    Bulk { address: u8, transfer_size: usize },
+}
+
+/// Selects the alternate setting and endpoint that can carry `payload_size` bytes per service
+/// interval.
+///
+/// The setting with the smallest isochronous bandwidth that is large enough is chosen. An
+/// interface without isochronous endpoints streams over a bulk endpoint of its first setting.
+fn select_setting(config: &ConfigDescriptor, interface: u8, payload_size: usize) -> ::Result<(u8, Endpoint)> {
+    let interface = config.interfaces().find(|i| i.number() == interface).ok_or(Error::NotFound)?;
+
+    let isochronous = interface.descriptors()
+        .filter_map(|setting| {
+            let endpoint = setting.first_endpoint(TransferType::Isochronous, Direction::In)?;
+            Some((setting.setting_number(), endpoint.address(), endpoint.max_bytes_per_interval()))
+        })
+        .collect::<Vec<_>>();
+
+    if isochronous.is_empty() {
+        let setting = interface.descriptors().next().ok_or(Error::NotFound)?;
+        let endpoint = setting.first_endpoint(TransferType::Bulk, Direction::In).ok_or(Error::NotFound)?;
+
+        return Ok((setting.setting_number(), Endpoint::Bulk { address: endpoint.address(), transfer_size: payload_size }));
+    }
+
+    isochronous.into_iter()
+        .filter(|&(_, _, packet_size)| packet_size >= payload_size)
+        .min_by_key(|&(_, _, packet_size)| packet_size)
+        .map(|(setting, address, packet_size)| (setting, Endpoint::Isochronous { address, packet_size }))
+        .ok_or(Error::NotFound)
+}
+
+/// A running video stream on a VideoStreaming interface.
+///
+/// The stream yields complete frames. It ends after the first transfer error, and dropping it
+/// cancels the transfers and selects alternate setting 0, which stops the device from
+/// streaming.
+pub struct VideoStream<'a> {
+    handle: &'a mut DeviceHandle,
+    interface: u8,
+    endpoint: Endpoint,
+    transfers: VecDeque<TransferFuture>,
+    assembler: FrameAssembler,
+    done: bool,
+}
+
+impl<'a> VideoStream<'a> {
+    /// Starts streaming with a committed streaming control.
+    ///
+    /// `control` is the control that [`negotiate`](fn.negotiate.html) resolved to. The
+    /// interface must already be claimed. The alternate setting is chosen from
+    /// `max_payload_transfer_size`, and the transfers are submitted right away.
+    ///
+    /// ## Errors
+    ///
+    /// * `NotFound` if the interface has no setting with enough bandwidth, or no streaming
+    ///   endpoint at all.
+    /// * Any error returned while selecting the setting or submitting the transfers.
+    pub fn start(handle: &'a mut DeviceHandle, config: &ConfigDescriptor, interface: u8, control: &StreamingControl) -> ::Result<VideoStream<'a>> {
+        let (setting, endpoint) = select_setting(config, interface, control.max_payload_transfer_size as usize)?;
+
+        if setting != 0 {
+            handle.set_alternate_setting(interface, setting)?;
+        }
+
+        let mut stream = VideoStream {
+            handle,
+            interface,
+            endpoint,
+            transfers: VecDeque::with_capacity(TRANSFERS),
+            assembler: FrameAssembler::new(control.max_video_frame_size as usize),
+            done: false,
+        };
+
+        for _ in 0..TRANSFERS {
+            let transfer = stream.submit()?;
+            stream.transfers.push_back(transfer);
+        }
+
+        Ok(stream)
+    }
+
+    fn submit(&self) -> ::Result<TransferFuture> {
+        match self.endpoint {
+            Endpoint::Isochronous { address, packet_size } => {
+                let mut transfer = self.handle.alloc_transfer(ISO_PACKETS)?;
+                transfer.fill_iso_read(address.into(), packet_size)?;
+                Ok(transfer.submit())
+            },
+            Endpoint::Bulk { address, transfer_size } => {
+                let mut transfer = self.handle.alloc_transfer(0)?;
+                transfer.fill_bulk_read(address.into(), transfer_size)?;
+                Ok(transfer.submit())
+            },
+        }
+    }
+}
+
+impl<'a> Drop for VideoStream<'a> {
+    fn drop(&mut self) {
+        self.transfers.clear();
+        let _ = self.handle.set_alternate_setting(self.interface, 0);
+    }
+}
+
+impl<'a> Stream for VideoStream<'a> {
+    type Item = ::Result<Frame>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(frame) = this.assembler.pop() {
+                return task::Poll::Ready(Some(Ok(frame)));
+            }
+
+            if this.done {
+                return task::Poll::Ready(None);
+            }
+
+            let result = match this.transfers.front_mut() {
+                Some(future) => match Pin::new(future).poll(cx) {
+                    task::Poll::Pending => return task::Poll::Pending,
+                    task::Poll::Ready(result) => result,
+                },
+                None => unreachable!(),
+            };
+
+            this.transfers.pop_front();
+
+            let transfer = match result.and_then(|transfer| transfer.check_status().map(|_| transfer)) {
+                Ok(transfer) => transfer,
+                Err(e) => {
+                    this.done = true;
+                    this.transfers.clear();
+                    return task::Poll::Ready(Some(Err(e)));
+                },
+            };
+
+            // Resubmit first to keep the ring full while the payloads are processed
+            match this.submit() {
+                Ok(future) => this.transfers.push_back(future),
+                Err(e) => {
+                    this.done = true;
+                    this.transfers.clear();
+                    return task::Poll::Ready(Some(Err(e)));
+                },
+            }
+
+            match this.endpoint {
+                Endpoint::Isochronous { .. } => {
+                    for packet in transfer.iso_packets() {
+                        if packet.status().to_result().is_ok() {
+                            this.assembler.push(packet.data());
+                        }
+                    }
+                },
+                Endpoint::Bulk { .. } => this.assembler.push(transfer.get_buffer()),
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::mem;
+
+    fn payload(info: u8, data: &[u8]) -> Vec<u8> {
+        let mut payload = vec![2, info];
+        payload.extend_from_slice(data);
+        payload
+    }
+
+    #[test]
+    fn it_assembles_frame_until_end_of_frame() {
+        let mut assembler = FrameAssembler::new(16);
+
+        assembler.push(&payload(0x80, &[1, 2]));
+        assert!(assembler.pop().is_none());
+
+        assembler.push(&payload(0x82, &[3]));
+        let frame = assembler.pop().unwrap();
+        assert_eq!(vec![1, 2, 3], frame.data);
+        assert!(!frame.frame_id);
+        assert!(!frame.error);
+    }
+
+    #[test]
+    fn it_ends_frame_when_frame_id_toggles() {
+        let mut assembler = FrameAssembler::new(16);
+
+        assembler.push(&payload(0x80, &[1]));
+        assembler.push(&payload(0x81, &[2]));
+        assert_eq!(vec![1], assembler.pop().unwrap().data);
+
+        assembler.push(&payload(0x83, &[3]));
+        let frame = assembler.pop().unwrap();
+        assert_eq!(vec![2, 3], frame.data);
+        assert!(frame.frame_id);
+    }
+
+    #[test]
+    fn it_reads_timestamps_from_header() {
+        let mut assembler = FrameAssembler::new(16);
+
+        assembler.push(&[12, 0x8E, 0x78, 0x56, 0x34, 0x12, 0x01, 0x00, 0x00, 0x00, 0xFF, 0xFF, 9]);
+        let frame = assembler.pop().unwrap();
+        assert_eq!(Some(0x1234_5678), frame.pts);
+        assert_eq!(Some((1, 0x07FF)), frame.scr);
+        assert_eq!(vec![9], frame.data);
+    }
+
+    #[test]
+    fn it_flags_errors_and_oversized_frames() {
+        let mut assembler = FrameAssembler::new(2);
+
+        assembler.push(&payload(0xC2, &[1]));
+        assert!(assembler.pop().unwrap().error);
+
+        assembler.push(&payload(0x82, &[1, 2, 3]));
+        assert!(assembler.pop().unwrap().error);
+    }
+
+    #[test]
+    fn it_drops_malformed_and_empty_payloads() {
+        let mut assembler = FrameAssembler::new(16);
+
+        assembler.push(&[]);
+        assembler.push(&[1, 0x82]);
+        assembler.push(&[6, 0x86, 0, 0]);
+        assembler.push(&payload(0x80, &[]));
+        assert!(assembler.pop().is_none());
+    }
+
+    #[test]
+    fn it_selects_smallest_sufficient_setting() {
+        let interface = interface!(
+            interface_descriptor!(bInterfaceClass: 0x0E, bInterfaceSubClass: 0x02),
+            merge!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x81, bmAttributes: 0x05, wMaxPacketSize: 0x0200))
+                   => bAlternateSetting: 1),
+            merge!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x81, bmAttributes: 0x05, wMaxPacketSize: 0x0C00))
+                   => bAlternateSetting: 2),
+            merge!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x81, bmAttributes: 0x05, wMaxPacketSize: 0x1400))
+                   => bAlternateSetting: 3));
+        let config = config_descriptor!(interface);
+        let config = unsafe { ::config_descriptor::from_libusb(&config) };
+
+        assert_eq!((2, Endpoint::Isochronous { address: 0x81, packet_size: 2048 }), select_setting(&config, 0, 1024).unwrap());
+        assert_eq!((1, Endpoint::Isochronous { address: 0x81, packet_size: 512 }), select_setting(&config, 0, 512).unwrap());
+        assert!(select_setting(&config, 0, 4096).is_err());
+        mem::forget(config);
+    }
+
+    #[test]
+    fn it_falls_back_to_bulk_endpoint() {
+        let interface = interface!(merge!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x82, bmAttributes: 0x02))
+                                          => bInterfaceClass: 0x0E, bInterfaceSubClass: 0x02));
+        let config = config_descriptor!(interface);
+        let config = unsafe { ::config_descriptor::from_libusb(&config) };
+
+        assert_eq!((0, Endpoint::Bulk { address: 0x82, transfer_size: 1024 }), select_setting(&config, 0, 1024).unwrap());
+        mem::forget(config);
+    }
+}